@@ -0,0 +1,7 @@
+PRAGMA foreign_keys = ON;
+
+ALTER TABLE chat_sessions
+    ADD COLUMN translation_enabled INTEGER NOT NULL DEFAULT 0;
+
+ALTER TABLE chat_sessions
+    ADD COLUMN translation_language TEXT;
@@ -0,0 +1,4 @@
+PRAGMA foreign_keys = ON;
+
+ALTER TABLE chat_runs
+    ADD COLUMN tags TEXT NOT NULL DEFAULT '[]';
@@ -0,0 +1,4 @@
+PRAGMA foreign_keys = ON;
+
+ALTER TABLE chat_session_compression_states
+    ADD COLUMN message_count_threshold INTEGER;
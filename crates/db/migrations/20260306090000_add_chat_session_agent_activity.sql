@@ -0,0 +1,10 @@
+PRAGMA foreign_keys = ON;
+
+ALTER TABLE chat_session_agents
+    ADD COLUMN last_active_at TEXT;
+ALTER TABLE chat_session_agents
+    ADD COLUMN total_runs INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE chat_session_agents
+    ADD COLUMN total_tokens INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE chat_session_agents
+    ADD COLUMN failed_runs INTEGER NOT NULL DEFAULT 0;
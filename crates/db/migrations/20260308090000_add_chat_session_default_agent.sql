@@ -0,0 +1,4 @@
+PRAGMA foreign_keys = ON;
+
+ALTER TABLE chat_sessions
+    ADD COLUMN default_agent_id BLOB REFERENCES chat_agents(id) ON DELETE SET NULL;
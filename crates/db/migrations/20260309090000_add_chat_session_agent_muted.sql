@@ -0,0 +1,2 @@
+ALTER TABLE chat_session_agents
+    ADD COLUMN muted INTEGER NOT NULL DEFAULT 0;
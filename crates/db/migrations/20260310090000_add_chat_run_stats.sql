@@ -0,0 +1,20 @@
+PRAGMA foreign_keys = ON;
+
+-- Per-member daily run totals, kept after the member or session is removed so reports
+-- stay complete.
+CREATE TABLE chat_run_stats (
+    session_agent_id BLOB NOT NULL,
+    day              TEXT NOT NULL,
+    session_id       BLOB NOT NULL,
+    agent_id         BLOB NOT NULL,
+    run_count        INTEGER NOT NULL DEFAULT 0,
+    total_tokens     INTEGER NOT NULL DEFAULT 0,
+    estimated_cost   REAL NOT NULL DEFAULT 0,
+    failed_runs      INTEGER NOT NULL DEFAULT 0,
+    updated_at       TEXT NOT NULL DEFAULT (datetime('now', 'subsec')),
+    PRIMARY KEY (session_agent_id, day)
+);
+
+CREATE INDEX idx_chat_run_stats_session_id ON chat_run_stats(session_id);
+CREATE INDEX idx_chat_run_stats_agent_id ON chat_run_stats(agent_id);
+CREATE INDEX idx_chat_run_stats_day ON chat_run_stats(day);
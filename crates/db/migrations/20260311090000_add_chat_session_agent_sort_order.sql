@@ -0,0 +1,13 @@
+ALTER TABLE chat_session_agents
+    ADD COLUMN sort_order INTEGER NOT NULL DEFAULT 0;
+
+-- Existing members keep their join order.
+UPDATE chat_session_agents
+SET sort_order = (
+    SELECT COUNT(*)
+    FROM chat_session_agents AS earlier
+    WHERE earlier.session_id = chat_session_agents.session_id
+      AND (earlier.created_at < chat_session_agents.created_at
+           OR (earlier.created_at = chat_session_agents.created_at
+               AND earlier.id < chat_session_agents.id))
+);
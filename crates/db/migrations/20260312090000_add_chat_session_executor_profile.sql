@@ -0,0 +1,4 @@
+PRAGMA foreign_keys = ON;
+
+ALTER TABLE chat_sessions
+    ADD COLUMN executor_profile TEXT;
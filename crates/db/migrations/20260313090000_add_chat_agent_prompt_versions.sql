@@ -0,0 +1,13 @@
+PRAGMA foreign_keys = ON;
+
+-- System prompts an agent used before each change, so edits can be reviewed and reverted.
+CREATE TABLE chat_agent_prompt_versions (
+    id            BLOB PRIMARY KEY,
+    agent_id      BLOB NOT NULL,
+    system_prompt TEXT NOT NULL,
+    created_at    TEXT NOT NULL DEFAULT (datetime('now', 'subsec')),
+    FOREIGN KEY (agent_id) REFERENCES chat_agents(id) ON DELETE CASCADE
+);
+
+CREATE INDEX idx_chat_agent_prompt_versions_agent_id
+    ON chat_agent_prompt_versions(agent_id, created_at);
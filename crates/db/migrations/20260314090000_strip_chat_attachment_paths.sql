@@ -0,0 +1,18 @@
+PRAGMA foreign_keys = ON;
+
+-- Attachment storage paths are derived from the message and attachment ids; stop
+-- exposing the recorded local path in message meta.
+UPDATE chat_messages
+SET meta = json_set(
+    meta,
+    '$.attachments',
+    json((
+        SELECT json_group_array(json_remove(attachment, '$.relative_path'))
+        FROM (
+            SELECT value AS attachment
+            FROM json_each(chat_messages.meta, '$.attachments')
+            ORDER BY key
+        )
+    ))
+)
+WHERE json_type(meta, '$.attachments') = 'array';
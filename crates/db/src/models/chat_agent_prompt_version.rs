@@ -0,0 +1,79 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, SqlitePool};
+use ts_rs::TS;
+use uuid::Uuid;
+
+/// A system prompt an agent used until it was changed at `created_at`.
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, TS)]
+pub struct ChatAgentPromptVersion {
+    pub id: Uuid,
+    pub agent_id: Uuid,
+    pub system_prompt: String,
+    pub created_at: DateTime<Utc>,
+}
+
+impl ChatAgentPromptVersion {
+    /// Keep `system_prompt` as a previous version of the agent's prompt.
+    pub async fn create(
+        pool: &SqlitePool,
+        agent_id: Uuid,
+        system_prompt: &str,
+    ) -> Result<Self, sqlx::Error> {
+        let id = Uuid::new_v4();
+        sqlx::query_as!(
+            ChatAgentPromptVersion,
+            r#"INSERT INTO chat_agent_prompt_versions (id, agent_id, system_prompt)
+               VALUES ($1, $2, $3)
+               RETURNING id as "id!: Uuid",
+                         agent_id as "agent_id!: Uuid",
+                         system_prompt,
+                         created_at as "created_at!: DateTime<Utc>""#,
+            id,
+            agent_id,
+            system_prompt
+        )
+        .fetch_one(pool)
+        .await
+    }
+
+    /// Previous prompts of an agent, newest first.
+    pub async fn find_by_agent_id(
+        pool: &SqlitePool,
+        agent_id: Uuid,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        sqlx::query_as!(
+            ChatAgentPromptVersion,
+            r#"SELECT id as "id!: Uuid",
+                      agent_id as "agent_id!: Uuid",
+                      system_prompt,
+                      created_at as "created_at!: DateTime<Utc>"
+               FROM chat_agent_prompt_versions
+               WHERE agent_id = $1
+               ORDER BY created_at DESC, rowid DESC"#,
+            agent_id
+        )
+        .fetch_all(pool)
+        .await
+    }
+
+    pub async fn find_by_id(
+        pool: &SqlitePool,
+        agent_id: Uuid,
+        id: Uuid,
+    ) -> Result<Option<Self>, sqlx::Error> {
+        sqlx::query_as!(
+            ChatAgentPromptVersion,
+            r#"SELECT id as "id!: Uuid",
+                      agent_id as "agent_id!: Uuid",
+                      system_prompt,
+                      created_at as "created_at!: DateTime<Utc>"
+               FROM chat_agent_prompt_versions
+               WHERE agent_id = $1 AND id = $2"#,
+            agent_id,
+            id
+        )
+        .fetch_optional(pool)
+        .await
+    }
+}
@@ -143,18 +143,121 @@ impl ChatMessage {
         Ok(result.rows_affected())
     }
 
+    /// Delete `ids` from a session in one transaction and return the ids actually removed.
+    /// Messages that quoted a deleted message lose their reference and are flagged with
+    /// `reference_deleted` instead.
+    pub async fn delete_many_in_session(
+        pool: &SqlitePool,
+        session_id: Uuid,
+        ids: &[Uuid],
+    ) -> Result<Vec<Uuid>, sqlx::Error> {
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut tx = pool.begin().await?;
+        let mut deleted = Vec::new();
+        for id in ids {
+            let result = sqlx::query("DELETE FROM chat_messages WHERE id = $1 AND session_id = $2")
+                .bind(id)
+                .bind(session_id)
+                .execute(&mut *tx)
+                .await?;
+            if result.rows_affected() == 0 {
+                continue;
+            }
+            deleted.push(*id);
+
+            sqlx::query(
+                r#"UPDATE chat_messages
+                   SET meta = json_set(
+                       json_remove(meta, '$.reference', '$.reference_message_id'),
+                       '$.reference_deleted',
+                       json('true')
+                   )
+                   WHERE session_id = $1
+                     AND (json_extract(meta, '$.reference.message_id') = $2
+                          OR json_extract(meta, '$.reference_message_id') = $2)"#,
+            )
+            .bind(session_id)
+            .bind(id.to_string())
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(deleted)
+    }
+
     pub async fn update_meta(
         pool: &SqlitePool,
         id: Uuid,
         meta: serde_json::Value,
     ) -> Result<u64, sqlx::Error> {
         let meta_str = serde_json::to_string(&meta).unwrap_or_default();
-        let id_str = id.to_string();
         let result = sqlx::query("UPDATE chat_messages SET meta = $1 WHERE id = $2")
             .bind(meta_str)
-            .bind(id_str)
+            .bind(id)
             .execute(pool)
             .await?;
         Ok(result.rows_affected())
     }
+
+    /// Apply `patch` to the stored meta as an RFC 7396 merge patch in a single statement,
+    /// so concurrent writers touching different keys never overwrite each other. Nested
+    /// objects are merged, `null` removes a key, and any other value replaces it.
+    pub async fn merge_meta(
+        pool: &SqlitePool,
+        id: Uuid,
+        patch: serde_json::Value,
+    ) -> Result<u64, sqlx::Error> {
+        let patch_str = serde_json::to_string(&patch).unwrap_or_else(|_| "{}".to_string());
+        let result = sqlx::query(
+            r#"UPDATE chat_messages
+               SET meta = json_patch(
+                   CASE WHEN json_type(meta) = 'object' THEN meta ELSE '{}' END,
+                   $1
+               )
+               WHERE id = $2"#,
+        )
+        .bind(patch_str)
+        .bind(id)
+        .execute(pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    /// Append `item` to the array at meta key `key` in a single statement, creating the
+    /// array when missing, so concurrent appends to the same message are all kept.
+    pub async fn append_meta_item(
+        pool: &SqlitePool,
+        id: Uuid,
+        key: &str,
+        item: serde_json::Value,
+    ) -> Result<u64, sqlx::Error> {
+        let path = format!("$.{key}");
+        let append_path = format!("$.{key}[#]");
+        let item_str = serde_json::to_string(&item).unwrap_or_else(|_| "null".to_string());
+        let result = sqlx::query!(
+            r#"UPDATE chat_messages
+               SET meta = json_insert(
+                   CASE WHEN json_type(meta, $1) = 'array' THEN meta
+                        ELSE json_set(
+                            CASE WHEN json_type(meta) = 'object' THEN meta ELSE '{}' END,
+                            $1,
+                            json('[]')
+                        )
+                   END,
+                   $2,
+                   json($3)
+               )
+               WHERE id = $4"#,
+            path,
+            append_path,
+            item_str,
+            id
+        )
+        .execute(pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
 }
@@ -18,6 +18,14 @@ pub struct ChatRun {
     pub created_at: DateTime<Utc>,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+pub struct ChatRunWithTags {
+    #[serde(flatten)]
+    #[ts(flatten)]
+    pub run: ChatRun,
+    pub tags: Vec<String>,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct CreateChatRun {
     pub session_id: Uuid,
@@ -78,6 +86,35 @@ impl ChatRun {
         .await
     }
 
+    /// Every run, grouped by session agent with the newest run first in each group.
+    pub async fn find_all(pool: &SqlitePool) -> Result<Vec<Self>, sqlx::Error> {
+        sqlx::query_as!(
+            ChatRun,
+            r#"SELECT id as "id!: Uuid",
+                      session_id as "session_id!: Uuid",
+                      session_agent_id as "session_agent_id!: Uuid",
+                      run_index,
+                      run_dir,
+                      input_path,
+                      output_path,
+                      raw_log_path,
+                      meta_path,
+                      created_at as "created_at!: DateTime<Utc>"
+               FROM chat_runs
+               ORDER BY session_agent_id, run_index DESC"#
+        )
+        .fetch_all(pool)
+        .await
+    }
+
+    pub async fn delete(pool: &SqlitePool, id: Uuid) -> Result<u64, sqlx::Error> {
+        let result = sqlx::query("DELETE FROM chat_runs WHERE id = $1")
+            .bind(id)
+            .execute(pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
     pub async fn next_run_index(
         pool: &SqlitePool,
         session_agent_id: Uuid,
@@ -127,4 +164,75 @@ impl ChatRun {
         .fetch_one(pool)
         .await
     }
+
+    pub async fn find_tags(pool: &SqlitePool, id: Uuid) -> Result<Vec<String>, sqlx::Error> {
+        let tags = sqlx::query_scalar!(r#"SELECT tags FROM chat_runs WHERE id = $1"#, id)
+            .fetch_optional(pool)
+            .await?;
+        Ok(tags
+            .and_then(|tags| serde_json::from_str(&tags).ok())
+            .unwrap_or_default())
+    }
+
+    pub async fn set_tags(
+        pool: &SqlitePool,
+        id: Uuid,
+        tags: &[String],
+    ) -> Result<u64, sqlx::Error> {
+        let tags = serde_json::to_string(tags).unwrap_or_else(|_| "[]".to_string());
+        let result = sqlx::query!(r#"UPDATE chat_runs SET tags = $2 WHERE id = $1"#, id, tags)
+            .execute(pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    /// Runs in a session with their tags, newest first, optionally limited to one tag.
+    pub async fn find_by_session_with_tags(
+        pool: &SqlitePool,
+        session_id: Uuid,
+        tag: Option<&str>,
+    ) -> Result<Vec<ChatRunWithTags>, sqlx::Error> {
+        let rows = sqlx::query!(
+            r#"SELECT id as "id!: Uuid",
+                      session_id as "session_id!: Uuid",
+                      session_agent_id as "session_agent_id!: Uuid",
+                      run_index,
+                      run_dir,
+                      input_path,
+                      output_path,
+                      raw_log_path,
+                      meta_path,
+                      created_at as "created_at!: DateTime<Utc>",
+                      tags
+               FROM chat_runs
+               WHERE session_id = $1
+                 AND ($2 IS NULL OR EXISTS (
+                      SELECT 1 FROM json_each(chat_runs.tags) WHERE json_each.value = $2
+                 ))
+               ORDER BY created_at DESC"#,
+            session_id,
+            tag
+        )
+        .fetch_all(pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| ChatRunWithTags {
+                run: ChatRun {
+                    id: row.id,
+                    session_id: row.session_id,
+                    session_agent_id: row.session_agent_id,
+                    run_index: row.run_index,
+                    run_dir: row.run_dir,
+                    input_path: row.input_path,
+                    output_path: row.output_path,
+                    raw_log_path: row.raw_log_path,
+                    meta_path: row.meta_path,
+                    created_at: row.created_at,
+                },
+                tags: serde_json::from_str(&row.tags).unwrap_or_default(),
+            })
+            .collect())
+    }
 }
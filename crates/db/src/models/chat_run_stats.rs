@@ -0,0 +1,148 @@
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use ts_rs::TS;
+use uuid::Uuid;
+
+use super::chat_session_agent::ChatSessionAgent;
+
+/// Dimension run statistics are totalled over.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, TS)]
+#[serde(rename_all = "snake_case")]
+pub enum ChatRunStatsGroupBy {
+    #[default]
+    Agent,
+    Session,
+    Day,
+}
+
+/// Run totals for one agent, session or day; only the grouped key is set.
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+pub struct ChatRunStats {
+    pub agent_id: Option<Uuid>,
+    pub session_id: Option<Uuid>,
+    /// UTC day as `YYYY-MM-DD`
+    pub day: Option<String>,
+    pub run_count: i64,
+    pub total_tokens: i64,
+    /// Estimated cost in US dollars
+    pub estimated_cost: f64,
+    pub failed_runs: i64,
+}
+
+impl ChatRunStats {
+    /// Count one finished run toward today's totals of its member and the member's activity
+    /// aggregates, in one transaction so both always agree.
+    pub async fn record_run(
+        pool: &SqlitePool,
+        session_id: Uuid,
+        session_agent_id: Uuid,
+        agent_id: Uuid,
+        tokens: u32,
+        estimated_cost: f64,
+        failed: bool,
+    ) -> Result<(), sqlx::Error> {
+        let tokens_total = i64::from(tokens);
+        let failed_runs = i64::from(failed);
+        let mut tx = pool.begin().await?;
+        sqlx::query!(
+            r#"INSERT INTO chat_run_stats
+                   (session_agent_id, day, session_id, agent_id, run_count, total_tokens,
+                    estimated_cost, failed_runs)
+               VALUES ($1, date('now'), $2, $3, 1, $4, $5, $6)
+               ON CONFLICT (session_agent_id, day) DO UPDATE SET
+                   run_count = run_count + 1,
+                   total_tokens = total_tokens + excluded.total_tokens,
+                   estimated_cost = estimated_cost + excluded.estimated_cost,
+                   failed_runs = failed_runs + excluded.failed_runs,
+                   updated_at = datetime('now', 'subsec')"#,
+            session_agent_id,
+            session_id,
+            agent_id,
+            tokens_total,
+            estimated_cost,
+            failed_runs
+        )
+        .execute(&mut *tx)
+        .await?;
+        ChatSessionAgent::record_run_activity(&mut *tx, session_agent_id, tokens, failed).await?;
+        tx.commit().await
+    }
+
+    /// Totals over every recorded run, grouped by `group_by` and ordered by its key.
+    pub async fn aggregate(
+        pool: &SqlitePool,
+        group_by: ChatRunStatsGroupBy,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        Ok(match group_by {
+            ChatRunStatsGroupBy::Agent => sqlx::query!(
+                r#"SELECT agent_id as "agent_id!: Uuid",
+                          SUM(run_count) as "run_count!: i64",
+                          SUM(total_tokens) as "total_tokens!: i64",
+                          SUM(estimated_cost) as "estimated_cost!: f64",
+                          SUM(failed_runs) as "failed_runs!: i64"
+                   FROM chat_run_stats
+                   GROUP BY agent_id
+                   ORDER BY agent_id"#
+            )
+            .fetch_all(pool)
+            .await?
+            .into_iter()
+            .map(|row| Self {
+                agent_id: Some(row.agent_id),
+                session_id: None,
+                day: None,
+                run_count: row.run_count,
+                total_tokens: row.total_tokens,
+                estimated_cost: row.estimated_cost,
+                failed_runs: row.failed_runs,
+            })
+            .collect(),
+            ChatRunStatsGroupBy::Session => sqlx::query!(
+                r#"SELECT session_id as "session_id!: Uuid",
+                          SUM(run_count) as "run_count!: i64",
+                          SUM(total_tokens) as "total_tokens!: i64",
+                          SUM(estimated_cost) as "estimated_cost!: f64",
+                          SUM(failed_runs) as "failed_runs!: i64"
+                   FROM chat_run_stats
+                   GROUP BY session_id
+                   ORDER BY session_id"#
+            )
+            .fetch_all(pool)
+            .await?
+            .into_iter()
+            .map(|row| Self {
+                agent_id: None,
+                session_id: Some(row.session_id),
+                day: None,
+                run_count: row.run_count,
+                total_tokens: row.total_tokens,
+                estimated_cost: row.estimated_cost,
+                failed_runs: row.failed_runs,
+            })
+            .collect(),
+            ChatRunStatsGroupBy::Day => sqlx::query!(
+                r#"SELECT day,
+                          SUM(run_count) as "run_count!: i64",
+                          SUM(total_tokens) as "total_tokens!: i64",
+                          SUM(estimated_cost) as "estimated_cost!: f64",
+                          SUM(failed_runs) as "failed_runs!: i64"
+                   FROM chat_run_stats
+                   GROUP BY day
+                   ORDER BY day"#
+            )
+            .fetch_all(pool)
+            .await?
+            .into_iter()
+            .map(|row| Self {
+                agent_id: None,
+                session_id: None,
+                day: Some(row.day),
+                run_count: row.run_count,
+                total_tokens: row.total_tokens,
+                estimated_cost: row.estimated_cost,
+                failed_runs: row.failed_runs,
+            })
+            .collect(),
+        })
+    }
+}
@@ -175,6 +175,167 @@ impl ChatSession {
         .await
     }
 
+    /// Agent that must approve agent-to-agent forwards in this session, if any.
+    pub async fn find_moderator_agent_id(
+        pool: &SqlitePool,
+        id: Uuid,
+    ) -> Result<Option<Uuid>, sqlx::Error> {
+        let moderator: Option<Option<Uuid>> =
+            sqlx::query_scalar("SELECT moderator_agent_id FROM chat_sessions WHERE id = $1")
+                .bind(id)
+                .fetch_optional(pool)
+                .await?;
+        Ok(moderator.flatten())
+    }
+
+    pub async fn set_moderator_agent_id(
+        pool: &SqlitePool,
+        id: Uuid,
+        moderator_agent_id: Option<Uuid>,
+    ) -> Result<u64, sqlx::Error> {
+        let result = sqlx::query(
+            "UPDATE chat_sessions
+             SET moderator_agent_id = $2,
+                 updated_at = datetime('now', 'subsec')
+             WHERE id = $1",
+        )
+        .bind(id)
+        .bind(moderator_agent_id)
+        .execute(pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    /// Agent that answers user messages mentioning nobody in this session, if any.
+    pub async fn find_default_agent_id(
+        pool: &SqlitePool,
+        id: Uuid,
+    ) -> Result<Option<Uuid>, sqlx::Error> {
+        let default_agent: Option<Option<Uuid>> =
+            sqlx::query_scalar("SELECT default_agent_id FROM chat_sessions WHERE id = $1")
+                .bind(id)
+                .fetch_optional(pool)
+                .await?;
+        Ok(default_agent.flatten())
+    }
+
+    pub async fn set_default_agent_id(
+        pool: &SqlitePool,
+        id: Uuid,
+        default_agent_id: Option<Uuid>,
+    ) -> Result<u64, sqlx::Error> {
+        let result = sqlx::query(
+            "UPDATE chat_sessions
+             SET default_agent_id = $2,
+                 updated_at = datetime('now', 'subsec')
+             WHERE id = $1",
+        )
+        .bind(id)
+        .bind(default_agent_id)
+        .execute(pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    /// Whether agent replies are translated, and the explicit target language.
+    /// A missing language means the UI language from config is used.
+    pub async fn find_translation_settings(
+        pool: &SqlitePool,
+        id: Uuid,
+    ) -> Result<(bool, Option<String>), sqlx::Error> {
+        let settings: Option<(bool, Option<String>)> = sqlx::query_as(
+            "SELECT translation_enabled, translation_language FROM chat_sessions WHERE id = $1",
+        )
+        .bind(id)
+        .fetch_optional(pool)
+        .await?;
+        Ok(settings.unwrap_or((false, None)))
+    }
+
+    pub async fn set_translation_settings(
+        pool: &SqlitePool,
+        id: Uuid,
+        enabled: bool,
+        language: Option<String>,
+    ) -> Result<u64, sqlx::Error> {
+        let result = sqlx::query(
+            "UPDATE chat_sessions
+             SET translation_enabled = $2,
+                 translation_language = $3,
+                 updated_at = datetime('now', 'subsec')
+             WHERE id = $1",
+        )
+        .bind(id)
+        .bind(enabled)
+        .bind(language)
+        .execute(pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    /// Session override of the agent history file format; `None` uses the configured default.
+    pub async fn find_context_format(
+        pool: &SqlitePool,
+        id: Uuid,
+    ) -> Result<Option<String>, sqlx::Error> {
+        let format: Option<Option<String>> =
+            sqlx::query_scalar("SELECT context_format FROM chat_sessions WHERE id = $1")
+                .bind(id)
+                .fetch_optional(pool)
+                .await?;
+        Ok(format.flatten())
+    }
+
+    pub async fn set_context_format(
+        pool: &SqlitePool,
+        id: Uuid,
+        format: Option<&str>,
+    ) -> Result<u64, sqlx::Error> {
+        let result = sqlx::query(
+            "UPDATE chat_sessions
+             SET context_format = $2,
+                 updated_at = datetime('now', 'subsec')
+             WHERE id = $1",
+        )
+        .bind(id)
+        .bind(format)
+        .execute(pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    /// Session default executor profile, stored as JSON, for agents without a runner type.
+    pub async fn find_executor_profile(
+        pool: &SqlitePool,
+        id: Uuid,
+    ) -> Result<Option<String>, sqlx::Error> {
+        let profile = sqlx::query_scalar!(
+            r#"SELECT executor_profile FROM chat_sessions WHERE id = $1"#,
+            id
+        )
+        .fetch_optional(pool)
+        .await?;
+        Ok(profile.flatten())
+    }
+
+    pub async fn set_executor_profile(
+        pool: &SqlitePool,
+        id: Uuid,
+        profile: Option<&str>,
+    ) -> Result<u64, sqlx::Error> {
+        let result = sqlx::query!(
+            r#"UPDATE chat_sessions
+               SET executor_profile = $2,
+                   updated_at = datetime('now', 'subsec')
+               WHERE id = $1"#,
+            id,
+            profile
+        )
+        .execute(pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
     pub async fn touch(pool: &SqlitePool, id: Uuid) -> Result<(), sqlx::Error> {
         sqlx::query!(
             "UPDATE chat_sessions SET updated_at = datetime('now', 'subsec') WHERE id = $1",
@@ -11,6 +11,8 @@ use uuid::Uuid;
 pub enum ChatSessionAgentState {
     Idle,
     Running,
+    // The schema spells it with an underscore, which `lowercase` alone would drop.
+    #[sqlx(rename = "waiting_approval")]
     WaitingApproval,
     Dead,
 }
@@ -25,10 +27,26 @@ pub struct ChatSessionAgent {
     pub pty_session_key: Option<String>,
     pub agent_session_id: Option<String>,
     pub agent_message_id: Option<String>,
+    /// Mentions of a muted member are queued until it is unmuted
+    pub muted: bool,
+    /// Position in the session's member list, lowest first
+    pub sort_order: i64,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
 }
 
+/// Run aggregates for one session member, kept across restarts.
+#[derive(Debug, Clone, Serialize, TS)]
+pub struct ChatSessionAgentActivity {
+    pub session_agent_id: Uuid,
+    /// When the member's most recent run finished
+    pub last_active_at: Option<DateTime<Utc>>,
+    pub total_runs: i64,
+    /// Sum of reported (or estimated) tokens across runs
+    pub total_tokens: i64,
+    pub failed_runs: i64,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct CreateChatSessionAgent {
     pub session_id: Uuid,
@@ -48,6 +66,8 @@ impl ChatSessionAgent {
                       pty_session_key,
                       agent_session_id,
                       agent_message_id,
+                      muted as "muted!: bool",
+                      sort_order as "sort_order!: i64",
                       created_at as "created_at!: DateTime<Utc>",
                       updated_at as "updated_at!: DateTime<Utc>"
                FROM chat_session_agents
@@ -73,6 +93,8 @@ impl ChatSessionAgent {
                       pty_session_key,
                       agent_session_id,
                       agent_message_id,
+                      muted as "muted!: bool",
+                      sort_order as "sort_order!: i64",
                       created_at as "created_at!: DateTime<Utc>",
                       updated_at as "updated_at!: DateTime<Utc>"
                FROM chat_session_agents
@@ -98,17 +120,44 @@ impl ChatSessionAgent {
                       pty_session_key,
                       agent_session_id,
                       agent_message_id,
+                      muted as "muted!: bool",
+                      sort_order as "sort_order!: i64",
                       created_at as "created_at!: DateTime<Utc>",
                       updated_at as "updated_at!: DateTime<Utc>"
                FROM chat_session_agents
                WHERE session_id = $1
-               ORDER BY created_at ASC"#,
+               ORDER BY sort_order ASC, created_at ASC"#,
             session_id
         )
         .fetch_all(pool)
         .await
     }
 
+    /// Members of any session currently marked `Running` or `WaitingApproval`, oldest state
+    /// change first.
+    pub async fn find_all_running(pool: &SqlitePool) -> Result<Vec<Self>, sqlx::Error> {
+        sqlx::query_as!(
+            ChatSessionAgent,
+            r#"SELECT id as "id!: Uuid",
+                      session_id as "session_id!: Uuid",
+                      agent_id as "agent_id!: Uuid",
+                      state as "state!: ChatSessionAgentState",
+                      workspace_path,
+                      pty_session_key,
+                      agent_session_id,
+                      agent_message_id,
+                      muted as "muted!: bool",
+                      sort_order as "sort_order!: i64",
+                      created_at as "created_at!: DateTime<Utc>",
+                      updated_at as "updated_at!: DateTime<Utc>"
+               FROM chat_session_agents
+               WHERE state IN ('running', 'waiting_approval')
+               ORDER BY updated_at ASC"#
+        )
+        .fetch_all(pool)
+        .await
+    }
+
     pub async fn create(
         pool: &SqlitePool,
         data: &CreateChatSessionAgent,
@@ -116,8 +165,12 @@ impl ChatSessionAgent {
     ) -> Result<Self, sqlx::Error> {
         sqlx::query_as!(
             ChatSessionAgent,
-            r#"INSERT INTO chat_session_agents (id, session_id, agent_id, workspace_path, state)
-               VALUES ($1, $2, $3, $4, 'idle')
+            r#"INSERT INTO chat_session_agents
+                   (id, session_id, agent_id, workspace_path, state, sort_order)
+               VALUES ($1, $2, $3, $4, 'idle',
+                       (SELECT COALESCE(MAX(sort_order) + 1, 0)
+                        FROM chat_session_agents
+                        WHERE session_id = $2))
                RETURNING id as "id!: Uuid",
                          session_id as "session_id!: Uuid",
                          agent_id as "agent_id!: Uuid",
@@ -126,6 +179,8 @@ impl ChatSessionAgent {
                          pty_session_key,
                          agent_session_id,
                          agent_message_id,
+                         muted as "muted!: bool",
+                         sort_order as "sort_order!: i64",
                          created_at as "created_at!: DateTime<Utc>",
                          updated_at as "updated_at!: DateTime<Utc>""#,
             id,
@@ -156,6 +211,8 @@ impl ChatSessionAgent {
                          pty_session_key,
                          agent_session_id,
                          agent_message_id,
+                         muted as "muted!: bool",
+                         sort_order as "sort_order!: i64",
                          created_at as "created_at!: DateTime<Utc>",
                          updated_at as "updated_at!: DateTime<Utc>""#,
             id,
@@ -165,6 +222,32 @@ impl ChatSessionAgent {
         .await
     }
 
+    /// Leaves `updated_at` alone, since muting does not change the member's state.
+    pub async fn set_muted(pool: &SqlitePool, id: Uuid, muted: bool) -> Result<Self, sqlx::Error> {
+        sqlx::query_as!(
+            ChatSessionAgent,
+            r#"UPDATE chat_session_agents
+               SET muted = $2
+               WHERE id = $1
+               RETURNING id as "id!: Uuid",
+                         session_id as "session_id!: Uuid",
+                         agent_id as "agent_id!: Uuid",
+                         state as "state!: ChatSessionAgentState",
+                         workspace_path,
+                         pty_session_key,
+                         agent_session_id,
+                         agent_message_id,
+                         muted as "muted!: bool",
+                         sort_order as "sort_order!: i64",
+                         created_at as "created_at!: DateTime<Utc>",
+                         updated_at as "updated_at!: DateTime<Utc>""#,
+            id,
+            muted
+        )
+        .fetch_one(pool)
+        .await
+    }
+
     pub async fn update_workspace_path(
         pool: &SqlitePool,
         id: Uuid,
@@ -184,6 +267,8 @@ impl ChatSessionAgent {
                          pty_session_key,
                          agent_session_id,
                          agent_message_id,
+                         muted as "muted!: bool",
+                         sort_order as "sort_order!: i64",
                          created_at as "created_at!: DateTime<Utc>",
                          updated_at as "updated_at!: DateTime<Utc>""#,
             id,
@@ -212,6 +297,8 @@ impl ChatSessionAgent {
                          pty_session_key,
                          agent_session_id,
                          agent_message_id,
+                         muted as "muted!: bool",
+                         sort_order as "sort_order!: i64",
                          created_at as "created_at!: DateTime<Utc>",
                          updated_at as "updated_at!: DateTime<Utc>""#,
             id,
@@ -240,6 +327,8 @@ impl ChatSessionAgent {
                          pty_session_key,
                          agent_session_id,
                          agent_message_id,
+                         muted as "muted!: bool",
+                         sort_order as "sort_order!: i64",
                          created_at as "created_at!: DateTime<Utc>",
                          updated_at as "updated_at!: DateTime<Utc>""#,
             id,
@@ -276,4 +365,71 @@ impl ChatSessionAgent {
         .await?;
         Ok(result.rows_affected())
     }
+
+    pub async fn find_activity(
+        pool: &SqlitePool,
+        id: Uuid,
+    ) -> Result<Option<ChatSessionAgentActivity>, sqlx::Error> {
+        sqlx::query_as!(
+            ChatSessionAgentActivity,
+            r#"SELECT id as "session_agent_id!: Uuid",
+                      last_active_at as "last_active_at: DateTime<Utc>",
+                      total_runs,
+                      total_tokens,
+                      failed_runs
+               FROM chat_session_agents
+               WHERE id = $1"#,
+            id
+        )
+        .fetch_optional(pool)
+        .await
+    }
+
+    /// Count one finished run toward the member's activity aggregates.
+    pub async fn record_run_activity(
+        executor: impl sqlx::Executor<'_, Database = sqlx::Sqlite>,
+        id: Uuid,
+        tokens: u32,
+        failed: bool,
+    ) -> Result<u64, sqlx::Error> {
+        let tokens = i64::from(tokens);
+        let failed = i64::from(failed);
+        let result = sqlx::query!(
+            r#"UPDATE chat_session_agents
+               SET last_active_at = datetime('now', 'subsec'),
+                   total_runs = total_runs + 1,
+                   total_tokens = total_tokens + $2,
+                   failed_runs = failed_runs + $3
+               WHERE id = $1"#,
+            id,
+            tokens,
+            failed
+        )
+        .execute(executor)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    /// Store the member order of a session; `ordered_ids` lists its session agents first to
+    /// last. Ids of other sessions are ignored.
+    pub async fn reorder(
+        pool: &SqlitePool,
+        session_id: Uuid,
+        ordered_ids: &[Uuid],
+    ) -> Result<(), sqlx::Error> {
+        let mut tx = pool.begin().await?;
+        for (position, id) in ordered_ids.iter().enumerate() {
+            sqlx::query(
+                "UPDATE chat_session_agents
+                 SET sort_order = $3
+                 WHERE id = $1 AND session_id = $2",
+            )
+            .bind(id)
+            .bind(session_id)
+            .bind(position as i64)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await
+    }
 }
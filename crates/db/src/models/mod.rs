@@ -1,8 +1,10 @@
 pub mod chat_agent;
+pub mod chat_agent_prompt_version;
 pub mod chat_artifact;
 pub mod chat_message;
 pub mod chat_permission;
 pub mod chat_run;
+pub mod chat_run_stats;
 pub mod chat_session;
 pub mod chat_session_agent;
 pub mod coding_agent_turn;
@@ -34,8 +34,9 @@ use crate::{
     command::{CmdOverrides, CommandBuildError, CommandBuilder, CommandParts, apply_overrides},
     env::ExecutionEnv,
     executors::{
-        AppendPrompt, AvailabilityInfo, ExecutorError, SpawnedChild, StandardCodingAgentExecutor,
-        codex::client::LogWriter, utils::reorder_slash_commands,
+        AppendPrompt, AvailabilityInfo, ExecutorError, ExecutorExitResult, PersistentChild,
+        SpawnedChild, StandardCodingAgentExecutor, codex::client::LogWriter,
+        utils::reorder_slash_commands,
     },
     logs::{
         ActionType, FileChange, NormalizedEntry, NormalizedEntryError, NormalizedEntryType,
@@ -224,6 +225,22 @@ impl StandardCodingAgentExecutor for ClaudeCode {
             .await
     }
 
+    async fn spawn_persistent(
+        &self,
+        current_dir: &Path,
+        session_id: Option<&str>,
+        env: &ExecutionEnv,
+    ) -> Result<PersistentChild, ExecutorError> {
+        let command_builder = self.build_command_builder().await?;
+        let command_parts = match session_id {
+            Some(session_id) => command_builder
+                .build_follow_up(&["--resume".to_string(), session_id.to_string()])?,
+            None => command_builder.build_initial()?,
+        };
+        self.spawn_persistent_internal(current_dir, command_parts, env)
+            .await
+    }
+
     fn normalize_logs(&self, msg_store: Arc<MsgStore>, current_dir: &Path) {
         let entry_index_provider = EntryIndexProvider::start_from(&msg_store);
 
@@ -291,15 +308,13 @@ impl StandardCodingAgentExecutor for ClaudeCode {
 }
 
 impl ClaudeCode {
-    async fn spawn_internal(
+    async fn build_process_command(
         &self,
         current_dir: &Path,
-        prompt: &str,
         command_parts: CommandParts,
         env: &ExecutionEnv,
-    ) -> Result<SpawnedChild, ExecutorError> {
+    ) -> Result<Command, ExecutorError> {
         let (program_path, args) = command_parts.into_resolved().await?;
-        let combined_prompt = self.append_prompt.combine_prompt(prompt);
 
         let mut command = Command::new(program_path);
         command
@@ -321,6 +336,21 @@ impl ClaudeCode {
             tracing::info!("ANTHROPIC_API_KEY removed from environment");
         }
 
+        Ok(command)
+    }
+
+    async fn spawn_internal(
+        &self,
+        current_dir: &Path,
+        prompt: &str,
+        command_parts: CommandParts,
+        env: &ExecutionEnv,
+    ) -> Result<SpawnedChild, ExecutorError> {
+        let combined_prompt = self.append_prompt.combine_prompt(prompt);
+        let mut command = self
+            .build_process_command(current_dir, command_parts, env)
+            .await?;
+
         let mut child = command.group_spawn()?;
         let child_stdout = child.inner().stdout.take().ok_or_else(|| {
             ExecutorError::Io(std::io::Error::other("Claude Code missing stdout"))
@@ -397,6 +427,97 @@ impl ClaudeCode {
             cancel: Some(cancel),
         })
     }
+
+    /// Starts Claude without a prompt and feeds it each prompt received on the returned
+    /// channel; the process exits once the channel closes or `cancel` fires.
+    async fn spawn_persistent_internal(
+        &self,
+        current_dir: &Path,
+        command_parts: CommandParts,
+        env: &ExecutionEnv,
+    ) -> Result<PersistentChild, ExecutorError> {
+        let mut command = self
+            .build_process_command(current_dir, command_parts, env)
+            .await?;
+
+        let mut child = command.group_spawn()?;
+        let child_stdout = child.inner().stdout.take().ok_or_else(|| {
+            ExecutorError::Io(std::io::Error::other("Claude Code missing stdout"))
+        })?;
+        let child_stdin =
+            child.inner().stdin.take().ok_or_else(|| {
+                ExecutorError::Io(std::io::Error::other("Claude Code missing stdin"))
+            })?;
+
+        let new_stdout = create_stdout_pipe_writer(&mut child)?;
+        let (prompt_tx, mut prompt_rx) = tokio::sync::mpsc::unbounded_channel::<String>();
+        let (turn_tx, turn_rx) = tokio::sync::mpsc::unbounded_channel();
+        let permission_mode = self.permission_mode();
+        let hooks = self.get_hooks(env.commit_reminder);
+        let cancel = CancellationToken::new();
+
+        let append_prompt = self.append_prompt.clone();
+        let approvals_clone = self.approvals_service.clone();
+        let repo_context = env.repo_context.clone();
+        let commit_reminder_prompt = env.commit_reminder_prompt.clone();
+        let cancel_for_task = cancel.clone();
+        tokio::spawn(async move {
+            let log_writer = LogWriter::new(new_stdout);
+            let client = ClaudeAgentClient::new(
+                log_writer.clone(),
+                approvals_clone,
+                repo_context,
+                commit_reminder_prompt,
+                cancel_for_task.clone(),
+            );
+            let protocol_peer = ProtocolPeer::spawn_persistent(
+                child_stdin,
+                child_stdout,
+                client.clone(),
+                cancel_for_task.clone(),
+                turn_tx.clone(),
+            );
+
+            if let Err(e) = protocol_peer.initialize(hooks).await {
+                tracing::error!("Failed to initialize control protocol: {e}");
+                let _ = log_writer
+                    .log_raw(&format!("Error: Failed to initialize - {e}"))
+                    .await;
+                let _ = turn_tx.send(ExecutorExitResult::Failure);
+                return;
+            }
+
+            if let Err(e) = protocol_peer.set_permission_mode(permission_mode).await {
+                tracing::warn!("Failed to set permission mode to {permission_mode}: {e}");
+            }
+
+            loop {
+                let prompt = tokio::select! {
+                    _ = cancel_for_task.cancelled() => break,
+                    prompt = prompt_rx.recv() => match prompt {
+                        Some(prompt) => prompt,
+                        None => break,
+                    },
+                };
+                let combined_prompt = append_prompt.combine_prompt(&prompt);
+                if let Err(e) = protocol_peer.send_user_message(combined_prompt).await {
+                    tracing::error!("Failed to send prompt: {e}");
+                    let _ = log_writer
+                        .log_raw(&format!("Error: Failed to send prompt - {e}"))
+                        .await;
+                    let _ = turn_tx.send(ExecutorExitResult::Failure);
+                    break;
+                }
+            }
+        });
+
+        Ok(PersistentChild {
+            child,
+            prompts: prompt_tx,
+            turns: turn_rx,
+            cancel,
+        })
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
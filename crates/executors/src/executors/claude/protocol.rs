@@ -3,7 +3,7 @@ use std::sync::Arc;
 use tokio::{
     io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
     process::{ChildStdin, ChildStdout},
-    sync::{Mutex, oneshot},
+    sync::{Mutex, mpsc, oneshot},
 };
 use tokio_util::sync::CancellationToken;
 
@@ -64,7 +64,7 @@ impl ProtocolPeer {
 
         let reader_peer = peer.clone();
         tokio::spawn(async move {
-            match reader_peer.read_loop(stdout, client, cancel).await {
+            match reader_peer.read_loop(stdout, client, cancel, None).await {
                 Ok(ReadLoopExit::ResultReceived) => {
                     exit_signal
                         .send_exit_signal(ExecutorExitResult::Success)
@@ -88,11 +88,41 @@ impl ProtocolPeer {
         peer
     }
 
+    /// Like [`Self::spawn`], but keeps reading after each result so the process can take
+    /// further prompts. Every result is reported on `turns`; a stream that ends reports a
+    /// final failure for the turn in progress.
+    pub fn spawn_persistent(
+        stdin: ChildStdin,
+        stdout: ChildStdout,
+        client: Arc<ClaudeAgentClient>,
+        cancel: CancellationToken,
+        turns: mpsc::UnboundedSender<ExecutorExitResult>,
+    ) -> Self {
+        let peer = Self {
+            stdin: Arc::new(Mutex::new(stdin)),
+        };
+
+        let reader_peer = peer.clone();
+        tokio::spawn(async move {
+            match reader_peer
+                .read_loop(stdout, client, cancel, Some(&turns))
+                .await
+            {
+                Ok(_) => tracing::info!("Persistent Claude process closed its output"),
+                Err(e) => tracing::error!("Persistent protocol reader loop error: {}", e),
+            }
+            let _ = turns.send(ExecutorExitResult::Failure);
+        });
+
+        peer
+    }
+
     async fn read_loop(
         &self,
         stdout: ChildStdout,
         client: Arc<ClaudeAgentClient>,
         cancel: CancellationToken,
+        turns: Option<&mpsc::UnboundedSender<ExecutorExitResult>>,
     ) -> Result<ReadLoopExit, ExecutorError> {
         let mut reader = BufReader::new(stdout);
         let mut buffer = String::new();
@@ -129,9 +159,12 @@ impl ProtocolPeer {
                                     self.handle_control_request(&client, request_id, request)
                                         .await;
                                 }
-                                Ok(CLIMessage::Result(_)) => {
-                                    return Ok(ReadLoopExit::ResultReceived);
-                                }
+                                Ok(CLIMessage::Result(_)) => match turns {
+                                    Some(turns) => {
+                                        let _ = turns.send(ExecutorExitResult::Success);
+                                    }
+                                    None => return Ok(ReadLoopExit::ResultReceived),
+                                },
                                 _ => {}
                             }
                         }
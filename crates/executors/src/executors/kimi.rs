@@ -1,8 +1,14 @@
-use std::{collections::HashMap, path::Path, process::Stdio, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+    process::Stdio,
+    sync::Arc,
+};
 
 use async_trait::async_trait;
 use chrono::Utc;
 use command_group::AsyncCommandGroup;
+use derivative::Derivative;
 use futures::StreamExt;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
@@ -15,17 +21,19 @@ use crate::{
     command::{CmdOverrides, CommandBuildError, CommandBuilder, CommandParts, apply_overrides},
     env::ExecutionEnv,
     executors::{
-        AppendPrompt, AvailabilityInfo, ExecutorError, SpawnedChild, StandardCodingAgentExecutor,
+        AppendPrompt, AvailabilityInfo, ExecutorError, ExecutorOutputFormat, SpawnedChild,
+        StandardCodingAgentExecutor, utils::probe_help_output,
     },
     logs::{
-        ActionType, NormalizedEntry, NormalizedEntryType, ToolResult, ToolStatus,
+        ActionType, Citation, NormalizedEntry, NormalizedEntryType, ToolResult, ToolStatus,
         stderr_processor::normalize_stderr_logs,
         utils::{EntryIndexProvider, patch::ConversationPatch},
     },
     stdout_dup,
 };
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, TS, JsonSchema)]
+#[derive(Derivative, Clone, Serialize, Deserialize, TS, JsonSchema)]
+#[derivative(Debug, PartialEq)]
 pub struct KimiCode {
     #[serde(default)]
     pub append_prompt: AppendPrompt,
@@ -35,6 +43,11 @@ pub struct KimiCode {
     pub yolo: Option<bool>,
     #[serde(flatten)]
     pub cmd: CmdOverrides,
+    /// Output format negotiated by the last spawn, so the logs are parsed as requested.
+    #[serde(skip)]
+    #[ts(skip)]
+    #[derivative(Debug = "ignore", PartialEq = "ignore")]
+    spawned_output_format: Arc<std::sync::Mutex<Option<ExecutorOutputFormat>>>,
 }
 
 #[derive(Clone)]
@@ -52,12 +65,14 @@ impl KimiCode {
         "kimi"
     }
 
-    fn build_command_builder(&self) -> Result<CommandBuilder, CommandBuildError> {
-        let mut builder = CommandBuilder::new(Self::base_command()).params([
-            "--print",
-            "--output-format",
-            "stream-json",
-        ]);
+    fn build_command_builder(
+        &self,
+        output_format: ExecutorOutputFormat,
+    ) -> Result<CommandBuilder, CommandBuildError> {
+        let mut builder = CommandBuilder::new(Self::base_command()).params(["--print"]);
+        if output_format == ExecutorOutputFormat::StreamJson {
+            builder = builder.extend_params(["--output-format", "stream-json"]);
+        }
 
         if let Some(model) = &self.model {
             builder = builder.extend_params(["--model", model.as_str()]);
@@ -70,6 +85,43 @@ impl KimiCode {
         apply_overrides(builder, &self.cmd)
     }
 
+    /// Older Kimi CLI releases lack `--output-format stream-json`; their `--help` decides
+    /// whether the run streams JSON events or plain text.
+    fn negotiated_output_format(&self) -> ExecutorOutputFormat {
+        let overrides = CmdOverrides {
+            base_command_override: self.cmd.base_command_override.clone(),
+            ..Default::default()
+        };
+        let help = apply_overrides(
+            CommandBuilder::new(Self::base_command()).params(["--help"]),
+            &overrides,
+        )
+        .and_then(|builder| builder.build_initial())
+        .ok()
+        .and_then(|command| probe_help_output(&command));
+        ExecutorOutputFormat::select(
+            help.as_deref().map(String::as_str),
+            &self.supported_output_formats(),
+        )
+    }
+
+    async fn resolve_output_format(&self) -> ExecutorOutputFormat {
+        let executor = self.clone();
+        tokio::task::spawn_blocking(move || executor.negotiated_output_format())
+            .await
+            .unwrap_or(ExecutorOutputFormat::StreamJson)
+    }
+
+    /// Resolve the output format for a spawn and remember it for `normalize_logs`.
+    async fn spawn_output_format(&self) -> ExecutorOutputFormat {
+        let output_format = self.resolve_output_format().await;
+        *self
+            .spawned_output_format
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(output_format);
+        output_format
+    }
+
     fn extract_assistant_text(message: &Value) -> String {
         let Some(content) = message.get("content") else {
             return String::new();
@@ -79,6 +131,7 @@ impl KimiCode {
             Value::String(text) => text.clone(),
             Value::Array(parts) => parts
                 .iter()
+                .filter(|part| !Self::is_citation_part(part))
                 .filter_map(|part| {
                     if let Some(text) = part.as_str() {
                         return Some(text.to_string());
@@ -98,6 +151,58 @@ impl KimiCode {
         }
     }
 
+    /// Collects source references from message-level `citations`/`references`
+    /// arrays and from citation-typed content parts.
+    fn extract_citations(message: &Value) -> Vec<Citation> {
+        let mut citations = Vec::new();
+        for key in ["citations", "references"] {
+            if let Some(items) = message.get(key).and_then(|v| v.as_array()) {
+                citations.extend(items.iter().filter_map(Self::parse_citation));
+            }
+        }
+        if let Some(parts) = message.get("content").and_then(|v| v.as_array()) {
+            citations.extend(
+                parts
+                    .iter()
+                    .filter(|part| Self::is_citation_part(part))
+                    .filter_map(Self::parse_citation),
+            );
+        }
+        citations
+    }
+
+    fn is_citation_part(part: &Value) -> bool {
+        matches!(
+            part.get("type").and_then(|v| v.as_str()),
+            Some("citation" | "reference" | "url_citation")
+        )
+    }
+
+    fn parse_citation(value: &Value) -> Option<Citation> {
+        if let Some(url) = value.as_str() {
+            let url = url.trim();
+            return (!url.is_empty()).then(|| Citation {
+                title: None,
+                url: Some(url.to_string()),
+                snippet: None,
+            });
+        }
+
+        let field = |keys: &[&str]| {
+            keys.iter()
+                .find_map(|key| value.get(*key).and_then(|v| v.as_str()))
+                .map(str::trim)
+                .filter(|text| !text.is_empty())
+                .map(str::to_string)
+        };
+        let citation = Citation {
+            title: field(&["title", "name"]),
+            url: field(&["url", "link", "href"]),
+            snippet: field(&["snippet", "text", "summary"]),
+        };
+        (citation.title.is_some() || citation.url.is_some()).then_some(citation)
+    }
+
     fn extract_event_type_and_message(payload: &Value) -> (&str, &Value) {
         let event_type = payload
             .get("type")
@@ -253,7 +358,8 @@ impl StandardCodingAgentExecutor for KimiCode {
         prompt: &str,
         env: &ExecutionEnv,
     ) -> Result<SpawnedChild, ExecutorError> {
-        let command = self.build_command_builder()?.build_initial()?;
+        let output_format = self.spawn_output_format().await;
+        let command = self.build_command_builder(output_format)?.build_initial()?;
         let combined_prompt = self.append_prompt.combine_prompt(prompt);
         spawn_kimi(command, &combined_prompt, current_dir, env, &self.cmd).await
     }
@@ -272,8 +378,9 @@ impl StandardCodingAgentExecutor for KimiCode {
             vec!["--session".to_string(), session_id.to_string()]
         };
 
+        let output_format = self.spawn_output_format().await;
         let command = self
-            .build_command_builder()?
+            .build_command_builder(output_format)?
             .build_follow_up(&additional_args)?;
         let combined_prompt = self.append_prompt.combine_prompt(prompt);
         spawn_kimi(command, &combined_prompt, current_dir, env, &self.cmd).await
@@ -283,168 +390,22 @@ impl StandardCodingAgentExecutor for KimiCode {
         let entry_index_provider = EntryIndexProvider::start_from(&msg_store);
         normalize_stderr_logs(msg_store.clone(), entry_index_provider.clone());
 
+        let spawned_output_format = *self
+            .spawned_output_format
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let executor = self.clone();
         tokio::spawn(async move {
-            let mut stdout_lines = msg_store.stdout_lines_stream();
-            let mut model_reported = false;
-            let mut current_assistant_index: Option<usize> = None;
-            let mut current_assistant_text = String::new();
-            let mut tool_entries: HashMap<String, ToolEntryState> = HashMap::new();
-
-            while let Some(Ok(line)) = stdout_lines.next().await {
-                if let Some(session_id) = line.strip_prefix(KimiCode::SESSION_PREFIX) {
-                    msg_store.push_session_id(session_id.trim().to_string());
-                    continue;
-                }
-
-                let trimmed = line.trim();
-                if trimmed.is_empty() {
-                    continue;
+            let output_format = match spawned_output_format {
+                Some(output_format) => output_format,
+                None => executor.resolve_output_format().await,
+            };
+            match output_format {
+                ExecutorOutputFormat::StreamJson => {
+                    normalize_stream_json_logs(msg_store, entry_index_provider).await
                 }
-
-                let payload: Value = match serde_json::from_str(trimmed) {
-                    Ok(value) => value,
-                    Err(_) => {
-                        let entry = NormalizedEntry {
-                            timestamp: None,
-                            entry_type: NormalizedEntryType::SystemMessage,
-                            content: strip_ansi_escapes::strip_str(trimmed),
-                            metadata: None,
-                        };
-                        let index = entry_index_provider.next();
-                        msg_store.push_patch(ConversationPatch::add_normalized_entry(index, entry));
-                        continue;
-                    }
-                };
-
-                let (event_type, message) = KimiCode::extract_event_type_and_message(&payload);
-
-                match event_type {
-                    "assistant" => {
-                        if !model_reported
-                            && let Some(model) = message.get("model").and_then(|v| v.as_str())
-                        {
-                            model_reported = true;
-                            let entry = NormalizedEntry {
-                                timestamp: None,
-                                entry_type: NormalizedEntryType::SystemMessage,
-                                content: format!("model: {model}"),
-                                metadata: None,
-                            };
-                            let index = entry_index_provider.next();
-                            msg_store
-                                .push_patch(ConversationPatch::add_normalized_entry(index, entry));
-                        }
-
-                        for (tool_call_id, tool_name, arguments) in
-                            KimiCode::extract_tool_calls(message)
-                        {
-                            if tool_entries.contains_key(&tool_call_id) {
-                                continue;
-                            }
-
-                            let action_type = ActionType::Tool {
-                                tool_name: tool_name.clone(),
-                                arguments: arguments.clone(),
-                                result: None,
-                            };
-                            let entry = NormalizedEntry {
-                                timestamp: None,
-                                entry_type: NormalizedEntryType::ToolUse {
-                                    tool_name: tool_name.clone(),
-                                    action_type,
-                                    status: ToolStatus::Created,
-                                },
-                                content: tool_name.clone(),
-                                metadata: None,
-                            };
-
-                            let index = entry_index_provider.next();
-                            msg_store
-                                .push_patch(ConversationPatch::add_normalized_entry(index, entry));
-                            tool_entries.insert(
-                                tool_call_id,
-                                ToolEntryState {
-                                    index,
-                                    tool_name,
-                                    arguments,
-                                },
-                            );
-                        }
-
-                        let text = KimiCode::extract_assistant_text(message);
-                        if text.is_empty() {
-                            continue;
-                        }
-
-                        let merged = KimiCode::merge_assistant_text(&current_assistant_text, &text);
-                        current_assistant_text = merged.clone();
-
-                        let entry = NormalizedEntry {
-                            timestamp: None,
-                            entry_type: NormalizedEntryType::AssistantMessage,
-                            content: merged,
-                            metadata: None,
-                        };
-
-                        if let Some(index) = current_assistant_index {
-                            msg_store.push_patch(ConversationPatch::replace(index, entry));
-                        } else {
-                            let index = entry_index_provider.next();
-                            current_assistant_index = Some(index);
-                            msg_store
-                                .push_patch(ConversationPatch::add_normalized_entry(index, entry));
-                        }
-                    }
-                    "tool" => {
-                        current_assistant_index = None;
-                        current_assistant_text.clear();
-
-                        let (tool_call_id, result_text) = KimiCode::extract_tool_result(message);
-                        let Some(tool_call_id) = tool_call_id else {
-                            continue;
-                        };
-                        let Some(state) = tool_entries.get(&tool_call_id).cloned() else {
-                            continue;
-                        };
-
-                        let action_type = ActionType::Tool {
-                            tool_name: state.tool_name.clone(),
-                            arguments: state.arguments.clone(),
-                            result: if result_text.trim().is_empty() {
-                                None
-                            } else {
-                                Some(ToolResult::markdown(result_text.clone()))
-                            },
-                        };
-
-                        let entry = NormalizedEntry {
-                            timestamp: None,
-                            entry_type: NormalizedEntryType::ToolUse {
-                                tool_name: state.tool_name,
-                                action_type,
-                                status: ToolStatus::Success,
-                            },
-                            content: if result_text.trim().is_empty() {
-                                "Tool completed".to_string()
-                            } else {
-                                result_text
-                            },
-                            metadata: None,
-                        };
-                        msg_store.push_patch(ConversationPatch::replace(state.index, entry));
-                    }
-                    _ => {
-                        current_assistant_index = None;
-                        current_assistant_text.clear();
-                        let entry = NormalizedEntry {
-                            timestamp: None,
-                            entry_type: NormalizedEntryType::SystemMessage,
-                            content: payload.to_string(),
-                            metadata: None,
-                        };
-                        let index = entry_index_provider.next();
-                        msg_store.push_patch(ConversationPatch::add_normalized_entry(index, entry));
-                    }
+                ExecutorOutputFormat::PlainText => {
+                    normalize_plain_text_logs(msg_store, entry_index_provider).await
                 }
             }
         });
@@ -454,6 +415,17 @@ impl StandardCodingAgentExecutor for KimiCode {
         dirs::home_dir().map(|home| home.join(".kimi").join("mcp.json"))
     }
 
+    fn supported_output_formats(&self) -> Vec<ExecutorOutputFormat> {
+        vec![
+            ExecutorOutputFormat::StreamJson,
+            ExecutorOutputFormat::PlainText,
+        ]
+    }
+
+    fn output_format(&self) -> Option<ExecutorOutputFormat> {
+        Some(self.negotiated_output_format())
+    }
+
     fn get_availability_info(&self) -> AvailabilityInfo {
         if resolve_executable_path_blocking(Self::base_command()).is_none() {
             return AvailabilityInfo::NotFound;
@@ -472,6 +444,225 @@ impl StandardCodingAgentExecutor for KimiCode {
     }
 }
 
+/// Without stream-json the whole stdout (minus the session sentinel) is the reply.
+async fn normalize_plain_text_logs(
+    msg_store: Arc<MsgStore>,
+    entry_index_provider: EntryIndexProvider,
+) {
+    let mut stdout_lines = msg_store.stdout_lines_stream();
+    let mut assistant_index: Option<usize> = None;
+    let mut assistant_text = String::new();
+
+    while let Some(Ok(line)) = stdout_lines.next().await {
+        if let Some(session_id) = line.strip_prefix(KimiCode::SESSION_PREFIX) {
+            msg_store.push_session_id(session_id.trim().to_string());
+            continue;
+        }
+
+        let line = strip_ansi_escapes::strip_str(line.trim_end());
+        if assistant_text.is_empty() && line.trim().is_empty() {
+            continue;
+        }
+        if !assistant_text.is_empty() {
+            assistant_text.push('\n');
+        }
+        assistant_text.push_str(&line);
+
+        let entry = NormalizedEntry {
+            timestamp: None,
+            entry_type: NormalizedEntryType::AssistantMessage,
+            content: assistant_text.trim_end().to_string(),
+            metadata: None,
+        };
+        if let Some(index) = assistant_index {
+            msg_store.push_patch(ConversationPatch::replace(index, entry));
+        } else {
+            let index = entry_index_provider.next();
+            assistant_index = Some(index);
+            msg_store.push_patch(ConversationPatch::add_normalized_entry(index, entry));
+        }
+    }
+}
+
+async fn normalize_stream_json_logs(
+    msg_store: Arc<MsgStore>,
+    entry_index_provider: EntryIndexProvider,
+) {
+    let mut stdout_lines = msg_store.stdout_lines_stream();
+    let mut model_reported = false;
+    let mut current_assistant_index: Option<usize> = None;
+    let mut current_assistant_text = String::new();
+    let mut tool_entries: HashMap<String, ToolEntryState> = HashMap::new();
+    let mut seen_citations: HashSet<String> = HashSet::new();
+
+    while let Some(Ok(line)) = stdout_lines.next().await {
+        if let Some(session_id) = line.strip_prefix(KimiCode::SESSION_PREFIX) {
+            msg_store.push_session_id(session_id.trim().to_string());
+            continue;
+        }
+
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            continue;
+        }
+
+        let payload: Value = match serde_json::from_str(trimmed) {
+            Ok(value) => value,
+            Err(_) => {
+                let entry = NormalizedEntry {
+                    timestamp: None,
+                    entry_type: NormalizedEntryType::SystemMessage,
+                    content: strip_ansi_escapes::strip_str(trimmed),
+                    metadata: None,
+                };
+                let index = entry_index_provider.next();
+                msg_store.push_patch(ConversationPatch::add_normalized_entry(index, entry));
+                continue;
+            }
+        };
+
+        let (event_type, message) = KimiCode::extract_event_type_and_message(&payload);
+
+        match event_type {
+            "assistant" => {
+                if !model_reported
+                    && let Some(model) = message.get("model").and_then(|v| v.as_str())
+                {
+                    model_reported = true;
+                    let entry = NormalizedEntry {
+                        timestamp: None,
+                        entry_type: NormalizedEntryType::SystemMessage,
+                        content: format!("model: {model}"),
+                        metadata: None,
+                    };
+                    let index = entry_index_provider.next();
+                    msg_store.push_patch(ConversationPatch::add_normalized_entry(index, entry));
+                }
+
+                for (tool_call_id, tool_name, arguments) in KimiCode::extract_tool_calls(message) {
+                    if tool_entries.contains_key(&tool_call_id) {
+                        continue;
+                    }
+
+                    let action_type = ActionType::Tool {
+                        tool_name: tool_name.clone(),
+                        arguments: arguments.clone(),
+                        result: None,
+                    };
+                    let entry = NormalizedEntry {
+                        timestamp: None,
+                        entry_type: NormalizedEntryType::ToolUse {
+                            tool_name: tool_name.clone(),
+                            action_type,
+                            status: ToolStatus::Created,
+                        },
+                        content: tool_name.clone(),
+                        metadata: None,
+                    };
+
+                    let index = entry_index_provider.next();
+                    msg_store.push_patch(ConversationPatch::add_normalized_entry(index, entry));
+                    tool_entries.insert(
+                        tool_call_id,
+                        ToolEntryState {
+                            index,
+                            tool_name,
+                            arguments,
+                        },
+                    );
+                }
+
+                for citation in KimiCode::extract_citations(message) {
+                    if !seen_citations.insert(citation.dedupe_key()) {
+                        continue;
+                    }
+                    let entry = NormalizedEntry {
+                        timestamp: None,
+                        entry_type: NormalizedEntryType::Citation(citation),
+                        content: String::new(),
+                        metadata: None,
+                    };
+                    let index = entry_index_provider.next();
+                    msg_store.push_patch(ConversationPatch::add_normalized_entry(index, entry));
+                }
+
+                let text = KimiCode::extract_assistant_text(message);
+                if text.is_empty() {
+                    continue;
+                }
+
+                let merged = KimiCode::merge_assistant_text(&current_assistant_text, &text);
+                current_assistant_text = merged.clone();
+
+                let entry = NormalizedEntry {
+                    timestamp: None,
+                    entry_type: NormalizedEntryType::AssistantMessage,
+                    content: merged,
+                    metadata: None,
+                };
+
+                if let Some(index) = current_assistant_index {
+                    msg_store.push_patch(ConversationPatch::replace(index, entry));
+                } else {
+                    let index = entry_index_provider.next();
+                    current_assistant_index = Some(index);
+                    msg_store.push_patch(ConversationPatch::add_normalized_entry(index, entry));
+                }
+            }
+            "tool" => {
+                current_assistant_index = None;
+                current_assistant_text.clear();
+
+                let (tool_call_id, result_text) = KimiCode::extract_tool_result(message);
+                let Some(tool_call_id) = tool_call_id else {
+                    continue;
+                };
+                let Some(state) = tool_entries.get(&tool_call_id).cloned() else {
+                    continue;
+                };
+
+                let action_type = ActionType::Tool {
+                    tool_name: state.tool_name.clone(),
+                    arguments: state.arguments.clone(),
+                    result: if result_text.trim().is_empty() {
+                        None
+                    } else {
+                        Some(ToolResult::markdown(result_text.clone()))
+                    },
+                };
+
+                let entry = NormalizedEntry {
+                    timestamp: None,
+                    entry_type: NormalizedEntryType::ToolUse {
+                        tool_name: state.tool_name,
+                        action_type,
+                        status: ToolStatus::Success,
+                    },
+                    content: if result_text.trim().is_empty() {
+                        "Tool completed".to_string()
+                    } else {
+                        result_text
+                    },
+                    metadata: None,
+                };
+                msg_store.push_patch(ConversationPatch::replace(state.index, entry));
+            }
+            _ => {
+                current_assistant_index = None;
+                current_assistant_text.clear();
+                let entry = NormalizedEntry {
+                    timestamp: None,
+                    entry_type: NormalizedEntryType::SystemMessage,
+                    content: payload.to_string(),
+                    metadata: None,
+                };
+                let index = entry_index_provider.next();
+                msg_store.push_patch(ConversationPatch::add_normalized_entry(index, entry));
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::sync::Arc;
@@ -481,7 +672,7 @@ mod tests {
 
     use super::KimiCode;
     use crate::{
-        executors::{AppendPrompt, StandardCodingAgentExecutor},
+        executors::{AppendPrompt, ExecutorOutputFormat, StandardCodingAgentExecutor},
         logs::{NormalizedEntryType, utils::patch::extract_normalized_entry_from_patch},
     };
 
@@ -532,6 +723,25 @@ mod tests {
         assert_eq!(text, "你好，我是 Kimi。");
     }
 
+    #[test]
+    fn extract_citations_reads_message_and_content_parts() {
+        let message = json!({
+            "role": "assistant",
+            "citations": ["https://example.com/a"],
+            "content": [
+                {"type": "text", "text": "See sources."},
+                {"type": "citation", "title": "Docs", "url": "https://example.com/b", "snippet": "intro"},
+                {"type": "citation", "snippet": "no source"}
+            ]
+        });
+
+        let citations = KimiCode::extract_citations(&message);
+        assert_eq!(citations.len(), 2);
+        assert_eq!(citations[0].url.as_deref(), Some("https://example.com/a"));
+        assert_eq!(citations[1].title.as_deref(), Some("Docs"));
+        assert_eq!(citations[1].snippet.as_deref(), Some("intro"));
+    }
+
     #[tokio::test]
     async fn normalize_logs_supports_role_stream_json_payload() {
         let executor = KimiCode {
@@ -539,6 +749,7 @@ mod tests {
             model: None,
             yolo: None,
             cmd: Default::default(),
+            spawned_output_format: Default::default(),
         };
         let msg_store = Arc::new(MsgStore::new());
         let current_dir = std::path::PathBuf::from("/tmp/test-worktree");
@@ -569,4 +780,41 @@ mod tests {
             "expected assistant message patch from role payload"
         );
     }
+
+    #[tokio::test]
+    async fn normalize_logs_uses_output_format_resolved_at_spawn() {
+        let executor = KimiCode {
+            append_prompt: AppendPrompt::default(),
+            model: None,
+            yolo: None,
+            cmd: Default::default(),
+            spawned_output_format: Arc::new(std::sync::Mutex::new(Some(
+                ExecutorOutputFormat::PlainText,
+            ))),
+        };
+        let msg_store = Arc::new(MsgStore::new());
+        let line = r#"{"role":"assistant","content":"plain"}"#;
+        msg_store.push_stdout(format!("{line}\n"));
+        msg_store.push_finished();
+
+        executor.normalize_logs(
+            msg_store.clone(),
+            std::path::Path::new("/tmp/test-worktree"),
+        );
+        tokio::time::sleep(tokio::time::Duration::from_millis(150)).await;
+
+        // Plain-text normalization keeps the line verbatim instead of parsing it as JSON.
+        let assistant = msg_store
+            .get_history()
+            .into_iter()
+            .find_map(|item| match item {
+                LogMsg::JsonPatch(patch) => extract_normalized_entry_from_patch(&patch)
+                    .map(|(_, entry)| entry)
+                    .filter(|entry| {
+                        matches!(entry.entry_type, NormalizedEntryType::AssistantMessage)
+                    }),
+                _ => None,
+            });
+        assert_eq!(assistant.map(|entry| entry.content).as_deref(), Some(line));
+    }
 }
@@ -60,6 +60,8 @@ pub enum BaseAgentCapability {
     SetupHelper,
     /// Agent reports context/token usage information
     ContextUsage,
+    /// Agent can keep one process alive and take further prompts over stdin
+    PersistentProcess,
 }
 
 #[derive(Debug, Error)]
@@ -86,6 +88,8 @@ pub enum ExecutorError {
     ExecutableNotFound { program: String },
     #[error("Setup helper not supported")]
     SetupHelperNotSupported,
+    #[error("Persistent process mode not supported")]
+    PersistentProcessNotSupported,
     #[error("Auth required: {0}")]
     AuthRequired(String),
 }
@@ -179,6 +183,7 @@ impl CodingAgent {
             Self::ClaudeCode(_) => vec![
                 BaseAgentCapability::SessionFork,
                 BaseAgentCapability::ContextUsage,
+                BaseAgentCapability::PersistentProcess,
             ],
             Self::Opencode(_) => vec![
                 BaseAgentCapability::SessionFork,
@@ -199,6 +204,31 @@ impl CodingAgent {
             Self::QaMock(_) => vec![], // QA mock doesn't need special capabilities
         }
     }
+
+    /// Map `effort` onto the executor's own reasoning setting. Only Codex
+    /// (`model_reasoning_effort`) and Droid (`--reasoning-effort`) honor it; returns whether
+    /// the executor did.
+    pub fn apply_thinking_effort(&mut self, effort: ThinkingEffort) -> bool {
+        match self {
+            Self::Codex(executor) => {
+                executor.model_reasoning_effort = Some(match effort {
+                    ThinkingEffort::Low => codex::ReasoningEffort::Low,
+                    ThinkingEffort::Medium => codex::ReasoningEffort::Medium,
+                    ThinkingEffort::High => codex::ReasoningEffort::High,
+                });
+                true
+            }
+            Self::Droid(executor) => {
+                executor.reasoning_effort = Some(match effort {
+                    ThinkingEffort::Low => droid::ReasoningEffortLevel::Low,
+                    ThinkingEffort::Medium => droid::ReasoningEffortLevel::Medium,
+                    ThinkingEffort::High => droid::ReasoningEffortLevel::High,
+                });
+                true
+            }
+            _ => false,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, TS)]
@@ -219,6 +249,50 @@ impl AvailabilityInfo {
     }
 }
 
+/// Stdout shape an executor CLI is asked to produce.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, TS)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+#[ts(use_ts_enum)]
+pub enum ExecutorOutputFormat {
+    /// One JSON event per line
+    StreamJson,
+    /// Unstructured text, normalized as the assistant reply
+    PlainText,
+}
+
+impl ExecutorOutputFormat {
+    /// Whether a CLI whose `--help` prints `help` can be asked for this format.
+    pub fn advertised_in(self, help: &str) -> bool {
+        match self {
+            Self::StreamJson => help.contains("stream-json"),
+            Self::PlainText => true,
+        }
+    }
+
+    /// First of `supported` (most preferred first) that `help` advertises, falling back to
+    /// plain text. Without help output the preferred format is assumed.
+    pub fn select(help: Option<&str>, supported: &[ExecutorOutputFormat]) -> Self {
+        match help {
+            Some(help) => supported
+                .iter()
+                .copied()
+                .find(|format| format.advertised_in(help))
+                .unwrap_or(Self::PlainText),
+            None => supported.first().copied().unwrap_or(Self::PlainText),
+        }
+    }
+}
+
+/// Executor-independent reasoning effort, see [`CodingAgent::apply_thinking_effort`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, EnumString, Display)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum ThinkingEffort {
+    Low,
+    Medium,
+    High,
+}
+
 #[async_trait]
 #[enum_dispatch(CodingAgent)]
 pub trait StandardCodingAgentExecutor {
@@ -266,6 +340,17 @@ pub trait StandardCodingAgentExecutor {
         }
     }
 
+    /// Start a process that stays alive between prompts, resuming `session_id` when given.
+    /// Implemented by executors with [`BaseAgentCapability::PersistentProcess`].
+    async fn spawn_persistent(
+        &self,
+        _current_dir: &Path,
+        _session_id: Option<&str>,
+        _env: &ExecutionEnv,
+    ) -> Result<PersistentChild, ExecutorError> {
+        Err(ExecutorError::PersistentProcessNotSupported)
+    }
+
     fn normalize_logs(&self, _raw_logs_event_store: Arc<MsgStore>, _worktree_path: &Path);
 
     // MCP configuration methods
@@ -275,6 +360,17 @@ pub trait StandardCodingAgentExecutor {
         Err(ExecutorError::SetupHelperNotSupported)
     }
 
+    /// Output formats this executor can normalize, most preferred first. Empty means the
+    /// executor always runs its CLI in one fixed format.
+    fn supported_output_formats(&self) -> Vec<ExecutorOutputFormat> {
+        Vec::new()
+    }
+
+    /// Format the installed CLI will be run with, for executors that negotiate one.
+    fn output_format(&self) -> Option<ExecutorOutputFormat> {
+        None
+    }
+
     fn get_availability_info(&self) -> AvailabilityInfo {
         let config_files_found = self
             .default_mcp_config_path()
@@ -316,6 +412,19 @@ pub struct SpawnedChild {
     pub cancel: Option<CancellationToken>,
 }
 
+/// A long-lived executor process that runs one prompt per turn.
+#[derive(Debug)]
+pub struct PersistentChild {
+    /// Output of every turn; a turn's output ends when its result arrives on `turns`
+    pub child: AsyncGroupChild,
+    /// Container → Executor: prompts to run, one turn each
+    pub prompts: tokio::sync::mpsc::UnboundedSender<String>,
+    /// Executor → Container: result of each finished turn, in prompt order
+    pub turns: tokio::sync::mpsc::UnboundedReceiver<ExecutorExitResult>,
+    /// Container → Executor: interrupts the current turn ahead of shutdown
+    pub cancel: CancellationToken,
+}
+
 impl From<AsyncGroupChild> for SpawnedChild {
     fn from(child: AsyncGroupChild) -> Self {
         Self {
@@ -408,4 +517,50 @@ mod tests {
         assert!(result.is_ok(), "CURSOR should deserialize via serde");
         assert_eq!(result.unwrap(), BaseCodingAgent::CursorAgent);
     }
+
+    #[test]
+    fn output_format_falls_back_to_plain_text_when_not_advertised() {
+        let supported = [
+            ExecutorOutputFormat::StreamJson,
+            ExecutorOutputFormat::PlainText,
+        ];
+        assert_eq!(
+            ExecutorOutputFormat::select(Some("--output-format [text|stream-json]"), &supported),
+            ExecutorOutputFormat::StreamJson
+        );
+        assert_eq!(
+            ExecutorOutputFormat::select(Some("--print  Run non-interactively"), &supported),
+            ExecutorOutputFormat::PlainText
+        );
+        assert_eq!(
+            ExecutorOutputFormat::select(None, &supported),
+            ExecutorOutputFormat::StreamJson
+        );
+    }
+
+    #[test]
+    fn thinking_effort_maps_to_supporting_executors_only() {
+        let mut codex = CodingAgent::Codex(serde_json::from_str("{}").unwrap());
+        assert!(codex.apply_thinking_effort(ThinkingEffort::High));
+        let CodingAgent::Codex(executor) = codex else {
+            unreachable!()
+        };
+        assert_eq!(
+            executor.model_reasoning_effort,
+            Some(codex::ReasoningEffort::High)
+        );
+
+        let mut droid = CodingAgent::Droid(serde_json::from_str("{}").unwrap());
+        assert!(droid.apply_thinking_effort(ThinkingEffort::Low));
+        let CodingAgent::Droid(executor) = droid else {
+            unreachable!()
+        };
+        assert_eq!(
+            executor.reasoning_effort,
+            Some(droid::ReasoningEffortLevel::Low)
+        );
+
+        let mut gemini = CodingAgent::Gemini(serde_json::from_str("{}").unwrap());
+        assert!(!gemini.apply_thinking_effort(ThinkingEffort::Medium));
+    }
 }
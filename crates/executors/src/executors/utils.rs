@@ -1,14 +1,17 @@
 use std::{
+    collections::HashMap,
     num::NonZeroUsize,
-    path::PathBuf,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
     sync::{Arc, Mutex, OnceLock},
     time::{Duration, Instant},
 };
 
 use lru::LruCache;
+use workspace_utils::shell::resolve_executable_path_blocking;
 
 use super::SlashCommandDescription;
-use crate::executors::BaseCodingAgent;
+use crate::{command::CommandParts, executors::BaseCodingAgent};
 
 /// Parsed slash command with name and arguments.
 
@@ -130,3 +133,68 @@ impl SlashCommandCache {
         );
     }
 }
+
+const HELP_PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+type HelpProbeKey = (PathBuf, Vec<String>);
+
+/// Output of `command` (typically `<cli> --help`), or `None` when the program is missing,
+/// fails, or does not finish within a few seconds. Results are cached for a few minutes so
+/// capability checks stay cheap while still noticing CLI upgrades.
+pub fn probe_help_output(command: &CommandParts) -> Option<Arc<String>> {
+    static CACHE: OnceLock<Mutex<HashMap<HelpProbeKey, (Instant, Option<Arc<String>>)>>> =
+        OnceLock::new();
+
+    let program = resolve_executable_path_blocking(command.program())?;
+    let key = (program, command.args().to_vec());
+    let cache = CACHE.get_or_init(|| Mutex::new(HashMap::new()));
+    if let Some((probed_at, output)) = cache
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(&key)
+        .cloned()
+        && probed_at.elapsed() <= TTL
+    {
+        return output;
+    }
+
+    let output = run_help_probe(&key.0, &key.1).map(Arc::new);
+    cache
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(key, (Instant::now(), output.clone()));
+    output
+}
+
+fn run_help_probe(program: &Path, args: &[String]) -> Option<String> {
+    let mut child = Command::new(program)
+        .args(args)
+        .env("NO_COLOR", "1")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .ok()?;
+
+    let deadline = Instant::now() + HELP_PROBE_TIMEOUT;
+    loop {
+        match child.try_wait() {
+            Ok(Some(_)) => break,
+            Ok(None) if Instant::now() < deadline => {
+                std::thread::sleep(Duration::from_millis(50));
+            }
+            _ => {
+                let _ = child.kill();
+                let _ = child.wait();
+                tracing::warn!(program = %program.display(), "executor help probe timed out");
+                return None;
+            }
+        }
+    }
+
+    let output = child.wait_with_output().ok()?;
+    // Some CLIs print usage to stderr, so both streams are searched.
+    let mut text = String::from_utf8_lossy(&output.stdout).into_owned();
+    text.push_str(&String::from_utf8_lossy(&output.stderr));
+    Some(text)
+}
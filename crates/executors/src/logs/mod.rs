@@ -122,6 +122,7 @@ pub enum NormalizedEntryType {
         needs_setup: bool,
     },
     TokenUsageInfo(TokenUsageInfo),
+    Citation(Citation),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, TS)]
@@ -138,6 +139,29 @@ pub struct TokenUsageInfo {
     pub is_estimated: bool,
 }
 
+/// A source reference reported by the executor alongside its answer.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, TS)]
+pub struct Citation {
+    #[serde(default)]
+    pub title: Option<String>,
+    #[serde(default)]
+    pub url: Option<String>,
+    #[serde(default)]
+    pub snippet: Option<String>,
+}
+
+impl Citation {
+    /// Identity used to dedupe repeated citations of the same source.
+    pub fn dedupe_key(&self) -> String {
+        self.url
+            .as_deref()
+            .or(self.title.as_deref())
+            .unwrap_or_default()
+            .trim()
+            .to_string()
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, TS)]
 pub struct NormalizedEntry {
     pub timestamp: Option<String>,
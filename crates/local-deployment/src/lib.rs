@@ -9,6 +9,7 @@ use services::services::{
     analytics::{AnalyticsConfig, AnalyticsContext, AnalyticsService, generate_user_id},
     approvals::Approvals,
     auth::AuthContext,
+    chat_run_retention::spawn_run_retention_sweeper,
     chat_runner::ChatRunner,
     config::{Config, load_config_from_file, save_config_to_file},
     container::ContainerService,
@@ -136,6 +137,14 @@ impl Deployment for LocalDeployment {
         let approvals = Approvals::new(msg_stores.clone());
         let queued_message_service = QueuedMessageService::new();
         let chat_runner = ChatRunner::new(db.clone());
+        chat_runner
+            .set_stream_channel_capacity(config.read().await.chat_runner.stream_channel_capacity());
+        spawn_run_retention_sweeper(db.clone());
+        match chat_runner.recover_interrupted_runs().await {
+            Ok(0) => {}
+            Ok(count) => tracing::info!("Recovered {} interrupted chat runs", count),
+            Err(e) => tracing::error!("Failed to recover interrupted chat runs: {}", e),
+        }
 
         let oauth_credentials = Arc::new(OAuthCredentials::new(credentials_path()));
         if let Err(e) = oauth_credentials.load().await {
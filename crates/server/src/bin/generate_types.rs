@@ -52,18 +52,42 @@ fn generate_types_content() -> String {
         db::models::chat_agent::ChatAgent::decl(),
         db::models::chat_agent::CreateChatAgent::decl(),
         db::models::chat_agent::UpdateChatAgent::decl(),
+        db::models::chat_agent_prompt_version::ChatAgentPromptVersion::decl(),
         db::models::chat_message::ChatMessage::decl(),
         db::models::chat_message::ChatSenderType::decl(),
         db::models::chat_session_agent::ChatSessionAgent::decl(),
         db::models::chat_session_agent::ChatSessionAgentState::decl(),
+        db::models::chat_session_agent::ChatSessionAgentActivity::decl(),
         db::models::chat_permission::ChatPermission::decl(),
         db::models::chat_permission::ChatPermissionTtlType::decl(),
         db::models::chat_artifact::ChatArtifact::decl(),
         db::models::chat_run::ChatRun::decl(),
+        db::models::chat_run::ChatRunWithTags::decl(),
+        db::models::chat_run_stats::ChatRunStatsGroupBy::decl(),
+        db::models::chat_run_stats::ChatRunStats::decl(),
         services::services::chat_runner::ChatStreamEvent::decl(),
         services::services::chat_runner::ChatStreamDeltaType::decl(),
         services::services::chat_runner::MentionStatus::decl(),
+        services::services::chat_runner::RoutingSkipReason::decl(),
+        services::services::chat_runner::RoutingSkip::decl(),
+        services::services::chat_runner::RoutingOutcome::decl(),
         services::services::chat_runner::CompressionWarning::decl(),
+        services::services::chat_runner::SessionAgentSummary::decl(),
+        services::services::chat_runner::ChatStreamStats::decl(),
+        services::services::chat_export::ChatExportJobStatus::decl(),
+        services::services::chat_export::ChatExportJob::decl(),
+        services::services::chat_runner::RunningAgentDiscrepancy::decl(),
+        services::services::chat_runner::RunningAgent::decl(),
+        services::services::chat_runner::ChatCutoffSummary::decl(),
+        services::services::chat_runner::ChatCutoffArchive::decl(),
+        services::services::chat_runner::StopAllAgentsOutcome::decl(),
+        services::services::chat::CompactionPreviewMessage::decl(),
+        services::services::chat::CompactionPreview::decl(),
+        services::services::chat::DebugSummary::decl(),
+        services::services::chat::SummaryFallbackReason::decl(),
+        services::services::chat::SummaryAgentPreview::decl(),
+        services::services::chat::ChatMessagePriority::decl(),
+        services::services::chat::SessionAuditEvent::decl(),
         db::models::image::Image::decl(),
         db::models::image::CreateImage::decl(),
         db::models::workspace::Workspace::decl(),
@@ -126,13 +150,44 @@ fn generate_types_content() -> String {
         server::routes::config::CheckEditorAvailabilityQuery::decl(),
         server::routes::config::CheckEditorAvailabilityResponse::decl(),
         server::routes::config::CheckAgentAvailabilityQuery::decl(),
+        server::routes::config::AgentAvailability::decl(),
         server::routes::oauth::CurrentUserResponse::decl(),
         server::routes::sessions::CreateFollowUpAttempt::decl(),
         server::routes::chat::sessions::ChatSessionListQuery::decl(),
         server::routes::chat::sessions::CreateChatSessionAgentRequest::decl(),
         server::routes::chat::sessions::UpdateChatSessionAgentRequest::decl(),
+        server::routes::chat::sessions::ReorderChatSessionAgentsRequest::decl(),
+        server::routes::chat::sessions::ApplyChatTeamResult::decl(),
+        server::routes::chat::sessions::ReplayChatSessionRequest::decl(),
+        server::routes::chat::sessions::ChatSessionModerator::decl(),
+        server::routes::chat::sessions::ChatSessionDefaultAgent::decl(),
+        server::routes::chat::sessions::ChatSessionTranslation::decl(),
+        server::routes::chat::sessions::ChatSessionContextFormat::decl(),
+        server::routes::chat::sessions::ChatSessionExecutorProfile::decl(),
+        server::routes::chat::sessions::EstimateChatTokensRequest::decl(),
+        server::routes::chat::sessions::ChatTokenEstimate::decl(),
+        server::routes::chat::sessions::ChatCutoffQuery::decl(),
+        server::routes::chat::runs::DenyChatRunApproval::decl(),
+        server::routes::chat::runs::UpdateChatRunTags::decl(),
+        server::routes::chat::runs::RunDiffLineKind::decl(),
+        server::routes::chat::runs::RunDiffLine::decl(),
+        server::routes::chat::runs::RunDiffHunk::decl(),
+        server::routes::chat::runs::RunDiffFile::decl(),
+        server::routes::chat::runs::ChatRunOutput::decl(),
+        server::routes::chat::runs::ChatRunComparisonSide::decl(),
+        server::routes::chat::runs::ChatRunComparison::decl(),
+        server::routes::chat::compression::ChatCompressionConfigUpdate::decl(),
+        server::routes::chat::presets::ChatPresetPromptValidation::decl(),
+        server::routes::chat::debug::DebugSummaryMessage::decl(),
+        server::routes::chat::debug::DebugSummarizeRequest::decl(),
+        server::routes::chat::sessions::ChatStreamControl::decl(),
+        server::routes::chat::sessions::ChatStreamControlReply::decl(),
+        server::routes::chat::sessions::ChatStreamQuery::decl(),
         server::routes::chat::messages::ChatMessageListQuery::decl(),
         server::routes::chat::messages::CreateChatMessageRequest::decl(),
+        server::routes::chat::messages::CreateAgentMessageRequest::decl(),
+        server::routes::chat::messages::SignedAttachmentUrl::decl(),
+        server::routes::chat::messages::SignedAttachmentQuery::decl(),
         server::routes::task_attempts::ChangeTargetBranchRequest::decl(),
         server::routes::task_attempts::ChangeTargetBranchResponse::decl(),
         server::routes::task_attempts::MergeTaskAttemptRequest::decl(),
@@ -193,6 +248,16 @@ fn generate_types_content() -> String {
         services::services::config::ShowcaseState::decl(),
         services::services::config::SendMessageShortcut::decl(),
         services::services::config::ChatCompressionConfig::decl(),
+        services::services::config::ChatRunnerConfig::decl(),
+        services::services::config::ModeratorTimeoutAction::decl(),
+        services::services::config::UnknownMentionPolicy::decl(),
+        services::services::config::AgentUnresolvedRoutingPolicy::decl(),
+        services::services::config::SystemMessageCategory::decl(),
+        services::services::config::SummaryBusyAgentsPolicy::decl(),
+        services::services::config::RunContextMode::decl(),
+        services::services::config::ChatContextFormat::decl(),
+        services::services::config::RedactionRule::decl(),
+        services::services::config::SlashCommand::decl(),
         services::services::config::ChatPresetsConfig::decl(),
         services::services::config::ChatMemberPreset::decl(),
         services::services::config::ChatTeamPreset::decl(),
@@ -210,6 +275,7 @@ fn generate_types_content() -> String {
         executors::executors::CodingAgent::decl(),
         executors::executors::SlashCommandDescription::decl(),
         executors::executors::AvailabilityInfo::decl(),
+        executors::executors::ExecutorOutputFormat::decl(),
         executors::command::CommandBuilder::decl(),
         executors::profile::ExecutorProfileId::decl(),
         executors::profile::ExecutorConfig::decl(),
@@ -242,6 +308,7 @@ fn generate_types_content() -> String {
         executors::logs::NormalizedEntry::decl(),
         executors::logs::NormalizedEntryType::decl(),
         executors::logs::TokenUsageInfo::decl(),
+        executors::logs::Citation::decl(),
         executors::logs::FileChange::decl(),
         executors::logs::ActionType::decl(),
         executors::logs::TodoItem::decl(),
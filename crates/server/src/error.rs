@@ -383,6 +383,9 @@ impl IntoResponse for ApiError {
             ApiError::Container(_) => ErrorInfo::internal("ContainerError"),
             ApiError::Executor(_) => ErrorInfo::internal("ExecutorError"),
             ApiError::CommandBuilder(_) => ErrorInfo::internal("CommandBuildError"),
+            ApiError::Database(sqlx::Error::RowNotFound) => {
+                ErrorInfo::not_found("DatabaseError", "Record not found.")
+            }
             ApiError::Database(_) => ErrorInfo::internal("DatabaseError"),
             ApiError::Worktree(_) => ErrorInfo::internal("WorktreeError"),
             ApiError::Config(_) => ErrorInfo::internal("ConfigError"),
@@ -405,6 +408,17 @@ impl IntoResponse for ApiError {
             ApiError::ChatRunner(ChatRunnerError::UnknownRunnerType(_)) => {
                 ErrorInfo::bad_request("ChatRunnerError", "Unknown runner type.")
             }
+            ApiError::ChatRunner(ChatRunnerError::AgentRunActive(_)) => ErrorInfo::conflict(
+                "ChatRunnerError",
+                "Agent has a live run. Stop it instead of resetting.",
+            ),
+            ApiError::ChatRunner(ChatRunnerError::ApprovalNotFound(_)) => {
+                ErrorInfo::conflict("ChatRunnerError", "This run is not waiting for approval.")
+            }
+            ApiError::ChatRunner(ChatRunnerError::CompactionInFlight(_)) => ErrorInfo::conflict(
+                "ChatRunnerError",
+                "Context compaction is already running for this session.",
+            ),
             ApiError::ChatRunner(_) => ErrorInfo::internal("ChatRunnerError"),
             ApiError::Io(_) => ErrorInfo::internal("IoError"),
             ApiError::Migration(MigrationError::Database(_)) => {
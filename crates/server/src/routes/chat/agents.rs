@@ -1,14 +1,58 @@
-use axum::{Extension, Json, extract::State, response::Json as ResponseJson};
+use axum::{
+    Extension, Json,
+    extract::{Path, State},
+    response::Json as ResponseJson,
+};
 use db::models::{
     chat_agent::{ChatAgent, CreateChatAgent, UpdateChatAgent},
+    chat_agent_prompt_version::ChatAgentPromptVersion,
     chat_session_agent::ChatSessionAgent,
 };
 use deployment::Deployment;
+use executors::profile::ExecutorConfigs;
+use services::services::{
+    chat::{normalize_executor_profile_variant, validate_thinking_effort},
+    chat_redaction::validate_agent_redaction_rules,
+    chat_runner::validate_max_output_tokens,
+    chat_workspace::{validate_additional_workspace_paths, validate_git_diff_base},
+};
 use utils::response::ApiResponse;
 use uuid::Uuid;
 
 use crate::{DeploymentImpl, error::ApiError};
 
+async fn ensure_name_not_reserved(deployment: &DeploymentImpl, name: &str) -> Result<(), ApiError> {
+    if deployment
+        .config()
+        .read()
+        .await
+        .chat_runner
+        .is_reserved_handle(name)
+    {
+        return Err(ApiError::BadRequest(format!(
+            "\"{}\" is a reserved handle and cannot be used as an AI member name.",
+            name.trim()
+        )));
+    }
+    Ok(())
+}
+
+/// Reject `tools_enabled` values the runner would misread or ignore.
+pub async fn validate_tools_enabled(
+    tools_enabled: Option<&serde_json::Value>,
+) -> Result<(), ApiError> {
+    if let Some(tools_enabled) = tools_enabled {
+        validate_additional_workspace_paths(tools_enabled)
+            .await
+            .map_err(|err| ApiError::BadRequest(err.to_string()))?;
+        validate_agent_redaction_rules(tools_enabled).map_err(ApiError::BadRequest)?;
+        validate_git_diff_base(tools_enabled).map_err(ApiError::BadRequest)?;
+        validate_thinking_effort(tools_enabled).map_err(ApiError::BadRequest)?;
+        validate_max_output_tokens(tools_enabled).map_err(ApiError::BadRequest)?;
+    }
+    Ok(())
+}
+
 pub async fn get_agents(
     State(deployment): State<DeploymentImpl>,
 ) -> Result<ResponseJson<ApiResponse<Vec<ChatAgent>>>, ApiError> {
@@ -24,8 +68,17 @@ pub async fn get_agent(
 
 pub async fn create_agent(
     State(deployment): State<DeploymentImpl>,
-    Json(payload): Json<CreateChatAgent>,
+    Json(mut payload): Json<CreateChatAgent>,
 ) -> Result<ResponseJson<ApiResponse<ChatAgent>>, ApiError> {
+    ensure_name_not_reserved(&deployment, &payload.name).await?;
+    validate_tools_enabled(payload.tools_enabled.as_ref()).await?;
+    if let Some(tools_enabled) = payload.tools_enabled.as_mut() {
+        normalize_executor_profile_variant(
+            &payload.runner_type,
+            tools_enabled,
+            &ExecutorConfigs::get_cached(),
+        )?;
+    }
     let agent = ChatAgent::create(&deployment.db().pool, &payload, Uuid::new_v4()).await?;
     Ok(ResponseJson(ApiResponse::success(agent)))
 }
@@ -33,14 +86,44 @@ pub async fn create_agent(
 pub async fn update_agent(
     Extension(agent): Extension<ChatAgent>,
     State(deployment): State<DeploymentImpl>,
-    Json(payload): Json<UpdateChatAgent>,
+    Json(mut payload): Json<UpdateChatAgent>,
 ) -> Result<ResponseJson<ApiResponse<ChatAgent>>, ApiError> {
+    if let Some(name) = payload.name.as_deref() {
+        ensure_name_not_reserved(&deployment, name).await?;
+    }
+    validate_tools_enabled(payload.tools_enabled.as_ref()).await?;
+    // A new runner type must also offer the variant the agent already uses.
+    if payload.runner_type.is_some() || payload.tools_enabled.is_some() {
+        let runner_type = payload.runner_type.as_deref().unwrap_or(&agent.runner_type);
+        let mut tools_enabled = payload
+            .tools_enabled
+            .clone()
+            .unwrap_or_else(|| agent.tools_enabled.0.clone());
+        normalize_executor_profile_variant(
+            runner_type,
+            &mut tools_enabled,
+            &ExecutorConfigs::get_cached(),
+        )?;
+        if payload.tools_enabled.is_some() {
+            payload.tools_enabled = Some(tools_enabled);
+        }
+    }
+
     // Check if runner_type is being changed
     let runner_type_changing = payload
         .runner_type
         .as_ref()
         .is_some_and(|new_type| new_type != &agent.runner_type);
 
+    if payload
+        .system_prompt
+        .as_ref()
+        .is_some_and(|prompt| prompt != &agent.system_prompt)
+    {
+        ChatAgentPromptVersion::create(&deployment.db().pool, agent.id, &agent.system_prompt)
+            .await?;
+    }
+
     let updated = ChatAgent::update(&deployment.db().pool, agent.id, &payload).await?;
 
     // If runner_type changed, clear the agent_session_id and agent_message_id
@@ -60,6 +143,46 @@ pub async fn update_agent(
     Ok(ResponseJson(ApiResponse::success(updated)))
 }
 
+/// Prompts the agent used before each change, newest first.
+pub async fn get_agent_prompt_history(
+    Extension(agent): Extension<ChatAgent>,
+    State(deployment): State<DeploymentImpl>,
+) -> Result<ResponseJson<ApiResponse<Vec<ChatAgentPromptVersion>>>, ApiError> {
+    let versions =
+        ChatAgentPromptVersion::find_by_agent_id(&deployment.db().pool, agent.id).await?;
+    Ok(ResponseJson(ApiResponse::success(versions)))
+}
+
+/// Restore a previous prompt. The prompt being replaced is kept in the history, so a
+/// revert can itself be reverted. Runs read the agent fresh, so the next run uses it.
+pub async fn revert_agent_prompt(
+    Extension(agent): Extension<ChatAgent>,
+    State(deployment): State<DeploymentImpl>,
+    Path((_agent_id, version_id)): Path<(Uuid, Uuid)>,
+) -> Result<ResponseJson<ApiResponse<ChatAgent>>, ApiError> {
+    let pool = &deployment.db().pool;
+    let version = ChatAgentPromptVersion::find_by_id(pool, agent.id, version_id)
+        .await?
+        .ok_or(ApiError::Database(sqlx::Error::RowNotFound))?;
+    if version.system_prompt == agent.system_prompt {
+        return Ok(ResponseJson(ApiResponse::success(agent)));
+    }
+
+    ChatAgentPromptVersion::create(pool, agent.id, &agent.system_prompt).await?;
+    let updated = ChatAgent::update(
+        pool,
+        agent.id,
+        &UpdateChatAgent {
+            name: None,
+            runner_type: None,
+            system_prompt: Some(version.system_prompt),
+            tools_enabled: None,
+        },
+    )
+    .await?;
+    Ok(ResponseJson(ApiResponse::success(updated)))
+}
+
 pub async fn delete_agent(
     Extension(agent): Extension<ChatAgent>,
     State(deployment): State<DeploymentImpl>,
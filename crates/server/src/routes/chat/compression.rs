@@ -0,0 +1,68 @@
+use axum::{
+    Json,
+    extract::{Query, State},
+    response::Json as ResponseJson,
+};
+use deployment::Deployment;
+use serde::{Deserialize, Serialize};
+use services::services::{
+    chat::{invalidate_stale_compression_caches, validate_compression_config},
+    config::{ChatCompressionConfig, save_config_to_file},
+};
+use ts_rs::TS;
+use utils::{assets::config_path, response::ApiResponse};
+use uuid::Uuid;
+
+use crate::{DeploymentImpl, error::ApiError};
+
+#[derive(Debug, Default, Deserialize)]
+pub struct UpdateCompressionConfigQuery {
+    /// Recompute the compacted context of affected sessions right away
+    #[serde(default)]
+    pub recompute: bool,
+}
+
+#[derive(Debug, Serialize, TS)]
+pub struct ChatCompressionConfigUpdate {
+    pub config: ChatCompressionConfig,
+    /// Sessions whose cached compression result no longer matched the new settings
+    pub invalidated_sessions: Vec<Uuid>,
+}
+
+/// Replace the compression settings, save them and drop cached compression results that
+/// were computed under different settings.
+pub async fn update_compression_config(
+    State(deployment): State<DeploymentImpl>,
+    Query(query): Query<UpdateCompressionConfigQuery>,
+    Json(compression): Json<ChatCompressionConfig>,
+) -> Result<ResponseJson<ApiResponse<ChatCompressionConfigUpdate>>, ApiError> {
+    validate_compression_config(&compression).map_err(ApiError::BadRequest)?;
+
+    // Held until the new settings are in memory so a concurrent update cannot interleave
+    // its read and save with this one.
+    {
+        let mut config = deployment.config().write().await;
+        let mut new_config = config.clone();
+        new_config.chat_compression = compression.clone();
+        save_config_to_file(&new_config, &config_path()).await?;
+        *config = new_config;
+    }
+
+    let pool = &deployment.db().pool;
+    let invalidated_sessions = invalidate_stale_compression_caches(pool, &compression).await?;
+    if query.recompute {
+        for session_id in &invalidated_sessions {
+            deployment
+                .chat_runner()
+                .recompute_context_compaction(*session_id, false)
+                .await?;
+        }
+    }
+
+    Ok(ResponseJson(ApiResponse::success(
+        ChatCompressionConfigUpdate {
+            config: compression,
+            invalidated_sessions,
+        },
+    )))
+}
@@ -0,0 +1,68 @@
+use axum::{Json, extract::State, response::Json as ResponseJson};
+use chrono::Utc;
+use db::models::chat_agent::ChatAgent;
+use deployment::Deployment;
+use serde::Deserialize;
+use services::services::{chat::DebugSummary, chat_history_file::SimplifiedMessage};
+use ts_rs::TS;
+use utils::response::ApiResponse;
+use uuid::Uuid;
+
+use crate::{DeploymentImpl, error::ApiError};
+
+#[derive(Debug, Deserialize, TS)]
+pub struct DebugSummaryMessage {
+    /// Sender as written in the history, e.g. "user:you" or "agent:coder"
+    pub sender: String,
+    pub content: String,
+}
+
+#[derive(Debug, Deserialize, TS)]
+pub struct DebugSummarizeRequest {
+    /// Agent whose executor writes the summary
+    pub agent_id: Uuid,
+    pub messages: Vec<DebugSummaryMessage>,
+    /// Replaces the regular summarization prompt; `{messages}` marks where the messages go
+    pub prompt_template: Option<String>,
+}
+
+/// Summarize sample messages with one agent, for tuning the summarization prompt.
+/// Only served when `chat_runner.debug_summarize_endpoint` is enabled.
+pub async fn summarize(
+    State(deployment): State<DeploymentImpl>,
+    Json(payload): Json<DebugSummarizeRequest>,
+) -> Result<ResponseJson<ApiResponse<DebugSummary>>, ApiError> {
+    if !deployment
+        .config()
+        .read()
+        .await
+        .chat_runner
+        .debug_summarize_endpoint
+    {
+        return Err(ApiError::Forbidden(
+            "Debug summarization is disabled".to_string(),
+        ));
+    }
+    let Some(agent) = ChatAgent::find_by_id(&deployment.db().pool, payload.agent_id).await? else {
+        return Err(ApiError::BadRequest("Chat agent not found".to_string()));
+    };
+
+    let timestamp = Utc::now().to_rfc3339();
+    let messages: Vec<SimplifiedMessage> = payload
+        .messages
+        .into_iter()
+        .map(|message| SimplifiedMessage {
+            sender: message.sender,
+            content: message.content,
+            timestamp: timestamp.clone(),
+            id: None,
+        })
+        .collect();
+    let summary = services::services::chat::debug_summarize(
+        &agent,
+        &messages,
+        payload.prompt_template.as_deref(),
+    )
+    .await?;
+    Ok(ResponseJson(ApiResponse::success(summary)))
+}
@@ -0,0 +1,14 @@
+use axum::response::Json as ResponseJson;
+use executors::profile::ExecutorConfigs;
+use utils::response::ApiResponse;
+
+/// Re-read executor profiles from disk into the process-wide cache.
+///
+/// Every later `ExecutorConfigs::get_cached()` call (new chat runs, task attempts,
+/// config endpoints) sees the refreshed profiles. Runs that are already executing
+/// resolved their executor when they were spawned and keep using it.
+pub async fn reload_executors() -> ResponseJson<ApiResponse<ExecutorConfigs>> {
+    ExecutorConfigs::reload();
+    tracing::info!("Executor profiles cache reloaded");
+    ResponseJson(ApiResponse::success(ExecutorConfigs::get_cached()))
+}
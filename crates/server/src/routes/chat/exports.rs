@@ -0,0 +1,169 @@
+use axum::{
+    Extension,
+    extract::{Path, State},
+    http::{StatusCode, header},
+    response::{Json as ResponseJson, Response},
+};
+use db::models::chat_session::ChatSession;
+use deployment::Deployment;
+use services::services::chat_export::{self, ChatExportJob};
+use tokio::fs::File;
+use tokio_util::io::ReaderStream;
+use utils::{assets::asset_dir, response::ApiResponse};
+use uuid::Uuid;
+
+use crate::{DeploymentImpl, error::ApiError};
+
+/// Start exporting the session in the background; poll the returned job for its result.
+pub async fn start_session_export(
+    Extension(session): Extension<ChatSession>,
+    State(deployment): State<DeploymentImpl>,
+) -> Result<ResponseJson<ApiResponse<ChatExportJob>>, ApiError> {
+    let export_root = asset_dir()
+        .join("chat")
+        .join(format!("session_{}", session.id))
+        .join("exports");
+    let job = chat_export::start_export_job(deployment.db().pool.clone(), session, &export_root);
+    Ok(ResponseJson(ApiResponse::success(job)))
+}
+
+pub async fn get_export_job(
+    Path(job_id): Path<Uuid>,
+) -> Result<ResponseJson<ApiResponse<ChatExportJob>>, ApiError> {
+    let job =
+        chat_export::get_export_job(job_id).ok_or(ApiError::Database(sqlx::Error::RowNotFound))?;
+    Ok(ResponseJson(ApiResponse::success(job)))
+}
+
+/// Exported messages of a completed job as JSONL.
+pub async fn download_export(Path(job_id): Path<Uuid>) -> Result<Response, ApiError> {
+    let job =
+        chat_export::get_export_job(job_id).ok_or(ApiError::Database(sqlx::Error::RowNotFound))?;
+    let Some(path) = job.messages_file() else {
+        return Err(ApiError::Conflict(
+            "Export job has not completed".to_string(),
+        ));
+    };
+    let file = File::open(&path)
+        .await
+        .map_err(|_| ApiError::BadRequest("Export file not found".to_string()))?;
+    let metadata = file.metadata().await?;
+    let body = axum::body::Body::from_stream(ReaderStream::new(file));
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/x-ndjson")
+        .header(header::CONTENT_LENGTH, metadata.len())
+        .header(
+            header::CONTENT_DISPOSITION,
+            format!(
+                "attachment; filename=\"session_{}_messages.jsonl\"",
+                job.session_id
+            ),
+        )
+        .body(body)
+        .map_err(|e| ApiError::BadRequest(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        extract::Path,
+        http::{StatusCode, header},
+        response::IntoResponse,
+    };
+    use db::models::{
+        chat_message::ChatSenderType,
+        chat_session::{ChatSession, CreateChatSession},
+    };
+    use services::services::{
+        chat,
+        chat_export::{self, ChatExportJobStatus},
+    };
+    use uuid::Uuid;
+
+    use super::{download_export, get_export_job};
+
+    #[tokio::test]
+    async fn export_job_is_polled_until_complete_then_downloaded() {
+        let pool = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .expect("create sqlite memory pool");
+        sqlx::migrate!("../db/migrations")
+            .run(&pool)
+            .await
+            .expect("run migrations");
+        let session =
+            ChatSession::create(&pool, &CreateChatSession { title: None }, Uuid::new_v4())
+                .await
+                .expect("create session");
+        chat::create_message(
+            &pool,
+            session.id,
+            ChatSenderType::User,
+            None,
+            "hello export".to_string(),
+            None,
+        )
+        .await
+        .expect("create message");
+        let export_root = std::env::temp_dir().join(format!("chat_export_{}", Uuid::new_v4()));
+
+        let job = chat_export::start_export_job(pool, session, &export_root);
+        assert_eq!(job.status, ChatExportJobStatus::Running);
+        let job = tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            loop {
+                let job = get_export_job(Path(job.job_id))
+                    .await
+                    .expect("poll export job")
+                    .0
+                    .into_data()
+                    .expect("job in response");
+                if job.status != ChatExportJobStatus::Running {
+                    return job;
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("export finished in time");
+        assert_eq!(job.status, ChatExportJobStatus::Completed);
+        assert_eq!(
+            job.download_url,
+            Some(format!("/api/chat/exports/{}/download", job.job_id))
+        );
+
+        let response = download_export(Path(job.job_id))
+            .await
+            .expect("download export");
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/x-ndjson"
+        );
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .expect("read export body");
+        let body = String::from_utf8(body.to_vec()).expect("utf-8 export");
+        assert_eq!(body.lines().count(), 1);
+        assert!(body.contains("hello export"));
+
+        let _ = std::fs::remove_dir_all(&export_root);
+    }
+
+    #[tokio::test]
+    async fn unknown_export_job_is_not_found() {
+        let missing = Uuid::new_v4();
+        let poll = get_export_job(Path(missing))
+            .await
+            .map(IntoResponse::into_response)
+            .unwrap_or_else(IntoResponse::into_response);
+        assert_eq!(poll.status(), StatusCode::NOT_FOUND);
+        let download = download_export(Path(missing))
+            .await
+            .unwrap_or_else(IntoResponse::into_response);
+        assert_eq!(download.status(), StatusCode::NOT_FOUND);
+    }
+}
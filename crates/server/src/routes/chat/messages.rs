@@ -1,18 +1,31 @@
-use std::path::{Component, PathBuf};
+use std::sync::OnceLock;
 
 use axum::{
     Extension, Json,
-    extract::{Multipart, Path, Query, State},
+    extract::{Multipart, Path, Query, State, multipart::Field},
     http::{StatusCode, header},
     response::{Json as ResponseJson, Response},
 };
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
 use db::models::{
+    chat_agent::ChatAgent,
     chat_message::{ChatMessage, ChatSenderType},
-    chat_session::ChatSession,
+    chat_session::{ChatSession, ChatSessionStatus},
+    chat_session_agent::ChatSessionAgent,
 };
 use deployment::Deployment;
-use serde::Deserialize;
-use services::services::chat::ChatAttachmentMeta;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use services::services::{
+    chat::{
+        ChatAttachmentMeta, ChatMessagePriority, PINNED_KEY, PRIORITY_KEY,
+        sanitize_attachment_filename, sniff_attachment,
+    },
+    chat_runner::OUTPUT_TRUNCATED_META_KEY,
+    chat_slash_commands::{SLASH_COMMAND_KEY, expand_slash_command},
+};
+use sha2::Sha256;
 use tokio::{fs, fs::File};
 use tokio_util::io::ReaderStream;
 use ts_rs::TS;
@@ -30,6 +43,25 @@ const ALLOWED_TEXT_EXTENSIONS: &[&str] = &[
 const ALLOWED_IMAGE_EXTENSIONS: &[&str] =
     &[".png", ".jpg", ".jpeg", ".gif", ".webp", ".bmp", ".svg"];
 
+/// Lifetime of URLs issued by `sign_message_attachment`.
+const ATTACHMENT_URL_TTL_SECS: i64 = 5 * 60;
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Debug, Serialize, TS)]
+pub struct SignedAttachmentUrl {
+    /// Path serving the attachment without session context, valid until `expires_at`
+    pub url: String,
+    /// Unix timestamp (seconds)
+    pub expires_at: i64,
+}
+
+#[derive(Debug, Deserialize, TS)]
+pub struct SignedAttachmentQuery {
+    pub expires: i64,
+    pub signature: String,
+}
+
 #[derive(Debug, Deserialize, TS)]
 pub struct ChatMessageListQuery {
     pub limit: Option<i64>,
@@ -41,6 +73,16 @@ pub struct CreateChatMessageRequest {
     pub sender_id: Option<Uuid>,
     pub content: String,
     pub meta: Option<serde_json::Value>,
+    /// Queue priority for busy agents; stored as `meta.priority`, defaults to `normal`
+    pub priority: Option<ChatMessagePriority>,
+}
+
+#[derive(Debug, Deserialize, TS)]
+pub struct CreateAgentMessageRequest {
+    /// Session member the message is posted as
+    pub agent_id: Uuid,
+    pub content: String,
+    pub meta: Option<serde_json::Value>,
 }
 
 #[derive(Debug, Deserialize, TS)]
@@ -49,25 +91,11 @@ pub struct DeleteMessagesRequest {
     pub message_ids: Vec<Uuid>,
 }
 
-fn sanitize_filename(name: &str) -> String {
-    let sanitized: String = name
-        .chars()
-        .filter(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
-        .collect();
-    if sanitized.is_empty() {
-        "file".to_string()
-    } else {
-        sanitized.chars().take(120).collect()
-    }
-}
-
-fn attachment_kind(mime: Option<&str>) -> String {
-    if let Some(mime) = mime
-        && mime.starts_with("image/")
-    {
-        return "image".to_string();
-    }
-    "file".to_string()
+/// Bounds for a range delete; both are exclusive message ids and at least one is required.
+#[derive(Debug, Deserialize)]
+pub struct DeleteMessagesRangeQuery {
+    pub before: Option<Uuid>,
+    pub after: Option<Uuid>,
 }
 
 fn is_allowed_attachment(filename: &str, mime: Option<&str>) -> bool {
@@ -83,26 +111,43 @@ fn is_allowed_attachment(filename: &str, mime: Option<&str>) -> bool {
         .any(|ext| lower.ends_with(ext))
 }
 
-fn attachment_storage_dir(session_id: Uuid, message_id: Uuid) -> PathBuf {
-    asset_dir()
-        .join("chat")
-        .join(format!("session_{session_id}"))
-        .join("attachments")
-        .join(message_id.to_string())
-}
-
-fn resolve_relative_path(relative_path: &str) -> Option<PathBuf> {
-    let rel = PathBuf::from(relative_path);
-    if rel.is_absolute() {
-        return None;
+/// Persist one multipart file field under the message's attachment directory.
+/// Returns `None` for non-file fields and empty uploads.
+async fn store_attachment_field(
+    field: Field<'_>,
+    session_id: Uuid,
+    message_id: Uuid,
+) -> Result<Option<ChatAttachmentMeta>, ApiError> {
+    let Some(filename) = field.file_name().map(|name| name.to_string()) else {
+        return Ok(None);
+    };
+    let mime_type = field.content_type().map(|value| value.to_string());
+    if !is_allowed_attachment(&filename, mime_type.as_deref()) {
+        return Err(ApiError::BadRequest(
+            "Only text files and images are allowed.".to_string(),
+        ));
     }
-    if rel
-        .components()
-        .any(|component| matches!(component, Component::ParentDir))
-    {
-        return None;
+    let data = field.bytes().await?;
+    if data.is_empty() {
+        return Ok(None);
     }
-    Some(asset_dir().join(rel))
+
+    let (mime_type, kind) = sniff_attachment(&data, mime_type.as_deref());
+    let attachment = ChatAttachmentMeta {
+        id: Uuid::new_v4(),
+        name: filename,
+        mime_type,
+        size_bytes: data.len() as i64,
+        kind,
+    };
+
+    let storage_path = asset_dir().join(attachment.relative_path(session_id, message_id));
+    if let Some(storage_dir) = storage_path.parent() {
+        fs::create_dir_all(storage_dir).await?;
+    }
+    fs::write(&storage_path, &data).await?;
+
+    Ok(Some(attachment))
 }
 
 pub async fn get_messages(
@@ -120,13 +165,151 @@ pub async fn create_message(
     State(deployment): State<DeploymentImpl>,
     Json(payload): Json<CreateChatMessageRequest>,
 ) -> Result<ResponseJson<ApiResponse<ChatMessage>>, ApiError> {
+    let mut meta = payload.meta;
+    if let Some(priority) = payload.priority {
+        let object = meta
+            .get_or_insert_with(|| serde_json::json!({}))
+            .as_object_mut()
+            .ok_or_else(|| ApiError::BadRequest("meta must be a JSON object".to_string()))?;
+        object.insert(PRIORITY_KEY.to_string(), serde_json::json!(priority));
+    }
+
+    let mut content = payload.content;
+    if payload.sender_type == ChatSenderType::User {
+        let commands = deployment
+            .config()
+            .read()
+            .await
+            .chat_runner
+            .slash_commands
+            .clone();
+        if let Some(expanded) =
+            expand_slash_command(&commands, &content).map_err(ApiError::BadRequest)?
+        {
+            let object = meta
+                .get_or_insert_with(|| serde_json::json!({}))
+                .as_object_mut()
+                .ok_or_else(|| ApiError::BadRequest("meta must be a JSON object".to_string()))?;
+            object.insert(SLASH_COMMAND_KEY.to_string(), expanded.meta(&content));
+            content = expanded.content;
+        }
+    }
+
     let message = services::services::chat::create_message(
         &deployment.db().pool,
         session.id,
         payload.sender_type,
         payload.sender_id,
+        content,
+        meta,
+    )
+    .await?;
+
+    deployment
+        .chat_runner()
+        .handle_message(&session, &message)
+        .await;
+
+    Ok(ResponseJson(ApiResponse::success(message)))
+}
+
+/// Post a message as one of the session's agents and route its directives as if the agent
+/// had replied. Only served when `chat_runner.debug_agent_messages_endpoint` is enabled.
+pub async fn create_agent_message(
+    Extension(session): Extension<ChatSession>,
+    State(deployment): State<DeploymentImpl>,
+    Json(payload): Json<CreateAgentMessageRequest>,
+) -> Result<ResponseJson<ApiResponse<ChatMessage>>, ApiError> {
+    if !deployment
+        .config()
+        .read()
+        .await
+        .chat_runner
+        .debug_agent_messages_endpoint
+    {
+        return Err(ApiError::Forbidden(
+            "Posting messages as an agent is disabled".to_string(),
+        ));
+    }
+    if ChatSessionAgent::find_by_session_and_agent(
+        &deployment.db().pool,
+        session.id,
+        payload.agent_id,
+    )
+    .await?
+    .is_none()
+    {
+        return Err(ApiError::BadRequest(
+            "Agent is not a member of this session".to_string(),
+        ));
+    }
+
+    let mut meta = payload.meta.unwrap_or_else(|| serde_json::json!({}));
+    meta.as_object_mut()
+        .ok_or_else(|| ApiError::BadRequest("meta must be a JSON object".to_string()))?
+        .insert("impersonated".to_string(), serde_json::json!(true));
+
+    let message = services::services::chat::create_message(
+        &deployment.db().pool,
+        session.id,
+        ChatSenderType::Agent,
+        Some(payload.agent_id),
         payload.content,
-        payload.meta,
+        Some(meta),
+    )
+    .await?;
+
+    deployment
+        .chat_runner()
+        .handle_message(&session, &message)
+        .await;
+
+    Ok(ResponseJson(ApiResponse::success(message)))
+}
+
+/// Ask the agent of a reply cut off at its `max_output_tokens` to pick up where it stopped.
+/// The request is posted as a user message mentioning the agent, so the run resumes the
+/// agent's executor session.
+pub async fn continue_message(
+    Extension(session): Extension<ChatSession>,
+    State(deployment): State<DeploymentImpl>,
+    Path((_session_id, message_id)): Path<(Uuid, Uuid)>,
+) -> Result<ResponseJson<ApiResponse<ChatMessage>>, ApiError> {
+    let pool = &deployment.db().pool;
+    let truncated = ChatMessage::find_by_id(pool, message_id)
+        .await?
+        .filter(|message| message.session_id == session.id)
+        .ok_or(ApiError::Database(sqlx::Error::RowNotFound))?;
+    if truncated.sender_type != ChatSenderType::Agent
+        || truncated
+            .meta
+            .0
+            .get(OUTPUT_TRUNCATED_META_KEY)
+            .is_none_or(serde_json::Value::is_null)
+    {
+        return Err(ApiError::BadRequest(
+            "Only replies cut off at their output cap can be continued".to_string(),
+        ));
+    }
+    let agent_id = truncated
+        .sender_id
+        .ok_or_else(|| ApiError::BadRequest("Message has no sending agent".to_string()))?;
+    let agent = ChatAgent::find_by_id(pool, agent_id)
+        .await?
+        .ok_or(ApiError::Database(sqlx::Error::RowNotFound))?;
+
+    let mention = if agent.name.contains(char::is_whitespace) {
+        format!("@\"{}\"", agent.name)
+    } else {
+        format!("@{}", agent.name)
+    };
+    let message = services::services::chat::create_message(
+        pool,
+        session.id,
+        ChatSenderType::User,
+        None,
+        format!("{mention} Continue your previous reply exactly where it was cut off."),
+        Some(serde_json::json!({ "continues_message_id": truncated.id })),
     )
     .await?;
 
@@ -170,44 +353,11 @@ pub async fn upload_message_attachments(
                 }
             }
             _ => {
-                let filename = field.file_name().map(|name| name.to_string());
-                let mime_type = field.content_type().map(|value| value.to_string());
-                let Some(filename) = filename else {
-                    continue;
-                };
-                if !is_allowed_attachment(&filename, mime_type.as_deref()) {
-                    return Err(ApiError::BadRequest(
-                        "Only text files and images are allowed.".to_string(),
-                    ));
-                }
-                let data = field.bytes().await?;
-                if data.is_empty() {
-                    continue;
+                if let Some(attachment) =
+                    store_attachment_field(field, session.id, message_id).await?
+                {
+                    attachments.push(attachment);
                 }
-
-                let attachment_id = Uuid::new_v4();
-                let original_name = filename.to_string();
-                let sanitized = sanitize_filename(&filename);
-                let stored_name = format!("{attachment_id}_{sanitized}");
-                let storage_dir = attachment_storage_dir(session.id, message_id);
-                fs::create_dir_all(&storage_dir).await?;
-                let storage_path = storage_dir.join(&stored_name);
-                fs::write(&storage_path, &data).await?;
-
-                let kind = attachment_kind(mime_type.as_deref());
-                let relative_path = format!(
-                    "chat/session_{}/attachments/{}/{}",
-                    session.id, message_id, stored_name
-                );
-
-                attachments.push(ChatAttachmentMeta {
-                    id: attachment_id,
-                    name: original_name,
-                    mime_type,
-                    size_bytes: data.len() as i64,
-                    kind,
-                    relative_path,
-                });
             }
         }
     }
@@ -252,6 +402,62 @@ pub async fn upload_message_attachments(
     Ok(ResponseJson(ApiResponse::success(message)))
 }
 
+/// Attach files to an existing user message after it was sent.
+pub async fn add_message_attachments(
+    State(deployment): State<DeploymentImpl>,
+    Path(message_id): Path<Uuid>,
+    mut multipart: Multipart,
+) -> Result<ResponseJson<ApiResponse<ChatMessage>>, ApiError> {
+    let pool = &deployment.db().pool;
+    let message = ChatMessage::find_by_id(pool, message_id)
+        .await?
+        .ok_or(ApiError::Database(sqlx::Error::RowNotFound))?;
+    let session = ChatSession::find_by_id(pool, message.session_id)
+        .await?
+        .ok_or(ApiError::Database(sqlx::Error::RowNotFound))?;
+    if session.status != ChatSessionStatus::Active {
+        return Err(ApiError::Conflict("Chat session is archived".to_string()));
+    }
+    if message.sender_type != ChatSenderType::User {
+        return Err(ApiError::BadRequest(
+            "Attachments can only be added to user messages.".to_string(),
+        ));
+    }
+
+    let mut attachments = Vec::new();
+    while let Some(field) = multipart.next_field().await? {
+        if let Some(attachment) = store_attachment_field(field, session.id, message.id).await? {
+            attachments.push(attachment);
+        }
+    }
+    if attachments.is_empty() {
+        return Err(ApiError::BadRequest(
+            "No attachments were uploaded.".to_string(),
+        ));
+    }
+
+    // Appended one statement at a time so a concurrent upload to the same message
+    // cannot overwrite these.
+    for attachment in attachments {
+        ChatMessage::append_meta_item(
+            pool,
+            message.id,
+            "attachments",
+            serde_json::to_value(&attachment).unwrap_or_default(),
+        )
+        .await?;
+    }
+
+    let updated = ChatMessage::find_by_id(pool, message.id)
+        .await?
+        .ok_or(ApiError::Database(sqlx::Error::RowNotFound))?;
+    deployment
+        .chat_runner()
+        .emit_message_updated(session.id, updated.clone());
+
+    Ok(ResponseJson(ApiResponse::success(updated)))
+}
+
 pub async fn serve_message_attachment(
     Extension(session): Extension<ChatSession>,
     State(deployment): State<DeploymentImpl>,
@@ -265,16 +471,128 @@ pub async fn serve_message_attachment(
         return Err(ApiError::Database(sqlx::Error::RowNotFound));
     }
 
+    stream_attachment(&message, attachment_id).await
+}
+
+/// Issue a short-lived signed URL for an attachment of a message in this session.
+pub async fn sign_message_attachment(
+    Extension(session): Extension<ChatSession>,
+    State(deployment): State<DeploymentImpl>,
+    Path((_session_id, message_id, attachment_id)): Path<(Uuid, Uuid, Uuid)>,
+) -> Result<ResponseJson<ApiResponse<SignedAttachmentUrl>>, ApiError> {
+    let message = ChatMessage::find_by_id(&deployment.db().pool, message_id)
+        .await?
+        .ok_or(ApiError::Database(sqlx::Error::RowNotFound))?;
+
+    if message.session_id != session.id {
+        return Err(ApiError::Database(sqlx::Error::RowNotFound));
+    }
+    if !services::services::chat::extract_attachments(&message.meta.0)
+        .iter()
+        .any(|item| item.id == attachment_id)
+    {
+        return Err(ApiError::BadRequest("Attachment not found".to_string()));
+    }
+
+    let expires_at = chrono::Utc::now().timestamp() + ATTACHMENT_URL_TTL_SECS;
+    let signature = sign_attachment_access(message_id, attachment_id, expires_at);
+    Ok(ResponseJson(ApiResponse::success(SignedAttachmentUrl {
+        url: format!(
+            "{}{}/attachments/{message_id}/{attachment_id}?expires={expires_at}&signature={signature}",
+            crate::routes::API_PREFIX,
+            super::CHAT_PREFIX,
+        ),
+        expires_at,
+    })))
+}
+
+/// Serve an attachment through a URL issued by `sign_message_attachment`.
+pub async fn serve_signed_attachment(
+    State(deployment): State<DeploymentImpl>,
+    Path((message_id, attachment_id)): Path<(Uuid, Uuid)>,
+    Query(query): Query<SignedAttachmentQuery>,
+) -> Result<Response, ApiError> {
+    check_attachment_access(
+        message_id,
+        attachment_id,
+        &query,
+        chrono::Utc::now().timestamp(),
+    )?;
+
+    let message = ChatMessage::find_by_id(&deployment.db().pool, message_id)
+        .await?
+        .ok_or(ApiError::Database(sqlx::Error::RowNotFound))?;
+
+    stream_attachment(&message, attachment_id).await
+}
+
+/// Per-process signing key, so issued URLs stop working when the server restarts.
+fn attachment_signing_key() -> &'static [u8; 32] {
+    static KEY: OnceLock<[u8; 32]> = OnceLock::new();
+    KEY.get_or_init(|| {
+        let mut key = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut key);
+        key
+    })
+}
+
+fn attachment_access_mac(message_id: Uuid, attachment_id: Uuid, expires_at: i64) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(attachment_signing_key())
+        .expect("HMAC accepts keys of any length");
+    mac.update(format!("{message_id}:{attachment_id}:{expires_at}").as_bytes());
+    mac
+}
+
+fn sign_attachment_access(message_id: Uuid, attachment_id: Uuid, expires_at: i64) -> String {
+    let tag = attachment_access_mac(message_id, attachment_id, expires_at)
+        .finalize()
+        .into_bytes();
+    URL_SAFE_NO_PAD.encode(tag)
+}
+
+/// Reject expired links and signatures not issued for this message and attachment.
+fn check_attachment_access(
+    message_id: Uuid,
+    attachment_id: Uuid,
+    query: &SignedAttachmentQuery,
+    now: i64,
+) -> Result<(), ApiError> {
+    if query.expires < now {
+        return Err(ApiError::Forbidden("Attachment link expired".to_string()));
+    }
+    if !verify_attachment_access(message_id, attachment_id, query.expires, &query.signature) {
+        return Err(ApiError::Forbidden(
+            "Invalid attachment signature".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+fn verify_attachment_access(
+    message_id: Uuid,
+    attachment_id: Uuid,
+    expires_at: i64,
+    signature: &str,
+) -> bool {
+    let Ok(tag) = URL_SAFE_NO_PAD.decode(signature) else {
+        return false;
+    };
+    attachment_access_mac(message_id, attachment_id, expires_at)
+        .verify_slice(&tag)
+        .is_ok()
+}
+
+async fn stream_attachment(
+    message: &ChatMessage,
+    attachment_id: Uuid,
+) -> Result<Response, ApiError> {
     let attachments = services::services::chat::extract_attachments(&message.meta.0);
     let attachment = attachments
         .into_iter()
         .find(|item| item.id == attachment_id)
         .ok_or_else(|| ApiError::BadRequest("Attachment not found".to_string()))?;
 
-    let Some(path) = resolve_relative_path(&attachment.relative_path) else {
-        return Err(ApiError::BadRequest("Invalid attachment path".to_string()));
-    };
-
+    let path = asset_dir().join(attachment.relative_path(message.session_id, message.id));
     let file = File::open(&path).await?;
     let metadata = file.metadata().await?;
     let stream = ReaderStream::new(file);
@@ -285,7 +603,7 @@ pub async fn serve_message_attachment(
         .as_deref()
         .unwrap_or("application/octet-stream");
 
-    let header_name = sanitize_filename(&attachment.name);
+    let header_name = sanitize_attachment_filename(&attachment.name);
     let response = Response::builder()
         .status(StatusCode::OK)
         .header(header::CONTENT_TYPE, content_type)
@@ -322,6 +640,103 @@ pub async fn delete_message(
     }
 }
 
+/// Delete every message strictly between the `after` and `before` anchors, skipping
+/// pinned messages. Returns the number of messages deleted.
+pub async fn delete_messages_range(
+    Extension(session): Extension<ChatSession>,
+    State(deployment): State<DeploymentImpl>,
+    Query(query): Query<DeleteMessagesRangeQuery>,
+) -> Result<ResponseJson<ApiResponse<u64>>, ApiError> {
+    if query.before.is_none() && query.after.is_none() {
+        return Err(ApiError::BadRequest(
+            "Specify at least one of `before` or `after`".to_string(),
+        ));
+    }
+
+    let pool = &deployment.db().pool;
+    let messages = ChatMessage::find_by_session_id(pool, session.id, None).await?;
+    let anchor_index = |anchor: Uuid| {
+        messages
+            .iter()
+            .position(|message| message.id == anchor)
+            .ok_or_else(|| ApiError::BadRequest(format!("Message {anchor} is not in this session")))
+    };
+    let end = match query.before {
+        Some(before) => anchor_index(before)?,
+        None => messages.len(),
+    };
+    let start = match query.after {
+        Some(after) => anchor_index(after)? + 1,
+        None => 0,
+    };
+    if start >= end {
+        return Ok(ResponseJson(ApiResponse::success(0)));
+    }
+
+    let message_ids: Vec<Uuid> = messages[start..end]
+        .iter()
+        .filter(|message| !services::services::chat::is_message_pinned(&message.meta.0))
+        .map(|message| message.id)
+        .collect();
+    let deleted = ChatMessage::delete_many_in_session(pool, session.id, &message_ids).await?;
+    let deleted_count = deleted.len() as u64;
+    if !deleted.is_empty() {
+        services::services::chat::invalidate_compression_cache(pool, session.id).await?;
+        deployment
+            .chat_runner()
+            .emit_messages_deleted(session.id, deleted);
+    }
+
+    Ok(ResponseJson(ApiResponse::success(deleted_count)))
+}
+
+/// Pin a message so range deletes skip it.
+pub async fn pin_message(
+    Extension(session): Extension<ChatSession>,
+    State(deployment): State<DeploymentImpl>,
+    Path((_session_id, message_id)): Path<(Uuid, Uuid)>,
+) -> Result<ResponseJson<ApiResponse<ChatMessage>>, ApiError> {
+    set_message_pinned(&session, &deployment, message_id, true).await
+}
+
+pub async fn unpin_message(
+    Extension(session): Extension<ChatSession>,
+    State(deployment): State<DeploymentImpl>,
+    Path((_session_id, message_id)): Path<(Uuid, Uuid)>,
+) -> Result<ResponseJson<ApiResponse<ChatMessage>>, ApiError> {
+    set_message_pinned(&session, &deployment, message_id, false).await
+}
+
+async fn set_message_pinned(
+    session: &ChatSession,
+    deployment: &DeploymentImpl,
+    message_id: Uuid,
+    pinned: bool,
+) -> Result<ResponseJson<ApiResponse<ChatMessage>>, ApiError> {
+    let pool = &deployment.db().pool;
+    let message = ChatMessage::find_by_id(pool, message_id)
+        .await?
+        .filter(|message| message.session_id == session.id)
+        .ok_or(ApiError::Database(sqlx::Error::RowNotFound))?;
+
+    // Unpinning removes the key rather than storing `false`.
+    let value = if pinned {
+        serde_json::Value::Bool(true)
+    } else {
+        serde_json::Value::Null
+    };
+    ChatMessage::merge_meta(pool, message.id, serde_json::json!({ PINNED_KEY: value })).await?;
+
+    let updated = ChatMessage::find_by_id(pool, message.id)
+        .await?
+        .ok_or(ApiError::Database(sqlx::Error::RowNotFound))?;
+    deployment
+        .chat_runner()
+        .emit_message_updated(session.id, updated.clone());
+
+    Ok(ResponseJson(ApiResponse::success(updated)))
+}
+
 /// Delete multiple messages at once
 pub async fn delete_messages_batch(
     Extension(session): Extension<ChatSession>,
@@ -345,3 +760,74 @@ pub async fn delete_messages_batch(
 
     Ok(ResponseJson(ApiResponse::success(total_deleted)))
 }
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::{SignedAttachmentQuery, check_attachment_access, sign_attachment_access};
+    use crate::error::ApiError;
+
+    fn signed_query(message_id: Uuid, attachment_id: Uuid, expires: i64) -> SignedAttachmentQuery {
+        SignedAttachmentQuery {
+            expires,
+            signature: sign_attachment_access(message_id, attachment_id, expires),
+        }
+    }
+
+    #[test]
+    fn valid_signature_grants_access_until_expiry() {
+        let (message_id, attachment_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let query = signed_query(message_id, attachment_id, 1_000);
+
+        assert!(check_attachment_access(message_id, attachment_id, &query, 900).is_ok());
+        assert!(check_attachment_access(message_id, attachment_id, &query, 1_000).is_ok());
+    }
+
+    #[test]
+    fn expired_link_is_rejected() {
+        let (message_id, attachment_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let query = signed_query(message_id, attachment_id, 1_000);
+
+        assert!(matches!(
+            check_attachment_access(message_id, attachment_id, &query, 1_001),
+            Err(ApiError::Forbidden(_))
+        ));
+    }
+
+    #[test]
+    fn tampered_signature_or_path_is_rejected() {
+        let (message_id, attachment_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let query = signed_query(message_id, attachment_id, 1_000);
+        let rejected = |message_id, attachment_id, query: &SignedAttachmentQuery| {
+            matches!(
+                check_attachment_access(message_id, attachment_id, query, 900),
+                Err(ApiError::Forbidden(_))
+            )
+        };
+
+        // Signed for another attachment or message.
+        assert!(rejected(message_id, Uuid::new_v4(), &query));
+        assert!(rejected(Uuid::new_v4(), attachment_id, &query));
+        // Expiry pushed back without re-signing.
+        let extended = SignedAttachmentQuery {
+            expires: 5_000,
+            signature: query.signature.clone(),
+        };
+        assert!(rejected(message_id, attachment_id, &extended));
+        // Altered or malformed signature.
+        let mut altered = query.signature.clone();
+        let last = altered.pop().unwrap();
+        altered.push(if last == 'A' { 'B' } else { 'A' });
+        let altered = SignedAttachmentQuery {
+            expires: 1_000,
+            signature: altered,
+        };
+        assert!(rejected(message_id, attachment_id, &altered));
+        let malformed = SignedAttachmentQuery {
+            expires: 1_000,
+            signature: "not base64!".to_string(),
+        };
+        assert!(rejected(message_id, attachment_id, &malformed));
+    }
+}
@@ -1,5 +1,10 @@
 pub mod agents;
+pub mod compression;
+pub mod debug;
+pub mod executors;
+pub mod exports;
 pub mod messages;
+pub mod presets;
 pub mod runs;
 pub mod sessions;
 
@@ -10,6 +15,9 @@ use crate::{
     middleware::{load_chat_agent_middleware, load_chat_session_middleware},
 };
 
+/// Prefix of the chat routes within the API router.
+pub const CHAT_PREFIX: &str = "/chat";
+
 pub fn router(deployment: &DeploymentImpl) -> Router<DeploymentImpl> {
     let session_router = Router::new()
         .route(
@@ -20,23 +28,102 @@ pub fn router(deployment: &DeploymentImpl) -> Router<DeploymentImpl> {
         )
         .route("/archive", axum::routing::post(sessions::archive_session))
         .route("/restore", axum::routing::post(sessions::restore_session))
+        .route("/replay", axum::routing::post(sessions::replay_session))
+        .route(
+            "/stop-all",
+            axum::routing::post(sessions::stop_all_session_agents),
+        )
+        .route(
+            "/apply-team/{team_id}",
+            axum::routing::post(sessions::apply_team_preset),
+        )
         .route("/stream", get(sessions::stream_session_ws))
+        .route("/stream/stats", get(sessions::get_session_stream_stats))
         .route(
             "/agents",
             get(sessions::get_session_agents).post(sessions::create_session_agent),
         )
+        .route("/members", get(sessions::get_session_members))
+        .route("/cutoffs", get(sessions::get_session_cutoffs))
+        .route("/compact", axum::routing::post(sessions::compact_session))
+        .route(
+            "/compact/preview",
+            get(sessions::preview_session_compaction),
+        )
+        .route(
+            "/summary-agent",
+            get(sessions::preview_session_summary_agent),
+        )
+        .route("/cutoffs/{index}", get(sessions::get_session_cutoff))
+        .route("/events/export", get(sessions::export_session_events))
+        .route(
+            "/export",
+            axum::routing::post(exports::start_session_export),
+        )
+        .route(
+            "/estimate-tokens",
+            axum::routing::post(sessions::estimate_session_tokens),
+        )
+        .route(
+            "/moderator",
+            get(sessions::get_session_moderator).put(sessions::update_session_moderator),
+        )
+        .route(
+            "/default-agent",
+            get(sessions::get_session_default_agent).put(sessions::update_session_default_agent),
+        )
+        .route(
+            "/context-format",
+            get(sessions::get_session_context_format).put(sessions::update_session_context_format),
+        )
+        .route(
+            "/executor-profile",
+            get(sessions::get_session_executor_profile)
+                .put(sessions::update_session_executor_profile),
+        )
+        .route(
+            "/translation",
+            get(sessions::get_session_translation).put(sessions::update_session_translation),
+        )
+        .route(
+            "/agents/order",
+            axum::routing::put(sessions::reorder_session_agents),
+        )
         .route(
             "/agents/{session_agent_id}",
             axum::routing::put(sessions::update_session_agent)
                 .delete(sessions::delete_session_agent),
         )
+        .route(
+            "/agents/{session_agent_id}/activity",
+            get(sessions::get_session_agent_activity),
+        )
         .route(
             "/agents/{session_agent_id}/stop",
             axum::routing::post(sessions::stop_session_agent),
         )
+        .route(
+            "/agents/{session_agent_id}/mute",
+            axum::routing::post(sessions::mute_session_agent),
+        )
+        .route(
+            "/agents/{session_agent_id}/unmute",
+            axum::routing::post(sessions::unmute_session_agent),
+        )
+        .route(
+            "/agents/{session_agent_id}/reset",
+            axum::routing::post(sessions::reset_session_agent),
+        )
+        .route("/runs", get(runs::get_session_runs))
         .route(
             "/messages",
-            get(messages::get_messages).post(messages::create_message),
+            get(messages::get_messages)
+                .post(messages::create_message)
+                .delete(messages::delete_messages_range),
+        )
+        .route(
+            "/messages/as-agent",
+            axum::routing::post(messages::create_agent_message),
         )
         .route(
             "/messages/batch-delete",
@@ -47,10 +134,22 @@ pub fn router(deployment: &DeploymentImpl) -> Router<DeploymentImpl> {
             axum::routing::post(messages::upload_message_attachments)
                 .layer(DefaultBodyLimit::max(25 * 1024 * 1024)),
         )
+        .route(
+            "/messages/{message_id}/pin",
+            axum::routing::post(messages::pin_message).delete(messages::unpin_message),
+        )
+        .route(
+            "/messages/{message_id}/continue",
+            axum::routing::post(messages::continue_message),
+        )
         .route(
             "/messages/{message_id}/attachments/{attachment_id}",
             get(messages::serve_message_attachment),
         )
+        .route(
+            "/messages/{message_id}/attachments/{attachment_id}/sign",
+            axum::routing::post(messages::sign_message_attachment),
+        )
         .layer(from_fn_with_state(
             deployment.clone(),
             load_chat_session_middleware,
@@ -70,6 +169,11 @@ pub fn router(deployment: &DeploymentImpl) -> Router<DeploymentImpl> {
                 .put(agents::update_agent)
                 .delete(agents::delete_agent),
         )
+        .route("/prompt-history", get(agents::get_agent_prompt_history))
+        .route(
+            "/prompt-history/{version_id}/revert",
+            axum::routing::post(agents::revert_agent_prompt),
+        )
         .layer(from_fn_with_state(
             deployment.clone(),
             load_chat_agent_middleware,
@@ -79,19 +183,60 @@ pub fn router(deployment: &DeploymentImpl) -> Router<DeploymentImpl> {
         .route("/", get(agents::get_agents).post(agents::create_agent))
         .nest("/{agent_id}", agent_router);
 
-    let messages_router = Router::new().route(
-        "/{message_id}",
-        get(messages::get_message).delete(messages::delete_message),
-    );
+    let messages_router = Router::new()
+        .route(
+            "/{message_id}",
+            get(messages::get_message).delete(messages::delete_message),
+        )
+        .route(
+            "/{message_id}/attachments",
+            axum::routing::post(messages::add_message_attachments)
+                .layer(DefaultBodyLimit::max(25 * 1024 * 1024)),
+        );
 
     Router::new().nest(
-        "/chat",
+        CHAT_PREFIX,
         Router::new()
             .nest("/sessions", sessions_router)
             .nest("/agents", agents_router)
             .nest("/messages", messages_router)
+            .route(
+                "/attachments/{message_id}/{attachment_id}",
+                get(messages::serve_signed_attachment),
+            )
+            .route(
+                "/compression-config",
+                axum::routing::put(compression::update_compression_config),
+            )
+            .route("/debug/summarize", axum::routing::post(debug::summarize))
+            .route("/exports/{job_id}", get(exports::get_export_job))
+            .route("/exports/{job_id}/download", get(exports::download_export))
+            .route(
+                "/executors/reload",
+                axum::routing::post(executors::reload_executors),
+            )
+            .route(
+                "/presets/{preset_id}/validate",
+                axum::routing::post(presets::validate_member_preset),
+            )
+            .route("/running", get(runs::get_running_agents))
+            .route("/stats", get(runs::get_run_stats))
             .route("/runs/{run_id}/log", get(runs::get_run_log))
+            .route("/runs/{run_id}/output", get(runs::get_run_output))
+            .route(
+                "/runs/{run_id}/tags",
+                axum::routing::post(runs::update_run_tags),
+            )
+            .route(
+                "/runs/{run_id}/approve",
+                axum::routing::post(runs::approve_run),
+            )
+            .route("/runs/{run_id}/deny", axum::routing::post(runs::deny_run))
             .route("/runs/{run_id}/diff", get(runs::get_run_diff))
+            .route(
+                "/runs/{run_id}/compare/{other_run_id}",
+                get(runs::compare_runs),
+            )
             .route(
                 "/runs/{run_id}/untracked",
                 get(runs::get_run_untracked_file),
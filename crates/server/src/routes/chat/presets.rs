@@ -0,0 +1,62 @@
+use axum::{
+    extract::{Path, State},
+    response::Json as ResponseJson,
+};
+use deployment::Deployment;
+use serde::Serialize;
+use services::services::chat_runner::ChatRunner;
+use ts_rs::TS;
+use utils::response::ApiResponse;
+
+use crate::{DeploymentImpl, error::ApiError};
+
+/// System prompts above this many tokens get a warning; they are sent with every run.
+const PRESET_PROMPT_WARN_TOKENS: u32 = 4000;
+
+#[derive(Debug, Serialize, TS)]
+pub struct ChatPresetPromptValidation {
+    pub preset_id: String,
+    pub is_builtin: bool,
+    /// tiktoken (cl100k) estimate of the preset's system prompt
+    pub token_estimate: u32,
+    pub warn_threshold: u32,
+    /// Set when `token_estimate` exceeds `warn_threshold`
+    pub warning: Option<String>,
+}
+
+/// Estimate the size of a member preset's system prompt, built-in or custom.
+pub async fn validate_member_preset(
+    State(deployment): State<DeploymentImpl>,
+    Path(preset_id): Path<String>,
+) -> Result<ResponseJson<ApiResponse<ChatPresetPromptValidation>>, ApiError> {
+    let preset = deployment
+        .config()
+        .read()
+        .await
+        .chat_presets
+        .members
+        .iter()
+        .find(|preset| preset.id == preset_id)
+        .cloned()
+        .ok_or_else(|| ApiError::BadRequest(format!("Member preset '{preset_id}' not found")))?;
+
+    let token_estimate = ChatRunner::estimate_tokens_with_tiktoken(&preset.system_prompt);
+    let warning = (token_estimate > PRESET_PROMPT_WARN_TOKENS).then(|| {
+        format!(
+            "System prompt of '{}' is about {token_estimate} tokens, above the \
+             {PRESET_PROMPT_WARN_TOKENS} token guideline; it is sent with every run and \
+             leaves less room for context.",
+            preset.name
+        )
+    });
+
+    Ok(ResponseJson(ApiResponse::success(
+        ChatPresetPromptValidation {
+            preset_id: preset.id,
+            is_builtin: preset.is_builtin,
+            token_estimate,
+            warn_threshold: PRESET_PROMPT_WARN_TOKENS,
+            warning,
+        },
+    )))
+}
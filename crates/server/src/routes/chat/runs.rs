@@ -1,17 +1,198 @@
 use std::path::PathBuf;
 
 use axum::{
+    Extension, Json,
     extract::{Path, Query, State},
     http::header::CONTENT_TYPE,
-    response::{IntoResponse, Response},
+    response::{IntoResponse, Json as ResponseJson, Response},
+};
+use db::models::{
+    chat_run::{ChatRun, ChatRunWithTags},
+    chat_run_stats::{ChatRunStats, ChatRunStatsGroupBy},
+    chat_session::ChatSession,
 };
-use db::models::chat_run::ChatRun;
 use deployment::Deployment;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use services::services::chat_runner::RunningAgent;
+use ts_rs::TS;
+use utils::{approvals::ApprovalStatus, response::ApiResponse};
 use uuid::Uuid;
 
 use crate::{DeploymentImpl, error::ApiError};
 
+const MAX_RUN_TAGS: usize = 32;
+const MAX_RUN_TAG_LEN: usize = 64;
+
+#[derive(Debug, Deserialize, TS)]
+pub struct UpdateChatRunTags {
+    /// Complete tag set for the run; duplicates and blank tags are dropped
+    pub tags: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize, TS)]
+pub struct DenyChatRunApproval {
+    /// Passed back to the executor as the denial reason
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RunDiffQuery {
+    /// `structured` returns parsed hunks as JSON instead of the raw patch
+    pub format: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, TS)]
+#[serde(rename_all = "snake_case")]
+pub enum RunDiffLineKind {
+    Context,
+    Added,
+    Removed,
+}
+
+#[derive(Debug, Serialize, TS)]
+pub struct RunDiffLine {
+    pub kind: RunDiffLineKind,
+    /// Line text without its leading ` `, `+` or `-`
+    pub content: String,
+}
+
+#[derive(Debug, Serialize, TS)]
+pub struct RunDiffHunk {
+    pub old_start: u32,
+    pub old_lines: u32,
+    pub new_start: u32,
+    pub new_lines: u32,
+    /// Text after the closing `@@`, usually the enclosing function
+    pub section: String,
+    pub lines: Vec<RunDiffLine>,
+}
+
+#[derive(Debug, Default, Serialize, TS)]
+pub struct RunDiffFile {
+    /// Path before the change; null for added files
+    pub old_path: Option<String>,
+    /// Path after the change; null for deleted files
+    pub new_path: Option<String>,
+    pub hunks: Vec<RunDiffHunk>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChatRunListQuery {
+    pub tag: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChatRunStatsQuery {
+    #[serde(default)]
+    pub group_by: ChatRunStatsGroupBy,
+}
+
+fn normalize_run_tags(tags: Vec<String>) -> Result<Vec<String>, ApiError> {
+    let mut normalized: Vec<String> = Vec::new();
+    for tag in tags {
+        let tag = tag.trim();
+        if tag.is_empty() || normalized.iter().any(|existing| existing == tag) {
+            continue;
+        }
+        if tag.chars().count() > MAX_RUN_TAG_LEN {
+            return Err(ApiError::BadRequest(format!(
+                "Run tags must be at most {MAX_RUN_TAG_LEN} characters."
+            )));
+        }
+        normalized.push(tag.to_string());
+    }
+    if normalized.len() > MAX_RUN_TAGS {
+        return Err(ApiError::BadRequest(format!(
+            "A run can have at most {MAX_RUN_TAGS} tags."
+        )));
+    }
+    Ok(normalized)
+}
+
+pub async fn update_run_tags(
+    State(deployment): State<DeploymentImpl>,
+    Path(run_id): Path<Uuid>,
+    Json(payload): Json<UpdateChatRunTags>,
+) -> Result<ResponseJson<ApiResponse<ChatRunWithTags>>, ApiError> {
+    let pool = &deployment.db().pool;
+    let Some(run) = ChatRun::find_by_id(pool, run_id).await? else {
+        return Err(ApiError::BadRequest("Chat run not found".to_string()));
+    };
+
+    let tags = normalize_run_tags(payload.tags)?;
+    ChatRun::set_tags(pool, run.id, &tags).await?;
+    Ok(ResponseJson(ApiResponse::success(ChatRunWithTags {
+        run,
+        tags,
+    })))
+}
+
+pub async fn approve_run(
+    State(deployment): State<DeploymentImpl>,
+    Path(run_id): Path<Uuid>,
+) -> Result<ResponseJson<ApiResponse<()>>, ApiError> {
+    let Some(run) = ChatRun::find_by_id(&deployment.db().pool, run_id).await? else {
+        return Err(ApiError::BadRequest("Chat run not found".to_string()));
+    };
+    deployment.chat_runner().respond_to_approval(
+        run.session_id,
+        run.id,
+        ApprovalStatus::Approved,
+    )?;
+    Ok(ResponseJson(ApiResponse::success(())))
+}
+
+pub async fn deny_run(
+    State(deployment): State<DeploymentImpl>,
+    Path(run_id): Path<Uuid>,
+    payload: Option<Json<DenyChatRunApproval>>,
+) -> Result<ResponseJson<ApiResponse<()>>, ApiError> {
+    let Some(run) = ChatRun::find_by_id(&deployment.db().pool, run_id).await? else {
+        return Err(ApiError::BadRequest("Chat run not found".to_string()));
+    };
+    let reason = payload
+        .and_then(|Json(payload)| payload.reason)
+        .map(|reason| reason.trim().to_string())
+        .filter(|reason| !reason.is_empty());
+    deployment.chat_runner().respond_to_approval(
+        run.session_id,
+        run.id,
+        ApprovalStatus::Denied { reason },
+    )?;
+    Ok(ResponseJson(ApiResponse::success(())))
+}
+
+pub async fn get_session_runs(
+    Extension(session): Extension<ChatSession>,
+    State(deployment): State<DeploymentImpl>,
+    Query(query): Query<ChatRunListQuery>,
+) -> Result<ResponseJson<ApiResponse<Vec<ChatRunWithTags>>>, ApiError> {
+    let tag = query
+        .tag
+        .as_deref()
+        .map(str::trim)
+        .filter(|tag| !tag.is_empty());
+    let runs = ChatRun::find_by_session_with_tags(&deployment.db().pool, session.id, tag).await?;
+    Ok(ResponseJson(ApiResponse::success(runs)))
+}
+
+/// Run totals across all sessions, grouped by agent (default), session or day.
+pub async fn get_run_stats(
+    State(deployment): State<DeploymentImpl>,
+    Query(query): Query<ChatRunStatsQuery>,
+) -> Result<ResponseJson<ApiResponse<Vec<ChatRunStats>>>, ApiError> {
+    let stats = ChatRunStats::aggregate(&deployment.db().pool, query.group_by).await?;
+    Ok(ResponseJson(ApiResponse::success(stats)))
+}
+
+/// Members running in any session, for spotting load and stuck runs.
+pub async fn get_running_agents(
+    State(deployment): State<DeploymentImpl>,
+) -> Result<ResponseJson<ApiResponse<Vec<RunningAgent>>>, ApiError> {
+    let running = deployment.chat_runner().list_running_agents().await?;
+    Ok(ResponseJson(ApiResponse::success(running)))
+}
+
 pub async fn get_run_log(
     State(deployment): State<DeploymentImpl>,
     Path(run_id): Path<Uuid>,
@@ -36,14 +217,186 @@ pub async fn get_run_log(
     Ok(([(CONTENT_TYPE, "text/plain; charset=utf-8")], content).into_response())
 }
 
-pub async fn get_run_diff(
+/// Final output of a finished run, for clients that missed the live stream.
+#[derive(Debug, Serialize, TS)]
+pub struct ChatRunOutput {
+    pub run_id: Uuid,
+    /// Content of the run's `output.md`
+    pub output: String,
+    /// Parsed `meta.json`; null when the run has none or it cannot be read
+    #[ts(type = "JsonValue | null")]
+    pub meta: Option<serde_json::Value>,
+}
+
+/// Resolve a file recorded on a run, refusing paths that escape the run directory.
+async fn run_record_file(
+    run: &ChatRun,
+    recorded: Option<&str>,
+    file_name: &str,
+) -> Option<PathBuf> {
+    let run_dir = tokio::fs::canonicalize(&run.run_dir).await.ok()?;
+    let path = recorded
+        .map(PathBuf::from)
+        .unwrap_or_else(|| run_dir.join(file_name));
+    let path = tokio::fs::canonicalize(&path).await.ok()?;
+    path.starts_with(&run_dir).then_some(path)
+}
+
+async fn read_run_output(run: &ChatRun) -> Result<ChatRunOutput, ApiError> {
+    let Some(output_path) = run_record_file(run, run.output_path.as_deref(), "output.md").await
+    else {
+        return Err(ApiError::BadRequest(
+            "Chat run output file not found".to_string(),
+        ));
+    };
+    let output = tokio::fs::read_to_string(&output_path)
+        .await
+        .map_err(|_| ApiError::BadRequest("Chat run output file not found".to_string()))?;
+
+    let meta = match run_record_file(run, run.meta_path.as_deref(), "meta.json").await {
+        Some(meta_path) => tokio::fs::read_to_string(&meta_path)
+            .await
+            .ok()
+            .and_then(|raw| serde_json::from_str::<serde_json::Value>(&raw).ok()),
+        None => None,
+    };
+
+    Ok(ChatRunOutput {
+        run_id: run.id,
+        output,
+        meta,
+    })
+}
+
+pub async fn get_run_output(
     State(deployment): State<DeploymentImpl>,
     Path(run_id): Path<Uuid>,
-) -> Result<Response, ApiError> {
+) -> Result<ResponseJson<ApiResponse<ChatRunOutput>>, ApiError> {
     let Some(run) = ChatRun::find_by_id(&deployment.db().pool, run_id).await? else {
         return Err(ApiError::BadRequest("Chat run not found".to_string()));
     };
 
+    Ok(ResponseJson(ApiResponse::success(
+        read_run_output(&run).await?,
+    )))
+}
+
+/// `a/src/lib.rs` -> `src/lib.rs`; `/dev/null` -> none.
+fn diff_path(raw: &str, prefix: &str) -> Option<String> {
+    let raw = raw.split('\t').next().unwrap_or(raw).trim();
+    if raw == "/dev/null" {
+        return None;
+    }
+    Some(raw.strip_prefix(prefix).unwrap_or(raw).to_string())
+}
+
+/// `-12,3` -> (12, 3); a missing count means one line.
+fn parse_hunk_range(range: &str) -> Option<(u32, u32)> {
+    let (start, count) = match range.split_once(',') {
+        Some((start, count)) => (start, count.parse().ok()?),
+        None => (range, 1),
+    };
+    Some((start.parse().ok()?, count))
+}
+
+/// `@@ -1,4 +1,5 @@ fn main()`
+fn parse_hunk_header(line: &str) -> Option<RunDiffHunk> {
+    let rest = line.strip_prefix("@@ ")?;
+    let (ranges, section) = rest.split_once(" @@")?;
+    let (old, new) = ranges.split_once(' ')?;
+    let (old_start, old_lines) = parse_hunk_range(old.strip_prefix('-')?)?;
+    let (new_start, new_lines) = parse_hunk_range(new.strip_prefix('+')?)?;
+    Some(RunDiffHunk {
+        old_start,
+        old_lines,
+        new_start,
+        new_lines,
+        section: section.trim().to_string(),
+        lines: Vec::new(),
+    })
+}
+
+/// Minimal unified diff parser covering what `git diff` writes for a run. Binary and
+/// mode-only changes yield files without hunks.
+fn parse_unified_diff(patch: &str) -> Vec<RunDiffFile> {
+    let mut files: Vec<RunDiffFile> = Vec::new();
+    // Lines of the current hunk still expected on the old and new side.
+    let mut remaining = (0u32, 0u32);
+
+    for line in patch.lines() {
+        if remaining.0 > 0 || remaining.1 > 0 {
+            let Some(hunk) = files.last_mut().and_then(|file| file.hunks.last_mut()) else {
+                remaining = (0, 0);
+                continue;
+            };
+            let (kind, content) = match line.chars().next() {
+                Some('+') => (RunDiffLineKind::Added, &line[1..]),
+                Some('-') => (RunDiffLineKind::Removed, &line[1..]),
+                Some(' ') => (RunDiffLineKind::Context, &line[1..]),
+                Some('\\') => continue,
+                // Some tools drop the space of empty context lines.
+                None => (RunDiffLineKind::Context, ""),
+                Some(_) => {
+                    remaining = (0, 0);
+                    continue;
+                }
+            };
+            match kind {
+                RunDiffLineKind::Added => remaining.1 = remaining.1.saturating_sub(1),
+                RunDiffLineKind::Removed => remaining.0 = remaining.0.saturating_sub(1),
+                RunDiffLineKind::Context => {
+                    remaining.0 = remaining.0.saturating_sub(1);
+                    remaining.1 = remaining.1.saturating_sub(1);
+                }
+            }
+            hunk.lines.push(RunDiffLine {
+                kind,
+                content: content.to_string(),
+            });
+            continue;
+        }
+
+        if let Some(paths) = line.strip_prefix("diff --git ") {
+            let (old, new) = paths.split_once(" b/").unwrap_or((paths, ""));
+            files.push(RunDiffFile {
+                old_path: diff_path(old, "a/"),
+                new_path: (!new.is_empty()).then(|| new.to_string()),
+                hunks: Vec::new(),
+            });
+        } else if let Some(old) = line.strip_prefix("--- ") {
+            // Plain unified diffs have no `diff --git` line, and a new file starts here.
+            if files.last().is_none_or(|file| !file.hunks.is_empty()) {
+                files.push(RunDiffFile::default());
+            }
+            if let Some(file) = files.last_mut() {
+                file.old_path = diff_path(old, "a/");
+            }
+        } else if let Some(new) = line.strip_prefix("+++ ") {
+            if let Some(file) = files.last_mut() {
+                file.new_path = diff_path(new, "b/");
+            }
+        } else if line.starts_with("new file mode") {
+            if let Some(file) = files.last_mut() {
+                file.old_path = None;
+            }
+        } else if line.starts_with("deleted file mode") {
+            if let Some(file) = files.last_mut() {
+                file.new_path = None;
+            }
+        } else if line.starts_with("@@ ")
+            && let Some(hunk) = parse_hunk_header(line)
+            && let Some(file) = files.last_mut()
+        {
+            remaining = (hunk.old_lines, hunk.new_lines);
+            file.hunks.push(hunk);
+        }
+    }
+
+    files
+}
+
+/// Raw patch captured for a run, trying the current file name before older layouts.
+async fn read_run_diff(run: &ChatRun) -> Option<String> {
     let scoped_diff_path = PathBuf::from(&run.run_dir).join(format!(
         "session_agent_{}_run_{:04}_diff.patch",
         run.session_agent_id, run.run_index
@@ -51,22 +404,105 @@ pub async fn get_run_diff(
     let prefixed_diff_path =
         PathBuf::from(&run.run_dir).join(format!("run_{:04}_diff.patch", run.run_index));
     let legacy_diff_path = PathBuf::from(&run.run_dir).join("diff.patch");
-    let content = match tokio::fs::read_to_string(&scoped_diff_path).await {
-        Ok(content) => content,
-        Err(_) => match tokio::fs::read_to_string(&prefixed_diff_path).await {
-            Ok(content) => content,
-            Err(_) => match tokio::fs::read_to_string(&legacy_diff_path).await {
-                Ok(content) => content,
-                Err(_) => {
-                    return Err(ApiError::BadRequest(
-                        "Chat run diff file not found".to_string(),
-                    ));
-                }
-            },
-        },
+    for path in [scoped_diff_path, prefixed_diff_path, legacy_diff_path] {
+        if let Ok(content) = tokio::fs::read_to_string(&path).await {
+            return Some(content);
+        }
+    }
+    None
+}
+
+pub async fn get_run_diff(
+    State(deployment): State<DeploymentImpl>,
+    Path(run_id): Path<Uuid>,
+    Query(query): Query<RunDiffQuery>,
+) -> Result<Response, ApiError> {
+    let Some(run) = ChatRun::find_by_id(&deployment.db().pool, run_id).await? else {
+        return Err(ApiError::BadRequest("Chat run not found".to_string()));
     };
 
-    Ok(([(CONTENT_TYPE, "text/plain; charset=utf-8")], content).into_response())
+    let Some(content) = read_run_diff(&run).await else {
+        return Err(ApiError::BadRequest(
+            "Chat run diff file not found".to_string(),
+        ));
+    };
+
+    match query.format.as_deref().map(str::trim) {
+        None | Some("") | Some("raw") => {
+            Ok(([(CONTENT_TYPE, "text/plain; charset=utf-8")], content).into_response())
+        }
+        Some("structured") => {
+            Ok(ResponseJson(ApiResponse::success(parse_unified_diff(&content))).into_response())
+        }
+        Some(other) => Err(ApiError::BadRequest(format!(
+            "Unknown diff format '{other}'; expected 'raw' or 'structured'"
+        ))),
+    }
+}
+
+/// One run of a [`ChatRunComparison`].
+#[derive(Debug, Serialize, TS)]
+pub struct ChatRunComparisonSide {
+    pub run_index: i64,
+    #[serde(flatten)]
+    #[ts(flatten)]
+    pub output: ChatRunOutput,
+    /// `token_usage` from the run's meta; null when it was not recorded
+    #[ts(type = "JsonValue | null")]
+    pub token_usage: Option<serde_json::Value>,
+    /// Parsed workspace diff; null when the run captured none
+    pub diff: Option<Vec<RunDiffFile>>,
+}
+
+/// Two runs of the same session agent side by side, e.g. before and after a prompt change.
+#[derive(Debug, Serialize, TS)]
+pub struct ChatRunComparison {
+    pub session_agent_id: Uuid,
+    pub base: ChatRunComparisonSide,
+    pub other: ChatRunComparisonSide,
+}
+
+async fn comparison_side(run: &ChatRun) -> Result<ChatRunComparisonSide, ApiError> {
+    let output = read_run_output(run).await?;
+    let token_usage = output
+        .meta
+        .as_ref()
+        .and_then(|meta| meta.get("token_usage"))
+        .filter(|usage| !usage.is_null())
+        .cloned();
+    let diff = read_run_diff(run)
+        .await
+        .map(|patch| parse_unified_diff(&patch));
+    Ok(ChatRunComparisonSide {
+        run_index: run.run_index,
+        output,
+        token_usage,
+        diff,
+    })
+}
+
+pub async fn compare_runs(
+    State(deployment): State<DeploymentImpl>,
+    Path((run_id, other_run_id)): Path<(Uuid, Uuid)>,
+) -> Result<ResponseJson<ApiResponse<ChatRunComparison>>, ApiError> {
+    let pool = &deployment.db().pool;
+    let Some(run) = ChatRun::find_by_id(pool, run_id).await? else {
+        return Err(ApiError::BadRequest("Chat run not found".to_string()));
+    };
+    let Some(other_run) = ChatRun::find_by_id(pool, other_run_id).await? else {
+        return Err(ApiError::BadRequest("Chat run not found".to_string()));
+    };
+    if run.session_agent_id != other_run.session_agent_id {
+        return Err(ApiError::BadRequest(
+            "Only runs of the same session agent can be compared".to_string(),
+        ));
+    }
+
+    Ok(ResponseJson(ApiResponse::success(ChatRunComparison {
+        session_agent_id: run.session_agent_id,
+        base: comparison_side(&run).await?,
+        other: comparison_side(&other_run).await?,
+    })))
 }
 
 #[derive(Debug, Deserialize)]
@@ -123,3 +559,117 @@ pub async fn get_run_untracked_file(
 
     Ok(([(CONTENT_TYPE, "text/plain; charset=utf-8")], content).into_response())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{RunDiffLineKind, parse_hunk_header, parse_unified_diff};
+
+    #[test]
+    fn parses_git_diff_with_added_deleted_and_binary_files() {
+        let patch = "\
+diff --git a/src/lib.rs b/src/lib.rs
+index 1111111..2222222 100644
+--- a/src/lib.rs
++++ b/src/lib.rs
+@@ -1,3 +1,3 @@ mod chat;
+ fn main() {
+-    old();
++    new();
+ }
+@@ -10 +10,2 @@
+ last
++appended
+\\ No newline at end of file
+diff --git a/notes.md b/notes.md
+new file mode 100644
+index 0000000..3333333
+--- /dev/null
++++ b/notes.md
+@@ -0,0 +1 @@
++hello
+diff --git a/old.txt b/old.txt
+deleted file mode 100644
+index 4444444..0000000
+--- a/old.txt
++++ /dev/null
+@@ -1 +0,0 @@
+-bye
+diff --git a/logo.png b/logo.png
+index 5555555..6666666 100644
+Binary files a/logo.png and b/logo.png differ
+";
+        let files = parse_unified_diff(patch);
+        assert_eq!(files.len(), 4);
+
+        let modified = &files[0];
+        assert_eq!(modified.old_path.as_deref(), Some("src/lib.rs"));
+        assert_eq!(modified.new_path.as_deref(), Some("src/lib.rs"));
+        assert_eq!(modified.hunks.len(), 2);
+        assert_eq!(modified.hunks[0].section, "mod chat;");
+        let kinds: Vec<RunDiffLineKind> = modified.hunks[0]
+            .lines
+            .iter()
+            .map(|line| line.kind)
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                RunDiffLineKind::Context,
+                RunDiffLineKind::Removed,
+                RunDiffLineKind::Added,
+                RunDiffLineKind::Context,
+            ]
+        );
+        assert_eq!(modified.hunks[0].lines[2].content, "    new();");
+        assert_eq!(
+            (modified.hunks[1].old_start, modified.hunks[1].old_lines),
+            (10, 1)
+        );
+        assert_eq!(modified.hunks[1].lines.len(), 2);
+
+        assert_eq!(files[1].old_path, None);
+        assert_eq!(files[1].new_path.as_deref(), Some("notes.md"));
+        assert_eq!(files[1].hunks[0].lines[0].content, "hello");
+        assert_eq!(files[2].old_path.as_deref(), Some("old.txt"));
+        assert_eq!(files[2].new_path, None);
+        assert_eq!(files[3].new_path.as_deref(), Some("logo.png"));
+        assert!(files[3].hunks.is_empty());
+    }
+
+    #[test]
+    fn parses_plain_unified_diff_with_bare_empty_context_lines() {
+        let patch = "\
+--- a/one.txt\t2026-01-01 00:00:00
++++ b/one.txt\t2026-01-02 00:00:00
+@@ -1,3 +1,3 @@
+ first
+
+-second
++SECOND
+--- a/two.txt
++++ b/two.txt
+@@ -1 +1 @@
+-x
++y
+";
+        let files = parse_unified_diff(patch);
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].old_path.as_deref(), Some("one.txt"));
+        assert_eq!(files[0].new_path.as_deref(), Some("one.txt"));
+        let lines = &files[0].hunks[0].lines;
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[1].kind, RunDiffLineKind::Context);
+        assert_eq!(lines[1].content, "");
+        assert_eq!(files[1].new_path.as_deref(), Some("two.txt"));
+        assert_eq!(files[1].hunks[0].lines.len(), 2);
+    }
+
+    #[test]
+    fn malformed_hunk_headers_are_ignored() {
+        assert!(parse_hunk_header("@@ -a,1 +1 @@").is_none());
+        assert!(parse_hunk_header("@@ -1 +1").is_none());
+        let files = parse_unified_diff("--- a/x\n+++ b/x\n@@ nonsense @@\n+ignored\n");
+        assert_eq!(files.len(), 1);
+        assert!(files[0].hunks.is_empty());
+    }
+}
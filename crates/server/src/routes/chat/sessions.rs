@@ -1,4 +1,4 @@
-use std::path::{Component, PathBuf};
+use std::collections::HashSet;
 
 use axum::{
     Extension, Json,
@@ -6,20 +6,33 @@ use axum::{
         Query, State,
         ws::{Message, WebSocket, WebSocketUpgrade},
     },
-    response::{IntoResponse, Json as ResponseJson},
+    http::header,
+    response::{IntoResponse, Json as ResponseJson, Response},
 };
 use db::models::{
-    chat_agent::ChatAgent,
+    chat_agent::{ChatAgent, CreateChatAgent},
+    chat_message::{ChatMessage, ChatSenderType},
+    chat_run::ChatRun,
     chat_session::{ChatSession, ChatSessionStatus, CreateChatSession, UpdateChatSession},
-    chat_session_agent::{ChatSessionAgent, CreateChatSessionAgent},
+    chat_session_agent::{ChatSessionAgent, ChatSessionAgentActivity, CreateChatSessionAgent},
 };
 use deployment::Deployment;
-use serde::Deserialize;
+use executors::profile::{ExecutorConfigs, ExecutorProfileId};
+use serde::{Deserialize, Serialize};
+use services::services::{
+    chat::{CompactionPreview, SummaryAgentPreview},
+    chat_runner::{
+        ChatCutoffArchive, ChatCutoffSummary, ChatStreamStats, SessionAgentSummary,
+        StopAllAgentsOutcome,
+    },
+    chat_workspace::validate_workspace_root,
+    config::ChatContextFormat,
+};
 use ts_rs::TS;
 use utils::{assets::asset_dir, response::ApiResponse};
 use uuid::Uuid;
 
-use crate::{DeploymentImpl, error::ApiError};
+use crate::{DeploymentImpl, error::ApiError, routes::chat::agents::validate_tools_enabled};
 
 #[derive(Debug, Deserialize, TS)]
 pub struct ChatSessionListQuery {
@@ -65,6 +78,7 @@ pub async fn delete_session(
     if rows_affected == 0 {
         Err(ApiError::Database(sqlx::Error::RowNotFound))
     } else {
+        deployment.chat_runner().forget_session(session.id);
         Ok(ResponseJson(ApiResponse::success(())))
     }
 }
@@ -80,77 +94,64 @@ pub struct UpdateChatSessionAgentRequest {
     pub workspace_path: Option<String>,
 }
 
-#[cfg(windows)]
-fn is_windows_reserved_name(name: &str) -> bool {
-    let upper = name.trim().trim_end_matches('.').to_ascii_uppercase();
-    matches!(
-        upper.as_str(),
-        "CON"
-            | "PRN"
-            | "AUX"
-            | "NUL"
-            | "COM1"
-            | "COM2"
-            | "COM3"
-            | "COM4"
-            | "COM5"
-            | "COM6"
-            | "COM7"
-            | "COM8"
-            | "COM9"
-            | "LPT1"
-            | "LPT2"
-            | "LPT3"
-            | "LPT4"
-            | "LPT5"
-            | "LPT6"
-            | "LPT7"
-            | "LPT8"
-            | "LPT9"
-    )
+/// Every member of the session, first to last.
+#[derive(Debug, Deserialize, TS)]
+pub struct ReorderChatSessionAgentsRequest {
+    pub session_agent_ids: Vec<Uuid>,
 }
 
-fn validate_workspace_path_legality(trimmed: &str) -> Result<PathBuf, ApiError> {
-    if trimmed.chars().any(|ch| ch == '\0' || ch.is_control()) {
-        return Err(ApiError::BadRequest(
-            "Workspace path contains invalid characters.".to_string(),
-        ));
-    }
+#[derive(Debug, Deserialize, TS)]
+pub struct ReplayChatSessionRequest {
+    pub title: Option<String>,
+    /// Agents to add to the new session; user messages are replayed against them
+    pub agent_ids: Vec<Uuid>,
+}
 
-    let parsed_path = PathBuf::from(trimmed);
-    if parsed_path
-        .components()
-        .any(|component| matches!(component, Component::ParentDir))
-    {
-        return Err(ApiError::BadRequest(
-            "Workspace path cannot contain '..'.".to_string(),
-        ));
-    }
+#[derive(Debug, Serialize, Deserialize, TS)]
+pub struct ChatSessionModerator {
+    pub moderator_agent_id: Option<Uuid>,
+}
 
-    #[cfg(windows)]
-    {
-        for component in parsed_path.components() {
-            if let Component::Normal(value) = component {
-                let segment = value.to_string_lossy();
-                if segment
-                    .chars()
-                    .any(|ch| matches!(ch, '<' | '>' | ':' | '"' | '|' | '?' | '*'))
-                {
-                    return Err(ApiError::BadRequest(
-                        "Workspace path contains invalid Windows filename characters.".to_string(),
-                    ));
-                }
+#[derive(Debug, Serialize, Deserialize, TS)]
+pub struct ChatSessionDefaultAgent {
+    /// Member that answers user messages mentioning nobody; unset keeps them unrouted
+    pub default_agent_id: Option<Uuid>,
+}
 
-                if is_windows_reserved_name(&segment) {
-                    return Err(ApiError::BadRequest(format!(
-                        "Workspace path contains reserved Windows name: {segment}"
-                    )));
-                }
-            }
-        }
-    }
+#[derive(Debug, Serialize, Deserialize, TS)]
+pub struct ChatSessionTranslation {
+    pub enabled: bool,
+    /// Target language for agent replies; defaults to the UI language when unset
+    pub target_language: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, TS)]
+pub struct ChatSessionContextFormat {
+    /// Session override of the history file format; unset uses the configured default
+    pub format: Option<ChatContextFormat>,
+}
+
+#[derive(Debug, Serialize, Deserialize, TS)]
+pub struct ChatSessionExecutorProfile {
+    /// Profile run by members whose agent has no runner type of its own
+    pub executor_profile: Option<ExecutorProfileId>,
+}
+
+#[derive(Debug, Deserialize, TS)]
+pub struct EstimateChatTokensRequest {
+    /// Draft message body that has not been sent yet
+    pub content: String,
+    /// Agent whose reported context window should be used; any agent when unset
+    pub agent_id: Option<Uuid>,
+}
 
-    Ok(parsed_path)
+#[derive(Debug, Serialize, TS)]
+pub struct ChatTokenEstimate {
+    pub context_tokens: u32,
+    pub draft_tokens: u32,
+    pub total_tokens: u32,
+    /// Context window last reported by an executor in this session, if any
+    pub model_context_window: Option<u32>,
 }
 
 async fn normalize_workspace_path(
@@ -160,29 +161,10 @@ async fn normalize_workspace_path(
         return Ok(None);
     };
 
-    let trimmed = raw_path.trim();
-    if trimmed.is_empty() {
-        return Err(ApiError::BadRequest(
-            "Workspace path is required.".to_string(),
-        ));
-    }
-
-    let parsed_path = validate_workspace_path_legality(trimmed)?;
-    let metadata = tokio::fs::metadata(&parsed_path)
+    let path = validate_workspace_root(&raw_path)
         .await
-        .map_err(|err| match err.kind() {
-            std::io::ErrorKind::NotFound => {
-                ApiError::BadRequest("Workspace path does not exist.".to_string())
-            }
-            _ => ApiError::BadRequest(format!("Workspace path is not accessible: {err}")),
-        })?;
-    if !metadata.is_dir() {
-        return Err(ApiError::BadRequest(
-            "Workspace path must be an existing directory.".to_string(),
-        ));
-    }
-
-    Ok(Some(trimmed.to_string()))
+        .map_err(|err| ApiError::BadRequest(err.to_string()))?;
+    Ok(Some(path.to_string_lossy().to_string()))
 }
 
 async fn session_has_duplicate_member_name(
@@ -216,6 +198,382 @@ pub async fn get_session_agents(
     Ok(ResponseJson(ApiResponse::success(agents)))
 }
 
+pub async fn get_session_members(
+    Extension(session): Extension<ChatSession>,
+    State(deployment): State<DeploymentImpl>,
+) -> Result<ResponseJson<ApiResponse<Vec<SessionAgentSummary>>>, ApiError> {
+    let members = deployment
+        .chat_runner()
+        .build_session_agent_summaries(session.id)
+        .await?;
+    Ok(ResponseJson(ApiResponse::success(members)))
+}
+
+pub async fn get_session_cutoffs(
+    Extension(session): Extension<ChatSession>,
+    State(deployment): State<DeploymentImpl>,
+) -> Result<ResponseJson<ApiResponse<Vec<ChatCutoffSummary>>>, ApiError> {
+    let cutoffs = deployment.chat_runner().list_cutoffs(session.id).await?;
+    Ok(ResponseJson(ApiResponse::success(cutoffs)))
+}
+
+#[derive(Debug, Deserialize, TS)]
+pub struct ChatCutoffQuery {
+    /// Member whose context directory holds the file; defaults to the first match
+    pub session_agent_id: Option<Uuid>,
+}
+
+pub async fn get_session_cutoff(
+    Extension(session): Extension<ChatSession>,
+    State(deployment): State<DeploymentImpl>,
+    axum::extract::Path((_session_id, index)): axum::extract::Path<(Uuid, u32)>,
+    Query(query): Query<ChatCutoffQuery>,
+) -> Result<ResponseJson<ApiResponse<ChatCutoffArchive>>, ApiError> {
+    let archive = deployment
+        .chat_runner()
+        .read_cutoff(session.id, index, query.session_agent_id)
+        .await?
+        .ok_or(ApiError::Database(sqlx::Error::RowNotFound))?;
+    Ok(ResponseJson(ApiResponse::success(archive)))
+}
+
+/// Copy this session's user messages into a new session and replay them in order.
+pub async fn replay_session(
+    Extension(session): Extension<ChatSession>,
+    State(deployment): State<DeploymentImpl>,
+    Json(payload): Json<ReplayChatSessionRequest>,
+) -> Result<ResponseJson<ApiResponse<ChatSession>>, ApiError> {
+    let pool = &deployment.db().pool;
+    if payload.agent_ids.is_empty() {
+        return Err(ApiError::BadRequest(
+            "At least one agent is required to replay a session".to_string(),
+        ));
+    }
+    let max_agents = deployment
+        .config()
+        .read()
+        .await
+        .chat_runner
+        .max_agents_per_session as usize;
+    if payload.agent_ids.len() > max_agents {
+        return Err(ApiError::BadRequest(format!(
+            "A session can have at most {max_agents} AI members."
+        )));
+    }
+
+    let mut agents = Vec::with_capacity(payload.agent_ids.len());
+    let mut seen_names = HashSet::new();
+    for agent_id in &payload.agent_ids {
+        let Some(agent) = ChatAgent::find_by_id(pool, *agent_id).await? else {
+            return Err(ApiError::BadRequest("Chat agent not found".to_string()));
+        };
+        if !seen_names.insert(agent.name.trim().to_lowercase()) {
+            return Err(ApiError::BadRequest(
+                "An AI member with this name already exists in this session.".to_string(),
+            ));
+        }
+        agents.push(agent);
+    }
+
+    let user_messages: Vec<ChatMessage> = ChatMessage::find_by_session_id(pool, session.id, None)
+        .await?
+        .into_iter()
+        .filter(|message| message.sender_type == ChatSenderType::User)
+        .collect();
+    if user_messages.is_empty() {
+        return Err(ApiError::BadRequest(
+            "Chat session has no user messages to replay".to_string(),
+        ));
+    }
+
+    let title = payload
+        .title
+        .filter(|title| !title.trim().is_empty())
+        .or_else(|| {
+            Some(format!(
+                "{} (replay)",
+                session.title.as_deref().unwrap_or("Untitled")
+            ))
+        });
+    let replay_session =
+        ChatSession::create(pool, &CreateChatSession { title }, Uuid::new_v4()).await?;
+
+    for agent in agents {
+        ChatSessionAgent::create(
+            pool,
+            &CreateChatSessionAgent {
+                session_id: replay_session.id,
+                agent_id: agent.id,
+                workspace_path: None,
+            },
+            Uuid::new_v4(),
+        )
+        .await?;
+    }
+
+    deployment
+        .chat_runner()
+        .spawn_session_replay(replay_session.clone(), user_messages);
+
+    Ok(ResponseJson(ApiResponse::success(replay_session)))
+}
+
+pub async fn get_session_moderator(
+    Extension(session): Extension<ChatSession>,
+    State(deployment): State<DeploymentImpl>,
+) -> Result<ResponseJson<ApiResponse<ChatSessionModerator>>, ApiError> {
+    let moderator_agent_id =
+        ChatSession::find_moderator_agent_id(&deployment.db().pool, session.id).await?;
+    Ok(ResponseJson(ApiResponse::success(ChatSessionModerator {
+        moderator_agent_id,
+    })))
+}
+
+pub async fn update_session_moderator(
+    Extension(session): Extension<ChatSession>,
+    State(deployment): State<DeploymentImpl>,
+    Json(payload): Json<ChatSessionModerator>,
+) -> Result<ResponseJson<ApiResponse<ChatSessionModerator>>, ApiError> {
+    if let Some(agent_id) = payload.moderator_agent_id
+        && ChatSessionAgent::find_by_session_and_agent(&deployment.db().pool, session.id, agent_id)
+            .await?
+            .is_none()
+    {
+        return Err(ApiError::BadRequest(
+            "Moderator must be a member of this session".to_string(),
+        ));
+    }
+
+    ChatSession::set_moderator_agent_id(
+        &deployment.db().pool,
+        session.id,
+        payload.moderator_agent_id,
+    )
+    .await?;
+    Ok(ResponseJson(ApiResponse::success(payload)))
+}
+
+pub async fn get_session_default_agent(
+    Extension(session): Extension<ChatSession>,
+    State(deployment): State<DeploymentImpl>,
+) -> Result<ResponseJson<ApiResponse<ChatSessionDefaultAgent>>, ApiError> {
+    let default_agent_id =
+        ChatSession::find_default_agent_id(&deployment.db().pool, session.id).await?;
+    Ok(ResponseJson(ApiResponse::success(
+        ChatSessionDefaultAgent { default_agent_id },
+    )))
+}
+
+pub async fn update_session_default_agent(
+    Extension(session): Extension<ChatSession>,
+    State(deployment): State<DeploymentImpl>,
+    Json(payload): Json<ChatSessionDefaultAgent>,
+) -> Result<ResponseJson<ApiResponse<ChatSessionDefaultAgent>>, ApiError> {
+    if let Some(agent_id) = payload.default_agent_id
+        && ChatSessionAgent::find_by_session_and_agent(&deployment.db().pool, session.id, agent_id)
+            .await?
+            .is_none()
+    {
+        return Err(ApiError::BadRequest(
+            "Default agent must be a member of this session".to_string(),
+        ));
+    }
+
+    ChatSession::set_default_agent_id(&deployment.db().pool, session.id, payload.default_agent_id)
+        .await?;
+    Ok(ResponseJson(ApiResponse::success(payload)))
+}
+
+pub async fn estimate_session_tokens(
+    Extension(session): Extension<ChatSession>,
+    State(deployment): State<DeploymentImpl>,
+    Json(payload): Json<EstimateChatTokensRequest>,
+) -> Result<ResponseJson<ApiResponse<ChatTokenEstimate>>, ApiError> {
+    let pool = &deployment.db().pool;
+    let context_tokens =
+        services::services::chat::estimate_context_tokens(pool, session.id).await?;
+    let draft_tokens = services::services::chat::estimate_draft_tokens(
+        services::services::config::RESERVED_USER_HANDLE,
+        &payload.content,
+    );
+    let model_context_window =
+        services::services::chat::find_model_context_window(pool, session.id, payload.agent_id)
+            .await?;
+
+    Ok(ResponseJson(ApiResponse::success(ChatTokenEstimate {
+        context_tokens,
+        draft_tokens,
+        total_tokens: context_tokens.saturating_add(draft_tokens),
+        model_context_window,
+    })))
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct CompactSessionQuery {
+    /// Summarize again instead of reusing the cached compression result
+    #[serde(default)]
+    pub force: bool,
+}
+
+/// Recompute the session's compacted context in the background. A forced recompute
+/// while another compaction runs is rejected with a conflict rather than dropped.
+pub async fn compact_session(
+    Extension(session): Extension<ChatSession>,
+    State(deployment): State<DeploymentImpl>,
+    Query(query): Query<CompactSessionQuery>,
+) -> Result<ResponseJson<ApiResponse<()>>, ApiError> {
+    deployment
+        .chat_runner()
+        .recompute_context_compaction(session.id, query.force)
+        .await?;
+    Ok(ResponseJson(ApiResponse::success(())))
+}
+
+/// Which messages the next compaction would archive; nothing is summarized or written.
+pub async fn preview_session_compaction(
+    Extension(session): Extension<ChatSession>,
+    State(deployment): State<DeploymentImpl>,
+) -> Result<ResponseJson<ApiResponse<CompactionPreview>>, ApiError> {
+    let preview =
+        services::services::chat::preview_compaction(&deployment.db().pool, session.id).await?;
+    Ok(ResponseJson(ApiResponse::success(preview)))
+}
+
+/// Which agent would summarize the history if compaction ran now, or the fallback it
+/// would take; nothing is run.
+pub async fn preview_session_summary_agent(
+    Extension(session): Extension<ChatSession>,
+    State(deployment): State<DeploymentImpl>,
+) -> Result<ResponseJson<ApiResponse<SummaryAgentPreview>>, ApiError> {
+    let preview =
+        services::services::chat::preview_summary_agent(&deployment.db().pool, session.id).await?;
+    Ok(ResponseJson(ApiResponse::success(preview)))
+}
+
+/// Session audit log as JSONL, one `SessionAuditEvent` per line, oldest first.
+pub async fn export_session_events(
+    Extension(session): Extension<ChatSession>,
+    State(deployment): State<DeploymentImpl>,
+) -> Result<Response, ApiError> {
+    let events =
+        services::services::chat::build_session_audit_log(&deployment.db().pool, session.id)
+            .await?;
+    let mut body = String::new();
+    for event in events {
+        body.push_str(&serde_json::to_string(&event).unwrap_or_default());
+        body.push('\n');
+    }
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/x-ndjson".to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!(
+                    "attachment; filename=\"session_{}_events.jsonl\"",
+                    session.id
+                ),
+            ),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+pub async fn get_session_translation(
+    Extension(session): Extension<ChatSession>,
+    State(deployment): State<DeploymentImpl>,
+) -> Result<ResponseJson<ApiResponse<ChatSessionTranslation>>, ApiError> {
+    let (enabled, target_language) =
+        ChatSession::find_translation_settings(&deployment.db().pool, session.id).await?;
+    Ok(ResponseJson(ApiResponse::success(ChatSessionTranslation {
+        enabled,
+        target_language,
+    })))
+}
+
+pub async fn update_session_translation(
+    Extension(session): Extension<ChatSession>,
+    State(deployment): State<DeploymentImpl>,
+    Json(payload): Json<ChatSessionTranslation>,
+) -> Result<ResponseJson<ApiResponse<ChatSessionTranslation>>, ApiError> {
+    let target_language = payload
+        .target_language
+        .map(|language| language.trim().to_string())
+        .filter(|language| !language.is_empty());
+
+    ChatSession::set_translation_settings(
+        &deployment.db().pool,
+        session.id,
+        payload.enabled,
+        target_language.clone(),
+    )
+    .await?;
+    Ok(ResponseJson(ApiResponse::success(ChatSessionTranslation {
+        enabled: payload.enabled,
+        target_language,
+    })))
+}
+
+pub async fn get_session_context_format(
+    Extension(session): Extension<ChatSession>,
+    State(deployment): State<DeploymentImpl>,
+) -> Result<ResponseJson<ApiResponse<ChatSessionContextFormat>>, ApiError> {
+    let format = ChatSession::find_context_format(&deployment.db().pool, session.id)
+        .await?
+        .and_then(|format| serde_json::from_value(serde_json::Value::String(format)).ok());
+    Ok(ResponseJson(ApiResponse::success(
+        ChatSessionContextFormat { format },
+    )))
+}
+
+pub async fn update_session_context_format(
+    Extension(session): Extension<ChatSession>,
+    State(deployment): State<DeploymentImpl>,
+    Json(payload): Json<ChatSessionContextFormat>,
+) -> Result<ResponseJson<ApiResponse<ChatSessionContextFormat>>, ApiError> {
+    let stored = payload
+        .format
+        .and_then(|format| serde_json::to_value(format).ok())
+        .and_then(|value| value.as_str().map(str::to_string));
+    ChatSession::set_context_format(&deployment.db().pool, session.id, stored.as_deref()).await?;
+    Ok(ResponseJson(ApiResponse::success(payload)))
+}
+
+pub async fn get_session_executor_profile(
+    Extension(session): Extension<ChatSession>,
+    State(deployment): State<DeploymentImpl>,
+) -> Result<ResponseJson<ApiResponse<ChatSessionExecutorProfile>>, ApiError> {
+    let executor_profile = ChatSession::find_executor_profile(&deployment.db().pool, session.id)
+        .await?
+        .and_then(|profile| serde_json::from_str(&profile).ok());
+    Ok(ResponseJson(ApiResponse::success(
+        ChatSessionExecutorProfile { executor_profile },
+    )))
+}
+
+pub async fn update_session_executor_profile(
+    Extension(session): Extension<ChatSession>,
+    State(deployment): State<DeploymentImpl>,
+    Json(payload): Json<ChatSessionExecutorProfile>,
+) -> Result<ResponseJson<ApiResponse<ChatSessionExecutorProfile>>, ApiError> {
+    if let Some(profile) = payload.executor_profile.as_ref()
+        && ExecutorConfigs::get_cached()
+            .get_coding_agent(profile)
+            .is_none()
+    {
+        return Err(ApiError::BadRequest(format!(
+            "Unknown executor profile {profile}"
+        )));
+    }
+    let stored = payload
+        .executor_profile
+        .as_ref()
+        .and_then(|profile| serde_json::to_string(profile).ok());
+    ChatSession::set_executor_profile(&deployment.db().pool, session.id, stored.as_deref()).await?;
+    Ok(ResponseJson(ApiResponse::success(payload)))
+}
+
 pub async fn create_session_agent(
     Extension(session): Extension<ChatSession>,
     State(deployment): State<DeploymentImpl>,
@@ -271,6 +629,21 @@ pub async fn create_session_agent(
         ));
     }
 
+    let max_agents = deployment
+        .config()
+        .read()
+        .await
+        .chat_runner
+        .max_agents_per_session as usize;
+    let member_count = ChatSessionAgent::find_all_for_session(&deployment.db().pool, session.id)
+        .await?
+        .len();
+    if member_count >= max_agents {
+        return Err(ApiError::BadRequest(format!(
+            "This session already has the maximum of {max_agents} AI members."
+        )));
+    }
+
     let created = ChatSessionAgent::create(
         &deployment.db().pool,
         &CreateChatSessionAgent {
@@ -284,6 +657,158 @@ pub async fn create_session_agent(
     Ok(ResponseJson(ApiResponse::success(created)))
 }
 
+/// Outcome of adding a team preset's members to a session.
+#[derive(Debug, Serialize, TS)]
+pub struct ApplyChatTeamResult {
+    /// Agents created because no agent had the member preset's name yet
+    pub created_agents: Vec<ChatAgent>,
+    /// Session members added for the team
+    pub added: Vec<ChatSessionAgent>,
+    /// Member presets skipped because the session already has a member with their name
+    pub skipped_preset_ids: Vec<String>,
+}
+
+/// Add every member preset of a team to the session, creating agents for presets that
+/// have none. Presets whose name is already a session member are skipped.
+pub async fn apply_team_preset(
+    Extension(session): Extension<ChatSession>,
+    State(deployment): State<DeploymentImpl>,
+    axum::extract::Path((_session_id, team_id)): axum::extract::Path<(Uuid, String)>,
+) -> Result<ResponseJson<ApiResponse<ApplyChatTeamResult>>, ApiError> {
+    if session.status != ChatSessionStatus::Active {
+        return Err(ApiError::Conflict("Chat session is archived".to_string()));
+    }
+
+    let (members, default_runner_type, max_agents) = {
+        let config = deployment.config().read().await;
+        let team = config
+            .chat_presets
+            .teams
+            .iter()
+            .find(|team| team.id == team_id)
+            .ok_or_else(|| ApiError::BadRequest(format!("Team preset '{team_id}' not found")))?;
+        let members = team
+            .member_ids
+            .iter()
+            .map(|member_id| {
+                config
+                    .chat_presets
+                    .members
+                    .iter()
+                    .find(|member| &member.id == member_id)
+                    .cloned()
+                    .ok_or_else(|| {
+                        ApiError::BadRequest(format!(
+                            "Member preset '{member_id}' of team '{team_id}' not found"
+                        ))
+                    })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        for member in &members {
+            if config.chat_runner.is_reserved_handle(&member.name) {
+                return Err(ApiError::BadRequest(format!(
+                    "\"{}\" is a reserved handle and cannot be used as an AI member name.",
+                    member.name.trim()
+                )));
+            }
+        }
+        (
+            members,
+            config.executor_profile.executor.to_string(),
+            config.chat_runner.max_agents_per_session as usize,
+        )
+    };
+
+    let pool = &deployment.db().pool;
+    let project_name = session.title.as_deref().map(str::trim).unwrap_or("");
+    let session_members = ChatSessionAgent::find_all_for_session(pool, session.id).await?;
+    let mut member_count = session_members.len();
+    let mut member_names: HashSet<String> = HashSet::new();
+    for member in session_members {
+        if let Some(agent) = ChatAgent::find_by_id(pool, member.agent_id).await? {
+            member_names.insert(agent.name.trim().to_lowercase());
+        }
+    }
+
+    let mut result = ApplyChatTeamResult {
+        created_agents: Vec::new(),
+        added: Vec::new(),
+        skipped_preset_ids: Vec::new(),
+    };
+    // Every member is checked before anything is created, so a rejected team leaves the
+    // session and the agent list untouched.
+    let mut planned = Vec::new();
+    for member in members {
+        let name_key = member.name.trim().to_lowercase();
+        if member_names.contains(&name_key) {
+            result.skipped_preset_ids.push(member.id);
+            continue;
+        }
+        if !project_name.is_empty() && project_name.to_lowercase() == name_key {
+            return Err(ApiError::BadRequest(
+                "AI member name cannot match the project name.".to_string(),
+            ));
+        }
+        if member_count >= max_agents {
+            return Err(ApiError::BadRequest(format!(
+                "This session already has the maximum of {max_agents} AI members."
+            )));
+        }
+        let workspace_path =
+            normalize_workspace_path(member.default_workspace_path.clone()).await?;
+        let existing = ChatAgent::find_by_name(pool, member.name.trim()).await?;
+        if existing.is_none() {
+            validate_tools_enabled(
+                (!member.tools_enabled.is_null()).then_some(&member.tools_enabled),
+            )
+            .await?;
+        }
+        member_names.insert(name_key);
+        member_count += 1;
+        planned.push((member, workspace_path, existing));
+    }
+
+    for (member, workspace_path, existing) in planned {
+        let name = member.name.trim();
+        let agent = match existing {
+            Some(agent) => agent,
+            None => {
+                let agent = ChatAgent::create(
+                    pool,
+                    &CreateChatAgent {
+                        name: name.to_string(),
+                        runner_type: member
+                            .runner_type
+                            .clone()
+                            .unwrap_or_else(|| default_runner_type.clone()),
+                        system_prompt: Some(member.system_prompt.clone()),
+                        tools_enabled: (!member.tools_enabled.is_null())
+                            .then(|| member.tools_enabled.clone()),
+                    },
+                    Uuid::new_v4(),
+                )
+                .await?;
+                result.created_agents.push(agent.clone());
+                agent
+            }
+        };
+
+        let added = ChatSessionAgent::create(
+            pool,
+            &CreateChatSessionAgent {
+                session_id: session.id,
+                agent_id: agent.id,
+                workspace_path,
+            },
+            Uuid::new_v4(),
+        )
+        .await?;
+        result.added.push(added);
+    }
+
+    Ok(ResponseJson(ApiResponse::success(result)))
+}
+
 pub async fn update_session_agent(
     Extension(session): Extension<ChatSession>,
     State(deployment): State<DeploymentImpl>,
@@ -336,6 +861,19 @@ pub async fn delete_session_agent(
     }
 
     let rows = ChatSessionAgent::delete(&deployment.db().pool, existing.id).await?;
+    deployment
+        .chat_runner()
+        .shutdown_persistent_process(existing.id);
+
+    // A removed member can no longer moderate forwards or answer unaddressed messages.
+    let pool = &deployment.db().pool;
+    if ChatSession::find_moderator_agent_id(pool, session.id).await? == Some(existing.agent_id) {
+        ChatSession::set_moderator_agent_id(pool, session.id, None).await?;
+    }
+    if ChatSession::find_default_agent_id(pool, session.id).await? == Some(existing.agent_id) {
+        ChatSession::set_default_agent_id(pool, session.id, None).await?;
+    }
+
     if rows == 0 {
         Err(ApiError::BadRequest(
             "Chat session agent not found".to_string(),
@@ -345,6 +883,34 @@ pub async fn delete_session_agent(
     }
 }
 
+pub async fn reorder_session_agents(
+    Extension(session): Extension<ChatSession>,
+    State(deployment): State<DeploymentImpl>,
+    Json(payload): Json<ReorderChatSessionAgentsRequest>,
+) -> Result<ResponseJson<ApiResponse<Vec<ChatSessionAgent>>>, ApiError> {
+    let pool = &deployment.db().pool;
+    let members: HashSet<Uuid> = ChatSessionAgent::find_all_for_session(pool, session.id)
+        .await?
+        .into_iter()
+        .map(|member| member.id)
+        .collect();
+    let ordered: HashSet<Uuid> = payload.session_agent_ids.iter().copied().collect();
+    if ordered.len() != payload.session_agent_ids.len() {
+        return Err(ApiError::BadRequest(
+            "Session agent ids must not repeat".to_string(),
+        ));
+    }
+    if ordered != members {
+        return Err(ApiError::BadRequest(
+            "Session agent ids must list every member of this session exactly once".to_string(),
+        ));
+    }
+
+    ChatSessionAgent::reorder(pool, session.id, &payload.session_agent_ids).await?;
+    let agents = ChatSessionAgent::find_all_for_session(pool, session.id).await?;
+    Ok(ResponseJson(ApiResponse::success(agents)))
+}
+
 pub async fn archive_session(
     Extension(session): Extension<ChatSession>,
     State(deployment): State<DeploymentImpl>,
@@ -375,6 +941,7 @@ pub async fn archive_session(
         },
     )
     .await?;
+    deployment.chat_runner().forget_session(session.id);
 
     Ok(ResponseJson(ApiResponse::success(updated)))
 }
@@ -402,45 +969,387 @@ pub async fn restore_session(
     Ok(ResponseJson(ApiResponse::success(updated)))
 }
 
+/// Control frames a client may send over the session stream socket.
+#[derive(Debug, Deserialize, TS)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum ChatStreamControl {
+    StopAgent { session_agent_id: Uuid },
+    SubscribeRun { run_id: Uuid },
+    UnsubscribeRun { run_id: Uuid },
+    // Limits the stream to these event types; an empty list restores every event.
+    FilterEvents { events: Vec<String> },
+}
+
+impl ChatStreamControl {
+    fn action(&self) -> &'static str {
+        match self {
+            Self::StopAgent { .. } => "stop_agent",
+            Self::SubscribeRun { .. } => "subscribe_run",
+            Self::UnsubscribeRun { .. } => "unsubscribe_run",
+            Self::FilterEvents { .. } => "filter_events",
+        }
+    }
+}
+
+/// Replies to control frames, interleaved with the regular stream events.
+#[derive(Debug, Serialize, TS)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ChatStreamControlReply {
+    ControlAck {
+        action: String,
+    },
+    ControlNack {
+        action: Option<String>,
+        error: String,
+    },
+}
+
+#[derive(Debug, Deserialize, TS)]
+pub struct ChatStreamQuery {
+    /// Comma-separated event types to receive, e.g. "message_new,agent_state"; all when
+    /// omitted
+    pub events: Option<String>,
+}
+
+/// Parse requested event types, rejecting names that are not stream event types. An empty
+/// set means every event is sent.
+fn parse_event_filter<'a>(
+    events: impl IntoIterator<Item = &'a str>,
+) -> Result<HashSet<String>, String> {
+    use services::services::chat_runner::ChatStreamEvent;
+
+    let mut filter = HashSet::new();
+    for event in events
+        .into_iter()
+        .map(str::trim)
+        .filter(|event| !event.is_empty())
+    {
+        if !ChatStreamEvent::EVENT_TYPES.contains(&event) {
+            return Err(format!("Unknown stream event type: {event}"));
+        }
+        filter.insert(event.to_string());
+    }
+    Ok(filter)
+}
+
+/// Heartbeat pings that may go unanswered before the stream socket is treated as dead.
+const MAX_UNANSWERED_PINGS: u32 = 2;
+
 pub async fn stream_session_ws(
     ws: WebSocketUpgrade,
     Extension(session): Extension<ChatSession>,
     State(deployment): State<DeploymentImpl>,
+    Query(query): Query<ChatStreamQuery>,
 ) -> Result<impl IntoResponse, ApiError> {
+    let event_filter = parse_event_filter(query.events.as_deref().unwrap_or_default().split(','))
+        .map_err(ApiError::BadRequest)?;
     let rx = deployment.chat_runner().subscribe(session.id);
 
     Ok(ws.on_upgrade(move |socket| async move {
-        if let Err(err) = handle_chat_stream_ws(socket, rx).await {
+        if let Err(err) =
+            handle_chat_stream_ws(socket, rx, deployment, session.id, event_filter).await
+        {
             tracing::warn!("chat stream ws closed: {}", err);
         }
     }))
 }
 
+/// Subscriber count and backlog of the session's event channel, for spotting clients that
+/// fall behind and lose events.
+pub async fn get_session_stream_stats(
+    Extension(session): Extension<ChatSession>,
+    State(deployment): State<DeploymentImpl>,
+) -> Result<ResponseJson<ApiResponse<ChatStreamStats>>, ApiError> {
+    let stats = deployment.chat_runner().stream_stats(session.id);
+    Ok(ResponseJson(ApiResponse::success(stats)))
+}
+
 async fn handle_chat_stream_ws(
     socket: WebSocket,
     mut rx: tokio::sync::broadcast::Receiver<services::services::chat_runner::ChatStreamEvent>,
+    deployment: DeploymentImpl,
+    session_id: Uuid,
+    mut event_filter: HashSet<String>,
 ) -> anyhow::Result<()> {
     use futures_util::{SinkExt, StreamExt};
+    use services::services::chat_runner::ChatStreamEvent;
 
     let (mut sender, mut receiver) = socket.split();
-    tokio::spawn(async move { while let Some(Ok(_)) = receiver.next().await {} });
+    // When non-empty, agent deltas and stderr lines are limited to these runs.
+    let mut subscribed_runs: HashSet<Uuid> = HashSet::new();
+    // Protocol-level pings keep idle proxies from dropping the socket; browsers answer them
+    // without surfacing a frame, so clients never see anything but stream events.
+    let mut heartbeat = deployment
+        .config()
+        .read()
+        .await
+        .chat_runner
+        .ws_heartbeat_interval()
+        .map(|period| {
+            let mut interval =
+                tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            interval
+        });
+    let mut unanswered_pings: u32 = 0;
 
     loop {
-        match rx.recv().await {
-            Ok(event) => {
-                let json = serde_json::to_string(&event)?;
+        tokio::select! {
+            _ = next_heartbeat(&mut heartbeat) => {
+                if unanswered_pings >= MAX_UNANSWERED_PINGS {
+                    tracing::debug!(session_id = %session_id, "chat stream ws missed heartbeat pongs");
+                    break;
+                }
+                unanswered_pings += 1;
+                if sender.send(Message::Ping(Vec::new().into())).await.is_err() {
+                    break;
+                }
+            }
+            event = rx.recv() => {
+                match event {
+                    Ok(event) => {
+                        if !event_filter.is_empty() && !event_filter.contains(event.event_type()) {
+                            continue;
+                        }
+                        if let ChatStreamEvent::AgentDelta { run_id, .. }
+                        | ChatStreamEvent::AgentStderr { run_id, .. } = &event
+                            && !subscribed_runs.is_empty()
+                            && !subscribed_runs.contains(run_id)
+                        {
+                            continue;
+                        }
+                        let json = serde_json::to_string(&event)?;
+                        if sender.send(Message::Text(json.into())).await.is_err() {
+                            break;
+                        }
+                        if let Some(heartbeat) = heartbeat.as_mut() {
+                            heartbeat.reset();
+                        }
+                    }
+                    Err(tokio::sync::broadcast::error::RecvError::Lagged(skipped)) => {
+                        tracing::debug!(session_id = %session_id, skipped, "chat stream ws lagged");
+                        deployment.chat_runner().record_stream_lag(session_id, skipped);
+                        continue;
+                    }
+                    Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+                }
+            }
+            msg = receiver.next() => {
+                // Any frame from the client proves the connection is alive.
+                unanswered_pings = 0;
+                let text = match msg {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => continue,
+                };
+                let reply = handle_chat_stream_control(
+                    &deployment,
+                    session_id,
+                    text.as_str(),
+                    &mut subscribed_runs,
+                    &mut event_filter,
+                )
+                .await;
+                let json = serde_json::to_string(&reply)?;
                 if sender.send(Message::Text(json.into())).await.is_err() {
                     break;
                 }
             }
-            Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
-            Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
         }
     }
 
     Ok(())
 }
 
+/// Resolves on the next heartbeat tick, or never when heartbeats are disabled.
+async fn next_heartbeat(heartbeat: &mut Option<tokio::time::Interval>) {
+    match heartbeat {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+async fn handle_chat_stream_control(
+    deployment: &DeploymentImpl,
+    session_id: Uuid,
+    text: &str,
+    subscribed_runs: &mut HashSet<Uuid>,
+    event_filter: &mut HashSet<String>,
+) -> ChatStreamControlReply {
+    let control = match serde_json::from_str::<ChatStreamControl>(text) {
+        Ok(control) => control,
+        Err(err) => {
+            let action = serde_json::from_str::<serde_json::Value>(text)
+                .ok()
+                .and_then(|value| value.get("action")?.as_str().map(str::to_string));
+            return ChatStreamControlReply::ControlNack {
+                action,
+                error: format!("Invalid control frame: {err}"),
+            };
+        }
+    };
+
+    let action = control.action().to_string();
+    let result = match control {
+        ChatStreamControl::StopAgent { session_agent_id } => {
+            stop_agent_from_stream(deployment, session_id, session_agent_id).await
+        }
+        ChatStreamControl::SubscribeRun { run_id } => {
+            match ChatRun::find_by_id(&deployment.db().pool, run_id).await {
+                Ok(Some(run)) if run.session_id == session_id => {
+                    subscribed_runs.insert(run_id);
+                    Ok(())
+                }
+                Ok(_) => Err("Chat run not found in this session".to_string()),
+                Err(err) => Err(err.to_string()),
+            }
+        }
+        ChatStreamControl::UnsubscribeRun { run_id } => {
+            subscribed_runs.remove(&run_id);
+            Ok(())
+        }
+        ChatStreamControl::FilterEvents { events } => {
+            parse_event_filter(events.iter().map(String::as_str))
+                .map(|filter| *event_filter = filter)
+        }
+    };
+
+    match result {
+        Ok(()) => ChatStreamControlReply::ControlAck { action },
+        Err(error) => ChatStreamControlReply::ControlNack {
+            action: Some(action),
+            error,
+        },
+    }
+}
+
+async fn stop_agent_from_stream(
+    deployment: &DeploymentImpl,
+    session_id: Uuid,
+    session_agent_id: Uuid,
+) -> Result<(), String> {
+    let existing = ChatSessionAgent::find_by_id(&deployment.db().pool, session_agent_id)
+        .await
+        .map_err(|err| err.to_string())?;
+    match existing {
+        Some(existing) if existing.session_id == session_id => deployment
+            .chat_runner()
+            .stop_agent(session_id, session_agent_id)
+            .await
+            .map_err(|err| err.to_string()),
+        Some(_) => Err("Chat session agent does not belong to this session".to_string()),
+        None => Err("Chat session agent not found".to_string()),
+    }
+}
+
+/// Force an agent stuck in `Running` without a live process back to `Idle`
+pub async fn reset_session_agent(
+    Extension(session): Extension<ChatSession>,
+    State(deployment): State<DeploymentImpl>,
+    axum::extract::Path((_session_id, session_agent_id)): axum::extract::Path<(Uuid, Uuid)>,
+) -> Result<ResponseJson<ApiResponse<ChatSessionAgent>>, ApiError> {
+    let Some(existing) =
+        ChatSessionAgent::find_by_id(&deployment.db().pool, session_agent_id).await?
+    else {
+        return Err(ApiError::BadRequest(
+            "Chat session agent not found".to_string(),
+        ));
+    };
+
+    if existing.session_id != session.id {
+        return Err(ApiError::Forbidden(
+            "Chat session agent does not belong to this session".to_string(),
+        ));
+    }
+
+    let reset = deployment
+        .chat_runner()
+        .reset_agent(session.id, session_agent_id)
+        .await?;
+
+    Ok(ResponseJson(ApiResponse::success(reset)))
+}
+
+/// Run totals and last activity of one session member.
+pub async fn get_session_agent_activity(
+    Extension(session): Extension<ChatSession>,
+    State(deployment): State<DeploymentImpl>,
+    axum::extract::Path((_session_id, session_agent_id)): axum::extract::Path<(Uuid, Uuid)>,
+) -> Result<ResponseJson<ApiResponse<ChatSessionAgentActivity>>, ApiError> {
+    let pool = &deployment.db().pool;
+    let Some(existing) = ChatSessionAgent::find_by_id(pool, session_agent_id).await? else {
+        return Err(ApiError::BadRequest(
+            "Chat session agent not found".to_string(),
+        ));
+    };
+
+    if existing.session_id != session.id {
+        return Err(ApiError::Forbidden(
+            "Chat session agent does not belong to this session".to_string(),
+        ));
+    }
+
+    let activity = ChatSessionAgent::find_activity(pool, session_agent_id)
+        .await?
+        .ok_or(ApiError::Database(sqlx::Error::RowNotFound))?;
+    Ok(ResponseJson(ApiResponse::success(activity)))
+}
+
+pub async fn mute_session_agent(
+    Extension(session): Extension<ChatSession>,
+    State(deployment): State<DeploymentImpl>,
+    axum::extract::Path((_session_id, session_agent_id)): axum::extract::Path<(Uuid, Uuid)>,
+) -> Result<ResponseJson<ApiResponse<ChatSessionAgent>>, ApiError> {
+    set_session_agent_muted(&deployment, &session, session_agent_id, true).await
+}
+
+pub async fn unmute_session_agent(
+    Extension(session): Extension<ChatSession>,
+    State(deployment): State<DeploymentImpl>,
+    axum::extract::Path((_session_id, session_agent_id)): axum::extract::Path<(Uuid, Uuid)>,
+) -> Result<ResponseJson<ApiResponse<ChatSessionAgent>>, ApiError> {
+    set_session_agent_muted(&deployment, &session, session_agent_id, false).await
+}
+
+async fn set_session_agent_muted(
+    deployment: &DeploymentImpl,
+    session: &ChatSession,
+    session_agent_id: Uuid,
+    muted: bool,
+) -> Result<ResponseJson<ApiResponse<ChatSessionAgent>>, ApiError> {
+    let Some(existing) =
+        ChatSessionAgent::find_by_id(&deployment.db().pool, session_agent_id).await?
+    else {
+        return Err(ApiError::BadRequest(
+            "Chat session agent not found".to_string(),
+        ));
+    };
+
+    if existing.session_id != session.id {
+        return Err(ApiError::Forbidden(
+            "Chat session agent does not belong to this session".to_string(),
+        ));
+    }
+
+    let session_agent = deployment
+        .chat_runner()
+        .set_agent_muted(session.id, session_agent_id, muted)
+        .await?;
+    Ok(ResponseJson(ApiResponse::success(session_agent)))
+}
+
+/// Stop every running agent of the session at once; reports how many were stopped and
+/// which members failed to stop.
+pub async fn stop_all_session_agents(
+    Extension(session): Extension<ChatSession>,
+    State(deployment): State<DeploymentImpl>,
+) -> Result<ResponseJson<ApiResponse<StopAllAgentsOutcome>>, ApiError> {
+    let outcome = deployment.chat_runner().stop_all_agents(session.id).await?;
+    Ok(ResponseJson(ApiResponse::success(outcome)))
+}
+
 /// Stop a running agent
 pub async fn stop_session_agent(
     Extension(session): Extension<ChatSession>,
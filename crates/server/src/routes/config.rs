@@ -14,7 +14,8 @@ use axum::{
 use deployment::{Deployment, DeploymentError};
 use executors::{
     executors::{
-        AvailabilityInfo, BaseAgentCapability, BaseCodingAgent, StandardCodingAgentExecutor,
+        AvailabilityInfo, BaseAgentCapability, BaseCodingAgent, ExecutorOutputFormat,
+        StandardCodingAgentExecutor,
     },
     mcp_config::{McpConfig, read_agent_config, write_agent_config},
     profile::{ExecutorConfigs, ExecutorProfileId},
@@ -25,7 +26,7 @@ use services::services::{
     config::{
         Config, ConfigError, SoundFile,
         editor::{EditorConfig, EditorType},
-        save_config_to_file,
+        load_config_from_file, save_config_to_file,
     },
     container::ContainerService,
 };
@@ -39,7 +40,7 @@ use crate::{DeploymentImpl, error::ApiError};
 pub fn router() -> Router<DeploymentImpl> {
     Router::new()
         .route("/info", get(get_user_system_info))
-        .route("/config", put(update_config))
+        .route("/config", get(get_effective_config).put(update_config))
         .route("/sounds/{sound}", get(get_sound))
         .route("/mcp-config", get(get_mcp_servers).post(update_mcp_servers))
         .route("/profiles", get(get_profiles).put(update_profiles))
@@ -106,7 +107,7 @@ async fn get_user_system_info(
     .unwrap_or(LoginStatus::LoggedOut);
 
     let user_system_info = UserSystemInfo {
-        config: config.clone(),
+        config: config.redacted(),
         analytics_user_id: deployment.user_id().to_string(),
         login_status,
         profiles: ExecutorConfigs::get_cached(),
@@ -126,9 +127,16 @@ async fn get_user_system_info(
     ResponseJson(ApiResponse::success(user_system_info))
 }
 
+/// The config exactly as the next load from disk would apply it (defaults filled in,
+/// older versions migrated, built-in presets completed), with credentials redacted.
+async fn get_effective_config() -> ResponseJson<ApiResponse<Config>> {
+    let config = load_config_from_file(&config_path()).await;
+    ResponseJson(ApiResponse::success(config.redacted()))
+}
+
 async fn update_config(
     State(deployment): State<DeploymentImpl>,
-    Json(new_config): Json<Config>,
+    Json(mut new_config): Json<Config>,
 ) -> ResponseJson<ApiResponse<Config>> {
     let config_path = config_path();
 
@@ -141,17 +149,21 @@ async fn update_config(
 
     // Get old config state before updating
     let old_config = deployment.config().read().await.clone();
+    new_config.restore_redacted_secrets(&old_config);
 
     match save_config_to_file(&new_config, &config_path).await {
         Ok(_) => {
             let mut config = deployment.config().write().await;
             *config = new_config.clone();
             drop(config);
+            deployment
+                .chat_runner()
+                .set_stream_channel_capacity(new_config.chat_runner.stream_channel_capacity());
 
             // Track config events when fields transition from false → true and run side effects
             handle_config_events(&deployment, &old_config, &new_config).await;
 
-            ResponseJson(ApiResponse::success(new_config))
+            ResponseJson(ApiResponse::success(new_config.redacted()))
         }
         Err(e) => ResponseJson(ApiResponse::error(&format!("Failed to save config: {}", e))),
     }
@@ -487,19 +499,50 @@ pub struct CheckAgentAvailabilityQuery {
     executor: BaseCodingAgent,
 }
 
+#[derive(Debug, Serialize, TS)]
+pub struct AgentAvailability {
+    #[serde(flatten)]
+    pub info: AvailabilityInfo,
+    /// Output format the installed CLI will be run with, for executors that negotiate one
+    pub output_format: Option<ExecutorOutputFormat>,
+}
+
 async fn check_agent_availability(
     State(_deployment): State<DeploymentImpl>,
     Query(query): Query<CheckAgentAvailabilityQuery>,
-) -> ResponseJson<ApiResponse<AvailabilityInfo>> {
+) -> ResponseJson<ApiResponse<AgentAvailability>> {
     let profiles = ExecutorConfigs::get_cached();
     let profile_id = ExecutorProfileId::new(query.executor);
 
-    let info = match profiles.get_coding_agent(&profile_id) {
-        Some(agent) => agent.get_availability_info(),
-        None => AvailabilityInfo::NotFound,
+    let availability = match profiles.get_coding_agent(&profile_id) {
+        // Both checks probe the installed CLI synchronously, so keep them off the runtime.
+        Some(agent) => tokio::task::spawn_blocking(move || {
+            let info = agent.get_availability_info();
+            let output_format = if info.is_available() {
+                agent.output_format()
+            } else {
+                None
+            };
+            AgentAvailability {
+                info,
+                output_format,
+            }
+        })
+        .await
+        .unwrap_or_else(|err| {
+            tracing::warn!(error = %err, "agent availability check panicked");
+            AgentAvailability {
+                info: AvailabilityInfo::NotFound,
+                output_format: None,
+            }
+        }),
+        None => AgentAvailability {
+            info: AvailabilityInfo::NotFound,
+            output_format: None,
+        },
     };
 
-    ResponseJson(ApiResponse::success(info))
+    ResponseJson(ApiResponse::success(availability))
 }
 
 #[derive(Debug, Deserialize)]
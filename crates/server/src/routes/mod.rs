@@ -30,6 +30,9 @@ pub mod task_attempts;
 pub mod tasks;
 pub mod terminal;
 
+/// Prefix every API route is nested under.
+pub const API_PREFIX: &str = "/api";
+
 pub fn router(deployment: DeploymentImpl) -> IntoMakeService<Router> {
     // Create routers with different middleware layers
     let base_routes = Router::new()
@@ -62,6 +65,6 @@ pub fn router(deployment: DeploymentImpl) -> IntoMakeService<Router> {
     Router::new()
         .route("/", get(frontend::serve_frontend_root))
         .route("/{*path}", get(frontend::serve_frontend))
-        .nest("/api", base_routes)
+        .nest(API_PREFIX, base_routes)
         .into_make_service()
 }
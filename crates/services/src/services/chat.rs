@@ -1,7 +1,7 @@
 use std::{
     collections::{HashMap, HashSet, hash_map::DefaultHasher},
     hash::Hasher,
-    path::Path,
+    path::{Path, PathBuf},
     str::FromStr,
     sync::Arc,
     time::Duration,
@@ -12,6 +12,7 @@ use dashmap::DashMap;
 use db::models::{
     chat_agent::ChatAgent,
     chat_message::{ChatMessage, ChatSenderType, CreateChatMessage},
+    chat_run::{ChatRun, ChatRunWithTags},
     chat_session::{ChatSession, ChatSessionStatus},
     chat_session_agent::{ChatSessionAgent, ChatSessionAgentState},
 };
@@ -20,7 +21,7 @@ use executors::{
     env::{ExecutionEnv, RepoContext},
     executors::{
         BaseCodingAgent, ExecutorError, ExecutorExitResult, SpawnedChild,
-        StandardCodingAgentExecutor,
+        StandardCodingAgentExecutor, ThinkingEffort,
     },
     logs::{NormalizedEntryType, utils::patch::extract_normalized_entry_from_patch},
     profile::{ExecutorConfigs, ExecutorProfileId, canonical_variant_key},
@@ -34,7 +35,11 @@ use thiserror::Error;
 use tokio::{fs, io::AsyncWriteExt};
 use tokio_util::io::ReaderStream;
 use ts_rs::TS;
-use utils::{assets::config_path, log_msg::LogMsg, msg_store::MsgStore};
+use utils::{
+    assets::{asset_dir, config_path},
+    log_msg::LogMsg,
+    msg_store::MsgStore,
+};
 use uuid::Uuid;
 
 #[derive(Debug, Error)]
@@ -61,6 +66,8 @@ const SUMMARY_REAP_TIMEOUT: Duration = Duration::from_secs(3);
 const SUMMARY_KILL_WAIT_TIMEOUT: Duration = Duration::from_secs(2);
 const SUMMARY_INPUT_TOKEN_LIMIT: u32 = 60_000;
 const EXECUTOR_PROFILE_VARIANT_KEY: &str = "executor_profile_variant";
+const IDLE_AGENT_POLL_INTERVAL: Duration = Duration::from_millis(500);
+const SUMMARIZER_LABEL: &str = "summarizer";
 
 #[derive(Clone)]
 struct CompressionCacheEntry {
@@ -68,6 +75,7 @@ struct CompressionCacheEntry {
     source_message_count: usize,
     token_threshold: u32,
     compression_percentage: u8,
+    message_count_threshold: Option<u32>,
     source_token_count: u32,
     effective_token_count: u32,
     result: CompressionResult,
@@ -118,7 +126,144 @@ pub struct ChatAttachmentMeta {
     pub mime_type: Option<String>,
     pub size_bytes: i64,
     pub kind: String,
-    pub relative_path: String,
+}
+
+impl ChatAttachmentMeta {
+    /// Location of the stored file relative to the asset dir. Derived rather than
+    /// recorded, so message meta never exposes local paths.
+    pub fn relative_path(&self, session_id: Uuid, message_id: Uuid) -> PathBuf {
+        PathBuf::from("chat")
+            .join(format!("session_{session_id}"))
+            .join("attachments")
+            .join(message_id.to_string())
+            .join(format!(
+                "{}_{}",
+                self.id,
+                sanitize_attachment_filename(&self.name)
+            ))
+    }
+}
+
+/// Reduce an uploaded file name to characters safe in a file name and a header.
+pub fn sanitize_attachment_filename(name: &str) -> String {
+    let sanitized: String = name
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+        .collect();
+    if sanitized.is_empty() {
+        "file".to_string()
+    } else {
+        sanitized.chars().take(120).collect()
+    }
+}
+
+/// Normalized attachment kinds recorded in `ChatAttachmentMeta::kind`.
+pub const ATTACHMENT_KIND_IMAGE: &str = "image";
+pub const ATTACHMENT_KIND_TEXT: &str = "text";
+pub const ATTACHMENT_KIND_PDF: &str = "pdf";
+pub const ATTACHMENT_KIND_ARCHIVE: &str = "archive";
+pub const ATTACHMENT_KIND_OTHER: &str = "other";
+
+/// Number of leading bytes inspected when deciding whether content is text.
+const TEXT_SNIFF_LIMIT: usize = 8192;
+
+/// Detect an attachment's mime type and normalized kind from its leading
+/// bytes. The client-declared mime type is only trusted when the content
+/// itself carries no recognizable signature.
+pub fn sniff_attachment(data: &[u8], declared_mime: Option<&str>) -> (Option<String>, String) {
+    let signature = if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some(("image/png", ATTACHMENT_KIND_IMAGE))
+    } else if data.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some(("image/jpeg", ATTACHMENT_KIND_IMAGE))
+    } else if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+        Some(("image/gif", ATTACHMENT_KIND_IMAGE))
+    } else if data.len() >= 12 && data.starts_with(b"RIFF") && &data[8..12] == b"WEBP" {
+        Some(("image/webp", ATTACHMENT_KIND_IMAGE))
+    } else if data.len() >= 14 && data.starts_with(b"BM") && data[6..10] == [0, 0, 0, 0] {
+        Some(("image/bmp", ATTACHMENT_KIND_IMAGE))
+    } else if data.starts_with(b"%PDF-") {
+        Some(("application/pdf", ATTACHMENT_KIND_PDF))
+    } else if data.starts_with(b"PK\x03\x04") || data.starts_with(b"PK\x05\x06") {
+        Some(("application/zip", ATTACHMENT_KIND_ARCHIVE))
+    } else if data.starts_with(&[0x1F, 0x8B]) {
+        Some(("application/gzip", ATTACHMENT_KIND_ARCHIVE))
+    } else if data.starts_with(&[0x37, 0x7A, 0xBC, 0xAF, 0x27, 0x1C]) {
+        Some(("application/x-7z-compressed", ATTACHMENT_KIND_ARCHIVE))
+    } else if data.starts_with(b"Rar!\x1a\x07") {
+        Some(("application/vnd.rar", ATTACHMENT_KIND_ARCHIVE))
+    } else {
+        None
+    };
+    if let Some((mime, kind)) = signature {
+        return (Some(mime.to_string()), kind.to_string());
+    }
+
+    if looks_like_text(data) {
+        let head = String::from_utf8_lossy(&data[..data.len().min(TEXT_SNIFF_LIMIT)]);
+        let head = head.trim_start().to_ascii_lowercase();
+        if head.starts_with("<svg") || (head.starts_with("<?xml") && head.contains("<svg")) {
+            return (
+                Some("image/svg+xml".to_string()),
+                ATTACHMENT_KIND_IMAGE.to_string(),
+            );
+        }
+        let mime = declared_mime
+            .filter(|mime| !mime.starts_with("image/") && *mime != "application/octet-stream")
+            .unwrap_or("text/plain");
+        return (Some(mime.to_string()), ATTACHMENT_KIND_TEXT.to_string());
+    }
+
+    (
+        Some(
+            declared_mime
+                .filter(|mime| !mime.starts_with("text/") && !mime.starts_with("image/"))
+                .unwrap_or("application/octet-stream")
+                .to_string(),
+        ),
+        ATTACHMENT_KIND_OTHER.to_string(),
+    )
+}
+
+fn looks_like_text(data: &[u8]) -> bool {
+    let head = &data[..data.len().min(TEXT_SNIFF_LIMIT)];
+    if head.contains(&0) {
+        return false;
+    }
+    match std::str::from_utf8(head) {
+        Ok(_) => true,
+        // A multi-byte sequence cut off by the sniff limit is still text.
+        Err(err) => err.error_len().is_none() && data.len() > TEXT_SNIFF_LIMIT,
+    }
+}
+
+/// Map a stored attachment kind onto the normalized set. Attachments
+/// uploaded before sniffing was introduced only recorded `image`/`file`.
+pub fn normalize_attachment_kind(kind: &str, mime_type: Option<&str>) -> String {
+    match kind {
+        ATTACHMENT_KIND_IMAGE
+        | ATTACHMENT_KIND_TEXT
+        | ATTACHMENT_KIND_PDF
+        | ATTACHMENT_KIND_ARCHIVE
+        | ATTACHMENT_KIND_OTHER => return kind.to_string(),
+        _ => {}
+    }
+    let kind = match mime_type.unwrap_or_default() {
+        mime if mime.starts_with("image/") => ATTACHMENT_KIND_IMAGE,
+        mime if mime.starts_with("text/")
+            || mime == "application/json"
+            || mime == "application/xml" =>
+        {
+            ATTACHMENT_KIND_TEXT
+        }
+        "application/pdf" => ATTACHMENT_KIND_PDF,
+        "application/zip"
+        | "application/gzip"
+        | "application/x-7z-compressed"
+        | "application/vnd.rar"
+        | "application/x-tar" => ATTACHMENT_KIND_ARCHIVE,
+        _ => ATTACHMENT_KIND_OTHER,
+    };
+    kind.to_string()
 }
 
 pub fn extract_attachments(meta: &Value) -> Vec<ChatAttachmentMeta> {
@@ -131,6 +276,15 @@ pub fn has_attachments(meta: &Value) -> bool {
     !extract_attachments(meta).is_empty()
 }
 
+/// Message meta key set by the pin endpoint; pinned messages survive range deletes.
+pub const PINNED_KEY: &str = "pinned";
+
+pub fn is_message_pinned(meta: &Value) -> bool {
+    meta.get(PINNED_KEY)
+        .and_then(Value::as_bool)
+        .unwrap_or(false)
+}
+
 pub fn extract_reference_message_id(meta: &Value) -> Option<Uuid> {
     let id = meta
         .get("reference")
@@ -143,6 +297,27 @@ pub fn extract_reference_message_id(meta: &Value) -> Option<Uuid> {
     id.and_then(|value| Uuid::parse_str(value).ok())
 }
 
+fn is_mention_name_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '-'
+}
+
+/// Collapses whitespace runs so multi-word agent names compare consistently.
+pub fn normalize_mention_name(name: &str) -> String {
+    name.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Validates the body of a quoted mention such as `@"Product Manager"`.
+fn parse_quoted_mention_name(raw: &str) -> Option<String> {
+    if raw.contains(['\n', '\r']) {
+        return None;
+    }
+    let name = normalize_mention_name(raw);
+    if name.is_empty() || !name.chars().all(|c| is_mention_name_char(c) || c == ' ') {
+        return None;
+    }
+    Some(name)
+}
+
 pub fn parse_mentions(content: &str) -> Vec<String> {
     let chars: Vec<char> = content.chars().collect();
     let mut mentions = Vec::new();
@@ -160,11 +335,24 @@ pub fn parse_mentions(content: &str) -> Vec<String> {
             }
         }
 
+        if chars.get(i + 1) == Some(&'"') {
+            let Some(close_rel) = chars[i + 2..].iter().position(|c| *c == '"') else {
+                continue;
+            };
+            let raw: String = chars[i + 2..i + 2 + close_rel].iter().collect();
+            if let Some(name) = parse_quoted_mention_name(&raw)
+                && seen.insert(name.clone())
+            {
+                mentions.push(name);
+            }
+            continue;
+        }
+
         let mut name = String::new();
         let mut j = i + 1;
         while j < chars.len() {
             let c = chars[j];
-            if c.is_alphanumeric() || c == '_' || c == '-' {
+            if is_mention_name_char(c) {
                 name.push(c);
                 j += 1;
             } else {
@@ -180,18 +368,33 @@ pub fn parse_mentions(content: &str) -> Vec<String> {
     mentions
 }
 
-pub fn parse_send_message_directives(content: &str) -> Vec<String> {
+/// Byte range and target of every well-formed `[sendMessageTo@@...]` directive, in order.
+fn send_message_directive_spans(content: &str) -> Vec<(std::ops::Range<usize>, String)> {
     const PREFIX: &str = "[sendMessageTo@@";
 
-    let mut mentions = Vec::new();
-    let mut seen = HashSet::new();
+    let mut spans = Vec::new();
     let mut cursor = 0usize;
 
     while cursor < content.len() {
         let Some(prefix_rel) = content[cursor..].find(PREFIX) else {
             break;
         };
-        let mut name_start = cursor + prefix_rel + PREFIX.len();
+        let directive_start = cursor + prefix_rel;
+        let mut name_start = directive_start + PREFIX.len();
+
+        if content[name_start..].starts_with('"') {
+            name_start += 1;
+            let Some(suffix_rel) = content[name_start..].find("\"]") else {
+                cursor = name_start;
+                continue;
+            };
+            let name_end = name_start + suffix_rel;
+            if let Some(name) = parse_quoted_mention_name(&content[name_start..name_end]) {
+                spans.push((directive_start..name_end + 2, name));
+            }
+            cursor = name_end + 2;
+            continue;
+        }
 
         let (name_end, next_cursor) = if content[name_start..].starts_with('{') {
             name_start += 1;
@@ -212,19 +415,62 @@ pub fn parse_send_message_directives(content: &str) -> Vec<String> {
 
         let name = content[name_start..name_end].trim();
 
-        if !name.is_empty()
-            && name
-                .chars()
-                .all(|c| c.is_alphanumeric() || c == '_' || c == '-')
-            && seen.insert(name.to_string())
-        {
-            mentions.push(name.to_string());
+        if !name.is_empty() && name.chars().all(is_mention_name_char) {
+            spans.push((directive_start..next_cursor, name.to_string()));
         }
 
         cursor = next_cursor;
     }
 
-    mentions
+    spans
+}
+
+pub fn parse_send_message_directives(content: &str) -> Vec<String> {
+    let mut seen = HashSet::new();
+    send_message_directive_spans(content)
+        .into_iter()
+        .map(|(_, name)| name)
+        .filter(|name| seen.insert(name.clone()))
+        .collect()
+}
+
+/// Message meta key holding agent content with routing directives removed, for display.
+pub const DISPLAY_CONTENT_KEY: &str = "display_content";
+
+/// `content` without its `[sendMessageTo@@...]` directives. Lines left blank by the removal
+/// are dropped; other lines keep their text with the directive's surrounding space folded.
+pub fn strip_send_message_directives(content: &str) -> String {
+    if send_message_directive_spans(content).is_empty() {
+        return content.to_string();
+    }
+
+    let mut lines = Vec::new();
+    for line in content.lines() {
+        // Directive targets cannot contain newlines, so every span lies within one line.
+        let spans = send_message_directive_spans(line);
+        if spans.is_empty() {
+            lines.push(line.to_string());
+            continue;
+        }
+        let mut stripped = String::with_capacity(line.len());
+        let mut cursor = 0usize;
+        for (range, _) in spans {
+            stripped.push_str(&line[cursor..range.start]);
+            cursor = range.end;
+            let rest = &line[cursor..];
+            if stripped.trim().is_empty() {
+                cursor += rest.len() - rest.trim_start().len();
+            } else if stripped.ends_with(' ') && (rest.is_empty() || rest.starts_with([' ', '\t']))
+            {
+                stripped.pop();
+            }
+        }
+        stripped.push_str(&line[cursor..]);
+        if !stripped.trim().is_empty() {
+            lines.push(stripped.trim_end().to_string());
+        }
+    }
+    lines.join("\n").trim().to_string()
 }
 
 pub async fn create_message(
@@ -319,6 +565,21 @@ pub async fn create_message_with_id(
         });
     }
 
+    if matches!(sender_type, ChatSenderType::User)
+        && meta.get(DETECTED_LANGUAGE_KEY).is_none()
+        && super::config::load_config_from_file(&config_path())
+            .await
+            .chat_runner
+            .detect_message_language
+        && let Some(language) = detect_language(&content)
+    {
+        meta[DETECTED_LANGUAGE_KEY] = serde_json::json!(language);
+    }
+
+    if matches!(sender_type, ChatSenderType::Agent) && !mentions.is_empty() {
+        meta[DISPLAY_CONTENT_KEY] = serde_json::json!(strip_send_message_directives(&content));
+    }
+
     meta["structured"] = serde_json::json!({
         "sender_type": sender_type,
         "sender_id": sender_id,
@@ -359,6 +620,7 @@ pub async fn build_structured_messages(
         .map(|agent| (agent.id, agent.name))
         .collect();
 
+    let display_offset = load_display_timezone().await;
     let mut result = Vec::with_capacity(messages.len());
 
     for message in messages {
@@ -390,6 +652,7 @@ pub async fn build_structured_messages(
             "id": message.id,
             "session_id": message.session_id,
             "created_at": message.created_at,
+            "display_time": format_display_time(&message.created_at.to_rfc3339(), display_offset),
             "sender": sender,
             "content": message.content,
             "mentions": message.mentions.0,
@@ -400,61 +663,517 @@ pub async fn build_structured_messages(
     Ok(result)
 }
 
+pub const CONTEXT_SCOPE_KEY: &str = "context_scope";
+pub const CONTEXT_LAST_N_KEY: &str = "context_last_n";
+/// Agent `tools_enabled` flag limiting its context to messages relevant to it.
+pub const FOCUS_MODE_KEY: &str = "focus_mode";
+/// Agent `tools_enabled` key selecting a reasoning effort (`low`, `medium` or `high`).
+pub const THINKING_EFFORT_KEY: &str = "thinking_effort";
+const DEFAULT_CONTEXT_LAST_N: usize = 20;
+
+/// Which part of the group history an agent receives in its context file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ContextScope {
+    #[default]
+    Full,
+    /// Only the most recent N messages
+    LastN(usize),
+    /// Only messages that mention the agent, plus the agent's own messages
+    MentionsOnly,
+}
+
+impl ContextScope {
+    /// Reads `context_scope` (and `context_last_n`) from an agent's `tools_enabled`.
+    pub fn from_tools_enabled(tools_enabled: &Value) -> Self {
+        let scope = tools_enabled
+            .get(CONTEXT_SCOPE_KEY)
+            .and_then(Value::as_str)
+            .map(|value| value.trim().to_ascii_lowercase());
+        match scope.as_deref() {
+            Some("last_n") => {
+                let last_n = tools_enabled
+                    .get(CONTEXT_LAST_N_KEY)
+                    .and_then(Value::as_u64)
+                    .and_then(|value| usize::try_from(value).ok())
+                    .filter(|value| *value > 0)
+                    .unwrap_or(DEFAULT_CONTEXT_LAST_N);
+                Self::LastN(last_n)
+            }
+            Some("mentions_only") => Self::MentionsOnly,
+            _ => Self::Full,
+        }
+    }
+}
+
+fn apply_context_scope(
+    messages: Vec<ChatMessage>,
+    scope: ContextScope,
+    agent_id: Uuid,
+    agent_name: &str,
+) -> Vec<ChatMessage> {
+    match scope {
+        ContextScope::Full => messages,
+        ContextScope::LastN(last_n) => {
+            let skip = messages.len().saturating_sub(last_n);
+            messages.into_iter().skip(skip).collect()
+        }
+        ContextScope::MentionsOnly => messages
+            .into_iter()
+            .filter(|message| {
+                (message.sender_type == ChatSenderType::Agent
+                    && message.sender_id == Some(agent_id))
+                    || mentions_agent(message, agent_name)
+            })
+            .collect(),
+    }
+}
+
+/// Focus mode is opt-in per agent via `tools_enabled.focus_mode = true`.
+pub fn focus_mode_enabled(tools_enabled: &Value) -> bool {
+    tools_enabled
+        .get(FOCUS_MODE_KEY)
+        .and_then(Value::as_bool)
+        .unwrap_or(false)
+}
+
+/// Reasoning effort requested via `tools_enabled.thinking_effort`. Only executors that
+/// expose a reasoning setting (Codex, Droid) honor it; the others ignore it.
+pub fn thinking_effort(tools_enabled: &Value) -> Option<ThinkingEffort> {
+    tools_enabled
+        .get(THINKING_EFFORT_KEY)
+        .and_then(Value::as_str)
+        .and_then(|effort| ThinkingEffort::from_str(effort.trim()).ok())
+}
+
+/// Check that an agent's `tools_enabled.thinking_effort`, when set, is a known effort.
+pub fn validate_thinking_effort(tools_enabled: &Value) -> Result<(), String> {
+    match tools_enabled.get(THINKING_EFFORT_KEY) {
+        None | Some(Value::Null) => Ok(()),
+        Some(_) if thinking_effort(tools_enabled).is_some() => Ok(()),
+        Some(value) => Err(format!(
+            "Invalid {THINKING_EFFORT_KEY} {value}; expected one of: low, medium, high"
+        )),
+    }
+}
+
+fn mentions_agent(message: &ChatMessage, agent_name: &str) -> bool {
+    let agent_name = normalize_mention_name(agent_name);
+    message
+        .mentions
+        .0
+        .iter()
+        .any(|mention| normalize_mention_name(mention).eq_ignore_ascii_case(&agent_name))
+}
+
+/// Drop other agents' messages unless they mention the given agent; user and system
+/// messages and the agent's own messages are always kept.
+fn apply_focus_mode(
+    messages: Vec<ChatMessage>,
+    agent_id: Uuid,
+    agent_name: &str,
+) -> Vec<ChatMessage> {
+    messages
+        .into_iter()
+        .filter(|message| {
+            message.sender_type != ChatSenderType::Agent
+                || message.sender_id == Some(agent_id)
+                || mentions_agent(message, agent_name)
+        })
+        .collect()
+}
+
+/// Message meta key holding the message's [`ChatMessagePriority`].
+pub const PRIORITY_KEY: &str = "priority";
+
+/// How a message is ordered in a busy agent's queue; FIFO within the same priority.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, TS,
+)]
+#[serde(rename_all = "snake_case")]
+#[ts(export)]
+pub enum ChatMessagePriority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl ChatMessagePriority {
+    /// Reads `meta.priority`; missing or unrecognised values are `Normal`.
+    pub fn from_meta(meta: &Value) -> Self {
+        meta.get(PRIORITY_KEY)
+            .and_then(|value| serde_json::from_value(value.clone()).ok())
+            .unwrap_or_default()
+    }
+}
+
+/// Message meta key listing the agent ids allowed to see a message; absent or null means public.
+pub const VISIBLE_TO_KEY: &str = "visible_to";
+
+/// Agent ids a message is restricted to, or `None` when it is visible to everyone.
+pub fn extract_visible_to(meta: &Value) -> Option<Vec<Uuid>> {
+    let ids = meta.get(VISIBLE_TO_KEY)?.as_array()?;
+    Some(
+        ids.iter()
+            .filter_map(Value::as_str)
+            .filter_map(|id| Uuid::parse_str(id.trim()).ok())
+            .collect(),
+    )
+}
+
+/// Whether `viewer` may see `message`. Passing `None` asks about shared context,
+/// which only ever contains public messages.
+pub fn is_message_visible_to(message: &ChatMessage, viewer: Option<Uuid>) -> bool {
+    match extract_visible_to(&message.meta.0) {
+        None => true,
+        Some(allowed) => viewer.is_some_and(|agent_id| {
+            allowed.contains(&agent_id)
+                || (message.sender_type == ChatSenderType::Agent
+                    && message.sender_id == Some(agent_id))
+        }),
+    }
+}
+
+fn filter_visible_messages(messages: Vec<ChatMessage>, viewer: Option<Uuid>) -> Vec<ChatMessage> {
+    messages
+        .into_iter()
+        .filter(|message| is_message_visible_to(message, viewer))
+        .collect()
+}
+
+/// Whether the session holds private messages that `agent_id` is allowed to see.
+pub async fn has_private_messages_for(
+    pool: &SqlitePool,
+    session_id: Uuid,
+    agent_id: Uuid,
+) -> Result<bool, ChatServiceError> {
+    let messages = ChatMessage::find_by_session_id(pool, session_id, None).await?;
+    Ok(messages.iter().any(|message| {
+        !is_message_visible_to(message, None) && is_message_visible_to(message, Some(agent_id))
+    }))
+}
+
 /// Context with LLM-compressed summary message included
 pub struct CompactedContext {
     /// The compacted messages (summary + recent messages)
     pub messages: Vec<Value>,
-    /// Raw JSONL string for prompt injection
-    pub jsonl: String,
+    /// Context file contents rendered in `format`: JSONL lines, or markdown sections in
+    /// markdown mode
+    pub contents: String,
+    /// Format `contents` was rendered in
+    pub format: ChatContextFormat,
     /// Whether context compression has been applied
     pub context_compacted: bool,
     /// Warning if compression fell back to truncation
     pub compression_warning: Option<CompressionWarning>,
 }
 
-async fn load_chat_compression_settings() -> (u32, u8) {
+async fn load_chat_compression_settings() -> (u32, u8, Option<u32>) {
     let config = super::config::load_config_from_file(&config_path()).await;
-    let threshold = config.chat_compression.token_threshold.max(1);
-    let percentage = config.chat_compression.compression_percentage.clamp(1, 100);
-    (threshold, percentage)
+    effective_compression_settings(&config.chat_compression)
 }
 
-fn simplified_to_context_value(message: &SimplifiedMessage) -> Value {
-    let time = chrono::DateTime::parse_from_rfc3339(&message.timestamp)
-        .map(|dt| {
-            dt.with_timezone(&chrono::Local)
-                .format("%Y-%m-%d %H:%M:%S")
-                .to_string()
-        })
-        .unwrap_or_else(|_| message.timestamp.clone());
+/// Token threshold, percentage and message-count threshold as compression applies them;
+/// cached results record these values.
+fn effective_compression_settings(config: &ChatCompressionConfig) -> (u32, u8, Option<u32>) {
+    let threshold = config.token_threshold.max(1);
+    let percentage = config.compression_percentage.clamp(1, 100);
+    let message_count_threshold = config.message_count_threshold.filter(|count| *count > 0);
+    (threshold, percentage, message_count_threshold)
+}
+
+/// Reject compression settings that compression would otherwise silently adjust.
+pub fn validate_compression_config(config: &ChatCompressionConfig) -> Result<(), String> {
+    if config.token_threshold == 0 {
+        return Err("token_threshold must be greater than 0".to_string());
+    }
+    if !(1..=100).contains(&config.compression_percentage) {
+        return Err("compression_percentage must be between 1 and 100".to_string());
+    }
+    if config.message_count_threshold == Some(0) {
+        return Err("message_count_threshold must be greater than 0 when set".to_string());
+    }
+    if !(1..=60).contains(&config.busy_agents_wait_secs) {
+        return Err("busy_agents_wait_secs must be between 1 and 60".to_string());
+    }
+    Ok(())
+}
+
+/// Parse the configured display timezone. `None` means the server's local timezone,
+/// which is also the fallback for unrecognised values.
+pub fn parse_display_timezone(value: &str) -> Option<chrono::FixedOffset> {
+    let value = value.trim();
+    if value.is_empty() || value.eq_ignore_ascii_case("local") {
+        return None;
+    }
+    let upper = value.to_ascii_uppercase();
+    let offset = upper
+        .strip_prefix("UTC")
+        .or_else(|| upper.strip_prefix("GMT"))
+        .unwrap_or(&upper);
+    if offset.is_empty() || offset == "Z" {
+        return chrono::FixedOffset::east_opt(0);
+    }
+
+    let (sign, digits) = if let Some(rest) = offset.strip_prefix('+') {
+        (1, rest)
+    } else if let Some(rest) = offset.strip_prefix('-') {
+        (-1, rest)
+    } else {
+        return None;
+    };
+    let digits = digits.replace(':', "");
+    if digits.is_empty() || digits.len() > 4 || !digits.chars().all(|ch| ch.is_ascii_digit()) {
+        return None;
+    }
+    let (hours, minutes) = if digits.len() <= 2 {
+        (digits.parse::<i32>().ok()?, 0)
+    } else {
+        let (hours, minutes) = digits.split_at(digits.len() - 2);
+        (hours.parse::<i32>().ok()?, minutes.parse::<i32>().ok()?)
+    };
+    if hours > 14 || minutes >= 60 {
+        return None;
+    }
+    chrono::FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60))
+}
+
+/// Render an RFC3339 timestamp in the display timezone, keeping the offset visible so
+/// readers in other zones can convert it. Unparseable input is returned unchanged.
+pub fn format_display_time(timestamp: &str, offset: Option<chrono::FixedOffset>) -> String {
+    const DISPLAY_FORMAT: &str = "%Y-%m-%d %H:%M:%S %:z";
+    let Ok(parsed) = chrono::DateTime::parse_from_rfc3339(timestamp) else {
+        return timestamp.to_string();
+    };
+    match offset {
+        Some(offset) => parsed
+            .with_timezone(&offset)
+            .format(DISPLAY_FORMAT)
+            .to_string(),
+        None => parsed
+            .with_timezone(&chrono::Local)
+            .format(DISPLAY_FORMAT)
+            .to_string(),
+    }
+}
+
+async fn load_display_timezone() -> Option<chrono::FixedOffset> {
+    let config = super::config::load_config_from_file(&config_path()).await;
+    let configured = config.chat_runner.display_timezone;
+    let offset = parse_display_timezone(&configured);
+    if offset.is_none() && !configured.trim().eq_ignore_ascii_case("local") {
+        tracing::warn!(
+            display_timezone = %configured,
+            "Unrecognised display timezone; falling back to server local time"
+        );
+    }
+    offset
+}
 
+fn simplified_to_context_value(
+    message: &SimplifiedMessage,
+    display_offset: Option<chrono::FixedOffset>,
+) -> Value {
     serde_json::json!({
         "sender": message.sender,
         "content": message.content,
-        "time": time,
+        "time": format_display_time(&message.timestamp, display_offset),
+        "timestamp": message.timestamp,
     })
 }
 
-fn simplified_messages_to_jsonl(messages: &[SimplifiedMessage]) -> (Vec<Value>, String) {
-    let context_messages: Vec<Value> = messages.iter().map(simplified_to_context_value).collect();
-    let jsonl = context_messages
+/// Mentions of each message, which only the rich formats carry, keyed by message id.
+type ContextMessageDetails = HashMap<Uuid, Vec<String>>;
+
+fn context_message_details(messages: &[ChatMessage]) -> ContextMessageDetails {
+    messages
         .iter()
-        .filter_map(|msg| serde_json::to_string(msg).ok())
-        .collect::<Vec<_>>()
-        .join("\n")
-        + "\n";
-    (context_messages, jsonl)
+        .map(|message| (message.id, message.mentions.0.clone()))
+        .collect()
+}
+
+fn context_value_to_markdown(value: &Value) -> String {
+    let field = |key: &str| value.get(key).and_then(Value::as_str).unwrap_or_default();
+    let mut section = format!("### {} ({})\n", field("sender"), field("time"));
+    if let Some(id) = value.get("id").and_then(Value::as_str) {
+        section.push_str(&format!("id: {id}\n"));
+    }
+    if let Some(mentions) = value.get("mentions").and_then(Value::as_array) {
+        let mentions: Vec<String> = mentions
+            .iter()
+            .filter_map(Value::as_str)
+            .map(|mention| format!("@{mention}"))
+            .collect();
+        section.push_str(&format!("mentions: {}\n", mentions.join(", ")));
+    }
+    section.push('\n');
+    section.push_str(field("content").trim());
+    section.push_str("\n\n");
+    section
+}
+
+/// Render context messages as the chosen file format. Returns the structured messages
+/// alongside the file contents.
+fn render_context(
+    messages: &[SimplifiedMessage],
+    details: &ContextMessageDetails,
+    format: ChatContextFormat,
+    display_offset: Option<chrono::FixedOffset>,
+) -> (Vec<Value>, String) {
+    let context_messages: Vec<Value> = messages
+        .iter()
+        .map(|message| {
+            let mut value = simplified_to_context_value(message, display_offset);
+            if format != ChatContextFormat::JsonlCompact
+                && let Some(id) = message.id
+            {
+                value["id"] = Value::String(id.to_string());
+                if let Some(mentions) = details.get(&id)
+                    && !mentions.is_empty()
+                {
+                    value["mentions"] = serde_json::json!(mentions);
+                }
+            }
+            value
+        })
+        .collect();
+    let contents = match format {
+        ChatContextFormat::Markdown => context_messages
+            .iter()
+            .map(context_value_to_markdown)
+            .collect::<String>(),
+        ChatContextFormat::JsonlCompact | ChatContextFormat::JsonlRich => {
+            context_messages
+                .iter()
+                .filter_map(|msg| serde_json::to_string(msg).ok())
+                .collect::<Vec<_>>()
+                .join("\n")
+                + "\n"
+        }
+    };
+    (context_messages, contents)
+}
+
+/// The session's context format override, falling back to the configured default.
+pub async fn resolve_context_format(
+    pool: &SqlitePool,
+    session_id: Uuid,
+) -> Result<ChatContextFormat, ChatServiceError> {
+    if let Some(format) = ChatSession::find_context_format(pool, session_id).await? {
+        match serde_json::from_value(Value::String(format.clone())) {
+            Ok(format) => return Ok(format),
+            Err(_) => tracing::warn!(
+                session_id = %session_id,
+                context_format = %format,
+                "Ignoring unknown session context format"
+            ),
+        }
+    }
+    let config = super::config::load_config_from_file(&config_path()).await;
+    Ok(config.chat_runner.context_format)
 }
 
 /// Build full (uncompressed) context.
 ///
 /// This is used by the non-blocking main execution path so agent runs are never
 /// delayed by summarization/compression.
+///
+/// Private messages are only included when `viewer` is one of their recipients.
 pub async fn build_full_context(
     pool: &SqlitePool,
     session_id: Uuid,
+    viewer: Option<Uuid>,
+) -> Result<CompactedContext, ChatServiceError> {
+    let all_messages = ChatMessage::find_by_session_id(pool, session_id, None).await?;
+    build_context_from_messages(
+        pool,
+        session_id,
+        filter_visible_messages(all_messages, viewer),
+    )
+    .await
+}
+
+/// Build an uncompressed context limited to what `scope` lets the given agent see; focus
+/// mode first drops other agents' messages that do not involve it.
+pub async fn build_scoped_context(
+    pool: &SqlitePool,
+    session_id: Uuid,
+    scope: ContextScope,
+    focus_mode: bool,
+    agent_id: Uuid,
+    agent_name: &str,
 ) -> Result<CompactedContext, ChatServiceError> {
     let all_messages = ChatMessage::find_by_session_id(pool, session_id, None).await?;
+    let mut visible_messages = filter_visible_messages(all_messages, Some(agent_id));
+    if focus_mode {
+        visible_messages = apply_focus_mode(visible_messages, agent_id, agent_name);
+    }
+    let scoped_messages = apply_context_scope(visible_messages, scope, agent_id, agent_name);
+    build_context_from_messages(pool, session_id, scoped_messages).await
+}
+
+/// Longest message body kept in a recent-context snippet line.
+const RECENT_SNIPPET_MESSAGE_CHARS: usize = 400;
+
+/// The last `limit` messages the agent can see (excluding `exclude_message_id`), rendered as
+/// short `sender: content` lines for agents that skip reading the history file.
+pub async fn build_recent_context_snippet(
+    pool: &SqlitePool,
+    session_id: Uuid,
+    agent: &ChatAgent,
+    exclude_message_id: Uuid,
+    limit: usize,
+) -> Result<String, ChatServiceError> {
+    let all_messages = ChatMessage::find_by_session_id(pool, session_id, None).await?;
+    let mut visible_messages: Vec<ChatMessage> =
+        filter_visible_messages(all_messages, Some(agent.id))
+            .into_iter()
+            .filter(|message| message.id != exclude_message_id)
+            .collect();
+    if focus_mode_enabled(&agent.tools_enabled.0) {
+        visible_messages = apply_focus_mode(visible_messages, agent.id, &agent.name);
+    }
+    let scope = ContextScope::from_tools_enabled(&agent.tools_enabled.0);
+    let scoped_messages = apply_context_scope(visible_messages, scope, agent.id, &agent.name);
+    let skip = scoped_messages.len().saturating_sub(limit);
+
+    let agents = ChatAgent::find_all(pool).await?;
+    let agent_map: HashMap<Uuid, String> = agents
+        .into_iter()
+        .map(|member| (member.id, member.name))
+        .collect();
+    let simplified_messages: Vec<SimplifiedMessage> = scoped_messages
+        .iter()
+        .skip(skip)
+        .map(|message| to_simplified_message(message, &agent_map))
+        .collect();
+    Ok(render_recent_context_snippet(&simplified_messages))
+}
+
+fn render_recent_context_snippet(messages: &[SimplifiedMessage]) -> String {
+    let mut snippet = String::new();
+    for message in messages {
+        let content = message
+            .content
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ");
+        let content = if content.chars().count() > RECENT_SNIPPET_MESSAGE_CHARS {
+            let truncated: String = content.chars().take(RECENT_SNIPPET_MESSAGE_CHARS).collect();
+            format!("{truncated}...")
+        } else {
+            content
+        };
+        snippet.push_str(&format!("{}: {}\n", message.sender, content));
+    }
+    snippet
+}
+
+async fn build_context_from_messages(
+    pool: &SqlitePool,
+    session_id: Uuid,
+    all_messages: Vec<ChatMessage>,
+) -> Result<CompactedContext, ChatServiceError> {
     let agents = ChatAgent::find_all(pool).await?;
     let agent_map: HashMap<Uuid, String> = agents
         .into_iter()
@@ -466,10 +1185,18 @@ pub async fn build_full_context(
         .map(|message| to_simplified_message(message, &agent_map))
         .collect();
 
-    let (messages, jsonl) = simplified_messages_to_jsonl(&simplified_messages);
+    let format = resolve_context_format(pool, session_id).await?;
+    let details = context_message_details(&all_messages);
+    let (messages, contents) = render_context(
+        &simplified_messages,
+        &details,
+        format,
+        load_display_timezone().await,
+    );
     Ok(CompactedContext {
         messages,
-        jsonl,
+        contents,
+        format,
         context_compacted: false,
         compression_warning: None,
     })
@@ -493,39 +1220,75 @@ pub async fn build_compacted_context(
     workspace_path: Option<&std::path::Path>,
     context_dir: Option<&std::path::Path>,
 ) -> Result<CompactedContext, ChatServiceError> {
-    // Fetch all messages for the session
-    let all_messages = ChatMessage::find_by_session_id(pool, session_id, None).await?;
-    let agents = ChatAgent::find_all(pool).await?;
-    let agent_map: HashMap<Uuid, String> = agents
-        .into_iter()
-        .map(|agent| (agent.id, agent.name))
-        .collect();
+    compacted_context(pool, session_id, workspace_path, context_dir, false).await
+}
 
-    let simplified_messages: Vec<SimplifiedMessage> = all_messages
-        .iter()
-        .map(|message| to_simplified_message(message, &agent_map))
-        .collect();
+/// Like [`build_compacted_context`], but ignores any cached compression result and
+/// summarizes again, e.g. after the summarization prompt changed. The fresh result
+/// replaces the cached one.
+pub async fn rebuild_compacted_context(
+    pool: &SqlitePool,
+    session_id: Uuid,
+    workspace_path: Option<&std::path::Path>,
+    context_dir: Option<&std::path::Path>,
+) -> Result<CompactedContext, ChatServiceError> {
+    compacted_context(pool, session_id, workspace_path, context_dir, true).await
+}
+
+async fn compacted_context(
+    pool: &SqlitePool,
+    session_id: Uuid,
+    workspace_path: Option<&std::path::Path>,
+    context_dir: Option<&std::path::Path>,
+    bypass_cache: bool,
+) -> Result<CompactedContext, ChatServiceError> {
+    // Fetch all messages for the session; the compacted file is shared, so keep it public
+    let all_messages = filter_visible_messages(
+        ChatMessage::find_by_session_id(pool, session_id, None).await?,
+        None,
+    );
+    let agents = ChatAgent::find_all(pool).await?;
+    let agent_map: HashMap<Uuid, String> = agents
+        .into_iter()
+        .map(|agent| (agent.id, agent.name))
+        .collect();
+
+    let simplified_messages: Vec<SimplifiedMessage> = all_messages
+        .iter()
+        .map(|message| to_simplified_message(message, &agent_map))
+        .collect();
+    let details = context_message_details(&all_messages);
     let session_agents = ChatSessionAgent::find_all_for_session(pool, session_id).await?;
-    let (token_threshold, compression_percentage) = load_chat_compression_settings().await;
+    let (token_threshold, compression_percentage, message_count_threshold) =
+        load_chat_compression_settings().await;
     let workspace_path = workspace_path.unwrap_or(std::path::Path::new("."));
 
-    let compression_result = compress_messages_if_needed(
+    let compression_result = compress_messages(
         pool,
         session_id,
         simplified_messages,
         token_threshold,
         compression_percentage,
+        message_count_threshold,
         &session_agents,
         workspace_path,
         context_dir,
+        bypass_cache,
     )
     .await?;
 
-    let (messages, jsonl) = simplified_messages_to_jsonl(&compression_result.messages);
+    let format = resolve_context_format(pool, session_id).await?;
+    let (messages, contents) = render_context(
+        &compression_result.messages,
+        &details,
+        format,
+        load_display_timezone().await,
+    );
 
     Ok(CompactedContext {
         messages,
-        jsonl,
+        contents,
+        format,
         context_compacted: compression_result.compression_type != CompressionType::None,
         compression_warning: compression_result.warning,
     })
@@ -539,7 +1302,7 @@ pub async fn export_session_archive(
     fs::create_dir_all(archive_dir).await?;
 
     let messages = build_structured_messages(pool, session.id).await?;
-    let export_path = archive_dir.join("messages_export.jsonl");
+    let export_path = archive_dir.join(super::chat_export::EXPORT_MESSAGES_FILE_NAME);
     let mut file = fs::File::create(&export_path).await?;
     for message in messages {
         let line = serde_json::to_string(&message).unwrap_or_default();
@@ -557,11 +1320,181 @@ pub async fn export_session_archive(
     Ok(archive_dir.to_string_lossy().to_string())
 }
 
+/// One entry of a session's audit log. Live stream events are not stored, so these are
+/// reconstructed from messages, run records and each run's `meta.json`.
+#[derive(Debug, Clone, Serialize, TS)]
+#[serde(tag = "type", rename_all = "snake_case")]
+#[ts(export)]
+pub enum SessionAuditEvent {
+    MessageCreated {
+        timestamp: chrono::DateTime<Utc>,
+        message_id: Uuid,
+        sender_type: ChatSenderType,
+        sender_id: Option<Uuid>,
+        mentions: Vec<String>,
+    },
+    RunStarted {
+        timestamp: chrono::DateTime<Utc>,
+        run_id: Uuid,
+        session_agent_id: Uuid,
+        run_index: i64,
+    },
+    CompressionWarning {
+        timestamp: chrono::DateTime<Utc>,
+        run_id: Uuid,
+        session_agent_id: Uuid,
+        code: String,
+        message: String,
+    },
+    RunFinished {
+        timestamp: chrono::DateTime<Utc>,
+        run_id: Uuid,
+        session_agent_id: Uuid,
+        failed: bool,
+        failure_reason: Option<String>,
+    },
+    AgentStateChanged {
+        timestamp: chrono::DateTime<Utc>,
+        run_id: Uuid,
+        session_agent_id: Uuid,
+        state: ChatSessionAgentState,
+    },
+}
+
+impl SessionAuditEvent {
+    pub fn timestamp(&self) -> chrono::DateTime<Utc> {
+        match self {
+            Self::MessageCreated { timestamp, .. }
+            | Self::RunStarted { timestamp, .. }
+            | Self::CompressionWarning { timestamp, .. }
+            | Self::RunFinished { timestamp, .. }
+            | Self::AgentStateChanged { timestamp, .. } => *timestamp,
+        }
+    }
+}
+
+/// Chronological audit trail of a session; events sharing a timestamp keep the order
+/// they happened in.
+pub async fn build_session_audit_log(
+    pool: &SqlitePool,
+    session_id: Uuid,
+) -> Result<Vec<SessionAuditEvent>, ChatServiceError> {
+    let mut events: Vec<SessionAuditEvent> =
+        ChatMessage::find_by_session_id(pool, session_id, None)
+            .await?
+            .into_iter()
+            .map(|message| SessionAuditEvent::MessageCreated {
+                timestamp: message.created_at,
+                message_id: message.id,
+                sender_type: message.sender_type,
+                sender_id: message.sender_id,
+                mentions: message.mentions.0,
+            })
+            .collect();
+
+    let mut runs = ChatRun::find_by_session_with_tags(pool, session_id, None).await?;
+    runs.reverse();
+    for ChatRunWithTags { run, .. } in runs {
+        let meta = match run.meta_path.as_deref() {
+            Some(meta_path) => fs::read_to_string(meta_path)
+                .await
+                .ok()
+                .and_then(|raw| serde_json::from_str::<Value>(&raw).ok()),
+            None => None,
+        };
+        events.extend(run_audit_events(&run, meta.as_ref()));
+    }
+
+    events.sort_by_key(SessionAuditEvent::timestamp);
+    Ok(events)
+}
+
+/// Audit events for one run. Without a `meta.json` the run never finished (or is still
+/// going), so only its start is reported.
+fn run_audit_events(run: &ChatRun, meta: Option<&Value>) -> Vec<SessionAuditEvent> {
+    let mut events = vec![
+        SessionAuditEvent::RunStarted {
+            timestamp: run.created_at,
+            run_id: run.id,
+            session_agent_id: run.session_agent_id,
+            run_index: run.run_index,
+        },
+        SessionAuditEvent::AgentStateChanged {
+            timestamp: run.created_at,
+            run_id: run.id,
+            session_agent_id: run.session_agent_id,
+            state: ChatSessionAgentState::Running,
+        },
+    ];
+    let Some(meta) = meta else {
+        return events;
+    };
+
+    if let Some(warning) = meta.get("compression_warning") {
+        let field = |key: &str| {
+            warning
+                .get(key)
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_string()
+        };
+        events.push(SessionAuditEvent::CompressionWarning {
+            timestamp: run.created_at,
+            run_id: run.id,
+            session_agent_id: run.session_agent_id,
+            code: field("code"),
+            message: field("message"),
+        });
+    }
+
+    let finished_at = meta
+        .get("finished_at")
+        .and_then(Value::as_str)
+        .and_then(|raw| chrono::DateTime::parse_from_rfc3339(raw).ok())
+        .map(|value| value.with_timezone(&Utc))
+        .unwrap_or(run.created_at);
+    let failure_reason = meta
+        .get("failure_reason")
+        .and_then(Value::as_str)
+        .map(str::to_string)
+        .or_else(|| {
+            meta.get("workspace_unavailable")
+                .and_then(Value::as_bool)
+                .filter(|unavailable| *unavailable)
+                .map(|_| "workspace unavailable".to_string())
+        });
+    let failed = meta
+        .get("failed")
+        .and_then(Value::as_bool)
+        .unwrap_or(failure_reason.is_some());
+    events.push(SessionAuditEvent::RunFinished {
+        timestamp: finished_at,
+        run_id: run.id,
+        session_agent_id: run.session_agent_id,
+        failed,
+        failure_reason,
+    });
+    events.push(SessionAuditEvent::AgentStateChanged {
+        timestamp: finished_at,
+        run_id: run.id,
+        session_agent_id: run.session_agent_id,
+        state: if failed {
+            ChatSessionAgentState::Dead
+        } else {
+            ChatSessionAgentState::Idle
+        },
+    });
+    events
+}
+
 // ==========================================
 // New Token-Based Compression System
 // ==========================================
 
-use super::chat_history_file::{SimplifiedMessage, append_to_split_file, estimate_token_count};
+use super::{
+    chat_history_file::{SimplifiedMessage, append_to_split_file, estimate_token_count},
+    config::{ChatCompressionConfig, ChatContextFormat, SummaryBusyAgentsPolicy, UiLanguage},
+};
 
 /// Convert ChatMessage to SimplifiedMessage format (sender + content only)
 pub fn to_simplified_message(
@@ -588,6 +1521,7 @@ pub fn to_simplified_message(
         sender,
         content: message.content.clone(),
         timestamp: message.created_at.to_rfc3339(),
+        id: Some(message.id),
     }
 }
 
@@ -609,19 +1543,439 @@ pub async fn build_simplified_messages(
         .collect())
 }
 
+/// A message the next compaction would fold into the history summary.
+#[derive(Debug, Clone, Serialize, TS)]
+pub struct CompactionPreviewMessage {
+    /// `None` for the summary or truncation note left by an earlier compaction
+    pub message_id: Option<Uuid>,
+    pub sender: String,
+    pub content: String,
+    pub timestamp: String,
+    pub tokens: u32,
+}
+
+/// What compaction would archive from the current history, with the settings in effect.
+#[derive(Debug, Clone, Serialize, TS)]
+pub struct CompactionPreview {
+    pub total_messages: usize,
+    pub total_tokens: u32,
+    pub token_threshold: u32,
+    pub compression_percentage: u8,
+    pub message_count_threshold: Option<u32>,
+    /// Whether the history currently crosses a threshold, i.e. the next run would compact
+    pub triggered: bool,
+    /// Token budget the compressed prefix is chosen to cover
+    pub target_tokens: u32,
+    pub selected_tokens: u32,
+    /// Oldest-first prefix that would be summarized and written to a cutoff file
+    pub messages: Vec<CompactionPreviewMessage>,
+}
+
+/// Run the compaction selection over the shared history without summarizing or
+/// writing anything.
+pub async fn preview_compaction(
+    pool: &SqlitePool,
+    session_id: Uuid,
+) -> Result<CompactionPreview, ChatServiceError> {
+    let messages = filter_visible_messages(
+        ChatMessage::find_by_session_id(pool, session_id, None).await?,
+        None,
+    );
+    let agent_map: HashMap<Uuid, String> = ChatAgent::find_all(pool)
+        .await?
+        .into_iter()
+        .map(|agent| (agent.id, agent.name))
+        .collect();
+    let simplified: Vec<SimplifiedMessage> = messages
+        .iter()
+        .map(|message| to_simplified_message(message, &agent_map))
+        .collect();
+    let (token_threshold, compression_percentage, message_count_threshold) =
+        load_chat_compression_settings().await;
+    let cached_entry = get_compression_cache_entry(pool, session_id).await?;
+
+    Ok(build_compaction_preview(
+        cached_entry.as_ref(),
+        simplified,
+        token_threshold,
+        compression_percentage,
+        message_count_threshold,
+    ))
+}
+
+/// Selection for [`preview_compaction`], starting from the same incremental base as
+/// [`compress_messages`] so an already-compacted prefix is not previewed again.
+fn build_compaction_preview(
+    cached_entry: Option<&CompressionCacheEntry>,
+    source_messages: Vec<SimplifiedMessage>,
+    token_threshold: u32,
+    compression_percentage: u8,
+    message_count_threshold: Option<u32>,
+) -> CompactionPreview {
+    let messages = cached_entry
+        .and_then(|cached| {
+            incremental_compression_base(
+                cached,
+                &source_messages,
+                token_threshold,
+                compression_percentage,
+                message_count_threshold,
+            )
+        })
+        .unwrap_or(source_messages);
+
+    let total_tokens = estimate_token_count(&messages);
+    let (selected_count, target_tokens, selected_tokens) =
+        select_messages_to_compress_by_token(&messages, total_tokens, compression_percentage);
+    let preview_messages = messages
+        .iter()
+        .take(selected_count)
+        .map(|message| CompactionPreviewMessage {
+            message_id: message.id,
+            sender: message.sender.clone(),
+            content: message.content.clone(),
+            timestamp: message.timestamp.clone(),
+            tokens: estimate_token_count(std::slice::from_ref(message)).max(1),
+        })
+        .collect();
+
+    CompactionPreview {
+        total_messages: messages.len(),
+        total_tokens,
+        token_threshold,
+        compression_percentage,
+        message_count_threshold,
+        triggered: compression_triggered(
+            total_tokens,
+            token_threshold,
+            messages.len(),
+            message_count_threshold,
+        ),
+        target_tokens,
+        selected_tokens,
+        messages: preview_messages,
+    }
+}
+
+/// Estimated tokens of the shared session context, reusing the cached compression
+/// result when it was computed from the current history.
+pub async fn estimate_context_tokens(
+    pool: &SqlitePool,
+    session_id: Uuid,
+) -> Result<u32, ChatServiceError> {
+    let messages = filter_visible_messages(
+        ChatMessage::find_by_session_id(pool, session_id, None).await?,
+        None,
+    );
+    let agent_map: HashMap<Uuid, String> = ChatAgent::find_all(pool)
+        .await?
+        .into_iter()
+        .map(|agent| (agent.id, agent.name))
+        .collect();
+    let simplified: Vec<SimplifiedMessage> = messages
+        .iter()
+        .map(|message| to_simplified_message(message, &agent_map))
+        .collect();
+
+    if let Some(cached) = get_compression_cache_entry(pool, session_id).await?
+        && cached.source_fingerprint == calculate_messages_fingerprint(&simplified)
+    {
+        return Ok(cached.effective_token_count);
+    }
+    Ok(estimate_token_count(&simplified))
+}
+
+/// Estimated tokens a draft adds to the context once sent by `sender_handle`.
+pub fn estimate_draft_tokens(sender_handle: &str, content: &str) -> u32 {
+    estimate_token_count(&[SimplifiedMessage {
+        sender: format!("user:{sender_handle}"),
+        content: content.to_string(),
+        timestamp: Utc::now().to_rfc3339(),
+        id: None,
+    }])
+}
+
+/// Context window most recently reported by an agent run in the session, optionally
+/// limited to one agent. Returns `None` when no executor has reported it.
+pub async fn find_model_context_window(
+    pool: &SqlitePool,
+    session_id: Uuid,
+    agent_id: Option<Uuid>,
+) -> Result<Option<u32>, ChatServiceError> {
+    let messages = ChatMessage::find_by_session_id(pool, session_id, None).await?;
+    Ok(messages
+        .iter()
+        .rev()
+        .filter(|message| message.sender_type == ChatSenderType::Agent)
+        .filter(|message| agent_id.is_none() || message.sender_id == agent_id)
+        .find_map(|message| {
+            message
+                .meta
+                .0
+                .get("token_usage")
+                .and_then(|usage| usage.get("model_context_window"))
+                .and_then(Value::as_u64)
+                .filter(|window| *window > 0)
+                .and_then(|window| u32::try_from(window).ok())
+        }))
+}
+
+/// Length instruction for the regular summarization prompt.
+const SUMMARY_LENGTH_INSTRUCTION: &str = "Keep the summary concise (under 500 words).";
+
+/// Length instruction for the retry after a summary failed to reduce the token count.
+const STRICT_SUMMARY_LENGTH_INSTRUCTION: &str = "The previous summary was too long to help. \
+Be much more concise: keep only what later work depends on, in under 200 words.";
+
 /// Build the prompt for AI summarization
-fn build_summarization_prompt(messages_to_compress: &[SimplifiedMessage]) -> String {
-    let mut prompt = String::from(
+fn build_summarization_prompt(messages_to_compress: &[SimplifiedMessage], strict: bool) -> String {
+    let length_instruction = if strict {
+        STRICT_SUMMARY_LENGTH_INSTRUCTION
+    } else {
+        SUMMARY_LENGTH_INSTRUCTION
+    };
+    let mut prompt = format!(
         "Summarize the following chat history while preserving key tasks, decisions, \
-constraints, and references. Keep the summary concise (under 500 words).\n\
+constraints, and references. {length_instruction}\n\
 Return only the summary body. Do not ask follow-up questions. Do not run any tools or shell commands.\n\nMessages:\n",
     );
+    prompt.push_str(&format_summary_messages(messages_to_compress));
+    prompt
+}
 
-    for msg in messages_to_compress {
-        prompt.push_str(&format!("{}: {}\n", msg.sender, msg.content));
+fn format_summary_messages(messages: &[SimplifiedMessage]) -> String {
+    messages
+        .iter()
+        .map(|msg| format!("{}: {}\n", msg.sender, msg.content))
+        .collect()
+}
+
+/// History entry that replaces the summarized messages.
+fn history_summary_message(summary: &str) -> SimplifiedMessage {
+    SimplifiedMessage {
+        sender: "system:summary".to_string(),
+        content: format!("[History Summary]\n{}", summary),
+        timestamp: Utc::now().to_rfc3339(),
+        id: None,
     }
+}
 
-    prompt
+/// Placeholder in a debug summarization template that receives the formatted messages.
+pub const SUMMARY_PROMPT_MESSAGES_PLACEHOLDER: &str = "{messages}";
+
+/// Fill a debug summarization template; templates without the placeholder get the
+/// messages appended, as in the regular prompt.
+fn build_debug_summarization_prompt(messages: &[SimplifiedMessage], template: &str) -> String {
+    let formatted = format_summary_messages(messages);
+    if template.contains(SUMMARY_PROMPT_MESSAGES_PLACEHOLDER) {
+        template.replace(SUMMARY_PROMPT_MESSAGES_PLACEHOLDER, &formatted)
+    } else {
+        format!("{}\n\nMessages:\n{formatted}", template.trim_end())
+    }
+}
+
+/// Result of summarizing sample messages outside any session.
+#[derive(Debug, Clone, Serialize, TS)]
+pub struct DebugSummary {
+    pub summary: String,
+    /// Prompt the agent was given
+    pub prompt: String,
+    /// Estimated tokens of the sample messages
+    pub tokens_before: u32,
+    /// Estimated tokens of the summary entry that would replace them in the history
+    pub tokens_after: u32,
+}
+
+/// Summarize `messages` with `agent`, using `prompt_template` instead of the regular
+/// prompt when given. Runs in a scratch workspace and touches no session.
+pub async fn debug_summarize(
+    agent: &ChatAgent,
+    messages: &[SimplifiedMessage],
+    prompt_template: Option<&str>,
+) -> Result<DebugSummary, ChatServiceError> {
+    if messages.is_empty() {
+        return Err(ChatServiceError::Validation(
+            "At least one message is required".to_string(),
+        ));
+    }
+    let prompt = match prompt_template.map(str::trim).filter(|t| !t.is_empty()) {
+        Some(template) => build_debug_summarization_prompt(messages, template),
+        None => build_summarization_prompt(messages, false),
+    };
+    let workspace_path = asset_dir().join("chat").join("debug_summarize");
+    fs::create_dir_all(&workspace_path).await?;
+
+    let summary = call_agent_for_summary(agent, None, &prompt, &workspace_path).await?;
+    let tokens_after = estimate_token_count(&[history_summary_message(&summary)]);
+    Ok(DebugSummary {
+        summary,
+        prompt,
+        tokens_before: estimate_token_count(messages),
+        tokens_after,
+    })
+}
+
+/// Build the prompt for translating an agent reply into `target_language`
+fn build_translation_prompt(content: &str, target_language: &str) -> String {
+    format!(
+        "Translate the following message into {target_language}. Preserve markdown, code blocks, \
+@mentions, file paths, and identifiers exactly as written.\n\
+Return only the translated text. Do not ask follow-up questions. Do not run any tools or shell commands.\n\n\
+Message:\n{content}\n"
+    )
+}
+
+/// Message meta key holding the ISO 639-1 language detected in a user message.
+pub const DETECTED_LANGUAGE_KEY: &str = "detected_language";
+/// Letters a message needs before its language is guessed.
+const MIN_LANGUAGE_DETECTION_LETTERS: usize = 12;
+/// Function words that tell Latin-script languages apart.
+const LATIN_LANGUAGE_MARKERS: &[(&str, &[&str])] = &[
+    (
+        "en",
+        &[
+            "the", "and", "is", "are", "to", "of", "it", "you", "this", "that", "with", "for",
+            "please", "can", "what",
+        ],
+    ),
+    (
+        "fr",
+        &[
+            "le", "les", "et", "est", "des", "une", "pour", "avec", "vous", "je", "pas", "dans",
+            "nous", "sur", "mais",
+        ],
+    ),
+    (
+        "es",
+        &[
+            "el", "los", "las", "y", "es", "una", "por", "para", "con", "pero", "como", "del",
+            "yo", "muy", "está",
+        ],
+    ),
+    (
+        "de",
+        &[
+            "der", "die", "das", "und", "ist", "nicht", "ich", "mit", "ein", "eine", "zu", "auf",
+            "für", "wir", "bitte",
+        ],
+    ),
+];
+
+/// English name of a language code returned by [`detect_language`].
+pub fn language_name(code: &str) -> Option<&'static str> {
+    Some(match code {
+        "en" => "English",
+        "fr" => "French",
+        "es" => "Spanish",
+        "de" => "German",
+        "ja" => "Japanese",
+        "ko" => "Korean",
+        "zh" => "Chinese",
+        "ru" => "Russian",
+        "ar" => "Arabic",
+        _ => return None,
+    })
+}
+
+/// Guess the ISO 639-1 language of a message from its script and, for Latin text, common
+/// function words. Fenced code is ignored. Returns `None` for short or ambiguous text.
+pub fn detect_language(content: &str) -> Option<&'static str> {
+    let mut in_fence = false;
+    let prose: String = content
+        .lines()
+        .filter(|line| {
+            if line.trim_start().starts_with("```") {
+                in_fence = !in_fence;
+                return false;
+            }
+            !in_fence
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    let (mut latin, mut kana, mut hangul, mut han, mut cyrillic, mut arabic) = (0, 0, 0, 0, 0, 0);
+    for ch in prose.chars() {
+        match ch {
+            '\u{3040}'..='\u{30FF}' => kana += 1,
+            '\u{1100}'..='\u{11FF}' | '\u{AC00}'..='\u{D7AF}' => hangul += 1,
+            '\u{4E00}'..='\u{9FFF}' => han += 1,
+            '\u{0400}'..='\u{04FF}' => cyrillic += 1,
+            '\u{0600}'..='\u{06FF}' => arabic += 1,
+            'a'..='z' | 'A'..='Z' | '\u{00C0}'..='\u{024F}' => latin += 1,
+            _ => {}
+        }
+    }
+    let total = latin + kana + hangul + han + cyrillic + arabic;
+    if total < MIN_LANGUAGE_DETECTION_LETTERS {
+        return None;
+    }
+    let dominant = |count: usize| count * 2 >= total;
+    if kana > 0 && dominant(kana + han) {
+        return Some("ja");
+    }
+    if dominant(hangul) {
+        return Some("ko");
+    }
+    if dominant(han) {
+        return Some("zh");
+    }
+    if dominant(cyrillic) {
+        return Some("ru");
+    }
+    if dominant(arabic) {
+        return Some("ar");
+    }
+    if !dominant(latin) {
+        return None;
+    }
+
+    let words: Vec<String> = prose
+        .split(|ch: char| !ch.is_alphabetic())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect();
+    let mut scores: Vec<(&'static str, usize)> = LATIN_LANGUAGE_MARKERS
+        .iter()
+        .map(|(code, markers)| {
+            let hits = words
+                .iter()
+                .filter(|word| markers.contains(&word.as_str()))
+                .count();
+            (*code, hits)
+        })
+        .collect();
+    scores.sort_by_key(|(_, hits)| std::cmp::Reverse(*hits));
+    match scores.as_slice() {
+        [(code, best), (_, runner_up), ..] if *best >= 2 && best > runner_up => Some(*code),
+        _ => None,
+    }
+}
+
+/// Human-readable target language for a UI language setting.
+/// `Browser` cannot be resolved server-side and falls back to English.
+pub fn translation_language_for_ui(language: &UiLanguage) -> &'static str {
+    match language {
+        UiLanguage::Browser | UiLanguage::En => "English",
+        UiLanguage::Fr => "French",
+        UiLanguage::Ja => "Japanese",
+        UiLanguage::Es => "Spanish",
+        UiLanguage::Ko => "Korean",
+        UiLanguage::ZhHans => "Simplified Chinese",
+        UiLanguage::ZhHant => "Traditional Chinese",
+    }
+}
+
+/// Translate a message with the given agent, reusing the summarization executor path.
+/// `session_profile` is the session's default executor profile, if any.
+pub async fn translate_with_agent(
+    agent: &ChatAgent,
+    session_profile: Option<&ExecutorProfileId>,
+    content: &str,
+    target_language: &str,
+    workspace_path: &Path,
+) -> Result<String, ChatServiceError> {
+    let prompt = build_translation_prompt(content, target_language);
+    call_agent_for_summary(agent, session_profile, &prompt, workspace_path).await
 }
 
 fn limit_summary_input_messages(
@@ -679,16 +2033,128 @@ fn summary_agent_priority(state: ChatSessionAgentState) -> u8 {
     }
 }
 
-fn prioritize_summary_agents(session_agents: &[ChatSessionAgent]) -> Vec<ChatSessionAgent> {
+/// Configured agents come first in their configured order; the rest fall back to state ordering.
+fn prioritize_summary_agents(
+    session_agents: &[ChatSessionAgent],
+    preferred_agent_ids: &[Uuid],
+) -> Vec<ChatSessionAgent> {
     let mut agents = session_agents.to_vec();
-    agents.sort_by_key(|agent| summary_agent_priority(agent.state.clone()));
+    agents.sort_by_key(|agent| {
+        let preferred_rank = preferred_agent_ids
+            .iter()
+            .position(|id| *id == agent.agent_id)
+            .unwrap_or(usize::MAX);
+        (preferred_rank, summary_agent_priority(agent.state.clone()))
+    });
     agents
 }
 
+/// Why summarization would not use a session agent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, TS)]
+#[serde(rename_all = "snake_case")]
+pub enum SummaryFallbackReason {
+    /// The session has no members, or none of them resolves to an agent
+    NoAgents,
+    /// Every member is running
+    AllAgentsBusy,
+}
+
+/// Who would summarize the session history if compaction ran now.
+#[derive(Debug, Clone, Serialize, TS)]
+#[serde(tag = "outcome", rename_all = "snake_case")]
+pub enum SummaryAgentPreview {
+    /// The first member tried, by configured priority and then state
+    Agent {
+        session_agent_id: Uuid,
+        agent_id: Uuid,
+        agent_name: String,
+        state: ChatSessionAgentState,
+        /// Whether the agent is listed in `summary_agent_priority`
+        preferred: bool,
+    },
+    /// The dedicated summarizer of the SUMMARIZER policy
+    Summarizer { executor_profile: ExecutorProfileId },
+    /// Messages would be truncated to a cutoff file without a summary
+    Truncation {
+        reason: SummaryFallbackReason,
+        /// Seconds the WAIT policy would first give a busy agent to become idle
+        wait_secs: Option<u64>,
+    },
+}
+
+/// The fallback summarization would take for these members without trying any agent.
+fn summary_fallback(
+    session_agents: &[ChatSessionAgent],
+    config: &ChatCompressionConfig,
+) -> Option<SummaryAgentPreview> {
+    if session_agents.is_empty() {
+        return Some(SummaryAgentPreview::Truncation {
+            reason: SummaryFallbackReason::NoAgents,
+            wait_secs: None,
+        });
+    }
+    if !all_agents_running(session_agents) {
+        return None;
+    }
+    match (
+        config.busy_agents_policy,
+        config.summarizer_profile.as_ref(),
+    ) {
+        (SummaryBusyAgentsPolicy::Summarizer, Some(profile)) => {
+            Some(SummaryAgentPreview::Summarizer {
+                executor_profile: profile.clone(),
+            })
+        }
+        (policy, _) => Some(SummaryAgentPreview::Truncation {
+            reason: SummaryFallbackReason::AllAgentsBusy,
+            wait_secs: (policy == SummaryBusyAgentsPolicy::Wait && session_agents.len() > 1)
+                .then(|| config.busy_agents_wait().as_secs()),
+        }),
+    }
+}
+
+/// Which agent would summarize the session history right now, following the same
+/// availability and priority rules as compaction; nothing is run.
+pub async fn preview_summary_agent(
+    pool: &SqlitePool,
+    session_id: Uuid,
+) -> Result<SummaryAgentPreview, ChatServiceError> {
+    let session_agents = ChatSessionAgent::find_all_for_session(pool, session_id).await?;
+    let compression_config = super::config::load_config_from_file(&config_path())
+        .await
+        .chat_compression;
+    if let Some(fallback) = summary_fallback(&session_agents, &compression_config) {
+        return Ok(fallback);
+    }
+
+    for session_agent in
+        prioritize_summary_agents(&session_agents, &compression_config.summary_agent_priority)
+    {
+        let Some(agent) = ChatAgent::find_by_id(pool, session_agent.agent_id).await? else {
+            continue;
+        };
+        return Ok(SummaryAgentPreview::Agent {
+            session_agent_id: session_agent.id,
+            agent_id: agent.id,
+            agent_name: agent.name,
+            state: session_agent.state,
+            preferred: compression_config
+                .summary_agent_priority
+                .contains(&session_agent.agent_id),
+        });
+    }
+    Ok(SummaryAgentPreview::Truncation {
+        reason: SummaryFallbackReason::NoAgents,
+        wait_secs: None,
+    })
+}
+
 async fn wait_for_idle_agent_if_needed(
     pool: &SqlitePool,
     session_id: Uuid,
     session_agents: &[ChatSessionAgent],
+    policy: SummaryBusyAgentsPolicy,
+    max_wait: Duration,
 ) -> Result<Vec<ChatSessionAgent>, ChatServiceError> {
     if !all_agents_running(session_agents) {
         return Ok(session_agents.to_vec());
@@ -704,6 +2170,20 @@ async fn wait_for_idle_agent_if_needed(
         return Ok(session_agents.to_vec());
     }
 
+    if policy == SummaryBusyAgentsPolicy::Wait {
+        tracing::info!(
+            session_id = %session_id,
+            max_wait_secs = max_wait.as_secs(),
+            "All session agents are running; waiting briefly for one to become idle"
+        );
+        return wait_for_idle_agents(
+            || ChatSessionAgent::find_all_for_session(pool, session_id),
+            max_wait,
+            IDLE_AGENT_POLL_INTERVAL,
+        )
+        .await;
+    }
+
     // Do not block the active mention execution path.
     // When all agents are currently running, summarization should quickly fall back
     // so normal group chat delivery is not stalled.
@@ -716,14 +2196,38 @@ async fn wait_for_idle_agent_if_needed(
         .map_err(ChatServiceError::from)
 }
 
+/// Polls `refresh` until some agent is no longer running or `max_wait` elapses, and
+/// returns the latest snapshot either way.
+async fn wait_for_idle_agents<F, Fut>(
+    mut refresh: F,
+    max_wait: Duration,
+    poll_interval: Duration,
+) -> Result<Vec<ChatSessionAgent>, ChatServiceError>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<Vec<ChatSessionAgent>, sqlx::Error>>,
+{
+    let deadline = tokio::time::Instant::now() + max_wait;
+    loop {
+        let agents = refresh().await?;
+        if !all_agents_running(&agents) || tokio::time::Instant::now() >= deadline {
+            return Ok(agents);
+        }
+        let remaining = deadline.saturating_duration_since(tokio::time::Instant::now());
+        tokio::time::sleep(poll_interval.min(remaining)).await;
+    }
+}
+
 /// Try to summarize messages using available AI agents
 /// Returns Some(summary) if any agent succeeds, None if all fail
+/// `strict` asks for a much shorter summary, for retrying an ineffective one
 async fn try_summarize_with_agents(
     pool: &SqlitePool,
     session_id: Uuid,
     session_agents: &[ChatSessionAgent],
     messages_to_compress: &[SimplifiedMessage],
     workspace_path: &Path,
+    strict: bool,
 ) -> Option<String> {
     let (summary_input_messages, input_tokens_before_limit, input_tokens_after_limit) =
         limit_summary_input_messages(messages_to_compress, SUMMARY_INPUT_TOKEN_LIMIT);
@@ -738,21 +2242,60 @@ async fn try_summarize_with_agents(
             "Summarization input exceeded token limit; truncating to most recent messages"
         );
     }
-    let summarize_prompt = build_summarization_prompt(&summary_input_messages);
-    let candidate_agents =
-        match wait_for_idle_agent_if_needed(pool, session_id, session_agents).await {
-            Ok(agents) => agents,
-            Err(err) => {
-                tracing::warn!(
-                    session_id = %session_id,
-                    error = %err,
-                    "Failed to refresh session agents before summarization; using initial snapshot"
-                );
-                session_agents.to_vec()
-            }
-        };
+    let summarize_prompt = build_summarization_prompt(&summary_input_messages, strict);
+    let compression_config = super::config::load_config_from_file(&config_path())
+        .await
+        .chat_compression;
+    let candidate_agents = match wait_for_idle_agent_if_needed(
+        pool,
+        session_id,
+        session_agents,
+        compression_config.busy_agents_policy,
+        compression_config.busy_agents_wait(),
+    )
+    .await
+    {
+        Ok(agents) => agents,
+        Err(err) => {
+            tracing::warn!(
+                session_id = %session_id,
+                error = %err,
+                "Failed to refresh session agents before summarization; using initial snapshot"
+            );
+            session_agents.to_vec()
+        }
+    };
 
     if all_agents_running(&candidate_agents) {
+        if compression_config.busy_agents_policy == SummaryBusyAgentsPolicy::Summarizer
+            && let Some(profile) = compression_config.summarizer_profile.as_ref()
+        {
+            match call_executor_for_summary(
+                profile,
+                SUMMARIZER_LABEL,
+                &summarize_prompt,
+                workspace_path,
+            )
+            .await
+            {
+                Ok(summary) => {
+                    tracing::info!(
+                        session_id = %session_id,
+                        executor = %profile.executor,
+                        "AI summarization successful with dedicated summarizer"
+                    );
+                    return Some(summary);
+                }
+                Err(err) => {
+                    tracing::warn!(
+                        session_id = %session_id,
+                        executor = %profile.executor,
+                        error = %err,
+                        "Dedicated summarizer failed"
+                    );
+                }
+            }
+        }
         tracing::warn!(
             session_id = %session_id,
             "Skipping AI summarization because all agents are still running"
@@ -760,7 +2303,22 @@ async fn try_summarize_with_agents(
         return None;
     }
 
-    for session_agent in prioritize_summary_agents(&candidate_agents) {
+    let session_profile = match find_session_executor_profile(pool, session_id).await {
+        Ok(profile) => profile,
+        Err(err) => {
+            tracing::warn!(
+                session_id = %session_id,
+                error = %err,
+                "Failed to load session executor profile for summarization"
+            );
+            None
+        }
+    };
+
+    for session_agent in prioritize_summary_agents(
+        &candidate_agents,
+        &compression_config.summary_agent_priority,
+    ) {
         // Get the agent details
         let agent = match ChatAgent::find_by_id(pool, session_agent.agent_id).await {
             Ok(Some(agent)) => agent,
@@ -777,7 +2335,14 @@ async fn try_summarize_with_agents(
         let effective_workspace_path = workspace_override.unwrap_or(workspace_path);
 
         // Try to call the agent for summarization
-        match call_agent_for_summary(&agent, &summarize_prompt, effective_workspace_path).await {
+        match call_agent_for_summary(
+            &agent,
+            session_profile.as_ref(),
+            &summarize_prompt,
+            effective_workspace_path,
+        )
+        .await
+        {
             Ok(summary) => {
                 tracing::info!(
                     session_id = %session_id,
@@ -809,12 +2374,24 @@ async fn try_summarize_with_agents(
 /// This spawns a temporary agent process to summarize messages
 async fn call_agent_for_summary(
     agent: &ChatAgent,
+    session_profile: Option<&ExecutorProfileId>,
+    prompt: &str,
+    workspace_path: &Path,
+) -> Result<String, ChatServiceError> {
+    let executor_profile_id = parse_executor_profile_id(agent, session_profile)?;
+    call_executor_for_summary(&executor_profile_id, &agent.name, prompt, workspace_path).await
+}
+
+/// Spawn a temporary executor process with `executor_profile_id` and return its reply.
+/// `agent_name` only labels logs and errors.
+async fn call_executor_for_summary(
+    executor_profile_id: &ExecutorProfileId,
+    agent_name: &str,
     prompt: &str,
     workspace_path: &Path,
 ) -> Result<String, ChatServiceError> {
-    let executor_profile_id = parse_executor_profile_id(agent)?;
     let mut executor =
-        ExecutorConfigs::get_cached().get_coding_agent_or_default(&executor_profile_id);
+        ExecutorConfigs::get_cached().get_coding_agent_or_default(executor_profile_id);
     executor.use_approvals(Arc::new(NoopExecutorApprovalService));
 
     let repo_context = RepoContext::new(workspace_path.to_path_buf(), Vec::new());
@@ -837,17 +2414,17 @@ async fn call_agent_for_summary(
             Ok(Ok(ExecutorExitResult::Failure)) => failed_by_signal = true,
             Ok(Err(err)) => {
                 tracing::warn!(
-                    agent_name = %agent.name,
+                    agent_name = %agent_name,
                     error = %err,
                     "Summarization exit signal dropped; falling back to process wait"
                 );
-                status = Some(wait_for_summary_process_exit(&mut spawned, &agent.name).await?);
+                status = Some(wait_for_summary_process_exit(&mut spawned, agent_name).await?);
             }
             Err(_) => {
                 terminate_summary_child(&mut spawned).await;
                 return Err(ChatServiceError::Validation(format!(
                     "AI summarization timed out for agent {} after {} seconds",
-                    agent.name,
+                    agent_name,
                     SUMMARY_EXECUTION_TIMEOUT.as_secs()
                 )));
             }
@@ -859,7 +2436,7 @@ async fn call_agent_for_summary(
                 Ok(Err(err)) => return Err(ChatServiceError::Io(err)),
                 Err(_) => {
                     tracing::debug!(
-                        agent_name = %agent.name,
+                        agent_name = %agent_name,
                         timeout_ms = SUMMARY_REAP_TIMEOUT.as_millis(),
                         "Summarization process did not exit after completion signal; forcing shutdown"
                     );
@@ -868,7 +2445,7 @@ async fn call_agent_for_summary(
             }
         }
     } else {
-        status = Some(wait_for_summary_process_exit(&mut spawned, &agent.name).await?);
+        status = Some(wait_for_summary_process_exit(&mut spawned, agent_name).await?);
     }
 
     msg_store.push_finished();
@@ -877,7 +2454,7 @@ async fn call_agent_for_summary(
     if failed_by_signal {
         return Err(ChatServiceError::Validation(format!(
             "AI summarization process failed for agent {}",
-            agent.name
+            agent_name
         )));
     }
 
@@ -886,14 +2463,14 @@ async fn call_agent_for_summary(
     {
         return Err(ChatServiceError::Validation(format!(
             "AI summarization process failed for agent {}",
-            agent.name
+            agent_name
         )));
     }
 
     extract_latest_assistant_from_history(&msg_store.get_history()).ok_or_else(|| {
         ChatServiceError::Validation(format!(
             "No assistant summary output generated by agent {}",
-            agent.name
+            agent_name
         ))
     })
 }
@@ -925,7 +2502,11 @@ async fn terminate_summary_child(spawned: &mut SpawnedChild) {
 }
 
 fn parse_runner_type(agent: &ChatAgent) -> Result<BaseCodingAgent, ChatServiceError> {
-    let raw = agent.runner_type.trim();
+    parse_runner_type_name(&agent.runner_type)
+}
+
+fn parse_runner_type_name(runner_type: &str) -> Result<BaseCodingAgent, ChatServiceError> {
+    let raw = runner_type.trim();
     let normalized = raw.replace(['-', ' '], "_").to_ascii_uppercase();
     BaseCodingAgent::from_str(&normalized)
         .map_err(|_| ChatServiceError::Validation(format!("unknown runner type: {raw}")))
@@ -943,7 +2524,79 @@ fn extract_executor_profile_variant(tools_enabled: &serde_json::Value) -> Option
     Some(canonical_variant_key(variant))
 }
 
-fn parse_executor_profile_id(agent: &ChatAgent) -> Result<ExecutorProfileId, ChatServiceError> {
+/// Check an agent's `tools_enabled.executor_profile_variant` against the variants configured
+/// for its runner type and store it in canonical form. Empty or `DEFAULT` keeps the default
+/// variant; unknown variants are rejected with the list of valid ones.
+pub fn normalize_executor_profile_variant(
+    runner_type: &str,
+    tools_enabled: &mut serde_json::Value,
+    configs: &ExecutorConfigs,
+) -> Result<(), ChatServiceError> {
+    let Some(raw) = tools_enabled.get(EXECUTOR_PROFILE_VARIANT_KEY) else {
+        return Ok(());
+    };
+    if !raw.is_string() && !raw.is_null() {
+        return Err(ChatServiceError::Validation(format!(
+            "{EXECUTOR_PROFILE_VARIANT_KEY} must be a string"
+        )));
+    }
+    let Some(variant) = extract_executor_profile_variant(tools_enabled) else {
+        return Ok(());
+    };
+
+    let executor = parse_runner_type_name(runner_type)?;
+    let mut variants = configs
+        .executors
+        .get(&executor)
+        .map(|config| config.variant_names())
+        .unwrap_or_default();
+    if variants.iter().any(|name| **name == variant) {
+        tools_enabled[EXECUTOR_PROFILE_VARIANT_KEY] = variant.into();
+        return Ok(());
+    }
+
+    variants.sort();
+    let valid = std::iter::once("DEFAULT")
+        .chain(variants.iter().map(|name| name.as_str()))
+        .collect::<Vec<_>>()
+        .join(", ");
+    Err(ChatServiceError::Validation(format!(
+        "Unknown executor profile variant '{variant}' for {executor}; valid variants: {valid}"
+    )))
+}
+
+/// Load the session's default executor profile. A stored value that no longer
+/// parses is logged and ignored.
+pub async fn find_session_executor_profile(
+    pool: &SqlitePool,
+    session_id: Uuid,
+) -> Result<Option<ExecutorProfileId>, sqlx::Error> {
+    let Some(profile) = ChatSession::find_executor_profile(pool, session_id).await? else {
+        return Ok(None);
+    };
+    match serde_json::from_str::<ExecutorProfileId>(&profile) {
+        Ok(profile) => Ok(Some(profile)),
+        Err(err) => {
+            tracing::warn!(
+                session_id = %session_id,
+                error = %err,
+                "Ignoring invalid session executor profile"
+            );
+            Ok(None)
+        }
+    }
+}
+
+/// Agents without a `runner_type` inherit `session_profile` when the session has one.
+fn parse_executor_profile_id(
+    agent: &ChatAgent,
+    session_profile: Option<&ExecutorProfileId>,
+) -> Result<ExecutorProfileId, ChatServiceError> {
+    if agent.runner_type.trim().is_empty()
+        && let Some(profile) = session_profile
+    {
+        return Ok(profile.clone());
+    }
     let executor = parse_runner_type(agent)?;
     let variant = extract_executor_profile_variant(&agent.tools_enabled.0);
     Ok(match variant {
@@ -1026,6 +2679,18 @@ fn extract_latest_assistant_from_history(history: &[LogMsg]) -> Option<String> {
         .filter(|content| !content.is_empty())
 }
 
+/// Compression runs once either the token threshold or the optional message-count
+/// threshold is exceeded, whichever comes first.
+fn compression_triggered(
+    token_count: u32,
+    token_threshold: u32,
+    message_count: usize,
+    message_count_threshold: Option<u32>,
+) -> bool {
+    token_count > token_threshold
+        || message_count_threshold.is_some_and(|threshold| message_count > threshold as usize)
+}
+
 fn select_messages_to_compress_by_token(
     messages: &[SimplifiedMessage],
     total_tokens: u32,
@@ -1088,6 +2753,80 @@ fn compression_type_from_db_value(value: &str) -> Option<CompressionType> {
     }
 }
 
+/// Drop the cached and persisted compression result for a session so the next
+/// context build recomputes it from the current history.
+pub async fn invalidate_compression_cache(
+    pool: &SqlitePool,
+    session_id: Uuid,
+) -> Result<(), ChatServiceError> {
+    COMPRESSION_RESULT_CACHE.remove(&session_id);
+    let query = format!("DELETE FROM {COMPRESSION_STATE_TABLE} WHERE session_id = ?1");
+    match sqlx::query(&query).bind(session_id).execute(pool).await {
+        Ok(_) => Ok(()),
+        Err(err) if is_missing_compression_state_table_error(&err) => Ok(()),
+        Err(err) => Err(ChatServiceError::Database(err)),
+    }
+}
+
+/// Drop cached compression results, in memory and persisted, that were computed under
+/// settings other than `config`'s. Returns the sessions whose cache was dropped.
+pub async fn invalidate_stale_compression_caches(
+    pool: &SqlitePool,
+    config: &ChatCompressionConfig,
+) -> Result<Vec<Uuid>, ChatServiceError> {
+    let sessions = find_stale_compression_caches(&COMPRESSION_RESULT_CACHE, pool, config).await?;
+    for session_id in &sessions {
+        invalidate_compression_cache(pool, *session_id).await?;
+    }
+    Ok(sessions)
+}
+
+/// Sessions whose compression result, cached in `cache` or persisted, was computed under
+/// settings other than `config`'s, sorted.
+async fn find_stale_compression_caches(
+    cache: &DashMap<Uuid, CompressionCacheEntry>,
+    pool: &SqlitePool,
+    config: &ChatCompressionConfig,
+) -> Result<Vec<Uuid>, ChatServiceError> {
+    let current = effective_compression_settings(config);
+    let mut stale: HashSet<Uuid> = cache
+        .iter()
+        .filter(|entry| {
+            (
+                entry.token_threshold,
+                entry.compression_percentage,
+                entry.message_count_threshold,
+            ) != current
+        })
+        .map(|entry| *entry.key())
+        .collect();
+
+    let query = format!(
+        "SELECT session_id, token_threshold, compression_percentage, message_count_threshold
+         FROM {COMPRESSION_STATE_TABLE}"
+    );
+    let rows = match sqlx::query(&query).fetch_all(pool).await {
+        Ok(rows) => rows,
+        Err(err) if is_missing_compression_state_table_error(&err) => Vec::new(),
+        Err(err) => return Err(ChatServiceError::Database(err)),
+    };
+    for row in rows {
+        let session_id: Uuid = row.try_get("session_id")?;
+        let persisted = (
+            parse_required_u32(&row, "token_threshold")?,
+            parse_required_u32(&row, "compression_percentage")?,
+            row.try_get::<Option<i64>, _>("message_count_threshold")?,
+        );
+        if persisted != (current.0, u32::from(current.1), current.2.map(i64::from)) {
+            stale.insert(session_id);
+        }
+    }
+
+    let mut sessions: Vec<Uuid> = stale.into_iter().collect();
+    sessions.sort();
+    Ok(sessions)
+}
+
 fn is_missing_compression_state_table_error(err: &sqlx::Error) -> bool {
     match err {
         sqlx::Error::Database(db_err) => {
@@ -1124,6 +2863,7 @@ fn cache_compression_result_in_memory(
     source_message_count: usize,
     token_threshold: u32,
     compression_percentage: u8,
+    message_count_threshold: Option<u32>,
     source_token_count: u32,
     result: &CompressionResult,
 ) -> CompressionCacheEntry {
@@ -1133,6 +2873,7 @@ fn cache_compression_result_in_memory(
         source_message_count,
         token_threshold,
         compression_percentage,
+        message_count_threshold,
         source_token_count,
         effective_token_count,
         result: result.clone(),
@@ -1168,18 +2909,20 @@ async fn persist_compression_result(
             source_message_count,
             token_threshold,
             compression_percentage,
+            message_count_threshold,
             source_token_count,
             effective_token_count,
             compression_type,
             warning_json,
             result_messages_json,
             updated_at
-        ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, datetime('now', 'subsec'))
+        ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, datetime('now', 'subsec'))
         ON CONFLICT(session_id) DO UPDATE SET
             source_fingerprint = excluded.source_fingerprint,
             source_message_count = excluded.source_message_count,
             token_threshold = excluded.token_threshold,
             compression_percentage = excluded.compression_percentage,
+            message_count_threshold = excluded.message_count_threshold,
             source_token_count = excluded.source_token_count,
             effective_token_count = excluded.effective_token_count,
             compression_type = excluded.compression_type,
@@ -1194,6 +2937,7 @@ async fn persist_compression_result(
         .bind(entry.source_message_count as i64)
         .bind(entry.token_threshold as i64)
         .bind(entry.compression_percentage as i64)
+        .bind(entry.message_count_threshold.map(i64::from))
         .bind(entry.source_token_count as i64)
         .bind(entry.effective_token_count as i64)
         .bind(compression_type_to_db_value(&entry.result.compression_type))
@@ -1223,6 +2967,7 @@ async fn cache_compression_result(
     source_message_count: usize,
     token_threshold: u32,
     compression_percentage: u8,
+    message_count_threshold: Option<u32>,
     source_token_count: u32,
     result: &CompressionResult,
 ) {
@@ -1232,6 +2977,7 @@ async fn cache_compression_result(
         source_message_count,
         token_threshold,
         compression_percentage,
+        message_count_threshold,
         source_token_count,
         result,
     );
@@ -1255,6 +3001,7 @@ async fn load_persisted_compression_result(
             source_message_count,
             token_threshold,
             compression_percentage,
+            message_count_threshold,
             source_token_count,
             effective_token_count,
             compression_type,
@@ -1313,6 +3060,9 @@ async fn load_persisted_compression_result(
             return Ok(None);
         }
     };
+    let message_count_threshold = row
+        .try_get::<Option<i64>, _>("message_count_threshold")?
+        .and_then(|value| u32::try_from(value).ok());
     let source_token_count = parse_required_u32(&row, "source_token_count")?;
     let effective_token_count = parse_required_u32(&row, "effective_token_count")?;
 
@@ -1349,6 +3099,7 @@ async fn load_persisted_compression_result(
         source_message_count,
         token_threshold,
         compression_percentage,
+        message_count_threshold,
         source_token_count,
         effective_token_count,
         result: CompressionResult {
@@ -1359,6 +3110,78 @@ async fn load_persisted_compression_result(
     }))
 }
 
+/// Compacted shared context built from the cached compression result without running
+/// compression: the cached messages followed by everything appended since. Returns `None`
+/// when the cache holds no compaction computed under the current settings for a prefix of
+/// the session's history.
+pub async fn build_cached_compacted_context(
+    pool: &SqlitePool,
+    session_id: Uuid,
+) -> Result<Option<CompactedContext>, ChatServiceError> {
+    let Some(cached) = get_compression_cache_entry(pool, session_id).await? else {
+        return Ok(None);
+    };
+    let all_messages = filter_visible_messages(
+        ChatMessage::find_by_session_id(pool, session_id, None).await?,
+        None,
+    );
+    let agents = ChatAgent::find_all(pool).await?;
+    let agent_map: HashMap<Uuid, String> = agents
+        .into_iter()
+        .map(|agent| (agent.id, agent.name))
+        .collect();
+    let simplified_messages: Vec<SimplifiedMessage> = all_messages
+        .iter()
+        .map(|message| to_simplified_message(message, &agent_map))
+        .collect();
+    let (token_threshold, compression_percentage, message_count_threshold) =
+        load_chat_compression_settings().await;
+    let Some(merged) = cached_compacted_messages(
+        &cached,
+        &simplified_messages,
+        token_threshold,
+        compression_percentage,
+        message_count_threshold,
+    ) else {
+        return Ok(None);
+    };
+
+    let details = context_message_details(&all_messages);
+    let format = resolve_context_format(pool, session_id).await?;
+    let (messages, contents) =
+        render_context(&merged, &details, format, load_display_timezone().await);
+
+    Ok(Some(CompactedContext {
+        messages,
+        contents,
+        format,
+        context_compacted: true,
+        compression_warning: cached.result.warning,
+    }))
+}
+
+/// Messages of the compacted shared context: the cached compaction followed by the
+/// messages appended since. `None` when the cache holds no compaction, or one computed
+/// under other settings or for a different history.
+fn cached_compacted_messages(
+    cached: &CompressionCacheEntry,
+    source_messages: &[SimplifiedMessage],
+    token_threshold: u32,
+    compression_percentage: u8,
+    message_count_threshold: Option<u32>,
+) -> Option<Vec<SimplifiedMessage>> {
+    if cached.result.compression_type == CompressionType::None {
+        return None;
+    }
+    incremental_compression_base(
+        cached,
+        source_messages,
+        token_threshold,
+        compression_percentage,
+        message_count_threshold,
+    )
+}
+
 async fn get_compression_cache_entry(
     pool: &SqlitePool,
     session_id: Uuid,
@@ -1381,12 +3204,32 @@ async fn get_compression_cache_entry(
     Ok(persisted)
 }
 
-/// Compress messages if they exceed the token threshold
+const CUTOFF_FILE_PREFIX: &str = "cutoff_message_";
+const CUTOFF_FILE_EXTENSION: &str = ".json";
+
+/// File name of the cutoff archive with the given index.
+pub fn cutoff_file_name(index: u32) -> String {
+    format!("{CUTOFF_FILE_PREFIX}{index}{CUTOFF_FILE_EXTENSION}")
+}
+
+/// Index of a cutoff archive file name; `None` for anything not shaped exactly like
+/// `cutoff_message_{n}.json`.
+pub fn parse_cutoff_file_index(file_name: &str) -> Option<u32> {
+    let digits = file_name
+        .strip_prefix(CUTOFF_FILE_PREFIX)?
+        .strip_suffix(CUTOFF_FILE_EXTENSION)?;
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    digits.parse().ok()
+}
+
+/// Compress messages if they exceed the token or message-count threshold
 ///
 /// This function implements the compression strategy:
 /// 1. Calculate total token count using tiktoken
-/// 2. If under threshold, return messages unchanged
-/// 3. If over threshold:
+/// 2. If under both thresholds, return messages unchanged
+/// 3. If over either threshold:
 ///    - Select a prefix whose tokens are >= configured compression percentage
 ///    - Try AI summarization with each session agent
 ///    - If all agents fail, truncate to cutoff file and return warning
@@ -1397,19 +3240,77 @@ async fn get_compression_cache_entry(
 /// * `messages` - Messages to potentially compress
 /// * `token_threshold` - Token count that triggers compression
 /// * `compression_percentage` - Percentage of messages to compress (default 25)
+/// * `message_count_threshold` - Optional message count that also triggers compression
 /// * `session_agents` - AI agents in the session for summarization
 /// * `workspace_path` - Workspace path for running agents
 /// * `context_dir` - Path to context directory for storing cutoff files
 #[allow(clippy::too_many_arguments)]
-pub async fn compress_messages_if_needed(
+pub async fn compress_messages_if_needed(
+    pool: &SqlitePool,
+    session_id: Uuid,
+    messages: Vec<SimplifiedMessage>,
+    token_threshold: u32,
+    compression_percentage: u8,
+    message_count_threshold: Option<u32>,
+    session_agents: &[ChatSessionAgent],
+    workspace_path: &Path,
+    context_dir: Option<&Path>,
+) -> Result<CompressionResult, ChatServiceError> {
+    compress_messages(
+        pool,
+        session_id,
+        messages,
+        token_threshold,
+        compression_percentage,
+        message_count_threshold,
+        session_agents,
+        workspace_path,
+        context_dir,
+        false,
+    )
+    .await
+}
+
+/// The cached compression result followed by the messages appended since, when the cache
+/// was built with the same settings from a prefix of `source_messages`.
+fn incremental_compression_base(
+    cached: &CompressionCacheEntry,
+    source_messages: &[SimplifiedMessage],
+    token_threshold: u32,
+    compression_percentage: u8,
+    message_count_threshold: Option<u32>,
+) -> Option<Vec<SimplifiedMessage>> {
+    if cached.token_threshold != token_threshold
+        || cached.compression_percentage != compression_percentage
+        || cached.message_count_threshold != message_count_threshold
+        || cached.source_message_count > source_messages.len()
+    {
+        return None;
+    }
+    let prefix_fingerprint =
+        calculate_messages_fingerprint(&source_messages[..cached.source_message_count]);
+    if prefix_fingerprint != cached.source_fingerprint {
+        return None;
+    }
+    let mut merged = cached.result.messages.clone();
+    merged.extend_from_slice(&source_messages[cached.source_message_count..]);
+    Some(merged)
+}
+
+/// [`compress_messages_if_needed`], optionally ignoring the cached result so the history
+/// is compressed from scratch. The new result is cached either way.
+#[allow(clippy::too_many_arguments)]
+async fn compress_messages(
     pool: &SqlitePool,
     session_id: Uuid,
     messages: Vec<SimplifiedMessage>,
     token_threshold: u32,
     compression_percentage: u8,
+    message_count_threshold: Option<u32>,
     session_agents: &[ChatSessionAgent],
     workspace_path: &Path,
     context_dir: Option<&Path>,
+    bypass_cache: bool,
 ) -> Result<CompressionResult, ChatServiceError> {
     let source_messages = messages;
     let source_fingerprint = calculate_messages_fingerprint(&source_messages);
@@ -1417,12 +3318,17 @@ pub async fn compress_messages_if_needed(
     let mut effective_messages = source_messages.clone();
     let mut inherited_compression_type: Option<CompressionType> = None;
     let mut inherited_warning: Option<CompressionWarning> = None;
-    let cached_entry = get_compression_cache_entry(pool, session_id).await?;
+    let cached_entry = if bypass_cache {
+        None
+    } else {
+        get_compression_cache_entry(pool, session_id).await?
+    };
 
     if let Some(cached) = cached_entry.as_ref()
         && cached.source_fingerprint == source_fingerprint
         && cached.token_threshold == token_threshold
         && cached.compression_percentage == compression_percentage
+        && cached.message_count_threshold == message_count_threshold
     {
         tracing::debug!(
             session_id = %session_id,
@@ -1434,28 +3340,26 @@ pub async fn compress_messages_if_needed(
         return Ok(cached.result.clone());
     }
     if let Some(cached) = cached_entry.as_ref()
-        && cached.token_threshold == token_threshold
-        && cached.compression_percentage == compression_percentage
-        && cached.source_message_count <= source_messages.len()
+        && let Some(merged) = incremental_compression_base(
+            cached,
+            &source_messages,
+            token_threshold,
+            compression_percentage,
+            message_count_threshold,
+        )
     {
-        let prefix_fingerprint =
-            calculate_messages_fingerprint(&source_messages[..cached.source_message_count]);
-        if prefix_fingerprint == cached.source_fingerprint {
-            let mut merged = cached.result.messages.clone();
-            merged.extend_from_slice(&source_messages[cached.source_message_count..]);
-            effective_messages = merged;
-            if cached.result.compression_type != CompressionType::None {
-                inherited_compression_type = Some(cached.result.compression_type.clone());
-                inherited_warning = cached.result.warning.clone();
-            }
-            tracing::debug!(
-                session_id = %session_id,
-                base_source_messages = cached.source_message_count,
-                new_messages = source_messages.len().saturating_sub(cached.source_message_count),
-                inherited_compression_type = ?cached.result.compression_type,
-                "Using incremental compression base for appended session history"
-            );
+        effective_messages = merged;
+        if cached.result.compression_type != CompressionType::None {
+            inherited_compression_type = Some(cached.result.compression_type.clone());
+            inherited_warning = cached.result.warning.clone();
         }
+        tracing::debug!(
+            session_id = %session_id,
+            base_source_messages = cached.source_message_count,
+            new_messages = source_messages.len().saturating_sub(cached.source_message_count),
+            inherited_compression_type = ?cached.result.compression_type,
+            "Using incremental compression base for appended session history"
+        );
     }
 
     let token_count = estimate_token_count(&effective_messages);
@@ -1466,11 +3370,18 @@ pub async fn compress_messages_if_needed(
         effective_token_count = token_count,
         token_count = token_count,
         threshold = token_threshold,
+        message_count = effective_messages.len(),
+        message_count_threshold = ?message_count_threshold,
         "Checking if compression is needed"
     );
 
-    // If under threshold, no compression needed
-    if token_count <= token_threshold {
+    // If under every threshold, no compression needed
+    if !compression_triggered(
+        token_count,
+        token_threshold,
+        effective_messages.len(),
+        message_count_threshold,
+    ) {
         let compression_type = inherited_compression_type.unwrap_or(CompressionType::None);
         let warning = if compression_type == CompressionType::None {
             None
@@ -1489,6 +3400,7 @@ pub async fn compress_messages_if_needed(
             source_messages.len(),
             token_threshold,
             compression_percentage,
+            message_count_threshold,
             source_token_count,
             &result,
         )
@@ -1510,6 +3422,7 @@ pub async fn compress_messages_if_needed(
             source_messages.len(),
             token_threshold,
             compression_percentage,
+            message_count_threshold,
             source_token_count,
             &result,
         )
@@ -1539,59 +3452,73 @@ pub async fn compress_messages_if_needed(
     );
 
     // Try AI summarization with available agents
-    if !session_agents.is_empty()
+    let mut strict_summary = false;
+    while !session_agents.is_empty()
         && let Some(summary) = try_summarize_with_agents(
             pool,
             session_id,
             session_agents,
             messages_to_compress,
             workspace_path,
+            strict_summary,
         )
         .await
     {
         // Create summary message and prepend to kept messages
-        let summary_message = SimplifiedMessage {
-            sender: "system:summary".to_string(),
-            content: format!("[History Summary]\n{}", summary),
-            timestamp: Utc::now().to_rfc3339(),
-        };
-
-        let mut result_messages = vec![summary_message];
+        let mut result_messages = vec![history_summary_message(&summary)];
         result_messages.extend(messages_to_keep.to_vec());
         let compressed_token_count = estimate_token_count(&result_messages);
 
         if compressed_token_count >= token_count {
+            if !strict_summary
+                && super::config::load_config_from_file(&config_path())
+                    .await
+                    .chat_compression
+                    .retry_ineffective_summary
+            {
+                tracing::warn!(
+                    session_id = %session_id,
+                    before_tokens = token_count,
+                    after_tokens = compressed_token_count,
+                    "AI summarization did not reduce token usage, retrying with a stricter prompt"
+                );
+                strict_summary = true;
+                continue;
+            }
             tracing::warn!(
                 session_id = %session_id,
                 before_tokens = token_count,
                 after_tokens = compressed_token_count,
                 "AI summarization did not reduce token usage, falling back to truncation"
             );
-        } else {
-            tracing::info!(
-                session_id = %session_id,
-                before_tokens = token_count,
-                after_tokens = compressed_token_count,
-                "AI summarization reduced token usage"
-            );
-            let result = CompressionResult {
-                messages: result_messages,
-                compression_type: CompressionType::AiSummarized,
-                warning: None,
-            };
-            cache_compression_result(
-                pool,
-                session_id,
-                source_fingerprint,
-                source_messages.len(),
-                token_threshold,
-                compression_percentage,
-                source_token_count,
-                &result,
-            )
-            .await;
-            return Ok(result);
+            break;
         }
+
+        tracing::info!(
+            session_id = %session_id,
+            before_tokens = token_count,
+            after_tokens = compressed_token_count,
+            strict = strict_summary,
+            "AI summarization reduced token usage"
+        );
+        let result = CompressionResult {
+            messages: result_messages,
+            compression_type: CompressionType::AiSummarized,
+            warning: None,
+        };
+        cache_compression_result(
+            pool,
+            session_id,
+            source_fingerprint,
+            source_messages.len(),
+            token_threshold,
+            compression_percentage,
+            message_count_threshold,
+            source_token_count,
+            &result,
+        )
+        .await;
+        return Ok(result);
     }
 
     // All agents failed - fallback to truncation
@@ -1605,7 +3532,7 @@ pub async fn compress_messages_if_needed(
         // Find next available cutoff index
         let mut index = 0;
         loop {
-            let candidate = ctx_dir.join(format!("cutoff_message_{}.json", index));
+            let candidate = ctx_dir.join(cutoff_file_name(index));
             if !candidate.exists() {
                 break candidate;
             }
@@ -1651,6 +3578,7 @@ pub async fn compress_messages_if_needed(
             messages_to_compress_count, selected_compress_tokens, cutoff_path_str
         ),
         timestamp: Utc::now().to_rfc3339(),
+        id: None,
     }];
     result_messages.extend(messages_to_keep.to_vec());
 
@@ -1674,6 +3602,7 @@ pub async fn compress_messages_if_needed(
         source_messages.len(),
         token_threshold,
         compression_percentage,
+        message_count_threshold,
         source_token_count,
         &result,
     )
@@ -1683,16 +3612,174 @@ pub async fn compress_messages_if_needed(
 
 #[cfg(test)]
 mod tests {
-    use db::models::chat_session_agent::{ChatSessionAgent, ChatSessionAgentState};
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use db::models::{
+        chat_agent::ChatAgent,
+        chat_message::{ChatMessage, ChatSenderType},
+        chat_run::ChatRun,
+        chat_session_agent::{ChatSessionAgent, ChatSessionAgentState},
+    };
+    use executors::{
+        executors::{BaseCodingAgent, ThinkingEffort},
+        profile::ExecutorProfileId,
+    };
     use sqlx::SqlitePool;
     use uuid::Uuid;
 
     use super::{
-        CompressionType, SimplifiedMessage, all_agents_running, compress_messages_if_needed,
-        limit_summary_input_messages, parse_mentions, parse_send_message_directives,
-        prioritize_summary_agents, select_messages_to_compress_by_token,
+        ChatCompressionConfig, ChatContextFormat, CompressionType, ContextMessageDetails,
+        ContextScope, SimplifiedMessage, SummaryAgentPreview, SummaryBusyAgentsPolicy,
+        SummaryFallbackReason, UiLanguage, all_agents_running, apply_context_scope,
+        apply_focus_mode, build_debug_summarization_prompt, build_summarization_prompt,
+        build_translation_prompt, compress_messages_if_needed, compression_triggered,
+        cutoff_file_name, detect_language, focus_mode_enabled, format_display_time,
+        limit_summary_input_messages, normalize_attachment_kind,
+        normalize_executor_profile_variant, parse_cutoff_file_index, parse_display_timezone,
+        parse_executor_profile_id, parse_mentions, parse_send_message_directives,
+        prioritize_summary_agents, render_context, render_recent_context_snippet, run_audit_events,
+        select_messages_to_compress_by_token, sniff_attachment, strip_send_message_directives,
+        summary_fallback, thinking_effort, translation_language_for_ui,
+        validate_compression_config, validate_thinking_effort, wait_for_idle_agents,
     };
 
+    #[test]
+    fn compression_config_validation_rejects_adjusted_values() {
+        assert!(validate_compression_config(&ChatCompressionConfig::default()).is_ok());
+        let with = |update: fn(&mut ChatCompressionConfig)| {
+            let mut config = ChatCompressionConfig::default();
+            update(&mut config);
+            validate_compression_config(&config)
+        };
+        assert!(with(|config| config.token_threshold = 0).is_err());
+        assert!(with(|config| config.compression_percentage = 0).is_err());
+        assert!(with(|config| config.compression_percentage = 101).is_err());
+        assert!(with(|config| config.message_count_threshold = Some(0)).is_err());
+        assert!(with(|config| config.message_count_threshold = Some(200)).is_ok());
+        assert!(with(|config| config.busy_agents_wait_secs = 61).is_err());
+    }
+
+    #[test]
+    fn strict_summarization_prompt_asks_for_a_shorter_summary() {
+        let messages = vec![SimplifiedMessage {
+            sender: "user:alice".to_string(),
+            content: "ship the release".to_string(),
+            timestamp: "2026-01-01T00:00:00Z".to_string(),
+            id: None,
+        }];
+
+        let regular = build_summarization_prompt(&messages, false);
+        let strict = build_summarization_prompt(&messages, true);
+        assert!(regular.contains("under 500 words"));
+        assert!(strict.contains("under 200 words"));
+        assert!(!strict.contains("under 500 words"));
+        assert!(strict.ends_with("user:alice: ship the release\n"));
+    }
+
+    #[test]
+    fn detect_language_uses_script_then_function_words() {
+        assert_eq!(
+            detect_language("Could you please review the parser and fix this bug?"),
+            Some("en")
+        );
+        assert_eq!(
+            detect_language("Est-ce que vous pouvez vérifier les tests dans ce module ?"),
+            Some("fr")
+        );
+        assert_eq!(
+            detect_language("¿Puedes revisar el código y los tests para mañana?"),
+            Some("es")
+        );
+        assert_eq!(detect_language("このバグを修正してください。"), Some("ja"));
+        assert_eq!(
+            detect_language("이 버그를 수정해 주세요. 감사합니다."),
+            Some("ko")
+        );
+        assert_eq!(
+            detect_language("请帮我修复这个解析器中的错误，谢谢。"),
+            Some("zh")
+        );
+        assert_eq!(detect_language("ok thanks"), None);
+        assert_eq!(
+            detect_language("```\nfn main() { println!(\"the and is\"); }\n```\nok"),
+            None
+        );
+    }
+
+    #[test]
+    fn debug_summarization_template_receives_messages() {
+        let messages = vec![SimplifiedMessage {
+            sender: "agent:coder".to_string(),
+            content: "tests pass".to_string(),
+            timestamp: "2026-01-01T00:00:00Z".to_string(),
+            id: None,
+        }];
+
+        assert_eq!(
+            build_debug_summarization_prompt(&messages, "Recap:\n{messages}Done."),
+            "Recap:\nagent:coder: tests pass\nDone."
+        );
+        assert_eq!(
+            build_debug_summarization_prompt(&messages, "Recap briefly.\n"),
+            "Recap briefly.\n\nMessages:\nagent:coder: tests pass\n"
+        );
+    }
+
+    #[test]
+    fn run_audit_events_follow_run_meta() {
+        let run = ChatRun {
+            id: Uuid::new_v4(),
+            session_id: Uuid::new_v4(),
+            session_agent_id: Uuid::new_v4(),
+            run_index: 3,
+            run_dir: "/tmp/run".to_string(),
+            input_path: None,
+            output_path: None,
+            raw_log_path: None,
+            meta_path: None,
+            created_at: chrono::Utc::now(),
+        };
+        let event_types = |meta: Option<&serde_json::Value>| {
+            run_audit_events(&run, meta)
+                .iter()
+                .map(|event| serde_json::to_value(event).unwrap()["type"].clone())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            event_types(None),
+            vec!["run_started", "agent_state_changed"]
+        );
+
+        let meta = serde_json::json!({
+            "finished_at": "2026-01-02T03:04:05Z",
+            "failure_reason": "idle timeout",
+            "compression_warning": { "code": "split", "message": "history split" },
+        });
+        assert_eq!(
+            event_types(Some(&meta)),
+            vec![
+                "run_started",
+                "agent_state_changed",
+                "compression_warning",
+                "run_finished",
+                "agent_state_changed",
+            ]
+        );
+        let events = serde_json::to_value(run_audit_events(&run, Some(&meta))).unwrap();
+        assert_eq!(events[3]["failed"], true);
+        assert_eq!(events[3]["timestamp"], "2026-01-02T03:04:05Z");
+        assert_eq!(events[4]["state"], "dead");
+
+        let meta = serde_json::json!({ "failed": false, "finished_at": "bad" });
+        let events = serde_json::to_value(run_audit_events(&run, Some(&meta))).unwrap();
+        assert_eq!(events[2]["failure_reason"], serde_json::Value::Null);
+        assert_eq!(events[3]["state"], "idle");
+    }
+
     #[test]
     fn parses_mentions_with_basic_tokens() {
         let mentions = parse_mentions("@coder please check @planner");
@@ -1752,6 +3839,21 @@ mod tests {
         );
     }
 
+    #[test]
+    fn strip_send_message_directives_keeps_text_around_markers() {
+        assert_eq!(strip_send_message_directives("no markers"), "no markers");
+        assert_eq!(
+            strip_send_message_directives(
+                "[sendMessageTo@@planner] Plan is ready.\n\nSee below [sendMessageTo@@{qa}] now\n[sendMessageTo@@\"Product Manager\"]\n  - step"
+            ),
+            "Plan is ready.\n\nSee below now\n  - step"
+        );
+        assert_eq!(
+            strip_send_message_directives("keep [sendMessageTo@@{bad target}]"),
+            "keep [sendMessageTo@@{bad target}]"
+        );
+    }
+
     fn make_session_agent(state: ChatSessionAgentState) -> ChatSessionAgent {
         ChatSessionAgent {
             id: Uuid::new_v4(),
@@ -1762,6 +3864,8 @@ mod tests {
             pty_session_key: None,
             agent_session_id: None,
             agent_message_id: None,
+            muted: false,
+            sort_order: 0,
             created_at: chrono::Utc::now(),
             updated_at: chrono::Utc::now(),
         }
@@ -1774,12 +3878,10 @@ mod tests {
         let idle = make_session_agent(ChatSessionAgentState::Idle);
         let dead = make_session_agent(ChatSessionAgentState::Dead);
 
-        let prioritized = prioritize_summary_agents(&[
-            running.clone(),
-            waiting.clone(),
-            idle.clone(),
-            dead.clone(),
-        ]);
+        let prioritized = prioritize_summary_agents(
+            &[running.clone(), waiting.clone(), idle.clone(), dead.clone()],
+            &[],
+        );
 
         assert_eq!(prioritized[0].id, idle.id);
         assert_eq!(prioritized[1].id, waiting.id);
@@ -1787,6 +3889,247 @@ mod tests {
         assert_eq!(prioritized[3].id, running.id);
     }
 
+    #[test]
+    fn prioritize_summary_agents_honors_configured_order_first() {
+        let running = make_session_agent(ChatSessionAgentState::Running);
+        let idle = make_session_agent(ChatSessionAgentState::Idle);
+        let dead = make_session_agent(ChatSessionAgentState::Dead);
+
+        let prioritized = prioritize_summary_agents(
+            &[idle.clone(), dead.clone(), running.clone()],
+            &[running.agent_id, Uuid::new_v4(), dead.agent_id],
+        );
+
+        assert_eq!(prioritized[0].id, running.id);
+        assert_eq!(prioritized[1].id, dead.id);
+        assert_eq!(prioritized[2].id, idle.id);
+    }
+
+    fn make_chat_message(
+        sender_type: ChatSenderType,
+        sender_id: Option<Uuid>,
+        mentions: &[&str],
+    ) -> ChatMessage {
+        ChatMessage {
+            id: Uuid::new_v4(),
+            session_id: Uuid::new_v4(),
+            sender_type,
+            sender_id,
+            content: "message".to_string(),
+            mentions: sqlx::types::Json(mentions.iter().map(|m| m.to_string()).collect()),
+            meta: sqlx::types::Json(serde_json::json!({})),
+            created_at: chrono::Utc::now(),
+        }
+    }
+
+    #[test]
+    fn private_messages_are_visible_only_to_listed_agents() {
+        let allowed = Uuid::new_v4();
+        let other = Uuid::new_v4();
+
+        let public = make_chat_message(ChatSenderType::User, None, &[]);
+        assert!(is_message_visible_to(&public, None));
+        assert!(is_message_visible_to(&public, Some(other)));
+
+        let mut private = make_chat_message(ChatSenderType::User, None, &[]);
+        private.meta = sqlx::types::Json(serde_json::json!({
+            "visible_to": [allowed.to_string()]
+        }));
+        assert_eq!(extract_visible_to(&private.meta.0), Some(vec![allowed]));
+        assert!(is_message_visible_to(&private, Some(allowed)));
+        assert!(!is_message_visible_to(&private, Some(other)));
+        assert!(!is_message_visible_to(&private, None));
+
+        let mut reply = make_chat_message(ChatSenderType::Agent, Some(other), &[]);
+        reply.meta = sqlx::types::Json(serde_json::json!({ "visible_to": [] }));
+        assert!(is_message_visible_to(&reply, Some(other)));
+        assert!(!is_message_visible_to(&reply, Some(allowed)));
+
+        let mut explicit_null = make_chat_message(ChatSenderType::User, None, &[]);
+        explicit_null.meta = sqlx::types::Json(serde_json::json!({ "visible_to": null }));
+        assert!(is_message_visible_to(&explicit_null, None));
+    }
+
+    #[test]
+    fn context_scope_parses_tools_enabled_with_full_default() {
+        assert_eq!(
+            ContextScope::from_tools_enabled(&serde_json::json!({})),
+            ContextScope::Full
+        );
+        assert_eq!(
+            ContextScope::from_tools_enabled(&serde_json::json!({ "context_scope": "last_n" })),
+            ContextScope::LastN(20)
+        );
+        assert_eq!(
+            ContextScope::from_tools_enabled(
+                &serde_json::json!({ "context_scope": "last_n", "context_last_n": 5 })
+            ),
+            ContextScope::LastN(5)
+        );
+        assert_eq!(
+            ContextScope::from_tools_enabled(
+                &serde_json::json!({ "context_scope": "mentions_only" })
+            ),
+            ContextScope::MentionsOnly
+        );
+    }
+
+    #[test]
+    fn apply_context_scope_filters_messages() {
+        let agent_id = Uuid::new_v4();
+        let messages = vec![
+            make_chat_message(ChatSenderType::User, None, &["reviewer"]),
+            make_chat_message(ChatSenderType::User, None, &["coder"]),
+            make_chat_message(ChatSenderType::Agent, Some(agent_id), &["coder"]),
+            make_chat_message(ChatSenderType::Agent, Some(Uuid::new_v4()), &["Reviewer"]),
+        ];
+
+        let last_two = apply_context_scope(
+            messages.clone(),
+            ContextScope::LastN(2),
+            agent_id,
+            "reviewer",
+        );
+        assert_eq!(
+            last_two.iter().map(|m| m.id).collect::<Vec<_>>(),
+            vec![messages[2].id, messages[3].id]
+        );
+
+        let mentions_only = apply_context_scope(
+            messages.clone(),
+            ContextScope::MentionsOnly,
+            agent_id,
+            "reviewer",
+        );
+        assert_eq!(
+            mentions_only.iter().map(|m| m.id).collect::<Vec<_>>(),
+            vec![messages[0].id, messages[2].id, messages[3].id]
+        );
+
+        let full = apply_context_scope(messages.clone(), ContextScope::Full, agent_id, "reviewer");
+        assert_eq!(full.len(), messages.len());
+    }
+
+    #[test]
+    fn executor_profile_variant_is_checked_against_configured_variants() {
+        let configs = executors::profile::ExecutorConfigs::from_defaults();
+
+        let mut tools_enabled = serde_json::json!({ "executor_profile_variant": "plan" });
+        normalize_executor_profile_variant("claude-code", &mut tools_enabled, &configs).unwrap();
+        assert_eq!(tools_enabled["executor_profile_variant"], "PLAN");
+
+        let mut default_variant = serde_json::json!({ "executor_profile_variant": "default" });
+        assert!(
+            normalize_executor_profile_variant("CLAUDE_CODE", &mut default_variant, &configs)
+                .is_ok()
+        );
+
+        let mut unknown = serde_json::json!({ "executor_profile_variant": "turbo" });
+        let err = normalize_executor_profile_variant("CLAUDE_CODE", &mut unknown, &configs)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("TURBO"));
+        assert!(err.contains("DEFAULT, "));
+        assert!(err.contains("PLAN"));
+
+        let mut not_a_string = serde_json::json!({ "executor_profile_variant": 3 });
+        assert!(
+            normalize_executor_profile_variant("CLAUDE_CODE", &mut not_a_string, &configs).is_err()
+        );
+    }
+
+    #[test]
+    fn focus_mode_drops_unrelated_agent_messages() {
+        let agent_id = Uuid::new_v4();
+        let messages = vec![
+            make_chat_message(ChatSenderType::User, None, &["coder"]),
+            make_chat_message(ChatSenderType::Agent, Some(agent_id), &["coder"]),
+            make_chat_message(ChatSenderType::Agent, Some(Uuid::new_v4()), &["ops"]),
+            make_chat_message(ChatSenderType::Agent, Some(Uuid::new_v4()), &["Reviewer"]),
+            make_chat_message(ChatSenderType::System, None, &[]),
+        ];
+
+        let focused = apply_focus_mode(messages.clone(), agent_id, "reviewer");
+        assert_eq!(
+            focused.iter().map(|m| m.id).collect::<Vec<_>>(),
+            vec![
+                messages[0].id,
+                messages[1].id,
+                messages[3].id,
+                messages[4].id
+            ]
+        );
+
+        assert!(!focus_mode_enabled(&serde_json::json!({})));
+        assert!(!focus_mode_enabled(
+            &serde_json::json!({ "focus_mode": "yes" })
+        ));
+        assert!(focus_mode_enabled(
+            &serde_json::json!({ "focus_mode": true })
+        ));
+    }
+
+    #[test]
+    fn thinking_effort_is_parsed_and_validated() {
+        assert_eq!(thinking_effort(&serde_json::json!({})), None);
+        assert_eq!(
+            thinking_effort(&serde_json::json!({ "thinking_effort": "high" })),
+            Some(ThinkingEffort::High)
+        );
+        assert!(validate_thinking_effort(&serde_json::json!({})).is_ok());
+        assert!(validate_thinking_effort(&serde_json::json!({ "thinking_effort": null })).is_ok());
+        assert!(
+            validate_thinking_effort(&serde_json::json!({ "thinking_effort": "medium" })).is_ok()
+        );
+        assert!(
+            validate_thinking_effort(&serde_json::json!({ "thinking_effort": "extreme" })).is_err()
+        );
+        assert!(validate_thinking_effort(&serde_json::json!({ "thinking_effort": 2 })).is_err());
+    }
+
+    #[test]
+    fn summary_fallback_reflects_busy_agents_policy() {
+        let mut config = ChatCompressionConfig::default();
+        assert!(matches!(
+            summary_fallback(&[], &config),
+            Some(SummaryAgentPreview::Truncation {
+                reason: SummaryFallbackReason::NoAgents,
+                wait_secs: None,
+            })
+        ));
+        assert!(
+            summary_fallback(
+                &[
+                    make_session_agent(ChatSessionAgentState::Running),
+                    make_session_agent(ChatSessionAgentState::Dead),
+                ],
+                &config,
+            )
+            .is_none()
+        );
+
+        let busy = [
+            make_session_agent(ChatSessionAgentState::Running),
+            make_session_agent(ChatSessionAgentState::Running),
+        ];
+        config.busy_agents_policy = SummaryBusyAgentsPolicy::Wait;
+        config.busy_agents_wait_secs = 90;
+        assert!(matches!(
+            summary_fallback(&busy, &config),
+            Some(SummaryAgentPreview::Truncation {
+                reason: SummaryFallbackReason::AllAgentsBusy,
+                wait_secs: Some(60),
+            })
+        ));
+
+        config.busy_agents_policy = SummaryBusyAgentsPolicy::Summarizer;
+        config.summarizer_profile = Some(ExecutorProfileId::new(BaseCodingAgent::Codex));
+        assert!(matches!(
+            summary_fallback(&busy, &config),
+            Some(SummaryAgentPreview::Summarizer { .. })
+        ));
+    }
+
     #[test]
     fn all_agents_running_only_true_when_non_empty_and_all_running() {
         assert!(!all_agents_running(&[]));
@@ -1800,6 +4143,58 @@ mod tests {
         ]));
     }
 
+    #[tokio::test]
+    async fn wait_for_idle_agents_times_out_when_agents_stay_busy() {
+        let polls = AtomicUsize::new(0);
+        let max_wait = Duration::from_millis(60);
+        let started = tokio::time::Instant::now();
+
+        let agents = wait_for_idle_agents(
+            || {
+                polls.fetch_add(1, Ordering::Relaxed);
+                std::future::ready(Ok(vec![
+                    make_session_agent(ChatSessionAgentState::Running),
+                    make_session_agent(ChatSessionAgentState::Running),
+                ]))
+            },
+            max_wait,
+            Duration::from_millis(10),
+        )
+        .await
+        .expect("wait should not fail");
+
+        assert!(all_agents_running(&agents));
+        assert!(started.elapsed() >= max_wait);
+        assert!(polls.load(Ordering::Relaxed) > 1);
+    }
+
+    #[tokio::test]
+    async fn wait_for_idle_agents_returns_once_an_agent_is_idle() {
+        let polls = AtomicUsize::new(0);
+
+        let agents = wait_for_idle_agents(
+            || {
+                let poll = polls.fetch_add(1, Ordering::Relaxed);
+                let state = if poll == 0 {
+                    ChatSessionAgentState::Running
+                } else {
+                    ChatSessionAgentState::Idle
+                };
+                std::future::ready(Ok(vec![
+                    make_session_agent(ChatSessionAgentState::Running),
+                    make_session_agent(state),
+                ]))
+            },
+            Duration::from_secs(5),
+            Duration::from_millis(10),
+        )
+        .await
+        .expect("wait should not fail");
+
+        assert!(!all_agents_running(&agents));
+        assert_eq!(polls.load(Ordering::Relaxed), 2);
+    }
+
     #[test]
     fn select_messages_to_compress_uses_token_budget() {
         let messages = vec![
@@ -1807,21 +4202,25 @@ mod tests {
                 sender: "user:alice".to_string(),
                 content: "heavy ".repeat(500),
                 timestamp: chrono::Utc::now().to_rfc3339(),
+                id: None,
             },
             SimplifiedMessage {
                 sender: "user:bob".to_string(),
                 content: "small".to_string(),
                 timestamp: chrono::Utc::now().to_rfc3339(),
+                id: None,
             },
             SimplifiedMessage {
                 sender: "agent:bot".to_string(),
                 content: "small".to_string(),
                 timestamp: chrono::Utc::now().to_rfc3339(),
+                id: None,
             },
             SimplifiedMessage {
                 sender: "agent:bot".to_string(),
                 content: "small".to_string(),
                 timestamp: chrono::Utc::now().to_rfc3339(),
+                id: None,
             },
         ];
 
@@ -1841,11 +4240,13 @@ mod tests {
                 sender: "user:alice".to_string(),
                 content: "short".to_string(),
                 timestamp: chrono::Utc::now().to_rfc3339(),
+                id: None,
             },
             SimplifiedMessage {
                 sender: "agent:bot".to_string(),
                 content: "short reply".to_string(),
                 timestamp: chrono::Utc::now().to_rfc3339(),
+                id: None,
             },
         ];
 
@@ -1861,16 +4262,19 @@ mod tests {
                 sender: "user:a".to_string(),
                 content: "old ".repeat(300),
                 timestamp: chrono::Utc::now().to_rfc3339(),
+                id: None,
             },
             SimplifiedMessage {
                 sender: "agent:b".to_string(),
                 content: "middle ".repeat(300),
                 timestamp: chrono::Utc::now().to_rfc3339(),
+                id: None,
             },
             SimplifiedMessage {
                 sender: "user:c".to_string(),
                 content: "recent ".repeat(300),
                 timestamp: chrono::Utc::now().to_rfc3339(),
+                id: None,
             },
         ];
 
@@ -1880,8 +4284,31 @@ mod tests {
             limited.last().map(|m| m.content.as_str()),
             Some(messages[2].content.as_str())
         );
-        assert!(before > after);
-        assert!(after <= 200 || limited.len() == 1);
+        assert!(before > after);
+        assert!(after <= 200 || limited.len() == 1);
+    }
+
+    #[test]
+    fn parses_mixed_bare_and_quoted_mentions() {
+        let mentions = parse_mentions(
+            "@coder sync with @\"Product  Manager\" and @\"QA-Lead\", then @coder again",
+        );
+        assert_eq!(mentions, vec!["coder", "Product Manager", "QA-Lead"]);
+    }
+
+    #[test]
+    fn ignores_unterminated_or_invalid_quoted_mentions() {
+        let mentions = parse_mentions("@\"Product Manager and @\"bad/name\" @ok");
+        assert_eq!(mentions, vec!["ok"]);
+        assert!(parse_mentions("@\"   \"").is_empty());
+    }
+
+    #[test]
+    fn parses_quoted_send_message_directives() {
+        let mentions = parse_send_message_directives(
+            "[sendMessageTo@@\"Product Manager\"] spec ready; [sendMessageTo@@coder] build it",
+        );
+        assert_eq!(mentions, vec!["Product Manager", "coder"]);
     }
 
     #[test]
@@ -1915,21 +4342,25 @@ mod tests {
                 sender: "user:alice".to_string(),
                 content: "A very long message that should exceed tiny threshold quickly".repeat(8),
                 timestamp: chrono::Utc::now().to_rfc3339(),
+                id: None,
             },
             SimplifiedMessage {
                 sender: "agent:bot".to_string(),
                 content: "Second long message for compression coverage".repeat(8),
                 timestamp: chrono::Utc::now().to_rfc3339(),
+                id: None,
             },
             SimplifiedMessage {
                 sender: "user:bob".to_string(),
                 content: "Recent message to keep".to_string(),
                 timestamp: chrono::Utc::now().to_rfc3339(),
+                id: None,
             },
             SimplifiedMessage {
                 sender: "agent:bot".to_string(),
                 content: "Another recent message to keep".to_string(),
                 timestamp: chrono::Utc::now().to_rfc3339(),
+                id: None,
             },
         ];
 
@@ -1937,8 +4368,9 @@ mod tests {
             &pool,
             session_id,
             messages.clone(),
-            1,   // force compression
-            50,  // compress half
+            1,  // force compression
+            50, // compress half
+            None,
             &[], // no agents available
             workspace,
             None, // no context_dir, use legacy split file
@@ -1992,16 +4424,19 @@ mod tests {
                 sender: "user:alice".to_string(),
                 content: "A very long message that should exceed tiny threshold quickly".repeat(8),
                 timestamp: chrono::Utc::now().to_rfc3339(),
+                id: None,
             },
             SimplifiedMessage {
                 sender: "agent:bot".to_string(),
                 content: "Second long message for compression coverage".repeat(8),
                 timestamp: chrono::Utc::now().to_rfc3339(),
+                id: None,
             },
             SimplifiedMessage {
                 sender: "user:bob".to_string(),
                 content: "Recent message to keep".to_string(),
                 timestamp: chrono::Utc::now().to_rfc3339(),
+                id: None,
             },
         ];
 
@@ -2011,6 +4446,7 @@ mod tests {
             messages.clone(),
             1,
             50,
+            None,
             &[],
             workspace,
             Some(context_dir.path()),
@@ -2031,6 +4467,7 @@ mod tests {
             messages.clone(),
             1,
             50,
+            None,
             &[],
             workspace,
             Some(context_dir.path()),
@@ -2064,6 +4501,50 @@ mod tests {
             cutoff_count, 1,
             "cached compression should avoid creating extra cutoff files"
         );
+
+        let forced = compress_messages(
+            &pool,
+            session_id,
+            messages.clone(),
+            1,
+            50,
+            None,
+            &[],
+            workspace,
+            Some(context_dir.path()),
+            true,
+        )
+        .await
+        .expect("forced compression should succeed");
+        let forced_path = forced
+            .warning
+            .as_ref()
+            .expect("warning expected")
+            .split_file_path
+            .clone();
+        assert_ne!(
+            forced_path, first_path,
+            "bypassing the cache should compress again"
+        );
+
+        let after = compress_messages_if_needed(
+            &pool,
+            session_id,
+            messages,
+            1,
+            50,
+            None,
+            &[],
+            workspace,
+            Some(context_dir.path()),
+        )
+        .await
+        .expect("compression after forced run should succeed");
+        assert_eq!(
+            after.warning.expect("warning expected").split_file_path,
+            forced_path,
+            "forced result should replace the cached one"
+        );
     }
 
     #[tokio::test]
@@ -2101,16 +4582,19 @@ mod tests {
                 sender: "user:alice".to_string(),
                 content: "A very long message that should exceed tiny threshold quickly".repeat(8),
                 timestamp: chrono::Utc::now().to_rfc3339(),
+                id: None,
             },
             SimplifiedMessage {
                 sender: "agent:bot".to_string(),
                 content: "Second long message for compression coverage".repeat(8),
                 timestamp: chrono::Utc::now().to_rfc3339(),
+                id: None,
             },
             SimplifiedMessage {
                 sender: "user:bob".to_string(),
                 content: "Recent message to keep".to_string(),
                 timestamp: chrono::Utc::now().to_rfc3339(),
+                id: None,
             },
         ];
 
@@ -2120,6 +4604,7 @@ mod tests {
             messages.clone(),
             1,
             50,
+            None,
             &[],
             workspace,
             Some(context_dir.path()),
@@ -2152,6 +4637,7 @@ mod tests {
             messages,
             1,
             50,
+            None,
             &[],
             workspace,
             Some(context_dir.path()),
@@ -2200,21 +4686,25 @@ mod tests {
                 sender: "user:alice".to_string(),
                 content: "A very long message that should exceed threshold".repeat(200),
                 timestamp: chrono::Utc::now().to_rfc3339(),
+                id: None,
             },
             SimplifiedMessage {
                 sender: "agent:bot".to_string(),
                 content: "Another very long message for compression".repeat(200),
                 timestamp: chrono::Utc::now().to_rfc3339(),
+                id: None,
             },
             SimplifiedMessage {
                 sender: "user:bob".to_string(),
                 content: "small keep".to_string(),
                 timestamp: chrono::Utc::now().to_rfc3339(),
+                id: None,
             },
             SimplifiedMessage {
                 sender: "agent:bot".to_string(),
                 content: "small keep too".to_string(),
                 timestamp: chrono::Utc::now().to_rfc3339(),
+                id: None,
             },
         ];
 
@@ -2227,6 +4717,7 @@ mod tests {
             base_messages.clone(),
             threshold,
             50,
+            None,
             &[],
             workspace,
             Some(context_dir.path()),
@@ -2240,6 +4731,7 @@ mod tests {
             sender: "user:charlie".to_string(),
             content: "new tail message".to_string(),
             timestamp: chrono::Utc::now().to_rfc3339(),
+            id: None,
         });
 
         let second = compress_messages_if_needed(
@@ -2248,6 +4740,7 @@ mod tests {
             appended,
             threshold,
             50,
+            None,
             &[],
             workspace,
             Some(context_dir.path()),
@@ -2274,6 +4767,236 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn compaction_preview_starts_from_compacted_base() {
+        let pool = SqlitePool::connect("sqlite::memory:")
+            .await
+            .expect("create sqlite memory pool");
+        let session_id = Uuid::new_v4();
+        let context_dir = tempfile::tempdir().expect("create temp context dir");
+        let message = |sender: &str, content: String| SimplifiedMessage {
+            sender: sender.to_string(),
+            content,
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            id: Some(Uuid::new_v4()),
+        };
+        let base_messages = vec![
+            message("user:alice", "an old long request ".repeat(200)),
+            message("agent:bot", "an old long reply ".repeat(200)),
+            message("user:bob", "small keep".to_string()),
+            message("agent:bot", "small keep too".to_string()),
+        ];
+        let threshold = super::estimate_token_count(&base_messages)
+            .saturating_sub(1)
+            .max(1);
+        let compacted = compress_messages_if_needed(
+            &pool,
+            session_id,
+            base_messages.clone(),
+            threshold,
+            50,
+            None,
+            &[],
+            std::path::Path::new("."),
+            Some(context_dir.path()),
+        )
+        .await
+        .expect("compress history");
+        assert_eq!(compacted.compression_type, CompressionType::Truncated);
+        let cached = super::get_compression_cache_entry(&pool, session_id)
+            .await
+            .expect("load cache")
+            .expect("cached result");
+
+        let mut appended = base_messages.clone();
+        appended.push(message("user:charlie", "new tail message".to_string()));
+        let preview =
+            super::build_compaction_preview(Some(&cached), appended.clone(), threshold, 50, None);
+        let mut effective = compacted.messages.clone();
+        effective.push(appended[4].clone());
+        assert_eq!(preview.total_messages, effective.len());
+        assert_eq!(
+            preview.total_tokens,
+            super::estimate_token_count(&effective)
+        );
+        assert_eq!(preview.messages[0].message_id, None);
+        assert!(preview.messages[0].content.starts_with("[History Summary"));
+        assert!(
+            preview
+                .messages
+                .iter()
+                .all(|message| message.message_id != base_messages[0].id)
+        );
+
+        // Changed settings ignore the cached base, like compression itself.
+        let preview = super::build_compaction_preview(Some(&cached), appended, threshold, 40, None);
+        assert_eq!(preview.total_messages, 5);
+        assert_eq!(preview.messages[0].message_id, base_messages[0].id);
+    }
+
+    #[tokio::test]
+    async fn stale_compression_caches_are_found_in_memory_and_persisted() {
+        let pool = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .expect("create sqlite memory pool");
+        sqlx::migrate!("../db/migrations")
+            .run(&pool)
+            .await
+            .expect("run migrations");
+        let config = ChatCompressionConfig::default();
+        let (token_threshold, compression_percentage, message_count_threshold) =
+            super::effective_compression_settings(&config);
+        let entry =
+            |token_threshold: u32, compression_percentage: u8| super::CompressionCacheEntry {
+                source_fingerprint: 1,
+                source_message_count: 0,
+                token_threshold,
+                compression_percentage,
+                message_count_threshold,
+                source_token_count: 0,
+                effective_token_count: 0,
+                result: super::CompressionResult {
+                    messages: Vec::new(),
+                    compression_type: CompressionType::None,
+                    warning: None,
+                },
+            };
+
+        let cache = dashmap::DashMap::new();
+        let [
+            current_in_memory,
+            stale_in_memory,
+            current_persisted,
+            stale_persisted,
+        ] = [(); 4].map(|_| Uuid::new_v4());
+        cache.insert(
+            current_in_memory,
+            entry(token_threshold, compression_percentage),
+        );
+        cache.insert(
+            stale_in_memory,
+            entry(token_threshold + 1, compression_percentage),
+        );
+        for (session_id, entry) in [
+            (
+                current_persisted,
+                entry(token_threshold, compression_percentage),
+            ),
+            (
+                stale_persisted,
+                entry(token_threshold, compression_percentage - 1),
+            ),
+        ] {
+            super::persist_compression_result(&pool, session_id, &entry)
+                .await
+                .expect("persist compression state");
+        }
+
+        let mut expected = vec![stale_in_memory, stale_persisted];
+        expected.sort();
+        assert_eq!(
+            super::find_stale_compression_caches(&cache, &pool, &config)
+                .await
+                .expect("find stale caches"),
+            expected
+        );
+
+        super::invalidate_compression_cache(&pool, stale_persisted)
+            .await
+            .expect("invalidate cache");
+        assert!(
+            super::load_persisted_compression_result(&pool, stale_persisted)
+                .await
+                .expect("load persisted state")
+                .is_none()
+        );
+        assert_eq!(
+            super::find_stale_compression_caches(&cache, &pool, &config)
+                .await
+                .expect("find stale caches"),
+            vec![stale_in_memory]
+        );
+    }
+
+    #[tokio::test]
+    async fn cached_compacted_context_extends_a_matching_compaction() {
+        let pool = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .expect("create sqlite memory pool");
+        sqlx::migrate!("../db/migrations")
+            .run(&pool)
+            .await
+            .expect("run migrations");
+        // A session without a cached compaction has no cached context
+        assert!(
+            super::build_cached_compacted_context(&pool, Uuid::new_v4())
+                .await
+                .expect("build cached context")
+                .is_none()
+        );
+
+        let message = |sender: &str, content: &str| SimplifiedMessage {
+            sender: sender.to_string(),
+            content: content.to_string(),
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            id: Some(Uuid::new_v4()),
+        };
+        let history = vec![
+            message("user:alice", "an old request"),
+            message("agent:bot", "an old reply"),
+            message("user:alice", "a new request"),
+        ];
+        let summary = message("system", "[History Summary] alice asked, bot replied");
+        let config = ChatCompressionConfig::default();
+        let (token_threshold, compression_percentage, message_count_threshold) =
+            super::effective_compression_settings(&config);
+        let cached = super::CompressionCacheEntry {
+            source_fingerprint: super::calculate_messages_fingerprint(&history[..2]),
+            source_message_count: 2,
+            token_threshold,
+            compression_percentage,
+            message_count_threshold,
+            source_token_count: 0,
+            effective_token_count: 0,
+            result: super::CompressionResult {
+                messages: vec![summary.clone()],
+                compression_type: CompressionType::AiSummarized,
+                warning: None,
+            },
+        };
+        let merge = |cached: &super::CompressionCacheEntry, token_threshold: u32| {
+            super::cached_compacted_messages(
+                cached,
+                &history,
+                token_threshold,
+                compression_percentage,
+                message_count_threshold,
+            )
+        };
+
+        // Messages appended since the compaction follow its summary
+        let merged = merge(&cached, token_threshold).expect("cache hit");
+        let contents: Vec<&str> = merged.iter().map(|entry| entry.content.as_str()).collect();
+        assert_eq!(contents, vec![summary.content.as_str(), "a new request"]);
+
+        // Changed settings ignore the cached compaction
+        assert!(merge(&cached, token_threshold + 1).is_none());
+
+        // So does one computed for a different history
+        let mut edited = cached.clone();
+        edited.source_fingerprint = super::calculate_messages_fingerprint(&history[1..3]);
+        assert!(merge(&edited, token_threshold).is_none());
+
+        // And one that compacted nothing
+        let mut uncompressed = cached.clone();
+        uncompressed.result.compression_type = CompressionType::None;
+        assert!(merge(&uncompressed, token_threshold).is_none());
+    }
+
     #[tokio::test]
     async fn compress_messages_keeps_original_when_under_threshold() {
         let pool = SqlitePool::connect("sqlite::memory:")
@@ -2286,11 +5009,13 @@ mod tests {
                 sender: "user:alice".to_string(),
                 content: "short message".to_string(),
                 timestamp: chrono::Utc::now().to_rfc3339(),
+                id: None,
             },
             SimplifiedMessage {
                 sender: "agent:bot".to_string(),
                 content: "another short one".to_string(),
                 timestamp: chrono::Utc::now().to_rfc3339(),
+                id: None,
             },
         ];
 
@@ -2300,6 +5025,7 @@ mod tests {
             messages.clone(),
             u32::MAX, // never trigger compression
             25,
+            None,
             &[],
             workspace,
             None, // no context_dir
@@ -2311,4 +5037,288 @@ mod tests {
         assert_eq!(result.messages.len(), messages.len());
         assert!(result.warning.is_none());
     }
+
+    #[test]
+    fn display_timezone_parses_named_and_offset_forms() {
+        let east = |seconds| chrono::FixedOffset::east_opt(seconds);
+        assert_eq!(parse_display_timezone("local"), None);
+        assert_eq!(parse_display_timezone(""), None);
+        assert_eq!(parse_display_timezone("UTC"), east(0));
+        assert_eq!(parse_display_timezone("z"), east(0));
+        assert_eq!(parse_display_timezone("+08:00"), east(8 * 3600));
+        assert_eq!(parse_display_timezone("UTC-5"), east(-5 * 3600));
+        assert_eq!(parse_display_timezone("+0530"), east(5 * 3600 + 30 * 60));
+        assert_eq!(parse_display_timezone("Europe/Berlin"), None);
+        assert_eq!(parse_display_timezone("+25:00"), None);
+    }
+
+    #[test]
+    fn display_time_renders_in_configured_offset() {
+        let offset = parse_display_timezone("+08:00");
+        assert_eq!(
+            format_display_time("2026-03-01T20:30:00+00:00", offset),
+            "2026-03-02 04:30:00 +08:00"
+        );
+        assert_eq!(
+            format_display_time("2026-03-01T20:30:00Z", parse_display_timezone("UTC")),
+            "2026-03-01 20:30:00 +00:00"
+        );
+        assert_eq!(format_display_time("not a time", offset), "not a time");
+    }
+
+    #[test]
+    fn context_formats_add_details_only_when_rich() {
+        let id = Uuid::new_v4();
+        let message = SimplifiedMessage {
+            sender: "user:alice".to_string(),
+            content: "  @coder please review  ".to_string(),
+            timestamp: "2026-03-01T12:00:00+00:00".to_string(),
+            id: Some(id),
+        };
+        let details: ContextMessageDetails =
+            [(id, vec!["coder".to_string()])].into_iter().collect();
+        let utc = parse_display_timezone("UTC");
+        let messages = std::slice::from_ref(&message);
+
+        let (_, compact) = render_context(messages, &details, ChatContextFormat::JsonlCompact, utc);
+        assert!(!compact.contains(&id.to_string()));
+        assert!(compact.ends_with("}\n"));
+
+        let (values, rich) = render_context(messages, &details, ChatContextFormat::JsonlRich, utc);
+        assert_eq!(values[0]["id"], id.to_string());
+        assert_eq!(values[0]["mentions"], serde_json::json!(["coder"]));
+        assert_eq!(rich.lines().count(), 1);
+
+        let (_, markdown) = render_context(messages, &details, ChatContextFormat::Markdown, utc);
+        assert_eq!(
+            markdown,
+            format!(
+                "### user:alice (2026-03-01 12:00:00 +00:00)\nid: {id}\nmentions: @coder\n\n@coder please review\n\n"
+            )
+        );
+    }
+
+    #[test]
+    fn context_details_follow_message_ids_not_sender_and_time() {
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+        let message = |id, content: &str| SimplifiedMessage {
+            sender: "user:alice".to_string(),
+            content: content.to_string(),
+            timestamp: "2026-03-01T12:00:00+00:00".to_string(),
+            id: Some(id),
+        };
+        let messages = [message(first, "@coder one"), message(second, "@tester two")];
+        let details: ContextMessageDetails = [
+            (first, vec!["coder".to_string()]),
+            (second, vec!["tester".to_string()]),
+        ]
+        .into_iter()
+        .collect();
+
+        let (values, _) = render_context(
+            &messages,
+            &details,
+            ChatContextFormat::JsonlRich,
+            parse_display_timezone("UTC"),
+        );
+        assert_eq!(values[0]["id"], first.to_string());
+        assert_eq!(values[0]["mentions"], serde_json::json!(["coder"]));
+        assert_eq!(values[1]["id"], second.to_string());
+        assert_eq!(values[1]["mentions"], serde_json::json!(["tester"]));
+    }
+
+    #[test]
+    fn cutoff_file_index_only_accepts_exact_names() {
+        assert_eq!(parse_cutoff_file_index(&cutoff_file_name(7)), Some(7));
+        assert_eq!(parse_cutoff_file_index("cutoff_message_0.json"), Some(0));
+        assert_eq!(parse_cutoff_file_index("cutoff_message_.json"), None);
+        assert_eq!(parse_cutoff_file_index("cutoff_message_+1.json"), None);
+        assert_eq!(parse_cutoff_file_index("cutoff_message_1.json.bak"), None);
+        assert_eq!(parse_cutoff_file_index("cutoff_message_../1.json"), None);
+        assert_eq!(parse_cutoff_file_index("messages.jsonl"), None);
+    }
+
+    #[test]
+    fn compression_triggers_on_tokens_or_message_count() {
+        assert!(!compression_triggered(10, 100, 5, None));
+        assert!(compression_triggered(101, 100, 5, None));
+        assert!(!compression_triggered(10, 100, 5, Some(5)));
+        assert!(compression_triggered(10, 100, 6, Some(5)));
+        assert!(compression_triggered(101, 100, 1, Some(5)));
+    }
+
+    #[tokio::test]
+    async fn compress_messages_triggers_on_message_count_threshold() {
+        let pool = SqlitePool::connect("sqlite::memory:")
+            .await
+            .expect("create sqlite memory pool");
+        let session_id = Uuid::new_v4();
+        let workspace = std::path::Path::new(".");
+        let context_dir = tempfile::tempdir().expect("create temp context dir");
+        let messages: Vec<SimplifiedMessage> = (0..4)
+            .map(|index| SimplifiedMessage {
+                sender: "user:alice".to_string(),
+                content: format!("short message {index}"),
+                timestamp: chrono::Utc::now().to_rfc3339(),
+                id: None,
+            })
+            .collect();
+
+        let untouched = compress_messages_if_needed(
+            &pool,
+            session_id,
+            messages.clone(),
+            u32::MAX,
+            50,
+            Some(4),
+            &[],
+            workspace,
+            Some(context_dir.path()),
+        )
+        .await
+        .expect("compression should pass");
+        assert_eq!(untouched.compression_type, CompressionType::None);
+
+        let result = compress_messages_if_needed(
+            &pool,
+            session_id,
+            messages.clone(),
+            u32::MAX, // tokens alone would never trigger
+            50,
+            Some(3),
+            &[],
+            workspace,
+            Some(context_dir.path()),
+        )
+        .await
+        .expect("compression should succeed with fallback");
+
+        assert_eq!(result.compression_type, CompressionType::Truncated);
+        assert!(result.messages.len() <= messages.len());
+        assert_eq!(
+            result
+                .messages
+                .first()
+                .map(|message| message.sender.as_str()),
+            Some("system:summary")
+        );
+    }
+
+    #[test]
+    fn sniff_attachment_prefers_magic_bytes_over_declared_mime() {
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+        assert_eq!(
+            sniff_attachment(png, Some("text/plain")),
+            (Some("image/png".to_string()), "image".to_string())
+        );
+        assert_eq!(
+            sniff_attachment(b"%PDF-1.7\n", None),
+            (Some("application/pdf".to_string()), "pdf".to_string())
+        );
+        assert_eq!(
+            sniff_attachment(b"PK\x03\x04\x14\0", Some("text/plain")),
+            (Some("application/zip".to_string()), "archive".to_string())
+        );
+    }
+
+    #[test]
+    fn sniff_attachment_detects_text_and_binary_fallbacks() {
+        assert_eq!(
+            sniff_attachment("fn main() {}\n".as_bytes(), Some("text/x-rust")),
+            (Some("text/x-rust".to_string()), "text".to_string())
+        );
+        assert_eq!(
+            sniff_attachment(b"hello", Some("image/png")),
+            (Some("text/plain".to_string()), "text".to_string())
+        );
+        assert_eq!(
+            sniff_attachment(b"<svg xmlns='http://www.w3.org/2000/svg'/>", None),
+            (Some("image/svg+xml".to_string()), "image".to_string())
+        );
+        assert_eq!(
+            sniff_attachment(&[0x00, 0x01, 0x02, 0xFF], Some("text/plain")),
+            (
+                Some("application/octet-stream".to_string()),
+                "other".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn normalize_attachment_kind_maps_legacy_values() {
+        assert_eq!(normalize_attachment_kind("image", None), "image");
+        assert_eq!(
+            normalize_attachment_kind("file", Some("text/markdown")),
+            "text"
+        );
+        assert_eq!(
+            normalize_attachment_kind("file", Some("application/pdf")),
+            "pdf"
+        );
+        assert_eq!(normalize_attachment_kind("file", None), "other");
+    }
+
+    #[test]
+    fn translation_prompt_targets_language_and_defaults_browser_to_english() {
+        let prompt = build_translation_prompt("@bob see `src/main.rs`", "Japanese");
+        assert!(prompt.contains("into Japanese"));
+        assert!(prompt.contains("@bob see `src/main.rs`"));
+        assert_eq!(translation_language_for_ui(&UiLanguage::Browser), "English");
+        assert_eq!(
+            translation_language_for_ui(&UiLanguage::ZhHans),
+            "Simplified Chinese"
+        );
+    }
+
+    #[test]
+    fn recent_context_snippet_flattens_and_truncates_messages() {
+        let messages = vec![
+            SimplifiedMessage {
+                sender: "user:alice".to_string(),
+                content: "line one\n\nline   two".to_string(),
+                timestamp: chrono::Utc::now().to_rfc3339(),
+                id: None,
+            },
+            SimplifiedMessage {
+                sender: "agent:bot".to_string(),
+                content: "x".repeat(1000),
+                timestamp: chrono::Utc::now().to_rfc3339(),
+                id: None,
+            },
+        ];
+
+        let snippet = render_recent_context_snippet(&messages);
+        let lines: Vec<&str> = snippet.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0], "user:alice: line one line two");
+        assert!(lines[1].starts_with("agent:bot: xxx"));
+        assert!(lines[1].ends_with("..."));
+        assert!(lines[1].len() < 1000);
+    }
+
+    #[test]
+    fn summary_agent_without_runner_type_inherits_session_profile() {
+        let mut agent = ChatAgent {
+            id: Uuid::new_v4(),
+            name: "summarizer".to_string(),
+            runner_type: String::new(),
+            system_prompt: String::new(),
+            tools_enabled: sqlx::types::Json(serde_json::json!({})),
+            created_at: chrono::Utc::now(),
+            updated_at: chrono::Utc::now(),
+        };
+        let session_profile = ExecutorProfileId::new(BaseCodingAgent::Codex);
+
+        assert_eq!(
+            parse_executor_profile_id(&agent, Some(&session_profile)).unwrap(),
+            session_profile
+        );
+        assert!(parse_executor_profile_id(&agent, None).is_err());
+
+        agent.runner_type = "CLAUDE_CODE".to_string();
+        assert_eq!(
+            parse_executor_profile_id(&agent, Some(&session_profile)).unwrap(),
+            ExecutorProfileId::new(BaseCodingAgent::ClaudeCode)
+        );
+    }
 }
@@ -0,0 +1,191 @@
+//! Background session exports.
+//!
+//! An export writes the same files as [`chat::export_session_archive`] into its own
+//! directory without archiving the session. Jobs are tracked in memory, so they are lost
+//! on restart; finished jobs and their files are removed a day after they end.
+
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use db::models::chat_session::ChatSession;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use sqlx::SqlitePool;
+use ts_rs::TS;
+use uuid::Uuid;
+
+use crate::services::chat;
+
+/// File of an export holding one structured message per line.
+pub const EXPORT_MESSAGES_FILE_NAME: &str = "messages_export.jsonl";
+
+const FINISHED_JOB_RETENTION_HOURS: i64 = 24;
+
+static EXPORT_JOBS: Lazy<DashMap<Uuid, ChatExportJob>> = Lazy::new(DashMap::new);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, TS)]
+#[serde(rename_all = "snake_case")]
+#[ts(export)]
+pub enum ChatExportJobStatus {
+    Running,
+    Completed,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, TS)]
+#[ts(export)]
+pub struct ChatExportJob {
+    pub job_id: Uuid,
+    pub session_id: Uuid,
+    pub status: ChatExportJobStatus,
+    /// Directory holding the export files once completed
+    pub archive_path: Option<String>,
+    /// Relative URL downloading the exported messages once completed
+    pub download_url: Option<String>,
+    /// Why the export failed
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+    /// Directory the export writes into, removed with the job once it is pruned
+    #[serde(skip)]
+    #[ts(skip)]
+    export_dir: PathBuf,
+}
+
+impl ChatExportJob {
+    /// Exported messages file of a completed job.
+    pub fn messages_file(&self) -> Option<PathBuf> {
+        if self.status != ChatExportJobStatus::Completed {
+            return None;
+        }
+        self.archive_path
+            .as_deref()
+            .map(|dir| Path::new(dir).join(EXPORT_MESSAGES_FILE_NAME))
+    }
+}
+
+/// Start exporting `session` into `export_root/{job_id}` and return the running job.
+pub fn start_export_job(
+    pool: SqlitePool,
+    session: ChatSession,
+    export_root: &Path,
+) -> ChatExportJob {
+    let job_id = Uuid::new_v4();
+    let export_dir = export_root.join(job_id.to_string());
+    let job = ChatExportJob {
+        job_id,
+        session_id: session.id,
+        status: ChatExportJobStatus::Running,
+        archive_path: None,
+        download_url: None,
+        error: None,
+        created_at: Utc::now(),
+        finished_at: None,
+        export_dir: export_dir.clone(),
+    };
+    EXPORT_JOBS.insert(job_id, job.clone());
+
+    tokio::spawn(async move {
+        prune_finished_jobs().await;
+        let result = chat::export_session_archive(&pool, &session, &export_dir).await;
+        if let Some(mut job) = EXPORT_JOBS.get_mut(&job_id) {
+            job.finished_at = Some(Utc::now());
+            match result {
+                Ok(archive_path) => {
+                    job.status = ChatExportJobStatus::Completed;
+                    job.archive_path = Some(archive_path);
+                    job.download_url = Some(format!("/api/chat/exports/{job_id}/download"));
+                }
+                Err(err) => {
+                    tracing::warn!(
+                        session_id = %session.id,
+                        job_id = %job_id,
+                        error = %err,
+                        "session export failed"
+                    );
+                    job.status = ChatExportJobStatus::Failed;
+                    job.error = Some(err.to_string());
+                }
+            }
+        }
+    });
+
+    job
+}
+
+pub fn get_export_job(job_id: Uuid) -> Option<ChatExportJob> {
+    EXPORT_JOBS.get(&job_id).map(|job| job.clone())
+}
+
+/// Forget jobs that finished over a day ago and delete their export directories.
+async fn prune_finished_jobs() {
+    let cutoff = Utc::now() - chrono::Duration::hours(FINISHED_JOB_RETENTION_HOURS);
+    let mut expired = Vec::new();
+    EXPORT_JOBS.retain(|_, job| {
+        let keep = job.finished_at.is_none_or(|finished| finished > cutoff);
+        if !keep {
+            expired.push(job.export_dir.clone());
+        }
+        keep
+    });
+    for export_dir in expired {
+        match tokio::fs::remove_dir_all(&export_dir).await {
+            Ok(()) => {}
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => tracing::warn!(
+                export_dir = %export_dir.display(),
+                error = %err,
+                "failed to remove expired session export"
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use uuid::Uuid;
+
+    use super::{
+        ChatExportJob, ChatExportJobStatus, EXPORT_JOBS, FINISHED_JOB_RETENTION_HOURS,
+        prune_finished_jobs,
+    };
+
+    #[tokio::test]
+    async fn pruning_a_finished_job_deletes_its_export_directory() {
+        let export_root = tempfile::tempdir().expect("create export root");
+        let job = |finished_hours_ago: i64| {
+            let job_id = Uuid::new_v4();
+            let export_dir = export_root.path().join(job_id.to_string());
+            std::fs::create_dir_all(&export_dir).expect("create export dir");
+            std::fs::write(export_dir.join("session_summary.md"), "summary")
+                .expect("write export file");
+            let finished_at = Utc::now() - chrono::Duration::hours(finished_hours_ago);
+            EXPORT_JOBS.insert(
+                job_id,
+                ChatExportJob {
+                    job_id,
+                    session_id: Uuid::new_v4(),
+                    status: ChatExportJobStatus::Completed,
+                    archive_path: Some(export_dir.to_string_lossy().to_string()),
+                    download_url: None,
+                    error: None,
+                    created_at: finished_at,
+                    finished_at: Some(finished_at),
+                    export_dir: export_dir.clone(),
+                },
+            );
+            (job_id, export_dir)
+        };
+        let (expired_id, expired_dir) = job(FINISHED_JOB_RETENTION_HOURS + 1);
+        let (recent_id, recent_dir) = job(1);
+
+        prune_finished_jobs().await;
+
+        assert!(!EXPORT_JOBS.contains_key(&expired_id));
+        assert!(!expired_dir.exists());
+        assert!(EXPORT_JOBS.contains_key(&recent_id));
+        assert!(recent_dir.exists());
+    }
+}
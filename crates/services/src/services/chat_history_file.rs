@@ -6,12 +6,12 @@
 //! - Token estimation using tiktoken
 //! - Creating split files for archived messages
 
-use std::path::PathBuf;
+use std::{path::PathBuf, sync::LazyLock};
 
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
-use tiktoken_rs::cl100k_base;
+use tiktoken_rs::{CoreBPE, cl100k_base};
 use tokio::fs;
 use uuid::Uuid;
 
@@ -25,6 +25,10 @@ pub struct SimplifiedMessage {
     pub content: String,
     /// ISO 8601 timestamp
     pub timestamp: String,
+    /// Id of the chat message this entry was built from; absent for synthesized entries
+    /// such as history summaries
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<Uuid>,
 }
 
 /// Metadata about the chat history file
@@ -80,34 +84,43 @@ pub fn chat_history_split_path(session_id: Uuid) -> Result<PathBuf, ChatHistoryF
     Ok(chat_history_dir()?.join(format!("{}_split.json", session_id)))
 }
 
-/// Estimate the token count for a list of messages using tiktoken (cl100k_base).
-pub fn estimate_token_count(messages: &[SimplifiedMessage]) -> u32 {
-    let bpe = match cl100k_base() {
-        Ok(bpe) => bpe,
-        Err(_) => {
-            // Fallback to character-based estimation if tiktoken fails
-            return estimate_token_count_fallback(messages);
-        }
-    };
-
-    let mut total_tokens: u32 = 0;
-    for msg in messages {
-        // Count tokens in sender and content
-        let text = format!("{}: {}", msg.sender, msg.content);
-        total_tokens += bpe.encode_with_special_tokens(&text).len() as u32;
+/// cl100k_base encoder, loaded once on first use. `None` when it failed to load, in
+/// which case every estimate uses [`estimate_text_tokens_fallback`].
+static CL100K_BPE: LazyLock<Option<CoreBPE>> = LazyLock::new(|| match cl100k_base() {
+    Ok(bpe) => Some(bpe),
+    Err(err) => {
+        tracing::warn!("Failed to load tiktoken cl100k_base, using character estimate: {err}");
+        None
+    }
+});
+
+/// Estimate the token count of a text using tiktoken (cl100k_base).
+///
+/// This is the single estimation path for chat: prompts, presets, drafts and history
+/// compression all go through it, so their numbers agree.
+pub fn estimate_text_tokens(text: &str) -> u32 {
+    match CL100K_BPE.as_ref() {
+        Some(bpe) => bpe.encode_with_special_tokens(text).len() as u32,
+        None => estimate_text_tokens_fallback(text),
     }
-    total_tokens
 }
 
-/// Fallback token estimation using character count.
-/// Assumes roughly 4 characters per token for English, 2 for Chinese.
-fn estimate_token_count_fallback(messages: &[SimplifiedMessage]) -> u32 {
-    let mut total_chars: usize = 0;
-    for msg in messages {
-        total_chars += msg.sender.len() + msg.content.len() + 2; // +2 for ": " separator
-    }
-    // Use a conservative estimate of 3 chars per token (accounting for mixed content)
-    (total_chars / 3) as u32
+/// Fallback token estimation using byte count.
+/// Uses a conservative 3 bytes per token to account for mixed English and CJK content.
+fn estimate_text_tokens_fallback(text: &str) -> u32 {
+    (text.len() / 3) as u32
+}
+
+fn message_token_text(message: &SimplifiedMessage) -> String {
+    format!("{}: {}", message.sender, message.content)
+}
+
+/// Estimate the token count for a list of messages using tiktoken (cl100k_base).
+pub fn estimate_token_count(messages: &[SimplifiedMessage]) -> u32 {
+    messages
+        .iter()
+        .map(|message| estimate_text_tokens(&message_token_text(message)))
+        .sum()
 }
 
 /// Write chat history to a file.
@@ -244,11 +257,13 @@ mod tests {
                 sender: "user:alice".to_string(),
                 content: "Hello, how are you?".to_string(),
                 timestamp: "2026-02-27T10:00:00Z".to_string(),
+                id: None,
             },
             SimplifiedMessage {
                 sender: "agent:assistant".to_string(),
                 content: "I'm doing well, thank you!".to_string(),
                 timestamp: "2026-02-27T10:00:01Z".to_string(),
+                id: None,
             },
         ];
 
@@ -264,9 +279,51 @@ mod tests {
             sender: "user:alice".to_string(),
             content: "你好，世界！".to_string(),
             timestamp: "2026-02-27T10:00:00Z".to_string(),
+            id: None,
         }];
 
         let token_count = estimate_token_count(&messages);
         assert!(token_count > 0);
     }
+
+    #[test]
+    fn test_fallback_estimate_counts_three_bytes_per_token() {
+        assert_eq!(estimate_text_tokens_fallback(""), 0);
+        assert_eq!(estimate_text_tokens_fallback("user:alice: Hello!"), 6);
+        // CJK characters take three bytes each
+        assert_eq!(estimate_text_tokens_fallback("你好世界"), 4);
+    }
+
+    /// Compares loading the encoder per call with the cached encoder.
+    /// Run with `cargo test -p services --release -- --ignored bench_cached_encoder --nocapture`.
+    #[test]
+    #[ignore = "benchmark"]
+    fn bench_cached_encoder() {
+        use std::{hint::black_box, time::Instant};
+
+        const ITERATIONS: u32 = 20;
+        let text = "Please review the compression changes and report regressions. ".repeat(20);
+        // Warm the cache so its one-off load is not counted.
+        estimate_text_tokens(&text);
+
+        let start = Instant::now();
+        for _ in 0..ITERATIONS {
+            let bpe = cl100k_base().expect("load cl100k_base");
+            black_box(bpe.encode_with_special_tokens(black_box(&text)).len());
+        }
+        let uncached = start.elapsed();
+
+        let start = Instant::now();
+        for _ in 0..ITERATIONS {
+            black_box(estimate_text_tokens(black_box(&text)));
+        }
+        let cached = start.elapsed();
+
+        println!(
+            "per call: load each time {:?}, cached encoder {:?}",
+            uncached / ITERATIONS,
+            cached / ITERATIONS
+        );
+        assert!(cached < uncached);
+    }
 }
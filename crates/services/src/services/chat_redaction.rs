@@ -0,0 +1,229 @@
+//! Redaction of secrets from agent output.
+//!
+//! Rules come from `chat_runner.redaction_rules` plus the agent's own
+//! `tools_enabled.redaction_rules`, and are applied to streamed content and to the reply
+//! before it is written to `output.md` or stored as a message, as well as to the raw
+//! stdout/stderr kept in `raw.log` and the run's log stream.
+
+use std::borrow::Cow;
+
+use regex::Regex;
+
+use crate::services::config::RedactionRule;
+
+/// Agent `tools_enabled` key holding redaction rules applied after the configured ones.
+pub const REDACTION_RULES_KEY: &str = "redaction_rules";
+/// Raw output held back waiting for the end of its line; longer lines are redacted in parts.
+const MAX_PENDING_LINE_BYTES: usize = 64 * 1024;
+
+/// Redaction rules from an agent's `tools_enabled`; a malformed list yields none.
+pub fn agent_redaction_rules(tools_enabled: &serde_json::Value) -> Vec<RedactionRule> {
+    tools_enabled
+        .get(REDACTION_RULES_KEY)
+        .and_then(|rules| serde_json::from_value(rules.clone()).ok())
+        .unwrap_or_default()
+}
+
+/// The configured rules followed by the agent's own.
+pub fn effective_redaction_rules(
+    configured: &[RedactionRule],
+    tools_enabled: &serde_json::Value,
+) -> Vec<RedactionRule> {
+    configured
+        .iter()
+        .cloned()
+        .chain(agent_redaction_rules(tools_enabled))
+        .collect()
+}
+
+/// Check that an agent's `tools_enabled.redaction_rules`, when present, is a list of
+/// rules with compilable patterns.
+pub fn validate_agent_redaction_rules(tools_enabled: &serde_json::Value) -> Result<(), String> {
+    let Some(rules) = tools_enabled.get(REDACTION_RULES_KEY) else {
+        return Ok(());
+    };
+    let rules: Vec<RedactionRule> = serde_json::from_value(rules.clone())
+        .map_err(|err| format!("Invalid redaction rules: {err}"))?;
+    for rule in &rules {
+        Regex::new(&rule.pattern)
+            .map_err(|err| format!("Invalid redaction pattern '{}': {err}", rule.pattern))?;
+    }
+    Ok(())
+}
+
+/// Compiled redaction rules for one run, remembering whether any of them matched.
+#[derive(Debug, Default)]
+pub struct OutputRedactor {
+    rules: Vec<(Regex, String)>,
+    applied: bool,
+}
+
+impl OutputRedactor {
+    /// Compile `rules`; patterns that fail to compile are logged and skipped.
+    pub fn new<'a>(rules: impl IntoIterator<Item = &'a RedactionRule>) -> Self {
+        let rules = rules
+            .into_iter()
+            .filter_map(|rule| match Regex::new(&rule.pattern) {
+                Ok(regex) => Some((regex, rule.replacement.clone())),
+                Err(err) => {
+                    tracing::warn!(
+                        pattern = %rule.pattern,
+                        error = %err,
+                        "skipping invalid redaction pattern"
+                    );
+                    None
+                }
+            })
+            .collect();
+        Self {
+            rules,
+            applied: false,
+        }
+    }
+
+    /// Redactor for an agent: the configured rules followed by the agent's own.
+    pub fn for_agent(configured: &[RedactionRule], tools_enabled: &serde_json::Value) -> Self {
+        Self::new(effective_redaction_rules(configured, tools_enabled).iter())
+    }
+
+    pub fn redact<'t>(&mut self, text: &'t str) -> Cow<'t, str> {
+        let mut redacted = Cow::Borrowed(text);
+        for (regex, replacement) in &self.rules {
+            let replaced = match regex.replace_all(&redacted, replacement.as_str()) {
+                Cow::Owned(replaced) => replaced,
+                Cow::Borrowed(_) => continue,
+            };
+            redacted = Cow::Owned(replaced);
+        }
+        if matches!(redacted, Cow::Owned(_)) {
+            self.applied = true;
+        }
+        redacted
+    }
+
+    /// Whether any rule has matched so far.
+    pub fn applied(&self) -> bool {
+        self.applied
+    }
+}
+
+/// Redaction of raw executor output read in arbitrary chunks. Text is redacted a line at a
+/// time so a secret split across two reads is still matched; without rules, chunks pass
+/// through untouched.
+#[derive(Debug, Default)]
+pub struct RawOutputRedactor {
+    redactor: OutputRedactor,
+    pending: String,
+}
+
+impl RawOutputRedactor {
+    pub fn new(redactor: OutputRedactor) -> Self {
+        Self {
+            redactor,
+            pending: String::new(),
+        }
+    }
+
+    /// Redacted text of the lines `chunk` completes. A trailing partial line is held until
+    /// its newline arrives or [`Self::finish`] is called.
+    pub fn push(&mut self, chunk: &str) -> String {
+        if self.redactor.rules.is_empty() {
+            return chunk.to_string();
+        }
+        self.pending.push_str(chunk);
+        let complete = match self.pending.rfind('\n') {
+            Some(end) => {
+                let rest = self.pending.split_off(end + 1);
+                std::mem::replace(&mut self.pending, rest)
+            }
+            None if self.pending.len() > MAX_PENDING_LINE_BYTES => {
+                std::mem::take(&mut self.pending)
+            }
+            None => return String::new(),
+        };
+        self.redactor.redact(&complete).into_owned()
+    }
+
+    /// Redacted text still held back, once the output has ended.
+    pub fn finish(&mut self) -> Option<String> {
+        if self.pending.is_empty() {
+            return None;
+        }
+        let rest = std::mem::take(&mut self.pending);
+        Some(self.redactor.redact(&rest).into_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        OutputRedactor, RawOutputRedactor, agent_redaction_rules, validate_agent_redaction_rules,
+    };
+    use crate::services::config::RedactionRule;
+
+    #[test]
+    fn redactor_applies_configured_then_agent_rules() {
+        let configured = vec![RedactionRule {
+            pattern: r"sk-[A-Za-z0-9]{8,}".to_string(),
+            replacement: "[REDACTED]".to_string(),
+        }];
+        let tools_enabled = serde_json::json!({
+            "redaction_rules": [{ "pattern": r"token=(\w+)" }, { "pattern": "(" }],
+        });
+        let mut redactor = OutputRedactor::for_agent(&configured, &tools_enabled);
+
+        assert_eq!(redactor.redact("nothing to hide"), "nothing to hide");
+        assert!(!redactor.applied());
+        assert_eq!(
+            redactor.redact("key sk-abcdef123456 and token=abc"),
+            "key [REDACTED] and [REDACTED]"
+        );
+        assert!(redactor.applied());
+    }
+
+    #[test]
+    fn agent_rules_are_validated() {
+        assert!(validate_agent_redaction_rules(&serde_json::json!({})).is_ok());
+        assert!(
+            validate_agent_redaction_rules(&serde_json::json!({
+                "redaction_rules": [{ "pattern": "secret", "replacement": "***" }],
+            }))
+            .is_ok()
+        );
+        assert!(
+            validate_agent_redaction_rules(&serde_json::json!({
+                "redaction_rules": [{ "pattern": "(" }],
+            }))
+            .is_err()
+        );
+        assert!(
+            validate_agent_redaction_rules(&serde_json::json!({ "redaction_rules": "secret" }))
+                .is_err()
+        );
+        assert!(agent_redaction_rules(&serde_json::json!({ "redaction_rules": 1 })).is_empty());
+    }
+
+    #[test]
+    fn raw_output_redaction_matches_secrets_split_across_chunks() {
+        let rules = vec![RedactionRule {
+            pattern: r"sk-[A-Za-z0-9]{8,}".to_string(),
+            replacement: "[REDACTED]".to_string(),
+        }];
+        let mut raw = RawOutputRedactor::new(OutputRedactor::new(rules.iter()));
+
+        assert_eq!(raw.push("{\"key\":\"sk-abcd"), "");
+        assert_eq!(
+            raw.push("ef123456\"}\nnext sk-"),
+            "{\"key\":\"[REDACTED]\"}\n"
+        );
+        assert_eq!(raw.finish().as_deref(), Some("next sk-"));
+        assert_eq!(raw.finish(), None);
+
+        let mut passthrough = RawOutputRedactor::default();
+        assert_eq!(
+            passthrough.push("partial sk-abcdef123456"),
+            "partial sk-abcdef123456"
+        );
+        assert_eq!(passthrough.finish(), None);
+    }
+}
@@ -0,0 +1,317 @@
+//! Retention of run record directories.
+//!
+//! Every run leaves `input.md`, `output.md`, `raw.log`, diffs and untracked copies in its
+//! record directory. When `chat_runner.run_retention_days` or
+//! `chat_runner.run_retention_max_per_agent` is set, a background sweeper deletes the
+//! directories of old runs, optionally pruning their database records too.
+
+use std::{
+    collections::HashSet,
+    ffi::OsStr,
+    path::{Component, Path},
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use db::{DBService, models::chat_run::ChatRun};
+use sqlx::SqlitePool;
+use tokio::fs;
+use utils::assets::config_path;
+use uuid::Uuid;
+
+use crate::services::{
+    chat_workspace::{AGENTS_CHATGROUP_WORKSPACE_DIR, RUN_RECORDS_DIR_NAME, run_dir_name},
+    config::{ChatRunnerConfig, load_config_from_file},
+};
+
+/// How often the sweeper checks for expired runs.
+const RUN_SWEEP_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Runs past the retention limits. `runs` must be grouped by session agent with the newest
+/// run first, as [`ChatRun::find_all`] returns them; the newest run of every member is
+/// always kept so it can still be resumed from.
+pub fn runs_to_sweep(
+    runs: &[ChatRun],
+    now: DateTime<Utc>,
+    max_age: Option<Duration>,
+    max_per_agent: Option<usize>,
+) -> Vec<&ChatRun> {
+    let cutoff = max_age
+        .and_then(|age| chrono::Duration::from_std(age).ok())
+        .and_then(|age| now.checked_sub_signed(age));
+    let mut seen: HashSet<Uuid> = HashSet::new();
+    let mut position = 0;
+    runs.iter()
+        .filter(|run| {
+            if seen.insert(run.session_agent_id) {
+                position = 0;
+                return false;
+            }
+            position += 1;
+            max_per_agent.is_some_and(|max| position >= max)
+                || cutoff.is_some_and(|cutoff| run.created_at < cutoff)
+        })
+        .collect()
+}
+
+/// Only the run's own record directory, directly under `run_records` inside a workspace's
+/// chat runtime directory, is ever deleted.
+fn is_run_record_dir(run: &ChatRun) -> bool {
+    let path = Path::new(&run.run_dir);
+    let in_runtime_dir = path.components().any(|component| {
+        matches!(
+            component,
+            Component::Normal(name) if name == OsStr::new(AGENTS_CHATGROUP_WORKSPACE_DIR)
+        )
+    });
+    in_runtime_dir
+        && path.file_name()
+            == Some(OsStr::new(&run_dir_name(
+                run.session_agent_id,
+                run.run_index,
+            )))
+        && path.parent().and_then(Path::file_name) == Some(OsStr::new(RUN_RECORDS_DIR_NAME))
+}
+
+/// Delete the record directories of runs past the configured limits; returns how many
+/// runs were swept.
+pub async fn sweep_run_records(
+    pool: &SqlitePool,
+    config: &ChatRunnerConfig,
+) -> Result<usize, sqlx::Error> {
+    let max_age = config.run_retention();
+    let max_per_agent = config.run_retention_max_per_agent();
+    if max_age.is_none() && max_per_agent.is_none() {
+        return Ok(0);
+    }
+
+    let runs = ChatRun::find_all(pool).await?;
+    let mut swept = 0;
+    for run in runs_to_sweep(&runs, Utc::now(), max_age, max_per_agent) {
+        let run_dir = Path::new(&run.run_dir);
+        if !is_run_record_dir(run) {
+            tracing::warn!(
+                run_id = %run.id,
+                run_dir = %run_dir.display(),
+                "Skipping run directory that is not a chat run record directory"
+            );
+            continue;
+        }
+        match fs::remove_dir_all(run_dir).await {
+            Ok(()) => {}
+            // Swept earlier with the records kept; there is nothing left to do.
+            Err(err)
+                if err.kind() == std::io::ErrorKind::NotFound
+                    && !config.prune_swept_run_records =>
+            {
+                continue;
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => {
+                tracing::warn!(
+                    run_id = %run.id,
+                    run_dir = %run_dir.display(),
+                    error = %err,
+                    "Failed to delete expired run directory"
+                );
+                continue;
+            }
+        }
+        if config.prune_swept_run_records {
+            ChatRun::delete(pool, run.id).await?;
+        }
+        swept += 1;
+    }
+    Ok(swept)
+}
+
+/// Sweep expired run directories periodically, re-reading the retention settings each time.
+pub fn spawn_run_retention_sweeper(db: DBService) {
+    tokio::spawn(async move {
+        let mut sweep_interval = tokio::time::interval(RUN_SWEEP_INTERVAL);
+        loop {
+            sweep_interval.tick().await;
+            let config = load_config_from_file(&config_path()).await.chat_runner;
+            match sweep_run_records(&db.pool, &config).await {
+                Ok(0) => {}
+                Ok(swept) => tracing::info!(swept, "Swept expired chat run directories"),
+                Err(err) => tracing::error!("Failed to sweep expired chat runs: {}", err),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::Utc;
+    use db::models::{
+        chat_agent::{ChatAgent, CreateChatAgent},
+        chat_run::{ChatRun, CreateChatRun},
+        chat_session::{ChatSession, CreateChatSession},
+        chat_session_agent::{ChatSessionAgent, CreateChatSessionAgent},
+    };
+    use uuid::Uuid;
+
+    use super::{is_run_record_dir, runs_to_sweep, sweep_run_records};
+    use crate::services::{chat_workspace::run_dir_name, config::ChatRunnerConfig};
+
+    fn make_run(session_agent_id: Uuid, run_index: i64, age_days: i64) -> ChatRun {
+        ChatRun {
+            id: Uuid::new_v4(),
+            session_id: Uuid::new_v4(),
+            session_agent_id,
+            run_index,
+            run_dir: format!(
+                "/work/.agents_chatgroup/runs/s/run_records/{}",
+                run_dir_name(session_agent_id, run_index)
+            ),
+            input_path: None,
+            output_path: None,
+            raw_log_path: None,
+            meta_path: None,
+            created_at: Utc::now() - chrono::Duration::days(age_days),
+        }
+    }
+
+    #[test]
+    fn sweeps_runs_past_count_or_age_but_keeps_the_newest() {
+        let coder = Uuid::new_v4();
+        let reviewer = Uuid::new_v4();
+        let runs = vec![
+            make_run(coder, 4, 0),
+            make_run(coder, 3, 1),
+            make_run(coder, 2, 2),
+            make_run(coder, 1, 40),
+            make_run(reviewer, 1, 90),
+        ];
+        let ids = |swept: Vec<&ChatRun>| swept.iter().map(|run| run.id).collect::<Vec<_>>();
+
+        assert_eq!(
+            ids(runs_to_sweep(&runs, Utc::now(), None, Some(2))),
+            vec![runs[2].id, runs[3].id]
+        );
+        assert_eq!(
+            ids(runs_to_sweep(
+                &runs,
+                Utc::now(),
+                Some(Duration::from_secs(30 * 86_400)),
+                None
+            )),
+            vec![runs[3].id]
+        );
+        assert!(runs_to_sweep(&runs, Utc::now(), None, None).is_empty());
+    }
+
+    #[test]
+    fn only_run_record_directories_are_deleted() {
+        let run = make_run(Uuid::new_v4(), 1, 0);
+        assert!(is_run_record_dir(&run));
+
+        let with_dir = |run_dir: &str| ChatRun {
+            run_dir: run_dir.to_string(),
+            ..run.clone()
+        };
+        assert!(!is_run_record_dir(&with_dir("/work/src")));
+        // The runtime directory itself, or another run's directory, is never a target.
+        assert!(!is_run_record_dir(&with_dir("/work/.agents_chatgroup")));
+        assert!(!is_run_record_dir(&with_dir(
+            "/work/.agents_chatgroup/runs/s/run_records"
+        )));
+        assert!(!is_run_record_dir(&with_dir(&format!(
+            "/work/.agents_chatgroup/runs/s/run_records/{}",
+            run_dir_name(run.session_agent_id, 2)
+        ))));
+        assert!(!is_run_record_dir(&with_dir(&format!(
+            "/work/.agents_chatgroup/runs/s/{}",
+            run_dir_name(run.session_agent_id, 1)
+        ))));
+    }
+
+    #[tokio::test]
+    async fn directories_already_swept_are_not_counted_again() {
+        let pool = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .expect("create sqlite memory pool");
+        sqlx::migrate!("../db/migrations")
+            .run(&pool)
+            .await
+            .expect("run migrations");
+        let session =
+            ChatSession::create(&pool, &CreateChatSession { title: None }, Uuid::new_v4())
+                .await
+                .expect("create session");
+        let agent = ChatAgent::create(
+            &pool,
+            &CreateChatAgent {
+                name: "coder".to_string(),
+                runner_type: "CLAUDE_CODE".to_string(),
+                system_prompt: None,
+                tools_enabled: None,
+            },
+            Uuid::new_v4(),
+        )
+        .await
+        .expect("create agent");
+        let session_agent = ChatSessionAgent::create(
+            &pool,
+            &CreateChatSessionAgent {
+                session_id: session.id,
+                agent_id: agent.id,
+                workspace_path: None,
+            },
+            Uuid::new_v4(),
+        )
+        .await
+        .expect("create session agent");
+
+        let workspace = tempfile::tempdir().expect("tempdir");
+        let run_records = workspace
+            .path()
+            .join(".agents_chatgroup/runs")
+            .join(session.id.to_string())
+            .join("run_records");
+        for run_index in 1..=3 {
+            let run_dir = run_records.join(run_dir_name(session_agent.id, run_index));
+            std::fs::create_dir_all(&run_dir).unwrap();
+            std::fs::write(run_dir.join("output.md"), "done\n").unwrap();
+            ChatRun::create(
+                &pool,
+                &CreateChatRun {
+                    session_id: session.id,
+                    session_agent_id: session_agent.id,
+                    run_index,
+                    run_dir: run_dir.to_string_lossy().to_string(),
+                    input_path: None,
+                    output_path: None,
+                    raw_log_path: None,
+                    meta_path: None,
+                },
+                Uuid::new_v4(),
+            )
+            .await
+            .expect("create run");
+        }
+        let config = ChatRunnerConfig {
+            run_retention_max_per_agent: 1,
+            prune_swept_run_records: false,
+            ..Default::default()
+        };
+
+        assert_eq!(sweep_run_records(&pool, &config).await.unwrap(), 2);
+        assert!(run_records.join(run_dir_name(session_agent.id, 3)).exists());
+        assert!(!run_records.join(run_dir_name(session_agent.id, 2)).exists());
+        assert_eq!(sweep_run_records(&pool, &config).await.unwrap(), 0);
+        assert_eq!(ChatRun::find_all(&pool).await.unwrap().len(), 3);
+
+        let config = ChatRunnerConfig {
+            prune_swept_run_records: true,
+            ..config
+        };
+        assert_eq!(sweep_run_records(&pool, &config).await.unwrap(), 2);
+        assert_eq!(ChatRun::find_all(&pool).await.unwrap().len(), 1);
+    }
+}
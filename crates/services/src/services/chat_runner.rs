@@ -1,13 +1,14 @@
 use std::{
-    collections::{HashMap, VecDeque},
-    path::{Component, Path, PathBuf},
+    collections::{HashMap, HashSet, VecDeque},
+    path::{Path, PathBuf},
     str::FromStr,
     sync::{
         Arc,
-        atomic::{AtomicBool, Ordering},
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
     },
 };
 
+use async_trait::async_trait;
 use chrono::Utc;
 use dashmap::DashMap;
 use db::{
@@ -16,15 +17,17 @@ use db::{
         chat_agent::ChatAgent,
         chat_message::{ChatMessage, ChatSenderType},
         chat_run::{ChatRun, CreateChatRun},
+        chat_run_stats::ChatRunStats,
         chat_session::ChatSession,
         chat_session_agent::{ChatSessionAgent, ChatSessionAgentState},
     },
 };
 use executors::{
-    approvals::NoopExecutorApprovalService,
+    approvals::{ExecutorApprovalError, ExecutorApprovalService, NoopExecutorApprovalService},
     env::{ExecutionEnv, RepoContext},
     executors::{
-        BaseCodingAgent, CancellationToken, ExecutorError, ExecutorExitSignal,
+        BaseAgentCapability, BaseCodingAgent, CancellationToken, CodingAgent, ExecutorError,
+        ExecutorExitResult, ExecutorExitSignal, PersistentChild, SpawnedChild,
         StandardCodingAgentExecutor,
     },
     logs::{
@@ -39,30 +42,530 @@ use tokio::{
     fs,
     io::AsyncWriteExt,
     process::Command,
-    sync::{Mutex, broadcast},
+    sync::{Mutex, OwnedSemaphorePermit, Semaphore, broadcast, mpsc, oneshot},
 };
 use tokio_util::io::ReaderStream;
 use ts_rs::TS;
-use utils::{assets::asset_dir, log_msg::LogMsg, msg_store::MsgStore};
+use utils::{
+    approvals::{APPROVAL_TIMEOUT_SECONDS, ApprovalStatus},
+    assets::{asset_dir, config_path},
+    log_msg::LogMsg,
+    msg_store::MsgStore,
+    process::kill_process_group,
+};
 use uuid::Uuid;
 
-use crate::services::chat::{self, ChatServiceError};
+use crate::services::{
+    chat::{self, ChatServiceError},
+    chat_history_file,
+    chat_redaction::{OutputRedactor, RawOutputRedactor, effective_redaction_rules},
+    chat_workspace::{
+        AGENTS_CHATGROUP_WORKSPACE_DIR, GitDiffBase, WorkspaceLayout, additional_workspace_paths,
+        git_diff_base, validate_workspace_root, validate_workspace_root_legality, workspace_label,
+    },
+    config::{
+        AgentUnresolvedRoutingPolicy, ChatCompressionConfig, ChatRunnerConfig,
+        ModeratorTimeoutAction, RESERVED_USER_HANDLE, RedactionRule, RunContextMode,
+        SystemMessageCategory, UnknownMentionPolicy,
+    },
+};
 
 const UNTRACKED_FILE_LIMIT: u64 = 1024 * 1024;
 const MAX_AGENT_CHAIN_DEPTH: u32 = 5;
 const AGENTS_CHATGROUP_HOME_DIR: &str = ".agents-chatgroup";
-const AGENTS_CHATGROUP_WORKSPACE_DIR: &str = ".agents_chatgroup";
-const RUNS_DIR_NAME: &str = "runs";
-const CONTEXT_DIR_NAME: &str = "context";
+/// Reserved directory of a run's untracked copies holding additional workspaces' files,
+/// one subdirectory per label; primary-workspace files under it are not captured.
+const ADDITIONAL_UNTRACKED_DIR_NAME: &str = "_additional_workspaces";
 const LEGACY_COMPACTED_CONTEXT_FILE_NAME: &str = "messages_compacted.background.jsonl";
-const RUN_RECORDS_DIR_NAME: &str = "run_records";
-const RESERVED_USER_HANDLE: &str = "you";
 const EXECUTOR_PROFILE_VARIANT_KEY: &str = "executor_profile_variant";
+const AUTO_COMMIT_KEY: &str = "auto_commit";
+const REQUIRE_HISTORY_READ_KEY: &str = "require_history_read";
+const PERSISTENT_PROCESS_KEY: &str = "persistent_process";
+const CHAT_APPROVALS_KEY: &str = "chat_approvals";
+const MAX_RUN_DURATION_KEY: &str = "max_run_duration_secs";
+const MAX_OUTPUT_TOKENS_KEY: &str = "max_output_tokens";
+/// Message meta key marking a reply cut off at the agent's `max_output_tokens`.
+pub const OUTPUT_TRUNCATED_META_KEY: &str = "output_truncated";
+const COST_PER_MILLION_TOKENS_CENTS_KEY: &str = "cost_per_million_tokens_cents";
+/// Messages inlined in the prompt of agents that skip reading the history file.
+const RECENT_CONTEXT_MESSAGES: usize = 10;
+const ALLOWED_ROUTING_TARGETS_KEY: &str = "allowed_routing_targets";
+/// Message meta key holding the message's [`RoutingOutcome`].
+const ROUTING_META_KEY: &str = "routing";
+/// Message meta key holding a runner-generated system message's [`SystemMessageCategory`].
+const SYSTEM_CATEGORY_META_KEY: &str = "system_category";
+/// Message meta key naming an earlier run whose executor session the mentioned agent resumes.
+const FROM_RUN_ID_KEY: &str = "from_run_id";
+const REPLAY_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(500);
+const REPLAY_SETTLED_POLLS: u32 = 3;
+const REPLAY_MESSAGE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30 * 60);
+/// Time a run gets to exit after an idle-timeout cancellation before its stream is closed
+/// and its process is killed.
+const IDLE_CANCEL_GRACE: std::time::Duration = std::time::Duration::from_secs(10);
+/// Time a run gets to exit after hitting its maximum duration before its process is killed.
+const MAX_DURATION_KILL_GRACE: std::time::Duration = std::time::Duration::from_secs(10);
+/// How often a running process is polled for exit and checked against its maximum duration.
+const RUN_DURATION_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_millis(250);
+/// Time a stopped persistent process gets to finish its current turn before it is killed.
+const PERSISTENT_PROCESS_STOP_GRACE: std::time::Duration = std::time::Duration::from_secs(5);
+/// How often a streaming assistant draft is persisted to output.md before the run finishes.
+const ASSISTANT_DRAFT_FLUSH_INTERVAL: std::time::Duration = std::time::Duration::from_secs(3);
+/// Growth of the streamed reply, in bytes, between two output token estimates.
+const OUTPUT_TOKEN_CHECK_STEP_BYTES: usize = 1024;
+const APPROVE_FORWARD_MARKER: &str = "[approveForward@@";
+const REJECT_FORWARD_MARKER: &str = "[rejectForward@@";
 
 struct DiffInfo {
     truncated: bool,
 }
 
+/// A workspace an agent works in besides its primary one.
+#[derive(Debug, Clone)]
+struct AdditionalWorkspace {
+    path: PathBuf,
+    /// Prefix of this workspace's files in the run's diff and untracked-file records
+    label: String,
+}
+
+/// Maximum duration of one run, counted only while the run is not waiting on a tool
+/// approval so a pending approval cannot use up the budget.
+struct RunDurationBudget {
+    limit: std::time::Duration,
+    /// Time the run gets to exit once the budget is used up before it is killed
+    kill_grace: std::time::Duration,
+    run_id: Uuid,
+    elapsed: std::time::Duration,
+    last_tick: tokio::time::Instant,
+    exceeded: Arc<AtomicBool>,
+}
+
+impl RunDurationBudget {
+    fn new(limit: std::time::Duration, run_id: Uuid, exceeded: Arc<AtomicBool>) -> Self {
+        Self {
+            limit,
+            kill_grace: MAX_DURATION_KILL_GRACE,
+            run_id,
+            elapsed: std::time::Duration::ZERO,
+            last_tick: tokio::time::Instant::now(),
+            exceeded,
+        }
+    }
+
+    /// Count the time since the previous tick unless the run was waiting on an approval.
+    /// True exactly once, on the tick that uses up the budget.
+    fn tick(&mut self, now: tokio::time::Instant, waiting_approval: bool) -> bool {
+        if !waiting_approval {
+            self.elapsed += now.saturating_duration_since(self.last_tick);
+        }
+        self.last_tick = now;
+        self.elapsed >= self.limit && !self.exceeded.swap(true, Ordering::Relaxed)
+    }
+}
+
+/// raw.log writer that stops persisting output once the per-run cap is reached.
+struct CappedRunLog {
+    file: fs::File,
+    written_bytes: u64,
+    max_bytes: u64,
+    truncated: bool,
+}
+
+impl CappedRunLog {
+    fn new(file: fs::File, max_bytes: u64) -> Self {
+        Self {
+            file,
+            written_bytes: 0,
+            max_bytes,
+            truncated: false,
+        }
+    }
+
+    async fn write(&mut self, text: &str) {
+        if self.truncated {
+            return;
+        }
+
+        let remaining = self.max_bytes.saturating_sub(self.written_bytes);
+        if text.len() as u64 <= remaining {
+            let _ = self.file.write_all(text.as_bytes()).await;
+            self.written_bytes += text.len() as u64;
+            return;
+        }
+
+        let mut cut = remaining as usize;
+        while cut > 0 && !text.is_char_boundary(cut) {
+            cut -= 1;
+        }
+        let _ = self.file.write_all(&text.as_bytes()[..cut]).await;
+        self.written_bytes += cut as u64;
+
+        let marker = format!(
+            "\n[raw.log truncated: exceeded {} byte capture limit; further output was not written to disk]\n",
+            self.max_bytes
+        );
+        let _ = self.file.write_all(marker.as_bytes()).await;
+        let _ = self.file.flush().await;
+        self.truncated = true;
+        tracing::warn!(
+            max_bytes = self.max_bytes,
+            "chat run raw.log reached capture limit; disk logging stopped"
+        );
+    }
+}
+
+/// Resolve `.` and `..` segments without touching the filesystem.
+fn normalize_lexically(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            std::path::Component::CurDir => {}
+            std::path::Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+/// Debug copy of one run's stdout appended to the configured tee file. Each run is
+/// bracketed by start and end markers since concurrent runs share the file.
+struct StdoutTee {
+    file: fs::File,
+    run_id: Uuid,
+}
+
+impl StdoutTee {
+    /// Open the tee file unless it lies inside one of `protected_dirs` (the run and workspace
+    /// directories), where appending would corrupt run records or dirty the agent's checkout.
+    async fn open(
+        path: &Path,
+        protected_dirs: &[&Path],
+        run_id: Uuid,
+        agent_name: &str,
+    ) -> Option<Self> {
+        if Self::is_inside_any(path, protected_dirs).await {
+            tracing::warn!(
+                path = %path.display(),
+                "debug stdout tee path is inside a run or workspace directory; not teeing"
+            );
+            return None;
+        }
+        let opened = async {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent).await?;
+            }
+            fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .await
+        }
+        .await;
+        let mut file = match opened {
+            Ok(file) => file,
+            Err(err) => {
+                tracing::warn!(
+                    path = %path.display(),
+                    error = %err,
+                    "failed to open debug stdout tee file"
+                );
+                return None;
+            }
+        };
+        let header = format!(
+            "\n===== chat run {run_id} ({agent_name}) started {} =====\n",
+            Utc::now().to_rfc3339()
+        );
+        let _ = file.write_all(header.as_bytes()).await;
+        Some(Self { file, run_id })
+    }
+
+    /// Compares lexically normalized paths and, where they exist, canonical ones so `..`
+    /// segments and symlinked roots cannot slip past.
+    async fn is_inside_any(path: &Path, dirs: &[&Path]) -> bool {
+        let normalized = normalize_lexically(path);
+        let canonical = match (normalized.parent(), normalized.file_name()) {
+            (Some(parent), Some(name)) => fs::canonicalize(parent)
+                .await
+                .ok()
+                .map(|parent| parent.join(name)),
+            _ => None,
+        };
+        for dir in dirs {
+            let dir = normalize_lexically(dir);
+            if normalized.starts_with(&dir) {
+                return true;
+            }
+            if let (Some(canonical), Ok(canonical_dir)) =
+                (canonical.as_ref(), fs::canonicalize(&dir).await)
+                && canonical.starts_with(&canonical_dir)
+            {
+                return true;
+            }
+        }
+        false
+    }
+
+    async fn write(&mut self, text: &str) {
+        let _ = self.file.write_all(text.as_bytes()).await;
+    }
+
+    async fn close(mut self) {
+        let footer = format!(
+            "\n===== chat run {} ended {} =====\n",
+            self.run_id,
+            Utc::now().to_rfc3339()
+        );
+        let _ = self.file.write_all(footer.as_bytes()).await;
+        let _ = self.file.flush().await;
+    }
+}
+
+struct PendingDelta {
+    stream_type: ChatStreamDeltaType,
+    content: String,
+    delta: bool,
+}
+
+/// Coalesces `AgentDelta` events for one run so token-by-token executors do not
+/// flood the session channel. Events are merged in order and flushed after
+/// `flush_interval`, once `max_bytes` accumulate, or when the stream type changes.
+struct AgentDeltaBatcher {
+    sender: broadcast::Sender<ChatStreamEvent>,
+    session_id: Uuid,
+    session_agent_id: Uuid,
+    agent_id: Uuid,
+    run_id: Uuid,
+    flush_interval: std::time::Duration,
+    max_bytes: usize,
+    pending: Option<PendingDelta>,
+    deadline: Option<tokio::time::Instant>,
+    // What each stream shows once everything pushed so far is applied, used to drop
+    // full replacements that would not change it.
+    streamed: HashMap<ChatStreamDeltaType, String>,
+    dedupe_identical: bool,
+}
+
+impl AgentDeltaBatcher {
+    fn new(
+        sender: broadcast::Sender<ChatStreamEvent>,
+        session_id: Uuid,
+        session_agent_id: Uuid,
+        agent_id: Uuid,
+        run_id: Uuid,
+        flush_interval: std::time::Duration,
+        max_bytes: usize,
+    ) -> Self {
+        Self {
+            sender,
+            session_id,
+            session_agent_id,
+            agent_id,
+            run_id,
+            flush_interval,
+            max_bytes,
+            pending: None,
+            deadline: None,
+            streamed: HashMap::new(),
+            dedupe_identical: false,
+        }
+    }
+
+    /// Drop full replacements whose content the stream already shows.
+    fn dedupe_identical(mut self, enabled: bool) -> Self {
+        self.dedupe_identical = enabled;
+        self
+    }
+
+    fn push(&mut self, stream_type: ChatStreamDeltaType, content: String, delta: bool) {
+        if self.dedupe_identical {
+            let streamed = self.streamed.entry(stream_type.clone()).or_default();
+            if delta {
+                streamed.push_str(&content);
+            } else if *streamed == content {
+                return;
+            } else {
+                streamed.clone_from(&content);
+            }
+        }
+
+        if self.flush_interval.is_zero() {
+            self.send(stream_type, content, delta);
+            return;
+        }
+
+        match self.pending.as_mut() {
+            Some(pending) if pending.stream_type == stream_type => {
+                if delta {
+                    pending.content.push_str(&content);
+                } else {
+                    // A full replacement supersedes everything buffered for this stream.
+                    pending.content = content;
+                    pending.delta = false;
+                }
+            }
+            _ => {
+                self.flush();
+                self.pending = Some(PendingDelta {
+                    stream_type,
+                    content,
+                    delta,
+                });
+                self.deadline = Some(tokio::time::Instant::now() + self.flush_interval);
+            }
+        }
+
+        if self
+            .pending
+            .as_ref()
+            .is_some_and(|pending| pending.content.len() >= self.max_bytes)
+        {
+            self.flush();
+        }
+    }
+
+    fn deadline(&self) -> Option<tokio::time::Instant> {
+        self.deadline
+    }
+
+    fn flush(&mut self) {
+        self.deadline = None;
+        if let Some(pending) = self.pending.take() {
+            self.send(pending.stream_type, pending.content, pending.delta);
+        }
+    }
+
+    fn send(&self, stream_type: ChatStreamDeltaType, content: String, delta: bool) {
+        let _ = self.sender.send(ChatStreamEvent::AgentDelta {
+            session_id: self.session_id,
+            session_agent_id: self.session_agent_id,
+            agent_id: self.agent_id,
+            run_id: self.run_id,
+            stream_type,
+            content,
+            delta,
+            is_final: false,
+        });
+    }
+}
+
+/// Decodes executor output chunk by chunk. A multibyte character split across chunk
+/// boundaries is held back until its remaining bytes arrive instead of being replaced.
+#[derive(Default)]
+struct Utf8ChunkDecoder {
+    pending: Vec<u8>,
+}
+
+impl Utf8ChunkDecoder {
+    /// Text decodable so far; may be empty when the chunk only started a character.
+    fn decode(&mut self, chunk: &[u8]) -> String {
+        self.pending.extend_from_slice(chunk);
+        let complete = self.pending.len() - Self::incomplete_tail_len(&self.pending);
+        let tail = self.pending.split_off(complete);
+        let text = String::from_utf8_lossy(&self.pending).into_owned();
+        self.pending = tail;
+        text
+    }
+
+    /// Whatever is still held back once the stream ends, decoded lossily.
+    fn finish(&mut self) -> Option<String> {
+        if self.pending.is_empty() {
+            return None;
+        }
+        Some(String::from_utf8_lossy(&std::mem::take(&mut self.pending)).into_owned())
+    }
+
+    /// Length of a trailing sequence whose lead byte announces more bytes than follow it.
+    fn incomplete_tail_len(bytes: &[u8]) -> usize {
+        for back in 1..=bytes.len().min(3) {
+            let byte = bytes[bytes.len() - back];
+            if byte & 0b1100_0000 == 0b1000_0000 {
+                continue;
+            }
+            let needed = match byte {
+                0xC0..=0xDF => 2,
+                0xE0..=0xEF => 3,
+                0xF0..=0xF7 => 4,
+                _ => 1,
+            };
+            return if needed > back { back } else { 0 };
+        }
+        0
+    }
+}
+
+/// Repeats of the last emitted stderr line inside this window are dropped.
+const STDERR_EVENT_DEBOUNCE: std::time::Duration = std::time::Duration::from_secs(2);
+
+/// Splits raw stderr chunks into complete, non-empty lines for `AgentStderr` events,
+/// suppressing a line that repeats the previous one within `STDERR_EVENT_DEBOUNCE`.
+struct StderrLineDebouncer {
+    partial: String,
+    last_line: Option<(String, tokio::time::Instant)>,
+}
+
+impl StderrLineDebouncer {
+    fn new() -> Self {
+        Self {
+            partial: String::new(),
+            last_line: None,
+        }
+    }
+
+    fn push(&mut self, chunk: &str, now: tokio::time::Instant) -> Vec<String> {
+        self.partial.push_str(chunk);
+        let mut lines = Vec::new();
+        while let Some(end) = self.partial.find('\n') {
+            let line: String = self.partial.drain(..=end).collect();
+            if let Some(line) = self.accept(line.trim_end(), now) {
+                lines.push(line);
+            }
+        }
+        lines
+    }
+
+    /// Emit whatever is left after the stream closes without a trailing newline.
+    fn finish(&mut self, now: tokio::time::Instant) -> Option<String> {
+        let rest = std::mem::take(&mut self.partial);
+        self.accept(rest.trim_end(), now)
+    }
+
+    fn accept(&mut self, line: &str, now: tokio::time::Instant) -> Option<String> {
+        if line.trim().is_empty() {
+            return None;
+        }
+        if let Some((last, emitted_at)) = self.last_line.as_ref()
+            && last == line
+            && now.duration_since(*emitted_at) < STDERR_EVENT_DEBOUNCE
+        {
+            return None;
+        }
+        self.last_line = Some((line.to_string(), now));
+        Some(line.to_string())
+    }
+}
+
+/// Where live stderr lines of one run are published when stderr events are enabled.
+#[derive(Clone)]
+struct StderrEventSink {
+    sender: broadcast::Sender<ChatStreamEvent>,
+    session_id: Uuid,
+    session_agent_id: Uuid,
+    run_id: Uuid,
+}
+
+impl StderrEventSink {
+    fn send(&self, line: String) {
+        let _ = self.sender.send(ChatStreamEvent::AgentStderr {
+            session_id: self.session_id,
+            session_agent_id: self.session_agent_id,
+            run_id: self.run_id,
+            line,
+        });
+    }
+}
+
 struct ContextSnapshot {
     workspace_path: PathBuf,
     run_path: PathBuf,
@@ -76,6 +579,26 @@ struct ReferenceAttachment {
     size_bytes: i64,
     kind: String,
     local_path: String,
+    /// Larger than `max_attachment_copy_mb`, so `local_path` is the original upload.
+    too_large: bool,
+}
+
+impl ReferenceAttachment {
+    fn prompt_line(&self) -> String {
+        let mut line = format!(
+            "- name={} kind={} size_bytes={} mime_type={} local_path={}",
+            self.name,
+            self.kind,
+            self.size_bytes,
+            self.mime_type.as_deref().unwrap_or("unknown"),
+            self.local_path
+        );
+        if self.too_large {
+            line.push_str(" not_copied=too_large");
+        }
+        line.push('\n');
+        line
+    }
 }
 
 struct ReferenceContext {
@@ -110,41 +633,380 @@ impl From<chat::CompressionWarning> for CompressionWarning {
     }
 }
 
-#[derive(Debug, Serialize)]
-struct SessionAgentSummary {
+/// An agent-to-agent forward held until the session moderator decides on it.
+#[derive(Debug, Clone)]
+struct PendingForward {
+    session_id: Uuid,
+    source_message: ChatMessage,
+    targets: Vec<String>,
+}
+
+/// An executor tool call waiting for the user to approve or deny it.
+struct PendingApproval {
+    session_id: Uuid,
+    session_agent_id: Uuid,
+    responder: oneshot::Sender<ApprovalStatus>,
+}
+
+/// Routes executor approval requests for one run to the chat UI and waits for the answer.
+struct ChatApprovalBridge {
+    runner: ChatRunner,
+    session_id: Uuid,
     session_agent_id: Uuid,
     agent_id: Uuid,
-    name: String,
-    runner_type: String,
-    state: ChatSessionAgentState,
+    // Updated on every turn when the bridge belongs to a persistent process.
+    run_id: Mutex<Uuid>,
+    // Only one request per run is surfaced at a time, so the run id identifies it.
+    in_flight: Mutex<()>,
+}
+
+#[async_trait]
+impl ExecutorApprovalService for ChatApprovalBridge {
+    async fn request_tool_approval(
+        &self,
+        tool_name: &str,
+        tool_input: serde_json::Value,
+        _tool_call_id: &str,
+        cancel: CancellationToken,
+    ) -> Result<ApprovalStatus, ExecutorApprovalError> {
+        let _in_flight = self.in_flight.lock().await;
+        let run_id = *self.run_id.lock().await;
+        let (responder, waiter) = oneshot::channel();
+        self.runner.pending_approvals.insert(
+            run_id,
+            PendingApproval {
+                session_id: self.session_id,
+                session_agent_id: self.session_agent_id,
+                responder,
+            },
+        );
+        self.runner
+            .set_agent_approval_state(
+                self.session_id,
+                self.session_agent_id,
+                self.agent_id,
+                ChatSessionAgentState::Running,
+                ChatSessionAgentState::WaitingApproval,
+            )
+            .await;
+        self.runner.emit(
+            self.session_id,
+            ChatStreamEvent::ApprovalRequested {
+                session_id: self.session_id,
+                session_agent_id: self.session_agent_id,
+                run_id,
+                prompt: ChatRunner::approval_prompt(tool_name, &tool_input),
+                tool_name: tool_name.to_string(),
+                tool_input,
+            },
+        );
+
+        let timeout = std::time::Duration::from_secs(APPROVAL_TIMEOUT_SECONDS.unsigned_abs());
+        let status = tokio::select! {
+            _ = cancel.cancelled() => None,
+            result = tokio::time::timeout(timeout, waiter) => Some(match result {
+                Ok(Ok(status)) => status,
+                Ok(Err(_)) => ApprovalStatus::Denied {
+                    reason: Some("Run was stopped before approval".to_string()),
+                },
+                Err(_) => ApprovalStatus::TimedOut,
+            }),
+        };
+        self.runner.pending_approvals.remove(&run_id);
+        self.runner
+            .set_agent_approval_state(
+                self.session_id,
+                self.session_agent_id,
+                self.agent_id,
+                ChatSessionAgentState::WaitingApproval,
+                ChatSessionAgentState::Running,
+            )
+            .await;
+        self.runner.emit(
+            self.session_id,
+            ChatStreamEvent::ApprovalResolved {
+                session_id: self.session_id,
+                session_agent_id: self.session_agent_id,
+                run_id,
+                status: status.clone().unwrap_or(ApprovalStatus::Denied {
+                    reason: Some("Approval request cancelled".to_string()),
+                }),
+            },
+        );
+
+        status.ok_or(ExecutorApprovalError::Cancelled)
+    }
+}
+
+/// Stdout line shapes that carry token usage, keyed by how they are detected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TokenUsageFormat {
+    Plain,
+    CodexTokenCount,
+    NestedUsage,
+}
+
+impl TokenUsageFormat {
+    fn detect(value: &serde_json::Value) -> Option<Self> {
+        let value_obj = value.as_object()?;
+        if value_obj.get("type").and_then(|v| v.as_str()) == Some("token_usage") {
+            return Some(Self::Plain);
+        }
+        if value_obj.get("method").and_then(|v| v.as_str()) == Some("codex/event/token_count") {
+            return Some(Self::CodexTokenCount);
+        }
+        Self::nested_usage_object(value).map(|_| Self::NestedUsage)
+    }
+
+    fn nested_usage_object(value: &serde_json::Value) -> Option<&serde_json::Value> {
+        [
+            value.get("usage"),
+            value
+                .get("message")
+                .and_then(|message| message.get("usage")),
+        ]
+        .into_iter()
+        .flatten()
+        .find(|usage| {
+            usage.get("input_tokens").is_some_and(|v| v.is_u64())
+                || usage.get("prompt_tokens").is_some_and(|v| v.is_u64())
+        })
+    }
+}
+
+/// Substrings at least one of which appears in every line [`TokenUsageFormat::detect`] accepts.
+const TOKEN_USAGE_LINE_MARKERS: &[&str] = &["token_usage", "token_count", "\"usage\""];
+
+/// Check that an agent's `tools_enabled.max_output_tokens`, when set, is a positive integer.
+pub fn validate_max_output_tokens(tools_enabled: &serde_json::Value) -> Result<(), String> {
+    match tools_enabled.get(MAX_OUTPUT_TOKENS_KEY) {
+        None | Some(serde_json::Value::Null) => Ok(()),
+        Some(value) if value.as_u64().is_some_and(|limit| limit > 0) => Ok(()),
+        Some(value) => Err(format!(
+            "Invalid {MAX_OUTPUT_TOKENS_KEY} {value}; expected a positive integer"
+        )),
+    }
+}
+
+fn json_u32(value: &serde_json::Value, key: &str) -> Option<u32> {
+    value
+        .get(key)
+        .and_then(|v| v.as_u64())
+        .and_then(|v| u32::try_from(v).ok())
+}
+
+/// Prompt sections dropped to fit `max_prompt_tokens`, recorded in the reply's meta.
+#[derive(Debug, Clone, Serialize)]
+struct PromptTruncation {
+    max_prompt_tokens: u32,
+    estimated_tokens: u32,
+    omitted_sections: Vec<&'static str>,
+}
+
+/// Executor session state recorded in a finished run's `meta.json`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct RunCheckpoint {
+    agent_session_id: String,
+    agent_message_id: Option<String>,
+}
+
+/// Provenance of a run that was triggered by another agent's routing directive.
+#[derive(Debug, Clone, Serialize)]
+struct ForwardedFrom {
+    message_id: Uuid,
+    agent_id: Option<Uuid>,
+    directive_target: String,
+}
+
+/// Live state of a session's event broadcast channel.
+#[derive(Debug, Clone, Serialize, TS)]
+#[ts(export)]
+pub struct ChatStreamStats {
+    pub session_id: Uuid,
+    /// Open receivers, one per connected stream socket
+    pub subscriber_count: usize,
+    /// Events the slowest receiver has not read yet
+    pub queued_events: usize,
+    /// Events the channel holds before slow receivers start losing them
+    pub capacity: usize,
+    /// Events receivers of this session have lost by lagging since the channel was created
+    pub lagged_events: u64,
+}
+
+/// Event channel of one session plus the bookkeeping behind [`ChatStreamStats`].
+#[derive(Clone)]
+struct SessionStream {
+    sender: broadcast::Sender<ChatStreamEvent>,
+    capacity: usize,
+    lagged_events: Arc<AtomicU64>,
+}
+
+/// A session member as presented to agents in the `[GROUP_MEMBERS]` prompt block.
+#[derive(Debug, Clone, Serialize, TS)]
+#[ts(export)]
+pub struct SessionAgentSummary {
+    pub session_agent_id: Uuid,
+    pub agent_id: Uuid,
+    pub name: String,
+    pub runner_type: String,
+    pub state: ChatSessionAgentState,
     /// Description of the agent for GROUP_MEMBERS display
-    description: Option<String>,
+    pub description: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    system_prompt: Option<String>,
-    tools_enabled: serde_json::Value,
+    #[ts(optional)]
+    pub system_prompt: Option<String>,
+    #[ts(type = "JsonValue")]
+    pub tools_enabled: serde_json::Value,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+/// Why the database and the runner disagree about whether a member is running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, TS)]
 #[serde(rename_all = "snake_case")]
 #[ts(export)]
-pub enum MentionStatus {
-    Received, // Message queued, waiting for agent to be available
-    Running,
-    Completed,
-    Failed,
+pub enum RunningAgentDiscrepancy {
+    /// Marked running, but the runner has no live run for it (e.g. left over from a crash)
+    NoLiveRun,
+    /// The runner has a live run, but the member is not marked running
+    NotMarkedRunning,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, TS)]
-#[serde(tag = "type", rename_all = "snake_case")]
+/// A session member with a run in progress, as seen by `GET /chat/running`.
+#[derive(Debug, Clone, Serialize, TS)]
 #[ts(export)]
-pub enum ChatStreamEvent {
-    MessageNew {
-        message: ChatMessage,
-    },
-    AgentDelta {
-        session_id: Uuid,
-        session_agent_id: Uuid,
-        agent_id: Uuid,
+pub struct RunningAgent {
+    pub session_id: Uuid,
+    pub session_agent_id: Uuid,
+    pub agent_id: Uuid,
+    pub state: ChatSessionAgentState,
+    /// Run record of the current run, once it has been created
+    pub run_id: Option<Uuid>,
+    /// When the current run was claimed
+    pub started_at: chrono::DateTime<Utc>,
+    /// Whether the runner holds a cancellation token for the member
+    pub live: bool,
+    pub discrepancy: Option<RunningAgentDiscrepancy>,
+}
+
+/// Result of stopping every running agent of a session.
+#[derive(Debug, Clone, Default, Serialize, TS)]
+#[ts(export)]
+pub struct StopAllAgentsOutcome {
+    /// Number of runs that were stopped
+    pub stopped: usize,
+    /// Members whose run could not be stopped
+    pub failed: Vec<Uuid>,
+}
+
+/// A cutoff file archived by truncation-based compression in a member's context directory.
+#[derive(Debug, Clone, Serialize, TS)]
+#[ts(export)]
+pub struct ChatCutoffSummary {
+    pub index: u32,
+    /// Session member whose workspace holds the file
+    pub session_agent_id: Uuid,
+    pub cutoff_at: Option<String>,
+    pub message_count: usize,
+}
+
+/// Contents of a single cutoff file.
+#[derive(Debug, Clone, Serialize, TS)]
+#[ts(export)]
+pub struct ChatCutoffArchive {
+    #[serde(flatten)]
+    #[ts(flatten)]
+    pub summary: ChatCutoffSummary,
+    #[ts(type = "Array<JsonValue>")]
+    pub messages: Vec<serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CutoffFileContents {
+    #[serde(default)]
+    cutoff_at: Option<String>,
+    #[serde(default)]
+    message_count: Option<usize>,
+    #[serde(default)]
+    messages: Vec<serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+#[serde(rename_all = "snake_case")]
+#[ts(export)]
+pub enum MentionStatus {
+    Received, // Message queued, waiting for agent to be available
+    Running,
+    Completed,
+    Failed,
+}
+
+/// Why a mention did not start a run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, TS)]
+#[serde(rename_all = "snake_case")]
+#[ts(export)]
+pub enum RoutingSkipReason {
+    /// The message is already at the maximum agent chain depth
+    ChainDepthLimit,
+    /// The sending agent's routing allowlist excludes the target
+    NotAllowed,
+    /// Held until the session moderator approves the forward
+    HeldForModerator,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct RoutingSkip {
+    pub target: String,
+    pub reason: RoutingSkipReason,
+}
+
+/// What routing did with a message's mentions, stored under `meta.routing`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct RoutingOutcome {
+    /// Targets handed to a session member's run
+    pub dispatched: Vec<String>,
+    /// Targets that match no session member
+    pub not_found: Vec<String>,
+    pub skipped: Vec<RoutingSkip>,
+}
+
+impl RoutingOutcome {
+    fn is_empty(&self) -> bool {
+        self.dispatched.is_empty() && self.not_found.is_empty() && self.skipped.is_empty()
+    }
+
+    /// Record every target in `before` that a routing step left out of `after`.
+    fn skip_dropped(&mut self, before: &[String], after: &[String], reason: RoutingSkipReason) {
+        self.skipped.extend(
+            before
+                .iter()
+                .filter(|target| !after.contains(target))
+                .map(|target| RoutingSkip {
+                    target: target.clone(),
+                    reason,
+                }),
+        );
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+#[serde(tag = "type", rename_all = "snake_case")]
+#[ts(export)]
+pub enum ChatStreamEvent {
+    MessageNew {
+        message: ChatMessage,
+    },
+    MessageUpdated {
+        message: ChatMessage,
+    },
+    MessagesDeleted {
+        session_id: Uuid,
+        message_ids: Vec<Uuid>,
+    },
+    AgentDelta {
+        session_id: Uuid,
+        session_agent_id: Uuid,
+        agent_id: Uuid,
         run_id: Uuid,
         stream_type: ChatStreamDeltaType,
         content: String,
@@ -157,6 +1019,11 @@ pub enum ChatStreamEvent {
         state: ChatSessionAgentState,
         started_at: Option<chrono::DateTime<Utc>>,
     },
+    AgentMuted {
+        session_agent_id: Uuid,
+        agent_id: Uuid,
+        muted: bool,
+    },
     MentionAcknowledged {
         session_id: Uuid,
         message_id: Uuid,
@@ -168,9 +1035,74 @@ pub enum ChatStreamEvent {
         session_id: Uuid,
         warning: CompressionWarning,
     },
+    AgentStderr {
+        session_id: Uuid,
+        session_agent_id: Uuid,
+        run_id: Uuid,
+        line: String,
+    },
+    ApprovalRequested {
+        session_id: Uuid,
+        session_agent_id: Uuid,
+        run_id: Uuid,
+        prompt: String,
+        tool_name: String,
+        #[ts(type = "JsonValue")]
+        tool_input: serde_json::Value,
+    },
+    ApprovalResolved {
+        session_id: Uuid,
+        session_agent_id: Uuid,
+        run_id: Uuid,
+        status: ApprovalStatus,
+    },
+    /// A system message whose category is suppressed from the transcript
+    SystemNotice {
+        session_id: Uuid,
+        category: SystemMessageCategory,
+        content: String,
+        #[ts(type = "JsonValue")]
+        meta: serde_json::Value,
+    },
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+impl ChatStreamEvent {
+    /// Every value of the serialized `type` tag.
+    pub const EVENT_TYPES: &'static [&'static str] = &[
+        "message_new",
+        "message_updated",
+        "messages_deleted",
+        "agent_delta",
+        "agent_state",
+        "agent_muted",
+        "mention_acknowledged",
+        "compression_warning",
+        "agent_stderr",
+        "approval_requested",
+        "approval_resolved",
+        "system_notice",
+    ];
+
+    /// The serialized `type` tag of this event.
+    pub fn event_type(&self) -> &'static str {
+        match self {
+            Self::MessageNew { .. } => "message_new",
+            Self::MessageUpdated { .. } => "message_updated",
+            Self::MessagesDeleted { .. } => "messages_deleted",
+            Self::AgentDelta { .. } => "agent_delta",
+            Self::AgentState { .. } => "agent_state",
+            Self::AgentMuted { .. } => "agent_muted",
+            Self::MentionAcknowledged { .. } => "mention_acknowledged",
+            Self::CompressionWarning { .. } => "compression_warning",
+            Self::AgentStderr { .. } => "agent_stderr",
+            Self::ApprovalRequested { .. } => "approval_requested",
+            Self::ApprovalResolved { .. } => "approval_resolved",
+            Self::SystemNotice { .. } => "system_notice",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize, TS)]
 #[serde(rename_all = "snake_case")]
 #[ts(export)]
 pub enum ChatStreamDeltaType {
@@ -192,6 +1124,16 @@ pub enum ChatRunnerError {
     Io(#[from] std::io::Error),
     #[error(transparent)]
     ChatService(#[from] ChatServiceError),
+    #[error("workspace unavailable: {0}")]
+    WorkspaceUnavailable(String),
+    #[error("chat session agent has a live run: {0}")]
+    AgentRunActive(Uuid),
+    #[error("no pending approval for chat run: {0}")]
+    ApprovalNotFound(Uuid),
+    #[error("executor process did not start within {0}s")]
+    SpawnTimeout(u64),
+    #[error("context compaction already running for chat session: {0}")]
+    CompactionInFlight(Uuid),
 }
 
 /// Pending message to be processed by an agent
@@ -201,20 +1143,116 @@ struct PendingMessage {
     agent_id: Uuid,
     agent_name: String,
     message: ChatMessage,
+    priority: chat::ChatMessagePriority,
+}
+
+impl PendingMessage {
+    /// Queues ahead of every lower-priority message, behind those of equal or higher priority.
+    fn enqueue(queue: &mut VecDeque<PendingMessage>, pending: PendingMessage) {
+        let position = queue
+            .iter()
+            .position(|queued| queued.priority < pending.priority)
+            .unwrap_or(queue.len());
+        queue.insert(position, pending);
+    }
+}
+
+/// The executor process serving one run.
+enum RunProcess {
+    Spawned(SpawnedChild),
+    Persistent(Arc<PersistentAgentProcess>),
+}
+
+/// Where a persistent process's output goes while it serves one run.
+#[derive(Clone)]
+struct PersistentRunOutput {
+    msg_store: Arc<MsgStore>,
+    raw_log_file: Arc<Mutex<CappedRunLog>>,
+    stderr_events: Option<StderrEventSink>,
+    redaction_rules: Arc<Vec<RedactionRule>>,
+}
+
+/// Raw-output redaction of one persistent process stream, following the process from run
+/// to run. Text held back for a partial line goes to the run it was read for once the next
+/// run takes over.
+struct PersistentOutputRedactor {
+    log_msg: fn(String) -> LogMsg,
+    current: Option<(PersistentRunOutput, RawOutputRedactor)>,
+}
+
+impl PersistentOutputRedactor {
+    fn new(log_msg: fn(String) -> LogMsg) -> Self {
+        Self {
+            log_msg,
+            current: None,
+        }
+    }
+
+    async fn redact(&mut self, target: &PersistentRunOutput, text: &str) -> String {
+        let same_run = self
+            .current
+            .as_ref()
+            .is_some_and(|(current, _)| Arc::ptr_eq(&current.msg_store, &target.msg_store));
+        if !same_run {
+            if let Some((previous, mut redactor)) = self.current.take()
+                && let Some(rest) = redactor.finish()
+            {
+                previous.msg_store.push((self.log_msg)(rest.clone()));
+                previous.raw_log_file.lock().await.write(&rest).await;
+            }
+            let redactor =
+                RawOutputRedactor::new(OutputRedactor::new(target.redaction_rules.iter()));
+            self.current = Some((target.clone(), redactor));
+        }
+        match self.current.as_mut() {
+            Some((_, redactor)) => redactor.push(text),
+            None => text.to_string(),
+        }
+    }
+}
+
+/// An executor process kept alive between runs of one session agent.
+struct PersistentAgentProcess {
+    workspace_path: String,
+    prompts: mpsc::UnboundedSender<String>,
+    turns: Mutex<mpsc::UnboundedReceiver<ExecutorExitResult>>,
+    cancel: CancellationToken,
+    // Output keeps going to the last run until the next one takes over, so lines
+    // flushed just after a turn ends still land in that turn's log.
+    output: Arc<Mutex<Option<PersistentRunOutput>>>,
+    approvals: Arc<ChatApprovalBridge>,
+    // Whether the executor was handed `approvals` or auto-approves tool calls.
+    chat_approvals: bool,
 }
 
 #[derive(Clone)]
 pub struct ChatRunner {
     db: DBService,
-    streams: Arc<DashMap<Uuid, broadcast::Sender<ChatStreamEvent>>>,
+    streams: Arc<DashMap<Uuid, SessionStream>>,
+    // Capacity of session event channels created from now on
+    stream_channel_capacity: Arc<AtomicUsize>,
     // Store cancellation tokens for graceful shutdown, key = session_agent_id
     cancellation_tokens: Arc<DashMap<Uuid, CancellationToken>>,
     // Message queue for each session_agent, keyed by session_agent_id
     // When an agent is running, new messages are queued here and processed after completion
     pending_messages: Arc<DashMap<Uuid, VecDeque<PendingMessage>>>,
+    // Serializes start-or-queue decisions with the end of a run, keyed by session_agent_id
+    dispatch_locks: Arc<DashMap<Uuid, Arc<Mutex<()>>>>,
     // Session-level background context compaction dedupe.
     // At most one compaction task per session is allowed at a time.
     background_compaction_inflight: Arc<DashMap<Uuid, ()>>,
+    // Forwards awaiting a moderator decision, keyed by forward id
+    pending_forwards: Arc<DashMap<Uuid, PendingForward>>,
+    // Unknown handles already reported under the WARN_ONCE policy, keyed by (session, handle)
+    warned_unknown_mentions: Arc<DashMap<(Uuid, String), ()>>,
+    // Executor tool calls awaiting the user's approval, keyed by run id
+    pending_approvals: Arc<DashMap<Uuid, PendingApproval>>,
+    // Executor processes kept alive between runs, keyed by session_agent_id
+    persistent_processes: Arc<DashMap<Uuid, Arc<PersistentAgentProcess>>>,
+    // When each member's current or last run was claimed, keyed by session_agent_id
+    run_starts: Arc<DashMap<Uuid, chrono::DateTime<Utc>>>,
+    // Mention fan-out slots held by running members until their run finishes
+    fanout_permits: Arc<DashMap<Uuid, OwnedSemaphorePermit>>,
 }
 
 impl ChatRunner {
@@ -222,9 +1260,19 @@ impl ChatRunner {
         Self {
             db,
             streams: Arc::new(DashMap::new()),
+            stream_channel_capacity: Arc::new(AtomicUsize::new(
+                ChatRunnerConfig::default().stream_channel_capacity(),
+            )),
             cancellation_tokens: Arc::new(DashMap::new()),
             pending_messages: Arc::new(DashMap::new()),
+            dispatch_locks: Arc::new(DashMap::new()),
             background_compaction_inflight: Arc::new(DashMap::new()),
+            pending_forwards: Arc::new(DashMap::new()),
+            warned_unknown_mentions: Arc::new(DashMap::new()),
+            pending_approvals: Arc::new(DashMap::new()),
+            persistent_processes: Arc::new(DashMap::new()),
+            run_starts: Arc::new(DashMap::new()),
+            fanout_permits: Arc::new(DashMap::new()),
         }
     }
 
@@ -232,37 +1280,77 @@ impl ChatRunner {
         self.sender_for(session_id).subscribe()
     }
 
-    pub fn emit_message_new(&self, session_id: Uuid, message: ChatMessage) {
-        self.emit(session_id, ChatStreamEvent::MessageNew { message });
+    /// Set the capacity of session event channels; channels that already exist keep theirs.
+    pub fn set_stream_channel_capacity(&self, capacity: usize) {
+        self.stream_channel_capacity
+            .store(capacity, Ordering::Relaxed);
     }
 
-    /// Update the mention_statuses field in a message's meta
-    async fn update_mention_status(&self, message_id: Uuid, agent_name: &str, status: &str) {
-        // Fetch the current message
-        let Ok(Some(message)) = ChatMessage::find_by_id(&self.db.pool, message_id).await else {
-            tracing::warn!(
-                message_id = %message_id,
-                "failed to fetch message for mention status update"
-            );
-            return;
-        };
+    /// Record that a receiver of the session's stream skipped `skipped` events.
+    pub fn record_stream_lag(&self, session_id: Uuid, skipped: u64) {
+        if let Some(stream) = self.streams.get(&session_id) {
+            stream.lagged_events.fetch_add(skipped, Ordering::Relaxed);
+        }
+    }
 
-        // Update the meta with new mention status
-        let mut meta = message.meta.0.clone();
-        let mention_statuses = meta
-            .get_mut("mention_statuses")
-            .and_then(|v| v.as_object_mut());
+    /// Sessions nobody has streamed yet report zeroed stats; reading never creates a channel.
+    pub fn stream_stats(&self, session_id: Uuid) -> ChatStreamStats {
+        let Some(stream) = self.streams.get(&session_id) else {
+            return ChatStreamStats {
+                session_id,
+                subscriber_count: 0,
+                queued_events: 0,
+                capacity: 0,
+                lagged_events: 0,
+            };
+        };
+        ChatStreamStats {
+            session_id,
+            subscriber_count: stream.sender.receiver_count(),
+            queued_events: stream.sender.len(),
+            capacity: stream.capacity,
+            lagged_events: stream.lagged_events.load(Ordering::Relaxed),
+        }
+    }
 
-        if let Some(statuses) = mention_statuses {
-            statuses.insert(agent_name.to_string(), serde_json::json!(status));
-        } else {
-            let mut new_statuses = serde_json::Map::new();
-            new_statuses.insert(agent_name.to_string(), serde_json::json!(status));
-            meta["mention_statuses"] = serde_json::Value::Object(new_statuses);
+    /// Drop in-memory state kept for a session once it is archived or deleted, stopping
+    /// its members' persistent processes.
+    pub fn forget_session(&self, session_id: Uuid) {
+        self.warned_unknown_mentions
+            .retain(|(warned_session_id, _), _| *warned_session_id != session_id);
+        let session_agent_ids: Vec<Uuid> = self
+            .persistent_processes
+            .iter()
+            .filter(|entry| entry.value().approvals.session_id == session_id)
+            .map(|entry| *entry.key())
+            .collect();
+        for session_agent_id in session_agent_ids {
+            self.shutdown_persistent_process(session_agent_id);
         }
+    }
+
+    pub fn emit_message_new(&self, session_id: Uuid, message: ChatMessage) {
+        self.emit(session_id, ChatStreamEvent::MessageNew { message });
+    }
+
+    pub fn emit_message_updated(&self, session_id: Uuid, message: ChatMessage) {
+        self.emit(session_id, ChatStreamEvent::MessageUpdated { message });
+    }
+
+    pub fn emit_messages_deleted(&self, session_id: Uuid, message_ids: Vec<Uuid>) {
+        self.emit(
+            session_id,
+            ChatStreamEvent::MessagesDeleted {
+                session_id,
+                message_ids,
+            },
+        );
+    }
 
-        // Persist the updated meta
-        if let Err(err) = ChatMessage::update_meta(&self.db.pool, message_id, meta).await {
+    /// Update the mention_statuses field in a message's meta
+    async fn update_mention_status(&self, message_id: Uuid, agent_name: &str, status: &str) {
+        let patch = serde_json::json!({ "mention_statuses": { agent_name: status } });
+        if let Err(err) = ChatMessage::merge_meta(&self.db.pool, message_id, patch).await {
             tracing::warn!(
                 message_id = %message_id,
                 error = %err,
@@ -335,49 +1423,183 @@ impl ChatRunner {
         )
         .await;
 
-        let mut failure_meta = serde_json::json!({
+        let failure_meta =
+            Self::mention_failure_meta(message_id, agent_name, agent_id, &compact_reason);
+
+        let system_content = format!(
+            "Agent \"{}\" failed to execute this mention: {}",
+            agent_name, compact_reason
+        );
+
+        if let Err(err) = self
+            .post_system_message(
+                session_id,
+                SystemMessageCategory::MentionFailure,
+                system_content,
+                failure_meta,
+            )
+            .await
+        {
+            tracing::warn!(
+                session_id = %session_id,
+                message_id = %message_id,
+                agent_name = %agent_name,
+                error = %err,
+                "failed to emit mention failure system message"
+            );
+        }
+    }
+
+    /// Meta of a mention failure system message or notice.
+    fn mention_failure_meta(
+        message_id: Uuid,
+        agent_name: &str,
+        agent_id: Option<Uuid>,
+        reason: &str,
+    ) -> serde_json::Value {
+        let mut meta = serde_json::json!({
             "mention_failure": {
                 "source_message_id": message_id,
                 "mentioned_agent": agent_name,
-                "reason": compact_reason.clone(),
+                "reason": reason,
             }
         });
-
         if let Some(value) = agent_id {
-            failure_meta["mention_failure"]["agent_id"] = serde_json::json!(value);
+            meta["mention_failure"]["agent_id"] = serde_json::json!(value);
         }
+        meta
+    }
 
-        let system_content = format!(
-            "Agent \"{}\" failed to execute this mention: {}",
-            agent_name, compact_reason
-        );
-
-        match chat::create_message(
-            &self.db.pool,
+    /// Apply `agent_unresolved_routing_policy` to an agent-authored route whose target is
+    /// not an agent of the session. Returns `true` when the policy handled the route and no
+    /// mention failure should be reported.
+    async fn handle_unresolved_agent_route(
+        &self,
+        session_id: Uuid,
+        source_message: &ChatMessage,
+        agent_name: &str,
+        agent_id: Option<Uuid>,
+        reason: &str,
+    ) -> bool {
+        if source_message.sender_type != ChatSenderType::Agent {
+            return false;
+        }
+        let policy = Self::load_runner_config()
+            .await
+            .agent_unresolved_routing_policy;
+        self.apply_unresolved_agent_route_policy(
+            policy,
             session_id,
-            ChatSenderType::System,
-            None,
-            system_content,
-            Some(failure_meta),
+            source_message,
+            agent_name,
+            agent_id,
+            reason,
         )
         .await
-        {
-            Ok(message) => self.emit_message_new(session_id, message),
-            Err(err) => {
-                tracing::warn!(
+    }
+
+    /// Handle an unresolved agent route under `policy`. Routes the policy handles still
+    /// mark the mention failed; they only skip the system message.
+    async fn apply_unresolved_agent_route_policy(
+        &self,
+        policy: AgentUnresolvedRoutingPolicy,
+        session_id: Uuid,
+        source_message: &ChatMessage,
+        agent_name: &str,
+        agent_id: Option<Uuid>,
+        reason: &str,
+    ) -> bool {
+        if policy == AgentUnresolvedRoutingPolicy::Fail {
+            return false;
+        }
+        self.set_mention_status(
+            session_id,
+            source_message.id,
+            agent_name,
+            agent_id,
+            MentionStatus::Failed,
+        )
+        .await;
+        match policy {
+            AgentUnresolvedRoutingPolicy::Fail => false,
+            AgentUnresolvedRoutingPolicy::SilentDrop => {
+                tracing::debug!(
                     session_id = %session_id,
-                    message_id = %message_id,
-                    agent_name = %agent_name,
-                    error = %err,
-                    "failed to emit mention failure system message"
+                    message_id = %source_message.id,
+                    mention = agent_name,
+                    "dropping agent route to unresolved handle"
+                );
+                true
+            }
+            AgentUnresolvedRoutingPolicy::WarnEventOnly => {
+                let category = SystemMessageCategory::MentionFailure;
+                let mut meta =
+                    Self::mention_failure_meta(source_message.id, agent_name, agent_id, reason);
+                meta[SYSTEM_CATEGORY_META_KEY] = serde_json::json!(category);
+                self.emit(
+                    session_id,
+                    ChatStreamEvent::SystemNotice {
+                        session_id,
+                        category,
+                        content: format!("Message to \"{agent_name}\" was not delivered: {reason}"),
+                        meta,
+                    },
                 );
+                true
             }
         }
     }
 
+    /// Post a runner-generated system message tagged with `category`. Suppressed categories
+    /// are only emitted as [`ChatStreamEvent::SystemNotice`] and return `None`.
+    async fn post_system_message(
+        &self,
+        session_id: Uuid,
+        category: SystemMessageCategory,
+        content: String,
+        mut meta: serde_json::Value,
+    ) -> Result<Option<ChatMessage>, ChatServiceError> {
+        meta[SYSTEM_CATEGORY_META_KEY] = serde_json::json!(category);
+        if Self::load_runner_config()
+            .await
+            .suppresses_system_message(category)
+        {
+            self.emit(
+                session_id,
+                ChatStreamEvent::SystemNotice {
+                    session_id,
+                    category,
+                    content,
+                    meta,
+                },
+            );
+            return Ok(None);
+        }
+
+        let message = chat::create_message(
+            &self.db.pool,
+            session_id,
+            ChatSenderType::System,
+            None,
+            content,
+            Some(meta),
+        )
+        .await?;
+        self.emit_message_new(session_id, message.clone());
+        Ok(Some(message))
+    }
+
     pub async fn handle_message(&self, session: &ChatSession, message: &ChatMessage) {
         self.emit_message_new(session.id, message.clone());
 
+        if message.sender_type == ChatSenderType::Agent {
+            self.apply_moderator_decisions(session.id, message).await;
+        }
+
+        let session_id = session.id;
+        let mut mentions = message.mentions.0.clone();
+        let mut routing = RoutingOutcome::default();
+
         // Check chain depth to prevent infinite loops
         let chain_depth = self.extract_chain_depth(&message.meta);
         if chain_depth >= MAX_AGENT_CHAIN_DEPTH {
@@ -386,2005 +1608,7570 @@ impl ChatRunner {
                 chain_depth = chain_depth,
                 "agent chain depth limit reached; not triggering further agents"
             );
+            routing.skip_dropped(&mentions, &[], RoutingSkipReason::ChainDepthLimit);
+            self.record_routing_outcome(session_id, message.id, &routing)
+                .await;
             return;
         }
 
-        let session_id = session.id;
-        let mentions = message.mentions.0.clone();
-        for mention in mentions {
-            if message.sender_type == ChatSenderType::Agent
-                && mention.eq_ignore_ascii_case(RESERVED_USER_HANDLE)
-            {
-                tracing::debug!(
-                    session_id = %session_id,
-                    message_id = %message.id,
-                    mention = mention,
-                    "skipping reserved user mention in agent message"
-                );
-                continue;
-            }
-
-            let runner = self.clone();
-            let message_clone = message.clone();
-            tokio::spawn(async move {
-                if let Err(err) = runner
-                    .run_agent_for_mention(session_id, &mention, &message_clone)
-                    .await
-                {
-                    tracing::warn!(
-                        error = %err,
-                        mention = mention,
-                        session_id = %session_id,
-                        "chat runner failed for mention"
-                    );
-                }
-            });
+        if message.sender_type == ChatSenderType::User && mentions.is_empty() {
+            mentions.extend(self.default_agent_mention(session_id).await);
         }
-    }
 
-    fn extract_chain_depth(&self, meta: &sqlx::types::Json<serde_json::Value>) -> u32 {
-        meta.get("chain_depth")
-            .and_then(|v| v.as_u64())
-            .map(|v| v as u32)
-            .unwrap_or(0)
+        if message.sender_type == ChatSenderType::Agent {
+            let permitted = self
+                .enforce_routing_allowlist(session_id, message, mentions.clone())
+                .await;
+            routing.skip_dropped(&mentions, &permitted, RoutingSkipReason::NotAllowed);
+            let immediate = self
+                .hold_forwards_for_moderator(session_id, message, permitted.clone())
+                .await;
+            routing.skip_dropped(&permitted, &immediate, RoutingSkipReason::HeldForModerator);
+            mentions = immediate;
+        }
+        (routing.dispatched, routing.not_found) =
+            self.partition_known_mentions(session_id, &mentions).await;
+        self.record_routing_outcome(session_id, message.id, &routing)
+            .await;
+        self.dispatch_mentions(session_id, message, mentions);
     }
 
-    fn emit(&self, session_id: Uuid, event: ChatStreamEvent) {
-        let sender = self.sender_for(session_id);
-        let _ = sender.send(event);
-    }
-
-    fn sender_for(&self, session_id: Uuid) -> broadcast::Sender<ChatStreamEvent> {
-        if let Some(entry) = self.streams.get(&session_id) {
-            return entry.clone();
-        }
-
-        let (sender, _) = broadcast::channel(1024);
-        self.streams.insert(session_id, sender.clone());
-        sender
-    }
-
-    /// Process the next pending message for a session agent after it becomes idle
-    async fn process_pending_queue(&self, session_id: Uuid, session_agent_id: Uuid) {
-        // Get the next pending message from the queue
-        let pending = self
-            .pending_messages
-            .get_mut(&session_agent_id)
-            .and_then(|mut queue| queue.pop_front());
-
-        if let Some(pending_msg) = pending {
-            tracing::info!(
-                session_agent_id = %session_agent_id,
-                message_id = %pending_msg.message.id,
-                agent_name = %pending_msg.agent_name,
-                "processing queued message for agent"
-            );
-
-            // Process the queued message by calling run_agent_for_mention
-            // Use the stored agent_name to find the agent (handles rename gracefully)
-            if let Err(err) = self
-                .run_agent_for_mention(
-                    pending_msg.session_id,
-                    &pending_msg.agent_name,
-                    &pending_msg.message,
-                )
-                .await
-            {
+    /// Name of the session's default agent, which answers user messages that mention nobody.
+    /// A default agent that has since left the session is ignored.
+    async fn default_agent_mention(&self, session_id: Uuid) -> Option<String> {
+        let agent_id = match ChatSession::find_default_agent_id(&self.db.pool, session_id).await {
+            Ok(agent_id) => agent_id?,
+            Err(err) => {
                 tracing::warn!(
+                    session_id = %session_id,
                     error = %err,
-                    agent_name = %pending_msg.agent_name,
-                    session_agent_id = %session_agent_id,
-                    "failed to process queued message"
+                    "failed to load session default agent"
                 );
-                // Continue processing the rest of the queue
-                Box::pin(self.process_pending_queue(session_id, session_agent_id)).await;
+                return None;
             }
-        } else {
-            // Clean up empty queue entry
-            self.pending_messages.remove(&session_agent_id);
+        };
+        let is_member =
+            ChatSessionAgent::find_by_session_and_agent(&self.db.pool, session_id, agent_id)
+                .await
+                .ok()
+                .flatten()
+                .is_some();
+        if !is_member {
+            tracing::warn!(
+                session_id = %session_id,
+                agent_id = %agent_id,
+                "session default agent is not a member; leaving message unrouted"
+            );
+            return None;
         }
+        ChatAgent::find_by_id(&self.db.pool, agent_id)
+            .await
+            .ok()
+            .flatten()
+            .map(|agent| agent.name)
     }
 
-    /// Clear all pending messages for a session agent and mark them as failed
-    /// Called when an agent fails/dies to prevent messages from being stuck
-    async fn clear_pending_queue_on_failure(&self, _session_id: Uuid, session_agent_id: Uuid) {
-        // Remove and get all pending messages for this agent
-        let pending_messages = self.pending_messages.remove(&session_agent_id);
-
-        if let Some((_, messages)) = pending_messages {
-            for pending_msg in messages {
-                tracing::info!(
-                    session_agent_id = %session_agent_id,
-                    message_id = %pending_msg.message.id,
-                    agent_name = %pending_msg.agent_name,
-                    "marking queued message as failed due to agent failure"
-                );
-
-                // Update message meta to show failed status
-                self.update_mention_status(
-                    pending_msg.message.id,
-                    &pending_msg.agent_name,
-                    "failed",
-                )
-                .await;
-
-                // Emit failed event
-                self.emit(
-                    pending_msg.session_id,
-                    ChatStreamEvent::MentionAcknowledged {
-                        session_id: pending_msg.session_id,
-                        message_id: pending_msg.message.id,
-                        mentioned_agent: pending_msg.agent_name.clone(),
-                        agent_id: pending_msg.agent_id,
-                        status: MentionStatus::Failed,
-                    },
+    /// Split mentions into those naming a session member and those matching none.
+    /// Reserved handles are in neither list. Unknown mentions are still dispatched so
+    /// the unknown-mention policy can report them.
+    async fn partition_known_mentions(
+        &self,
+        session_id: Uuid,
+        mentions: &[String],
+    ) -> (Vec<String>, Vec<String>) {
+        if mentions.is_empty() {
+            return (Vec::new(), Vec::new());
+        }
+        let member_names: Vec<String> = match self.load_session_agent_names(session_id).await {
+            Ok(names) => names,
+            Err(err) => {
+                tracing::warn!(
+                    session_id = %session_id,
+                    error = %err,
+                    "failed to load session members for routing outcome"
                 );
+                return (mentions.to_vec(), Vec::new());
             }
-        }
+        };
+        let runner_config = Self::load_runner_config().await;
+        mentions
+            .iter()
+            .filter(|mention| !runner_config.is_reserved_handle(mention))
+            .cloned()
+            .partition(|mention| {
+                let mention = chat::normalize_mention_name(mention);
+                member_names
+                    .iter()
+                    .any(|name| name.eq_ignore_ascii_case(&mention))
+            })
     }
 
-    async fn resolve_session_agent_for_mention(
+    async fn load_session_agent_names(
         &self,
         session_id: Uuid,
-        mention: &str,
-    ) -> Result<Option<(ChatSessionAgent, ChatAgent)>, ChatRunnerError> {
+    ) -> Result<Vec<String>, ChatRunnerError> {
         let session_agents =
             ChatSessionAgent::find_all_for_session(&self.db.pool, session_id).await?;
-        if session_agents.is_empty() {
-            return Ok(None);
+        let agent_names: HashMap<Uuid, String> = ChatAgent::find_all(&self.db.pool)
+            .await?
+            .into_iter()
+            .map(|agent| (agent.id, chat::normalize_mention_name(&agent.name)))
+            .collect();
+        Ok(session_agents
+            .iter()
+            .filter_map(|session_agent| agent_names.get(&session_agent.agent_id).cloned())
+            .collect())
+    }
+
+    /// Store a message's routing outcome in its meta and announce the update.
+    async fn record_routing_outcome(
+        &self,
+        session_id: Uuid,
+        message_id: Uuid,
+        routing: &RoutingOutcome,
+    ) {
+        if routing.is_empty() {
+            return;
+        }
+        let patch = serde_json::json!({ ROUTING_META_KEY: routing });
+        if let Err(err) = ChatMessage::merge_meta(&self.db.pool, message_id, patch).await {
+            tracing::warn!(
+                message_id = %message_id,
+                error = %err,
+                "failed to store message routing outcome"
+            );
+            return;
+        }
+        if let Ok(Some(updated)) = ChatMessage::find_by_id(&self.db.pool, message_id).await {
+            self.emit_message_updated(session_id, updated);
         }
+    }
 
-        let agents = ChatAgent::find_all(&self.db.pool).await?;
-        let agent_map: HashMap<Uuid, ChatAgent> =
-            agents.into_iter().map(|agent| (agent.id, agent)).collect();
+    /// Re-posts `user_messages` into `session` one by one, waiting for every agent
+    /// (including forwarded chains) to settle before sending the next message.
+    pub fn spawn_session_replay(&self, session: ChatSession, user_messages: Vec<ChatMessage>) {
+        let runner = self.clone();
+        tokio::spawn(async move {
+            for source in user_messages {
+                let mut meta = serde_json::json!({
+                    "replayed_from": {
+                        "session_id": source.session_id,
+                        "message_id": source.id,
+                    }
+                });
+                if let Some(handle) = source.meta.0.get("sender_handle") {
+                    meta["sender_handle"] = handle.clone();
+                }
 
-        let mut exact_match: Option<(ChatSessionAgent, ChatAgent)> = None;
-        let mut ci_match: Option<(ChatSessionAgent, ChatAgent)> = None;
+                let message = match chat::create_message(
+                    &runner.db.pool,
+                    session.id,
+                    ChatSenderType::User,
+                    source.sender_id,
+                    source.content.clone(),
+                    Some(meta),
+                )
+                .await
+                {
+                    Ok(message) => message,
+                    Err(err) => {
+                        tracing::warn!(
+                            session_id = %session.id,
+                            source_message_id = %source.id,
+                            error = %err,
+                            "skipping message that could not be replayed"
+                        );
+                        continue;
+                    }
+                };
 
-        for session_agent in session_agents {
-            let Some(agent) = agent_map.get(&session_agent.agent_id) else {
-                tracing::warn!(
-                    session_agent_id = %session_agent.id,
-                    agent_id = %session_agent.agent_id,
-                    "chat session agent missing backing agent"
-                );
+                runner.handle_message(&session, &message).await;
+                if !runner.wait_for_session_settled(session.id).await {
+                    tracing::warn!(
+                        session_id = %session.id,
+                        message_id = %message.id,
+                        "session replay timed out waiting for agents; stopping replay"
+                    );
+                    break;
+                }
+            }
+        });
+    }
+
+    async fn wait_for_session_settled(&self, session_id: Uuid) -> bool {
+        let deadline = tokio::time::Instant::now() + REPLAY_MESSAGE_TIMEOUT;
+        let mut settled_polls = 0;
+
+        while tokio::time::Instant::now() < deadline {
+            tokio::time::sleep(REPLAY_POLL_INTERVAL).await;
+            let Ok(session_agents) =
+                ChatSessionAgent::find_all_for_session(&self.db.pool, session_id).await
+            else {
                 continue;
             };
 
-            if agent.name == mention {
-                exact_match = Some((session_agent, agent.clone()));
-                break;
-            }
-
-            if agent.name.eq_ignore_ascii_case(mention) {
-                if ci_match.is_some() {
-                    tracing::warn!(
-                        session_id = %session_id,
-                        mention = mention,
-                        "multiple session agents matched mention; skipping"
-                    );
-                    return Ok(None);
+            let busy = session_agents.iter().any(|session_agent| {
+                session_agent.state == ChatSessionAgentState::Running
+                    || self
+                        .pending_messages
+                        .get(&session_agent.id)
+                        .is_some_and(|queue| !queue.is_empty())
+            }) || self
+                .pending_forwards
+                .iter()
+                .any(|entry| entry.session_id == session_id);
+
+            if busy {
+                settled_polls = 0;
+            } else {
+                settled_polls += 1;
+                if settled_polls >= REPLAY_SETTLED_POLLS {
+                    return true;
                 }
-                ci_match = Some((session_agent, agent.clone()));
             }
         }
 
-        let Some((session_agent, agent)) = exact_match.or(ci_match) else {
-            return Ok(None);
-        };
+        false
+    }
 
-        if session_agent.workspace_path.is_none() {
-            let workspace_path = self.build_workspace_path(session_id, agent.id);
-            let updated = ChatSessionAgent::update_workspace_path(
-                &self.db.pool,
-                session_agent.id,
-                Some(workspace_path),
-            )
-            .await?;
-            return Ok(Some((updated, agent)));
+    /// Runs each mention in its own task; reserved handles are skipped by
+    /// `run_agent_for_mention`, which has the configured reserved set.
+    ///
+    /// At most `mention_fanout_concurrency` runs started by the message are in flight at
+    /// once; a slot is held until its run finishes. Slots are taken in mention order, so
+    /// runs start and are acknowledged in that order.
+    fn dispatch_mentions(&self, session_id: Uuid, message: &ChatMessage, mentions: Vec<String>) {
+        if mentions.is_empty() {
+            return;
         }
-
-        Ok(Some((session_agent, agent)))
+        let runner = self.clone();
+        let message = message.clone();
+        tokio::spawn(async move {
+            let limit = Self::load_runner_config()
+                .await
+                .mention_fanout_concurrency();
+            let semaphore = limit.map(|limit| Arc::new(Semaphore::new(limit)));
+            for mention in mentions {
+                let permit = match &semaphore {
+                    Some(semaphore) => match semaphore.clone().acquire_owned().await {
+                        Ok(permit) => Some(permit),
+                        Err(_) => break,
+                    },
+                    None => None,
+                };
+                let runner = runner.clone();
+                let message = message.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = runner
+                        .run_agent_for_mention(session_id, &mention, &message, permit)
+                        .await
+                    {
+                        tracing::warn!(
+                            error = %err,
+                            mention = mention,
+                            session_id = %session_id,
+                            "chat runner failed for mention"
+                        );
+                    }
+                });
+            }
+        });
     }
 
-    async fn run_agent_for_mention(
+    /// Drops directives from an agent to members outside its `allowed_routing_targets`,
+    /// posting a failure for each so the blocked route is visible in the session.
+    async fn enforce_routing_allowlist(
         &self,
         session_id: Uuid,
-        mention: &str,
-        source_message: &ChatMessage,
-    ) -> Result<(), ChatRunnerError> {
-        if source_message.sender_type == ChatSenderType::Agent
-            && mention.eq_ignore_ascii_case(RESERVED_USER_HANDLE)
-        {
-            tracing::debug!(
-                session_id = %session_id,
-                message_id = %source_message.id,
-                mention = mention,
-                "skipping reserved user mention in agent message"
-            );
-            return Ok(());
-        }
-
-        let resolved = self
-            .resolve_session_agent_for_mention(session_id, mention)
-            .await;
-        let Some((session_agent, agent)) = (match resolved {
-            Ok(value) => value,
+        message: &ChatMessage,
+        mentions: Vec<String>,
+    ) -> Vec<String> {
+        let Some(sender_id) = message.sender_id else {
+            return mentions;
+        };
+        let sender = match ChatAgent::find_by_id(&self.db.pool, sender_id).await {
+            Ok(Some(agent)) => agent,
+            Ok(None) => return mentions,
             Err(err) => {
-                self.report_mention_failure(
-                    session_id,
-                    source_message.id,
-                    mention,
-                    None,
-                    format!("Failed to resolve mentioned agent: {err}"),
-                )
-                .await;
-                return Err(err);
-            }
-        }) else {
-            if let Some(agent) = ChatAgent::find_by_name(&self.db.pool, mention).await? {
-                tracing::debug!(
+                tracing::warn!(
                     session_id = %session_id,
-                    agent_id = %agent.id,
-                    mention = mention,
-                    "chat session agent not configured; marking mention as failed"
+                    agent_id = %sender_id,
+                    error = %err,
+                    "failed to load sender agent; routing without allowlist"
                 );
-                self.report_mention_failure(
-                    session_id,
-                    source_message.id,
-                    &agent.name,
-                    Some(agent.id),
-                    "Agent is not configured in this session.",
-                )
-                .await;
-                return Err(ChatRunnerError::AgentNotFound(mention.to_string()));
+                return mentions;
             }
+        };
+        let Some(allowed) = Self::allowed_routing_targets(&sender.tools_enabled.0) else {
+            return mentions;
+        };
+
+        let runner_config = Self::load_runner_config().await;
+        let (permitted, blocked): (Vec<String>, Vec<String>) =
+            mentions.into_iter().partition(|mention| {
+                runner_config.is_reserved_handle(mention)
+                    || Self::is_routing_target_allowed(&allowed, mention)
+            });
+        for target in blocked {
+            tracing::warn!(
+                session_id = %session_id,
+                message_id = %message.id,
+                agent = %sender.name,
+                target = %target,
+                "dropping directive to target outside routing allowlist"
+            );
             self.report_mention_failure(
                 session_id,
-                source_message.id,
-                mention,
+                message.id,
+                &target,
                 None,
-                "Mentioned agent was not found.",
+                format!(
+                    "{} is not allowed to route to \"{}\" (allowed targets: {}).",
+                    sender.name,
+                    target,
+                    allowed.join(", ")
+                ),
             )
             .await;
-            return Err(ChatRunnerError::AgentNotFound(mention.to_string()));
-        };
-
-        if source_message.sender_type == ChatSenderType::Agent
-            && let Some(sender_id) = source_message.sender_id
-            && sender_id == agent.id
-        {
-            tracing::debug!(
-                agent_id = %sender_id,
-                mention = mention,
-                "skipping self-mention by agent"
-            );
-            return Ok(());
         }
+        permitted
+    }
 
-        if session_agent.state == ChatSessionAgentState::Running {
-            // Queue the message for later processing instead of skipping
-            tracing::debug!(
-                session_agent_id = %session_agent.id,
-                agent_id = %agent.id,
-                message_id = %source_message.id,
-                "chat session agent already running; queueing message for later"
-            );
+    /// Routing allowlist from an agent's `tools_enabled`; `None` (missing, null or
+    /// empty) means the agent may route to any member.
+    fn allowed_routing_targets(tools_enabled: &serde_json::Value) -> Option<Vec<String>> {
+        let targets: Vec<String> = tools_enabled
+            .get(ALLOWED_ROUTING_TARGETS_KEY)?
+            .as_array()?
+            .iter()
+            .filter_map(serde_json::Value::as_str)
+            .map(|target| chat::normalize_mention_name(target.trim_start_matches('@')))
+            .filter(|target| !target.is_empty())
+            .collect();
+        (!targets.is_empty()).then_some(targets)
+    }
 
-            let pending = PendingMessage {
-                session_id,
-                agent_id: agent.id,
-                agent_name: agent.name.clone(),
-                message: source_message.clone(),
-            };
-
-            self.pending_messages
-                .entry(session_agent.id)
-                .or_default()
-                .push_back(pending);
-
-            // Emit a "received" status to indicate the message is queued
-            self.emit(
-                session_id,
-                ChatStreamEvent::MentionAcknowledged {
-                    session_id,
-                    message_id: source_message.id,
-                    mentioned_agent: agent.name.clone(),
-                    agent_id: agent.id,
-                    status: MentionStatus::Received,
-                },
-            );
-
-            // Persist received status to message meta
-            self.update_mention_status(source_message.id, &agent.name, "received")
-                .await;
+    fn is_routing_target_allowed(allowed: &[String], mention: &str) -> bool {
+        let mention = chat::normalize_mention_name(mention);
+        allowed
+            .iter()
+            .any(|target| target.eq_ignore_ascii_case(&mention))
+    }
 
-            return Ok(());
+    /// When the session has a moderator, agent-to-agent forwards are held and the
+    /// moderator is asked to approve them. Returns the mentions that may run immediately.
+    async fn hold_forwards_for_moderator(
+        &self,
+        session_id: Uuid,
+        message: &ChatMessage,
+        mentions: Vec<String>,
+    ) -> Vec<String> {
+        let moderator_id =
+            match ChatSession::find_moderator_agent_id(&self.db.pool, session_id).await {
+                Ok(Some(moderator_id)) => moderator_id,
+                Ok(None) => return mentions,
+                Err(err) => {
+                    tracing::warn!(
+                        session_id = %session_id,
+                        error = %err,
+                        "failed to load session moderator; forwarding without moderation"
+                    );
+                    return mentions;
+                }
+            };
+        if message.sender_id == Some(moderator_id) {
+            return mentions;
         }
 
-        let session_agent = if session_agent.state != ChatSessionAgentState::Running {
-            ChatSessionAgent::update_state(
-                &self.db.pool,
-                session_agent.id,
-                ChatSessionAgentState::Running,
-            )
-            .await?
-        } else {
-            session_agent
+        let moderator = match ChatAgent::find_by_id(&self.db.pool, moderator_id).await {
+            Ok(Some(agent)) => agent,
+            Ok(None) => return mentions,
+            Err(err) => {
+                tracing::warn!(
+                    session_id = %session_id,
+                    moderator_agent_id = %moderator_id,
+                    error = %err,
+                    "failed to load moderator agent; forwarding without moderation"
+                );
+                return mentions;
+            }
         };
+        let moderator_name = chat::normalize_mention_name(&moderator.name);
+        let runner_config = Self::load_runner_config().await;
 
-        self.emit(
-            session_id,
-            ChatStreamEvent::AgentState {
-                session_agent_id: session_agent.id,
-                agent_id: agent.id,
-                state: ChatSessionAgentState::Running,
-                started_at: Some(session_agent.updated_at),
-            },
-        );
+        let (immediate, held): (Vec<String>, Vec<String>) =
+            mentions.into_iter().partition(|mention| {
+                runner_config.is_reserved_handle(mention)
+                    || chat::normalize_mention_name(mention).eq_ignore_ascii_case(&moderator_name)
+            });
+        if held.is_empty() {
+            return immediate;
+        }
 
-        // Emit MentionAcknowledged running event
-        self.emit(
-            session_id,
-            ChatStreamEvent::MentionAcknowledged {
+        let forward_id = Uuid::new_v4();
+        self.pending_forwards.insert(
+            forward_id,
+            PendingForward {
                 session_id,
-                message_id: source_message.id,
-                mentioned_agent: agent.name.clone(),
-                agent_id: agent.id,
-                status: MentionStatus::Running,
+                source_message: message.clone(),
+                targets: held.clone(),
             },
         );
 
-        // Persist running status to message meta
-        self.update_mention_status(source_message.id, &agent.name, "running")
-            .await;
+        let review_content = format!(
+            "Forward held for moderator review: message {} is addressed to {}. Moderator \"{}\", reply with {}{}] to deliver it or {}{}] to drop it.",
+            message.id,
+            held.join(", "),
+            moderator.name,
+            APPROVE_FORWARD_MARKER,
+            forward_id,
+            REJECT_FORWARD_MARKER,
+            forward_id,
+        );
+        let review_meta = serde_json::json!({
+            "forward_review": {
+                "forward_id": forward_id,
+                "source_message_id": message.id,
+                "targets": held,
+                "moderator_agent_id": moderator_id,
+            },
+            "chain_depth": self.extract_chain_depth(&message.meta),
+        });
 
-        let session_agent_id = session_agent.id;
-        let agent_id = agent.id;
+        match self
+            .post_system_message(
+                session_id,
+                SystemMessageCategory::ForwardReview,
+                review_content,
+                review_meta,
+            )
+            .await
+        {
+            Ok(Some(review_message)) => {
+                self.dispatch_mentions(session_id, &review_message, vec![moderator.name]);
+            }
+            Ok(None) => {}
+            Err(err) => {
+                tracing::warn!(
+                    session_id = %session_id,
+                    forward_id = %forward_id,
+                    error = %err,
+                    "failed to create moderator review message"
+                );
+            }
+        }
 
-        let reply_handle = self.resolve_reply_handle(source_message);
-        let chain_depth = self.extract_chain_depth(&source_message.meta);
+        self.spawn_forward_timeout(forward_id);
+        immediate
+    }
 
-        let result = async {
-            let workspace_path = session_agent
-                .workspace_path
-                .clone()
-                .unwrap_or_else(|| self.build_workspace_path(session_id, agent_id));
-            fs::create_dir_all(&workspace_path).await?;
-            let run_records_dir = Self::workspace_run_records_dir(
-                PathBuf::from(&workspace_path).as_path(),
-                session_id,
-            );
-            fs::create_dir_all(&run_records_dir).await?;
-            tracing::info!(
-                session_id = %session_id,
-                workspace_path = %workspace_path,
-                runs_dir = %run_records_dir.display(),
-                "Using workspace runs directory"
-            );
+    /// Applies approve/reject markers, but only when they come from the session's moderator.
+    async fn apply_moderator_decisions(&self, session_id: Uuid, message: &ChatMessage) {
+        let decisions = Self::parse_forward_decisions(&message.content);
+        if decisions.is_empty() {
+            return;
+        }
 
-            let run_index = ChatRun::next_run_index(&self.db.pool, session_agent_id).await?;
-            let run_id = Uuid::new_v4();
-            let run_dir =
-                run_records_dir.join(Self::run_records_prefix(session_agent_id, run_index));
-            fs::create_dir_all(&run_dir).await?;
+        let moderator_id =
+            match ChatSession::find_moderator_agent_id(&self.db.pool, session_id).await {
+                Ok(moderator_id) => moderator_id,
+                Err(err) => {
+                    tracing::warn!(
+                        session_id = %session_id,
+                        error = %err,
+                        "failed to load session moderator; ignoring forward decisions"
+                    );
+                    return;
+                }
+            };
+        if moderator_id.is_none() || message.sender_id != moderator_id {
+            return;
+        }
 
-            let input_path = run_dir.join("input.md");
-            let output_path = run_dir.join("output.md");
-            let raw_log_path = run_dir.join("raw.log");
-            let meta_path = run_dir.join("meta.json");
+        for (forward_id, approved) in decisions {
+            let Some((_, pending)) = self
+                .pending_forwards
+                .remove_if(&forward_id, |_, pending| pending.session_id == session_id)
+            else {
+                continue;
+            };
 
-            let context_snapshot = self
-                .build_context_snapshot(session_id, &workspace_path, &run_dir)
-                .await?;
-            if let Some(warning) = context_snapshot.compression_warning.clone() {
-                self.emit(
-                    session_id,
-                    ChatStreamEvent::CompressionWarning {
-                        session_id,
-                        warning: warning.into(),
-                    },
-                );
+            if approved {
+                self.emit_forward_outcome(forward_id, &pending, "approved")
+                    .await;
+                self.dispatch_mentions(session_id, &pending.source_message, pending.targets);
+            } else {
+                self.emit_forward_outcome(forward_id, &pending, "rejected")
+                    .await;
             }
-            let context_dir = context_snapshot
-                .workspace_path
-                .parent()
-                .map(|path| path.to_path_buf())
-                .unwrap_or_else(|| PathBuf::from(&workspace_path));
-            let reference_context = self
-                .build_reference_context(session_id, source_message, &context_dir)
-                .await?;
-            let message_attachments = self
-                .build_message_attachment_context(source_message, &context_dir)
-                .await?;
-            let session_agents = self.build_session_agent_summaries(session_id).await?;
-            let prompt = self.build_prompt(
-                &agent,
-                source_message,
-                &context_snapshot.workspace_path,
-                &session_agents,
-                message_attachments.as_ref(),
-                reference_context.as_ref(),
-            );
-            fs::write(&input_path, &prompt).await?;
+        }
+    }
 
-            let _run = ChatRun::create(
-                &self.db.pool,
-                &CreateChatRun {
-                    session_id,
-                    session_agent_id,
-                    run_index,
-                    run_dir: run_dir.to_string_lossy().to_string(),
-                    input_path: Some(input_path.to_string_lossy().to_string()),
-                    output_path: Some(output_path.to_string_lossy().to_string()),
-                    raw_log_path: Some(raw_log_path.to_string_lossy().to_string()),
-                    meta_path: Some(meta_path.to_string_lossy().to_string()),
-                },
-                run_id,
-            )
-            .await?;
+    fn spawn_forward_timeout(&self, forward_id: Uuid) {
+        let runner = self.clone();
+        tokio::spawn(async move {
+            let config = Self::load_runner_config().await;
+            tokio::time::sleep(std::time::Duration::from_secs(u64::from(
+                config.moderator_timeout_secs,
+            )))
+            .await;
 
-            let executor_profile_id = self.parse_executor_profile_id(&agent)?;
-            let mut executor =
-                ExecutorConfigs::get_cached().get_coding_agent_or_default(&executor_profile_id);
-            executor.use_approvals(Arc::new(NoopExecutorApprovalService));
+            let Some((_, pending)) = runner.pending_forwards.remove(&forward_id) else {
+                return;
+            };
+            match config.moderator_timeout_action {
+                ModeratorTimeoutAction::Proceed => {
+                    runner
+                        .emit_forward_outcome(forward_id, &pending, "timeout_proceeded")
+                        .await;
+                    runner.dispatch_mentions(
+                        pending.session_id,
+                        &pending.source_message,
+                        pending.targets,
+                    );
+                }
+                ModeratorTimeoutAction::Drop => {
+                    runner
+                        .emit_forward_outcome(forward_id, &pending, "timeout_dropped")
+                        .await;
+                }
+            }
+        });
+    }
 
-            let repo_context = RepoContext::new(PathBuf::from(&workspace_path), Vec::new());
-            let mut env = ExecutionEnv::new(repo_context, false, String::new());
-            env.insert("VK_CHAT_SESSION_ID", session_id.to_string());
-            env.insert("VK_CHAT_AGENT_ID", agent_id.to_string());
-            env.insert("VK_CHAT_SESSION_AGENT_ID", session_agent_id.to_string());
-            env.insert("VK_CHAT_RUN_ID", run_id.to_string());
-            env.insert(
-                "VK_CHAT_CONTEXT_PATH",
-                context_snapshot
-                    .workspace_path
-                    .to_string_lossy()
-                    .to_string(),
-            );
-            env.insert(
-                "VK_CHAT_CONTEXT_RUN_PATH",
-                context_snapshot.run_path.to_string_lossy().to_string(),
+    async fn emit_forward_outcome(
+        &self,
+        forward_id: Uuid,
+        pending: &PendingForward,
+        outcome: &str,
+    ) {
+        let description = match outcome {
+            "approved" => "approved by the moderator",
+            "rejected" => "rejected by the moderator",
+            "timeout_proceeded" => "delivered after the moderator did not respond in time",
+            _ => "dropped after the moderator did not respond in time",
+        };
+        let content = format!(
+            "Forward of message {} to {} was {}.",
+            pending.source_message.id,
+            pending.targets.join(", "),
+            description
+        );
+        let meta = serde_json::json!({
+            "forward_review_outcome": {
+                "forward_id": forward_id,
+                "source_message_id": pending.source_message.id,
+                "targets": pending.targets,
+                "outcome": outcome,
+            }
+        });
+
+        if let Err(err) = self
+            .post_system_message(
+                pending.session_id,
+                SystemMessageCategory::ForwardOutcome,
+                content,
+                meta,
+            )
+            .await
+        {
+            tracing::warn!(
+                session_id = %pending.session_id,
+                forward_id = %forward_id,
+                error = %err,
+                "failed to emit forward outcome message"
             );
+        }
+    }
 
-            let mut spawned = if session_agent.state != ChatSessionAgentState::Dead {
-                if let Some(agent_session_id) = session_agent.agent_session_id.as_deref() {
-                    executor
-                        .spawn_follow_up(
-                            PathBuf::from(&workspace_path).as_path(),
-                            &prompt,
-                            agent_session_id,
-                            session_agent.agent_message_id.as_deref(),
-                            &env,
-                        )
-                        .await?
-                } else {
-                    executor
-                        .spawn(PathBuf::from(&workspace_path).as_path(), &prompt, &env)
-                        .await?
+    fn parse_forward_decisions(content: &str) -> Vec<(Uuid, bool)> {
+        let mut decisions = Vec::new();
+        for (marker, approved) in [
+            (APPROVE_FORWARD_MARKER, true),
+            (REJECT_FORWARD_MARKER, false),
+        ] {
+            let mut rest = content;
+            while let Some(start) = rest.find(marker) {
+                rest = &rest[start + marker.len()..];
+                let Some(end) = rest.find(']') else {
+                    break;
+                };
+                if let Ok(forward_id) = Uuid::parse_str(rest[..end].trim()) {
+                    decisions.push((forward_id, approved));
                 }
-            } else {
-                executor
-                    .spawn(PathBuf::from(&workspace_path).as_path(), &prompt, &env)
-                    .await?
-            };
-
-            let msg_store = Arc::new(MsgStore::new());
-            let raw_log_file = Arc::new(Mutex::new(fs::File::create(&raw_log_path).await?));
-
-            self.spawn_log_forwarders(&mut spawned.child, msg_store.clone(), raw_log_file);
-            executor.normalize_logs(msg_store.clone(), PathBuf::from(&workspace_path).as_path());
+                rest = &rest[end + 1..];
+            }
+        }
+        decisions
+    }
 
-            let failed_flag = Arc::new(AtomicBool::new(false));
+    fn extract_chain_depth(&self, meta: &sqlx::types::Json<serde_json::Value>) -> u32 {
+        meta.get("chain_depth")
+            .and_then(|v| v.as_u64())
+            .map(|v| v as u32)
+            .unwrap_or(0)
+    }
 
-            self.spawn_stream_bridge(
-                msg_store.clone(),
-                session_id,
-                agent_id,
-                session_agent_id,
-                run_id,
-                output_path,
-                meta_path,
-                PathBuf::from(&workspace_path),
-                run_dir,
-                Some(reply_handle),
-                failed_flag.clone(),
-                chain_depth,
-                context_snapshot.context_compacted,
-                context_snapshot.compression_warning.clone(),
-                self.clone(),
-                source_message.id,
-                agent.name.clone(),
-            );
+    fn emit(&self, session_id: Uuid, event: ChatStreamEvent) {
+        let sender = self.sender_for(session_id);
+        let _ = sender.send(event);
+    }
 
-            self.spawn_exit_watcher(
-                spawned.child,
-                spawned.cancel,
-                spawned.exit_signal,
-                msg_store,
-                failed_flag,
-                session_agent_id,
-            );
+    fn sender_for(&self, session_id: Uuid) -> broadcast::Sender<ChatStreamEvent> {
+        self.stream_for(session_id).sender
+    }
 
-            Ok::<(), ChatRunnerError>(())
+    fn stream_for(&self, session_id: Uuid) -> SessionStream {
+        if let Some(entry) = self.streams.get(&session_id) {
+            return entry.clone();
         }
-        .await;
 
-        if result.is_err() {
-            if let Err(err) = &result {
-                self.report_mention_failure(
-                    session_id,
-                    source_message.id,
-                    &agent.name,
-                    Some(agent_id),
-                    format!("Failed to start agent run: {err}"),
-                )
-                .await;
-            }
-            let _ = ChatSessionAgent::update_state(
-                &self.db.pool,
-                session_agent_id,
-                ChatSessionAgentState::Dead,
-            )
-            .await;
-            self.emit(
-                session_id,
-                ChatStreamEvent::AgentState {
-                    session_agent_id,
-                    agent_id,
-                    state: ChatSessionAgentState::Dead,
-                    started_at: None,
-                },
-            );
-        }
-
-        result
+        let capacity = self.stream_channel_capacity.load(Ordering::Relaxed);
+        let (sender, _) = broadcast::channel(capacity);
+        let stream = SessionStream {
+            sender,
+            capacity,
+            lagged_events: Arc::new(AtomicU64::new(0)),
+        };
+        self.streams.insert(session_id, stream.clone());
+        stream
     }
 
-    fn build_workspace_path(&self, session_id: Uuid, agent_id: Uuid) -> String {
-        asset_dir()
-            .join("chat")
-            .join(format!("session_{session_id}"))
-            .join("agents")
-            .join(agent_id.to_string())
-            .to_string_lossy()
-            .to_string()
+    fn dispatch_lock(&self, session_agent_id: Uuid) -> Arc<Mutex<()>> {
+        self.dispatch_locks
+            .entry(session_agent_id)
+            .or_default()
+            .clone()
     }
 
-    fn workspace_runs_dir(workspace_path: &Path, session_id: Uuid) -> PathBuf {
-        workspace_path
-            .join(AGENTS_CHATGROUP_WORKSPACE_DIR)
-            .join(RUNS_DIR_NAME)
-            .join(session_id.to_string())
-    }
+    /// Claim an idle, unmuted session agent for a new run (returning it marked `Running`),
+    /// or queue `pending` behind the current run. Decided under the agent's dispatch lock
+    /// against its latest state, so a mention arriving while a run finishes is either
+    /// queued before [`Self::finish_run`] or sees the agent idle.
+    ///
+    /// While the agent has queued messages only the head of its queue may claim it, so a
+    /// mention arriving between [`Self::finish_run`] and the drain queues behind them.
+    /// The head leaves the queue once its run claims the agent.
+    ///
+    /// A claimed run keeps `fanout_permit` until it finishes; a queued message drops it.
+    async fn claim_or_queue(
+        &self,
+        session_agent_id: Uuid,
+        pending: PendingMessage,
+        fanout_permit: Option<OwnedSemaphorePermit>,
+    ) -> Result<Option<ChatSessionAgent>, ChatRunnerError> {
+        let lock = self.dispatch_lock(session_agent_id);
+        let _guard = lock.lock().await;
+        let session_agent = ChatSessionAgent::find_by_id(&self.db.pool, session_agent_id)
+            .await?
+            .ok_or_else(|| ChatRunnerError::AgentNotFound(pending.agent_name.clone()))?;
 
-    fn workspace_run_records_dir(workspace_path: &Path, session_id: Uuid) -> PathBuf {
-        Self::workspace_runs_dir(workspace_path, session_id).join(RUN_RECORDS_DIR_NAME)
-    }
+        let busy = matches!(
+            session_agent.state,
+            ChatSessionAgentState::Running | ChatSessionAgentState::WaitingApproval
+        );
+        let queue_head = self
+            .pending_messages
+            .get(&session_agent_id)
+            .and_then(|queue| queue.front().map(|head| head.message.id));
+        let dequeuing = queue_head == Some(pending.message.id);
+        let queued_ahead = queue_head.is_some() && !dequeuing;
+        if busy || session_agent.muted || queued_ahead {
+            tracing::debug!(
+                session_agent_id = %session_agent_id,
+                agent_id = %pending.agent_id,
+                message_id = %pending.message.id,
+                muted = session_agent.muted,
+                queued_ahead,
+                "chat session agent running, muted or has queued messages; queueing message for later"
+            );
+            if !dequeuing {
+                PendingMessage::enqueue(
+                    &mut self.pending_messages.entry(session_agent_id).or_default(),
+                    pending,
+                );
+            }
+            return Ok(None);
+        }
+        if dequeuing && let Some(mut queue) = self.pending_messages.get_mut(&session_agent_id) {
+            queue.pop_front();
+        }
 
-    fn run_records_prefix(session_agent_id: Uuid, run_index: i64) -> String {
-        format!("session_agent_{session_agent_id}_run_{run_index:04}")
+        let session_agent = ChatSessionAgent::update_state(
+            &self.db.pool,
+            session_agent_id,
+            ChatSessionAgentState::Running,
+        )
+        .await?;
+        // `updated_at` moves again on approval waits; the claim time marks the run's start.
+        self.run_starts
+            .insert(session_agent_id, session_agent.updated_at);
+        if let Some(permit) = fanout_permit {
+            self.fanout_permits.insert(session_agent_id, permit);
+        }
+        Ok(Some(session_agent))
     }
 
-    fn parse_runner_type(&self, agent: &ChatAgent) -> Result<BaseCodingAgent, ChatRunnerError> {
-        let raw = agent.runner_type.trim();
-        let normalized = raw.replace(['-', ' '], "_").to_ascii_uppercase();
-        BaseCodingAgent::from_str(&normalized)
-            .map_err(|_| ChatRunnerError::UnknownRunnerType(raw.to_string()))
+    /// Move a session agent out of `Running` once its run has finished. Mentions queued
+    /// before this are drained by [`Self::process_pending_queue`]; later ones start a run.
+    async fn finish_run(
+        &self,
+        session_agent_id: Uuid,
+        final_state: ChatSessionAgentState,
+    ) -> Result<ChatSessionAgent, sqlx::Error> {
+        let lock = self.dispatch_lock(session_agent_id);
+        let _guard = lock.lock().await;
+        self.fanout_permits.remove(&session_agent_id);
+        ChatSessionAgent::update_state(&self.db.pool, session_agent_id, final_state).await
     }
 
-    fn parse_executor_profile_id(
+    /// Hand back a claim whose run never started and move on to the next queued message.
+    async fn release_unstarted_run(
         &self,
-        agent: &ChatAgent,
-    ) -> Result<ExecutorProfileId, ChatRunnerError> {
-        let executor = self.parse_runner_type(agent)?;
-        let variant = Self::extract_executor_profile_variant(&agent.tools_enabled.0);
-        Ok(match variant {
-            Some(variant) => ExecutorProfileId::with_variant(executor, variant),
-            None => ExecutorProfileId::new(executor),
-        })
+        session_id: Uuid,
+        session_agent_id: Uuid,
+        agent_id: Uuid,
+    ) -> Result<(), ChatRunnerError> {
+        self.finish_run(session_agent_id, ChatSessionAgentState::Idle)
+            .await?;
+        self.emit(
+            session_id,
+            ChatStreamEvent::AgentState {
+                session_agent_id,
+                agent_id,
+                state: ChatSessionAgentState::Idle,
+                started_at: None,
+            },
+        );
+        Box::pin(self.process_pending_queue(session_id, session_agent_id)).await;
+        Ok(())
     }
 
-    fn extract_executor_profile_variant(tools_enabled: &serde_json::Value) -> Option<String> {
-        let variant = tools_enabled
-            .as_object()
-            .and_then(|value| value.get(EXECUTOR_PROFILE_VARIANT_KEY))
-            .and_then(serde_json::Value::as_str)?
-            .trim();
-        if variant.is_empty() || variant.eq_ignore_ascii_case("DEFAULT") {
-            return None;
+    /// Process the next pending message for a session agent after it becomes idle
+    async fn process_pending_queue(&self, session_id: Uuid, session_agent_id: Uuid) {
+        // A muted agent keeps its queue until it is unmuted
+        if let Ok(Some(session_agent)) =
+            ChatSessionAgent::find_by_id(&self.db.pool, session_agent_id).await
+            && session_agent.muted
+        {
+            return;
         }
-        Some(canonical_variant_key(variant))
-    }
 
-    fn resolve_reply_handle(&self, message: &ChatMessage) -> String {
-        let handle = message
-            .meta
-            .0
-            .get("sender_handle")
-            .and_then(|value| value.as_str())
-            .unwrap_or("you");
-        let sanitized = handle
-            .chars()
-            .filter(|c| c.is_ascii_alphanumeric() || *c == '_' || *c == '-')
-            .collect::<String>();
-        if sanitized.is_empty() {
-            "you".to_string()
+        // Peek at the next pending message; it stays queued until its run claims the agent
+        let pending = self
+            .pending_messages
+            .get(&session_agent_id)
+            .and_then(|queue| queue.front().cloned());
+
+        if let Some(pending_msg) = pending {
+            tracing::info!(
+                session_agent_id = %session_agent_id,
+                message_id = %pending_msg.message.id,
+                agent_name = %pending_msg.agent_name,
+                "processing queued message for agent"
+            );
+
+            // Process the queued message by calling run_agent_for_mention
+            // Use the stored agent_name to find the agent (handles rename gracefully)
+            if let Err(err) = self
+                .run_agent_for_mention(
+                    pending_msg.session_id,
+                    &pending_msg.agent_name,
+                    &pending_msg.message,
+                    None,
+                )
+                .await
+            {
+                tracing::warn!(
+                    error = %err,
+                    agent_name = %pending_msg.agent_name,
+                    session_agent_id = %session_agent_id,
+                    "failed to process queued message"
+                );
+            }
+            // Continue processing the rest of the queue if the message never claimed the agent
+            if self
+                .drop_unclaimed_queue_head(session_agent_id, pending_msg.message.id)
+                .await
+            {
+                Box::pin(self.process_pending_queue(session_id, session_agent_id)).await;
+            }
         } else {
-            sanitized
+            // Clean up empty queue entry, unless a mention was queued in the meantime
+            self.pending_messages
+                .remove_if(&session_agent_id, |_, queue| queue.is_empty());
         }
     }
 
-    fn apply_reply_prefix(content: &str, handle: Option<&str>) -> String {
-        let _ = handle;
-        content.to_string()
+    /// Remove `message_id` from the head of the agent's queue if it failed or was skipped
+    /// before claiming the agent. Kept while the agent is busy or muted, since the next
+    /// drain retries it. Returns whether the message was removed.
+    async fn drop_unclaimed_queue_head(&self, session_agent_id: Uuid, message_id: Uuid) -> bool {
+        let lock = self.dispatch_lock(session_agent_id);
+        let _guard = lock.lock().await;
+        if let Ok(Some(session_agent)) =
+            ChatSessionAgent::find_by_id(&self.db.pool, session_agent_id).await
+            && (session_agent.muted
+                || matches!(
+                    session_agent.state,
+                    ChatSessionAgentState::Running | ChatSessionAgentState::WaitingApproval
+                ))
+        {
+            return false;
+        }
+        let Some(mut queue) = self.pending_messages.get_mut(&session_agent_id) else {
+            return false;
+        };
+        if queue
+            .front()
+            .is_none_or(|head| head.message.id != message_id)
+        {
+            return false;
+        }
+        queue.pop_front();
+        true
     }
 
-    async fn capture_git_diff(workspace_path: &Path, run_dir: &Path) -> Option<DiffInfo> {
-        let check = Command::new("git")
-            .arg("-C")
-            .arg(workspace_path)
-            .args(["rev-parse", "--is-inside-work-tree"])
-            .output()
-            .await
-            .ok()?;
+    /// Clear all pending messages for a session agent and mark them as failed
+    /// Called when an agent fails/dies to prevent messages from being stuck
+    async fn clear_pending_queue_on_failure(&self, _session_id: Uuid, session_agent_id: Uuid) {
+        // Remove and get all pending messages for this agent
+        let pending_messages = self.pending_messages.remove(&session_agent_id);
 
-        if !check.status.success() {
-            return None;
-        }
+        if let Some((_, messages)) = pending_messages {
+            for pending_msg in messages {
+                tracing::info!(
+                    session_agent_id = %session_agent_id,
+                    message_id = %pending_msg.message.id,
+                    agent_name = %pending_msg.agent_name,
+                    "marking queued message as failed due to agent failure"
+                );
 
-        let status = Command::new("git")
-            .arg("-C")
-            .arg(workspace_path)
-            .args(["status", "--porcelain"])
-            .output()
-            .await
-            .ok()?;
+                // Update message meta to show failed status
+                self.update_mention_status(
+                    pending_msg.message.id,
+                    &pending_msg.agent_name,
+                    "failed",
+                )
+                .await;
 
-        if !status.status.success() {
-            return None;
+                // Emit failed event
+                self.emit(
+                    pending_msg.session_id,
+                    ChatStreamEvent::MentionAcknowledged {
+                        session_id: pending_msg.session_id,
+                        message_id: pending_msg.message.id,
+                        mentioned_agent: pending_msg.agent_name.clone(),
+                        agent_id: pending_msg.agent_id,
+                        status: MentionStatus::Failed,
+                    },
+                );
+            }
         }
+    }
 
-        let status_text = String::from_utf8_lossy(&status.stdout);
-        let has_tracked_changes = status_text.lines().any(|line| {
-            let trimmed = line.trim();
-            !trimmed.is_empty() && !trimmed.starts_with("??")
-        });
-
-        if !has_tracked_changes {
-            return None;
+    async fn resolve_session_agent_for_mention(
+        &self,
+        session_id: Uuid,
+        mention: &str,
+    ) -> Result<Option<(ChatSessionAgent, ChatAgent)>, ChatRunnerError> {
+        let session_agents =
+            ChatSessionAgent::find_all_for_session(&self.db.pool, session_id).await?;
+        if session_agents.is_empty() {
+            return Ok(None);
         }
 
-        let output = Command::new("git")
-            .arg("-C")
-            .arg(workspace_path)
-            .args(["diff", "--no-color"])
-            .output()
+        let agents = ChatAgent::find_all(&self.db.pool).await?;
+        let agent_map: HashMap<Uuid, ChatAgent> =
+            agents.into_iter().map(|agent| (agent.id, agent)).collect();
+        let mention = chat::normalize_mention_name(mention);
+        if Self::load_runner_config()
             .await
-            .ok()?;
-
-        if !output.status.success() {
-            return None;
-        }
-
-        let diff = String::from_utf8_lossy(&output.stdout).to_string();
-        if diff.trim().is_empty() {
-            return None;
+            .is_reserved_handle(&mention)
+        {
+            return Ok(None);
         }
 
-        let diff_path = run_dir.join("diff.patch");
-        if let Err(err) = fs::write(&diff_path, &diff).await {
-            tracing::warn!("Failed to write diff patch: {}", err);
-            return None;
-        }
+        let mut exact_match: Option<(ChatSessionAgent, ChatAgent)> = None;
+        let mut ci_match: Option<(ChatSessionAgent, ChatAgent)> = None;
 
-        // Consider diff truncated if it's over 4KB (for UI display purposes)
-        let truncated = diff.len() > 4000;
+        for session_agent in session_agents {
+            let Some(agent) = agent_map.get(&session_agent.agent_id) else {
+                tracing::warn!(
+                    session_agent_id = %session_agent.id,
+                    agent_id = %session_agent.agent_id,
+                    "chat session agent missing backing agent"
+                );
+                continue;
+            };
 
-        Some(DiffInfo { truncated })
-    }
+            let agent_name = chat::normalize_mention_name(&agent.name);
+            if agent_name == mention {
+                exact_match = Some((session_agent, agent.clone()));
+                break;
+            }
 
-    async fn capture_untracked_files(workspace_path: &Path, run_dir: &Path) -> Vec<String> {
-        let output = Command::new("git")
-            .arg("-C")
-            .arg(workspace_path)
-            .args(["ls-files", "--others", "--exclude-standard"])
-            .output()
-            .await;
+            if agent_name.eq_ignore_ascii_case(&mention) {
+                if ci_match.is_some() {
+                    tracing::warn!(
+                        session_id = %session_id,
+                        mention = %mention,
+                        "multiple session agents matched mention; skipping"
+                    );
+                    return Ok(None);
+                }
+                ci_match = Some((session_agent, agent.clone()));
+            }
+        }
 
-        let output = match output {
-            Ok(output) if output.status.success() => output,
-            _ => return Vec::new(),
+        let Some((session_agent, agent)) = exact_match.or(ci_match) else {
+            return Ok(None);
         };
 
-        let stdout = String::from_utf8_lossy(&output.stdout);
-        let mut files = Vec::new();
-        let untracked_dir = run_dir.join("untracked");
+        if session_agent.workspace_path.is_none() {
+            let workspace_path = WorkspaceLayout::default_root(session_id, agent.id)
+                .to_string_lossy()
+                .to_string();
+            let updated = ChatSessionAgent::update_workspace_path(
+                &self.db.pool,
+                session_agent.id,
+                Some(workspace_path),
+            )
+            .await?;
+            return Ok(Some((updated, agent)));
+        }
 
-        for line in stdout.lines() {
-            let rel = line.trim();
-            if rel.is_empty() {
-                continue;
+        Ok(Some((session_agent, agent)))
+    }
+
+    async fn run_agent_for_mention(
+        &self,
+        session_id: Uuid,
+        mention: &str,
+        source_message: &ChatMessage,
+        fanout_permit: Option<OwnedSemaphorePermit>,
+    ) -> Result<(), ChatRunnerError> {
+        // Reserved handles never name an agent. An agent mentioning the user handle is
+        // replying to the user; a user mentioning it addresses a human. Neither is routed,
+        // and neither is reported as an unknown agent.
+        let runner_config = Self::load_runner_config().await;
+        if runner_config.is_reserved_handle(mention) {
+            tracing::debug!(
+                session_id = %session_id,
+                message_id = %source_message.id,
+                mention = mention,
+                sender_type = ?source_message.sender_type,
+                addresses_user = runner_config.is_user_handle(mention),
+                "skipping reserved handle mention"
+            );
+            return Ok(());
+        }
+
+        let resolved = self
+            .resolve_session_agent_for_mention(session_id, mention)
+            .await;
+        let Some((session_agent, agent)) = (match resolved {
+            Ok(value) => value,
+            Err(err) => {
+                self.report_mention_failure(
+                    session_id,
+                    source_message.id,
+                    mention,
+                    None,
+                    format!("Failed to resolve mentioned agent: {err}"),
+                )
+                .await;
+                return Err(err);
             }
-            if rel == AGENTS_CHATGROUP_HOME_DIR
-                || rel.starts_with(&format!("{AGENTS_CHATGROUP_HOME_DIR}/"))
-                || rel.starts_with(&format!("{AGENTS_CHATGROUP_HOME_DIR}\\"))
-                || rel == AGENTS_CHATGROUP_WORKSPACE_DIR
-                || rel.starts_with(&format!("{AGENTS_CHATGROUP_WORKSPACE_DIR}/"))
-                || rel.starts_with(&format!("{AGENTS_CHATGROUP_WORKSPACE_DIR}\\"))
-            {
-                // Skip internal runtime artifacts generated by chat context snapshots.
-                continue;
+        }) else {
+            if let Some(agent) = ChatAgent::find_by_name(&self.db.pool, mention).await? {
+                tracing::debug!(
+                    session_id = %session_id,
+                    agent_id = %agent.id,
+                    mention = mention,
+                    "chat session agent not configured; marking mention as failed"
+                );
+                if self
+                    .handle_unresolved_agent_route(
+                        session_id,
+                        source_message,
+                        &agent.name,
+                        Some(agent.id),
+                        "Agent is not configured in this session.",
+                    )
+                    .await
+                {
+                    return Ok(());
+                }
+                self.report_mention_failure(
+                    session_id,
+                    source_message.id,
+                    &agent.name,
+                    Some(agent.id),
+                    "Agent is not configured in this session.",
+                )
+                .await;
+                return Err(ChatRunnerError::AgentNotFound(mention.to_string()));
             }
-            let rel_path = PathBuf::from(rel);
-            if rel_path.is_absolute()
-                || rel_path
-                    .components()
-                    .any(|component| matches!(component, std::path::Component::ParentDir))
+            if self
+                .handle_unresolved_agent_route(
+                    session_id,
+                    source_message,
+                    mention,
+                    None,
+                    "Mentioned agent was not found.",
+                )
+                .await
             {
-                continue;
+                return Ok(());
             }
-
-            let src = workspace_path.join(&rel_path);
-            let dest = untracked_dir.join(&rel_path);
-
-            if let Some(parent) = dest.parent()
-                && let Err(err) = fs::create_dir_all(parent).await
-            {
-                tracing::warn!("Failed to create untracked dir: {}", err);
-                continue;
+            let policy = Self::load_runner_config().await.unknown_mention_policy;
+            if !Self::should_report_unknown_mention(
+                policy,
+                &self.warned_unknown_mentions,
+                session_id,
+                mention,
+            ) {
+                tracing::debug!(
+                    session_id = %session_id,
+                    message_id = %source_message.id,
+                    mention = mention,
+                    policy = ?policy,
+                    "ignoring mention of unknown agent"
+                );
+                return Ok(());
             }
+            self.report_mention_failure(
+                session_id,
+                source_message.id,
+                mention,
+                None,
+                "Mentioned agent was not found.",
+            )
+            .await;
+            return Err(ChatRunnerError::AgentNotFound(mention.to_string()));
+        };
 
-            match fs::metadata(&src).await {
-                Ok(metadata) => {
-                    if metadata.len() > UNTRACKED_FILE_LIMIT {
-                        let placeholder =
-                            format!("File too large to display ({} bytes).", metadata.len());
-                        let _ = fs::write(&dest, placeholder).await;
-                    } else if let Ok(bytes) = fs::read(&src).await {
-                        let content = String::from_utf8_lossy(&bytes).to_string();
-                        let _ = fs::write(&dest, content).await;
-                    }
-                }
-                Err(err) => {
-                    tracing::warn!("Failed to read untracked file {}: {}", rel, err);
-                }
-            }
+        if source_message.sender_type == ChatSenderType::Agent
+            && let Some(sender_id) = source_message.sender_id
+            && sender_id == agent.id
+        {
+            tracing::debug!(
+                agent_id = %sender_id,
+                mention = mention,
+                "skipping self-mention by agent"
+            );
+            return Ok(());
+        }
 
-            files.push(rel.to_string());
+        if !chat::is_message_visible_to(source_message, Some(agent.id)) {
+            self.report_mention_failure(
+                session_id,
+                source_message.id,
+                &agent.name,
+                Some(agent.id),
+                "Message is private and not visible to this agent.",
+            )
+            .await;
+            return Ok(());
         }
 
-        files
-    }
+        let pending = PendingMessage {
+            session_id,
+            agent_id: agent.id,
+            agent_name: agent.name.clone(),
+            message: source_message.clone(),
+            priority: chat::ChatMessagePriority::from_meta(&source_message.meta.0),
+        };
+        let Some(session_agent) = self
+            .claim_or_queue(session_agent.id, pending, fanout_permit)
+            .await?
+        else {
+            // Emit a "received" status to indicate the message is queued
+            self.emit(
+                session_id,
+                ChatStreamEvent::MentionAcknowledged {
+                    session_id,
+                    message_id: source_message.id,
+                    mentioned_agent: agent.name.clone(),
+                    agent_id: agent.id,
+                    status: MentionStatus::Received,
+                },
+            );
 
-    async fn build_context_snapshot(
-        &self,
-        session_id: Uuid,
-        workspace_path: &str,
-        run_dir: &Path,
-    ) -> Result<ContextSnapshot, ChatRunnerError> {
-        // Create context directory first (needed for cutoff files)
-        let context_dir = PathBuf::from(workspace_path)
-            .join(AGENTS_CHATGROUP_WORKSPACE_DIR)
-            .join(CONTEXT_DIR_NAME)
-            .join(session_id.to_string());
-        fs::create_dir_all(&context_dir).await?;
-        let legacy_compacted_context_path = context_dir.join(LEGACY_COMPACTED_CONTEXT_FILE_NAME);
-        if let Err(err) = fs::remove_file(&legacy_compacted_context_path).await
-            && err.kind() != std::io::ErrorKind::NotFound
+            // Persist received status to message meta
+            self.update_mention_status(source_message.id, &agent.name, "received")
+                .await;
+
+            return Ok(());
+        };
+
+        // Resolved only once the run is claimed: a queued message may continue from the
+        // run that is still in progress.
+        let checkpoint = match self
+            .resolve_run_checkpoint(source_message, session_agent.id)
+            .await
         {
-            tracing::warn!(
-                session_id = %session_id,
-                error = %err,
-                path = %legacy_compacted_context_path.display(),
-                "Failed to remove legacy background compacted context file"
-            );
-        }
+            Ok(checkpoint) => checkpoint,
+            Err(reason) => {
+                self.report_mention_failure(
+                    session_id,
+                    source_message.id,
+                    &agent.name,
+                    Some(agent.id),
+                    reason,
+                )
+                .await;
+                self.release_unstarted_run(session_id, session_agent.id, agent.id)
+                    .await?;
+                return Ok(());
+            }
+        };
 
-        // Main path must never block on summarization: always build full context synchronously.
-        let full_context =
-            crate::services::chat::build_full_context(&self.db.pool, session_id).await?;
-        let jsonl = full_context.jsonl;
-        let context_path = context_dir.join("messages.jsonl");
-        fs::write(&context_path, jsonl.as_bytes()).await?;
-        tracing::info!(
-            session_id = %session_id,
-            workspace_path = %workspace_path,
-            context_path = %context_path.display(),
-            "Using workspace context (full, non-blocking)"
+        self.emit(
+            session_id,
+            ChatStreamEvent::AgentState {
+                session_agent_id: session_agent.id,
+                agent_id: agent.id,
+                state: ChatSessionAgentState::Running,
+                started_at: Some(session_agent.updated_at),
+            },
         );
 
-        // Kick off background compaction for future runs, without blocking current run.
-        self.spawn_background_context_compaction(
+        // Emit MentionAcknowledged running event
+        self.emit(
             session_id,
-            workspace_path.to_string(),
-            context_dir.clone(),
+            ChatStreamEvent::MentionAcknowledged {
+                session_id,
+                message_id: source_message.id,
+                mentioned_agent: agent.name.clone(),
+                agent_id: agent.id,
+                status: MentionStatus::Running,
+            },
         );
 
-        fs::create_dir_all(run_dir).await?;
-        let run_context_path = run_dir.join("context.jsonl");
-        fs::write(&run_context_path, jsonl.as_bytes()).await?;
+        // Persist running status to message meta
+        self.update_mention_status(source_message.id, &agent.name, "running")
+            .await;
 
-        Ok(ContextSnapshot {
-            workspace_path: context_path,
-            run_path: run_context_path,
-            context_compacted: false,
-            compression_warning: None,
-        })
-    }
+        let session_agent_id = session_agent.id;
+        let agent_id = agent.id;
 
-    fn spawn_background_context_compaction(
-        &self,
+        let reply_handle = self.resolve_reply_handle(source_message);
+        let chain_depth = self.extract_chain_depth(&source_message.meta);
+        let forwarded_from = Self::build_forwarded_from(source_message, mention);
+
+        let result = async {
+            let runner_config = Self::load_runner_config().await;
+            let workspace_io_timeout = runner_config.workspace_io_timeout();
+            let layout = WorkspaceLayout::for_session_agent(
+                session_id,
+                agent_id,
+                session_agent.workspace_path.as_deref(),
+            );
+            let workspace_path = layout.root_string();
+            let additional_workspaces =
+                Self::resolve_additional_workspaces(&layout, &agent.tools_enabled.0).await;
+            let run_records_dir = layout.run_records_dir();
+            Self::with_workspace_timeout(
+                workspace_io_timeout,
+                &workspace_path,
+                fs::create_dir_all(&run_records_dir),
+            )
+            .await?;
+            tracing::info!(
+                session_id = %session_id,
+                workspace_path = %workspace_path,
+                runs_dir = %run_records_dir.display(),
+                "Using workspace runs directory"
+            );
+
+            let run_index = ChatRun::next_run_index(&self.db.pool, session_agent_id).await?;
+            let run_id = Uuid::new_v4();
+            let run_dir = layout.run_dir(session_agent_id, run_index);
+            Self::with_workspace_timeout(
+                workspace_io_timeout,
+                &workspace_path,
+                fs::create_dir_all(&run_dir),
+            )
+            .await?;
+
+            let input_path = run_dir.join("input.md");
+            let output_path = run_dir.join("output.md");
+            let raw_log_path = run_dir.join("raw.log");
+            let meta_path = run_dir.join("meta.json");
+
+            let context_snapshot = Self::with_workspace_timeout(
+                workspace_io_timeout,
+                &workspace_path,
+                self.build_context_snapshot(session_id, &workspace_path, &run_dir, &agent),
+            )
+            .await?;
+            if let Some(warning) = context_snapshot.compression_warning.clone() {
+                self.emit(
+                    session_id,
+                    ChatStreamEvent::CompressionWarning {
+                        session_id,
+                        warning: warning.into(),
+                    },
+                );
+            }
+            let context_dir = context_snapshot
+                .workspace_path
+                .parent()
+                .map(|path| path.to_path_buf())
+                .unwrap_or_else(|| PathBuf::from(&workspace_path));
+            let reference_context = self
+                .build_reference_context(
+                    session_id,
+                    agent.id,
+                    source_message,
+                    &context_dir,
+                    runner_config.max_attachment_copy_bytes(),
+                )
+                .await?;
+            let message_attachments = self
+                .build_message_attachment_context(
+                    source_message,
+                    &context_dir,
+                    runner_config.max_attachment_copy_bytes(),
+                )
+                .await?;
+            let scratch_dir = layout.scratch_dir();
+            Self::with_workspace_timeout(
+                workspace_io_timeout,
+                &workspace_path,
+                Self::prepare_scratch_dir(&scratch_dir, runner_config.scratch_retention()),
+            )
+            .await?;
+            let session_agents = self.build_session_agent_summaries(session_id).await?;
+            let recent_context = if Self::require_history_read(&agent.tools_enabled.0) {
+                None
+            } else {
+                Some(
+                    chat::build_recent_context_snippet(
+                        &self.db.pool,
+                        session_id,
+                        &agent,
+                        source_message.id,
+                        RECENT_CONTEXT_MESSAGES,
+                    )
+                    .await?,
+                )
+            };
+            let (prompt, prompt_truncation) = self.build_prompt(
+                &agent,
+                source_message,
+                &context_snapshot.workspace_path,
+                recent_context.as_deref(),
+                &scratch_dir,
+                &session_agents,
+                message_attachments.as_ref(),
+                reference_context.as_ref(),
+                runner_config.max_prompt_tokens(),
+            );
+            fs::write(&input_path, &prompt).await?;
+
+            let _run = ChatRun::create(
+                &self.db.pool,
+                &CreateChatRun {
+                    session_id,
+                    session_agent_id,
+                    run_index,
+                    run_dir: run_dir.to_string_lossy().to_string(),
+                    input_path: Some(input_path.to_string_lossy().to_string()),
+                    output_path: Some(output_path.to_string_lossy().to_string()),
+                    raw_log_path: Some(raw_log_path.to_string_lossy().to_string()),
+                    meta_path: Some(meta_path.to_string_lossy().to_string()),
+                },
+                run_id,
+            )
+            .await?;
+
+            let executor_profile_id = self.parse_executor_profile_id(session_id, &agent).await?;
+            let mut executor =
+                ExecutorConfigs::get_cached().get_coding_agent_or_default(&executor_profile_id);
+            let approvals = Arc::new(ChatApprovalBridge {
+                runner: self.clone(),
+                session_id,
+                session_agent_id,
+                agent_id,
+                run_id: Mutex::new(run_id),
+                in_flight: Mutex::new(()),
+            });
+            let chat_approvals = Self::chat_approvals_enabled(&agent.tools_enabled.0);
+            if chat_approvals {
+                executor.use_approvals(approvals.clone());
+            } else {
+                executor.use_approvals(Arc::new(NoopExecutorApprovalService));
+            }
+            if let Some(effort) = chat::thinking_effort(&agent.tools_enabled.0)
+                && !executor.apply_thinking_effort(effort)
+            {
+                tracing::debug!(
+                    agent_id = %agent_id,
+                    runner_type = %agent.runner_type,
+                    "Executor has no reasoning setting; ignoring thinking_effort"
+                );
+            }
+            // A checkpoint resume forks the conversation, so it always gets a fresh process.
+            let persistent = checkpoint.is_none()
+                && Self::persistent_process_enabled(&agent.tools_enabled.0)
+                && executor
+                    .capabilities()
+                    .contains(&BaseAgentCapability::PersistentProcess);
+            if !persistent {
+                self.shutdown_persistent_process(session_agent_id);
+            }
+
+            let additional_paths: Vec<String> = additional_workspaces
+                .iter()
+                .map(|workspace| workspace.path.to_string_lossy().to_string())
+                .collect();
+            let repo_context =
+                RepoContext::new(PathBuf::from(&workspace_path), additional_paths.clone());
+            let mut env = ExecutionEnv::new(repo_context, false, String::new());
+            env.insert(
+                "VK_CHAT_WORKSPACE_PATHS",
+                serde_json::to_string(
+                    &std::iter::once(workspace_path.clone())
+                        .chain(additional_paths)
+                        .collect::<Vec<_>>(),
+                )
+                .unwrap_or_default(),
+            );
+            env.insert("VK_CHAT_SESSION_ID", session_id.to_string());
+            env.insert("VK_CHAT_AGENT_ID", agent_id.to_string());
+            env.insert("VK_CHAT_SESSION_AGENT_ID", session_agent_id.to_string());
+            env.insert("VK_CHAT_RUN_ID", run_id.to_string());
+            env.insert(
+                "VK_CHAT_CONTEXT_PATH",
+                context_snapshot
+                    .workspace_path
+                    .to_string_lossy()
+                    .to_string(),
+            );
+            env.insert(
+                "VK_CHAT_CONTEXT_RUN_PATH",
+                context_snapshot.run_path.to_string_lossy().to_string(),
+            );
+            env.insert(
+                "VK_CHAT_SCRATCH_PATH",
+                scratch_dir.to_string_lossy().to_string(),
+            );
+
+            let mut process = if persistent {
+                RunProcess::Persistent(
+                    self.persistent_process_for(
+                        &session_agent,
+                        &executor,
+                        &workspace_path,
+                        &env,
+                        approvals,
+                        chat_approvals,
+                        runner_config.spawn_timeout(),
+                    )
+                    .await?,
+                )
+            } else {
+                let spawn = async {
+                    if let Some(checkpoint) = checkpoint.as_ref() {
+                        tracing::info!(
+                            session_agent_id = %session_agent_id,
+                            agent_session_id = %checkpoint.agent_session_id,
+                            "continuing agent from an earlier run checkpoint"
+                        );
+                        executor
+                            .spawn_follow_up(
+                                PathBuf::from(&workspace_path).as_path(),
+                                &prompt,
+                                &checkpoint.agent_session_id,
+                                checkpoint.agent_message_id.as_deref(),
+                                &env,
+                            )
+                            .await
+                    } else if session_agent.state != ChatSessionAgentState::Dead
+                        && let Some(agent_session_id) = session_agent.agent_session_id.as_deref()
+                    {
+                        executor
+                            .spawn_follow_up(
+                                PathBuf::from(&workspace_path).as_path(),
+                                &prompt,
+                                agent_session_id,
+                                session_agent.agent_message_id.as_deref(),
+                                &env,
+                            )
+                            .await
+                    } else {
+                        executor
+                            .spawn(PathBuf::from(&workspace_path).as_path(), &prompt, &env)
+                            .await
+                    }
+                };
+                // Bounded separately from the idle timeout so a CLI stuck before it starts
+                // (e.g. on an auth prompt) is reported as a start failure, not a stalled run.
+                let spawned = match runner_config.spawn_timeout() {
+                    Some(timeout) => tokio::time::timeout(timeout, spawn)
+                        .await
+                        .map_err(|_| ChatRunnerError::SpawnTimeout(timeout.as_secs()))??,
+                    None => spawn.await?,
+                };
+                RunProcess::Spawned(spawned)
+            };
+
+            let msg_store = Arc::new(MsgStore::new());
+            let max_raw_log_bytes =
+                runner_config.max_run_log_bytes_for(&executor_profile_id.executor);
+            let raw_log_file = Arc::new(Mutex::new(CappedRunLog::new(
+                fs::File::create(&raw_log_path).await?,
+                max_raw_log_bytes,
+            )));
+
+            let mut stderr_events = runner_config.stream_stderr_events.then(|| StderrEventSink {
+                sender: self.sender_for(session_id),
+                session_id,
+                session_agent_id,
+                run_id,
+            });
+            let redaction_rules =
+                effective_redaction_rules(&runner_config.redaction_rules, &agent.tools_enabled.0);
+            // Persistent processes outlive the run, so their output is not teed.
+            if let RunProcess::Spawned(spawned) = &mut process {
+                let stdout_tee = match runner_config.debug_stdout_tee_path() {
+                    Some(path) => {
+                        let protected_dirs: Vec<&Path> =
+                            std::iter::once(Path::new(&workspace_path))
+                                .chain(std::iter::once(run_dir.as_path()))
+                                .chain(
+                                    additional_workspaces
+                                        .iter()
+                                        .map(|workspace| workspace.path.as_path()),
+                                )
+                                .collect();
+                        StdoutTee::open(&path, &protected_dirs, run_id, &agent.name).await
+                    }
+                    None => None,
+                };
+                self.spawn_log_forwarders(
+                    &mut spawned.child,
+                    msg_store.clone(),
+                    raw_log_file.clone(),
+                    stderr_events.take(),
+                    stdout_tee,
+                    &redaction_rules,
+                );
+            }
+            executor.normalize_logs(msg_store.clone(), PathBuf::from(&workspace_path).as_path());
+
+            let failed_flag = Arc::new(AtomicBool::new(false));
+            let max_duration_exceeded = Arc::new(AtomicBool::new(false));
+            let kill_requested = Arc::new(AtomicBool::new(false));
+
+            self.spawn_stream_bridge(
+                msg_store.clone(),
+                session_id,
+                agent_id,
+                session_agent_id,
+                run_id,
+                output_path,
+                meta_path,
+                PathBuf::from(&workspace_path),
+                additional_workspaces,
+                run_dir,
+                Some(reply_handle),
+                failed_flag.clone(),
+                chain_depth,
+                context_snapshot.context_compacted,
+                context_snapshot.compression_warning.clone(),
+                prompt_truncation,
+                self.clone(),
+                source_message.id,
+                agent.name.clone(),
+                forwarded_from,
+                Self::auto_commit_enabled(&agent.tools_enabled.0),
+                git_diff_base(&agent.tools_enabled.0),
+                Self::cost_per_million_tokens_cents(&agent.tools_enabled.0, &runner_config),
+                workspace_io_timeout,
+                AgentDeltaBatcher::new(
+                    self.sender_for(session_id),
+                    session_id,
+                    session_agent_id,
+                    agent_id,
+                    run_id,
+                    runner_config.delta_flush_interval(),
+                    runner_config.delta_flush_max_bytes as usize,
+                )
+                .dedupe_identical(runner_config.dedupe_identical_deltas),
+                runner_config.idle_timeout(),
+                kill_requested.clone(),
+                max_duration_exceeded.clone(),
+                OutputRedactor::for_agent(&runner_config.redaction_rules, &agent.tools_enabled.0),
+                Self::max_output_tokens(&agent.tools_enabled.0),
+                runner_config.max_token_usage_line_bytes as usize,
+            );
+
+            let duration_budget = Self::max_run_duration(&agent.tools_enabled.0, &runner_config)
+                .map(|limit| RunDurationBudget::new(limit, run_id, max_duration_exceeded));
+            match process {
+                RunProcess::Spawned(spawned) => self.spawn_exit_watcher(
+                    spawned.child,
+                    spawned.cancel,
+                    spawned.exit_signal,
+                    msg_store,
+                    failed_flag,
+                    session_agent_id,
+                    duration_budget,
+                    kill_requested,
+                ),
+                RunProcess::Persistent(process) => {
+                    self.start_persistent_turn(
+                        process,
+                        PersistentRunOutput {
+                            msg_store,
+                            raw_log_file,
+                            stderr_events,
+                            redaction_rules: Arc::new(redaction_rules),
+                        },
+                        run_id,
+                        prompt,
+                        failed_flag,
+                        session_agent_id,
+                        duration_budget,
+                    )
+                    .await
+                }
+            }
+
+            Ok::<(), ChatRunnerError>(())
+        }
+        .await;
+
+        if result.is_err() {
+            if let Err(err) = &result {
+                self.report_mention_failure(
+                    session_id,
+                    source_message.id,
+                    &agent.name,
+                    Some(agent_id),
+                    format!("Failed to start agent run: {err}"),
+                )
+                .await;
+            }
+            let _ = ChatRunStats::record_run(
+                &self.db.pool,
+                session_id,
+                session_agent_id,
+                agent_id,
+                0,
+                0.0,
+                true,
+            )
+            .await;
+            self.fanout_permits.remove(&session_agent_id);
+            let _ = ChatSessionAgent::update_state(
+                &self.db.pool,
+                session_agent_id,
+                ChatSessionAgentState::Dead,
+            )
+            .await;
+            self.emit(
+                session_id,
+                ChatStreamEvent::AgentState {
+                    session_agent_id,
+                    agent_id,
+                    state: ChatSessionAgentState::Dead,
+                    started_at: None,
+                },
+            );
+        }
+
+        result
+    }
+
+    /// Create the scratch directory and remove top-level entries not modified within
+    /// `retention`. Pruning is best effort; only failing to create the directory is an error.
+    async fn prepare_scratch_dir(
+        scratch_dir: &Path,
+        retention: Option<std::time::Duration>,
+    ) -> std::io::Result<()> {
+        fs::create_dir_all(scratch_dir).await?;
+        let Some(cutoff) = retention.and_then(|age| std::time::SystemTime::now().checked_sub(age))
+        else {
+            return Ok(());
+        };
+
+        let mut entries = fs::read_dir(scratch_dir).await?;
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let Ok(metadata) = entry.metadata().await else {
+                continue;
+            };
+            let path = entry.path();
+            if Self::modified_since(&path, &metadata, cutoff).await {
+                continue;
+            }
+            let removed = if metadata.is_dir() {
+                fs::remove_dir_all(&path).await
+            } else {
+                fs::remove_file(&path).await
+            };
+            if let Err(err) = removed {
+                tracing::warn!(
+                    path = %path.display(),
+                    error = %err,
+                    "Failed to prune expired scratch entry"
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether `path` or, for a directory, anything inside it was modified at or after
+    /// `cutoff`, so a directory whose files are still in use is not pruned as a whole.
+    /// Symlinks are judged by their own time and never followed.
+    async fn modified_since(
+        path: &Path,
+        metadata: &std::fs::Metadata,
+        cutoff: std::time::SystemTime,
+    ) -> bool {
+        let is_recent =
+            |metadata: &std::fs::Metadata| metadata.modified().is_ok_and(|time| time >= cutoff);
+        if is_recent(metadata) {
+            return true;
+        }
+        if !metadata.is_dir() {
+            return false;
+        }
+        let mut pending = vec![path.to_path_buf()];
+        while let Some(dir) = pending.pop() {
+            let Ok(mut entries) = fs::read_dir(&dir).await else {
+                continue;
+            };
+            while let Ok(Some(entry)) = entries.next_entry().await {
+                let Ok(metadata) = entry.metadata().await else {
+                    continue;
+                };
+                if is_recent(&metadata) {
+                    return true;
+                }
+                if metadata.is_dir() {
+                    pending.push(entry.path());
+                }
+            }
+        }
+        false
+    }
+
+    fn parse_runner_type(&self, agent: &ChatAgent) -> Result<BaseCodingAgent, ChatRunnerError> {
+        let raw = agent.runner_type.trim();
+        let normalized = raw.replace(['-', ' '], "_").to_ascii_uppercase();
+        BaseCodingAgent::from_str(&normalized)
+            .map_err(|_| ChatRunnerError::UnknownRunnerType(raw.to_string()))
+    }
+
+    /// Executor profile a run of `agent` uses. Agents without a runner type inherit the
+    /// session's executor profile when one is set.
+    async fn parse_executor_profile_id(
+        &self,
+        session_id: Uuid,
+        agent: &ChatAgent,
+    ) -> Result<ExecutorProfileId, ChatRunnerError> {
+        if agent.runner_type.trim().is_empty()
+            && let Some(profile) =
+                chat::find_session_executor_profile(&self.db.pool, session_id).await?
+        {
+            return Ok(profile);
+        }
+        let executor = self.parse_runner_type(agent)?;
+        let variant = Self::extract_executor_profile_variant(&agent.tools_enabled.0);
+        Ok(match variant {
+            Some(variant) => ExecutorProfileId::with_variant(executor, variant),
+            None => ExecutorProfileId::new(executor),
+        })
+    }
+
+    fn extract_executor_profile_variant(tools_enabled: &serde_json::Value) -> Option<String> {
+        let variant = tools_enabled
+            .as_object()
+            .and_then(|value| value.get(EXECUTOR_PROFILE_VARIANT_KEY))
+            .and_then(serde_json::Value::as_str)?
+            .trim();
+        if variant.is_empty() || variant.eq_ignore_ascii_case("DEFAULT") {
+            return None;
+        }
+        Some(canonical_variant_key(variant))
+    }
+
+    fn resolve_reply_handle(&self, message: &ChatMessage) -> String {
+        Self::reply_handle_from_meta(&message.meta.0)
+    }
+
+    /// Handle agents address their reply to. Missing or unusable handles, and any casing
+    /// of [`RESERVED_USER_HANDLE`], become that handle so the reply is never routed.
+    fn reply_handle_from_meta(meta: &serde_json::Value) -> String {
+        let handle = meta
+            .get("sender_handle")
+            .and_then(|value| value.as_str())
+            .unwrap_or(RESERVED_USER_HANDLE);
+        let sanitized = handle
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric() || *c == '_' || *c == '-')
+            .collect::<String>();
+        if sanitized.is_empty() || sanitized.eq_ignore_ascii_case(RESERVED_USER_HANDLE) {
+            RESERVED_USER_HANDLE.to_string()
+        } else {
+            sanitized
+        }
+    }
+
+    /// Whether a mention that matches no agent should post a failure under `policy`.
+    /// WARN_ONCE records the handle so later mentions in the same session stay quiet.
+    fn should_report_unknown_mention(
+        policy: UnknownMentionPolicy,
+        warned: &DashMap<(Uuid, String), ()>,
+        session_id: Uuid,
+        mention: &str,
+    ) -> bool {
+        match policy {
+            UnknownMentionPolicy::Fail => true,
+            UnknownMentionPolicy::Ignore => false,
+            UnknownMentionPolicy::WarnOnce => warned
+                .insert((session_id, mention.trim().to_lowercase()), ())
+                .is_none(),
+        }
+    }
+
+    /// Resolve the checkpoint named by the message's `from_run_id`, if any. Only the session
+    /// agent that owns the run continues from it; other agents mentioned in the same message
+    /// start fresh. The run must have finished with a stored executor session.
+    async fn resolve_run_checkpoint(
+        &self,
+        source_message: &ChatMessage,
+        session_agent_id: Uuid,
+    ) -> Result<Option<RunCheckpoint>, String> {
+        let Some(value) = source_message.meta.0.get(FROM_RUN_ID_KEY) else {
+            return Ok(None);
+        };
+        if value.is_null() {
+            return Ok(None);
+        }
+        let run_id = value
+            .as_str()
+            .and_then(|raw| Uuid::parse_str(raw).ok())
+            .ok_or_else(|| format!("Invalid {FROM_RUN_ID_KEY}: {value}"))?;
+
+        let run = ChatRun::find_by_id(&self.db.pool, run_id)
+            .await
+            .map_err(|err| format!("Failed to load run {run_id}: {err}"))?
+            .ok_or_else(|| format!("Run {run_id} was not found."))?;
+        if run.session_agent_id != session_agent_id {
+            return Ok(None);
+        }
+
+        let meta = match run.meta_path.as_deref() {
+            Some(meta_path) => fs::read_to_string(meta_path)
+                .await
+                .ok()
+                .and_then(|raw| serde_json::from_str::<serde_json::Value>(&raw).ok()),
+            None => None,
+        };
+        meta.as_ref()
+            .and_then(Self::run_checkpoint_from_meta)
+            .map(Some)
+            .ok_or_else(|| format!("Run {run_id} has no agent session to continue from."))
+    }
+
+    fn run_checkpoint_from_meta(meta: &serde_json::Value) -> Option<RunCheckpoint> {
+        let agent_session_id = meta
+            .get("agent_session_id")
+            .and_then(|v| v.as_str())
+            .filter(|v| !v.is_empty())?;
+        Some(RunCheckpoint {
+            agent_session_id: agent_session_id.to_string(),
+            agent_message_id: meta
+                .get("agent_message_id")
+                .and_then(|v| v.as_str())
+                .map(str::to_string),
+        })
+    }
+
+    /// Only agent-authored messages are forwards; user mentions have no provenance chain.
+    fn build_forwarded_from(
+        source_message: &ChatMessage,
+        directive_target: &str,
+    ) -> Option<ForwardedFrom> {
+        if source_message.sender_type != ChatSenderType::Agent {
+            return None;
+        }
+        Some(ForwardedFrom {
+            message_id: source_message.id,
+            agent_id: source_message.sender_id,
+            directive_target: directive_target.to_string(),
+        })
+    }
+
+    fn apply_reply_prefix(content: &str, handle: Option<&str>) -> String {
+        let _ = handle;
+        content.to_string()
+    }
+
+    /// Write the tracked changes of the primary and additional workspaces to the run's
+    /// `diff.patch`.
+    async fn capture_git_diff(
+        workspace_path: &Path,
+        additional_workspaces: &[AdditionalWorkspace],
+        run_dir: &Path,
+        diff_base: &GitDiffBase,
+    ) -> Option<DiffInfo> {
+        let mut diff = Self::tracked_git_diff(workspace_path, None, diff_base)
+            .await
+            .unwrap_or_default();
+        for workspace in additional_workspaces {
+            if let Some(workspace_diff) =
+                Self::tracked_git_diff(&workspace.path, Some(&workspace.label), diff_base).await
+            {
+                diff.push_str(&workspace_diff);
+            }
+        }
+        if diff.is_empty() {
+            return None;
+        }
+
+        let diff_path = run_dir.join("diff.patch");
+        if let Err(err) = fs::write(&diff_path, &diff).await {
+            tracing::warn!("Failed to write diff patch: {}", err);
+            return None;
+        }
+
+        // Consider diff truncated if it's over 4KB (for UI display purposes)
+        let truncated = diff.len() > 4000;
+
+        Some(DiffInfo { truncated })
+    }
+
+    /// Additional workspaces whose changes the primary workspace's capture does not already
+    /// record. One inside the primary's git worktree is left out so its files are not
+    /// captured twice; a nested repository of its own is kept.
+    async fn separately_tracked_workspaces(
+        workspace_path: &Path,
+        additional_workspaces: &[AdditionalWorkspace],
+    ) -> Vec<AdditionalWorkspace> {
+        let primary_toplevel = Self::git_toplevel(workspace_path).await;
+        let mut workspaces = Vec::new();
+        for workspace in additional_workspaces {
+            if primary_toplevel.is_some()
+                && Self::git_toplevel(&workspace.path).await == primary_toplevel
+            {
+                continue;
+            }
+            workspaces.push(workspace.clone());
+        }
+        workspaces
+    }
+
+    async fn git_toplevel(path: &Path) -> Option<String> {
+        let output = Command::new("git")
+            .kill_on_drop(true)
+            .arg("-C")
+            .arg(path)
+            .args(["rev-parse", "--show-toplevel"])
+            .output()
+            .await
+            .ok()?;
+        output
+            .status
+            .success()
+            .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+    }
+
+    /// Tracked changes of one workspace against `diff_base` as a patch, with paths relative
+    /// to the workspace even when it is a subdirectory of its repository; `label` prefixes
+    /// its file paths.
+    async fn tracked_git_diff(
+        workspace_path: &Path,
+        label: Option<&str>,
+        diff_base: &GitDiffBase,
+    ) -> Option<String> {
+        let check = Command::new("git")
+            .kill_on_drop(true)
+            .arg("-C")
+            .arg(workspace_path)
+            .args(["rev-parse", "--is-inside-work-tree"])
+            .output()
+            .await
+            .ok()?;
+
+        if !check.status.success() {
+            return None;
+        }
+
+        let status = Command::new("git")
+            .kill_on_drop(true)
+            .arg("-C")
+            .arg(workspace_path)
+            .args(["status", "--porcelain"])
+            .output()
+            .await
+            .ok()?;
+
+        if !status.status.success() {
+            return None;
+        }
+
+        let status_text = String::from_utf8_lossy(&status.stdout);
+        let has_tracked_changes = status_text.lines().any(|line| {
+            let trimmed = line.trim();
+            !trimmed.is_empty() && !trimmed.starts_with("??")
+        });
+
+        if !has_tracked_changes && diff_base.requires_uncommitted_changes() {
+            return None;
+        }
+
+        let mut diff_command = Command::new("git");
+        diff_command
+            .kill_on_drop(true)
+            .arg("-C")
+            .arg(workspace_path)
+            .args(["diff", "--no-color", "--relative"]);
+        if let Some(label) = label {
+            diff_command
+                .arg(format!("--src-prefix=a/{label}/"))
+                .arg(format!("--dst-prefix=b/{label}/"));
+        }
+        diff_command.args(diff_base.diff_args());
+        let output = diff_command.output().await.ok()?;
+
+        if !output.status.success() {
+            return None;
+        }
+
+        let diff = String::from_utf8_lossy(&output.stdout).to_string();
+        if diff.trim().is_empty() {
+            return None;
+        }
+        Some(diff)
+    }
+
+    /// Auto-commit is opt-in per agent via `tools_enabled.auto_commit = true`.
+    fn auto_commit_enabled(tools_enabled: &serde_json::Value) -> bool {
+        tools_enabled
+            .as_object()
+            .and_then(|value| value.get(AUTO_COMMIT_KEY))
+            .and_then(serde_json::Value::as_bool)
+            .unwrap_or(false)
+    }
+
+    /// Tool approvals wait for the user in the chat UI only with
+    /// `tools_enabled.chat_approvals = true`; otherwise tool calls are auto-approved.
+    fn chat_approvals_enabled(tools_enabled: &serde_json::Value) -> bool {
+        tools_enabled
+            .as_object()
+            .and_then(|value| value.get(CHAT_APPROVALS_KEY))
+            .and_then(serde_json::Value::as_bool)
+            .unwrap_or(false)
+    }
+
+    /// Opt-in via `tools_enabled.persistent_process = true`; only honoured by executors that
+    /// can keep a process alive between runs.
+    fn persistent_process_enabled(tools_enabled: &serde_json::Value) -> bool {
+        tools_enabled
+            .as_object()
+            .and_then(|value| value.get(PERSISTENT_PROCESS_KEY))
+            .and_then(serde_json::Value::as_bool)
+            .unwrap_or(false)
+    }
+
+    /// Duration cap on one run: `tools_enabled.max_run_duration_secs` when set (0 disables),
+    /// otherwise the configured default.
+    fn max_run_duration(
+        tools_enabled: &serde_json::Value,
+        runner_config: &ChatRunnerConfig,
+    ) -> Option<std::time::Duration> {
+        match tools_enabled
+            .as_object()
+            .and_then(|value| value.get(MAX_RUN_DURATION_KEY))
+            .and_then(serde_json::Value::as_u64)
+        {
+            Some(0) => None,
+            Some(secs) => Some(std::time::Duration::from_secs(secs)),
+            None => runner_config.max_run_duration(),
+        }
+    }
+
+    /// Cap on the tokens of one streamed reply: `tools_enabled.max_output_tokens` when set and
+    /// non-zero.
+    fn max_output_tokens(tools_enabled: &serde_json::Value) -> Option<u32> {
+        tools_enabled
+            .as_object()
+            .and_then(|value| value.get(MAX_OUTPUT_TOKENS_KEY))
+            .and_then(serde_json::Value::as_u64)
+            .filter(|limit| *limit > 0)
+            .map(|limit| limit.min(u32::MAX as u64) as u32)
+    }
+
+    /// Token price for cost estimates: `tools_enabled.cost_per_million_tokens_cents` when set,
+    /// otherwise the configured price.
+    fn cost_per_million_tokens_cents(
+        tools_enabled: &serde_json::Value,
+        runner_config: &ChatRunnerConfig,
+    ) -> u32 {
+        tools_enabled
+            .as_object()
+            .and_then(|value| value.get(COST_PER_MILLION_TOKENS_CENTS_KEY))
+            .and_then(serde_json::Value::as_u64)
+            .and_then(|cents| u32::try_from(cents).ok())
+            .unwrap_or(runner_config.cost_per_million_tokens_cents)
+    }
+
+    /// Estimated cost in US dollars of `tokens` at `cents_per_million`.
+    fn estimate_run_cost(tokens: u32, cents_per_million: u32) -> f64 {
+        f64::from(tokens) * f64::from(cents_per_million) / 100_000_000.0
+    }
+
+    /// Agents read the history file first unless `tools_enabled.require_history_read = false`.
+    fn require_history_read(tools_enabled: &serde_json::Value) -> bool {
+        tools_enabled
+            .as_object()
+            .and_then(|value| value.get(REQUIRE_HISTORY_READ_KEY))
+            .and_then(serde_json::Value::as_bool)
+            .unwrap_or(true)
+    }
+
+    fn build_auto_commit_message(agent_name: &str, run_id: Uuid) -> String {
+        format!("{agent_name}: apply changes from chat run\n\nChat-Run-Id: {run_id}")
+    }
+
+    /// Stages and commits all workspace changes except the runner's own files under
+    /// `.agents_chatgroup/`, returning the new commit hash.
+    async fn auto_commit_changes(workspace_path: &Path, message: &str) -> Result<String, String> {
+        let run_git = |args: Vec<&str>| {
+            let mut command = Command::new("git");
+            command
+                .kill_on_drop(true)
+                .arg("-C")
+                .arg(workspace_path)
+                .args(args);
+            command
+        };
+
+        let exclude_runtime_dir = format!(":(exclude){AGENTS_CHATGROUP_WORKSPACE_DIR}");
+        let add = run_git(vec!["add", "-A", "--", ".", &exclude_runtime_dir])
+            .output()
+            .await
+            .map_err(|err| format!("git add failed: {err}"))?;
+        if !add.status.success() {
+            return Err(format!(
+                "git add failed: {}",
+                String::from_utf8_lossy(&add.stderr).trim()
+            ));
+        }
+
+        let commit = run_git(vec!["commit", "-m", message])
+            .output()
+            .await
+            .map_err(|err| format!("git commit failed: {err}"))?;
+        if !commit.status.success() {
+            let stderr = String::from_utf8_lossy(&commit.stderr);
+            let stdout = String::from_utf8_lossy(&commit.stdout);
+            let detail = if stderr.trim().is_empty() {
+                stdout.trim().to_string()
+            } else {
+                stderr.trim().to_string()
+            };
+            return Err(format!("git commit failed: {detail}"));
+        }
+
+        let head = run_git(vec!["rev-parse", "HEAD"])
+            .output()
+            .await
+            .map_err(|err| format!("git rev-parse failed: {err}"))?;
+        if !head.status.success() {
+            return Err(format!(
+                "git rev-parse failed: {}",
+                String::from_utf8_lossy(&head.stderr).trim()
+            ));
+        }
+
+        Ok(String::from_utf8_lossy(&head.stdout).trim().to_string())
+    }
+
+    /// Copy untracked files into the run's `untracked` directory; files of an additional
+    /// workspace are recorded under its `label` inside [`ADDITIONAL_UNTRACKED_DIR_NAME`].
+    async fn capture_untracked_files(
+        workspace_path: &Path,
+        label: Option<&str>,
+        run_dir: &Path,
+    ) -> Vec<String> {
+        let output = Command::new("git")
+            .kill_on_drop(true)
+            .arg("-C")
+            .arg(workspace_path)
+            .args(["ls-files", "--others", "--exclude-standard"])
+            .output()
+            .await;
+
+        let output = match output {
+            Ok(output) if output.status.success() => output,
+            _ => return Vec::new(),
+        };
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let mut files = Vec::new();
+        let untracked_dir = run_dir.join("untracked");
+
+        for line in stdout.lines() {
+            let rel = line.trim();
+            if rel.is_empty() {
+                continue;
+            }
+            if rel == AGENTS_CHATGROUP_HOME_DIR
+                || rel.starts_with(&format!("{AGENTS_CHATGROUP_HOME_DIR}/"))
+                || rel.starts_with(&format!("{AGENTS_CHATGROUP_HOME_DIR}\\"))
+                || rel == AGENTS_CHATGROUP_WORKSPACE_DIR
+                || rel.starts_with(&format!("{AGENTS_CHATGROUP_WORKSPACE_DIR}/"))
+                || rel.starts_with(&format!("{AGENTS_CHATGROUP_WORKSPACE_DIR}\\"))
+            {
+                // Skip internal runtime artifacts generated by chat context snapshots.
+                continue;
+            }
+            let rel_path = PathBuf::from(rel);
+            if rel_path.is_absolute()
+                || rel_path
+                    .components()
+                    .any(|component| matches!(component, std::path::Component::ParentDir))
+            {
+                continue;
+            }
+            if label.is_none() && rel_path.starts_with(ADDITIONAL_UNTRACKED_DIR_NAME) {
+                // Would be mistaken for an additional workspace's file.
+                continue;
+            }
+
+            let src = workspace_path.join(&rel_path);
+            let recorded = match label {
+                Some(label) => Path::new(ADDITIONAL_UNTRACKED_DIR_NAME)
+                    .join(label)
+                    .join(&rel_path),
+                None => rel_path,
+            };
+            let dest = untracked_dir.join(&recorded);
+
+            if let Some(parent) = dest.parent()
+                && let Err(err) = fs::create_dir_all(parent).await
+            {
+                tracing::warn!("Failed to create untracked dir: {}", err);
+                continue;
+            }
+
+            match fs::metadata(&src).await {
+                Ok(metadata) => {
+                    if metadata.len() > UNTRACKED_FILE_LIMIT {
+                        let placeholder =
+                            format!("File too large to display ({} bytes).", metadata.len());
+                        let _ = fs::write(&dest, placeholder).await;
+                    } else if let Ok(bytes) = fs::read(&src).await {
+                        let content = String::from_utf8_lossy(&bytes).to_string();
+                        let _ = fs::write(&dest, content).await;
+                    }
+                }
+                Err(err) => {
+                    tracing::warn!("Failed to read untracked file {}: {}", rel, err);
+                }
+            }
+
+            files.push(recorded.to_string_lossy().replace('\\', "/"));
+        }
+
+        files
+    }
+
+    /// The agent's additional workspaces that exist, labelled apart from each other.
+    /// Entries that fail workspace validation are skipped.
+    async fn resolve_additional_workspaces(
+        layout: &WorkspaceLayout,
+        tools_enabled: &serde_json::Value,
+    ) -> Vec<AdditionalWorkspace> {
+        let mut workspaces: Vec<AdditionalWorkspace> = Vec::new();
+        for (index, raw) in additional_workspace_paths(tools_enabled)
+            .into_iter()
+            .enumerate()
+        {
+            let validated = match validate_workspace_root_legality(&raw) {
+                Ok(_) => {
+                    let path = layout.resolve_additional(&raw);
+                    validate_workspace_root(&path.to_string_lossy())
+                        .await
+                        .map(|_| path)
+                }
+                Err(err) => Err(err),
+            };
+            let path = match validated {
+                Ok(path) => path,
+                Err(err) => {
+                    tracing::warn!(
+                        workspace_path = %raw,
+                        error = %err,
+                        "skipping invalid additional workspace"
+                    );
+                    continue;
+                }
+            };
+            let mut label = workspace_label(&path, index);
+            if workspaces.iter().any(|workspace| workspace.label == label) {
+                label = format!("{label}_{index}");
+            }
+            workspaces.push(AdditionalWorkspace { path, label });
+        }
+        workspaces
+    }
+
+    async fn build_context_snapshot(
+        &self,
+        session_id: Uuid,
+        workspace_path: &str,
+        run_dir: &Path,
+        agent: &ChatAgent,
+    ) -> Result<ContextSnapshot, ChatRunnerError> {
+        // Create context directory first (needed for cutoff files)
+        let context_dir = WorkspaceLayout::new(workspace_path, session_id).context_dir();
+        fs::create_dir_all(&context_dir).await?;
+        let legacy_compacted_context_path = context_dir.join(LEGACY_COMPACTED_CONTEXT_FILE_NAME);
+        if let Err(err) = fs::remove_file(&legacy_compacted_context_path).await
+            && err.kind() != std::io::ErrorKind::NotFound
+        {
+            tracing::warn!(
+                session_id = %session_id,
+                error = %err,
+                path = %legacy_compacted_context_path.display(),
+                "Failed to remove legacy background compacted context file"
+            );
+        }
+
+        // Main path must never block on summarization: build full context synchronously, or
+        // reuse a still-valid cached compaction when `run_context_mode` asks for it.
+        // Narrow-scoped or focused agents and agents with private messages get their own file so the
+        // shared history stays complete and never leaks private messages.
+        let context_scope = chat::ContextScope::from_tools_enabled(&agent.tools_enabled.0);
+        let focus_mode = chat::focus_mode_enabled(&agent.tools_enabled.0);
+        let has_private_messages =
+            chat::has_private_messages_for(&self.db.pool, session_id, agent.id).await?;
+        let mut context_compacted = false;
+        let (jsonl, context_path) =
+            if context_scope == chat::ContextScope::Full && !focus_mode && !has_private_messages {
+                let cached_context = match Self::load_compression_config().await.run_context_mode {
+                    RunContextMode::FullThenCompact => None,
+                    RunContextMode::CachedThenCompact => {
+                        chat::build_cached_compacted_context(&self.db.pool, session_id).await?
+                    }
+                };
+                let shared_context = match cached_context {
+                    Some(cached_context) => cached_context,
+                    None => chat::build_full_context(&self.db.pool, session_id, None).await?,
+                };
+                context_compacted = shared_context.context_compacted;
+                let file_name = format!("messages.{}", shared_context.format.file_extension());
+                (shared_context.contents, context_dir.join(file_name))
+            } else {
+                let scoped_context = chat::build_scoped_context(
+                    &self.db.pool,
+                    session_id,
+                    context_scope,
+                    focus_mode,
+                    agent.id,
+                    &agent.name,
+                )
+                .await?;
+                let file_name = format!(
+                    "messages.{}.{}",
+                    agent.id,
+                    scoped_context.format.file_extension()
+                );
+                (scoped_context.contents, context_dir.join(file_name))
+            };
+        fs::write(&context_path, jsonl.as_bytes()).await?;
+        tracing::info!(
+            session_id = %session_id,
+            workspace_path = %workspace_path,
+            context_path = %context_path.display(),
+            context_scope = ?context_scope,
+            focus_mode,
+            context_compacted,
+            "Using workspace context (non-blocking)"
+        );
+
+        // Kick off background compaction for future runs, without blocking current run.
+        self.spawn_background_context_compaction(
+            session_id,
+            workspace_path.to_string(),
+            context_dir.clone(),
+            false,
+        );
+
+        fs::create_dir_all(run_dir).await?;
+        let run_context_path = run_dir.join(format!(
+            "context.{}",
+            context_path
+                .extension()
+                .and_then(|ext| ext.to_str())
+                .unwrap_or("jsonl")
+        ));
+        fs::write(&run_context_path, jsonl.as_bytes()).await?;
+
+        Ok(ContextSnapshot {
+            workspace_path: context_path,
+            run_path: run_context_path,
+            context_compacted,
+            compression_warning: None,
+        })
+    }
+
+    /// Recompute a session's compacted context in the background. Summaries and cutoff
+    /// files use the workspace of the session's first member. With `force`, the cached
+    /// compression result is ignored and the history is summarized again; a forced request
+    /// fails with [`ChatRunnerError::CompactionInFlight`] while another compaction runs.
+    pub async fn recompute_context_compaction(
+        &self,
+        session_id: Uuid,
+        force: bool,
+    ) -> Result<(), ChatRunnerError> {
+        let Some(session_agent) = ChatSessionAgent::find_all_for_session(&self.db.pool, session_id)
+            .await?
+            .into_iter()
+            .next()
+        else {
+            return Ok(());
+        };
+        let layout = WorkspaceLayout::for_session_agent(
+            session_id,
+            session_agent.agent_id,
+            session_agent.workspace_path.as_deref(),
+        );
+        let context_dir = layout.context_dir();
+        fs::create_dir_all(&context_dir).await?;
+        let started = self.spawn_background_context_compaction(
+            session_id,
+            layout.root_string(),
+            context_dir,
+            force,
+        );
+        if force && !started {
+            return Err(ChatRunnerError::CompactionInFlight(session_id));
+        }
+        Ok(())
+    }
+
+    /// Returns `false` without starting anything while a compaction of the session runs.
+    fn spawn_background_context_compaction(
+        &self,
+        session_id: Uuid,
+        workspace_path: String,
+        context_dir: PathBuf,
+        bypass_cache: bool,
+    ) -> bool {
+        if self
+            .background_compaction_inflight
+            .insert(session_id, ())
+            .is_some()
+        {
+            return false;
+        }
+
+        let runner = self.clone();
+        tokio::spawn(async move {
+            let workspace_path_buf = PathBuf::from(&workspace_path);
+            let result = if bypass_cache {
+                chat::rebuild_compacted_context(
+                    &runner.db.pool,
+                    session_id,
+                    Some(workspace_path_buf.as_path()),
+                    Some(context_dir.as_path()),
+                )
+                .await
+            } else {
+                chat::build_compacted_context(
+                    &runner.db.pool,
+                    session_id,
+                    None,
+                    Some(workspace_path_buf.as_path()),
+                    Some(context_dir.as_path()),
+                )
+                .await
+            };
+
+            match result {
+                Ok(compacted) => {
+                    if compacted.context_compacted {
+                        let workspace_context_path = context_dir
+                            .join(format!("messages.{}", compacted.format.file_extension()));
+                        if let Err(err) =
+                            fs::write(&workspace_context_path, compacted.contents.as_bytes()).await
+                        {
+                            tracing::warn!(
+                                session_id = %session_id,
+                                error = %err,
+                                path = %workspace_context_path.display(),
+                                "Failed to update workspace context with compacted history"
+                            );
+                        } else {
+                            tracing::info!(
+                                session_id = %session_id,
+                                path = %workspace_context_path.display(),
+                                compacted_message_count = compacted.messages.len(),
+                                "Background context compaction completed and updated workspace context"
+                            );
+                        }
+                    }
+
+                    if let Some(warning) = compacted.compression_warning {
+                        runner.emit(
+                            session_id,
+                            ChatStreamEvent::CompressionWarning {
+                                session_id,
+                                warning: warning.into(),
+                            },
+                        );
+                    }
+                }
+                Err(err) => {
+                    tracing::warn!(
+                        session_id = %session_id,
+                        error = %err,
+                        "Background context compaction failed"
+                    );
+                }
+            }
+
+            runner.background_compaction_inflight.remove(&session_id);
+        });
+        true
+    }
+
+    async fn build_reference_context(
+        &self,
+        session_id: Uuid,
+        agent_id: Uuid,
+        source_message: &ChatMessage,
+        context_dir: &Path,
+        max_copy_bytes: Option<u64>,
+    ) -> Result<Option<ReferenceContext>, ChatRunnerError> {
+        let Some(reference_id) = chat::extract_reference_message_id(&source_message.meta.0) else {
+            return Ok(None);
+        };
+
+        let Some(reference) = ChatMessage::find_by_id(&self.db.pool, reference_id).await? else {
+            return Ok(None);
+        };
+
+        if reference.session_id != session_id
+            || !chat::is_message_visible_to(&reference, Some(agent_id))
+        {
+            return Ok(None);
+        }
+
+        let sender_label = reference
+            .meta
+            .0
+            .get("sender")
+            .and_then(|value| value.get("label"))
+            .and_then(|value| value.as_str())
+            .unwrap_or("unknown")
+            .to_string();
+
+        let attachments = chat::extract_attachments(&reference.meta.0);
+        let mut reference_attachments = Vec::new();
+
+        if !attachments.is_empty() {
+            let reference_dir = context_dir
+                .join("references")
+                .join(reference_id.to_string());
+            fs::create_dir_all(&reference_dir).await?;
+
+            for attachment in attachments {
+                let source_path =
+                    asset_dir().join(attachment.relative_path(reference.session_id, reference.id));
+                let file_name = source_path
+                    .file_name()
+                    .map(|name| name.to_string_lossy().to_string())
+                    .unwrap_or_else(|| attachment.name.clone());
+                let dest_path = reference_dir.join(&file_name);
+                let (local_path, too_large) =
+                    Self::copy_attachment(&source_path, &dest_path, max_copy_bytes).await;
+
+                reference_attachments.push(ReferenceAttachment {
+                    name: attachment.name,
+                    size_bytes: attachment.size_bytes,
+                    kind: chat::normalize_attachment_kind(
+                        &attachment.kind,
+                        attachment.mime_type.as_deref(),
+                    ),
+                    mime_type: attachment.mime_type,
+                    local_path,
+                    too_large,
+                });
+            }
+        }
+
+        Ok(Some(ReferenceContext {
+            message_id: reference.id,
+            sender_label,
+            sender_type: reference.sender_type,
+            created_at: reference.created_at.to_rfc3339(),
+            content: reference.content,
+            attachments: reference_attachments,
+        }))
+    }
+
+    async fn build_message_attachment_context(
+        &self,
+        source_message: &ChatMessage,
+        context_dir: &Path,
+        max_copy_bytes: Option<u64>,
+    ) -> Result<Option<MessageAttachmentContext>, ChatRunnerError> {
+        let attachments = chat::extract_attachments(&source_message.meta.0);
+        if attachments.is_empty() {
+            return Ok(None);
+        }
+
+        let message_dir = context_dir
+            .join("attachments")
+            .join(source_message.id.to_string());
+        fs::create_dir_all(&message_dir).await?;
+
+        let mut message_attachments = Vec::new();
+        for attachment in attachments {
+            let source_path = asset_dir()
+                .join(attachment.relative_path(source_message.session_id, source_message.id));
+            let file_name = source_path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_else(|| attachment.name.clone());
+            let dest_path = message_dir.join(&file_name);
+            let (local_path, too_large) =
+                Self::copy_attachment(&source_path, &dest_path, max_copy_bytes).await;
+
+            message_attachments.push(ReferenceAttachment {
+                name: attachment.name,
+                size_bytes: attachment.size_bytes,
+                kind: chat::normalize_attachment_kind(
+                    &attachment.kind,
+                    attachment.mime_type.as_deref(),
+                ),
+                mime_type: attachment.mime_type,
+                local_path,
+                too_large,
+            });
+        }
+
+        Ok(Some(MessageAttachmentContext {
+            message_id: source_message.id,
+            attachments: message_attachments,
+        }))
+    }
+
+    /// Copy an attachment into the run's context directory and return the path the agent
+    /// should read. Files over `max_bytes` are not duplicated per run: the original path is
+    /// returned with the too-large flag set. A failed copy also falls back to the original.
+    async fn copy_attachment(
+        source_path: &Path,
+        dest_path: &Path,
+        max_bytes: Option<u64>,
+    ) -> (String, bool) {
+        let original = source_path.to_string_lossy().to_string();
+        if let Some(max_bytes) = max_bytes
+            && let Ok(metadata) = fs::metadata(source_path).await
+            && metadata.len() > max_bytes
+        {
+            return (original, true);
+        }
+        if fs::copy(source_path, dest_path).await.is_ok() {
+            (dest_path.to_string_lossy().to_string(), false)
+        } else {
+            (original, false)
+        }
+    }
+
+    pub async fn build_session_agent_summaries(
+        &self,
+        session_id: Uuid,
+    ) -> Result<Vec<SessionAgentSummary>, ChatRunnerError> {
+        let session_agents =
+            ChatSessionAgent::find_all_for_session(&self.db.pool, session_id).await?;
+        if session_agents.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let agents = ChatAgent::find_all(&self.db.pool).await?;
+        let agent_map: HashMap<Uuid, ChatAgent> =
+            agents.into_iter().map(|agent| (agent.id, agent)).collect();
+
+        let mut summaries = Vec::with_capacity(session_agents.len());
+        for session_agent in session_agents {
+            let Some(agent) = agent_map.get(&session_agent.agent_id) else {
+                tracing::warn!(
+                    session_agent_id = %session_agent.id,
+                    agent_id = %session_agent.agent_id,
+                    "chat session agent missing backing agent"
+                );
+                continue;
+            };
+            let system_prompt = agent.system_prompt.trim();
+            // Extract description from first line of system prompt or use agent name
+            let description = if !system_prompt.is_empty() {
+                system_prompt
+                    .lines()
+                    .next()
+                    .map(|line| line.trim().to_string())
+                    .filter(|s| !s.is_empty())
+            } else {
+                None
+            };
+            summaries.push(SessionAgentSummary {
+                session_agent_id: session_agent.id,
+                agent_id: agent.id,
+                name: agent.name.clone(),
+                runner_type: agent.runner_type.clone(),
+                state: session_agent.state,
+                description,
+                system_prompt: if system_prompt.is_empty() {
+                    None
+                } else {
+                    Some(system_prompt.to_string())
+                },
+                tools_enabled: agent.tools_enabled.0.clone(),
+            });
+        }
+
+        Ok(summaries)
+    }
+
+    /// Every member marked running across all sessions, reconciled with the runs this runner
+    /// holds cancellation tokens for. Live runs of members not marked running are included
+    /// and flagged.
+    pub async fn list_running_agents(&self) -> Result<Vec<RunningAgent>, ChatRunnerError> {
+        let mut live: HashSet<Uuid> = self
+            .cancellation_tokens
+            .iter()
+            .map(|entry| *entry.key())
+            .collect();
+
+        let mut session_agents = ChatSessionAgent::find_all_running(&self.db.pool).await?;
+        for session_agent in &session_agents {
+            live.remove(&session_agent.id);
+        }
+        let marked_running = session_agents.len();
+        for session_agent_id in live {
+            if let Some(session_agent) =
+                ChatSessionAgent::find_by_id(&self.db.pool, session_agent_id).await?
+            {
+                session_agents.push(session_agent);
+            }
+        }
+
+        let mut running = Vec::with_capacity(session_agents.len());
+        for (index, session_agent) in session_agents.into_iter().enumerate() {
+            let is_live = self.cancellation_tokens.contains_key(&session_agent.id);
+            let discrepancy = if index >= marked_running {
+                Some(RunningAgentDiscrepancy::NotMarkedRunning)
+            } else if !is_live {
+                Some(RunningAgentDiscrepancy::NoLiveRun)
+            } else {
+                None
+            };
+            let latest_run =
+                ChatRun::find_latest_for_session_agent(&self.db.pool, session_agent.id).await?;
+            let claimed_at = self
+                .run_starts
+                .get(&session_agent.id)
+                .map(|entry| *entry.value());
+            let (started_at, run_id) = match (claimed_at, latest_run) {
+                // A run record older than the claim belongs to an earlier run.
+                (Some(claimed_at), latest_run) => (
+                    claimed_at,
+                    latest_run
+                        .filter(|run| run.created_at >= claimed_at)
+                        .map(|run| run.id),
+                ),
+                // Claimed before the runner started: the latest record is the best guess.
+                (None, Some(run)) => (run.created_at, Some(run.id)),
+                (None, None) => (session_agent.updated_at, None),
+            };
+            running.push(RunningAgent {
+                session_id: session_agent.session_id,
+                session_agent_id: session_agent.id,
+                agent_id: session_agent.agent_id,
+                state: session_agent.state,
+                run_id,
+                started_at,
+                live: is_live,
+                discrepancy,
+            });
+        }
+
+        Ok(running)
+    }
+
+    /// List the cutoff files archived in every member's context directory for this session.
+    pub async fn list_cutoffs(
+        &self,
+        session_id: Uuid,
+    ) -> Result<Vec<ChatCutoffSummary>, ChatRunnerError> {
+        let session_agents =
+            ChatSessionAgent::find_all_for_session(&self.db.pool, session_id).await?;
+
+        let mut cutoffs = Vec::new();
+        for session_agent in session_agents {
+            let context_dir = self.session_agent_context_dir(session_id, &session_agent);
+            let mut entries = match fs::read_dir(&context_dir).await {
+                Ok(entries) => entries,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err.into()),
+            };
+            while let Some(entry) = entries.next_entry().await? {
+                // Only regular files; never follow symlinks out of the context dir.
+                if !entry.file_type().await?.is_file() {
+                    continue;
+                }
+                let Some(index) =
+                    chat::parse_cutoff_file_index(&entry.file_name().to_string_lossy())
+                else {
+                    continue;
+                };
+                match Self::read_cutoff_file(&entry.path()).await {
+                    Ok(contents) => cutoffs.push(ChatCutoffSummary {
+                        index,
+                        session_agent_id: session_agent.id,
+                        cutoff_at: contents.cutoff_at,
+                        message_count: contents.message_count.unwrap_or(contents.messages.len()),
+                    }),
+                    Err(err) => tracing::warn!(
+                        session_id = %session_id,
+                        path = %entry.path().display(),
+                        error = %err,
+                        "Skipping unreadable cutoff file"
+                    ),
+                }
+            }
+        }
+
+        cutoffs.sort_by(|a, b| {
+            a.cutoff_at
+                .cmp(&b.cutoff_at)
+                .then(a.index.cmp(&b.index))
+                .then(a.session_agent_id.cmp(&b.session_agent_id))
+        });
+        Ok(cutoffs)
+    }
+
+    /// Read the archived messages of one cutoff file. When `session_agent_id` is omitted the
+    /// first member holding a file with that index is used.
+    pub async fn read_cutoff(
+        &self,
+        session_id: Uuid,
+        index: u32,
+        session_agent_id: Option<Uuid>,
+    ) -> Result<Option<ChatCutoffArchive>, ChatRunnerError> {
+        let session_agents =
+            ChatSessionAgent::find_all_for_session(&self.db.pool, session_id).await?;
+
+        for session_agent in session_agents {
+            if session_agent_id.is_some_and(|id| id != session_agent.id) {
+                continue;
+            }
+            // The file name is rebuilt from the numeric index, so the request cannot name
+            // anything outside the context directory.
+            let path = self
+                .session_agent_context_dir(session_id, &session_agent)
+                .join(chat::cutoff_file_name(index));
+            match fs::symlink_metadata(&path).await {
+                Ok(metadata) if metadata.is_file() => {}
+                Ok(_) => continue,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err.into()),
+            }
+            let contents = Self::read_cutoff_file(&path).await?;
+            return Ok(Some(ChatCutoffArchive {
+                summary: ChatCutoffSummary {
+                    index,
+                    session_agent_id: session_agent.id,
+                    cutoff_at: contents.cutoff_at,
+                    message_count: contents.message_count.unwrap_or(contents.messages.len()),
+                },
+                messages: contents.messages,
+            }));
+        }
+
+        Ok(None)
+    }
+
+    fn session_agent_context_dir(
+        &self,
+        session_id: Uuid,
+        session_agent: &ChatSessionAgent,
+    ) -> PathBuf {
+        WorkspaceLayout::for_session_agent(
+            session_id,
+            session_agent.agent_id,
+            session_agent.workspace_path.as_deref(),
+        )
+        .context_dir()
+    }
+
+    async fn read_cutoff_file(path: &Path) -> Result<CutoffFileContents, ChatRunnerError> {
+        let raw = fs::read_to_string(path).await?;
+        serde_json::from_str(&raw)
+            .map_err(|err| ChatRunnerError::Io(std::io::Error::other(err.to_string())))
+    }
+
+    /// Build the system prompt containing agent role, group members, and critical instructions.
+    /// This is separated from the user message for potential future API-level system prompt support.
+    /// With `recent_context`, the mandatory history-file read is replaced by that inline snippet.
+    fn build_system_prompt(
+        &self,
+        agent: &ChatAgent,
+        session_agents: &[SessionAgentSummary],
+        chat_history_path: &Path,
+        recent_context: Option<&str>,
+        scratch_path: &Path,
+    ) -> String {
+        let mut system = String::new();
+
+        // 1. Agent role settings
+        if !agent.system_prompt.trim().is_empty() {
+            system.push_str("[AGENT_ROLE]\n");
+            system.push_str(agent.system_prompt.trim());
+            system.push_str("\n[/AGENT_ROLE]\n\n");
+        }
+
+        // 2. Group members info (separate from AGENT_ROLE)
+        system.push_str("[GROUP_MEMBERS]\n");
+        system.push_str("Current AI members in this group:\n");
+        if session_agents.is_empty() {
+            system.push_str("- No other AI members\n");
+        } else {
+            for member in session_agents {
+                let description = member.description.as_deref().unwrap_or("AI assistant");
+                system.push_str(&format!(
+                    "- {}: {} (state: {:?})\n",
+                    member.name, description, member.state
+                ));
+            }
+        }
+        system.push_str("[/GROUP_MEMBERS]\n\n");
+
+        // 3. Message routing protocol
+        system.push_str("[MESSAGE_ROUTING]\n");
+        system.push_str(
+            "When you want to forward work to another member, include an explicit routing marker in your reply:\n",
+        );
+        system.push_str("[sendMessageTo@@member_name]\n");
+        system.push_str("Rules:\n");
+        system.push_str("- Only this marker triggers forwarding.\n");
+        system.push_str("- Both [sendMessageTo@@member_name] and [sendMessageTo@@{member_name}] are accepted.\n");
+        system.push_str(
+            "- For names containing spaces, quote the name: [sendMessageTo@@\"Member Name\"].\n",
+        );
+        system.push_str("- Plain @member text is normal content and never triggers forwarding.\n");
+        system.push_str("- member_name must exactly match a name in [GROUP_MEMBERS].\n");
+        system.push_str("- Multiple targets are allowed by adding multiple markers.\n");
+        system.push_str("[/MESSAGE_ROUTING]\n\n");
+
+        // 4. Critical instruction to read history file, or recent messages inline
+        let history_format = if chat_history_path.extension().is_some_and(|ext| ext == "md") {
+            "format: Markdown, one section per message headed by sender and time\n"
+        } else {
+            "format: JSON, containing sender and content fields\n"
+        };
+        if let Some(recent_context) = recent_context {
+            system.push_str("[RECENT_CONTEXT]\n");
+            system.push_str("Most recent group chat messages (oldest first):\n");
+            if recent_context.trim().is_empty() {
+                system.push_str("- No earlier messages\n");
+            } else {
+                system.push_str(recent_context);
+            }
+            system
+                .push_str("Read the full group chat history file only if you need more context:\n");
+            system.push_str(&format!(
+                "file_path: {}\n",
+                chat_history_path.to_string_lossy()
+            ));
+            system.push_str(history_format);
+            system.push_str("[/RECENT_CONTEXT]\n");
+        } else {
+            system.push_str("[CRITICAL_INSTRUCTION]\n");
+            system.push_str(
+                "Before doing any task, you must first read the group chat history file:\n",
+            );
+            system.push_str(&format!(
+                "file_path: {}\n",
+                chat_history_path.to_string_lossy()
+            ));
+            system.push_str(history_format);
+            system.push_str("This is mandatory: understand group context first, then respond.\n");
+            system.push_str("[/CRITICAL_INSTRUCTION]\n");
+        }
+
+        // 5. Scratch space outside the tracked workspace
+        system.push_str("\n[SCRATCH_SPACE]\n");
+        system.push_str(&format!(
+            "Put temporary files (notes, intermediate output, downloads) in: {}\n",
+            scratch_path.to_string_lossy()
+        ));
+        system.push_str("It is excluded from diffs and untracked-file capture; the path is also in $VK_CHAT_SCRATCH_PATH.\n");
+        system.push_str(
+            "Old files there are removed automatically; do not keep deliverables in it.\n",
+        );
+        system.push_str("[/SCRATCH_SPACE]\n");
+
+        system
+    }
+
+    /// Build the user message prompt (envelope, reference, attachments, message).
+    #[allow(clippy::too_many_arguments)]
+    fn build_user_prompt(
+        &self,
+        agent: &ChatAgent,
+        message: &ChatMessage,
+        message_attachments: Option<&MessageAttachmentContext>,
+        reference: Option<&ReferenceContext>,
+    ) -> String {
+        let mut prompt = String::new();
+
+        // Envelope metadata
+        prompt.push_str("[ENVELOPE]\n");
+        prompt.push_str(&format!("session_id={}\n", message.session_id));
+        let sender_handle = self.resolve_reply_handle(message);
+        prompt.push_str(&format!("from=user:{}\n", sender_handle));
+        prompt.push_str(&format!("to=agent:{}\n", agent.name));
+        prompt.push_str(&format!("message_id={}\n", message.id));
+        prompt.push_str(&format!("timestamp={}\n", message.created_at));
+        let language = message
+            .meta
+            .0
+            .get(chat::DETECTED_LANGUAGE_KEY)
+            .and_then(serde_json::Value::as_str)
+            .and_then(|code| Some((code, chat::language_name(code)?)));
+        if let Some((code, _)) = language {
+            prompt.push_str(&format!("language={code}\n"));
+        }
+        prompt.push_str("[/ENVELOPE]\n\n");
+        if let Some((_, name)) = language {
+            prompt.push_str(&format!(
+                "The message is written in {name}; reply in {name} unless asked otherwise.\n\n"
+            ));
+        }
+
+        // Reference message (if any)
+        if let Some(reference) = reference {
+            prompt.push_str("[REFERENCE_MESSAGE]\n");
+            prompt.push_str(
+                "User referenced the following historical group chat message. Prioritize it.\n",
+            );
+            prompt.push_str(&format!("reference_id={}\n", reference.message_id));
+            prompt.push_str(&format!("reference_sender={}\n", reference.sender_label));
+            prompt.push_str(&format!(
+                "reference_sender_type={:?}\n",
+                reference.sender_type
+            ));
+            prompt.push_str(&format!("reference_created_at={}\n", reference.created_at));
+            if !reference.attachments.is_empty() {
+                prompt.push_str("reference_attachments:\n");
+                for attachment in &reference.attachments {
+                    prompt.push_str(&attachment.prompt_line());
+                }
+            }
+            prompt.push_str("reference_content:\n");
+            prompt.push_str(reference.content.trim());
+            prompt.push_str("\n[/REFERENCE_MESSAGE]\n\n");
+        }
+
+        // Message attachments (if any)
+        if let Some(message_attachments) = message_attachments
+            && !message_attachments.attachments.is_empty()
+        {
+            prompt.push_str("[MESSAGE_ATTACHMENTS]\n");
+            prompt.push_str("Attachments included with this message.\n");
+            prompt.push_str(&format!("message_id={}\n", message_attachments.message_id));
+            for attachment in &message_attachments.attachments {
+                prompt.push_str(&attachment.prompt_line());
+            }
+            prompt.push_str("[/MESSAGE_ATTACHMENTS]\n\n");
+        }
+
+        // User message (simplified format: sender + content)
+        prompt.push_str("[USER_MESSAGE]\n");
+        prompt.push_str(&format!("{}: {}\n", sender_handle, message.content.trim()));
+        prompt.push_str("[/USER_MESSAGE]\n");
+
+        prompt
+    }
+
+    /// Build the full prompt by combining system prompt and user prompt.
+    /// When the estimate exceeds `max_prompt_tokens`, the referenced message, the attachment
+    /// list and then the inlined recent context are dropped; without the recent context the
+    /// agent is told to read the history file instead. The user message is always kept.
+    #[allow(clippy::too_many_arguments)]
+    fn build_prompt(
+        &self,
+        agent: &ChatAgent,
+        message: &ChatMessage,
+        context_path: &Path,
+        mut recent_context: Option<&str>,
+        scratch_path: &Path,
+        session_agents: &[SessionAgentSummary],
+        mut message_attachments: Option<&MessageAttachmentContext>,
+        mut reference: Option<&ReferenceContext>,
+        max_prompt_tokens: Option<u32>,
+    ) -> (String, Option<PromptTruncation>) {
+        let mut omitted_sections = Vec::new();
+        loop {
+            // Build system prompt with agent role, group members, and history file instruction
+            let system_prompt = self.build_system_prompt(
+                agent,
+                session_agents,
+                context_path,
+                recent_context,
+                scratch_path,
+            );
+            // Build user prompt with envelope, reference, attachments, and message
+            let user_prompt =
+                self.build_user_prompt(agent, message, message_attachments, reference);
+
+            // Combine system and user prompts
+            let mut full_prompt = system_prompt;
+            full_prompt.push('\n');
+            if !omitted_sections.is_empty() {
+                let history_hint = if recent_context.is_some() {
+                    "Read the group chat history file if you need them."
+                } else {
+                    "Read the group chat history file for details."
+                };
+                full_prompt.push_str("[PROMPT_TRUNCATED]\n");
+                full_prompt.push_str(&format!(
+                    "Omitted to fit the prompt budget: {}. {history_hint}\n",
+                    omitted_sections.join(", ")
+                ));
+                full_prompt.push_str("[/PROMPT_TRUNCATED]\n\n");
+            }
+            full_prompt.push_str(&user_prompt);
+
+            let Some(max_prompt_tokens) = max_prompt_tokens else {
+                return (full_prompt, None);
+            };
+            let estimated_tokens = Self::estimate_tokens_with_tiktoken(&full_prompt);
+            if estimated_tokens > max_prompt_tokens {
+                if reference.take().is_some() {
+                    omitted_sections.push("reference_message");
+                    continue;
+                }
+                if message_attachments.take().is_some() {
+                    omitted_sections.push("message_attachments");
+                    continue;
+                }
+                if recent_context.take().is_some() {
+                    omitted_sections.push("recent_context");
+                    continue;
+                }
+                tracing::warn!(
+                    message_id = %message.id,
+                    estimated_tokens,
+                    max_prompt_tokens,
+                    "Prompt exceeds budget after dropping optional sections"
+                );
+            }
+
+            let truncation = (!omitted_sections.is_empty()).then(|| PromptTruncation {
+                max_prompt_tokens,
+                estimated_tokens,
+                omitted_sections,
+            });
+            return (full_prompt, truncation);
+        }
+    }
+
+    async fn load_runner_config() -> ChatRunnerConfig {
+        crate::services::config::load_config_from_file(&config_path())
+            .await
+            .chat_runner
+    }
+
+    async fn load_compression_config() -> ChatCompressionConfig {
+        crate::services::config::load_config_from_file(&config_path())
+            .await
+            .chat_compression
+    }
+
+    /// Network or removable workspaces can hang filesystem calls; bound them so the
+    /// agent fails visibly instead of staying `Running` forever.
+    async fn with_workspace_timeout<T, E>(
+        timeout: std::time::Duration,
+        workspace_path: &str,
+        operation: impl std::future::Future<Output = Result<T, E>>,
+    ) -> Result<T, ChatRunnerError>
+    where
+        ChatRunnerError: From<E>,
+    {
+        match tokio::time::timeout(timeout, operation).await {
+            Ok(result) => Ok(result?),
+            Err(_) => Err(ChatRunnerError::WorkspaceUnavailable(format!(
+                "{workspace_path} did not respond within {}s",
+                timeout.as_secs()
+            ))),
+        }
+    }
+
+    /// Translate a finished agent reply in the background when the session has
+    /// translation enabled. The original content is kept; the translation is
+    /// stored under `meta.translation` and announced with `MessageUpdated`.
+    fn spawn_message_translation(&self, message: ChatMessage, workspace_path: PathBuf) {
+        let Some(agent_id) = message.sender_id else {
+            return;
+        };
+        let db = self.db.clone();
+        let sender = self.sender_for(message.session_id);
+
+        tokio::spawn(async move {
+            let (enabled, language) =
+                match ChatSession::find_translation_settings(&db.pool, message.session_id).await {
+                    Ok(settings) => settings,
+                    Err(err) => {
+                        tracing::warn!(
+                            session_id = %message.session_id,
+                            error = %err,
+                            "failed to load session translation settings"
+                        );
+                        return;
+                    }
+                };
+            if !enabled {
+                return;
+            }
+            let target_language = match language.filter(|value| !value.trim().is_empty()) {
+                Some(language) => language,
+                None => {
+                    let config =
+                        crate::services::config::load_config_from_file(&config_path()).await;
+                    chat::translation_language_for_ui(&config.language).to_string()
+                }
+            };
+            let Ok(Some(agent)) = ChatAgent::find_by_id(&db.pool, agent_id).await else {
+                return;
+            };
+            let session_profile =
+                match chat::find_session_executor_profile(&db.pool, message.session_id).await {
+                    Ok(profile) => profile,
+                    Err(err) => {
+                        tracing::warn!(
+                            session_id = %message.session_id,
+                            error = %err,
+                            "failed to load session executor profile"
+                        );
+                        None
+                    }
+                };
+
+            let translated = match chat::translate_with_agent(
+                &agent,
+                session_profile.as_ref(),
+                &message.content,
+                &target_language,
+                &workspace_path,
+            )
+            .await
+            {
+                Ok(translated) if !translated.trim().is_empty() => translated,
+                Ok(_) => return,
+                Err(err) => {
+                    tracing::warn!(
+                        message_id = %message.id,
+                        agent = %agent.name,
+                        error = %err,
+                        "chat message translation failed"
+                    );
+                    return;
+                }
+            };
+
+            let patch = serde_json::json!({
+                "translation": {
+                    "language": target_language,
+                    "content": translated.trim(),
+                }
+            });
+            if let Err(err) = ChatMessage::merge_meta(&db.pool, message.id, patch).await {
+                tracing::warn!(
+                    message_id = %message.id,
+                    error = %err,
+                    "failed to store chat message translation"
+                );
+                return;
+            }
+            if let Ok(Some(updated)) = ChatMessage::find_by_id(&db.pool, message.id).await {
+                let _ = sender.send(ChatStreamEvent::MessageUpdated { message: updated });
+            }
+        });
+    }
+
+    /// Writes the in-progress assistant text via a temp file and rename so a crash
+    /// mid-write never leaves a truncated draft behind.
+    async fn persist_assistant_draft(output_path: &Path, draft: &str) {
+        if draft.is_empty() {
+            return;
+        }
+        let temp_path = output_path.with_extension("md.partial");
+        let result = async {
+            fs::write(&temp_path, draft).await?;
+            fs::rename(&temp_path, output_path).await
+        }
+        .await;
+        if let Err(err) = result {
+            tracing::debug!(
+                output_path = %output_path.display(),
+                error = %err,
+                "failed to persist assistant draft"
+            );
+        }
+    }
+
+    /// The session agent's live persistent process, starting a new one when there is none
+    /// or the previous one was stopped, serves a different workspace or was started with
+    /// the other approval mode.
+    #[allow(clippy::too_many_arguments)]
+    async fn persistent_process_for(
+        &self,
+        session_agent: &ChatSessionAgent,
+        executor: &CodingAgent,
+        workspace_path: &str,
+        env: &ExecutionEnv,
+        approvals: Arc<ChatApprovalBridge>,
+        chat_approvals: bool,
+        spawn_timeout: Option<std::time::Duration>,
+    ) -> Result<Arc<PersistentAgentProcess>, ChatRunnerError> {
+        if let Some(process) =
+            self.reusable_persistent_process(session_agent.id, workspace_path, chat_approvals)
+        {
+            return Ok(process);
+        }
+
+        let resume_session_id = if session_agent.state != ChatSessionAgentState::Dead {
+            session_agent.agent_session_id.as_deref()
+        } else {
+            None
+        };
+        let spawn = executor.spawn_persistent(Path::new(workspace_path), resume_session_id, env);
+        let child = match spawn_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, spawn)
+                .await
+                .map_err(|_| ChatRunnerError::SpawnTimeout(timeout.as_secs()))??,
+            None => spawn.await?,
+        };
+        tracing::info!(
+            session_agent_id = %session_agent.id,
+            resumed = resume_session_id.is_some(),
+            "started persistent executor process"
+        );
+
+        Ok(self.register_persistent_process(
+            session_agent.id,
+            child,
+            workspace_path,
+            approvals,
+            chat_approvals,
+        ))
+    }
+
+    /// The session agent's persistent process if it can serve the next run. A stopped
+    /// process, or one serving another workspace or approval mode, is shut down instead.
+    fn reusable_persistent_process(
+        &self,
+        session_agent_id: Uuid,
+        workspace_path: &str,
+        chat_approvals: bool,
+    ) -> Option<Arc<PersistentAgentProcess>> {
+        let process = self
+            .persistent_processes
+            .get(&session_agent_id)
+            .map(|entry| entry.value().clone())?;
+        if !process.cancel.is_cancelled()
+            && process.workspace_path == workspace_path
+            && process.chat_approvals == chat_approvals
+        {
+            return Some(process);
+        }
+        self.shutdown_persistent_process(session_agent_id);
+        None
+    }
+
+    /// Track a freshly spawned persistent process until it exits or is stopped.
+    fn register_persistent_process(
+        &self,
+        session_agent_id: Uuid,
+        child: PersistentChild,
+        workspace_path: &str,
+        approvals: Arc<ChatApprovalBridge>,
+        chat_approvals: bool,
+    ) -> Arc<PersistentAgentProcess> {
+        let PersistentChild {
+            mut child,
+            prompts,
+            turns,
+            cancel,
+        } = child;
+        let output = Arc::new(Mutex::new(None));
+        Self::spawn_persistent_output_forwarders(&mut child, output.clone());
+        let process = Arc::new(PersistentAgentProcess {
+            workspace_path: workspace_path.to_string(),
+            prompts,
+            turns: Mutex::new(turns),
+            cancel,
+            output,
+            approvals,
+            chat_approvals,
+        });
+        self.persistent_processes
+            .insert(session_agent_id, process.clone());
+        self.spawn_persistent_process_watcher(child, process.clone(), session_agent_id);
+        process
+    }
+
+    /// Hand the next prompt to a persistent process and finish the run's log once the
+    /// process reports the end of that turn. A turn that outlasts `duration_budget` stops
+    /// the process, since a single turn cannot be interrupted on its own.
+    #[allow(clippy::too_many_arguments)]
+    async fn start_persistent_turn(
+        &self,
+        process: Arc<PersistentAgentProcess>,
+        output: PersistentRunOutput,
+        run_id: Uuid,
+        prompt: String,
+        failed_flag: Arc<AtomicBool>,
+        session_agent_id: Uuid,
+        mut duration_budget: Option<RunDurationBudget>,
+    ) {
+        let msg_store = output.msg_store.clone();
+        *process.approvals.run_id.lock().await = run_id;
+        *process.output.lock().await = Some(output);
+
+        if process.prompts.send(prompt).is_err() {
+            self.shutdown_persistent_process(session_agent_id);
+            failed_flag.store(true, Ordering::Relaxed);
+            msg_store.push(LogMsg::Stderr(
+                "persistent executor process is no longer running".to_string(),
+            ));
+            msg_store.push_finished();
+            return;
+        }
+        self.cancellation_tokens
+            .insert(session_agent_id, process.cancel.clone());
+
+        let cancellation_tokens = self.cancellation_tokens.clone();
+        let pending_approvals = self.pending_approvals.clone();
+        let persistent_processes = self.persistent_processes.clone();
+        tokio::spawn(async move {
+            let mut turns = process.turns.lock().await;
+            let mut give_up_at: Option<tokio::time::Instant> = None;
+            let result = loop {
+                tokio::select! {
+                    result = turns.recv() => break result,
+                    _ = tokio::time::sleep(RUN_DURATION_CHECK_INTERVAL) => {}
+                }
+                let now = tokio::time::Instant::now();
+                if let Some(deadline) = give_up_at {
+                    if now >= deadline {
+                        // The stopped process never reported the turn; finish it anyway.
+                        break None;
+                    }
+                    continue;
+                }
+                if let Some(budget) = duration_budget.as_mut()
+                    && budget.tick(now, pending_approvals.contains_key(&run_id))
+                {
+                    let limit_secs = budget.limit.as_secs();
+                    tracing::warn!(
+                        session_agent_id = %session_agent_id,
+                        limit_secs,
+                        "persistent chat run exceeded its maximum duration; stopping process"
+                    );
+                    msg_store.push(LogMsg::Stderr(format!(
+                        "max duration exceeded: run exceeded {limit_secs}s"
+                    )));
+                    persistent_processes.remove_if(&session_agent_id, |_, current| {
+                        Arc::ptr_eq(current, &process)
+                    });
+                    process.cancel.cancel();
+                    give_up_at = Some(now + budget.kill_grace);
+                }
+            };
+            drop(turns);
+            if !matches!(result, Some(ExecutorExitResult::Success))
+                || duration_budget
+                    .as_ref()
+                    .is_some_and(|budget| budget.exceeded.load(Ordering::Relaxed))
+            {
+                failed_flag.store(true, Ordering::Relaxed);
+            }
+            // The process keeps its token; only this run's registration goes away.
+            cancellation_tokens.remove(&session_agent_id);
+            msg_store.push_finished();
+        });
+    }
+
+    /// Relay a persistent process's stdout/stderr to whichever run it is currently serving.
+    fn spawn_persistent_output_forwarders(
+        child: &mut command_group::AsyncGroupChild,
+        output: Arc<Mutex<Option<PersistentRunOutput>>>,
+    ) {
+        let stdout = child
+            .inner()
+            .stdout
+            .take()
+            .expect("chat runner missing stdout");
+        let stderr = child
+            .inner()
+            .stderr
+            .take()
+            .expect("chat runner missing stderr");
+
+        let stdout_output = output.clone();
+        tokio::spawn(async move {
+            let mut stream = ReaderStream::new(stdout);
+            let mut decoder = Utf8ChunkDecoder::default();
+            let mut redactor = PersistentOutputRedactor::new(LogMsg::Stdout);
+            while let Some(chunk) = stream.next().await {
+                let Some(target) = stdout_output.lock().await.clone() else {
+                    continue;
+                };
+                match chunk {
+                    Ok(bytes) => {
+                        let text = redactor.redact(&target, &decoder.decode(&bytes)).await;
+                        if text.is_empty() {
+                            continue;
+                        }
+                        target.msg_store.push(LogMsg::Stdout(text.clone()));
+                        target.raw_log_file.lock().await.write(&text).await;
+                    }
+                    Err(err) => {
+                        target
+                            .msg_store
+                            .push(LogMsg::Stderr(format!("stdout error: {err}")));
+                    }
+                }
+            }
+        });
+
+        tokio::spawn(async move {
+            let mut stream = ReaderStream::new(stderr);
+            let mut decoder = Utf8ChunkDecoder::default();
+            let mut debouncer = StderrLineDebouncer::new();
+            let mut redactor = PersistentOutputRedactor::new(LogMsg::Stderr);
+            while let Some(chunk) = stream.next().await {
+                let Some(target) = output.lock().await.clone() else {
+                    continue;
+                };
+                match chunk {
+                    Ok(bytes) => {
+                        let text = redactor.redact(&target, &decoder.decode(&bytes)).await;
+                        if text.is_empty() {
+                            continue;
+                        }
+                        if let Some(sink) = target.stderr_events.as_ref() {
+                            for line in debouncer.push(&text, tokio::time::Instant::now()) {
+                                sink.send(line);
+                            }
+                        }
+                        target.msg_store.push(LogMsg::Stderr(text.clone()));
+                        target.raw_log_file.lock().await.write(&text).await;
+                    }
+                    Err(err) => {
+                        target
+                            .msg_store
+                            .push(LogMsg::Stderr(format!("stderr error: {err}")));
+                    }
+                }
+            }
+        });
+    }
+
+    /// Wait for a persistent process to exit, killing it if it outlives its stop grace,
+    /// and forget it so the next run starts a fresh one.
+    fn spawn_persistent_process_watcher(
+        &self,
+        mut child: command_group::AsyncGroupChild,
+        process: Arc<PersistentAgentProcess>,
+        session_agent_id: Uuid,
+    ) {
+        let persistent_processes = self.persistent_processes.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                status = child.wait() => {
+                    tracing::info!(
+                        session_agent_id = %session_agent_id,
+                        ?status,
+                        "persistent executor process exited"
+                    );
+                }
+                _ = process.cancel.cancelled() => {
+                    if tokio::time::timeout(PERSISTENT_PROCESS_STOP_GRACE, child.wait())
+                        .await
+                        .is_err()
+                    {
+                        let _ = child.kill().await;
+                    }
+                }
+            }
+            persistent_processes.remove_if(&session_agent_id, |_, current| {
+                Arc::ptr_eq(current, &process)
+            });
+        });
+    }
+
+    /// Stop a session agent's persistent process, if it has one.
+    pub fn shutdown_persistent_process(&self, session_agent_id: Uuid) {
+        if let Some((_, process)) = self.persistent_processes.remove(&session_agent_id) {
+            process.cancel.cancel();
+        }
+    }
+
+    fn spawn_log_forwarders(
+        &self,
+        child: &mut command_group::AsyncGroupChild,
+        msg_store: Arc<MsgStore>,
+        raw_log_file: Arc<Mutex<CappedRunLog>>,
+        stderr_events: Option<StderrEventSink>,
+        mut stdout_tee: Option<StdoutTee>,
+        redaction_rules: &[RedactionRule],
+    ) {
+        let stdout = child
+            .inner()
+            .stdout
+            .take()
+            .expect("chat runner missing stdout");
+        let stderr = child
+            .inner()
+            .stderr
+            .take()
+            .expect("chat runner missing stderr");
+
+        let stdout_store = msg_store.clone();
+        let stdout_log = raw_log_file.clone();
+        let mut stdout_redactor =
+            RawOutputRedactor::new(OutputRedactor::new(redaction_rules.iter()));
+        tokio::spawn(async move {
+            let mut stream = ReaderStream::new(stdout);
+            let mut decoder = Utf8ChunkDecoder::default();
+            loop {
+                let text = match stream.next().await {
+                    Some(Ok(bytes)) => stdout_redactor.push(&decoder.decode(&bytes)),
+                    Some(Err(err)) => {
+                        stdout_store.push(LogMsg::Stderr(format!("stdout error: {err}")));
+                        continue;
+                    }
+                    None => match decoder.finish() {
+                        Some(rest) => stdout_redactor.push(&rest),
+                        None => match stdout_redactor.finish() {
+                            Some(rest) => rest,
+                            None => break,
+                        },
+                    },
+                };
+                if text.is_empty() {
+                    continue;
+                }
+                stdout_store.push(LogMsg::Stdout(text.clone()));
+                stdout_log.lock().await.write(&text).await;
+                if let Some(tee) = stdout_tee.as_mut() {
+                    tee.write(&text).await;
+                }
+            }
+            if let Some(tee) = stdout_tee {
+                tee.close().await;
+            }
+        });
+
+        let stderr_store = msg_store.clone();
+        let stderr_log = raw_log_file.clone();
+        let mut stderr_redactor =
+            RawOutputRedactor::new(OutputRedactor::new(redaction_rules.iter()));
+        tokio::spawn(async move {
+            let mut stream = ReaderStream::new(stderr);
+            let mut decoder = Utf8ChunkDecoder::default();
+            let mut debouncer = StderrLineDebouncer::new();
+            loop {
+                let text = match stream.next().await {
+                    Some(Ok(bytes)) => stderr_redactor.push(&decoder.decode(&bytes)),
+                    Some(Err(err)) => {
+                        stderr_store.push(LogMsg::Stderr(format!("stderr error: {err}")));
+                        continue;
+                    }
+                    None => match decoder.finish() {
+                        Some(rest) => stderr_redactor.push(&rest),
+                        None => match stderr_redactor.finish() {
+                            Some(rest) => rest,
+                            None => break,
+                        },
+                    },
+                };
+                if text.is_empty() {
+                    continue;
+                }
+                if let Some(sink) = stderr_events.as_ref() {
+                    for line in debouncer.push(&text, tokio::time::Instant::now()) {
+                        sink.send(line);
+                    }
+                }
+                stderr_store.push(LogMsg::Stderr(text.clone()));
+                stderr_log.lock().await.write(&text).await;
+            }
+            if let Some(sink) = stderr_events.as_ref()
+                && let Some(line) = debouncer.finish(tokio::time::Instant::now())
+            {
+                sink.send(line);
+            }
+        });
+    }
+
+    fn parse_token_usage_from_stdout_line(
+        line: &str,
+        max_line_bytes: usize,
+    ) -> Option<TokenUsageInfo> {
+        if !Self::may_contain_token_usage(line, max_line_bytes) {
+            return None;
+        }
+        let value: serde_json::Value = serde_json::from_str(line).ok()?;
+        match TokenUsageFormat::detect(&value)? {
+            TokenUsageFormat::Plain => Self::parse_plain_token_usage(&value),
+            TokenUsageFormat::CodexTokenCount => Self::parse_codex_token_count(&value),
+            TokenUsageFormat::NestedUsage => Self::parse_nested_usage(&value),
+        }
+    }
+
+    /// Cheap filter run before the full JSON parse: every stdout line passes through here,
+    /// and most are either not JSON objects or carry no usage data. Lines over
+    /// `max_line_bytes` are skipped; usage events are small and very long lines are
+    /// typically file dumps or tool output.
+    fn may_contain_token_usage(line: &str, max_line_bytes: usize) -> bool {
+        line.len() <= max_line_bytes
+            && line.trim_start().starts_with('{')
+            && TOKEN_USAGE_LINE_MARKERS
+                .iter()
+                .any(|marker| line.contains(marker))
+    }
+
+    /// `{"type":"token_usage","total_tokens":..,"model_context_window":..}`
+    fn parse_plain_token_usage(value: &serde_json::Value) -> Option<TokenUsageInfo> {
+        Some(TokenUsageInfo {
+            total_tokens: json_u32(value, "total_tokens")?,
+            model_context_window: json_u32(value, "model_context_window")?,
+            input_tokens: json_u32(value, "input_tokens"),
+            output_tokens: json_u32(value, "output_tokens"),
+            cache_read_tokens: json_u32(value, "cache_read_tokens"),
+            is_estimated: false,
+        })
+    }
+
+    /// `{"method":"codex/event/token_count","params":{"msg":{"info":{"last_token_usage":{..}}}}}`
+    fn parse_codex_token_count(value: &serde_json::Value) -> Option<TokenUsageInfo> {
+        let info = value
+            .get("params")
+            .and_then(|v| v.get("msg"))
+            .and_then(|v| v.get("info"))?;
+        let usage = info.get("last_token_usage")?;
+
+        Some(TokenUsageInfo {
+            total_tokens: json_u32(usage, "total_tokens")?,
+            model_context_window: json_u32(info, "model_context_window").unwrap_or(0),
+            input_tokens: json_u32(usage, "input_tokens"),
+            output_tokens: json_u32(usage, "output_tokens"),
+            cache_read_tokens: json_u32(usage, "cached_input_tokens"),
+            is_estimated: false,
+        })
+    }
+
+    /// A `usage` object at the top level or under `message`, using either
+    /// `input_tokens`/`output_tokens` or `prompt_tokens`/`completion_tokens` naming.
+    fn parse_nested_usage(value: &serde_json::Value) -> Option<TokenUsageInfo> {
+        let usage = TokenUsageFormat::nested_usage_object(value)?;
+        let input_tokens =
+            json_u32(usage, "input_tokens").or_else(|| json_u32(usage, "prompt_tokens"));
+        let output_tokens =
+            json_u32(usage, "output_tokens").or_else(|| json_u32(usage, "completion_tokens"));
+        let cache_read_tokens = json_u32(usage, "cache_read_input_tokens")
+            .or_else(|| json_u32(usage, "cached_input_tokens"))
+            .or_else(|| {
+                usage
+                    .get("prompt_tokens_details")
+                    .and_then(|details| json_u32(details, "cached_tokens"))
+            });
+        let cache_creation_tokens = json_u32(usage, "cache_creation_input_tokens");
+
+        let total_tokens = json_u32(usage, "total_tokens").unwrap_or_else(|| {
+            [
+                input_tokens,
+                output_tokens,
+                cache_read_tokens,
+                cache_creation_tokens,
+            ]
+            .into_iter()
+            .flatten()
+            .fold(0u32, u32::saturating_add)
+        });
+        if total_tokens == 0 {
+            return None;
+        }
+
+        Some(TokenUsageInfo {
+            total_tokens,
+            model_context_window: json_u32(value, "model_context_window").unwrap_or(0),
+            input_tokens,
+            output_tokens,
+            cache_read_tokens,
+            is_estimated: false,
+        })
+    }
+
+    fn update_token_usage_from_stdout_chunk(
+        stdout_line_buffer: &mut String,
+        last_token_usage: &mut Option<TokenUsageInfo>,
+        chunk: &str,
+        max_line_bytes: usize,
+    ) {
+        stdout_line_buffer.push_str(chunk);
+
+        while let Some(newline_index) = stdout_line_buffer.find('\n') {
+            let mut line: String = stdout_line_buffer.drain(..=newline_index).collect();
+            if line.ends_with('\n') {
+                line.pop();
+            }
+            if line.ends_with('\r') {
+                line.pop();
+            }
+            if line.is_empty() {
+                continue;
+            }
+            if let Some(usage) = Self::parse_token_usage_from_stdout_line(&line, max_line_bytes) {
+                *last_token_usage = Some(usage);
+            }
+        }
+    }
+
+    fn flush_token_usage_buffer(
+        stdout_line_buffer: &mut String,
+        last_token_usage: &mut Option<TokenUsageInfo>,
+        max_line_bytes: usize,
+    ) {
+        if stdout_line_buffer.is_empty() {
+            return;
+        }
+        let line = stdout_line_buffer.trim_end_matches(['\n', '\r']);
+        if !line.is_empty()
+            && let Some(usage) = Self::parse_token_usage_from_stdout_line(line, max_line_bytes)
+        {
+            *last_token_usage = Some(usage);
+        }
+        stdout_line_buffer.clear();
+    }
+
+    /// 浣跨敤tiktoken浼扮畻鏂囨湰鐨則oken鏁伴噺
+    pub fn estimate_tokens_with_tiktoken(text: &str) -> u32 {
+        chat_history_file::estimate_text_tokens(text)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn process_stream_patch(
+        patch: json_patch::Patch,
+        delta_batcher: &mut AgentDeltaBatcher,
+        last_content: &mut HashMap<usize, String>,
+        latest_assistant: &mut String,
+        last_token_usage: &mut Option<TokenUsageInfo>,
+        citations: &mut Vec<Citation>,
+        redactor: &mut OutputRedactor,
+    ) {
+        if let Some((index, entry)) = extract_normalized_entry_from_patch(&patch) {
+            let stream_type = match &entry.entry_type {
+                NormalizedEntryType::AssistantMessage => Some(ChatStreamDeltaType::Assistant),
+                NormalizedEntryType::Thinking => Some(ChatStreamDeltaType::Thinking),
+                NormalizedEntryType::TokenUsageInfo(usage) => {
+                    *last_token_usage = Some(usage.clone());
+                    None
+                }
+                NormalizedEntryType::Citation(citation) => {
+                    let key = citation.dedupe_key();
+                    if !citations
+                        .iter()
+                        .any(|existing| existing.dedupe_key() == key)
+                    {
+                        citations.push(citation.clone());
+                    }
+                    None
+                }
+                _ => None,
+            };
+
+            if let Some(stream_type) = stream_type {
+                // Redacting the whole entry keeps a secret out of both the deltas and the
+                // final reply, unless it was streamed before enough of it arrived to match.
+                let current = redactor.redact(&entry.content).into_owned();
+                let previous = last_content.get(&index).cloned().unwrap_or_default();
+                if current == previous && delta_batcher.dedupe_identical {
+                    // The executor re-emitted an unchanged entry; there is nothing to send.
+                    return;
+                }
+                let (delta, is_delta) = if current.starts_with(&previous) {
+                    (current[previous.len()..].to_string(), true)
+                } else {
+                    (current.clone(), false)
+                };
+
+                last_content.insert(index, current.clone());
+                if matches!(stream_type, ChatStreamDeltaType::Assistant) {
+                    *latest_assistant = current.clone();
+                }
+
+                if !delta.is_empty() {
+                    delta_batcher.push(stream_type, delta, is_delta);
+                }
+            }
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn spawn_stream_bridge(
+        &self,
+        msg_store: Arc<MsgStore>,
+        session_id: Uuid,
+        agent_id: Uuid,
+        session_agent_id: Uuid,
+        run_id: Uuid,
+        output_path: PathBuf,
+        meta_path: PathBuf,
+        workspace_path: PathBuf,
+        additional_workspaces: Vec<AdditionalWorkspace>,
+        run_dir: PathBuf,
+        reply_handle: Option<String>,
+        failed_flag: Arc<AtomicBool>,
+        chain_depth: u32,
+        context_compacted: bool,
+        compression_warning: Option<chat::CompressionWarning>,
+        prompt_truncation: Option<PromptTruncation>,
+        runner: ChatRunner,
+        source_message_id: Uuid,
+        agent_name: String,
+        forwarded_from: Option<ForwardedFrom>,
+        auto_commit: bool,
+        diff_base: GitDiffBase,
+        cost_per_million_tokens_cents: u32,
+        workspace_io_timeout: std::time::Duration,
+        mut delta_batcher: AgentDeltaBatcher,
+        mut idle_timeout: Option<std::time::Duration>,
+        kill_requested: Arc<AtomicBool>,
+        max_duration_exceeded: Arc<AtomicBool>,
+        mut redactor: OutputRedactor,
+        max_output_tokens: Option<u32>,
+        max_token_usage_line_bytes: usize,
+    ) {
+        let db = self.db.clone();
+        let sender = self.sender_for(session_id);
+
+        tokio::spawn(async move {
+            let mut stream = msg_store.history_plus_stream();
+            let mut last_content: HashMap<usize, String> = HashMap::new();
+            let mut latest_assistant = String::new();
+            let mut agent_session_id: Option<String> = None;
+            let mut agent_message_id: Option<String> = None;
+            let mut last_token_usage: Option<TokenUsageInfo> = None;
+            let mut citations: Vec<Citation> = Vec::new();
+            let mut idle_timed_out = false;
+            let mut output_truncated = false;
+            // A token spans at least one byte, so shorter replies cannot exceed the cap.
+            let mut next_output_check_len = max_output_tokens.unwrap_or_default() as usize;
+            let mut stdout_line_buffer = String::new();
+
+            let mut last_activity = tokio::time::Instant::now();
+            let mut idle_cancel_deadline: Option<tokio::time::Instant> = None;
+            // Partial assistant text is flushed to output.md periodically so it survives a crash.
+            let mut draft_flush_deadline: Option<tokio::time::Instant> = None;
+            loop {
+                let batch_deadline = delta_batcher.deadline();
+                let idle_deadline = idle_cancel_deadline
+                    .or_else(|| idle_timeout.map(|timeout| last_activity + timeout));
+                let next = tokio::select! {
+                    next = stream.next() => next,
+                    _ = tokio::time::sleep_until(
+                        batch_deadline.unwrap_or_else(tokio::time::Instant::now)
+                    ), if batch_deadline.is_some() => {
+                        delta_batcher.flush();
+                        continue;
+                    }
+                    _ = tokio::time::sleep_until(
+                        draft_flush_deadline.unwrap_or_else(tokio::time::Instant::now)
+                    ), if draft_flush_deadline.is_some() => {
+                        draft_flush_deadline = None;
+                        Self::persist_assistant_draft(&output_path, &latest_assistant).await;
+                        continue;
+                    }
+                    _ = tokio::time::sleep_until(
+                        idle_deadline.unwrap_or_else(tokio::time::Instant::now)
+                    ), if idle_deadline.is_some() => {
+                        if idle_cancel_deadline.is_none()
+                            && runner.pending_approvals.contains_key(&run_id)
+                        {
+                            // Waiting on the user is not executor silence.
+                            last_activity = tokio::time::Instant::now();
+                            continue;
+                        }
+                        if idle_cancel_deadline.is_none() {
+                            // First expiry: cancel the run and give the executor a grace
+                            // period to exit before finishing the stream ourselves.
+                            let idle_secs = idle_timeout.unwrap_or_default().as_secs();
+                            tracing::warn!(
+                                run_id = %run_id,
+                                session_agent_id = %session_agent_id,
+                                idle_secs,
+                                "chat run produced no output; cancelling on idle timeout"
+                            );
+                            idle_timed_out = true;
+                            failed_flag.store(true, Ordering::Relaxed);
+                            msg_store.push(LogMsg::Stderr(format!(
+                                "idle timeout: no output for {idle_secs}s"
+                            )));
+                            if let Some(token) = runner.cancellation_tokens.get(&session_agent_id) {
+                                token.cancel();
+                            }
+                            idle_cancel_deadline =
+                                Some(tokio::time::Instant::now() + IDLE_CANCEL_GRACE);
+                        } else {
+                            // The executor ignored the cancellation; its exit watcher kills
+                            // the process group. Persistent processes are killed by their
+                            // own watcher once their cancelled token's grace runs out.
+                            kill_requested.store(true, Ordering::Relaxed);
+                            runner.cancellation_tokens.remove(&session_agent_id);
+                            msg_store.push_finished();
+                            idle_cancel_deadline = None;
+                            idle_timeout = None;
+                        }
+                        continue;
+                    }
+                };
+                last_activity = tokio::time::Instant::now();
+                let Some(item) = next else {
+                    delta_batcher.flush();
+                    if draft_flush_deadline.is_some() {
+                        Self::persist_assistant_draft(&output_path, &latest_assistant).await;
+                    }
+                    break;
+                };
+                match item {
+                    Ok(LogMsg::SessionId(session_id_value)) => {
+                        if agent_session_id.as_deref() != Some(&session_id_value) {
+                            agent_session_id = Some(session_id_value.clone());
+                            let _ = ChatSessionAgent::update_agent_session_id(
+                                &db.pool,
+                                session_agent_id,
+                                Some(session_id_value),
+                            )
+                            .await;
+                        }
+                    }
+                    Ok(LogMsg::MessageId(message_id_value)) => {
+                        if agent_message_id.as_deref() != Some(&message_id_value) {
+                            agent_message_id = Some(message_id_value.clone());
+                            let _ = ChatSessionAgent::update_agent_message_id(
+                                &db.pool,
+                                session_agent_id,
+                                Some(message_id_value),
+                            )
+                            .await;
+                        }
+                    }
+                    Ok(LogMsg::Stdout(chunk)) => {
+                        Self::update_token_usage_from_stdout_chunk(
+                            &mut stdout_line_buffer,
+                            &mut last_token_usage,
+                            &chunk,
+                            max_token_usage_line_bytes,
+                        );
+                    }
+                    Ok(LogMsg::JsonPatch(patch)) => {
+                        Self::process_stream_patch(
+                            patch,
+                            &mut delta_batcher,
+                            &mut last_content,
+                            &mut latest_assistant,
+                            &mut last_token_usage,
+                            &mut citations,
+                            &mut redactor,
+                        );
+                        if draft_flush_deadline.is_none() && !latest_assistant.is_empty() {
+                            draft_flush_deadline =
+                                Some(tokio::time::Instant::now() + ASSISTANT_DRAFT_FLUSH_INTERVAL);
+                        }
+                        if let Some(limit) = max_output_tokens
+                            && !output_truncated
+                            && latest_assistant.len() >= next_output_check_len
+                        {
+                            next_output_check_len =
+                                latest_assistant.len() + OUTPUT_TOKEN_CHECK_STEP_BYTES;
+                            let output_tokens =
+                                Self::estimate_tokens_with_tiktoken(&latest_assistant);
+                            if output_tokens > limit {
+                                // Stop the run like an idle timeout, but keep its executor
+                                // session so the reply can be continued.
+                                tracing::info!(
+                                    run_id = %run_id,
+                                    session_agent_id = %session_agent_id,
+                                    max_output_tokens = limit,
+                                    "chat run reached its output cap; cancelling"
+                                );
+                                output_truncated = true;
+                                msg_store.push(LogMsg::Stderr(format!(
+                                    "max output reached: reply exceeded {limit} tokens"
+                                )));
+                                if let Some(token) =
+                                    runner.cancellation_tokens.get(&session_agent_id)
+                                {
+                                    token.cancel();
+                                }
+                                if idle_cancel_deadline.is_none() {
+                                    idle_cancel_deadline =
+                                        Some(tokio::time::Instant::now() + IDLE_CANCEL_GRACE);
+                                }
+                            }
+                        }
+                    }
+                    Ok(LogMsg::Finished) => {
+                        Self::flush_token_usage_buffer(
+                            &mut stdout_line_buffer,
+                            &mut last_token_usage,
+                            max_token_usage_line_bytes,
+                        );
+
+                        // Drain tail messages briefly to handle out-of-order `Finished` vs stdout/json patches.
+                        let drain_deadline =
+                            tokio::time::Instant::now() + std::time::Duration::from_millis(350);
+                        loop {
+                            let now = tokio::time::Instant::now();
+                            if now >= drain_deadline {
+                                break;
+                            }
+                            let remaining = drain_deadline.duration_since(now);
+                            let Ok(next_item) =
+                                tokio::time::timeout(remaining, stream.next()).await
+                            else {
+                                break;
+                            };
+                            let Some(next_item) = next_item else {
+                                break;
+                            };
+                            match next_item {
+                                Ok(LogMsg::SessionId(session_id_value)) => {
+                                    if agent_session_id.as_deref() != Some(&session_id_value) {
+                                        agent_session_id = Some(session_id_value.clone());
+                                        let _ = ChatSessionAgent::update_agent_session_id(
+                                            &db.pool,
+                                            session_agent_id,
+                                            Some(session_id_value),
+                                        )
+                                        .await;
+                                    }
+                                }
+                                Ok(LogMsg::MessageId(message_id_value)) => {
+                                    if agent_message_id.as_deref() != Some(&message_id_value) {
+                                        agent_message_id = Some(message_id_value.clone());
+                                        let _ = ChatSessionAgent::update_agent_message_id(
+                                            &db.pool,
+                                            session_agent_id,
+                                            Some(message_id_value),
+                                        )
+                                        .await;
+                                    }
+                                }
+                                Ok(LogMsg::Stdout(chunk)) => {
+                                    Self::update_token_usage_from_stdout_chunk(
+                                        &mut stdout_line_buffer,
+                                        &mut last_token_usage,
+                                        &chunk,
+                                        max_token_usage_line_bytes,
+                                    );
+                                }
+                                Ok(LogMsg::JsonPatch(patch)) => {
+                                    Self::process_stream_patch(
+                                        patch,
+                                        &mut delta_batcher,
+                                        &mut last_content,
+                                        &mut latest_assistant,
+                                        &mut last_token_usage,
+                                        &mut citations,
+                                        &mut redactor,
+                                    );
+                                }
+                                _ => {}
+                            }
+                        }
+
+                        Self::flush_token_usage_buffer(
+                            &mut stdout_line_buffer,
+                            &mut last_token_usage,
+                            max_token_usage_line_bytes,
+                        );
+                        delta_batcher.flush();
+
+                        let _ = fs::write(&output_path, &latest_assistant).await;
+
+                        if output_truncated {
+                            // The cancellation marks the run failed; a capped reply is not.
+                            failed_flag.store(false, Ordering::Relaxed);
+                        }
+
+                        let git_capture = tokio::time::timeout(workspace_io_timeout, async {
+                            let additional_workspaces = ChatRunner::separately_tracked_workspaces(
+                                &workspace_path,
+                                &additional_workspaces,
+                            )
+                            .await;
+                            let diff_info = ChatRunner::capture_git_diff(
+                                &workspace_path,
+                                &additional_workspaces,
+                                &run_dir,
+                                &diff_base,
+                            )
+                            .await;
+                            let mut untracked_files = ChatRunner::capture_untracked_files(
+                                &workspace_path,
+                                None,
+                                &run_dir,
+                            )
+                            .await;
+                            for workspace in &additional_workspaces {
+                                untracked_files.extend(
+                                    ChatRunner::capture_untracked_files(
+                                        &workspace.path,
+                                        Some(&workspace.label),
+                                        &run_dir,
+                                    )
+                                    .await,
+                                );
+                            }
+                            (diff_info, untracked_files)
+                        })
+                        .await;
+                        let workspace_unavailable = git_capture.is_err();
+                        let (diff_info, untracked_files) = git_capture.unwrap_or_default();
+                        if workspace_unavailable {
+                            failed_flag.store(true, Ordering::Relaxed);
+                            runner
+                                .report_mention_failure(
+                                    session_id,
+                                    source_message_id,
+                                    &agent_name,
+                                    Some(agent_id),
+                                    format!(
+                                        "workspace unavailable: git capture in {} did not respond within {}s",
+                                        workspace_path.display(),
+                                        workspace_io_timeout.as_secs()
+                                    ),
+                                )
+                                .await;
+                        }
+                        let failure_reason = if idle_timed_out {
+                            Some("idle timeout")
+                        } else if max_duration_exceeded.load(Ordering::Relaxed) {
+                            Some("max duration exceeded")
+                        } else {
+                            None
+                        };
+                        if let Some(reason) = failure_reason {
+                            runner
+                                .report_mention_failure(
+                                    session_id,
+                                    source_message_id,
+                                    &agent_name,
+                                    Some(agent_id),
+                                    reason.to_string(),
+                                )
+                                .await;
+                        }
+                        let failed = failed_flag.load(Ordering::Relaxed);
+
+                        let auto_commit_result = if auto_commit && !failed && diff_info.is_some() {
+                            let commit_message =
+                                Self::build_auto_commit_message(&agent_name, run_id);
+                            Some(
+                                tokio::time::timeout(
+                                    workspace_io_timeout,
+                                    ChatRunner::auto_commit_changes(
+                                        &workspace_path,
+                                        &commit_message,
+                                    ),
+                                )
+                                .await
+                                .unwrap_or_else(|_| {
+                                    Err("git commit did not respond in time".to_string())
+                                }),
+                            )
+                        } else {
+                            None
+                        };
+
+                        if failed {
+                            agent_session_id = None;
+                            agent_message_id = None;
+                            let _ = ChatSessionAgent::update_agent_session_id(
+                                &db.pool,
+                                session_agent_id,
+                                None,
+                            )
+                            .await;
+                            let _ = ChatSessionAgent::update_agent_message_id(
+                                &db.pool,
+                                session_agent_id,
+                                None,
+                            )
+                            .await;
+                        }
+
+                        let mut meta = serde_json::json!({
+                            "run_id": run_id,
+                            "session_id": session_id,
+                            "session_agent_id": session_agent_id,
+                            "agent_id": agent_id,
+                            "agent_session_id": agent_session_id,
+                            "agent_message_id": agent_message_id,
+                            "finished_at": Utc::now().to_rfc3339(),
+                            "chain_depth": chain_depth + 1,
+                            "failed": failed,
+                        });
+
+                        // 濡傛灉娌℃湁token_usage锛屼娇鐢╰iktoken浼扮畻
+                        let token_usage = if let Some(ref usage) = last_token_usage {
+                            usage.clone()
+                        } else {
+                            // 璇诲彇input prompt杩涜浼扮畻
+                            let input_path = run_dir.join("input.txt");
+                            let prompt_content =
+                                fs::read_to_string(&input_path).await.unwrap_or_default();
+                            let estimated_input =
+                                Self::estimate_tokens_with_tiktoken(&prompt_content);
+                            let estimated_output =
+                                Self::estimate_tokens_with_tiktoken(&latest_assistant);
+                            TokenUsageInfo {
+                                total_tokens: estimated_input + estimated_output,
+                                model_context_window: 0,
+                                input_tokens: Some(estimated_input),
+                                output_tokens: Some(estimated_output),
+                                cache_read_tokens: None,
+                                is_estimated: true,
+                            }
+                        };
+
+                        let estimated_cost = Self::estimate_run_cost(
+                            token_usage.total_tokens,
+                            cost_per_million_tokens_cents,
+                        );
+                        if let Err(err) = ChatRunStats::record_run(
+                            &db.pool,
+                            session_id,
+                            session_agent_id,
+                            agent_id,
+                            token_usage.total_tokens,
+                            estimated_cost,
+                            failed,
+                        )
+                        .await
+                        {
+                            tracing::warn!(
+                                session_agent_id = %session_agent_id,
+                                error = %err,
+                                "Failed to record run statistics"
+                            );
+                        }
+
+                        meta["token_usage"] = serde_json::json!({
+                            "total_tokens": token_usage.total_tokens,
+                            "model_context_window": token_usage.model_context_window,
+                            "input_tokens": token_usage.input_tokens,
+                            "output_tokens": token_usage.output_tokens,
+                            "is_estimated": token_usage.is_estimated,
+                        });
+                        meta["estimated_cost"] = estimated_cost.into();
+
+                        if !citations.is_empty() {
+                            meta["citations"] =
+                                serde_json::to_value(&citations).unwrap_or_default();
+                        }
+
+                        if let Some(forwarded_from) = forwarded_from.as_ref() {
+                            meta["forwarded_from"] =
+                                serde_json::to_value(forwarded_from).unwrap_or_default();
+                        }
+
+                        // Replies to a private message stay private to the same agents.
+                        if let Ok(Some(source)) =
+                            ChatMessage::find_by_id(&db.pool, source_message_id).await
+                            && let Some(visible_to) = source.meta.0.get(chat::VISIBLE_TO_KEY)
+                            && !visible_to.is_null()
+                        {
+                            meta[chat::VISIBLE_TO_KEY] = visible_to.clone();
+                        }
+
+                        if context_compacted {
+                            meta["context_compacted"] = true.into();
+                        }
+                        if let Some(warning) = compression_warning.as_ref() {
+                            meta["compression_warning"] = serde_json::json!({
+                                "code": warning.code,
+                                "message": warning.message,
+                                "split_file_path": warning.split_file_path,
+                            });
+                        }
+                        if let Some(truncation) = prompt_truncation.as_ref() {
+                            meta["prompt_truncated"] =
+                                serde_json::to_value(truncation).unwrap_or_default();
+                        }
+
+                        if workspace_unavailable {
+                            meta["workspace_unavailable"] = true.into();
+                        }
+
+                        if let Some(reason) = failure_reason {
+                            meta["failure_reason"] = reason.into();
+                        }
+
+                        if redactor.applied() {
+                            meta["redacted"] = true.into();
+                        }
+
+                        if output_truncated {
+                            meta[OUTPUT_TRUNCATED_META_KEY] = serde_json::json!({
+                                "reason": "max_output_reached",
+                                "max_output_tokens": max_output_tokens,
+                            });
+                        }
+
+                        if let Some(diff) = diff_info.as_ref() {
+                            meta["diff_available"] = true.into();
+                            meta["diff_truncated"] = diff.truncated.into();
+                            meta["diff_base"] =
+                                serde_json::to_value(&diff_base).unwrap_or_default();
+                        }
+
+                        if !untracked_files.is_empty() {
+                            meta["untracked_files"] =
+                                serde_json::to_value(&untracked_files).unwrap_or_default();
+                        }
+
+                        match auto_commit_result {
+                            Some(Ok(commit_hash)) => {
+                                meta["auto_commit_hash"] = commit_hash.into();
+                            }
+                            Some(Err(err)) => {
+                                tracing::warn!(
+                                    run_id = %run_id,
+                                    error = %err,
+                                    "chat run auto-commit failed"
+                                );
+                                meta["auto_commit_error"] = err.into();
+                            }
+                            None => {}
+                        }
+
+                        let _ = fs::write(&meta_path, serde_json::to_string_pretty(&meta).unwrap())
+                            .await;
+
+                        let final_content = ChatRunner::apply_reply_prefix(
+                            &latest_assistant,
+                            reply_handle.as_deref(),
+                        );
+
+                        if !final_content.trim().is_empty()
+                            && let Ok(message) = crate::services::chat::create_message(
+                                &db.pool,
+                                session_id,
+                                ChatSenderType::Agent,
+                                Some(agent_id),
+                                final_content.clone(),
+                                Some(meta.clone()),
+                            )
+                            .await
+                        {
+                            runner
+                                .spawn_message_translation(message.clone(), workspace_path.clone());
+
+                            // Call handle_message to process explicit routing directives
+                            // This enables AI-to-AI message forwarding (chain calls)
+                            if let Ok(Some(session)) =
+                                ChatSession::find_by_id(&db.pool, session_id).await
+                            {
+                                runner.handle_message(&session, &message).await;
+                            } else {
+                                // Fallback: emit MessageNew event if session lookup fails
+                                let _ = sender.send(ChatStreamEvent::MessageNew { message });
+                            }
+                        }
+
+                        let _ = sender.send(ChatStreamEvent::AgentDelta {
+                            session_id,
+                            session_agent_id,
+                            agent_id,
+                            run_id,
+                            stream_type: ChatStreamDeltaType::Assistant,
+                            content: latest_assistant.clone(),
+                            delta: false,
+                            is_final: true,
+                        });
+
+                        let final_state = if failed {
+                            ChatSessionAgentState::Dead
+                        } else {
+                            ChatSessionAgentState::Idle
+                        };
+
+                        let _ = runner
+                            .finish_run(session_agent_id, final_state.clone())
+                            .await;
+
+                        let _ = sender.send(ChatStreamEvent::AgentState {
+                            session_agent_id,
+                            agent_id,
+                            state: final_state.clone(),
+                            started_at: None,
+                        });
+
+                        // Emit MentionAcknowledged completed/failed event
+                        let mention_status = if final_state == ChatSessionAgentState::Dead {
+                            MentionStatus::Failed
+                        } else {
+                            MentionStatus::Completed
+                        };
+                        let _ = sender.send(ChatStreamEvent::MentionAcknowledged {
+                            session_id,
+                            message_id: source_message_id,
+                            mentioned_agent: agent_name.clone(),
+                            agent_id,
+                            status: mention_status.clone(),
+                        });
+
+                        // Persist completed/failed status to message meta
+                        let status_str = match mention_status {
+                            MentionStatus::Completed => "completed",
+                            MentionStatus::Failed => "failed",
+                            MentionStatus::Running => "running",
+                            MentionStatus::Received => "received",
+                        };
+                        let _ = ChatMessage::merge_meta(
+                            &db.pool,
+                            source_message_id,
+                            serde_json::json!({
+                                "mention_statuses": { agent_name.as_str(): status_str }
+                            }),
+                        )
+                        .await;
+
+                        // Process any pending messages in the queue for this agent
+                        // Only process if the agent completed successfully (not failed/dead)
+                        if final_state == ChatSessionAgentState::Idle {
+                            runner
+                                .process_pending_queue(session_id, session_agent_id)
+                                .await;
+                        } else {
+                            // Agent failed/died - clear pending queue and mark all as failed
+                            runner
+                                .clear_pending_queue_on_failure(session_id, session_agent_id)
+                                .await;
+                        }
+
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+        });
+    }
+
+    fn spawn_exit_watcher(
+        &self,
+        mut child: command_group::AsyncGroupChild,
+        cancel_token: Option<CancellationToken>,
+        exit_signal: Option<ExecutorExitSignal>,
+        msg_store: Arc<MsgStore>,
+        failed_flag: Arc<AtomicBool>,
+        session_agent_id: Uuid,
+        mut duration_budget: Option<RunDurationBudget>,
+        kill_requested: Arc<AtomicBool>,
+    ) {
+        // Store the cancellation token for graceful shutdown
+        if let Some(ref token) = cancel_token {
+            self.cancellation_tokens
+                .insert(session_agent_id, token.clone());
+        }
+
+        let finished_sent = Arc::new(AtomicBool::new(false));
+        let finished_from_exit_signal = Arc::new(AtomicBool::new(false));
+        let cancellation_tokens = self.cancellation_tokens.clone();
+        let process_finished = finished_sent.clone();
+        let process_finished_from_signal = finished_from_exit_signal.clone();
+        let process_msg_store = msg_store.clone();
+        let process_failed_flag = failed_flag.clone();
+        let process_cancel_token = cancel_token.clone();
+        let pending_approvals = self.pending_approvals.clone();
+        tokio::spawn(async move {
+            let mut kill_deadline: Option<tokio::time::Instant> = None;
+            loop {
+                match child.try_wait() {
+                    Ok(Some(status)) => {
+                        if !status.success() {
+                            process_failed_flag.store(true, Ordering::Relaxed);
+                        }
+                        if !process_finished.swap(true, Ordering::Relaxed) {
+                            process_msg_store.push_finished();
+                        }
+                        // If completion already came from exit_signal, token was cleaned there.
+                        if !process_finished_from_signal.load(Ordering::Relaxed) {
+                            cancellation_tokens.remove(&session_agent_id);
+                        }
+                        break;
+                    }
+                    Ok(None) => {
+                        if kill_requested.swap(false, Ordering::Relaxed) {
+                            // The run was cancelled on an idle timeout and outlived its grace.
+                            if let Err(err) = kill_process_group(&mut child).await {
+                                process_msg_store.push(LogMsg::Stderr(format!(
+                                    "failed to kill run after idle timeout: {err}"
+                                )));
+                            }
+                            continue;
+                        }
+                        let now = tokio::time::Instant::now();
+                        if let Some(deadline) = kill_deadline {
+                            if now >= deadline {
+                                // The executor ignored cancellation; the loop picks up its exit.
+                                if let Err(err) = kill_process_group(&mut child).await {
+                                    process_msg_store.push(LogMsg::Stderr(format!(
+                                        "failed to kill run after max duration: {err}"
+                                    )));
+                                }
+                                kill_deadline = None;
+                                continue;
+                            }
+                        } else if let Some(budget) = duration_budget.as_mut()
+                            && budget.tick(now, pending_approvals.contains_key(&budget.run_id))
+                        {
+                            // Regardless of activity: cancel, then kill after a grace period.
+                            let limit_secs = budget.limit.as_secs();
+                            tracing::warn!(
+                                session_agent_id = %session_agent_id,
+                                limit_secs,
+                                "chat run exceeded its maximum duration; cancelling"
+                            );
+                            process_failed_flag.store(true, Ordering::Relaxed);
+                            process_msg_store.push(LogMsg::Stderr(format!(
+                                "max duration exceeded: run exceeded {limit_secs}s"
+                            )));
+                            if let Some(token) = process_cancel_token.as_ref() {
+                                token.cancel();
+                            }
+                            kill_deadline = Some(now + budget.kill_grace);
+                        }
+                        tokio::time::sleep(RUN_DURATION_CHECK_INTERVAL).await;
+                    }
+                    Err(err) => {
+                        process_msg_store
+                            .push(LogMsg::Stderr(format!("process wait error: {err}")));
+                        process_failed_flag.store(true, Ordering::Relaxed);
+                        if !process_finished.swap(true, Ordering::Relaxed) {
+                            process_msg_store.push_finished();
+                        }
+                        if !process_finished_from_signal.load(Ordering::Relaxed) {
+                            cancellation_tokens.remove(&session_agent_id);
+                        }
+                        break;
+                    }
+                }
+            }
+        });
+
+        if let Some(exit_signal_rx) = exit_signal {
+            let signal_msg_store = msg_store;
+            let signal_failed_flag = failed_flag;
+            let signal_finished = finished_sent;
+            let signal_finished_from_signal = finished_from_exit_signal;
+            let signal_cancel_token = cancel_token;
+            let signal_cancellation_tokens = self.cancellation_tokens.clone();
+            tokio::spawn(async move {
+                match exit_signal_rx.await {
+                    Ok(exit_result) => {
+                        if matches!(
+                            exit_result,
+                            executors::executors::ExecutorExitResult::Failure
+                        ) {
+                            signal_failed_flag.store(true, Ordering::Relaxed);
+                        }
+
+                        // Ignore completion emitted after manual cancellation (e.g. stop_agent).
+                        let manually_cancelled = signal_cancel_token
+                            .as_ref()
+                            .map(CancellationToken::is_cancelled)
+                            .unwrap_or(false);
+
+                        if !manually_cancelled {
+                            signal_finished_from_signal.store(true, Ordering::Relaxed);
+                            if !signal_finished.swap(true, Ordering::Relaxed) {
+                                signal_msg_store.push_finished();
+                            }
+                            signal_cancellation_tokens.remove(&session_agent_id);
+                        }
+                    }
+                    Err(err) => {
+                        signal_msg_store
+                            .push(LogMsg::Stderr(format!("exit signal receive error: {err}")));
+                    }
+                }
+            });
+        }
+    }
+
+    /// Answer the approval request a run is blocked on. Fails when the run is not in
+    /// `session_id` or has nothing pending.
+    pub fn respond_to_approval(
+        &self,
         session_id: Uuid,
-        workspace_path: String,
-        context_dir: PathBuf,
+        run_id: Uuid,
+        status: ApprovalStatus,
+    ) -> Result<(), ChatRunnerError> {
+        let Some((_, pending)) = self
+            .pending_approvals
+            .remove_if(&run_id, |_, pending| pending.session_id == session_id)
+        else {
+            return Err(ChatRunnerError::ApprovalNotFound(run_id));
+        };
+        pending
+            .responder
+            .send(status)
+            .map_err(|_| ChatRunnerError::ApprovalNotFound(run_id))
+    }
+
+    /// Moves an agent between `Running` and `WaitingApproval`, leaving it alone if a stop
+    /// or completion already changed its state.
+    async fn set_agent_approval_state(
+        &self,
+        session_id: Uuid,
+        session_agent_id: Uuid,
+        agent_id: Uuid,
+        from: ChatSessionAgentState,
+        to: ChatSessionAgentState,
     ) {
-        if self
-            .background_compaction_inflight
-            .contains_key(&session_id)
-        {
-            return;
+        // Serialized with the end of the run so a late approval cannot revive a finished one.
+        let lock = self.dispatch_lock(session_agent_id);
+        let _guard = lock.lock().await;
+        match ChatSessionAgent::find_by_id(&self.db.pool, session_agent_id).await {
+            Ok(Some(session_agent)) if session_agent.state == from => {}
+            Ok(_) => return,
+            Err(err) => {
+                tracing::warn!(
+                    session_agent_id = %session_agent_id,
+                    error = %err,
+                    "failed to load session agent for approval state change"
+                );
+                return;
+            }
+        }
+        match ChatSessionAgent::update_state(&self.db.pool, session_agent_id, to.clone()).await {
+            Ok(updated) => self.emit(
+                session_id,
+                ChatStreamEvent::AgentState {
+                    session_agent_id,
+                    agent_id,
+                    state: to,
+                    started_at: Some(updated.updated_at),
+                },
+            ),
+            Err(err) => tracing::warn!(
+                session_agent_id = %session_agent_id,
+                error = %err,
+                "failed to update session agent approval state"
+            ),
+        }
+    }
+
+    /// One-line description of a tool call shown to the user when asking for approval.
+    fn approval_prompt(tool_name: &str, tool_input: &serde_json::Value) -> String {
+        let command = tool_input
+            .get("command")
+            .and_then(|value| match value {
+                serde_json::Value::String(command) => Some(command.clone()),
+                serde_json::Value::Array(parts) => Some(
+                    parts
+                        .iter()
+                        .filter_map(serde_json::Value::as_str)
+                        .collect::<Vec<_>>()
+                        .join(" "),
+                ),
+                _ => None,
+            })
+            .or_else(|| {
+                tool_input
+                    .get("file_path")
+                    .or_else(|| tool_input.get("path"))
+                    .and_then(serde_json::Value::as_str)
+                    .map(str::to_string)
+            });
+        match command.filter(|value| !value.trim().is_empty()) {
+            Some(detail) => format!("Allow {tool_name}: {}", detail.trim()),
+            None => format!("Allow {tool_name}?"),
+        }
+    }
+
+    /// Stop a running agent by triggering graceful cancellation via CancellationToken
+    pub async fn stop_agent(
+        &self,
+        session_id: Uuid,
+        session_agent_id: Uuid,
+    ) -> Result<(), ChatRunnerError> {
+        tracing::info!(
+            "stop_agent called for session_agent_id: {}",
+            session_agent_id
+        );
+
+        // Try to cancel the agent via CancellationToken (graceful shutdown)
+        let token_found = self.cancellation_tokens.contains_key(&session_agent_id);
+        tracing::info!("CancellationToken found: {}", token_found);
+
+        if let Some(token) = self.cancellation_tokens.get(&session_agent_id) {
+            tracing::info!(
+                "Cancelling agent for session_agent_id: {}",
+                session_agent_id
+            );
+            token.cancel();
+        } else {
+            tracing::warn!(
+                "No CancellationToken found for session_agent_id: {}",
+                session_agent_id
+            );
         }
-        self.background_compaction_inflight.insert(session_id, ());
 
-        let runner = self.clone();
-        tokio::spawn(async move {
-            let workspace_path_buf = PathBuf::from(&workspace_path);
-            let result = crate::services::chat::build_compacted_context(
-                &runner.db.pool,
-                session_id,
-                None,
-                Some(workspace_path_buf.as_path()),
-                Some(context_dir.as_path()),
-            )
-            .await;
+        // Dropping the responder unblocks an executor waiting on approval
+        self.pending_approvals
+            .retain(|_, pending| pending.session_agent_id != session_agent_id);
 
-            match result {
-                Ok(compacted) => {
-                    if compacted.context_compacted {
-                        let workspace_context_path = context_dir.join("messages.jsonl");
-                        if let Err(err) =
-                            fs::write(&workspace_context_path, compacted.jsonl.as_bytes()).await
-                        {
-                            tracing::warn!(
-                                session_id = %session_id,
-                                error = %err,
-                                path = %workspace_context_path.display(),
-                                "Failed to update workspace context with compacted history"
-                            );
-                        } else {
-                            tracing::info!(
-                                session_id = %session_id,
-                                path = %workspace_context_path.display(),
-                                compacted_message_count = compacted.messages.len(),
-                                "Background context compaction completed and updated workspace context"
-                            );
-                        }
-                    }
+        // A stopped agent does not keep its persistent process
+        self.shutdown_persistent_process(session_agent_id);
 
-                    if let Some(warning) = compacted.compression_warning {
-                        runner.emit(
-                            session_id,
-                            ChatStreamEvent::CompressionWarning {
-                                session_id,
-                                warning: warning.into(),
-                            },
-                        );
-                    }
-                }
+        // Update state to Dead
+        let session_agent = ChatSessionAgent::update_state(
+            &self.db.pool,
+            session_agent_id,
+            ChatSessionAgentState::Dead,
+        )
+        .await?;
+
+        // Emit state change event
+        self.emit(
+            session_id,
+            ChatStreamEvent::AgentState {
+                session_agent_id,
+                agent_id: session_agent.agent_id,
+                state: ChatSessionAgentState::Dead,
+                started_at: None,
+            },
+        );
+
+        // Clean up the cancellation token
+        self.cancellation_tokens.remove(&session_agent_id);
+
+        Ok(())
+    }
+
+    /// Stop every agent of a session that has a live run, failing all mentions queued in the
+    /// session. An agent that fails to stop is logged and skipped so the others still stop.
+    pub async fn stop_all_agents(
+        &self,
+        session_id: Uuid,
+    ) -> Result<StopAllAgentsOutcome, ChatRunnerError> {
+        let session_agents =
+            ChatSessionAgent::find_all_for_session(&self.db.pool, session_id).await?;
+        let mut outcome = StopAllAgentsOutcome::default();
+        for session_agent in session_agents {
+            // Clear first so the stopped run cannot pick up a queued mention
+            self.clear_pending_queue_on_failure(session_id, session_agent.id)
+                .await;
+            if !self.cancellation_tokens.contains_key(&session_agent.id) {
+                continue;
+            }
+            match self.stop_agent(session_id, session_agent.id).await {
+                Ok(()) => outcome.stopped += 1,
                 Err(err) => {
                     tracing::warn!(
                         session_id = %session_id,
+                        session_agent_id = %session_agent.id,
                         error = %err,
-                        "Background context compaction failed"
+                        "failed to stop agent while stopping all agents"
                     );
+                    outcome.failed.push(session_agent.id);
+                }
+            }
+        }
+        Ok(outcome)
+    }
+
+    /// Mute or unmute a session agent. Mentions of a muted agent are queued without starting
+    /// a run; unmuting an idle agent starts working through its queue.
+    pub async fn set_agent_muted(
+        &self,
+        session_id: Uuid,
+        session_agent_id: Uuid,
+        muted: bool,
+    ) -> Result<ChatSessionAgent, ChatRunnerError> {
+        let session_agent =
+            ChatSessionAgent::set_muted(&self.db.pool, session_agent_id, muted).await?;
+        self.emit(
+            session_id,
+            ChatStreamEvent::AgentMuted {
+                session_agent_id,
+                agent_id: session_agent.agent_id,
+                muted,
+            },
+        );
+
+        if !muted && session_agent.state != ChatSessionAgentState::Running {
+            self.process_pending_queue(session_id, session_agent_id)
+                .await;
+        }
+
+        Ok(session_agent)
+    }
+
+    /// Recovery for an agent left `Running` without a live process (e.g. after a crash).
+    /// Unlike [`Self::stop_agent`], this refuses to touch an agent that still has a run.
+    /// Post the assistant draft of a member's latest run when that run never finished, e.g.
+    /// because the server stopped mid-stream. The run's meta.json is written with the run
+    /// marked failed, so a draft is recovered at most once.
+    async fn recover_interrupted_run_draft(
+        &self,
+        session_agent: &ChatSessionAgent,
+    ) -> Result<Option<ChatMessage>, ChatRunnerError> {
+        let Some(run) =
+            ChatRun::find_latest_for_session_agent(&self.db.pool, session_agent.id).await?
+        else {
+            return Ok(None);
+        };
+        let (Some(output_path), Some(meta_path)) = (run.output_path, run.meta_path) else {
+            return Ok(None);
+        };
+        // A finished run always has its meta.json.
+        if fs::try_exists(&meta_path).await? {
+            return Ok(None);
+        }
+
+        let draft = match fs::read_to_string(&output_path).await {
+            Ok(draft) => draft,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(err.into()),
+        };
+        let draft_recovered = !draft.trim().is_empty();
+        let meta = serde_json::json!({
+            "run_id": run.id,
+            "session_id": run.session_id,
+            "session_agent_id": session_agent.id,
+            "agent_id": session_agent.agent_id,
+            "finished_at": Utc::now().to_rfc3339(),
+            "failed": true,
+            "failure_reason": "interrupted",
+            "draft_recovered": draft_recovered,
+        });
+        fs::write(&meta_path, serde_json::to_string_pretty(&meta).unwrap()).await?;
+        if !draft_recovered {
+            return Ok(None);
+        }
+
+        let message = crate::services::chat::create_message(
+            &self.db.pool,
+            run.session_id,
+            ChatSenderType::Agent,
+            Some(session_agent.agent_id),
+            draft,
+            Some(meta),
+        )
+        .await?;
+        self.emit(
+            run.session_id,
+            ChatStreamEvent::MessageNew {
+                message: message.clone(),
+            },
+        );
+        Ok(Some(message))
+    }
+
+    /// Members still marked running when the runner starts lost their run with the previous
+    /// server process: post each run's draft and mark the member dead.
+    pub async fn recover_interrupted_runs(&self) -> Result<usize, ChatRunnerError> {
+        let session_agents = ChatSessionAgent::find_all_running(&self.db.pool).await?;
+        let mut recovered = 0;
+        for session_agent in session_agents {
+            if self.cancellation_tokens.contains_key(&session_agent.id) {
+                continue;
+            }
+            if let Err(err) = self.recover_interrupted_run_draft(&session_agent).await {
+                tracing::warn!(
+                    session_agent_id = %session_agent.id,
+                    error = %err,
+                    "Failed to recover interrupted run draft"
+                );
+            }
+            ChatSessionAgent::update_state(
+                &self.db.pool,
+                session_agent.id,
+                ChatSessionAgentState::Dead,
+            )
+            .await?;
+            recovered += 1;
+        }
+        Ok(recovered)
+    }
+
+    pub async fn reset_agent(
+        &self,
+        session_id: Uuid,
+        session_agent_id: Uuid,
+    ) -> Result<ChatSessionAgent, ChatRunnerError> {
+        if self.cancellation_tokens.contains_key(&session_agent_id) {
+            return Err(ChatRunnerError::AgentRunActive(session_agent_id));
+        }
+        self.fanout_permits.remove(&session_agent_id);
+
+        if let Some(existing) =
+            ChatSessionAgent::find_by_id(&self.db.pool, session_agent_id).await?
+            && let Err(err) = self.recover_interrupted_run_draft(&existing).await
+        {
+            tracing::warn!(
+                session_agent_id = %session_agent_id,
+                error = %err,
+                "Failed to recover interrupted run draft"
+            );
+        }
+
+        let session_agent = ChatSessionAgent::update_state(
+            &self.db.pool,
+            session_agent_id,
+            ChatSessionAgentState::Idle,
+        )
+        .await?;
+        self.clear_pending_queue_on_failure(session_id, session_agent_id)
+            .await;
+
+        self.emit(
+            session_id,
+            ChatStreamEvent::AgentState {
+                session_agent_id,
+                agent_id: session_agent.agent_id,
+                state: ChatSessionAgentState::Idle,
+                started_at: None,
+            },
+        );
+
+        Ok(session_agent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    };
+
+    use dashmap::DashMap;
+    use db::{
+        DBService,
+        models::{
+            chat_agent::{ChatAgent, CreateChatAgent},
+            chat_message::{ChatMessage, ChatSenderType},
+            chat_run::{ChatRun, CreateChatRun},
+            chat_run_stats::{ChatRunStats, ChatRunStatsGroupBy},
+            chat_session::{ChatSession, CreateChatSession},
+            chat_session_agent::{ChatSessionAgent, ChatSessionAgentState, CreateChatSessionAgent},
+        },
+    };
+    use executors::{
+        executors::{CancellationToken, ExecutorExitResult, PersistentChild},
+        logs::{NormalizedEntry, NormalizedEntryType, TokenUsageInfo, utils::ConversationPatch},
+    };
+    use utils::{log_msg::LogMsg, msg_store::MsgStore};
+    use uuid::Uuid;
+
+    use super::{
+        ADDITIONAL_UNTRACKED_DIR_NAME, AdditionalWorkspace, AgentDeltaBatcher, CappedRunLog,
+        ChatApprovalBridge, ChatRunner, ChatRunnerConfig, ChatRunnerError, ChatStreamDeltaType,
+        ChatStreamEvent, MessageAttachmentContext, OUTPUT_TRUNCATED_META_KEY, PendingMessage,
+        PersistentRunOutput, ReferenceAttachment, ReferenceContext, RoutingOutcome, RoutingSkip,
+        RoutingSkipReason, RunCheckpoint, RunDurationBudget, STDERR_EVENT_DEBOUNCE,
+        StderrLineDebouncer, StdoutTee, SystemMessageCategory, UnknownMentionPolicy,
+        Utf8ChunkDecoder, normalize_lexically, validate_max_output_tokens,
+    };
+    use crate::services::{
+        chat::ChatMessagePriority,
+        chat_redaction::OutputRedactor,
+        chat_workspace::GitDiffBase,
+        config::{AgentUnresolvedRoutingPolicy, RedactionRule},
+    };
+
+    fn default_max_token_usage_line_bytes() -> usize {
+        ChatRunnerConfig::default().max_token_usage_line_bytes as usize
+    }
+
+    fn parse_usage(line: &str) -> Option<TokenUsageInfo> {
+        ChatRunner::parse_token_usage_from_stdout_line(line, default_max_token_usage_line_bytes())
+    }
+
+    fn make_message(sender_type: ChatSenderType, sender_id: Option<Uuid>) -> ChatMessage {
+        ChatMessage {
+            id: Uuid::new_v4(),
+            session_id: Uuid::new_v4(),
+            sender_type,
+            sender_id,
+            content: "[sendMessageTo@@reviewer] please check".to_string(),
+            mentions: sqlx::types::Json(vec!["reviewer".to_string()]),
+            meta: sqlx::types::Json(serde_json::json!({})),
+            created_at: chrono::Utc::now(),
+        }
+    }
+
+    #[test]
+    fn routing_outcome_records_dropped_targets() {
+        let mentions = vec![
+            "coder".to_string(),
+            "reviewer".to_string(),
+            "ops".to_string(),
+        ];
+        let permitted = vec!["coder".to_string(), "reviewer".to_string()];
+        let immediate = vec!["coder".to_string()];
+
+        let mut routing = RoutingOutcome::default();
+        assert!(routing.is_empty());
+        routing.skip_dropped(&mentions, &permitted, RoutingSkipReason::NotAllowed);
+        routing.skip_dropped(&permitted, &immediate, RoutingSkipReason::HeldForModerator);
+
+        assert_eq!(
+            routing.skipped,
+            vec![
+                RoutingSkip {
+                    target: "ops".to_string(),
+                    reason: RoutingSkipReason::NotAllowed,
+                },
+                RoutingSkip {
+                    target: "reviewer".to_string(),
+                    reason: RoutingSkipReason::HeldForModerator,
+                },
+            ]
+        );
+        assert_eq!(
+            serde_json::to_value(&routing.skipped[0]).unwrap(),
+            serde_json::json!({ "target": "ops", "reason": "not_allowed" })
+        );
+    }
+
+    #[test]
+    fn pending_queue_orders_by_priority_then_arrival() {
+        let mut queue = std::collections::VecDeque::new();
+        let mut ids = Vec::new();
+        for priority in [
+            ChatMessagePriority::Normal,
+            ChatMessagePriority::Low,
+            ChatMessagePriority::High,
+            ChatMessagePriority::Normal,
+            ChatMessagePriority::High,
+        ] {
+            let message = make_message(ChatSenderType::User, None);
+            ids.push(message.id);
+            PendingMessage::enqueue(
+                &mut queue,
+                PendingMessage {
+                    session_id: message.session_id,
+                    agent_id: Uuid::new_v4(),
+                    agent_name: "reviewer".to_string(),
+                    message,
+                    priority,
+                },
+            );
+        }
+
+        let order: Vec<Uuid> = queue.iter().map(|pending| pending.message.id).collect();
+        assert_eq!(order, vec![ids[2], ids[4], ids[0], ids[3], ids[1]]);
+        assert_eq!(
+            ChatMessagePriority::from_meta(&serde_json::json!({ "priority": "high" })),
+            ChatMessagePriority::High
+        );
+        assert_eq!(
+            ChatMessagePriority::from_meta(&serde_json::json!({ "priority": "urgent" })),
+            ChatMessagePriority::Normal
+        );
+    }
+
+    async fn runner_with_session_agent() -> (ChatRunner, Uuid) {
+        let pool = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .expect("create sqlite memory pool");
+        sqlx::migrate!("../db/migrations")
+            .run(&pool)
+            .await
+            .expect("run migrations");
+        let session =
+            ChatSession::create(&pool, &CreateChatSession { title: None }, Uuid::new_v4())
+                .await
+                .expect("create session");
+        let agent = ChatAgent::create(
+            &pool,
+            &CreateChatAgent {
+                name: "reviewer".to_string(),
+                runner_type: "CLAUDE_CODE".to_string(),
+                system_prompt: None,
+                tools_enabled: None,
+            },
+            Uuid::new_v4(),
+        )
+        .await
+        .expect("create agent");
+        let session_agent = ChatSessionAgent::create(
+            &pool,
+            &CreateChatSessionAgent {
+                session_id: session.id,
+                agent_id: agent.id,
+                workspace_path: None,
+            },
+            Uuid::new_v4(),
+        )
+        .await
+        .expect("create session agent");
+        (ChatRunner::new(DBService { pool }), session_agent.id)
+    }
+
+    fn make_pending() -> PendingMessage {
+        let message = make_message(ChatSenderType::User, None);
+        PendingMessage {
+            session_id: message.session_id,
+            agent_id: Uuid::new_v4(),
+            agent_name: "reviewer".to_string(),
+            message,
+            priority: ChatMessagePriority::Normal,
+        }
+    }
+
+    #[tokio::test]
+    async fn stream_stats_do_not_create_a_channel() {
+        let (runner, _) = runner_with_session_agent().await;
+        let session_id = Uuid::new_v4();
+
+        let stats = runner.stream_stats(session_id);
+        assert_eq!(stats.subscriber_count, 0);
+        assert_eq!(stats.capacity, 0);
+        assert!(!runner.streams.contains_key(&session_id));
+
+        let _receiver = runner.subscribe(session_id);
+        let stats = runner.stream_stats(session_id);
+        assert_eq!(stats.subscriber_count, 1);
+        assert!(stats.capacity > 0);
+    }
+
+    #[tokio::test]
+    async fn fanout_slot_is_held_until_the_run_finishes() {
+        let (runner, session_agent_id) = runner_with_session_agent().await;
+        let semaphore = Arc::new(tokio::sync::Semaphore::new(1));
+        let permit = semaphore.clone().acquire_owned().await.unwrap();
+
+        runner
+            .claim_or_queue(session_agent_id, make_pending(), Some(permit))
+            .await
+            .expect("claim idle agent")
+            .expect("agent claimed");
+        let next_run = tokio::spawn({
+            let semaphore = semaphore.clone();
+            async move { semaphore.acquire_owned().await.unwrap() }
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(!next_run.is_finished());
+
+        runner
+            .finish_run(session_agent_id, ChatSessionAgentState::Idle)
+            .await
+            .unwrap();
+        tokio::time::timeout(std::time::Duration::from_secs(5), next_run)
+            .await
+            .expect("next run starts once the first finishes")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn forced_compaction_conflicts_with_one_in_flight() {
+        let (runner, session_agent_id) = runner_with_session_agent().await;
+        let temp_dir = tempfile::tempdir().expect("tempdir");
+        let session_agent = ChatSessionAgent::update_workspace_path(
+            &runner.db.pool,
+            session_agent_id,
+            Some(temp_dir.path().to_string_lossy().into_owned()),
+        )
+        .await
+        .unwrap();
+        let session_id = session_agent.session_id;
+        runner.background_compaction_inflight.insert(session_id, ());
+
+        assert!(
+            runner
+                .recompute_context_compaction(session_id, false)
+                .await
+                .is_ok()
+        );
+        assert!(matches!(
+            runner.recompute_context_compaction(session_id, true).await,
+            Err(ChatRunnerError::CompactionInFlight(id)) if id == session_id
+        ));
+    }
+
+    #[tokio::test]
+    async fn run_stats_are_recorded_and_aggregated() {
+        let (runner, session_agent_id) = runner_with_session_agent().await;
+        let pool = &runner.db.pool;
+        let session_agent = ChatSessionAgent::find_by_id(pool, session_agent_id)
+            .await
+            .unwrap()
+            .unwrap();
+        let (session_id, agent_id) = (session_agent.session_id, session_agent.agent_id);
+
+        ChatRunStats::record_run(
+            pool,
+            session_id,
+            session_agent_id,
+            agent_id,
+            100,
+            0.5,
+            false,
+        )
+        .await
+        .unwrap();
+        ChatRunStats::record_run(pool, session_id, session_agent_id, agent_id, 50, 0.25, true)
+            .await
+            .unwrap();
+
+        for group_by in [
+            ChatRunStatsGroupBy::Agent,
+            ChatRunStatsGroupBy::Session,
+            ChatRunStatsGroupBy::Day,
+        ] {
+            let stats = ChatRunStats::aggregate(pool, group_by).await.unwrap();
+            assert_eq!(stats.len(), 1);
+            let stats = &stats[0];
+            assert_eq!(stats.run_count, 2);
+            assert_eq!(stats.total_tokens, 150);
+            assert_eq!(stats.failed_runs, 1);
+            assert!((stats.estimated_cost - 0.75).abs() < f64::EPSILON);
+            match group_by {
+                ChatRunStatsGroupBy::Agent => assert_eq!(stats.agent_id, Some(agent_id)),
+                ChatRunStatsGroupBy::Session => assert_eq!(stats.session_id, Some(session_id)),
+                ChatRunStatsGroupBy::Day => assert!(stats.day.is_some()),
+            }
+        }
+
+        let activity = ChatSessionAgent::find_activity(pool, session_agent_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(activity.total_runs, 2);
+        assert_eq!(activity.total_tokens, 150);
+        assert_eq!(activity.failed_runs, 1);
+        assert!(activity.last_active_at.is_some());
+    }
+
+    #[tokio::test]
+    async fn mention_during_run_finish_is_queued_or_starts_next_run() {
+        let (runner, session_agent_id) = runner_with_session_agent().await;
+        let queued_len = |runner: &ChatRunner| {
+            runner
+                .pending_messages
+                .get(&session_agent_id)
+                .map_or(0, |queue| queue.len())
+        };
+
+        let claimed = runner
+            .claim_or_queue(session_agent_id, make_pending(), None)
+            .await
+            .expect("claim idle agent");
+        assert_eq!(
+            claimed.map(|agent| agent.state),
+            Some(ChatSessionAgentState::Running)
+        );
+        assert!(
+            runner
+                .claim_or_queue(session_agent_id, make_pending(), None)
+                .await
+                .expect("queue behind run")
+                .is_none()
+        );
+        assert_eq!(queued_len(&runner), 1);
+        runner.pending_messages.remove(&session_agent_id);
+
+        for _ in 0..10 {
+            let (finished, claimed) = tokio::join!(
+                runner.finish_run(session_agent_id, ChatSessionAgentState::Idle),
+                runner.claim_or_queue(session_agent_id, make_pending(), None),
+            );
+            finished.expect("finish run");
+            match claimed.expect("claim or queue") {
+                // Queued before the finish: left for the drain that follows it.
+                None => assert_eq!(queued_len(&runner), 1),
+                // Arrived after the finish: it starts the next run itself.
+                Some(agent) => {
+                    assert_eq!(agent.state, ChatSessionAgentState::Running);
+                    assert_eq!(queued_len(&runner), 0);
                 }
             }
-
-            runner.background_compaction_inflight.remove(&session_id);
-        });
+            runner.pending_messages.remove(&session_agent_id);
+            let state = ChatSessionAgent::find_by_id(&runner.db.pool, session_agent_id)
+                .await
+                .expect("load session agent")
+                .expect("session agent exists")
+                .state;
+            if state == ChatSessionAgentState::Idle {
+                runner
+                    .claim_or_queue(session_agent_id, make_pending(), None)
+                    .await
+                    .expect("claim idle agent")
+                    .expect("idle agent is claimed");
+            }
+        }
     }
 
-    async fn build_reference_context(
-        &self,
-        session_id: Uuid,
-        source_message: &ChatMessage,
-        context_dir: &Path,
-    ) -> Result<Option<ReferenceContext>, ChatRunnerError> {
-        let Some(reference_id) = chat::extract_reference_message_id(&source_message.meta.0) else {
-            return Ok(None);
+    #[tokio::test]
+    async fn mention_after_run_finish_queues_behind_earlier_mentions() {
+        let (runner, session_agent_id) = runner_with_session_agent().await;
+        let session_agent = ChatSessionAgent::find_by_id(&runner.db.pool, session_agent_id)
+            .await
+            .expect("load session agent")
+            .expect("session agent exists");
+        let session = ChatSession::find_by_id(&runner.db.pool, session_agent.session_id)
+            .await
+            .expect("load session")
+            .expect("session exists");
+        let queued_ids = |runner: &ChatRunner| -> Vec<Uuid> {
+            runner
+                .pending_messages
+                .get(&session_agent_id)
+                .map(|queue| queue.iter().map(|pending| pending.message.id).collect())
+                .unwrap_or_default()
         };
 
-        let Some(reference) = ChatMessage::find_by_id(&self.db.pool, reference_id).await? else {
-            return Ok(None);
-        };
+        runner
+            .claim_or_queue(session_agent_id, make_pending(), None)
+            .await
+            .expect("claim idle agent")
+            .expect("agent claimed");
+        let earlier = make_pending();
+        let earlier_id = earlier.message.id;
+        assert!(
+            runner
+                .claim_or_queue(session_agent_id, earlier.clone(), None)
+                .await
+                .expect("queue behind run")
+                .is_none()
+        );
 
-        if reference.session_id != session_id {
-            return Ok(None);
+        // The run finishes, but the drain has not claimed the agent for its queue yet.
+        runner
+            .finish_run(session_agent_id, ChatSessionAgentState::Idle)
+            .await
+            .expect("finish run");
+        let mut later = make_message(ChatSenderType::User, None);
+        later.session_id = session.id;
+        runner.handle_message(&session, &later).await;
+        wait_until(|| queued_ids(&runner).len() == 2).await;
+        assert_eq!(queued_ids(&runner), vec![earlier_id, later.id]);
+        let state = ChatSessionAgent::find_by_id(&runner.db.pool, session_agent_id)
+            .await
+            .expect("load session agent")
+            .expect("session agent exists")
+            .state;
+        assert_eq!(state, ChatSessionAgentState::Idle);
+
+        // Only the head of the queue may claim the agent, and it leaves the queue doing so.
+        let claimed = runner
+            .claim_or_queue(session_agent_id, earlier, None)
+            .await
+            .expect("claim for queue head")
+            .expect("queue head claims the agent");
+        assert_eq!(claimed.state, ChatSessionAgentState::Running);
+        assert_eq!(queued_ids(&runner), vec![later.id]);
+    }
+
+    #[tokio::test]
+    async fn stop_all_agents_reports_failures_and_stops_the_rest() {
+        let (runner, session_agent_id) = runner_with_session_agent().await;
+        let session_id = ChatSessionAgent::find_by_id(&runner.db.pool, session_agent_id)
+            .await
+            .expect("load session agent")
+            .expect("session agent exists")
+            .session_id;
+        let stuck_agent = ChatAgent::create(
+            &runner.db.pool,
+            &CreateChatAgent {
+                name: "stuck".to_string(),
+                runner_type: "CLAUDE_CODE".to_string(),
+                system_prompt: None,
+                tools_enabled: None,
+            },
+            Uuid::new_v4(),
+        )
+        .await
+        .expect("create agent");
+        let stuck = ChatSessionAgent::create(
+            &runner.db.pool,
+            &CreateChatSessionAgent {
+                session_id,
+                agent_id: stuck_agent.id,
+                workspace_path: None,
+            },
+            Uuid::new_v4(),
+        )
+        .await
+        .expect("create session agent");
+        // Stopping the stuck member fails when its state is written
+        sqlx::query(
+            "CREATE TRIGGER refuse_stuck_state BEFORE UPDATE OF state ON chat_session_agents
+             WHEN OLD.agent_id = (SELECT id FROM chat_agents WHERE name = 'stuck')
+             BEGIN SELECT RAISE(ABORT, 'state is locked'); END",
+        )
+        .execute(&runner.db.pool)
+        .await
+        .expect("create trigger");
+        for id in [stuck.id, session_agent_id] {
+            runner
+                .cancellation_tokens
+                .insert(id, CancellationToken::new());
         }
 
-        let sender_label = reference
-            .meta
-            .0
-            .get("sender")
-            .and_then(|value| value.get("label"))
-            .and_then(|value| value.as_str())
-            .unwrap_or("unknown")
-            .to_string();
+        let outcome = runner
+            .stop_all_agents(session_id)
+            .await
+            .expect("stop all agents");
 
-        let attachments = chat::extract_attachments(&reference.meta.0);
-        let mut reference_attachments = Vec::new();
+        assert_eq!(outcome.stopped, 1);
+        assert_eq!(outcome.failed, vec![stuck.id]);
+        let state = ChatSessionAgent::find_by_id(&runner.db.pool, session_agent_id)
+            .await
+            .expect("load session agent")
+            .expect("session agent exists")
+            .state;
+        assert_eq!(state, ChatSessionAgentState::Dead);
+    }
 
-        if !attachments.is_empty() {
-            let reference_dir = context_dir
-                .join("references")
-                .join(reference_id.to_string());
-            fs::create_dir_all(&reference_dir).await?;
+    #[tokio::test]
+    async fn oversized_prompt_drops_reference_before_attachments() {
+        let (runner, _) = runner_with_session_agent().await;
+        let agent = ChatAgent {
+            id: Uuid::new_v4(),
+            name: "reviewer".to_string(),
+            runner_type: "CLAUDE_CODE".to_string(),
+            system_prompt: String::new(),
+            tools_enabled: sqlx::types::Json(serde_json::json!({})),
+            created_at: chrono::Utc::now(),
+            updated_at: chrono::Utc::now(),
+        };
+        let message = make_message(ChatSenderType::User, None);
+        let attachment = |index: usize| ReferenceAttachment {
+            name: format!("notes_{index}.md"),
+            mime_type: Some("text/markdown".to_string()),
+            size_bytes: 1024,
+            kind: "file".to_string(),
+            local_path: format!("/work/.agents_chatgroup/attachments/notes_{index}.md"),
+            too_large: false,
+        };
+        let attachments = MessageAttachmentContext {
+            message_id: message.id,
+            attachments: (0..20).map(attachment).collect(),
+        };
+        let reference = ReferenceContext {
+            message_id: Uuid::new_v4(),
+            sender_label: "coder".to_string(),
+            sender_type: ChatSenderType::Agent,
+            created_at: "2026-01-01T00:00:00Z".to_string(),
+            content: "a long earlier reply ".repeat(400),
+            attachments: Vec::new(),
+        };
+        let build = |attachments: Option<&MessageAttachmentContext>,
+                     reference: Option<&ReferenceContext>,
+                     max_prompt_tokens: Option<u32>| {
+            runner.build_prompt(
+                &agent,
+                &message,
+                std::path::Path::new("/work/messages.jsonl"),
+                None,
+                std::path::Path::new("/work/scratch"),
+                &[],
+                attachments,
+                reference,
+                max_prompt_tokens,
+            )
+        };
+        let tokens = |prompt: &str| ChatRunner::estimate_tokens_with_tiktoken(prompt);
+        let bare_tokens = tokens(&build(None, None, None).0);
+        let attachments_tokens = tokens(&build(Some(&attachments), None, None).0);
+
+        let (prompt, truncation) = build(Some(&attachments), Some(&reference), Some(u32::MAX));
+        assert!(truncation.is_none());
+        assert!(!prompt.contains("[PROMPT_TRUNCATED]"));
+
+        // Room for the attachments (plus the truncation notice) but not the reference.
+        let budget = attachments_tokens + 100;
+        let (prompt, truncation) = build(Some(&attachments), Some(&reference), Some(budget));
+        let truncation = truncation.expect("reference dropped");
+        assert_eq!(truncation.omitted_sections, vec!["reference_message"]);
+        assert!(prompt.contains("notes_19.md"));
+        assert!(prompt.contains("Omitted to fit the prompt budget: reference_message."));
+
+        let budget = bare_tokens + 100;
+        let (prompt, truncation) = build(Some(&attachments), Some(&reference), Some(budget));
+        let truncation = truncation.expect("both sections dropped");
+        assert!(!prompt.contains("notes_0.md"));
+        assert_eq!(
+            serde_json::to_value(&truncation).unwrap(),
+            serde_json::json!({
+                "max_prompt_tokens": budget,
+                "estimated_tokens": tokens(&prompt),
+                "omitted_sections": ["reference_message", "message_attachments"],
+            })
+        );
+    }
 
-            for attachment in attachments {
-                let relative = PathBuf::from(&attachment.relative_path);
-                if relative.is_absolute()
-                    || relative
-                        .components()
-                        .any(|component| matches!(component, Component::ParentDir))
-                {
-                    continue;
-                }
+    #[tokio::test]
+    async fn oversized_recent_context_falls_back_to_history_file() {
+        let (runner, _) = runner_with_session_agent().await;
+        let agent = ChatAgent {
+            id: Uuid::new_v4(),
+            name: "quick".to_string(),
+            runner_type: "CLAUDE_CODE".to_string(),
+            system_prompt: String::new(),
+            tools_enabled: sqlx::types::Json(serde_json::json!({ "require_history_read": false })),
+            created_at: chrono::Utc::now(),
+            updated_at: chrono::Utc::now(),
+        };
+        let message = make_message(ChatSenderType::User, None);
+        let reference = ReferenceContext {
+            message_id: Uuid::new_v4(),
+            sender_label: "coder".to_string(),
+            sender_type: ChatSenderType::Agent,
+            created_at: "2026-01-01T00:00:00Z".to_string(),
+            content: "a long earlier reply ".repeat(50),
+            attachments: Vec::new(),
+        };
+        let recent_context = "- [user] a long recent message\n".repeat(200);
+        let build = |recent_context: Option<&str>, max_prompt_tokens: Option<u32>| {
+            runner.build_prompt(
+                &agent,
+                &message,
+                std::path::Path::new("/work/messages.jsonl"),
+                recent_context,
+                std::path::Path::new("/work/scratch"),
+                &[],
+                None,
+                Some(&reference),
+                max_prompt_tokens,
+            )
+        };
+        let tokens = |prompt: &str| ChatRunner::estimate_tokens_with_tiktoken(prompt);
+        let with_context_tokens = tokens(&build(Some(&recent_context), None).0);
+        let bare_tokens = tokens(&build(None, None).0);
+
+        // The inlined context counts against the budget: dropping the reference suffices
+        // only when the context fits.
+        let (prompt, truncation) = build(Some(&recent_context), Some(with_context_tokens - 1));
+        assert_eq!(
+            truncation.expect("reference dropped").omitted_sections,
+            vec!["reference_message"]
+        );
+        assert!(prompt.contains("[RECENT_CONTEXT]"));
+        assert!(prompt.contains("Read the group chat history file if you need them."));
+
+        let budget = bare_tokens + 100;
+        assert!(budget < with_context_tokens);
+        let (prompt, truncation) = build(Some(&recent_context), Some(budget));
+        assert_eq!(
+            truncation.expect("recent context dropped").omitted_sections,
+            vec!["reference_message", "recent_context"]
+        );
+        assert!(!prompt.contains("[RECENT_CONTEXT]"));
+        assert!(prompt.contains("[CRITICAL_INSTRUCTION]"));
+        assert!(prompt.contains("Read the group chat history file for details."));
+    }
 
-                let source_path = asset_dir().join(&relative);
-                let file_name = source_path
-                    .file_name()
-                    .map(|name| name.to_string_lossy().to_string())
-                    .unwrap_or_else(|| attachment.name.clone());
-                let dest_path = reference_dir.join(&file_name);
-                let local_path = if fs::copy(&source_path, &dest_path).await.is_ok() {
-                    dest_path.to_string_lossy().to_string()
-                } else {
-                    source_path.to_string_lossy().to_string()
-                };
+    #[test]
+    fn stderr_debouncer_emits_complete_non_empty_lines() {
+        let mut debouncer = StderrLineDebouncer::new();
+        let start = tokio::time::Instant::now();
+
+        assert!(debouncer.push("warning: rate ", start).is_empty());
+        assert_eq!(
+            debouncer.push("limited\n\n   \ndeprecated flag\r\n", start),
+            vec!["warning: rate limited", "deprecated flag"]
+        );
+        assert!(debouncer.push("deprecated flag\n", start).is_empty());
+        assert_eq!(
+            debouncer.push(
+                "deprecated flag\n",
+                start + STDERR_EVENT_DEBOUNCE + std::time::Duration::from_millis(1)
+            ),
+            vec!["deprecated flag"]
+        );
+        assert!(debouncer.push("tail without newline", start).is_empty());
+        assert_eq!(
+            debouncer.finish(start),
+            Some("tail without newline".to_string())
+        );
+        assert_eq!(debouncer.finish(start), None);
+    }
 
-                reference_attachments.push(ReferenceAttachment {
-                    name: attachment.name,
-                    mime_type: attachment.mime_type,
-                    size_bytes: attachment.size_bytes,
-                    kind: attachment.kind,
-                    local_path,
-                });
+    #[tokio::test]
+    async fn stdout_tee_refuses_paths_inside_run_and_workspace_dirs() {
+        let temp_dir = tempfile::tempdir().expect("tempdir");
+        let workspace = temp_dir.path().join("workspace");
+        let run_dir = workspace.join(".agents_chatgroup/runs/session/run_records/run_0001");
+        let extra = temp_dir.path().join("extra");
+        std::fs::create_dir_all(&run_dir).unwrap();
+        std::fs::create_dir_all(&extra).unwrap();
+        let protected_dirs = [workspace.as_path(), run_dir.as_path(), extra.as_path()];
+        let open = |path: std::path::PathBuf| {
+            let protected_dirs = protected_dirs;
+            async move {
+                StdoutTee::open(&path, &protected_dirs, Uuid::new_v4(), "coder")
+                    .await
+                    .is_some()
             }
+        };
+
+        for inside in [
+            run_dir.join("tee.log"),
+            workspace.join("notes/tee.log"),
+            extra.join("tee.log"),
+            temp_dir.path().join("elsewhere/../workspace/tee.log"),
+        ] {
+            assert!(!open(inside.clone()).await, "{}", inside.display());
+            assert!(!normalize_lexically(&inside).exists());
+        }
+        #[cfg(unix)]
+        {
+            let link = temp_dir.path().join("link");
+            std::os::unix::fs::symlink(&workspace, &link).unwrap();
+            assert!(!open(link.join("tee.log")).await);
         }
 
-        Ok(Some(ReferenceContext {
-            message_id: reference.id,
-            sender_label,
-            sender_type: reference.sender_type,
-            created_at: reference.created_at.to_rfc3339(),
-            content: reference.content,
-            attachments: reference_attachments,
+        let outside = temp_dir.path().join("debug/tee.log");
+        assert!(open(outside.clone()).await);
+        assert!(outside.exists());
+    }
+
+    #[test]
+    fn utf8_decoder_holds_characters_split_across_chunks() {
+        let bytes = "ok 你好".as_bytes();
+        let split = bytes.len() - 2;
+        let mut decoder = Utf8ChunkDecoder::default();
+
+        assert_eq!(decoder.decode(&bytes[..split]), "ok 你");
+        assert_eq!(decoder.decode(&bytes[split..]), "好");
+        assert_eq!(decoder.finish(), None);
+
+        assert_eq!(decoder.decode(&[0xE2, 0x82]), "");
+        assert_eq!(decoder.decode(&[0xAC, b'!']), "€!");
+        assert_eq!(decoder.decode(&[b'a', 0xFF, b'b']), "a\u{FFFD}b");
+        assert_eq!(decoder.decode(&[0xF0, 0x9F]), "");
+        assert_eq!(decoder.finish(), Some("\u{FFFD}".to_string()));
+    }
+
+    #[test]
+    fn routing_allowlist_is_parsed_from_tools_enabled() {
+        assert_eq!(
+            ChatRunner::allowed_routing_targets(&serde_json::json!({})),
+            None
+        );
+        assert_eq!(
+            ChatRunner::allowed_routing_targets(
+                &serde_json::json!({ "allowed_routing_targets": null })
+            ),
+            None
+        );
+        assert_eq!(
+            ChatRunner::allowed_routing_targets(
+                &serde_json::json!({ "allowed_routing_targets": [] })
+            ),
+            None
+        );
+
+        let allowed = ChatRunner::allowed_routing_targets(&serde_json::json!({
+            "allowed_routing_targets": ["reviewer", "@Product  Manager", ""]
         }))
+        .expect("allowlist");
+        assert_eq!(allowed, vec!["reviewer", "Product Manager"]);
+        assert!(ChatRunner::is_routing_target_allowed(&allowed, "Reviewer"));
+        assert!(ChatRunner::is_routing_target_allowed(
+            &allowed,
+            "product manager"
+        ));
+        assert!(!ChatRunner::is_routing_target_allowed(&allowed, "deployer"));
     }
 
-    async fn build_message_attachment_context(
-        &self,
-        source_message: &ChatMessage,
-        context_dir: &Path,
-    ) -> Result<Option<MessageAttachmentContext>, ChatRunnerError> {
-        let attachments = chat::extract_attachments(&source_message.meta.0);
-        if attachments.is_empty() {
-            return Ok(None);
+    #[test]
+    fn unknown_mention_policy_controls_failure_reports() {
+        let warned = DashMap::new();
+        let session_id = Uuid::new_v4();
+        let other_session = Uuid::new_v4();
+
+        assert!(ChatRunner::should_report_unknown_mention(
+            UnknownMentionPolicy::Fail,
+            &warned,
+            session_id,
+            "ghost"
+        ));
+        assert!(!ChatRunner::should_report_unknown_mention(
+            UnknownMentionPolicy::Ignore,
+            &warned,
+            session_id,
+            "ghost"
+        ));
+        assert!(ChatRunner::should_report_unknown_mention(
+            UnknownMentionPolicy::WarnOnce,
+            &warned,
+            session_id,
+            "ghost"
+        ));
+        assert!(!ChatRunner::should_report_unknown_mention(
+            UnknownMentionPolicy::WarnOnce,
+            &warned,
+            session_id,
+            "Ghost"
+        ));
+        assert!(ChatRunner::should_report_unknown_mention(
+            UnknownMentionPolicy::WarnOnce,
+            &warned,
+            other_session,
+            "ghost"
+        ));
+    }
+
+    #[tokio::test]
+    async fn forgetting_a_session_rearms_its_unknown_mention_warnings() {
+        let (runner, _) = runner_with_session_agent().await;
+        let session_id = Uuid::new_v4();
+        let other_session = Uuid::new_v4();
+        for session in [session_id, other_session] {
+            assert!(ChatRunner::should_report_unknown_mention(
+                UnknownMentionPolicy::WarnOnce,
+                &runner.warned_unknown_mentions,
+                session,
+                "ghost"
+            ));
         }
 
-        let message_dir = context_dir
-            .join("attachments")
-            .join(source_message.id.to_string());
-        fs::create_dir_all(&message_dir).await?;
+        runner.forget_session(session_id);
 
-        let mut message_attachments = Vec::new();
-        for attachment in attachments {
-            let relative = PathBuf::from(&attachment.relative_path);
-            if relative.is_absolute()
-                || relative
-                    .components()
-                    .any(|component| matches!(component, Component::ParentDir))
-            {
-                continue;
-            }
+        assert!(ChatRunner::should_report_unknown_mention(
+            UnknownMentionPolicy::WarnOnce,
+            &runner.warned_unknown_mentions,
+            session_id,
+            "ghost"
+        ));
+        assert!(!ChatRunner::should_report_unknown_mention(
+            UnknownMentionPolicy::WarnOnce,
+            &runner.warned_unknown_mentions,
+            other_session,
+            "ghost"
+        ));
+    }
 
-            let source_path = asset_dir().join(&relative);
-            let file_name = source_path
-                .file_name()
-                .map(|name| name.to_string_lossy().to_string())
-                .unwrap_or_else(|| attachment.name.clone());
-            let dest_path = message_dir.join(&file_name);
-            let local_path = if fs::copy(&source_path, &dest_path).await.is_ok() {
-                dest_path.to_string_lossy().to_string()
-            } else {
-                source_path.to_string_lossy().to_string()
-            };
+    #[test]
+    fn forwarded_from_records_agent_source_and_target() {
+        let sender_id = Uuid::new_v4();
+        let message = make_message(ChatSenderType::Agent, Some(sender_id));
+        let forwarded =
+            ChatRunner::build_forwarded_from(&message, "reviewer").expect("forwarded_from");
+        assert_eq!(forwarded.message_id, message.id);
+        assert_eq!(forwarded.agent_id, Some(sender_id));
+        assert_eq!(forwarded.directive_target, "reviewer");
+    }
 
-            message_attachments.push(ReferenceAttachment {
-                name: attachment.name,
-                mime_type: attachment.mime_type,
-                size_bytes: attachment.size_bytes,
-                kind: attachment.kind,
-                local_path,
-            });
-        }
+    #[test]
+    fn forwarded_from_is_absent_for_user_messages() {
+        let message = make_message(ChatSenderType::User, None);
+        assert!(ChatRunner::build_forwarded_from(&message, "reviewer").is_none());
+    }
 
-        Ok(Some(MessageAttachmentContext {
-            message_id: source_message.id,
-            attachments: message_attachments,
-        }))
+    #[test]
+    fn parse_token_usage_from_codex_token_count_line() {
+        let line = r#"{"method":"codex/event/token_count","params":{"msg":{"info":{"last_token_usage":{"total_tokens":53002},"model_context_window":258400}}}}"#;
+        let usage = parse_usage(line).expect("usage");
+        assert_eq!(usage.total_tokens, 53002);
+        assert_eq!(usage.model_context_window, 258400);
     }
 
-    async fn build_session_agent_summaries(
-        &self,
-        session_id: Uuid,
-    ) -> Result<Vec<SessionAgentSummary>, ChatRunnerError> {
-        let session_agents =
-            ChatSessionAgent::find_all_for_session(&self.db.pool, session_id).await?;
-        if session_agents.is_empty() {
-            return Ok(Vec::new());
-        }
+    #[test]
+    fn parse_token_usage_from_codex_token_count_breakdown() {
+        let line = r#"{"method":"codex/event/token_count","params":{"msg":{"info":{"last_token_usage":{"input_tokens":50000,"cached_input_tokens":12000,"output_tokens":3002,"total_tokens":53002},"model_context_window":258400}}}}"#;
+        let usage = parse_usage(line).expect("usage");
+        assert_eq!(usage.input_tokens, Some(50000));
+        assert_eq!(usage.output_tokens, Some(3002));
+        assert_eq!(usage.cache_read_tokens, Some(12000));
+    }
 
-        let agents = ChatAgent::find_all(&self.db.pool).await?;
-        let agent_map: HashMap<Uuid, ChatAgent> =
-            agents.into_iter().map(|agent| (agent.id, agent)).collect();
+    #[test]
+    fn parse_token_usage_from_nested_usage_line() {
+        let line = r#"{"type":"result","usage":{"input_tokens":1200,"cache_read_input_tokens":8000,"cache_creation_input_tokens":300,"output_tokens":450}}"#;
+        let usage = parse_usage(line).expect("usage");
+        assert_eq!(usage.total_tokens, 9950);
+        assert_eq!(usage.model_context_window, 0);
+        assert_eq!(usage.input_tokens, Some(1200));
+        assert_eq!(usage.output_tokens, Some(450));
+        assert_eq!(usage.cache_read_tokens, Some(8000));
+    }
 
-        let mut summaries = Vec::with_capacity(session_agents.len());
-        for session_agent in session_agents {
-            let Some(agent) = agent_map.get(&session_agent.agent_id) else {
-                tracing::warn!(
-                    session_agent_id = %session_agent.id,
-                    agent_id = %session_agent.agent_id,
-                    "chat session agent missing backing agent"
+    #[test]
+    fn parse_token_usage_from_message_usage_with_prompt_naming() {
+        let line = r#"{"type":"assistant","message":{"usage":{"prompt_tokens":900,"completion_tokens":100,"total_tokens":1000,"prompt_tokens_details":{"cached_tokens":400}}}}"#;
+        let usage = parse_usage(line).expect("usage");
+        assert_eq!(usage.total_tokens, 1000);
+        assert_eq!(usage.input_tokens, Some(900));
+        assert_eq!(usage.output_tokens, Some(100));
+        assert_eq!(usage.cache_read_tokens, Some(400));
+    }
+
+    #[test]
+    fn parse_token_usage_ignores_unrelated_lines() {
+        assert!(parse_usage(r#"{"type":"text"}"#).is_none());
+        assert!(parse_usage(r#"{"usage":{"note":"n/a"}}"#).is_none());
+        assert!(parse_usage("not json").is_none());
+    }
+
+    #[test]
+    fn parse_token_usage_skips_oversized_lines() {
+        let max_line_bytes = default_max_token_usage_line_bytes();
+        let padding = "x".repeat(max_line_bytes);
+        let line = format!(
+            r#"{{"type":"token_usage","total_tokens":1,"model_context_window":2,"pad":"{padding}"}}"#
+        );
+        assert!(parse_usage(&line).is_none());
+        // A larger configured limit accepts the same line.
+        assert!(ChatRunner::parse_token_usage_from_stdout_line(&line, line.len()).is_some());
+        assert!(!ChatRunner::may_contain_token_usage(
+            r#"["usage"]"#,
+            max_line_bytes
+        ));
+        assert!(ChatRunner::may_contain_token_usage(
+            r#"  {"method":"codex/event/token_count"}"#,
+            max_line_bytes
+        ));
+    }
+
+    /// Throughput of the stdout token-usage scan over a typical executor stream. Run with
+    /// `cargo test -p services --release -- --ignored --nocapture token_usage_scan_benchmark`.
+    #[test]
+    #[ignore = "benchmark"]
+    fn token_usage_scan_benchmark() {
+        let max_line_bytes = default_max_token_usage_line_bytes();
+        let mut stream = String::new();
+        for index in 0..2_000 {
+            stream.push_str(&format!(
+                r#"{{"type":"assistant","message":{{"content":"line {index} {}"}}}}"#,
+                "text ".repeat(40)
+            ));
+            stream.push('\n');
+            if index % 100 == 0 {
+                stream.push_str(&format!(
+                    "{{\"type\":\"tool_result\",\"output\":\"{}\"}}\n",
+                    "x".repeat(256 * 1024)
+                ));
+                stream.push_str(
+                    r#"{"type":"result","usage":{"input_tokens":1200,"output_tokens":450}}"#,
                 );
-                continue;
-            };
-            let system_prompt = agent.system_prompt.trim();
-            // Extract description from first line of system prompt or use agent name
-            let description = if !system_prompt.is_empty() {
-                system_prompt
-                    .lines()
-                    .next()
-                    .map(|line| line.trim().to_string())
-                    .filter(|s| !s.is_empty())
-            } else {
-                None
-            };
-            summaries.push(SessionAgentSummary {
-                session_agent_id: session_agent.id,
-                agent_id: agent.id,
-                name: agent.name.clone(),
-                runner_type: agent.runner_type.clone(),
-                state: session_agent.state,
-                description,
-                system_prompt: if system_prompt.is_empty() {
-                    None
-                } else {
-                    Some(system_prompt.to_string())
-                },
-                tools_enabled: agent.tools_enabled.0.clone(),
-            });
+                stream.push('\n');
+            }
+        }
+        let chunks: Vec<&str> = stream
+            .as_bytes()
+            .chunks(8 * 1024)
+            .map(|chunk| std::str::from_utf8(chunk).unwrap())
+            .collect();
+
+        let iterations = 20;
+        let started = std::time::Instant::now();
+        for _ in 0..iterations {
+            let mut buffer = String::new();
+            let mut usage = None;
+            for chunk in &chunks {
+                ChatRunner::update_token_usage_from_stdout_chunk(
+                    &mut buffer,
+                    &mut usage,
+                    chunk,
+                    max_line_bytes,
+                );
+            }
+            ChatRunner::flush_token_usage_buffer(&mut buffer, &mut usage, max_line_bytes);
+            assert!(usage.is_some());
         }
+        let elapsed = started.elapsed();
+        let mib = (stream.len() * iterations) as f64 / (1024.0 * 1024.0);
+        println!(
+            "token usage scan: {mib:.1} MiB in {elapsed:?} ({:.1} MiB/s)",
+            mib / elapsed.as_secs_f64()
+        );
+    }
 
-        Ok(summaries)
+    #[test]
+    fn parse_token_usage_from_plain_token_usage_line() {
+        let line = r#"{"type":"token_usage","total_tokens":14596,"model_context_window":258400}"#;
+        let usage = parse_usage(line).expect("usage");
+        assert_eq!(usage.total_tokens, 14596);
+        assert_eq!(usage.model_context_window, 258400);
     }
 
-    /// Build the system prompt containing agent role, group members, and critical instructions.
-    /// This is separated from the user message for potential future API-level system prompt support.
-    fn build_system_prompt(
-        &self,
-        agent: &ChatAgent,
-        session_agents: &[SessionAgentSummary],
-        chat_history_path: &Path,
-    ) -> String {
-        let mut system = String::new();
+    #[tokio::test]
+    async fn scratch_dir_prunes_expired_entries_only_with_retention() {
+        let temp_dir = tempfile::tempdir().expect("tempdir");
+        let scratch = temp_dir.path().join("scratch");
+        ChatRunner::prepare_scratch_dir(&scratch, None)
+            .await
+            .expect("create scratch");
+        tokio::fs::write(scratch.join("notes.txt"), "draft")
+            .await
+            .expect("write scratch file");
+        tokio::fs::create_dir_all(scratch.join("tmp/nested"))
+            .await
+            .expect("create nested dir");
 
-        // 1. Agent role settings
-        if !agent.system_prompt.trim().is_empty() {
-            system.push_str("[AGENT_ROLE]\n");
-            system.push_str(agent.system_prompt.trim());
-            system.push_str("\n[/AGENT_ROLE]\n\n");
-        }
+        ChatRunner::prepare_scratch_dir(&scratch, None)
+            .await
+            .expect("keep scratch");
+        assert!(scratch.join("notes.txt").exists());
 
-        // 2. Group members info (separate from AGENT_ROLE)
-        system.push_str("[GROUP_MEMBERS]\n");
-        system.push_str("Current AI members in this group:\n");
-        if session_agents.is_empty() {
-            system.push_str("- No other AI members\n");
-        } else {
-            for member in session_agents {
-                let description = member.description.as_deref().unwrap_or("AI assistant");
-                system.push_str(&format!(
-                    "- {}: {} (state: {:?})\n",
-                    member.name, description, member.state
-                ));
-            }
-        }
-        system.push_str("[/GROUP_MEMBERS]\n\n");
+        ChatRunner::prepare_scratch_dir(&scratch, Some(std::time::Duration::from_secs(3600)))
+            .await
+            .expect("keep fresh entries");
+        assert!(scratch.join("notes.txt").exists());
+        assert!(scratch.join("tmp").exists());
 
-        // 3. Message routing protocol
-        system.push_str("[MESSAGE_ROUTING]\n");
-        system.push_str(
-            "When you want to forward work to another member, include an explicit routing marker in your reply:\n",
-        );
-        system.push_str("[sendMessageTo@@member_name]\n");
-        system.push_str("Rules:\n");
-        system.push_str("- Only this marker triggers forwarding.\n");
-        system.push_str("- Both [sendMessageTo@@member_name] and [sendMessageTo@@{member_name}] are accepted.\n");
-        system.push_str("- Plain @member text is normal content and never triggers forwarding.\n");
-        system.push_str("- member_name must exactly match a name in [GROUP_MEMBERS].\n");
-        system.push_str("- Multiple targets are allowed by adding multiple markers.\n");
-        system.push_str("[/MESSAGE_ROUTING]\n\n");
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        ChatRunner::prepare_scratch_dir(&scratch, Some(std::time::Duration::ZERO))
+            .await
+            .expect("prune expired entries");
+        assert!(scratch.exists());
+        assert!(!scratch.join("notes.txt").exists());
+        assert!(!scratch.join("tmp").exists());
+    }
 
-        // 4. Critical instruction to read history file
-        system.push_str("[CRITICAL_INSTRUCTION]\n");
-        system
-            .push_str("Before doing any task, you must first read the group chat history file:\n");
-        system.push_str(&format!(
-            "file_path: {}\n",
-            chat_history_path.to_string_lossy()
-        ));
-        system.push_str("format: JSON, containing sender and content fields\n");
-        system.push_str("This is mandatory: understand group context first, then respond.\n");
-        system.push_str("[/CRITICAL_INSTRUCTION]\n");
+    #[tokio::test]
+    async fn scratch_dir_keeps_directories_with_recent_contents() {
+        let temp_dir = tempfile::tempdir().expect("tempdir");
+        let scratch = temp_dir.path().join("scratch");
+        let two_hours_ago = std::time::SystemTime::now() - std::time::Duration::from_secs(7200);
+        let age = |path: &std::path::Path| {
+            std::fs::File::open(path)
+                .and_then(|file| file.set_modified(two_hours_ago))
+                .expect("backdate entry");
+        };
 
-        system
+        // Old directory whose nested file is still being written.
+        let active = scratch.join("build/out");
+        std::fs::create_dir_all(&active).expect("create active dir");
+        std::fs::write(active.join("log.txt"), "fresh").expect("write fresh file");
+        age(&active);
+        age(&scratch.join("build"));
+        // Old directory with only old contents.
+        let stale = scratch.join("stale/inner");
+        std::fs::create_dir_all(&stale).expect("create stale dir");
+        std::fs::write(stale.join("old.txt"), "old").expect("write old file");
+        age(&stale.join("old.txt"));
+        age(&stale);
+        age(&scratch.join("stale"));
+
+        ChatRunner::prepare_scratch_dir(&scratch, Some(std::time::Duration::from_secs(3600)))
+            .await
+            .expect("prune expired entries");
+        assert!(active.join("log.txt").exists());
+        assert!(!scratch.join("stale").exists());
     }
 
-    /// Build the user message prompt (envelope, reference, attachments, message).
-    #[allow(clippy::too_many_arguments)]
-    fn build_user_prompt(
-        &self,
-        agent: &ChatAgent,
-        message: &ChatMessage,
-        message_attachments: Option<&MessageAttachmentContext>,
-        reference: Option<&ReferenceContext>,
-    ) -> String {
-        let mut prompt = String::new();
+    #[tokio::test]
+    async fn oversized_attachments_are_referenced_instead_of_copied() {
+        let temp_dir = tempfile::tempdir().expect("tempdir");
+        let source = temp_dir.path().join("upload.bin");
+        tokio::fs::write(&source, vec![0u8; 64])
+            .await
+            .expect("write attachment");
+
+        let small_dest = temp_dir.path().join("copied.bin");
+        let (local_path, too_large) =
+            ChatRunner::copy_attachment(&source, &small_dest, Some(64)).await;
+        assert!(!too_large);
+        assert_eq!(local_path, small_dest.to_string_lossy());
+        assert!(small_dest.exists());
+
+        let large_dest = temp_dir.path().join("skipped.bin");
+        let (local_path, too_large) =
+            ChatRunner::copy_attachment(&source, &large_dest, Some(63)).await;
+        assert!(too_large);
+        assert_eq!(local_path, source.to_string_lossy());
+        assert!(!large_dest.exists());
+
+        let unlimited_dest = temp_dir.path().join("unlimited.bin");
+        let (_, too_large) = ChatRunner::copy_attachment(&source, &unlimited_dest, None).await;
+        assert!(!too_large);
+        assert!(unlimited_dest.exists());
+    }
 
-        // Envelope metadata
-        prompt.push_str("[ENVELOPE]\n");
-        prompt.push_str(&format!("session_id={}\n", message.session_id));
-        let sender_handle = self.resolve_reply_handle(message);
-        prompt.push_str(&format!("from=user:{}\n", sender_handle));
-        prompt.push_str(&format!("to=agent:{}\n", agent.name));
-        prompt.push_str(&format!("message_id={}\n", message.id));
-        prompt.push_str(&format!("timestamp={}\n", message.created_at));
-        prompt.push_str("[/ENVELOPE]\n\n");
+    #[tokio::test]
+    async fn capped_run_log_stops_writing_and_appends_marker() {
+        let temp_dir = tempfile::tempdir().expect("tempdir");
+        let path = temp_dir.path().join("raw.log");
+        let file = tokio::fs::File::create(&path)
+            .await
+            .expect("create raw.log");
+        let mut log = CappedRunLog::new(file, 8);
 
-        // Reference message (if any)
-        if let Some(reference) = reference {
-            prompt.push_str("[REFERENCE_MESSAGE]\n");
-            prompt.push_str(
-                "User referenced the following historical group chat message. Prioritize it.\n",
-            );
-            prompt.push_str(&format!("reference_id={}\n", reference.message_id));
-            prompt.push_str(&format!("reference_sender={}\n", reference.sender_label));
-            prompt.push_str(&format!(
-                "reference_sender_type={:?}\n",
-                reference.sender_type
-            ));
-            prompt.push_str(&format!("reference_created_at={}\n", reference.created_at));
-            if !reference.attachments.is_empty() {
-                prompt.push_str("reference_attachments:\n");
-                for attachment in &reference.attachments {
-                    prompt.push_str(&format!(
-                        "- name={} kind={} size_bytes={} mime_type={} local_path={}\n",
-                        attachment.name,
-                        attachment.kind,
-                        attachment.size_bytes,
-                        attachment.mime_type.as_deref().unwrap_or("unknown"),
-                        attachment.local_path
-                    ));
-                }
-            }
-            prompt.push_str("reference_content:\n");
-            prompt.push_str(reference.content.trim());
-            prompt.push_str("\n[/REFERENCE_MESSAGE]\n\n");
-        }
+        log.write("12345").await;
+        log.write("67890").await;
+        log.write("dropped").await;
+        drop(log);
 
-        // Message attachments (if any)
-        if let Some(message_attachments) = message_attachments
-            && !message_attachments.attachments.is_empty()
-        {
-            prompt.push_str("[MESSAGE_ATTACHMENTS]\n");
-            prompt.push_str("Attachments included with this message.\n");
-            prompt.push_str(&format!("message_id={}\n", message_attachments.message_id));
-            for attachment in &message_attachments.attachments {
-                prompt.push_str(&format!(
-                    "- name={} kind={} size_bytes={} mime_type={} local_path={}\n",
-                    attachment.name,
-                    attachment.kind,
-                    attachment.size_bytes,
-                    attachment.mime_type.as_deref().unwrap_or("unknown"),
-                    attachment.local_path
-                ));
-            }
-            prompt.push_str("[/MESSAGE_ATTACHMENTS]\n\n");
-        }
+        let contents = tokio::fs::read_to_string(&path)
+            .await
+            .expect("read raw.log");
+        assert!(contents.starts_with("12345678\n[raw.log truncated"));
+        assert!(!contents.contains("dropped"));
+        assert_eq!(contents.matches("raw.log truncated").count(), 1);
+    }
 
-        // User message (simplified format: sender + content)
-        prompt.push_str("[USER_MESSAGE]\n");
-        prompt.push_str(&format!("{}: {}\n", sender_handle, message.content.trim()));
-        prompt.push_str("[/USER_MESSAGE]\n");
+    #[test]
+    fn approval_prompt_describes_command_or_path() {
+        assert_eq!(
+            ChatRunner::approval_prompt("Bash", &serde_json::json!({ "command": "cargo test" })),
+            "Allow Bash: cargo test"
+        );
+        assert_eq!(
+            ChatRunner::approval_prompt("exec", &serde_json::json!({ "command": ["git", "push"] })),
+            "Allow exec: git push"
+        );
+        assert_eq!(
+            ChatRunner::approval_prompt("Edit", &serde_json::json!({ "file_path": "src/lib.rs" })),
+            "Allow Edit: src/lib.rs"
+        );
+        assert_eq!(
+            ChatRunner::approval_prompt("WebFetch", &serde_json::json!({})),
+            "Allow WebFetch?"
+        );
+    }
 
-        prompt
+    #[test]
+    fn auto_commit_requires_explicit_opt_in() {
+        assert!(!ChatRunner::auto_commit_enabled(&serde_json::json!({})));
+        assert!(!ChatRunner::auto_commit_enabled(
+            &serde_json::json!({ "auto_commit": "true" })
+        ));
+        assert!(ChatRunner::auto_commit_enabled(
+            &serde_json::json!({ "auto_commit": true })
+        ));
     }
 
-    /// Build the full prompt by combining system prompt and user prompt.
-    /// This maintains backwards compatibility while allowing future separation.
-    #[allow(clippy::too_many_arguments)]
-    fn build_prompt(
-        &self,
-        agent: &ChatAgent,
-        message: &ChatMessage,
-        context_path: &Path,
-        session_agents: &[SessionAgentSummary],
-        message_attachments: Option<&MessageAttachmentContext>,
-        reference: Option<&ReferenceContext>,
-    ) -> String {
-        // Build system prompt with agent role, group members, and history file instruction
-        let system_prompt = self.build_system_prompt(agent, session_agents, context_path);
+    #[test]
+    fn chat_approvals_require_explicit_opt_in() {
+        assert!(!ChatRunner::chat_approvals_enabled(&serde_json::json!({})));
+        assert!(!ChatRunner::chat_approvals_enabled(
+            &serde_json::json!({ "chat_approvals": "true" })
+        ));
+        assert!(ChatRunner::chat_approvals_enabled(
+            &serde_json::json!({ "chat_approvals": true })
+        ));
+    }
 
-        // Build user prompt with envelope, reference, attachments, and message
-        let user_prompt = self.build_user_prompt(agent, message, message_attachments, reference);
+    #[tokio::test]
+    async fn auto_commit_leaves_runtime_dir_unstaged() {
+        let temp_dir = tempfile::tempdir().expect("tempdir");
+        let git = |args: &[&str]| {
+            let status = std::process::Command::new("git")
+                .arg("-C")
+                .arg(temp_dir.path())
+                .args(args)
+                .status()
+                .expect("run git");
+            assert!(status.success(), "git {args:?}");
+        };
+        git(&["init", "-q"]);
+        git(&["config", "user.email", "test@example.com"]);
+        git(&["config", "user.name", "test"]);
+        std::fs::write(temp_dir.path().join("main.rs"), "fn main() {}\n").unwrap();
+        let runtime_dir = temp_dir.path().join(".agents_chatgroup");
+        std::fs::create_dir_all(&runtime_dir).unwrap();
+        std::fs::write(runtime_dir.join("context.jsonl"), "{}\n").unwrap();
+
+        ChatRunner::auto_commit_changes(temp_dir.path(), "apply changes")
+            .await
+            .expect("auto-commit");
 
-        // Combine system and user prompts
-        let mut full_prompt = system_prompt;
-        full_prompt.push('\n');
+        let tracked = std::process::Command::new("git")
+            .arg("-C")
+            .arg(temp_dir.path())
+            .args(["ls-files"])
+            .output()
+            .expect("list tracked files");
+        assert_eq!(String::from_utf8_lossy(&tracked.stdout), "main.rs\n");
+    }
 
-        full_prompt.push_str(&user_prompt);
-        full_prompt
+    #[test]
+    fn run_cost_uses_agent_price_over_configured_one() {
+        let config = ChatRunnerConfig {
+            cost_per_million_tokens_cents: 300,
+            ..Default::default()
+        };
+        assert_eq!(
+            ChatRunner::cost_per_million_tokens_cents(&serde_json::json!({}), &config),
+            300
+        );
+        let cents = ChatRunner::cost_per_million_tokens_cents(
+            &serde_json::json!({ "cost_per_million_tokens_cents": 1500 }),
+            &config,
+        );
+        assert_eq!(cents, 1500);
+        assert!((ChatRunner::estimate_run_cost(2_000_000, cents) - 30.0).abs() < f64::EPSILON);
+        assert_eq!(ChatRunner::estimate_run_cost(1_000, 0), 0.0);
     }
 
-    fn spawn_log_forwarders(
-        &self,
-        child: &mut command_group::AsyncGroupChild,
-        msg_store: Arc<MsgStore>,
-        raw_log_file: Arc<Mutex<fs::File>>,
-    ) {
-        let stdout = child
-            .inner()
-            .stdout
-            .take()
-            .expect("chat runner missing stdout");
-        let stderr = child
-            .inner()
-            .stderr
-            .take()
-            .expect("chat runner missing stderr");
+    #[test]
+    fn parses_forward_decisions_from_moderator_reply() {
+        let approved = Uuid::new_v4();
+        let rejected = Uuid::new_v4();
+        let content = format!(
+            "Looks good [approveForward@@{approved}] but not this one [rejectForward@@{rejected}] [approveForward@@not-a-uuid]"
+        );
+        let decisions = ChatRunner::parse_forward_decisions(&content);
+        assert_eq!(decisions, vec![(approved, true), (rejected, false)]);
+    }
 
-        let stdout_store = msg_store.clone();
-        let stdout_log = raw_log_file.clone();
-        tokio::spawn(async move {
-            let mut stream = ReaderStream::new(stdout);
-            while let Some(chunk) = stream.next().await {
-                match chunk {
-                    Ok(bytes) => {
-                        let text = String::from_utf8_lossy(&bytes).into_owned();
-                        stdout_store.push(LogMsg::Stdout(text.clone()));
-                        let mut file = stdout_log.lock().await;
-                        let _ = file.write_all(text.as_bytes()).await;
-                    }
-                    Err(err) => {
-                        stdout_store.push(LogMsg::Stderr(format!("stdout error: {err}")));
-                    }
-                }
-            }
-        });
+    #[test]
+    fn run_checkpoint_requires_stored_agent_session() {
+        let checkpoint = ChatRunner::run_checkpoint_from_meta(&serde_json::json!({
+            "agent_session_id": "sess-1",
+            "agent_message_id": "msg-9",
+        }));
+        assert_eq!(
+            checkpoint,
+            Some(RunCheckpoint {
+                agent_session_id: "sess-1".to_string(),
+                agent_message_id: Some("msg-9".to_string()),
+            })
+        );
 
-        let stderr_store = msg_store.clone();
-        let stderr_log = raw_log_file.clone();
-        tokio::spawn(async move {
-            let mut stream = ReaderStream::new(stderr);
-            while let Some(chunk) = stream.next().await {
-                match chunk {
-                    Ok(bytes) => {
-                        let text = String::from_utf8_lossy(&bytes).into_owned();
-                        stderr_store.push(LogMsg::Stderr(text.clone()));
-                        let mut file = stderr_log.lock().await;
-                        let _ = file.write_all(text.as_bytes()).await;
-                    }
-                    Err(err) => {
-                        stderr_store.push(LogMsg::Stderr(format!("stderr error: {err}")));
-                    }
-                }
-            }
-        });
+        let without_message = ChatRunner::run_checkpoint_from_meta(&serde_json::json!({
+            "agent_session_id": "sess-1",
+            "agent_message_id": null,
+        }))
+        .expect("session id alone is enough to continue");
+        assert_eq!(without_message.agent_message_id, None);
+
+        assert_eq!(
+            ChatRunner::run_checkpoint_from_meta(&serde_json::json!({ "agent_session_id": null })),
+            None
+        );
+        assert_eq!(
+            ChatRunner::run_checkpoint_from_meta(&serde_json::json!({ "agent_session_id": "" })),
+            None
+        );
     }
 
-    fn parse_token_usage_from_stdout_line(line: &str) -> Option<TokenUsageInfo> {
-        let value: serde_json::Value = serde_json::from_str(line).ok()?;
-        let value_obj = value.as_object()?;
+    #[test]
+    fn reserved_handles_include_user_handle_and_configured_names() {
+        let mut config = ChatRunnerConfig {
+            reserved_handles: vec!["system".to_string(), "Every One".to_string()],
+            ..Default::default()
+        };
+        assert!(config.is_reserved_handle("you"));
+        assert!(config.is_reserved_handle("@System"));
+        assert!(config.is_reserved_handle("every   one"));
+        assert!(!config.is_reserved_handle("coder"));
+
+        config.reserved_handles.clear();
+        assert!(config.is_reserved_handle("YOU"));
+        assert!(!config.is_reserved_handle("system"));
+        assert!(!config.is_reserved_handle(""));
+        assert!(!config.is_reserved_handle("@"));
+        assert!(!config.is_reserved_handle("young"));
+    }
 
-        if value_obj.get("type").and_then(|v| v.as_str()) == Some("token_usage") {
-            let total_tokens = value_obj
-                .get("total_tokens")
-                .and_then(|v| v.as_u64())
-                .and_then(|v| u32::try_from(v).ok())?;
-            let model_context_window = value_obj
-                .get("model_context_window")
-                .and_then(|v| v.as_u64())
-                .and_then(|v| u32::try_from(v).ok())?;
-            return Some(TokenUsageInfo {
-                total_tokens,
-                model_context_window,
-                input_tokens: None,
-                output_tokens: None,
-                cache_read_tokens: None,
-                is_estimated: false,
-            });
-        }
+    #[test]
+    fn case_sensitive_reserved_handles_only_match_exact_casing() {
+        let config = ChatRunnerConfig {
+            reserved_handles: vec!["System".to_string()],
+            reserved_handle_case_sensitive: true,
+            ..Default::default()
+        };
+        assert!(config.is_reserved_handle("@you"));
+        assert!(!config.is_reserved_handle("You"));
+        assert!(config.is_reserved_handle("System"));
+        assert!(!config.is_reserved_handle("system"));
+        assert!(config.is_user_handle(" @you "));
+        assert!(!config.is_user_handle("YOU"));
+        assert!(!config.is_user_handle("System"));
+        assert!(ChatRunnerConfig::default().is_user_handle("You"));
+    }
 
-        if value_obj.get("method").and_then(|v| v.as_str()) != Some("codex/event/token_count") {
-            return None;
+    #[test]
+    fn reply_handle_falls_back_to_user_handle() {
+        let reply = |meta: serde_json::Value| ChatRunner::reply_handle_from_meta(&meta);
+        assert_eq!(reply(serde_json::json!({})), "you");
+        assert_eq!(reply(serde_json::json!({ "sender_handle": "!!" })), "you");
+        assert_eq!(reply(serde_json::json!({ "sender_handle": "YOU" })), "you");
+        assert_eq!(
+            reply(serde_json::json!({ "sender_handle": "al ice" })),
+            "alice"
+        );
+        assert_eq!(
+            reply(serde_json::json!({ "sender_handle": "youth" })),
+            "youth"
+        );
+    }
+
+    fn drain_deltas(
+        receiver: &mut tokio::sync::broadcast::Receiver<ChatStreamEvent>,
+    ) -> Vec<(ChatStreamDeltaType, String, bool)> {
+        let mut deltas = Vec::new();
+        while let Ok(event) = receiver.try_recv() {
+            if let ChatStreamEvent::AgentDelta {
+                stream_type,
+                content,
+                delta,
+                ..
+            } = event
+            {
+                deltas.push((stream_type, content, delta));
+            }
         }
+        deltas
+    }
 
-        let info = value_obj
-            .get("params")
-            .and_then(|v| v.get("msg"))
-            .and_then(|v| v.get("info"))?;
+    #[tokio::test]
+    async fn delta_batcher_coalesces_in_order() {
+        let (sender, mut receiver) = tokio::sync::broadcast::channel(16);
+        let id = Uuid::new_v4();
+        let mut batcher = AgentDeltaBatcher::new(
+            sender,
+            id,
+            id,
+            id,
+            id,
+            std::time::Duration::from_millis(50),
+            1024,
+        );
 
-        let total_tokens = info
-            .get("last_token_usage")
-            .and_then(|v| v.get("total_tokens"))
-            .and_then(|v| v.as_u64())
-            .and_then(|v| u32::try_from(v).ok())?;
-        let model_context_window = info
-            .get("model_context_window")
-            .and_then(|v| v.as_u64())
-            .and_then(|v| u32::try_from(v).ok())
-            .unwrap_or(0);
+        batcher.push(ChatStreamDeltaType::Thinking, "plan".to_string(), true);
+        batcher.push(ChatStreamDeltaType::Assistant, "Hel".to_string(), true);
+        batcher.push(ChatStreamDeltaType::Assistant, "lo".to_string(), true);
+        assert!(batcher.deadline().is_some());
+        batcher.flush();
+        assert!(batcher.deadline().is_none());
+
+        batcher.push(ChatStreamDeltaType::Assistant, "draft".to_string(), true);
+        batcher.push(
+            ChatStreamDeltaType::Assistant,
+            "Rewritten".to_string(),
+            false,
+        );
+        batcher.push(ChatStreamDeltaType::Assistant, "!".to_string(), true);
+        batcher.flush();
+
+        assert_eq!(
+            drain_deltas(&mut receiver),
+            vec![
+                (ChatStreamDeltaType::Thinking, "plan".to_string(), true),
+                (ChatStreamDeltaType::Assistant, "Hello".to_string(), true),
+                (
+                    ChatStreamDeltaType::Assistant,
+                    "Rewritten!".to_string(),
+                    false
+                ),
+            ]
+        );
+    }
 
-        Some(TokenUsageInfo {
-            total_tokens,
-            model_context_window,
-            input_tokens: None,
-            output_tokens: None,
-            cache_read_tokens: None,
-            is_estimated: false,
-        })
+    #[tokio::test]
+    async fn delta_batcher_flushes_on_size_and_when_disabled() {
+        let (sender, mut receiver) = tokio::sync::broadcast::channel(16);
+        let id = Uuid::new_v4();
+        let mut batcher = AgentDeltaBatcher::new(
+            sender.clone(),
+            id,
+            id,
+            id,
+            id,
+            std::time::Duration::from_millis(50),
+            4,
+        );
+        batcher.push(ChatStreamDeltaType::Assistant, "ab".to_string(), true);
+        assert!(drain_deltas(&mut receiver).is_empty());
+        batcher.push(ChatStreamDeltaType::Assistant, "cd".to_string(), true);
+        assert_eq!(
+            drain_deltas(&mut receiver),
+            vec![(ChatStreamDeltaType::Assistant, "abcd".to_string(), true)]
+        );
+
+        let mut unbatched =
+            AgentDeltaBatcher::new(sender, id, id, id, id, std::time::Duration::ZERO, 1024);
+        unbatched.push(ChatStreamDeltaType::Assistant, "a".to_string(), true);
+        unbatched.push(ChatStreamDeltaType::Assistant, "b".to_string(), true);
+        assert_eq!(drain_deltas(&mut receiver).len(), 2);
+        assert!(unbatched.deadline().is_none());
     }
 
-    fn update_token_usage_from_stdout_chunk(
-        stdout_line_buffer: &mut String,
-        last_token_usage: &mut Option<TokenUsageInfo>,
-        chunk: &str,
-    ) {
-        stdout_line_buffer.push_str(chunk);
+    #[tokio::test]
+    async fn repeated_identical_patches_are_not_resent() {
+        let (sender, mut receiver) = tokio::sync::broadcast::channel(16);
+        let id = Uuid::new_v4();
+        let mut batcher =
+            AgentDeltaBatcher::new(sender, id, id, id, id, std::time::Duration::ZERO, 1024)
+                .dedupe_identical(true);
+        let mut last_content = std::collections::HashMap::new();
+        let mut latest_assistant = String::new();
+        let mut last_token_usage = None;
+        let mut citations = Vec::new();
+        let assistant = |content: &str| NormalizedEntry {
+            timestamp: None,
+            entry_type: NormalizedEntryType::AssistantMessage,
+            content: content.to_string(),
+            metadata: None,
+        };
 
-        while let Some(newline_index) = stdout_line_buffer.find('\n') {
-            let mut line: String = stdout_line_buffer.drain(..=newline_index).collect();
-            if line.ends_with('\n') {
-                line.pop();
-            }
-            if line.ends_with('\r') {
-                line.pop();
-            }
-            if line.is_empty() {
-                continue;
-            }
-            if let Some(usage) = Self::parse_token_usage_from_stdout_line(&line) {
-                *last_token_usage = Some(usage);
-            }
+        for patch in [
+            ConversationPatch::add_normalized_entry(0, assistant("Hello ")),
+            ConversationPatch::add_normalized_entry(1, assistant("world")),
+            // The executor re-emits the whole message, then repeats it unchanged.
+            ConversationPatch::replace(1, assistant("Hello world")),
+            ConversationPatch::replace(1, assistant("Hello world")),
+        ] {
+            ChatRunner::process_stream_patch(
+                patch,
+                &mut batcher,
+                &mut last_content,
+                &mut latest_assistant,
+                &mut last_token_usage,
+                &mut citations,
+                &mut OutputRedactor::default(),
+            );
         }
+
+        assert_eq!(
+            drain_deltas(&mut receiver),
+            vec![
+                (ChatStreamDeltaType::Assistant, "Hello ".to_string(), true),
+                (ChatStreamDeltaType::Assistant, "world".to_string(), true),
+            ]
+        );
+        assert_eq!(latest_assistant, "Hello world");
     }
 
-    fn flush_token_usage_buffer(
-        stdout_line_buffer: &mut String,
-        last_token_usage: &mut Option<TokenUsageInfo>,
-    ) {
-        if stdout_line_buffer.is_empty() {
-            return;
-        }
-        let line = stdout_line_buffer.trim_end_matches(['\n', '\r']);
-        if !line.is_empty()
-            && let Some(usage) = Self::parse_token_usage_from_stdout_line(line)
-        {
-            *last_token_usage = Some(usage);
+    #[tokio::test]
+    async fn unchanged_patches_still_update_reply_without_dedupe() {
+        let (sender, mut receiver) = tokio::sync::broadcast::channel(16);
+        let id = Uuid::new_v4();
+        let mut batcher =
+            AgentDeltaBatcher::new(sender, id, id, id, id, std::time::Duration::ZERO, 1024);
+        let mut last_content = std::collections::HashMap::new();
+        let mut latest_assistant = String::new();
+        let mut last_token_usage = None;
+        let mut citations = Vec::new();
+        let assistant = |content: &str| NormalizedEntry {
+            timestamp: None,
+            entry_type: NormalizedEntryType::AssistantMessage,
+            content: content.to_string(),
+            metadata: None,
+        };
+
+        for patch in [
+            ConversationPatch::add_normalized_entry(0, assistant("draft")),
+            ConversationPatch::add_normalized_entry(1, assistant("aside")),
+            // Re-emitting an earlier entry makes it the reply again.
+            ConversationPatch::replace(0, assistant("draft")),
+        ] {
+            ChatRunner::process_stream_patch(
+                patch,
+                &mut batcher,
+                &mut last_content,
+                &mut latest_assistant,
+                &mut last_token_usage,
+                &mut citations,
+                &mut OutputRedactor::default(),
+            );
         }
-        stdout_line_buffer.clear();
-    }
 
-    /// 浣跨敤tiktoken浼扮畻鏂囨湰鐨則oken鏁伴噺
-    fn estimate_tokens_with_tiktoken(text: &str) -> u32 {
-        use tiktoken_rs::cl100k_base;
+        assert_eq!(drain_deltas(&mut receiver).len(), 2);
+        assert_eq!(latest_assistant, "draft");
+    }
 
-        match cl100k_base() {
-            Ok(bpe) => bpe.encode_with_special_tokens(text).len() as u32,
-            Err(_) => {
-                // fallback: 绾?涓瓧绗︿竴涓猼oken
-                (text.len() / 4) as u32
-            }
+    #[test]
+    fn event_type_matches_serialized_tag() {
+        let events = [
+            ChatStreamEvent::MessagesDeleted {
+                session_id: Uuid::new_v4(),
+                message_ids: Vec::new(),
+            },
+            ChatStreamEvent::AgentStderr {
+                session_id: Uuid::new_v4(),
+                session_agent_id: Uuid::new_v4(),
+                run_id: Uuid::new_v4(),
+                line: "warning".to_string(),
+            },
+            ChatStreamEvent::MessageNew {
+                message: make_message(ChatSenderType::User, None),
+            },
+        ];
+        for event in events {
+            let value = serde_json::to_value(&event).unwrap();
+            assert_eq!(value["type"], event.event_type());
+            assert!(ChatStreamEvent::EVENT_TYPES.contains(&event.event_type()));
         }
     }
 
-    #[allow(clippy::too_many_arguments)]
-    fn process_stream_patch(
-        patch: json_patch::Patch,
-        session_id: Uuid,
-        session_agent_id: Uuid,
-        agent_id: Uuid,
-        run_id: Uuid,
-        sender: &broadcast::Sender<ChatStreamEvent>,
-        last_content: &mut HashMap<usize, String>,
-        latest_assistant: &mut String,
-        last_token_usage: &mut Option<TokenUsageInfo>,
-    ) {
-        if let Some((index, entry)) = extract_normalized_entry_from_patch(&patch) {
-            let stream_type = match &entry.entry_type {
-                NormalizedEntryType::AssistantMessage => Some(ChatStreamDeltaType::Assistant),
-                NormalizedEntryType::Thinking => Some(ChatStreamDeltaType::Thinking),
-                NormalizedEntryType::TokenUsageInfo(usage) => {
-                    *last_token_usage = Some(usage.clone());
-                    None
-                }
-                _ => None,
-            };
+    #[test]
+    fn forward_reviews_are_never_suppressed() {
+        let config = ChatRunnerConfig {
+            suppressed_system_messages: vec![
+                SystemMessageCategory::MentionFailure,
+                SystemMessageCategory::ForwardReview,
+            ],
+            ..Default::default()
+        };
+        assert!(config.suppresses_system_message(SystemMessageCategory::MentionFailure));
+        assert!(!config.suppresses_system_message(SystemMessageCategory::ForwardReview));
+        assert!(!config.suppresses_system_message(SystemMessageCategory::ForwardOutcome));
+        assert!(
+            !ChatRunnerConfig::default()
+                .suppresses_system_message(SystemMessageCategory::MentionFailure)
+        );
+    }
 
-            if let Some(stream_type) = stream_type {
-                let current = entry.content;
-                let previous = last_content.get(&index).cloned().unwrap_or_default();
-                let (delta, is_delta) = if current.starts_with(&previous) {
-                    (current[previous.len()..].to_string(), true)
-                } else {
-                    (current.clone(), false)
-                };
+    #[test]
+    fn max_output_tokens_ignores_missing_and_zero_caps() {
+        let cap = |tools_enabled: serde_json::Value| ChatRunner::max_output_tokens(&tools_enabled);
+        assert_eq!(cap(serde_json::json!({})), None);
+        assert_eq!(cap(serde_json::json!({ "max_output_tokens": 0 })), None);
+        assert_eq!(
+            cap(serde_json::json!({ "max_output_tokens": 2000 })),
+            Some(2000)
+        );
+    }
 
-                last_content.insert(index, current.clone());
-                if matches!(stream_type, ChatStreamDeltaType::Assistant) {
-                    *latest_assistant = current.clone();
-                }
+    #[test]
+    fn max_output_tokens_must_be_a_positive_integer() {
+        let check =
+            |tools_enabled: serde_json::Value| validate_max_output_tokens(&tools_enabled).is_ok();
+        assert!(check(serde_json::json!({})));
+        assert!(check(serde_json::json!({ "max_output_tokens": null })));
+        assert!(check(serde_json::json!({ "max_output_tokens": 2000 })));
+        assert!(!check(serde_json::json!({ "max_output_tokens": 0 })));
+        assert!(!check(serde_json::json!({ "max_output_tokens": "2000" })));
+        assert!(!check(serde_json::json!({ "max_output_tokens": 1.5 })));
+        assert!(!check(serde_json::json!({ "max_output_tokens": -1 })));
+    }
 
-                if !delta.is_empty() {
-                    let _ = sender.send(ChatStreamEvent::AgentDelta {
-                        session_id,
-                        session_agent_id,
-                        agent_id,
-                        run_id,
-                        stream_type,
-                        content: delta,
-                        delta: is_delta,
-                        is_final: false,
-                    });
+    #[tokio::test]
+    async fn reply_over_max_output_tokens_is_kept_and_continuable() {
+        let (runner, session_agent_id) = runner_with_session_agent().await;
+        let session_agent = runner
+            .claim_or_queue(session_agent_id, make_pending(), None)
+            .await
+            .expect("claim idle agent")
+            .expect("agent claimed");
+        let (session_id, agent_id) = (session_agent.session_id, session_agent.agent_id);
+        let token = CancellationToken::new();
+        runner
+            .cancellation_tokens
+            .insert(session_agent_id, token.clone());
+        let temp_dir = tempfile::tempdir().expect("tempdir");
+        let run_id = Uuid::new_v4();
+        let msg_store = Arc::new(MsgStore::new());
+        let failed_flag = Arc::new(AtomicBool::new(false));
+        let mut events = runner.subscribe(session_id);
+
+        runner.spawn_stream_bridge(
+            msg_store.clone(),
+            session_id,
+            agent_id,
+            session_agent_id,
+            run_id,
+            temp_dir.path().join("output.md"),
+            temp_dir.path().join("meta.json"),
+            temp_dir.path().to_path_buf(),
+            Vec::new(),
+            temp_dir.path().to_path_buf(),
+            None,
+            failed_flag.clone(),
+            0,
+            false,
+            None,
+            None,
+            runner.clone(),
+            Uuid::new_v4(),
+            "reviewer".to_string(),
+            None,
+            false,
+            GitDiffBase::default(),
+            0,
+            std::time::Duration::from_secs(5),
+            AgentDeltaBatcher::new(
+                runner.sender_for(session_id),
+                session_id,
+                session_agent_id,
+                agent_id,
+                run_id,
+                std::time::Duration::ZERO,
+                1024,
+            ),
+            None,
+            Arc::new(AtomicBool::new(false)),
+            Arc::new(AtomicBool::new(false)),
+            OutputRedactor::default(),
+            Some(10),
+            ChatRunnerConfig::default().max_token_usage_line_bytes as usize,
+        );
+        msg_store.push(LogMsg::SessionId("executor-session".to_string()));
+        msg_store.push_patch(ConversationPatch::add_normalized_entry(
+            0,
+            NormalizedEntry {
+                timestamp: None,
+                entry_type: NormalizedEntryType::AssistantMessage,
+                content: "word ".repeat(200),
+                metadata: None,
+            },
+        ));
+        wait_until(|| token.is_cancelled()).await;
+
+        // The exit watcher marks a cancelled run failed before the store finishes.
+        failed_flag.store(true, Ordering::Relaxed);
+        msg_store.push_finished();
+        let final_state = tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            loop {
+                if let Ok(ChatStreamEvent::AgentState { state, .. }) = events.recv().await
+                    && state != ChatSessionAgentState::Running
+                {
+                    return state;
                 }
             }
-        }
-    }
-
-    #[allow(clippy::too_many_arguments)]
-    fn spawn_stream_bridge(
-        &self,
-        msg_store: Arc<MsgStore>,
-        session_id: Uuid,
-        agent_id: Uuid,
-        session_agent_id: Uuid,
-        run_id: Uuid,
-        output_path: PathBuf,
-        meta_path: PathBuf,
-        workspace_path: PathBuf,
-        run_dir: PathBuf,
-        reply_handle: Option<String>,
-        failed_flag: Arc<AtomicBool>,
-        chain_depth: u32,
-        context_compacted: bool,
-        compression_warning: Option<chat::CompressionWarning>,
-        runner: ChatRunner,
-        source_message_id: Uuid,
-        agent_name: String,
-    ) {
-        let db = self.db.clone();
-        let sender = self.sender_for(session_id);
+        })
+        .await
+        .expect("run finished");
+        assert_eq!(final_state, ChatSessionAgentState::Idle);
 
-        tokio::spawn(async move {
-            let mut stream = msg_store.history_plus_stream();
-            let mut last_content: HashMap<usize, String> = HashMap::new();
-            let mut latest_assistant = String::new();
-            let mut agent_session_id: Option<String> = None;
-            let mut agent_message_id: Option<String> = None;
-            let mut last_token_usage: Option<TokenUsageInfo> = None;
-            let mut stdout_line_buffer = String::new();
+        let reply = ChatMessage::find_by_session_id(&runner.db.pool, session_id, None)
+            .await
+            .unwrap()
+            .into_iter()
+            .find(|message| message.sender_type == ChatSenderType::Agent)
+            .expect("truncated reply is posted");
+        assert_eq!(reply.meta.0["failed"], false);
+        assert_eq!(
+            reply.meta.0[OUTPUT_TRUNCATED_META_KEY]["reason"],
+            "max_output_reached"
+        );
+        assert_eq!(
+            reply.meta.0[OUTPUT_TRUNCATED_META_KEY]["max_output_tokens"],
+            10
+        );
+        // The executor session survives, so the reply can be continued.
+        assert_eq!(reply.meta.0["agent_session_id"], "executor-session");
+        let session_agent = ChatSessionAgent::find_by_id(&runner.db.pool, session_agent_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            session_agent.agent_session_id.as_deref(),
+            Some("executor-session")
+        );
+    }
 
-            while let Some(item) = stream.next().await {
-                match item {
-                    Ok(LogMsg::SessionId(session_id_value)) => {
-                        if agent_session_id.as_deref() != Some(&session_id_value) {
-                            agent_session_id = Some(session_id_value.clone());
-                            let _ = ChatSessionAgent::update_agent_session_id(
-                                &db.pool,
-                                session_agent_id,
-                                Some(session_id_value),
-                            )
-                            .await;
-                        }
-                    }
-                    Ok(LogMsg::MessageId(message_id_value)) => {
-                        if agent_message_id.as_deref() != Some(&message_id_value) {
-                            agent_message_id = Some(message_id_value.clone());
-                            let _ = ChatSessionAgent::update_agent_message_id(
-                                &db.pool,
-                                session_agent_id,
-                                Some(message_id_value),
-                            )
-                            .await;
-                        }
-                    }
-                    Ok(LogMsg::Stdout(chunk)) => {
-                        Self::update_token_usage_from_stdout_chunk(
-                            &mut stdout_line_buffer,
-                            &mut last_token_usage,
-                            &chunk,
-                        );
-                    }
-                    Ok(LogMsg::JsonPatch(patch)) => {
-                        Self::process_stream_patch(
-                            patch,
-                            session_id,
-                            session_agent_id,
-                            agent_id,
-                            run_id,
-                            &sender,
-                            &mut last_content,
-                            &mut latest_assistant,
-                            &mut last_token_usage,
-                        );
-                    }
-                    Ok(LogMsg::Finished) => {
-                        Self::flush_token_usage_buffer(
-                            &mut stdout_line_buffer,
-                            &mut last_token_usage,
-                        );
+    fn spawn_fake_executor(script: &str) -> command_group::AsyncGroupChild {
+        use command_group::AsyncCommandGroup;
+
+        let mut command = tokio::process::Command::new("sh");
+        command
+            .arg("-c")
+            .arg(script)
+            .stdin(std::process::Stdio::null())
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped());
+        command.group_spawn().expect("spawn fake executor")
+    }
 
-                        // Drain tail messages briefly to handle out-of-order `Finished` vs stdout/json patches.
-                        let drain_deadline =
-                            tokio::time::Instant::now() + std::time::Duration::from_millis(350);
-                        loop {
-                            let now = tokio::time::Instant::now();
-                            if now >= drain_deadline {
-                                break;
-                            }
-                            let remaining = drain_deadline.duration_since(now);
-                            let Ok(next_item) =
-                                tokio::time::timeout(remaining, stream.next()).await
-                            else {
-                                break;
-                            };
-                            let Some(next_item) = next_item else {
-                                break;
-                            };
-                            match next_item {
-                                Ok(LogMsg::SessionId(session_id_value)) => {
-                                    if agent_session_id.as_deref() != Some(&session_id_value) {
-                                        agent_session_id = Some(session_id_value.clone());
-                                        let _ = ChatSessionAgent::update_agent_session_id(
-                                            &db.pool,
-                                            session_agent_id,
-                                            Some(session_id_value),
-                                        )
-                                        .await;
-                                    }
-                                }
-                                Ok(LogMsg::MessageId(message_id_value)) => {
-                                    if agent_message_id.as_deref() != Some(&message_id_value) {
-                                        agent_message_id = Some(message_id_value.clone());
-                                        let _ = ChatSessionAgent::update_agent_message_id(
-                                            &db.pool,
-                                            session_agent_id,
-                                            Some(message_id_value),
-                                        )
-                                        .await;
-                                    }
-                                }
-                                Ok(LogMsg::Stdout(chunk)) => {
-                                    Self::update_token_usage_from_stdout_chunk(
-                                        &mut stdout_line_buffer,
-                                        &mut last_token_usage,
-                                        &chunk,
-                                    );
-                                }
-                                Ok(LogMsg::JsonPatch(patch)) => {
-                                    Self::process_stream_patch(
-                                        patch,
-                                        session_id,
-                                        session_agent_id,
-                                        agent_id,
-                                        run_id,
-                                        &sender,
-                                        &mut last_content,
-                                        &mut latest_assistant,
-                                        &mut last_token_usage,
-                                    );
-                                }
-                                _ => {}
-                            }
-                        }
+    fn fake_persistent_child(
+        script: &str,
+    ) -> (
+        PersistentChild,
+        tokio::sync::mpsc::UnboundedReceiver<String>,
+        tokio::sync::mpsc::UnboundedSender<ExecutorExitResult>,
+    ) {
+        let child = spawn_fake_executor(script);
+        let (prompts, prompt_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (turn_tx, turns) = tokio::sync::mpsc::unbounded_channel();
+        (
+            PersistentChild {
+                child,
+                prompts,
+                turns,
+                cancel: CancellationToken::new(),
+            },
+            prompt_rx,
+            turn_tx,
+        )
+    }
 
-                        Self::flush_token_usage_buffer(
-                            &mut stdout_line_buffer,
-                            &mut last_token_usage,
-                        );
+    fn approval_bridge(runner: &ChatRunner, session_agent_id: Uuid) -> Arc<ChatApprovalBridge> {
+        Arc::new(ChatApprovalBridge {
+            runner: runner.clone(),
+            session_id: Uuid::new_v4(),
+            session_agent_id,
+            agent_id: Uuid::new_v4(),
+            run_id: tokio::sync::Mutex::new(Uuid::nil()),
+            in_flight: tokio::sync::Mutex::new(()),
+        })
+    }
 
-                        let _ = fs::write(&output_path, &latest_assistant).await;
+    async fn wait_until(mut done: impl FnMut() -> bool) {
+        tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            while !done() {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("condition not reached in time");
+    }
 
-                        let diff_info =
-                            ChatRunner::capture_git_diff(&workspace_path, &run_dir).await;
-                        let untracked_files =
-                            ChatRunner::capture_untracked_files(&workspace_path, &run_dir).await;
-                        let failed = failed_flag.load(Ordering::Relaxed);
+    async fn persistent_run_output(dir: &std::path::Path) -> PersistentRunOutput {
+        let file = tokio::fs::File::create(dir.join(format!("{}.log", Uuid::new_v4())))
+            .await
+            .expect("create raw.log");
+        PersistentRunOutput {
+            msg_store: Arc::new(MsgStore::new()),
+            raw_log_file: Arc::new(tokio::sync::Mutex::new(CappedRunLog::new(file, 1024))),
+            stderr_events: None,
+            redaction_rules: Arc::default(),
+        }
+    }
 
-                        if failed {
-                            agent_session_id = None;
-                            agent_message_id = None;
-                            let _ = ChatSessionAgent::update_agent_session_id(
-                                &db.pool,
-                                session_agent_id,
-                                None,
-                            )
-                            .await;
-                            let _ = ChatSessionAgent::update_agent_message_id(
-                                &db.pool,
-                                session_agent_id,
-                                None,
-                            )
-                            .await;
-                        }
+    fn is_finished(msg_store: &MsgStore) -> bool {
+        msg_store
+            .get_history()
+            .iter()
+            .any(|msg| matches!(msg, LogMsg::Finished))
+    }
 
-                        let mut meta = serde_json::json!({
-                            "run_id": run_id,
-                            "session_id": session_id,
-                            "session_agent_id": session_agent_id,
-                            "agent_id": agent_id,
-                            "agent_session_id": agent_session_id,
-                            "agent_message_id": agent_message_id,
-                            "finished_at": Utc::now().to_rfc3339(),
-                            "chain_depth": chain_depth + 1,
-                        });
+    #[tokio::test]
+    async fn persistent_process_is_reused_only_in_the_same_mode() {
+        let (runner, session_agent_id) = runner_with_session_agent().await;
+        let (child, _prompts, _turns) = fake_persistent_child("sleep 5");
+        let process = runner.register_persistent_process(
+            session_agent_id,
+            child,
+            "/workspace",
+            approval_bridge(&runner, session_agent_id),
+            false,
+        );
 
-                        // 濡傛灉娌℃湁token_usage锛屼娇鐢╰iktoken浼扮畻
-                        let token_usage = if let Some(ref usage) = last_token_usage {
-                            usage.clone()
-                        } else {
-                            // 璇诲彇input prompt杩涜浼扮畻
-                            let input_path = run_dir.join("input.txt");
-                            let prompt_content =
-                                fs::read_to_string(&input_path).await.unwrap_or_default();
-                            let estimated_input =
-                                Self::estimate_tokens_with_tiktoken(&prompt_content);
-                            let estimated_output =
-                                Self::estimate_tokens_with_tiktoken(&latest_assistant);
-                            TokenUsageInfo {
-                                total_tokens: estimated_input + estimated_output,
-                                model_context_window: 0,
-                                input_tokens: Some(estimated_input),
-                                output_tokens: Some(estimated_output),
-                                cache_read_tokens: None,
-                                is_estimated: true,
-                            }
-                        };
+        let reused = runner
+            .reusable_persistent_process(session_agent_id, "/workspace", false)
+            .expect("live process is reused");
+        assert!(Arc::ptr_eq(&reused, &process));
 
-                        meta["token_usage"] = serde_json::json!({
-                            "total_tokens": token_usage.total_tokens,
-                            "model_context_window": token_usage.model_context_window,
-                            "input_tokens": token_usage.input_tokens,
-                            "output_tokens": token_usage.output_tokens,
-                            "is_estimated": token_usage.is_estimated,
-                        });
+        assert!(
+            runner
+                .reusable_persistent_process(session_agent_id, "/workspace", true)
+                .is_none()
+        );
+        assert!(process.cancel.is_cancelled());
+        assert!(!runner.persistent_processes.contains_key(&session_agent_id));
+    }
 
-                        if context_compacted {
-                            meta["context_compacted"] = true.into();
-                        }
-                        if let Some(warning) = compression_warning.as_ref() {
-                            meta["compression_warning"] = serde_json::json!({
-                                "code": warning.code,
-                                "message": warning.message,
-                                "split_file_path": warning.split_file_path,
-                            });
-                        }
+    #[tokio::test]
+    async fn forgetting_a_session_stops_its_persistent_processes() {
+        let (runner, session_agent_id) = runner_with_session_agent().await;
+        let bridge = approval_bridge(&runner, session_agent_id);
+        let session_id = bridge.session_id;
+        let (child, _prompts, _turns) = fake_persistent_child("sleep 5");
+        let process = runner.register_persistent_process(
+            session_agent_id,
+            child,
+            "/workspace",
+            bridge,
+            false,
+        );
+        let other_session_agent_id = Uuid::new_v4();
+        let (child, _prompts, _turns) = fake_persistent_child("sleep 5");
+        let other = runner.register_persistent_process(
+            other_session_agent_id,
+            child,
+            "/workspace",
+            approval_bridge(&runner, other_session_agent_id),
+            false,
+        );
 
-                        if let Some(diff) = diff_info.as_ref() {
-                            meta["diff_available"] = true.into();
-                            meta["diff_truncated"] = diff.truncated.into();
-                        }
+        runner.forget_session(session_id);
 
-                        if !untracked_files.is_empty() {
-                            meta["untracked_files"] =
-                                serde_json::to_value(&untracked_files).unwrap_or_default();
-                        }
+        assert!(process.cancel.is_cancelled());
+        assert!(!runner.persistent_processes.contains_key(&session_agent_id));
+        assert!(!other.cancel.is_cancelled());
+        assert!(
+            runner
+                .persistent_processes
+                .contains_key(&other_session_agent_id)
+        );
+        runner.shutdown_persistent_process(other_session_agent_id);
+    }
 
-                        let _ = fs::write(&meta_path, serde_json::to_string_pretty(&meta).unwrap())
-                            .await;
+    #[tokio::test]
+    async fn crashed_persistent_process_is_replaced() {
+        let (runner, session_agent_id) = runner_with_session_agent().await;
+        let (child, _prompts, _turns) = fake_persistent_child("exit 1");
+        let crashed = runner.register_persistent_process(
+            session_agent_id,
+            child,
+            "/workspace",
+            approval_bridge(&runner, session_agent_id),
+            false,
+        );
 
-                        let final_content = ChatRunner::apply_reply_prefix(
-                            &latest_assistant,
-                            reply_handle.as_deref(),
-                        );
+        wait_until(|| !runner.persistent_processes.contains_key(&session_agent_id)).await;
+        assert!(
+            runner
+                .reusable_persistent_process(session_agent_id, "/workspace", false)
+                .is_none()
+        );
 
-                        if !final_content.trim().is_empty()
-                            && let Ok(message) = crate::services::chat::create_message(
-                                &db.pool,
-                                session_id,
-                                ChatSenderType::Agent,
-                                Some(agent_id),
-                                final_content.clone(),
-                                Some(meta.clone()),
-                            )
-                            .await
-                        {
-                            // Call handle_message to process explicit routing directives
-                            // This enables AI-to-AI message forwarding (chain calls)
-                            if let Ok(Some(session)) =
-                                ChatSession::find_by_id(&db.pool, session_id).await
-                            {
-                                runner.handle_message(&session, &message).await;
-                            } else {
-                                // Fallback: emit MessageNew event if session lookup fails
-                                let _ = sender.send(ChatStreamEvent::MessageNew { message });
-                            }
-                        }
+        let (child, _prompts, _turns) = fake_persistent_child("sleep 5");
+        let restarted = runner.register_persistent_process(
+            session_agent_id,
+            child,
+            "/workspace",
+            approval_bridge(&runner, session_agent_id),
+            false,
+        );
+        assert!(!Arc::ptr_eq(&restarted, &crashed));
+        let current = runner
+            .reusable_persistent_process(session_agent_id, "/workspace", false)
+            .expect("restarted process is tracked");
+        assert!(Arc::ptr_eq(&current, &restarted));
+    }
 
-                        let _ = sender.send(ChatStreamEvent::AgentDelta {
-                            session_id,
-                            session_agent_id,
-                            agent_id,
-                            run_id,
-                            stream_type: ChatStreamDeltaType::Assistant,
-                            content: latest_assistant.clone(),
-                            delta: false,
-                            is_final: true,
-                        });
+    #[tokio::test]
+    async fn stopping_persistent_process_cancels_and_forgets_it() {
+        let (runner, session_agent_id) = runner_with_session_agent().await;
+        let (child, _prompts, _turns) = fake_persistent_child("sleep 5");
+        let process = runner.register_persistent_process(
+            session_agent_id,
+            child,
+            "/workspace",
+            approval_bridge(&runner, session_agent_id),
+            false,
+        );
 
-                        let final_state = if failed {
-                            ChatSessionAgentState::Dead
-                        } else {
-                            ChatSessionAgentState::Idle
-                        };
+        runner.shutdown_persistent_process(session_agent_id);
 
-                        let _ = ChatSessionAgent::update_state(
-                            &db.pool,
-                            session_agent_id,
-                            final_state.clone(),
-                        )
-                        .await;
+        assert!(process.cancel.is_cancelled());
+        assert!(!runner.persistent_processes.contains_key(&session_agent_id));
+    }
 
-                        let _ = sender.send(ChatStreamEvent::AgentState {
-                            session_agent_id,
-                            agent_id,
-                            state: final_state.clone(),
-                            started_at: None,
-                        });
+    #[tokio::test]
+    async fn persistent_turns_finish_their_run_logs() {
+        let (runner, session_agent_id) = runner_with_session_agent().await;
+        let temp_dir = tempfile::tempdir().expect("tempdir");
+        let (child, mut prompts, turns) = fake_persistent_child("sleep 5");
+        let process = runner.register_persistent_process(
+            session_agent_id,
+            child,
+            "/workspace",
+            approval_bridge(&runner, session_agent_id),
+            false,
+        );
 
-                        // Emit MentionAcknowledged completed/failed event
-                        let mention_status = if final_state == ChatSessionAgentState::Dead {
-                            MentionStatus::Failed
-                        } else {
-                            MentionStatus::Completed
-                        };
-                        let _ = sender.send(ChatStreamEvent::MentionAcknowledged {
-                            session_id,
-                            message_id: source_message_id,
-                            mentioned_agent: agent_name.clone(),
-                            agent_id,
-                            status: mention_status.clone(),
-                        });
+        let output = persistent_run_output(temp_dir.path()).await;
+        let first_store = output.msg_store.clone();
+        let first_failed = Arc::new(AtomicBool::new(false));
+        let first_run = Uuid::new_v4();
+        runner
+            .start_persistent_turn(
+                process.clone(),
+                output,
+                first_run,
+                "first".to_string(),
+                first_failed.clone(),
+                session_agent_id,
+                None,
+            )
+            .await;
+        assert_eq!(prompts.recv().await.as_deref(), Some("first"));
+        assert_eq!(*process.approvals.run_id.lock().await, first_run);
+        assert!(runner.cancellation_tokens.contains_key(&session_agent_id));
+        assert!(!is_finished(&first_store));
+
+        turns.send(ExecutorExitResult::Success).unwrap();
+        wait_until(|| is_finished(&first_store)).await;
+        assert!(!first_failed.load(Ordering::Relaxed));
+        assert!(!runner.cancellation_tokens.contains_key(&session_agent_id));
+
+        let output = persistent_run_output(temp_dir.path()).await;
+        let second_store = output.msg_store.clone();
+        let second_failed = Arc::new(AtomicBool::new(false));
+        runner
+            .start_persistent_turn(
+                process.clone(),
+                output,
+                Uuid::new_v4(),
+                "second".to_string(),
+                second_failed.clone(),
+                session_agent_id,
+                None,
+            )
+            .await;
+        assert_eq!(prompts.recv().await.as_deref(), Some("second"));
+
+        turns.send(ExecutorExitResult::Failure).unwrap();
+        wait_until(|| is_finished(&second_store)).await;
+        assert!(second_failed.load(Ordering::Relaxed));
+        assert!(runner.persistent_processes.contains_key(&session_agent_id));
+    }
+
+    #[tokio::test]
+    async fn persistent_turn_fails_when_process_stopped_reading_prompts() {
+        let (runner, session_agent_id) = runner_with_session_agent().await;
+        let temp_dir = tempfile::tempdir().expect("tempdir");
+        let (child, prompts, _turns) = fake_persistent_child("sleep 5");
+        let process = runner.register_persistent_process(
+            session_agent_id,
+            child,
+            "/workspace",
+            approval_bridge(&runner, session_agent_id),
+            false,
+        );
+        drop(prompts);
+
+        let output = persistent_run_output(temp_dir.path()).await;
+        let msg_store = output.msg_store.clone();
+        let failed = Arc::new(AtomicBool::new(false));
+        runner
+            .start_persistent_turn(
+                process.clone(),
+                output,
+                Uuid::new_v4(),
+                "lost".to_string(),
+                failed.clone(),
+                session_agent_id,
+                None,
+            )
+            .await;
+
+        assert!(failed.load(Ordering::Relaxed));
+        assert!(is_finished(&msg_store));
+        assert!(process.cancel.is_cancelled());
+        assert!(!runner.persistent_processes.contains_key(&session_agent_id));
+        assert!(!runner.cancellation_tokens.contains_key(&session_agent_id));
+    }
+
+    #[tokio::test]
+    async fn nested_additional_workspace_is_captured_with_primary() {
+        let temp_dir = tempfile::tempdir().expect("tempdir");
+        let git = |dir: &std::path::Path, args: &[&str]| {
+            let status = std::process::Command::new("git")
+                .arg("-C")
+                .arg(dir)
+                .args(args)
+                .status()
+                .expect("run git");
+            assert!(status.success(), "git {args:?}");
+        };
+        let primary = temp_dir.path();
+        git(primary, &["init", "-q"]);
+        let subdir = primary.join("frontend");
+        let nested_repo = primary.join("vendor");
+        std::fs::create_dir_all(&subdir).unwrap();
+        std::fs::create_dir_all(&nested_repo).unwrap();
+        git(&nested_repo, &["init", "-q"]);
+        let outside = tempfile::tempdir().expect("tempdir");
+
+        let workspaces = [
+            AdditionalWorkspace {
+                path: subdir,
+                label: "frontend".to_string(),
+            },
+            AdditionalWorkspace {
+                path: nested_repo,
+                label: "vendor".to_string(),
+            },
+            AdditionalWorkspace {
+                path: outside.path().to_path_buf(),
+                label: "outside".to_string(),
+            },
+        ];
+        let labels: Vec<String> = ChatRunner::separately_tracked_workspaces(primary, &workspaces)
+            .await
+            .into_iter()
+            .map(|workspace| workspace.label)
+            .collect();
+
+        assert_eq!(labels, vec!["vendor".to_string(), "outside".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn additional_workspace_changes_are_recorded_relative_to_it() {
+        let temp_dir = tempfile::tempdir().expect("tempdir");
+        let repo = temp_dir.path();
+        let git = |args: &[&str]| {
+            let status = std::process::Command::new("git")
+                .arg("-C")
+                .arg(repo)
+                .args(args)
+                .status()
+                .expect("run git");
+            assert!(status.success(), "git {args:?}");
+        };
+        git(&["init", "-q"]);
+        git(&["config", "user.email", "test@example.com"]);
+        git(&["config", "user.name", "test"]);
+        let workspace = repo.join("app");
+        std::fs::create_dir_all(&workspace).unwrap();
+        std::fs::write(repo.join("README.md"), "readme\n").unwrap();
+        std::fs::write(workspace.join("main.rs"), "fn main() {}\n").unwrap();
+        git(&["add", "."]);
+        git(&["commit", "-q", "-m", "init"]);
+        std::fs::write(repo.join("README.md"), "changed\n").unwrap();
+        std::fs::write(workspace.join("main.rs"), "fn main() { run() }\n").unwrap();
+        std::fs::write(workspace.join("notes.md"), "new\n").unwrap();
+
+        let diff = ChatRunner::tracked_git_diff(&workspace, Some("app"), &GitDiffBase::Working)
+            .await
+            .expect("workspace diff");
+        assert!(diff.contains("--- a/app/main.rs"));
+        assert!(diff.contains("+++ b/app/main.rs"));
+        assert!(!diff.contains("README.md"));
+
+        let run_dir = tempfile::tempdir().expect("tempdir");
+        let recorded =
+            ChatRunner::capture_untracked_files(&workspace, Some("app"), run_dir.path()).await;
+        assert_eq!(
+            recorded,
+            vec![format!("{ADDITIONAL_UNTRACKED_DIR_NAME}/app/notes.md")]
+        );
+        assert!(
+            run_dir
+                .path()
+                .join("untracked")
+                .join(ADDITIONAL_UNTRACKED_DIR_NAME)
+                .join("app/notes.md")
+                .exists()
+        );
+
+        // The primary workspace cannot shadow an additional workspace's copies.
+        std::fs::create_dir_all(repo.join(ADDITIONAL_UNTRACKED_DIR_NAME).join("app")).unwrap();
+        std::fs::write(
+            repo.join(ADDITIONAL_UNTRACKED_DIR_NAME)
+                .join("app/notes.md"),
+            "shadow\n",
+        )
+        .unwrap();
+        let recorded = ChatRunner::capture_untracked_files(repo, None, run_dir.path()).await;
+        assert_eq!(recorded, vec!["app/notes.md".to_string()]);
+    }
+
+    #[test]
+    fn run_duration_budget_skips_approval_wait() {
+        let exceeded = Arc::new(AtomicBool::new(false));
+        let mut budget = RunDurationBudget::new(
+            std::time::Duration::from_secs(60),
+            Uuid::new_v4(),
+            exceeded.clone(),
+        );
+        let start = budget.last_tick;
+        let at = |secs| start + std::time::Duration::from_secs(secs);
+
+        assert!(!budget.tick(at(40), false));
+        // Twenty minutes waiting on an approval do not count against the budget.
+        assert!(!budget.tick(at(1240), true));
+        assert!(!budget.tick(at(1259), false));
+        assert!(!exceeded.load(Ordering::Relaxed));
+
+        assert!(budget.tick(at(1261), false));
+        assert!(exceeded.load(Ordering::Relaxed));
+        assert!(!budget.tick(at(1300), false));
+    }
+
+    #[tokio::test]
+    async fn run_over_max_duration_is_killed() {
+        let (runner, session_agent_id) = runner_with_session_agent().await;
+        let msg_store = Arc::new(MsgStore::new());
+        let failed = Arc::new(AtomicBool::new(false));
+        let exceeded = Arc::new(AtomicBool::new(false));
+        let mut budget =
+            RunDurationBudget::new(std::time::Duration::ZERO, Uuid::new_v4(), exceeded.clone());
+        budget.kill_grace = std::time::Duration::ZERO;
+
+        // No cancellation token and no exit signal: only the kill ends this run.
+        runner.spawn_exit_watcher(
+            spawn_fake_executor("sleep 30"),
+            None,
+            None,
+            msg_store.clone(),
+            failed.clone(),
+            session_agent_id,
+            Some(budget),
+            Arc::new(AtomicBool::new(false)),
+        );
+
+        tokio::time::timeout(std::time::Duration::from_secs(20), async {
+            while !is_finished(&msg_store) {
+                tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .expect("run killed after exceeding its maximum duration");
+        assert!(exceeded.load(Ordering::Relaxed));
+        assert!(failed.load(Ordering::Relaxed));
+        assert!(msg_store.get_history().iter().any(|msg| matches!(
+            msg,
+            LogMsg::Stderr(text) if text.starts_with("max duration exceeded")
+        )));
+    }
+
+    #[tokio::test]
+    async fn idle_run_ignoring_cancellation_is_killed() {
+        let (runner, session_agent_id) = runner_with_session_agent().await;
+        let session_agent = runner
+            .claim_or_queue(session_agent_id, make_pending(), None)
+            .await
+            .expect("claim idle agent")
+            .expect("agent claimed");
+        let (session_id, agent_id) = (session_agent.session_id, session_agent.agent_id);
+        let temp_dir = tempfile::tempdir().expect("tempdir");
+        let pid_path = temp_dir.path().join("executor.pid");
+        let run_id = Uuid::new_v4();
+        let msg_store = Arc::new(MsgStore::new());
+        let failed_flag = Arc::new(AtomicBool::new(false));
+        let kill_requested = Arc::new(AtomicBool::new(false));
+        // Nothing listens to the token and the executor ignores SIGINT and SIGTERM.
+        let token = CancellationToken::new();
+
+        runner.spawn_exit_watcher(
+            spawn_fake_executor(&format!(
+                "echo $$ > '{}'; trap '' INT TERM; while true; do sleep 1; done",
+                pid_path.display()
+            )),
+            Some(token.clone()),
+            None,
+            msg_store.clone(),
+            failed_flag.clone(),
+            session_agent_id,
+            None,
+            kill_requested.clone(),
+        );
+        runner.spawn_stream_bridge(
+            msg_store.clone(),
+            session_id,
+            agent_id,
+            session_agent_id,
+            run_id,
+            temp_dir.path().join("output.md"),
+            temp_dir.path().join("meta.json"),
+            temp_dir.path().to_path_buf(),
+            Vec::new(),
+            temp_dir.path().to_path_buf(),
+            None,
+            failed_flag.clone(),
+            0,
+            false,
+            None,
+            None,
+            runner.clone(),
+            Uuid::new_v4(),
+            "reviewer".to_string(),
+            None,
+            false,
+            GitDiffBase::default(),
+            0,
+            std::time::Duration::from_secs(5),
+            AgentDeltaBatcher::new(
+                runner.sender_for(session_id),
+                session_id,
+                session_agent_id,
+                agent_id,
+                run_id,
+                std::time::Duration::ZERO,
+                1024,
+            ),
+            Some(std::time::Duration::from_millis(200)),
+            kill_requested,
+            Arc::new(AtomicBool::new(false)),
+            OutputRedactor::default(),
+            None,
+            ChatRunnerConfig::default().max_token_usage_line_bytes as usize,
+        );
+        wait_until(|| pid_path.exists()).await;
+        let pid = std::fs::read_to_string(&pid_path)
+            .expect("read executor pid")
+            .trim()
+            .to_string();
+
+        // The bridge finishes the stream after the grace period, then the exit watcher
+        // finishes it again once the killed process has exited.
+        tokio::time::timeout(IDLE_CANCEL_GRACE * 3, async {
+            while msg_store
+                .get_history()
+                .iter()
+                .filter(|msg| matches!(msg, LogMsg::Finished))
+                .count()
+                < 2
+            {
+                tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .expect("idle run killed after its grace period");
+        assert!(token.is_cancelled());
+        assert!(failed_flag.load(Ordering::Relaxed));
+        assert!(msg_store.get_history().iter().any(|msg| matches!(
+            msg,
+            LogMsg::Stderr(text) if text.starts_with("idle timeout")
+        )));
+        let alive = std::process::Command::new("kill")
+            .args(["-0", &pid])
+            .stderr(std::process::Stdio::null())
+            .status()
+            .expect("run kill -0");
+        assert!(!alive.success());
+    }
+
+    #[tokio::test]
+    async fn persistent_turn_over_max_duration_stops_process() {
+        let (runner, session_agent_id) = runner_with_session_agent().await;
+        let temp_dir = tempfile::tempdir().expect("tempdir");
+        let (child, _prompts, _turns) = fake_persistent_child("sleep 5");
+        let process = runner.register_persistent_process(
+            session_agent_id,
+            child,
+            "/workspace",
+            approval_bridge(&runner, session_agent_id),
+            false,
+        );
+        let exceeded = Arc::new(AtomicBool::new(false));
+        let run_id = Uuid::new_v4();
+        let mut budget =
+            RunDurationBudget::new(std::time::Duration::ZERO, run_id, exceeded.clone());
+        budget.kill_grace = std::time::Duration::ZERO;
+
+        let output = persistent_run_output(temp_dir.path()).await;
+        let msg_store = output.msg_store.clone();
+        let failed = Arc::new(AtomicBool::new(false));
+        runner
+            .start_persistent_turn(
+                process.clone(),
+                output,
+                run_id,
+                "never answered".to_string(),
+                failed.clone(),
+                session_agent_id,
+                Some(budget),
+            )
+            .await;
 
-                        // Persist completed/failed status to message meta
-                        let status_str = match mention_status {
-                            MentionStatus::Completed => "completed",
-                            MentionStatus::Failed => "failed",
-                            MentionStatus::Running => "running",
-                            MentionStatus::Received => "received",
-                        };
-                        if let Ok(Some(msg)) =
-                            ChatMessage::find_by_id(&db.pool, source_message_id).await
-                        {
-                            let mut meta = msg.meta.0.clone();
-                            let mention_statuses = meta
-                                .get_mut("mention_statuses")
-                                .and_then(|v| v.as_object_mut());
+        wait_until(|| is_finished(&msg_store)).await;
+        assert!(exceeded.load(Ordering::Relaxed));
+        assert!(failed.load(Ordering::Relaxed));
+        assert!(process.cancel.is_cancelled());
+        assert!(!runner.persistent_processes.contains_key(&session_agent_id));
+        assert!(!runner.cancellation_tokens.contains_key(&session_agent_id));
+    }
 
-                            if let Some(statuses) = mention_statuses {
-                                statuses.insert(agent_name.clone(), serde_json::json!(status_str));
-                            } else {
-                                let mut new_statuses = serde_json::Map::new();
-                                new_statuses
-                                    .insert(agent_name.clone(), serde_json::json!(status_str));
-                                meta["mention_statuses"] = serde_json::Value::Object(new_statuses);
-                            }
+    #[tokio::test]
+    async fn interrupted_run_draft_is_posted_once_on_recovery() {
+        let (runner, session_agent_id) = runner_with_session_agent().await;
+        let pool = runner.db.pool.clone();
+        let session_agent =
+            ChatSessionAgent::update_state(&pool, session_agent_id, ChatSessionAgentState::Running)
+                .await
+                .expect("mark agent running");
+        let temp_dir = tempfile::tempdir().expect("tempdir");
+        let output_path = temp_dir.path().join("output.md");
+        let meta_path = temp_dir.path().join("meta.json");
+        let run = ChatRun::create(
+            &pool,
+            &CreateChatRun {
+                session_id: session_agent.session_id,
+                session_agent_id,
+                run_index: 1,
+                run_dir: temp_dir.path().to_string_lossy().to_string(),
+                input_path: None,
+                output_path: Some(output_path.to_string_lossy().to_string()),
+                raw_log_path: None,
+                meta_path: Some(meta_path.to_string_lossy().to_string()),
+            },
+            Uuid::new_v4(),
+        )
+        .await
+        .expect("create run");
+        // The server stopped after a draft flush, before the run finished.
+        tokio::fs::write(&output_path, "half of the answer")
+            .await
+            .expect("write draft");
 
-                            let _ =
-                                ChatMessage::update_meta(&db.pool, source_message_id, meta).await;
-                        }
+        assert_eq!(runner.recover_interrupted_runs().await.unwrap(), 1);
 
-                        // Process any pending messages in the queue for this agent
-                        // Only process if the agent completed successfully (not failed/dead)
-                        if final_state == ChatSessionAgentState::Idle {
-                            runner
-                                .process_pending_queue(session_id, session_agent_id)
-                                .await;
-                        } else {
-                            // Agent failed/died - clear pending queue and mark all as failed
-                            runner
-                                .clear_pending_queue_on_failure(session_id, session_agent_id)
-                                .await;
-                        }
+        let messages = ChatMessage::find_by_session_id(&pool, session_agent.session_id, None)
+            .await
+            .expect("load messages");
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].content, "half of the answer");
+        assert_eq!(messages[0].sender_id, Some(session_agent.agent_id));
+        assert_eq!(messages[0].meta.0["run_id"], run.id.to_string());
+        assert_eq!(messages[0].meta.0["failed"], true);
+        assert_eq!(messages[0].meta.0["failure_reason"], "interrupted");
+        let meta: serde_json::Value =
+            serde_json::from_str(&tokio::fs::read_to_string(&meta_path).await.unwrap()).unwrap();
+        assert_eq!(meta["draft_recovered"], true);
+        let session_agent = ChatSessionAgent::find_by_id(&pool, session_agent_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(session_agent.state, ChatSessionAgentState::Dead);
 
-                        break;
-                    }
-                    _ => {}
-                }
-            }
-        });
+        // Resetting the member afterwards does not post the draft again.
+        runner
+            .reset_agent(session_agent.session_id, session_agent_id)
+            .await
+            .expect("reset agent");
+        let messages = ChatMessage::find_by_session_id(&pool, session_agent.session_id, None)
+            .await
+            .expect("load messages");
+        assert_eq!(messages.len(), 1);
     }
 
-    fn spawn_exit_watcher(
-        &self,
-        mut child: command_group::AsyncGroupChild,
-        cancel_token: Option<CancellationToken>,
-        exit_signal: Option<ExecutorExitSignal>,
-        msg_store: Arc<MsgStore>,
-        failed_flag: Arc<AtomicBool>,
-        session_agent_id: Uuid,
-    ) {
-        // Store the cancellation token for graceful shutdown
-        if let Some(ref token) = cancel_token {
-            self.cancellation_tokens
-                .insert(session_agent_id, token.clone());
-        }
-
-        let finished_sent = Arc::new(AtomicBool::new(false));
-        let finished_from_exit_signal = Arc::new(AtomicBool::new(false));
-        let cancellation_tokens = self.cancellation_tokens.clone();
-        let process_finished = finished_sent.clone();
-        let process_finished_from_signal = finished_from_exit_signal.clone();
-        let process_msg_store = msg_store.clone();
-        let process_failed_flag = failed_flag.clone();
-        tokio::spawn(async move {
-            loop {
-                match child.try_wait() {
-                    Ok(Some(status)) => {
-                        if !status.success() {
-                            process_failed_flag.store(true, Ordering::Relaxed);
-                        }
-                        if !process_finished.swap(true, Ordering::Relaxed) {
-                            process_msg_store.push_finished();
-                        }
-                        // If completion already came from exit_signal, token was cleaned there.
-                        if !process_finished_from_signal.load(Ordering::Relaxed) {
-                            cancellation_tokens.remove(&session_agent_id);
-                        }
-                        break;
-                    }
-                    Ok(None) => {
-                        tokio::time::sleep(std::time::Duration::from_millis(250)).await;
-                    }
-                    Err(err) => {
-                        process_msg_store
-                            .push(LogMsg::Stderr(format!("process wait error: {err}")));
-                        process_failed_flag.store(true, Ordering::Relaxed);
-                        if !process_finished.swap(true, Ordering::Relaxed) {
-                            process_msg_store.push_finished();
-                        }
-                        if !process_finished_from_signal.load(Ordering::Relaxed) {
-                            cancellation_tokens.remove(&session_agent_id);
-                        }
-                        break;
-                    }
-                }
+    #[tokio::test]
+    async fn running_agent_keeps_claim_time_across_approval_waits() {
+        let (runner, session_agent_id) = runner_with_session_agent().await;
+        let pool = runner.db.pool.clone();
+        let session_id = ChatSessionAgent::find_by_id(&pool, session_agent_id)
+            .await
+            .expect("load session agent")
+            .expect("session agent exists")
+            .session_id;
+        let create_run = |run_index| {
+            let pool = pool.clone();
+            async move {
+                ChatRun::create(
+                    &pool,
+                    &CreateChatRun {
+                        session_id,
+                        session_agent_id,
+                        run_index,
+                        run_dir: format!("run_{run_index}"),
+                        input_path: None,
+                        output_path: None,
+                        raw_log_path: None,
+                        meta_path: None,
+                    },
+                    Uuid::new_v4(),
+                )
+                .await
+                .expect("create run")
             }
-        });
+        };
+        let pause = || tokio::time::sleep(std::time::Duration::from_millis(5));
 
-        if let Some(exit_signal_rx) = exit_signal {
-            let signal_msg_store = msg_store;
-            let signal_failed_flag = failed_flag;
-            let signal_finished = finished_sent;
-            let signal_finished_from_signal = finished_from_exit_signal;
-            let signal_cancel_token = cancel_token;
-            let signal_cancellation_tokens = self.cancellation_tokens.clone();
-            tokio::spawn(async move {
-                match exit_signal_rx.await {
-                    Ok(exit_result) => {
-                        if matches!(
-                            exit_result,
-                            executors::executors::ExecutorExitResult::Failure
-                        ) {
-                            signal_failed_flag.store(true, Ordering::Relaxed);
-                        }
+        create_run(1).await;
+        pause().await;
+        let claimed = runner
+            .claim_or_queue(session_agent_id, make_pending(), None)
+            .await
+            .expect("claim idle agent")
+            .expect("idle agent is claimed");
+        pause().await;
+        let current_run = create_run(2).await;
+        pause().await;
+        for state in [
+            ChatSessionAgentState::WaitingApproval,
+            ChatSessionAgentState::Running,
+        ] {
+            ChatSessionAgent::update_state(&pool, session_agent_id, state)
+                .await
+                .expect("change approval state");
+        }
 
-                        // Ignore completion emitted after manual cancellation (e.g. stop_agent).
-                        let manually_cancelled = signal_cancel_token
-                            .as_ref()
-                            .map(CancellationToken::is_cancelled)
-                            .unwrap_or(false);
+        let running = runner
+            .list_running_agents()
+            .await
+            .expect("list running agents");
+        assert_eq!(running.len(), 1);
+        assert_eq!(running[0].started_at, claimed.updated_at);
+        assert_eq!(running[0].run_id, Some(current_run.id));
+    }
 
-                        if !manually_cancelled {
-                            signal_finished_from_signal.store(true, Ordering::Relaxed);
-                            if !signal_finished.swap(true, Ordering::Relaxed) {
-                                signal_msg_store.push_finished();
-                            }
-                            signal_cancellation_tokens.remove(&session_agent_id);
-                        }
-                    }
-                    Err(err) => {
-                        signal_msg_store
-                            .push(LogMsg::Stderr(format!("exit signal receive error: {err}")));
-                    }
-                }
-            });
-        }
+    #[tokio::test]
+    async fn members_waiting_on_approval_are_listed_as_running() {
+        let (runner, session_agent_id) = runner_with_session_agent().await;
+        ChatSessionAgent::update_state(
+            &runner.db.pool,
+            session_agent_id,
+            ChatSessionAgentState::WaitingApproval,
+        )
+        .await
+        .expect("wait for approval");
+
+        let running = runner
+            .list_running_agents()
+            .await
+            .expect("list running agents");
+        assert_eq!(running.len(), 1);
+        assert_eq!(running[0].state, ChatSessionAgentState::WaitingApproval);
+        assert_eq!(
+            ChatSessionAgent::find_all_running(&runner.db.pool)
+                .await
+                .expect("find running members")
+                .len(),
+            1
+        );
     }
 
-    /// Stop a running agent by triggering graceful cancellation via CancellationToken
-    pub async fn stop_agent(
-        &self,
-        session_id: Uuid,
-        session_agent_id: Uuid,
-    ) -> Result<(), ChatRunnerError> {
-        tracing::info!(
-            "stop_agent called for session_agent_id: {}",
-            session_agent_id
+    #[tokio::test]
+    async fn raw_output_is_redacted_before_it_is_logged() {
+        let (runner, _) = runner_with_session_agent().await;
+        let temp_dir = tempfile::tempdir().expect("tempdir");
+        let raw_log_path = temp_dir.path().join("raw.log");
+        let file = tokio::fs::File::create(&raw_log_path)
+            .await
+            .expect("create raw.log");
+        let raw_log_file = Arc::new(tokio::sync::Mutex::new(CappedRunLog::new(file, 1024)));
+        let msg_store = Arc::new(MsgStore::new());
+        let rules = vec![RedactionRule {
+            pattern: r"sk-[A-Za-z0-9]{8,}".to_string(),
+            replacement: "[REDACTED]".to_string(),
+        }];
+        let mut child = spawn_fake_executor(
+            "printf 'key sk-abcd'; printf 'ef123456\\n'; printf 'warn sk-abcdef123456' >&2",
         );
 
-        // Try to cancel the agent via CancellationToken (graceful shutdown)
-        let token_found = self.cancellation_tokens.contains_key(&session_agent_id);
-        tracing::info!("CancellationToken found: {}", token_found);
+        runner.spawn_log_forwarders(
+            &mut child,
+            msg_store.clone(),
+            raw_log_file,
+            None,
+            None,
+            &rules,
+        );
+        let _ = child.wait().await;
 
-        if let Some(token) = self.cancellation_tokens.get(&session_agent_id) {
-            tracing::info!(
-                "Cancelling agent for session_agent_id: {}",
-                session_agent_id
-            );
-            token.cancel();
-        } else {
-            tracing::warn!(
-                "No CancellationToken found for session_agent_id: {}",
-                session_agent_id
-            );
-        }
+        let raw_log = || std::fs::read_to_string(&raw_log_path).unwrap_or_default();
+        wait_until(|| {
+            raw_log().contains("key [REDACTED]") && raw_log().contains("warn [REDACTED]")
+        })
+        .await;
+        assert!(!raw_log().contains("sk-abcdef123456"));
+        let logged: Vec<String> = msg_store
+            .get_history()
+            .into_iter()
+            .filter_map(|msg| match msg {
+                LogMsg::Stdout(text) | LogMsg::Stderr(text) => Some(text),
+                _ => None,
+            })
+            .collect();
+        assert!(logged.iter().any(|text| text.contains("[REDACTED]")));
+        assert!(!logged.iter().any(|text| text.contains("sk-")));
+    }
 
-        // Update state to Dead
-        let session_agent = ChatSessionAgent::update_state(
-            &self.db.pool,
-            session_agent_id,
-            ChatSessionAgentState::Dead,
-        )
-        .await?;
+    #[tokio::test]
+    async fn muted_agent_keeps_mentions_queued_until_unmuted() {
+        let (runner, session_agent_id) = runner_with_session_agent().await;
+        let session_agent = ChatSessionAgent::find_by_id(&runner.db.pool, session_agent_id)
+            .await
+            .expect("load session agent")
+            .expect("session agent exists");
+        let session_id = session_agent.session_id;
+        let queued_len = |runner: &ChatRunner| {
+            runner
+                .pending_messages
+                .get(&session_agent_id)
+                .map_or(0, |queue| queue.len())
+        };
 
-        // Emit state change event
-        self.emit(
-            session_id,
-            ChatStreamEvent::AgentState {
-                session_agent_id,
-                agent_id: session_agent.agent_id,
-                state: ChatSessionAgentState::Dead,
-                started_at: None,
-            },
+        let muted = runner
+            .set_agent_muted(session_id, session_agent_id, true)
+            .await
+            .expect("mute agent");
+        assert!(muted.muted);
+        assert_eq!(muted.updated_at, session_agent.updated_at);
+        assert!(
+            runner
+                .claim_or_queue(session_agent_id, make_pending(), None)
+                .await
+                .expect("queue while muted")
+                .is_none()
         );
+        runner
+            .process_pending_queue(session_id, session_agent_id)
+            .await;
+        assert_eq!(queued_len(&runner), 1);
 
-        // Clean up the cancellation token
-        self.cancellation_tokens.remove(&session_agent_id);
-
-        Ok(())
+        let unmuted = runner
+            .set_agent_muted(session_id, session_agent_id, false)
+            .await
+            .expect("unmute agent");
+        assert!(!unmuted.muted);
+        assert_eq!(queued_len(&runner), 0);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::ChatRunner;
+    #[tokio::test]
+    async fn checkpoint_of_queued_mention_is_resolved_when_it_runs() {
+        let (runner, session_agent_id) = runner_with_session_agent().await;
+        let session_id = ChatSessionAgent::find_by_id(&runner.db.pool, session_agent_id)
+            .await
+            .expect("load session agent")
+            .expect("session agent exists")
+            .session_id;
+        runner
+            .claim_or_queue(session_agent_id, make_pending(), None)
+            .await
+            .expect("claim idle agent")
+            .expect("idle agent is claimed");
+
+        // The run to continue from is the one still in progress, so it has no meta yet.
+        let mut message = make_message(ChatSenderType::User, None);
+        message.session_id = session_id;
+        message.meta = sqlx::types::Json(serde_json::json!({
+            "from_run_id": Uuid::new_v4().to_string(),
+        }));
+        runner
+            .run_agent_for_mention(session_id, "reviewer", &message, None)
+            .await
+            .expect("queue mention behind the running run");
 
-    #[test]
-    fn parse_token_usage_from_codex_token_count_line() {
-        let line = r#"{"method":"codex/event/token_count","params":{"msg":{"info":{"last_token_usage":{"total_tokens":53002},"model_context_window":258400}}}}"#;
-        let usage = ChatRunner::parse_token_usage_from_stdout_line(line).expect("usage");
-        assert_eq!(usage.total_tokens, 53002);
-        assert_eq!(usage.model_context_window, 258400);
+        let queued = runner
+            .pending_messages
+            .get(&session_agent_id)
+            .map(|queue| {
+                queue
+                    .iter()
+                    .map(|pending| pending.message.id)
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        assert_eq!(queued, vec![message.id]);
     }
 
-    #[test]
-    fn parse_token_usage_from_plain_token_usage_line() {
-        let line = r#"{"type":"token_usage","total_tokens":14596,"model_context_window":258400}"#;
-        let usage = ChatRunner::parse_token_usage_from_stdout_line(line).expect("usage");
-        assert_eq!(usage.total_tokens, 14596);
-        assert_eq!(usage.model_context_window, 258400);
+    #[tokio::test]
+    async fn run_checkpoint_applies_only_to_the_run_owner() {
+        let (runner, session_agent_id) = runner_with_session_agent().await;
+        let session_id = ChatSessionAgent::find_by_id(&runner.db.pool, session_agent_id)
+            .await
+            .expect("load session agent")
+            .expect("session agent exists")
+            .session_id;
+        let temp_dir = tempfile::tempdir().expect("tempdir");
+        let meta_path = temp_dir.path().join("meta.json");
+        tokio::fs::write(
+            &meta_path,
+            serde_json::json!({ "agent_session_id": "executor-session" }).to_string(),
+        )
+        .await
+        .expect("write run meta");
+        let run = ChatRun::create(
+            &runner.db.pool,
+            &CreateChatRun {
+                session_id,
+                session_agent_id,
+                run_index: 1,
+                run_dir: temp_dir.path().to_string_lossy().to_string(),
+                input_path: None,
+                output_path: None,
+                raw_log_path: None,
+                meta_path: Some(meta_path.to_string_lossy().to_string()),
+            },
+            Uuid::new_v4(),
+        )
+        .await
+        .expect("create run");
+        let mut message = make_message(ChatSenderType::User, None);
+        message.session_id = session_id;
+        message.meta = sqlx::types::Json(serde_json::json!({
+            "from_run_id": run.id.to_string(),
+        }));
+
+        let owner = runner
+            .resolve_run_checkpoint(&message, session_agent_id)
+            .await
+            .expect("owner resolves the checkpoint")
+            .expect("owner continues from the run");
+        assert_eq!(owner.agent_session_id, "executor-session");
+        // Another agent mentioned in the same message starts fresh instead of failing.
+        let other = runner
+            .resolve_run_checkpoint(&message, Uuid::new_v4())
+            .await
+            .expect("other agent is not failed");
+        assert!(other.is_none());
+    }
+
+    #[tokio::test]
+    async fn unresolved_agent_route_policies_mark_the_mention_failed() {
+        let (runner, session_agent_id) = runner_with_session_agent().await;
+        let session_id = ChatSessionAgent::find_by_id(&runner.db.pool, session_agent_id)
+            .await
+            .expect("load session agent")
+            .expect("session agent exists")
+            .session_id;
+
+        for (policy, handled, notice) in [
+            (AgentUnresolvedRoutingPolicy::Fail, false, false),
+            (AgentUnresolvedRoutingPolicy::SilentDrop, true, false),
+            (AgentUnresolvedRoutingPolicy::WarnEventOnly, true, true),
+        ] {
+            let message = crate::services::chat::create_message(
+                &runner.db.pool,
+                session_id,
+                ChatSenderType::Agent,
+                Some(Uuid::new_v4()),
+                "@ghost please check".to_string(),
+                None,
+            )
+            .await
+            .expect("create agent message");
+            let mut events = runner.subscribe(session_id);
+
+            let result = runner
+                .apply_unresolved_agent_route_policy(
+                    policy,
+                    session_id,
+                    &message,
+                    "ghost",
+                    None,
+                    "Mentioned agent was not found.",
+                )
+                .await;
+            assert_eq!(result, handled, "{policy:?}");
+
+            let status = ChatMessage::find_by_id(&runner.db.pool, message.id)
+                .await
+                .expect("load message")
+                .expect("message exists")
+                .meta
+                .0
+                .pointer("/mention_statuses/ghost")
+                .cloned();
+            let expected_status = handled.then(|| serde_json::json!("failed"));
+            assert_eq!(status, expected_status, "{policy:?}");
+
+            let mut notices = Vec::new();
+            while let Ok(event) = events.try_recv() {
+                if let ChatStreamEvent::SystemNotice { meta, .. } = event {
+                    notices.push(meta);
+                }
+            }
+            assert_eq!(notices.len(), usize::from(notice), "{policy:?}");
+            if let Some(meta) = notices.first() {
+                assert_eq!(
+                    meta["mention_failure"]["source_message_id"],
+                    serde_json::json!(message.id)
+                );
+                assert_eq!(meta["mention_failure"]["mentioned_agent"], "ghost");
+            }
+        }
     }
 }
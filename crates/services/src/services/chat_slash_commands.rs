@@ -0,0 +1,269 @@
+//! Expansion of user slash commands into message templates.
+//!
+//! Commands come from `chat_runner.slash_commands`. A user message starting with
+//! `/name` is replaced by the command's template before mentions are parsed, and the
+//! original text is kept under `meta.slash_command`. Any other text, including an
+//! unknown `/word`, is sent as written.
+
+use std::collections::HashMap;
+
+use crate::services::config::SlashCommand;
+
+/// Message meta key holding the raw command of an expanded message.
+pub const SLASH_COMMAND_KEY: &str = "slash_command";
+
+/// Placeholder name replaced by everything after the command name.
+const ALL_ARGS_PLACEHOLDER: &str = "args";
+
+/// A message whose slash command was expanded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExpandedSlashCommand {
+    pub name: String,
+    pub content: String,
+}
+
+impl ExpandedSlashCommand {
+    /// Value stored under [`SLASH_COMMAND_KEY`] in the message meta.
+    pub fn meta(&self, raw: &str) -> serde_json::Value {
+        serde_json::json!({
+            "name": self.name,
+            "raw": raw,
+            "expanded": self.content,
+        })
+    }
+}
+
+fn is_command_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '-'
+}
+
+/// Split `/name rest` into the command name and its argument string. Text that only
+/// looks like a path (`/usr/bin`) or starts with `//` is not a command.
+fn split_command(content: &str) -> Option<(&str, &str)> {
+    let body = content.trim_start().strip_prefix('/')?;
+    let name_end = body
+        .find(|c: char| !is_command_name_char(c))
+        .unwrap_or(body.len());
+    let (name, rest) = body.split_at(name_end);
+    if name.is_empty() || !(rest.is_empty() || rest.starts_with(char::is_whitespace)) {
+        return None;
+    }
+    Some((name, rest.trim()))
+}
+
+/// Split an argument string on whitespace, keeping double-quoted runs together.
+fn tokenize_args(args: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    let mut in_quotes = false;
+    let mut has_token = false;
+    for c in args.chars() {
+        match c {
+            '"' => {
+                in_quotes = !in_quotes;
+                has_token = true;
+            }
+            c if c.is_whitespace() && !in_quotes => {
+                if has_token {
+                    tokens.push(std::mem::take(&mut current));
+                    has_token = false;
+                }
+            }
+            c => {
+                current.push(c);
+                has_token = true;
+            }
+        }
+    }
+    if has_token {
+        tokens.push(current);
+    }
+    tokens
+}
+
+fn find_command<'a>(commands: &'a [SlashCommand], name: &str) -> Option<&'a SlashCommand> {
+    commands.iter().find(|command| {
+        command
+            .name
+            .trim()
+            .trim_start_matches('/')
+            .eq_ignore_ascii_case(name)
+    })
+}
+
+/// Fill the `{args}` and `{param}` placeholders of `template` in a single pass, so a value
+/// that itself contains a placeholder is inserted as written. Other braces are kept.
+fn fill_template(template: &str, args: &str, values: &HashMap<&str, String>) -> String {
+    let mut filled = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        filled.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let replacement = after.find('}').and_then(|end| {
+            let key = &after[..end];
+            let value = if key == ALL_ARGS_PLACEHOLDER {
+                Some(args)
+            } else {
+                values.get(key).map(String::as_str)
+            };
+            value.map(|value| (value, end))
+        });
+        match replacement {
+            Some((value, end)) => {
+                filled.push_str(value);
+                rest = &after[end + 1..];
+            }
+            None => {
+                filled.push('{');
+                rest = after;
+            }
+        }
+    }
+    filled.push_str(rest);
+    filled
+}
+
+/// Expand `content` when it starts with a configured slash command. Returns `Ok(None)`
+/// for every other message, including text such as `/tmp is full` that names no
+/// configured command, and an error when a command is missing arguments.
+///
+/// Arguments written as `param=value` fill that parameter; the remaining arguments fill
+/// the other parameters in declaration order.
+pub fn expand_slash_command(
+    commands: &[SlashCommand],
+    content: &str,
+) -> Result<Option<ExpandedSlashCommand>, String> {
+    if commands.is_empty() {
+        return Ok(None);
+    }
+    let Some((name, args)) = split_command(content) else {
+        return Ok(None);
+    };
+    let Some(command) = find_command(commands, name) else {
+        return Ok(None);
+    };
+
+    let mut values: HashMap<&str, String> = HashMap::new();
+    let mut positional = Vec::new();
+    for token in tokenize_args(args) {
+        let named = token.split_once('=').and_then(|(key, value)| {
+            command
+                .params
+                .iter()
+                .find(|param| param.as_str() == key)
+                .map(|param| (param.as_str(), value.to_string()))
+        });
+        match named {
+            Some((param, value)) => {
+                values.insert(param, value);
+            }
+            None => positional.push(token),
+        }
+    }
+    let mut positional = positional.into_iter();
+    for param in &command.params {
+        if values.contains_key(param.as_str()) {
+            continue;
+        }
+        let value = positional
+            .next()
+            .ok_or_else(|| format!("Missing argument '{param}' for /{name}"))?;
+        values.insert(param.as_str(), value);
+    }
+
+    Ok(Some(ExpandedSlashCommand {
+        name: name.to_string(),
+        content: fill_template(&command.template, args, &values),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{expand_slash_command, tokenize_args};
+    use crate::services::config::SlashCommand;
+
+    fn review_command() -> SlashCommand {
+        SlashCommand {
+            name: "review".to_string(),
+            description: String::new(),
+            template: "@reviewer please review {file} focusing on {focus}. ({args})".to_string(),
+            params: vec!["file".to_string(), "focus".to_string()],
+        }
+    }
+
+    #[test]
+    fn expands_positional_and_named_arguments() {
+        let commands = vec![review_command()];
+        let expanded = expand_slash_command(&commands, "/review focus=perf src/main.rs")
+            .unwrap()
+            .unwrap();
+        assert_eq!(expanded.name, "review");
+        assert_eq!(
+            expanded.content,
+            "@reviewer please review src/main.rs focusing on perf. (focus=perf src/main.rs)"
+        );
+
+        let expanded = expand_slash_command(&commands, "/REVIEW \"a b.rs\" style")
+            .unwrap()
+            .unwrap();
+        assert!(
+            expanded
+                .content
+                .starts_with("@reviewer please review a b.rs focusing on style.")
+        );
+    }
+
+    #[test]
+    fn substituted_values_are_not_expanded_again() {
+        let commands = vec![review_command()];
+        let expanded = expand_slash_command(&commands, "/review {focus} {file}")
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            expanded.content,
+            "@reviewer please review {focus} focusing on {file}. ({focus} {file})"
+        );
+
+        let mut literal = review_command();
+        literal.template = "{file} {unknown} {".to_string();
+        let expanded = expand_slash_command(&[literal], "/review a b")
+            .unwrap()
+            .unwrap();
+        assert_eq!(expanded.content, "a {unknown} {");
+    }
+
+    #[test]
+    fn messages_pass_through_without_configured_commands() {
+        for content in ["/tmp is full", "/help me"] {
+            assert_eq!(expand_slash_command(&[], content), Ok(None), "{content}");
+        }
+    }
+
+    #[test]
+    fn rejects_missing_arguments() {
+        let commands = vec![review_command()];
+        assert!(expand_slash_command(&commands, "/review src/main.rs").is_err());
+    }
+
+    #[test]
+    fn ordinary_messages_are_not_commands() {
+        let commands = vec![review_command()];
+        for content in [
+            "hello",
+            "/usr/bin is missing",
+            "/tmp is full",
+            "/deploy now",
+            "// comment",
+            "//review a b",
+            "/",
+            "a /review x y",
+        ] {
+            assert_eq!(
+                expand_slash_command(&commands, content),
+                Ok(None),
+                "{content}"
+            );
+        }
+        assert_eq!(tokenize_args(" a  \"b c\" \"\" "), vec!["a", "b c", ""]);
+    }
+}
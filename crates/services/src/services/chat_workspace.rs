@@ -0,0 +1,566 @@
+//! Directory layout of a chat session agent's workspace.
+//!
+//! Every path the runner reads or writes inside a workspace is derived here, so the
+//! context, run record and scratch directories stay consistent across call sites.
+
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use utils::assets::asset_dir;
+use uuid::Uuid;
+
+/// Directory inside a workspace holding all chat runtime artifacts.
+pub const AGENTS_CHATGROUP_WORKSPACE_DIR: &str = ".agents_chatgroup";
+/// Agent `tools_enabled` key listing workspaces the agent works in besides its primary one.
+pub const ADDITIONAL_WORKSPACE_PATHS_KEY: &str = "additional_workspace_paths";
+/// Agent `tools_enabled` key choosing what a run's captured git diff is taken against.
+pub const GIT_DIFF_BASE_KEY: &str = "git_diff_base";
+const RUNS_DIR_NAME: &str = "runs";
+const CONTEXT_DIR_NAME: &str = "context";
+const SCRATCH_DIR_NAME: &str = "scratch";
+/// Directory under a session's runs directory holding one record directory per run.
+pub const RUN_RECORDS_DIR_NAME: &str = "run_records";
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum WorkspacePathError {
+    #[error("Workspace path is required.")]
+    Empty,
+    #[error("Workspace path contains invalid characters.")]
+    InvalidCharacters,
+    #[error("Workspace path cannot contain '..'.")]
+    ParentDir,
+    #[error("Workspace path contains invalid Windows filename characters.")]
+    InvalidWindowsCharacters,
+    #[error("Workspace path contains reserved Windows name: {0}")]
+    ReservedWindowsName(String),
+    #[error("Workspace path segment cannot end with a dot or space on Windows: {0}")]
+    TrailingDotOrSpace(String),
+    #[error("Workspace path must include a drive root such as C:\\, not be relative to a drive.")]
+    DriveRelative,
+    #[error("Workspace path does not exist.")]
+    NotFound,
+    #[error("Workspace path is not accessible: {0}")]
+    Inaccessible(String),
+    #[error("Workspace path must be an existing directory.")]
+    NotADirectory,
+}
+
+/// Paths derived from a session agent's workspace root, scoped to one session.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WorkspaceLayout {
+    root: PathBuf,
+    session_id: Uuid,
+}
+
+impl WorkspaceLayout {
+    pub fn new(root: impl Into<PathBuf>, session_id: Uuid) -> Self {
+        Self {
+            root: root.into(),
+            session_id,
+        }
+    }
+
+    /// Layout for a session agent, falling back to the managed default workspace when the
+    /// member has no workspace path of its own.
+    pub fn for_session_agent(
+        session_id: Uuid,
+        agent_id: Uuid,
+        workspace_path: Option<&str>,
+    ) -> Self {
+        let root = workspace_path
+            .map(PathBuf::from)
+            .unwrap_or_else(|| Self::default_root(session_id, agent_id));
+        Self::new(root, session_id)
+    }
+
+    /// Managed workspace used for members without a configured workspace path.
+    pub fn default_root(session_id: Uuid, agent_id: Uuid) -> PathBuf {
+        asset_dir()
+            .join("chat")
+            .join(format!("session_{session_id}"))
+            .join("agents")
+            .join(agent_id.to_string())
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn root_string(&self) -> String {
+        self.root.to_string_lossy().to_string()
+    }
+
+    fn session_dir(&self, kind: &str) -> PathBuf {
+        self.root
+            .join(AGENTS_CHATGROUP_WORKSPACE_DIR)
+            .join(kind)
+            .join(self.session_id.to_string())
+    }
+
+    pub fn runs_dir(&self) -> PathBuf {
+        self.session_dir(RUNS_DIR_NAME)
+    }
+
+    pub fn context_dir(&self) -> PathBuf {
+        self.session_dir(CONTEXT_DIR_NAME)
+    }
+
+    pub fn scratch_dir(&self) -> PathBuf {
+        self.session_dir(SCRATCH_DIR_NAME)
+    }
+
+    pub fn run_records_dir(&self) -> PathBuf {
+        self.runs_dir().join(RUN_RECORDS_DIR_NAME)
+    }
+
+    /// Record directory of one run: `input.md`, `output.md`, `raw.log`, `meta.json`.
+    pub fn run_dir(&self, session_agent_id: Uuid, run_index: i64) -> PathBuf {
+        self.run_records_dir()
+            .join(run_dir_name(session_agent_id, run_index))
+    }
+
+    /// Resolve an additional workspace path; relative paths are sub-paths of the root.
+    pub fn resolve_additional(&self, raw: &str) -> PathBuf {
+        self.root.join(raw.trim())
+    }
+}
+
+/// Name of a run's record directory inside [`RUN_RECORDS_DIR_NAME`].
+pub fn run_dir_name(session_agent_id: Uuid, run_index: i64) -> String {
+    format!("session_agent_{session_agent_id}_run_{run_index:04}")
+}
+
+/// Additional workspace paths from an agent's `tools_enabled`, as configured.
+pub fn additional_workspace_paths(tools_enabled: &serde_json::Value) -> Vec<String> {
+    tools_enabled
+        .get(ADDITIONAL_WORKSPACE_PATHS_KEY)
+        .and_then(serde_json::Value::as_array)
+        .map(|paths| {
+            paths
+                .iter()
+                .filter_map(serde_json::Value::as_str)
+                .map(str::trim)
+                .filter(|path| !path.is_empty())
+                .map(str::to_string)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Label separating an additional workspace's files from the primary workspace's in run
+/// records, e.g. `frontend` for `packages/frontend`.
+pub fn workspace_label(path: &Path, index: usize) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| format!("workspace_{index}"))
+}
+
+/// What a run's captured diff compares the workspace against.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GitDiffBase {
+    /// Unstaged changes to tracked files.
+    #[default]
+    Working,
+    /// Changes staged in the index.
+    Staged,
+    /// Staged and unstaged changes since `HEAD`.
+    Head,
+    /// Working tree against a branch, tag or commit, so committed work is included.
+    Ref(String),
+}
+
+impl GitDiffBase {
+    /// Arguments following `git diff --no-color`.
+    pub fn diff_args(&self) -> Vec<&str> {
+        match self {
+            Self::Working => Vec::new(),
+            Self::Staged => vec!["--cached"],
+            Self::Head => vec!["HEAD", "--"],
+            Self::Ref(reference) => vec![reference.as_str(), "--"],
+        }
+    }
+
+    /// Whether the diff can only be non-empty while `git status` lists tracked changes;
+    /// a ref base also covers changes that were committed since.
+    pub fn requires_uncommitted_changes(&self) -> bool {
+        !matches!(self, Self::Ref(_))
+    }
+}
+
+/// Diff base from an agent's `tools_enabled`; missing or malformed values, including a
+/// ref git would read as an option, keep the working-tree diff.
+pub fn git_diff_base(tools_enabled: &serde_json::Value) -> GitDiffBase {
+    tools_enabled
+        .get(GIT_DIFF_BASE_KEY)
+        .and_then(|base| serde_json::from_value(base.clone()).ok())
+        .filter(|base| match base {
+            GitDiffBase::Ref(reference) => is_usable_ref(reference),
+            _ => true,
+        })
+        .unwrap_or_default()
+}
+
+/// Whether `reference` can be handed to git as a revision: not empty, not option-like and
+/// free of whitespace and control characters.
+fn is_usable_ref(reference: &str) -> bool {
+    !reference.is_empty()
+        && !reference.starts_with('-')
+        && !reference
+            .chars()
+            .any(|ch| ch.is_whitespace() || ch.is_control())
+}
+
+/// Check that an agent's `tools_enabled.git_diff_base`, when present, names a known base
+/// or a usable ref.
+pub fn validate_git_diff_base(tools_enabled: &serde_json::Value) -> Result<(), String> {
+    let Some(base) = tools_enabled.get(GIT_DIFF_BASE_KEY) else {
+        return Ok(());
+    };
+    let base: GitDiffBase = serde_json::from_value(base.clone()).map_err(|err| {
+        format!(
+            "Invalid git diff base: {err}; expected \"working\", \"staged\", \"head\" or {{\"ref\": \"<ref>\"}}"
+        )
+    })?;
+    if let GitDiffBase::Ref(reference) = &base {
+        if reference.trim().is_empty() {
+            return Err("Git diff base ref is required.".to_string());
+        }
+        if !is_usable_ref(reference) {
+            return Err(format!("Invalid git diff base ref: {reference}"));
+        }
+    }
+    Ok(())
+}
+
+/// Whether Windows treats `name` as a device name. Windows ignores everything from the
+/// first dot and trailing spaces, so `CON.txt` and `nul ` are reserved too, as are the
+/// superscript-digit ports `COM¹`..`LPT³`.
+fn is_windows_reserved_name(name: &str) -> bool {
+    let base = name
+        .split('.')
+        .next()
+        .unwrap_or_default()
+        .trim_end_matches(' ');
+    let upper = base.to_ascii_uppercase();
+    if matches!(upper.as_str(), "CON" | "PRN" | "AUX" | "NUL") {
+        return true;
+    }
+    let Some(port) = upper
+        .strip_prefix("COM")
+        .or_else(|| upper.strip_prefix("LPT"))
+    else {
+        return false;
+    };
+    let mut chars = port.chars();
+    matches!(
+        (chars.next(), chars.next()),
+        (Some('1'..='9' | '¹' | '²' | '³'), None)
+    )
+}
+
+/// Path segments split on both `/` and `\`, since Windows accepts either separator.
+fn path_segments(path: &str) -> impl Iterator<Item = &str> {
+    path.split(['/', '\\'])
+}
+
+/// Check a path against the Windows naming rules: no drive-relative paths (`C:repo`,
+/// `\repo`), no reserved device names, no `<>:"|?*` and no segments ending in a dot or
+/// space. Applied to every workspace path on Windows; callable anywhere for testing.
+pub fn validate_windows_workspace_path(path: &str) -> Result<(), WorkspacePathError> {
+    let path = path.strip_prefix(r"\\?\").unwrap_or(path);
+    let is_separator = |ch: char| ch == '/' || ch == '\\';
+    let mut chars = path.chars();
+    let rest = match (chars.next(), chars.next(), chars.next()) {
+        // UNC share: \\server\share\...
+        (Some(first), Some(second), _) if is_separator(first) && is_separator(second) => &path[2..],
+        (Some(first), _, _) if is_separator(first) => {
+            return Err(WorkspacePathError::DriveRelative);
+        }
+        (Some(drive), Some(':'), next) if drive.is_ascii_alphabetic() => match next {
+            Some(ch) if is_separator(ch) => &path[2..],
+            _ => return Err(WorkspacePathError::DriveRelative),
+        },
+        _ => path,
+    };
+
+    for segment in path_segments(rest).filter(|segment| !matches!(*segment, "" | "." | "..")) {
+        if segment
+            .chars()
+            .any(|ch| matches!(ch, '<' | '>' | ':' | '"' | '|' | '?' | '*'))
+        {
+            return Err(WorkspacePathError::InvalidWindowsCharacters);
+        }
+        if is_windows_reserved_name(segment) {
+            return Err(WorkspacePathError::ReservedWindowsName(segment.to_string()));
+        }
+        if segment.ends_with(['.', ' ']) {
+            return Err(WorkspacePathError::TrailingDotOrSpace(segment.to_string()));
+        }
+    }
+    Ok(())
+}
+
+/// Check that a user-supplied workspace root is a legal path, without touching the disk.
+/// On Windows the returned path uses `\` separators throughout.
+pub fn validate_workspace_root_legality(raw: &str) -> Result<PathBuf, WorkspacePathError> {
+    let trimmed = raw.trim();
+    if trimmed.is_empty() {
+        return Err(WorkspacePathError::Empty);
+    }
+    if trimmed.chars().any(|ch| ch == '\0' || ch.is_control()) {
+        return Err(WorkspacePathError::InvalidCharacters);
+    }
+    // `..` between backslashes is rejected on every platform, so a stored path cannot turn
+    // into a traversal once it is used on Windows.
+    if path_segments(trimmed).any(|segment| segment == "..") {
+        return Err(WorkspacePathError::ParentDir);
+    }
+
+    #[cfg(windows)]
+    validate_windows_workspace_path(trimmed)?;
+    #[cfg(windows)]
+    let trimmed = trimmed.replace('/', "\\");
+    Ok(PathBuf::from(trimmed))
+}
+
+/// Validate the additional workspace paths in an agent's `tools_enabled`. Relative paths
+/// depend on the session's workspace, so only absolute ones must already exist.
+pub async fn validate_additional_workspace_paths(
+    tools_enabled: &serde_json::Value,
+) -> Result<(), WorkspacePathError> {
+    for raw in additional_workspace_paths(tools_enabled) {
+        if validate_workspace_root_legality(&raw)?.is_absolute() {
+            validate_workspace_root(&raw).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Validate a user-supplied workspace root and require it to be an existing directory.
+pub async fn validate_workspace_root(raw: &str) -> Result<PathBuf, WorkspacePathError> {
+    let parsed_path = validate_workspace_root_legality(raw)?;
+    let metadata = tokio::fs::metadata(&parsed_path)
+        .await
+        .map_err(|err| match err.kind() {
+            std::io::ErrorKind::NotFound => WorkspacePathError::NotFound,
+            _ => WorkspacePathError::Inaccessible(err.to_string()),
+        })?;
+    if !metadata.is_dir() {
+        return Err(WorkspacePathError::NotADirectory);
+    }
+    Ok(parsed_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::{
+        GitDiffBase, WorkspaceLayout, WorkspacePathError, additional_workspace_paths,
+        git_diff_base, is_windows_reserved_name, validate_git_diff_base,
+        validate_windows_workspace_path, validate_workspace_root_legality, workspace_label,
+    };
+
+    #[test]
+    fn layout_derives_session_scoped_paths() {
+        let session_id = Uuid::new_v4();
+        let session_agent_id = Uuid::new_v4();
+        let layout = WorkspaceLayout::new("/work/repo", session_id);
+        let internal = std::path::Path::new("/work/repo").join(".agents_chatgroup");
+
+        assert_eq!(
+            layout.context_dir(),
+            internal.join("context").join(session_id.to_string())
+        );
+        assert_eq!(
+            layout.scratch_dir(),
+            internal.join("scratch").join(session_id.to_string())
+        );
+        assert_eq!(
+            layout.run_dir(session_agent_id, 7),
+            internal
+                .join("runs")
+                .join(session_id.to_string())
+                .join("run_records")
+                .join(format!("session_agent_{session_agent_id}_run_0007"))
+        );
+    }
+
+    #[test]
+    fn session_agent_layout_prefers_configured_workspace() {
+        let session_id = Uuid::new_v4();
+        let agent_id = Uuid::new_v4();
+
+        let configured = WorkspaceLayout::for_session_agent(session_id, agent_id, Some("/repo"));
+        assert_eq!(configured.root(), std::path::Path::new("/repo"));
+
+        let managed = WorkspaceLayout::for_session_agent(session_id, agent_id, None);
+        assert_eq!(
+            managed.root(),
+            WorkspaceLayout::default_root(session_id, agent_id)
+        );
+    }
+
+    #[test]
+    fn workspace_root_legality_rejects_bad_input() {
+        assert_eq!(
+            validate_workspace_root_legality("   "),
+            Err(WorkspacePathError::Empty)
+        );
+        assert_eq!(
+            validate_workspace_root_legality("/tmp/a\u{7}b"),
+            Err(WorkspacePathError::InvalidCharacters)
+        );
+        assert_eq!(
+            validate_workspace_root_legality("/tmp/../etc"),
+            Err(WorkspacePathError::ParentDir)
+        );
+        assert_eq!(
+            validate_workspace_root_legality(r"repo\..\..\secrets"),
+            Err(WorkspacePathError::ParentDir)
+        );
+        #[cfg(not(windows))]
+        assert_eq!(
+            validate_workspace_root_legality(" /tmp/work "),
+            Ok(std::path::PathBuf::from("/tmp/work"))
+        );
+    }
+
+    #[test]
+    fn windows_reserved_names_ignore_extensions_and_casing() {
+        for name in [
+            "CON",
+            "con",
+            "Nul.txt",
+            "aux .tar.gz",
+            "COM1",
+            "lpt9",
+            "COM¹",
+            "LPT³",
+        ] {
+            assert!(is_windows_reserved_name(name), "{name}");
+        }
+        for name in [
+            "CONSOLE",
+            "COM",
+            "COM0",
+            "COM10",
+            "LPT¹¹",
+            "nullable",
+            "工作区",
+            "ÇON",
+        ] {
+            assert!(!is_windows_reserved_name(name), "{name}");
+        }
+    }
+
+    #[test]
+    fn windows_paths_reject_drive_relative_and_reserved_segments() {
+        for path in [
+            r"C:\Users\me\repo",
+            "C:/Users/me/repo",
+            r"\\server\share\repo",
+            r"\\?\C:\Users\me\repo",
+            r"packages\web",
+            r"D:\项目\仓库",
+            r".\repo",
+        ] {
+            assert_eq!(validate_windows_workspace_path(path), Ok(()), "{path}");
+        }
+
+        assert_eq!(
+            validate_windows_workspace_path("C:repo"),
+            Err(WorkspacePathError::DriveRelative)
+        );
+        assert_eq!(
+            validate_windows_workspace_path(r"\repo"),
+            Err(WorkspacePathError::DriveRelative)
+        );
+        assert_eq!(
+            validate_windows_workspace_path(r"C:\work\con.txt\repo"),
+            Err(WorkspacePathError::ReservedWindowsName(
+                "con.txt".to_string()
+            ))
+        );
+        assert_eq!(
+            validate_windows_workspace_path("C:/work/NUL"),
+            Err(WorkspacePathError::ReservedWindowsName("NUL".to_string()))
+        );
+        assert_eq!(
+            validate_windows_workspace_path(r"C:\work\a|b"),
+            Err(WorkspacePathError::InvalidWindowsCharacters)
+        );
+        assert_eq!(
+            validate_windows_workspace_path(r"C:\work\repo."),
+            Err(WorkspacePathError::TrailingDotOrSpace("repo.".to_string()))
+        );
+        assert_eq!(
+            validate_windows_workspace_path(r"C:\work\repo \src"),
+            Err(WorkspacePathError::TrailingDotOrSpace("repo ".to_string()))
+        );
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn windows_workspace_roots_use_backslash_separators() {
+        assert_eq!(
+            validate_workspace_root_legality("C:/Users/me/repo"),
+            Ok(std::path::PathBuf::from(r"C:\Users\me\repo"))
+        );
+    }
+
+    #[test]
+    fn additional_workspaces_resolve_against_the_root() {
+        let tools_enabled = serde_json::json!({
+            "additional_workspace_paths": ["packages/web", " ", "/srv/api", 7],
+        });
+        let paths = additional_workspace_paths(&tools_enabled);
+        assert_eq!(paths, vec!["packages/web", "/srv/api"]);
+
+        let layout = WorkspaceLayout::new("/work/repo", Uuid::new_v4());
+        let web = layout.resolve_additional(&paths[0]);
+        assert_eq!(web, std::path::Path::new("/work/repo/packages/web"));
+        assert_eq!(
+            layout.resolve_additional(&paths[1]),
+            std::path::Path::new("/srv/api")
+        );
+        assert_eq!(workspace_label(&web, 0), "web");
+        assert!(additional_workspace_paths(&serde_json::json!({})).is_empty());
+    }
+
+    #[test]
+    fn git_diff_base_defaults_to_working_tree() {
+        assert_eq!(git_diff_base(&serde_json::json!({})), GitDiffBase::Working);
+        assert_eq!(
+            git_diff_base(&serde_json::json!({ "git_diff_base": "staged" })),
+            GitDiffBase::Staged
+        );
+        assert_eq!(
+            git_diff_base(&serde_json::json!({ "git_diff_base": { "ref": "main" } })),
+            GitDiffBase::Ref("main".to_string())
+        );
+        assert_eq!(
+            git_diff_base(&serde_json::json!({ "git_diff_base": "upstream" })),
+            GitDiffBase::Working
+        );
+        assert_eq!(
+            git_diff_base(&serde_json::json!({ "git_diff_base": { "ref": "--output=/tmp/x" } })),
+            GitDiffBase::Working
+        );
+        assert_eq!(GitDiffBase::Head.diff_args(), vec!["HEAD", "--"]);
+
+        assert!(validate_git_diff_base(&serde_json::json!({ "git_diff_base": "head" })).is_ok());
+        assert!(
+            validate_git_diff_base(&serde_json::json!({ "git_diff_base": "upstream" })).is_err()
+        );
+        assert!(
+            validate_git_diff_base(
+                &serde_json::json!({ "git_diff_base": { "ref": "--output=x" } })
+            )
+            .is_err()
+        );
+    }
+}
@@ -20,6 +20,13 @@ Analyze the changes in this branch and write:
 
 Use the appropriate CLI tool to update the PR (gh pr edit for GitHub, az repos pr update for Azure DevOps)."#;
 
+/// Chat handle that addresses the human user; always reserved regardless of config.
+///
+/// Agents reply to it when a message has no usable `sender_handle`. A mention of it
+/// never starts a run: from an agent it is a reply to the user, and from a user it
+/// addresses a human (themselves or another user with that handle), not an agent.
+pub const RESERVED_USER_HANDLE: &str = "you";
+
 pub const DEFAULT_COMMIT_REMINDER_PROMPT: &str = "There are uncommitted changes. Please stage and commit them now with a descriptive commit message.";
 
 #[derive(Debug, Error)]
@@ -46,6 +53,16 @@ pub type ChatMemberPreset = versions::v9::ChatMemberPreset;
 pub type ChatTeamPreset = versions::v9::ChatTeamPreset;
 pub type ChatPresetsConfig = versions::v9::ChatPresetsConfig;
 pub type ChatCompressionConfig = versions::v9::ChatCompressionConfig;
+pub type ChatRunnerConfig = versions::v9::ChatRunnerConfig;
+pub type ModeratorTimeoutAction = versions::v9::ModeratorTimeoutAction;
+pub type UnknownMentionPolicy = versions::v9::UnknownMentionPolicy;
+pub type AgentUnresolvedRoutingPolicy = versions::v9::AgentUnresolvedRoutingPolicy;
+pub type SystemMessageCategory = versions::v9::SystemMessageCategory;
+pub type SummaryBusyAgentsPolicy = versions::v9::SummaryBusyAgentsPolicy;
+pub type RunContextMode = versions::v9::RunContextMode;
+pub type ChatContextFormat = versions::v9::ChatContextFormat;
+pub type RedactionRule = versions::v9::RedactionRule;
+pub type SlashCommand = versions::v9::SlashCommand;
 
 /// Will always return config, trying old schemas or eventually returning default
 pub async fn load_config_from_file(config_path: &PathBuf) -> Config {
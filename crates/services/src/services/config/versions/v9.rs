@@ -1,15 +1,16 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
 use anyhow::Error;
 use executors::{executors::BaseCodingAgent, profile::ExecutorProfileId};
 use serde::{Deserialize, Serialize};
 use ts_rs::TS;
+use uuid::Uuid;
 pub use v8::{
     EditorConfig, EditorType, GitHubConfig, NotificationConfig, SendMessageShortcut, ShowcaseState,
     SoundFile, ThemeMode, UiLanguage,
 };
 
-use crate::services::config::versions::v8;
+use crate::services::config::{RESERVED_USER_HANDLE, versions::v8};
 
 fn default_git_branch_prefix() -> String {
     "vk".to_string()
@@ -84,6 +85,60 @@ pub struct ChatCompressionConfig {
     /// Percentage of messages to compress (default: 25)
     #[serde(default = "default_compression_percentage")]
     pub compression_percentage: u8,
+    /// Agent ids tried first for summarization, in order, before state-based ordering
+    #[serde(default)]
+    pub summary_agent_priority: Vec<Uuid>,
+    /// Also compress once history holds more than this many messages (unset: tokens only)
+    #[serde(default)]
+    pub message_count_threshold: Option<u32>,
+    /// What summarization does when every session agent is busy (default: SKIP)
+    #[serde(default)]
+    pub busy_agents_policy: SummaryBusyAgentsPolicy,
+    /// Upper bound in seconds for the WAIT policy, clamped to 1..=60 (default: 15)
+    #[serde(default = "default_busy_agents_wait_secs")]
+    pub busy_agents_wait_secs: u32,
+    /// Executor profile used by the SUMMARIZER policy instead of a session agent
+    #[serde(default)]
+    pub summarizer_profile: Option<ExecutorProfileId>,
+    /// Retry once with a stricter prompt when a summary does not reduce the token count,
+    /// before falling back to truncation (default: false)
+    #[serde(default)]
+    pub retry_ineffective_summary: bool,
+    /// Where runs take their shared history from relative to background compaction
+    /// (default: FULL_THEN_COMPACT)
+    #[serde(default)]
+    pub run_context_mode: RunContextMode,
+}
+
+/// How a run's shared history file is built relative to background compaction
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, TS, Default, PartialEq, Eq)]
+#[ts(export)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum RunContextMode {
+    /// Write the full history, then compact in the background for later runs
+    #[default]
+    FullThenCompact,
+    /// Reuse a cached compaction still valid for the history's prefix plus the messages
+    /// appended since, falling back to the full history; compaction still runs afterwards
+    CachedThenCompact,
+}
+
+/// What summarization does when every agent in the session is running
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, TS, Default, PartialEq, Eq)]
+#[ts(export)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum SummaryBusyAgentsPolicy {
+    /// Fall back to truncation immediately
+    #[default]
+    Skip,
+    /// Poll for an idle agent up to `busy_agents_wait_secs`, then fall back
+    Wait,
+    /// Summarize with `summarizer_profile`, falling back when it is not configured
+    Summarizer,
+}
+
+fn default_busy_agents_wait_secs() -> u32 {
+    15
 }
 
 fn default_token_threshold() -> u32 {
@@ -94,11 +149,24 @@ fn default_compression_percentage() -> u8 {
     25
 }
 
+impl ChatCompressionConfig {
+    pub fn busy_agents_wait(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(u64::from(self.busy_agents_wait_secs.clamp(1, 60)))
+    }
+}
+
 impl Default for ChatCompressionConfig {
     fn default() -> Self {
         Self {
             token_threshold: default_token_threshold(),
             compression_percentage: default_compression_percentage(),
+            summary_agent_priority: Vec::new(),
+            message_count_threshold: None,
+            busy_agents_policy: SummaryBusyAgentsPolicy::default(),
+            busy_agents_wait_secs: default_busy_agents_wait_secs(),
+            summarizer_profile: None,
+            retry_ineffective_summary: false,
+            run_context_mode: RunContextMode::default(),
         }
     }
 }
@@ -107,6 +175,509 @@ fn default_chat_compression() -> ChatCompressionConfig {
     ChatCompressionConfig::default()
 }
 
+/// What happens to a moderated forward when the moderator does not decide in time
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, TS, Default, PartialEq, Eq)]
+#[ts(export)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ModeratorTimeoutAction {
+    #[default]
+    Drop,
+    Proceed,
+}
+
+/// How a mention that matches no agent at all is handled
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, TS, Default, PartialEq, Eq)]
+#[ts(export)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum UnknownMentionPolicy {
+    /// Post a failure system message every time
+    #[default]
+    Fail,
+    /// Drop the mention silently
+    Ignore,
+    /// Post a failure the first time a handle is mentioned in a session, then ignore it
+    WarnOnce,
+}
+
+/// How an agent's `[sendMessageTo@@X]` is handled when X is not an agent of the session
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, TS, Default, PartialEq, Eq)]
+#[ts(export)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum AgentUnresolvedRoutingPolicy {
+    /// Report a mention failure and post a system message
+    #[default]
+    Fail,
+    /// Drop the route without any report
+    SilentDrop,
+    /// Send a `system_notice` stream event without storing a system message
+    WarnEventOnly,
+}
+
+/// Kind of system message the chat runner posts into a transcript, stored in the
+/// message's `meta.system_category`
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, TS, PartialEq, Eq, Hash)]
+#[ts(export)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum SystemMessageCategory {
+    /// A mentioned agent could not run
+    MentionFailure,
+    /// A held forward handed to the session moderator for review
+    ForwardReview,
+    /// The moderator's decision on, or the timeout of, a held forward
+    ForwardOutcome,
+}
+
+/// Shape of the group history file handed to agents
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, TS, Default, PartialEq, Eq)]
+#[ts(export)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ChatContextFormat {
+    /// One JSON object per line with sender, content and time
+    #[default]
+    JsonlCompact,
+    /// JSON lines that also carry each message's id and mentions
+    JsonlRich,
+    /// One Markdown section per message
+    Markdown,
+}
+
+impl ChatContextFormat {
+    pub fn file_extension(self) -> &'static str {
+        match self {
+            Self::JsonlCompact | Self::JsonlRich => "jsonl",
+            Self::Markdown => "md",
+        }
+    }
+}
+
+/// A pattern whose matches are replaced in agent output before it is streamed or stored
+#[derive(Clone, Debug, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct RedactionRule {
+    /// Regular expression in Rust `regex` syntax
+    pub pattern: String,
+    /// Replacement text; `$1` style references to capture groups are expanded
+    /// (default: "[REDACTED]")
+    #[serde(default = "default_redaction_replacement")]
+    pub replacement: String,
+}
+
+fn default_redaction_replacement() -> String {
+    "[REDACTED]".to_string()
+}
+
+/// A message template users invoke by starting a message with `/name`
+#[derive(Clone, Debug, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct SlashCommand {
+    /// Command name without the leading slash, matched case-insensitively
+    pub name: String,
+    /// Short help text shown next to the command
+    #[serde(default)]
+    pub description: String,
+    /// Text the message is replaced with; `{param}` inserts a parameter and `{args}` the
+    /// whole argument string
+    pub template: String,
+    /// Parameter names, filled by `param=value` arguments or else by the remaining
+    /// arguments in order
+    #[serde(default)]
+    pub params: Vec<String>,
+}
+
+/// Chat Runner Configuration
+#[derive(Clone, Debug, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct ChatRunnerConfig {
+    /// Maximum size of a run's raw.log in MB before disk capture stops (default: 256)
+    #[serde(default = "default_max_run_log_mb")]
+    pub max_run_log_mb: u32,
+    /// Per-executor overrides of `max_run_log_mb`, keyed by runner type (e.g. "CODEX")
+    #[serde(default)]
+    pub max_run_log_mb_overrides: HashMap<String, u32>,
+    /// Largest attachment in MB copied into a run's context directory; larger ones are
+    /// referenced by their original path and marked as not copied. 0 copies any size
+    /// (default: 20)
+    #[serde(default = "default_max_attachment_copy_mb")]
+    pub max_attachment_copy_mb: u32,
+    /// Seconds to wait for a session moderator to decide on a forward (default: 300)
+    #[serde(default = "default_moderator_timeout_secs")]
+    pub moderator_timeout_secs: u32,
+    /// Action applied to a forward once `moderator_timeout_secs` elapses (default: DROP)
+    #[serde(default)]
+    pub moderator_timeout_action: ModeratorTimeoutAction,
+    /// Seconds allowed for workspace setup and git capture before a run fails (default: 30)
+    #[serde(default = "default_workspace_io_timeout_secs")]
+    pub workspace_io_timeout_secs: u32,
+    /// Seconds an executor may take to start its process before the run fails; 0 disables
+    /// (default: 60)
+    #[serde(default = "default_spawn_timeout_secs")]
+    pub spawn_timeout_secs: u32,
+    /// Maximum number of AI members a single session may contain (default: 20)
+    #[serde(default = "default_max_agents_per_session")]
+    pub max_agents_per_session: u32,
+    /// Runs a single message may start at once; further mentions wait for a slot and
+    /// start in mention order. 0 starts them all at once (default: 4)
+    #[serde(default = "default_mention_fanout_concurrency")]
+    pub mention_fanout_concurrency: u32,
+    /// Handles that cannot be used as AI member names or routed to as agents (default: ["you"])
+    #[serde(default = "default_reserved_handles")]
+    pub reserved_handles: Vec<String>,
+    /// Match reserved handles, including "you", only with their exact casing, so members
+    /// such as "You" or "System" can be created; by default casing is ignored (default: false)
+    #[serde(default)]
+    pub reserved_handle_case_sensitive: bool,
+    /// Milliseconds to coalesce streaming deltas before sending them; 0 sends each immediately (default: 50)
+    #[serde(default = "default_delta_flush_interval_ms")]
+    pub delta_flush_interval_ms: u32,
+    /// Buffered delta size in bytes that triggers an early flush (default: 4096)
+    #[serde(default = "default_delta_flush_max_bytes")]
+    pub delta_flush_max_bytes: u32,
+    /// Skip re-sending assistant/thinking content identical to what the stream already
+    /// shows, for executors that re-emit whole messages (default: true)
+    #[serde(default = "default_true")]
+    pub dedupe_identical_deltas: bool,
+    /// Executor stdout lines longer than this many bytes are not parsed for token usage;
+    /// usage events are small and long lines are typically file dumps (default: 65536)
+    #[serde(default = "default_max_token_usage_line_bytes")]
+    pub max_token_usage_line_bytes: u32,
+    /// Seconds without any executor output before a run is cancelled; 0 disables (default: 300)
+    #[serde(default = "default_idle_timeout_secs")]
+    pub idle_timeout_secs: u32,
+    /// Seconds a run may take regardless of activity before it is killed, not counting time
+    /// spent waiting on a tool approval; agents may override it with
+    /// `tools_enabled.max_run_duration_secs`. 0 disables (default: 7200)
+    #[serde(default = "default_max_run_duration_secs")]
+    pub max_run_duration_secs: u32,
+    /// Timezone for times shown in agent context and exports: "local", "UTC" or an offset
+    /// such as "+08:00" (default: "local")
+    #[serde(default = "default_display_timezone")]
+    pub display_timezone: String,
+    /// What to do when a message mentions a handle that matches no agent (default: FAIL)
+    #[serde(default)]
+    pub unknown_mention_policy: UnknownMentionPolicy,
+    /// What to do when an agent routes to a handle that is not an agent of the session;
+    /// anything but FAIL takes precedence over `unknown_mention_policy` (default: FAIL)
+    #[serde(default)]
+    pub agent_unresolved_routing_policy: AgentUnresolvedRoutingPolicy,
+    /// System message categories that are only sent as `system_notice` stream events
+    /// instead of being stored in the transcript. FORWARD_REVIEW is always stored since the
+    /// moderator is routed to it (default: none)
+    #[serde(default)]
+    pub suppressed_system_messages: Vec<SystemMessageCategory>,
+    /// Publish non-empty executor stderr lines as live `agent_stderr` events (default: false)
+    #[serde(default)]
+    pub stream_stderr_events: bool,
+    /// Detect the language of user messages and tell mentioned agents about it (default: false)
+    #[serde(default)]
+    pub detect_message_language: bool,
+    /// Rules redacting secrets from agent replies; agents may add their own under
+    /// `tools_enabled.redaction_rules` (default: none)
+    #[serde(default)]
+    pub redaction_rules: Vec<RedactionRule>,
+    /// Slash commands expanded into templates when a user message starts with `/name`;
+    /// text starting with an unknown `/word` or with `//` is sent as written (default: none)
+    #[serde(default)]
+    pub slash_commands: Vec<SlashCommand>,
+    /// Price in US cents per million tokens used to estimate run costs in the run
+    /// statistics; agents may override it with `tools_enabled.cost_per_million_tokens_cents`
+    /// (default: 0)
+    #[serde(default)]
+    pub cost_per_million_tokens_cents: u32,
+    /// Hours a file may sit untouched in an agent's scratch directory before it is removed
+    /// at the start of the next run; 0 keeps scratch files forever (default: 72)
+    #[serde(default = "default_scratch_retention_hours")]
+    pub scratch_retention_hours: u32,
+    /// Days a run's record directory is kept before the background sweeper deletes it; 0
+    /// keeps run directories regardless of age (default: 0)
+    #[serde(default)]
+    pub run_retention_days: u32,
+    /// Run directories kept per session agent, newest first, before older ones are swept; 0
+    /// keeps any number (default: 0)
+    #[serde(default)]
+    pub run_retention_max_per_agent: u32,
+    /// Also delete the database records of swept runs instead of keeping them without their
+    /// files (default: false)
+    #[serde(default)]
+    pub prune_swept_run_records: bool,
+    /// Estimated token budget for a run's prompt; larger prompts drop the referenced message
+    /// and then the attachment list. 0 disables the limit (default: 100000)
+    #[serde(default = "default_max_prompt_tokens")]
+    pub max_prompt_tokens: u32,
+    /// Format of the history file agents read; sessions may override it (default: JSONL_COMPACT)
+    #[serde(default)]
+    pub context_format: ChatContextFormat,
+    /// Seconds of stream silence before the session WebSocket sends a ping; 0 disables (default: 30)
+    #[serde(default = "default_ws_heartbeat_interval_secs")]
+    pub ws_heartbeat_interval_secs: u32,
+    /// Events buffered per session stream before slow subscribers start missing them,
+    /// clamped to 16..=65536; applies to streams opened afterwards (default: 1024)
+    #[serde(default = "default_stream_channel_capacity")]
+    pub stream_channel_capacity: u32,
+    /// Debug aid: absolute file that every run's raw executor stdout is also appended to,
+    /// for watching with `tail -f`; ignored inside run or workspace directories; unset
+    /// disables (default: unset)
+    #[serde(default)]
+    pub debug_stdout_tee_path: Option<String>,
+    /// Debug aid: enables `POST /chat/debug/summarize` for trying summarization prompts
+    /// against sample messages (default: false)
+    #[serde(default)]
+    pub debug_summarize_endpoint: bool,
+    /// Debug aid: enables `POST /chat/sessions/{session_id}/messages/as-agent` for posting
+    /// messages as a session agent, to exercise routing without running models (default: false)
+    #[serde(default)]
+    pub debug_agent_messages_endpoint: bool,
+}
+
+fn default_max_run_log_mb() -> u32 {
+    256
+}
+
+fn default_max_attachment_copy_mb() -> u32 {
+    20
+}
+
+fn default_moderator_timeout_secs() -> u32 {
+    300
+}
+
+fn default_workspace_io_timeout_secs() -> u32 {
+    30
+}
+
+fn default_spawn_timeout_secs() -> u32 {
+    60
+}
+
+fn default_max_agents_per_session() -> u32 {
+    20
+}
+
+fn default_delta_flush_interval_ms() -> u32 {
+    50
+}
+
+fn default_delta_flush_max_bytes() -> u32 {
+    4096
+}
+
+fn default_max_token_usage_line_bytes() -> u32 {
+    64 * 1024
+}
+
+fn default_idle_timeout_secs() -> u32 {
+    300
+}
+
+fn default_max_run_duration_secs() -> u32 {
+    7200
+}
+
+fn default_scratch_retention_hours() -> u32 {
+    72
+}
+
+fn default_max_prompt_tokens() -> u32 {
+    100_000
+}
+
+fn default_ws_heartbeat_interval_secs() -> u32 {
+    30
+}
+
+fn default_mention_fanout_concurrency() -> u32 {
+    4
+}
+
+fn default_stream_channel_capacity() -> u32 {
+    1024
+}
+
+fn default_display_timezone() -> String {
+    "local".to_string()
+}
+
+fn default_reserved_handles() -> Vec<String> {
+    vec![RESERVED_USER_HANDLE.to_string()]
+}
+
+impl ChatRunnerConfig {
+    /// Byte limit for copying attachments into run context, or `None` for no limit.
+    pub fn max_attachment_copy_bytes(&self) -> Option<u64> {
+        (self.max_attachment_copy_mb > 0)
+            .then(|| u64::from(self.max_attachment_copy_mb) * 1024 * 1024)
+    }
+
+    /// Effective raw.log capture cap in bytes for the given executor.
+    pub fn max_run_log_bytes_for(&self, executor: &BaseCodingAgent) -> u64 {
+        let executor_key = executor.to_string();
+        let megabytes = self
+            .max_run_log_mb_overrides
+            .iter()
+            .find(|(key, _)| {
+                key.trim().replace(['-', ' '], "_").to_ascii_uppercase() == executor_key
+            })
+            .map(|(_, value)| *value)
+            .unwrap_or(self.max_run_log_mb)
+            .max(1);
+        u64::from(megabytes) * 1024 * 1024
+    }
+
+    pub fn workspace_io_timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(u64::from(self.workspace_io_timeout_secs.max(1)))
+    }
+
+    pub fn spawn_timeout(&self) -> Option<std::time::Duration> {
+        (self.spawn_timeout_secs > 0)
+            .then(|| std::time::Duration::from_secs(u64::from(self.spawn_timeout_secs)))
+    }
+
+    pub fn idle_timeout(&self) -> Option<std::time::Duration> {
+        (self.idle_timeout_secs > 0)
+            .then(|| std::time::Duration::from_secs(u64::from(self.idle_timeout_secs)))
+    }
+
+    pub fn max_run_duration(&self) -> Option<std::time::Duration> {
+        (self.max_run_duration_secs > 0)
+            .then(|| std::time::Duration::from_secs(u64::from(self.max_run_duration_secs)))
+    }
+
+    pub fn scratch_retention(&self) -> Option<std::time::Duration> {
+        (self.scratch_retention_hours > 0)
+            .then(|| std::time::Duration::from_secs(u64::from(self.scratch_retention_hours) * 3600))
+    }
+
+    pub fn run_retention(&self) -> Option<std::time::Duration> {
+        (self.run_retention_days > 0)
+            .then(|| std::time::Duration::from_secs(u64::from(self.run_retention_days) * 86_400))
+    }
+
+    pub fn run_retention_max_per_agent(&self) -> Option<usize> {
+        (self.run_retention_max_per_agent > 0).then_some(self.run_retention_max_per_agent as usize)
+    }
+
+    pub fn max_prompt_tokens(&self) -> Option<u32> {
+        (self.max_prompt_tokens > 0).then_some(self.max_prompt_tokens)
+    }
+
+    /// The configured stdout tee file, ignoring blank or relative paths.
+    pub fn debug_stdout_tee_path(&self) -> Option<std::path::PathBuf> {
+        self.debug_stdout_tee_path
+            .as_deref()
+            .map(str::trim)
+            .filter(|path| !path.is_empty())
+            .map(std::path::PathBuf::from)
+            .filter(|path| path.is_absolute())
+    }
+
+    pub fn ws_heartbeat_interval(&self) -> Option<std::time::Duration> {
+        (self.ws_heartbeat_interval_secs > 0)
+            .then(|| std::time::Duration::from_secs(u64::from(self.ws_heartbeat_interval_secs)))
+    }
+
+    /// Concurrent run starts allowed per message, or `None` for no limit.
+    pub fn mention_fanout_concurrency(&self) -> Option<usize> {
+        (self.mention_fanout_concurrency > 0).then_some(self.mention_fanout_concurrency as usize)
+    }
+
+    pub fn stream_channel_capacity(&self) -> usize {
+        self.stream_channel_capacity.clamp(16, 65_536) as usize
+    }
+
+    pub fn delta_flush_interval(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(u64::from(self.delta_flush_interval_ms))
+    }
+
+    /// Whether system messages of `category` are kept out of the transcript.
+    pub fn suppresses_system_message(&self, category: SystemMessageCategory) -> bool {
+        category != SystemMessageCategory::ForwardReview
+            && self.suppressed_system_messages.contains(&category)
+    }
+
+    /// Whether `handle` (with or without a leading `@`) is reserved. Repeated whitespace is
+    /// ignored, and so is casing unless `reserved_handle_case_sensitive` is set.
+    ///
+    /// Reserved handles never name an agent: they cannot be used as member names, and
+    /// mentions of them are not routed to a run, whoever sent the message.
+    pub fn is_reserved_handle(&self, handle: &str) -> bool {
+        let handle = self.normalize_handle(handle);
+        if handle.is_empty() {
+            return false;
+        }
+        handle == RESERVED_USER_HANDLE
+            || self
+                .reserved_handles
+                .iter()
+                .any(|reserved| self.normalize_handle(reserved) == handle)
+    }
+
+    /// Whether `handle` is [`RESERVED_USER_HANDLE`], the handle addressing the human user.
+    pub fn is_user_handle(&self, handle: &str) -> bool {
+        self.normalize_handle(handle) == RESERVED_USER_HANDLE
+    }
+
+    fn normalize_handle(&self, handle: &str) -> String {
+        let handle = handle
+            .trim()
+            .trim_start_matches('@')
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ");
+        if self.reserved_handle_case_sensitive {
+            handle
+        } else {
+            handle.to_lowercase()
+        }
+    }
+}
+
+impl Default for ChatRunnerConfig {
+    fn default() -> Self {
+        Self {
+            max_run_log_mb: default_max_run_log_mb(),
+            max_run_log_mb_overrides: HashMap::new(),
+            max_attachment_copy_mb: default_max_attachment_copy_mb(),
+            moderator_timeout_secs: default_moderator_timeout_secs(),
+            moderator_timeout_action: ModeratorTimeoutAction::default(),
+            workspace_io_timeout_secs: default_workspace_io_timeout_secs(),
+            spawn_timeout_secs: default_spawn_timeout_secs(),
+            max_agents_per_session: default_max_agents_per_session(),
+            mention_fanout_concurrency: default_mention_fanout_concurrency(),
+            reserved_handles: default_reserved_handles(),
+            reserved_handle_case_sensitive: false,
+            delta_flush_interval_ms: default_delta_flush_interval_ms(),
+            delta_flush_max_bytes: default_delta_flush_max_bytes(),
+            dedupe_identical_deltas: true,
+            max_token_usage_line_bytes: default_max_token_usage_line_bytes(),
+            idle_timeout_secs: default_idle_timeout_secs(),
+            max_run_duration_secs: default_max_run_duration_secs(),
+            display_timezone: default_display_timezone(),
+            unknown_mention_policy: UnknownMentionPolicy::default(),
+            agent_unresolved_routing_policy: AgentUnresolvedRoutingPolicy::default(),
+            suppressed_system_messages: Vec::new(),
+            stream_stderr_events: false,
+            detect_message_language: false,
+            redaction_rules: Vec::new(),
+            slash_commands: Vec::new(),
+            cost_per_million_tokens_cents: 0,
+            scratch_retention_hours: default_scratch_retention_hours(),
+            run_retention_days: 0,
+            run_retention_max_per_agent: 0,
+            prune_swept_run_records: false,
+            max_prompt_tokens: default_max_prompt_tokens(),
+            context_format: ChatContextFormat::default(),
+            ws_heartbeat_interval_secs: default_ws_heartbeat_interval_secs(),
+            stream_channel_capacity: default_stream_channel_capacity(),
+            debug_stdout_tee_path: None,
+            debug_summarize_endpoint: false,
+            debug_agent_messages_endpoint: false,
+        }
+    }
+}
+
+fn default_chat_runner() -> ChatRunnerConfig {
+    ChatRunnerConfig::default()
+}
+
 fn default_true() -> bool {
     true
 }
@@ -771,9 +1342,39 @@ pub struct Config {
     /// Chat compression configuration
     #[serde(default = "default_chat_compression")]
     pub chat_compression: ChatCompressionConfig,
+    /// Chat runner configuration
+    #[serde(default = "default_chat_runner")]
+    pub chat_runner: ChatRunnerConfig,
 }
 
+/// Placeholder that replaces secret values in configs returned over HTTP.
+pub const REDACTED_SECRET: &str = "********";
+
 impl Config {
+    /// Copy of the config with credentials replaced by [`REDACTED_SECRET`].
+    pub fn redacted(&self) -> Self {
+        let mut config = self.clone();
+        let redact = |value: &mut Option<String>| {
+            if value.is_some() {
+                *value = Some(REDACTED_SECRET.to_string());
+            }
+        };
+        redact(&mut config.github.pat);
+        redact(&mut config.github.oauth_token);
+        config
+    }
+
+    /// Put back secrets that a client echoed as [`REDACTED_SECRET`] from a redacted config.
+    pub fn restore_redacted_secrets(&mut self, previous: &Config) {
+        let restore = |value: &mut Option<String>, previous: &Option<String>| {
+            if value.as_deref() == Some(REDACTED_SECRET) {
+                *value = previous.clone();
+            }
+        };
+        restore(&mut self.github.pat, &previous.github.pat);
+        restore(&mut self.github.oauth_token, &previous.github.oauth_token);
+    }
+
     fn with_completed_chat_presets(mut self) -> Self {
         complete_chat_presets_with_builtins(&mut self.chat_presets);
         self
@@ -805,6 +1406,7 @@ impl Config {
             send_message_shortcut: old_config.send_message_shortcut,
             chat_presets: default_chat_presets(),
             chat_compression: ChatCompressionConfig::default(),
+            chat_runner: ChatRunnerConfig::default(),
         }
         .with_completed_chat_presets()
     }
@@ -863,6 +1465,7 @@ impl Default for Config {
             send_message_shortcut: SendMessageShortcut::default(),
             chat_presets: default_chat_presets(),
             chat_compression: ChatCompressionConfig::default(),
+            chat_runner: ChatRunnerConfig::default(),
         }
     }
 }
@@ -2,8 +2,13 @@ pub mod analytics;
 pub mod approvals;
 pub mod auth;
 pub mod chat;
+pub mod chat_export;
 pub mod chat_history_file;
+pub mod chat_redaction;
+pub mod chat_run_retention;
 pub mod chat_runner;
+pub mod chat_slash_commands;
+pub mod chat_workspace;
 pub mod config;
 pub mod container;
 pub mod diff_stream;
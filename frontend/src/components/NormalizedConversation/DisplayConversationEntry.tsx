@@ -770,10 +770,11 @@ function DisplayConversationEntry({
   const isUserFeedback = entryType.type === 'user_feedback';
   const isLoading = entryType.type === 'loading';
   const isTokenUsage = entryType.type === 'token_usage_info';
+  const isCitation = entryType.type === 'citation';
   const isFileEdit = (a: ActionType): a is FileEditAction =>
     a.action === 'file_edit';
 
-  if (isTokenUsage) {
+  if (isTokenUsage || isCitation) {
     return null;
   }
 
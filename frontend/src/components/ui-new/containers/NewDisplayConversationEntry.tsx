@@ -350,6 +350,10 @@ function NewDisplayConversationEntry(props: Props) {
       // Displayed in the chat header as the context-usage gauge
       return null;
 
+    case 'citation':
+      // Collected into the chat message meta and rendered as footnotes there
+      return null;
+
     case 'user_feedback':
     case 'loading':
       // Fallback to legacy component for these entry types
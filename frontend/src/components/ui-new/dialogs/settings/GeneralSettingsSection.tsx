@@ -472,6 +472,21 @@ export function GeneralSettingsSection() {
                   token_threshold: value,
                   compression_percentage:
                     draft?.chat_compression?.compression_percentage ?? 25,
+                  summary_agent_priority:
+                    draft?.chat_compression?.summary_agent_priority ?? [],
+                  message_count_threshold:
+                    draft?.chat_compression?.message_count_threshold ?? null,
+                  busy_agents_policy:
+                    draft?.chat_compression?.busy_agents_policy ?? 'SKIP',
+                  busy_agents_wait_secs:
+                    draft?.chat_compression?.busy_agents_wait_secs ?? 15,
+                  summarizer_profile:
+                    draft?.chat_compression?.summarizer_profile ?? null,
+                  retry_ineffective_summary:
+                    draft?.chat_compression?.retry_ineffective_summary ?? false,
+                  run_context_mode:
+                    draft?.chat_compression?.run_context_mode ??
+                    'FULL_THEN_COMPACT',
                 },
               })
             }
@@ -497,6 +512,21 @@ export function GeneralSettingsSection() {
                   token_threshold:
                     draft?.chat_compression?.token_threshold ?? 50000,
                   compression_percentage: value,
+                  summary_agent_priority:
+                    draft?.chat_compression?.summary_agent_priority ?? [],
+                  message_count_threshold:
+                    draft?.chat_compression?.message_count_threshold ?? null,
+                  busy_agents_policy:
+                    draft?.chat_compression?.busy_agents_policy ?? 'SKIP',
+                  busy_agents_wait_secs:
+                    draft?.chat_compression?.busy_agents_wait_secs ?? 15,
+                  summarizer_profile:
+                    draft?.chat_compression?.summarizer_profile ?? null,
+                  retry_ineffective_summary:
+                    draft?.chat_compression?.retry_ineffective_summary ?? false,
+                  run_context_mode:
+                    draft?.chat_compression?.run_context_mode ??
+                    'FULL_THEN_COMPACT',
                 },
               })
             }
@@ -46,7 +46,7 @@ import {
   RenameBranchRequest,
   RenameBranchResponse,
   CheckEditorAvailabilityResponse,
-  AvailabilityInfo,
+  AgentAvailability,
   BaseCodingAgent,
   ExecutorProfileId,
   RunAgentSetupRequest,
@@ -104,10 +104,12 @@ import {
   CreateChatSession,
   UpdateChatSession,
   CreateChatMessageRequest,
+  ChatMessagePriority,
   ChatSessionAgent,
   CreateChatSessionAgentRequest,
   UpdateChatSessionAgentRequest,
   UpdateChatAgent,
+  SignedAttachmentUrl,
 } from 'shared/types';
 import type { WorkspaceWithSession } from '@/types/attempt';
 import { createWorkspaceWithSession } from '@/types/attempt';
@@ -940,6 +942,10 @@ export const configApi = {
     const response = await makeRequest('/api/info', { cache: 'no-store' });
     return handleApiResponse<UserSystemInfo>(response);
   },
+  getEffectiveConfig: async (): Promise<Config> => {
+    const response = await makeRequest('/api/config', { cache: 'no-store' });
+    return handleApiResponse<Config>(response);
+  },
   saveConfig: async (config: Config): Promise<Config> => {
     const response = await makeRequest('/api/config', {
       method: 'PUT',
@@ -957,11 +963,11 @@ export const configApi = {
   },
   checkAgentAvailability: async (
     agent: BaseCodingAgent
-  ): Promise<AvailabilityInfo> => {
+  ): Promise<AgentAvailability> => {
     const response = await makeRequest(
       `/api/agents/check-availability?executor=${encodeURIComponent(agent)}`
     );
-    return handleApiResponse<AvailabilityInfo>(response);
+    return handleApiResponse<AgentAvailability>(response);
   },
 };
 
@@ -1516,6 +1522,25 @@ export const chatApi = {
     return handleApiResponse<ChatMessage>(response);
   },
 
+  addMessageAttachments: async (
+    messageId: string,
+    files: File[]
+  ): Promise<ChatMessage> => {
+    const form = new FormData();
+    files.forEach((file) => {
+      form.append('file', file, file.name);
+    });
+
+    const response = await fetch(
+      `/api/chat/messages/${messageId}/attachments`,
+      {
+        method: 'POST',
+        body: form,
+      }
+    );
+    return handleApiResponse<ChatMessage>(response);
+  },
+
   getChatAttachmentUrl: (
     sessionId: string,
     messageId: string,
@@ -1523,6 +1548,20 @@ export const chatApi = {
   ): string =>
     `/api/chat/sessions/${sessionId}/messages/${messageId}/attachments/${attachmentId}`,
 
+  signChatAttachmentUrl: async (
+    sessionId: string,
+    messageId: string,
+    attachmentId: string
+  ): Promise<SignedAttachmentUrl> => {
+    const response = await makeRequest(
+      `/api/chat/sessions/${sessionId}/messages/${messageId}/attachments/${attachmentId}/sign`,
+      {
+        method: 'POST',
+      }
+    );
+    return handleApiResponse<SignedAttachmentUrl>(response);
+  },
+
   listMessages: async (
     sessionId: string,
     limit?: number
@@ -1647,6 +1686,9 @@ export const chatApi = {
 
   getRunDiffUrl: (runId: string): string => `/api/chat/runs/${runId}/diff`,
 
+  getSessionEventsExportUrl: (sessionId: string): string =>
+    `/api/chat/sessions/${sessionId}/events/export`,
+
   getRunDiff: async (runId: string): Promise<string> => {
     const response = await makeRequest(`/api/chat/runs/${runId}/diff`);
     if (!response.ok) {
@@ -1685,6 +1727,21 @@ export const chatApi = {
     return response.text();
   },
 
+  approveRun: async (runId: string): Promise<void> => {
+    const response = await makeRequest(`/api/chat/runs/${runId}/approve`, {
+      method: 'POST',
+    });
+    return handleApiResponse<void>(response);
+  },
+
+  denyRun: async (runId: string, reason?: string): Promise<void> => {
+    const response = await makeRequest(`/api/chat/runs/${runId}/deny`, {
+      method: 'POST',
+      body: JSON.stringify({ reason: reason ?? null }),
+    });
+    return handleApiResponse<void>(response);
+  },
+
   stopSessionAgent: async (
     sessionId: string,
     sessionAgentId: string
@@ -1700,12 +1757,14 @@ export const chatApi = {
 
   buildCreateMessageRequest: (
     content: string,
-    meta?: JsonValue | null
+    meta?: JsonValue | null,
+    priority?: ChatMessagePriority | null
   ): CreateChatMessageRequest => ({
     sender_type: ChatSenderType.user,
     sender_id: null,
     content,
     meta: meta ?? null,
+    priority: priority ?? null,
   }),
 };
 
@@ -495,6 +495,16 @@ export function useChatWebSocket(
             return;
           }
 
+          if (
+            payload.type === 'message_updated' ||
+            payload.type === 'messages_deleted'
+          ) {
+            queryClient.invalidateQueries({
+              queryKey: ['chatMessages', activeSessionId],
+            });
+            return;
+          }
+
           if (payload.type === 'agent_delta') {
             handleAgentDelta(payload);
             return;
@@ -42,7 +42,6 @@ export type ChatAttachment = {
   mime_type?: string | null;
   size_bytes?: number;
   kind?: string;
-  relative_path?: string;
 };
 
 export type DiffFileEntry = {
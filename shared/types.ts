@@ -126,14 +126,40 @@ export type CreateChatAgent = { name: string, runner_type: string, system_prompt
 
 export type UpdateChatAgent = { name: string | null, runner_type: string | null, system_prompt: string | null, tools_enabled: JsonValue | null, };
 
+/**
+ * A system prompt an agent used until it was changed at `created_at`.
+ */
+export type ChatAgentPromptVersion = { id: string, agent_id: string, system_prompt: string, created_at: string, };
+
 export type ChatMessage = { id: string, session_id: string, sender_type: ChatSenderType, sender_id: string | null, content: string, mentions: string[], meta: JsonValue, created_at: string, };
 
 export enum ChatSenderType { user = "user", agent = "agent", system = "system" }
 
-export type ChatSessionAgent = { id: string, session_id: string, agent_id: string, state: ChatSessionAgentState, workspace_path: string | null, pty_session_key: string | null, agent_session_id: string | null, agent_message_id: string | null, created_at: string, updated_at: string, };
+export type ChatSessionAgent = { id: string, session_id: string, agent_id: string, state: ChatSessionAgentState, workspace_path: string | null, pty_session_key: string | null, agent_session_id: string | null, agent_message_id: string | null, 
+/**
+ * Mentions of a muted member are queued until it is unmuted
+ */
+muted: boolean, 
+/**
+ * Position in the session's member list, lowest first
+ */
+sort_order: bigint, created_at: string, updated_at: string, };
 
 export enum ChatSessionAgentState { idle = "idle", running = "running", waitingapproval = "waitingapproval", dead = "dead" }
 
+/**
+ * Run aggregates for one session member, kept across restarts.
+ */
+export type ChatSessionAgentActivity = { session_agent_id: string, 
+/**
+ * When the member's most recent run finished
+ */
+last_active_at: string | null, total_runs: bigint, 
+/**
+ * Sum of reported (or estimated) tokens across runs
+ */
+total_tokens: bigint, failed_runs: bigint, };
+
 export type ChatPermission = { id: string, session_id: string, session_agent_id: string, capability: string, scope: JsonValue, ttl_type: ChatPermissionTtlType, expires_at: string | null, granted_by: string | null, created_at: string, };
 
 export enum ChatPermissionTtlType { once = "once", time = "time", session = "session" }
@@ -142,14 +168,225 @@ export type ChatArtifact = { id: string, session_id: string, name: string, path:
 
 export type ChatRun = { id: string, session_id: string, session_agent_id: string, run_index: bigint, run_dir: string, input_path: string | null, output_path: string | null, raw_log_path: string | null, meta_path: string | null, created_at: string, };
 
-export type ChatStreamEvent = { "type": "message_new", message: ChatMessage, } | { "type": "agent_delta", session_id: string, session_agent_id: string, agent_id: string, run_id: string, stream_type: ChatStreamDeltaType, content: string, delta: boolean, is_final: boolean, } | { "type": "agent_state", session_agent_id: string, agent_id: string, state: ChatSessionAgentState, started_at: string | null, } | { "type": "mention_acknowledged", session_id: string, message_id: string, mentioned_agent: string, agent_id: string, status: MentionStatus, } | { "type": "compression_warning", session_id: string, warning: CompressionWarning, };
+export type ChatRunWithTags = { tags: Array<string>, id: string, session_id: string, session_agent_id: string, run_index: bigint, run_dir: string, input_path: string | null, output_path: string | null, raw_log_path: string | null, meta_path: string | null, created_at: string, };
+
+/**
+ * Dimension run statistics are totalled over.
+ */
+export type ChatRunStatsGroupBy = "agent" | "session" | "day";
+
+/**
+ * Run totals for one agent, session or day; only the grouped key is set.
+ */
+export type ChatRunStats = { agent_id: string | null, session_id: string | null, 
+/**
+ * UTC day as `YYYY-MM-DD`
+ */
+day: string | null, run_count: bigint, total_tokens: bigint, 
+/**
+ * Estimated cost in US dollars
+ */
+estimated_cost: number, failed_runs: bigint, };
+
+export type ChatStreamEvent = { "type": "message_new", message: ChatMessage, } | { "type": "message_updated", message: ChatMessage, } | { "type": "messages_deleted", session_id: string, message_ids: Array<string>, } | { "type": "agent_delta", session_id: string, session_agent_id: string, agent_id: string, run_id: string, stream_type: ChatStreamDeltaType, content: string, delta: boolean, is_final: boolean, } | { "type": "agent_state", session_agent_id: string, agent_id: string, state: ChatSessionAgentState, started_at: string | null, } | { "type": "agent_muted", session_agent_id: string, agent_id: string, muted: boolean, } | { "type": "mention_acknowledged", session_id: string, message_id: string, mentioned_agent: string, agent_id: string, status: MentionStatus, } | { "type": "compression_warning", session_id: string, warning: CompressionWarning, } | { "type": "agent_stderr", session_id: string, session_agent_id: string, run_id: string, line: string, } | { "type": "approval_requested", session_id: string, session_agent_id: string, run_id: string, prompt: string, tool_name: string, tool_input: JsonValue, } | { "type": "approval_resolved", session_id: string, session_agent_id: string, run_id: string, status: ApprovalStatus, } | { "type": "system_notice", session_id: string, category: SystemMessageCategory, content: string, meta: JsonValue, };
 
 export type ChatStreamDeltaType = "assistant" | "thinking";
 
 export type MentionStatus = "received" | "running" | "completed" | "failed";
 
+/**
+ * Why a mention did not start a run.
+ */
+export type RoutingSkipReason = "chain_depth_limit" | "not_allowed" | "held_for_moderator";
+
+export type RoutingSkip = { target: string, reason: RoutingSkipReason, };
+
+/**
+ * What routing did with a message's mentions, stored under `meta.routing`.
+ */
+export type RoutingOutcome = { 
+/**
+ * Targets handed to a session member's run
+ */
+dispatched: Array<string>, 
+/**
+ * Targets that match no session member
+ */
+not_found: Array<string>, skipped: Array<RoutingSkip>, };
+
 export type CompressionWarning = { code: string, message: string, split_file_path: string, };
 
+/**
+ * A session member as presented to agents in the `[GROUP_MEMBERS]` prompt block.
+ */
+export type SessionAgentSummary = { session_agent_id: string, agent_id: string, name: string, runner_type: string, state: ChatSessionAgentState, 
+/**
+ * Description of the agent for GROUP_MEMBERS display
+ */
+description: string | null, system_prompt?: string, tools_enabled: JsonValue, };
+
+/**
+ * Live state of a session's event broadcast channel.
+ */
+export type ChatStreamStats = { session_id: string, 
+/**
+ * Open receivers, one per connected stream socket
+ */
+subscriber_count: number, 
+/**
+ * Events the slowest receiver has not read yet
+ */
+queued_events: number, 
+/**
+ * Events the channel holds before slow receivers start losing them
+ */
+capacity: number, 
+/**
+ * Events receivers of this session have lost by lagging since the channel was created
+ */
+lagged_events: bigint, };
+
+export type ChatExportJobStatus = "running" | "completed" | "failed";
+
+export type ChatExportJob = { job_id: string, session_id: string, status: ChatExportJobStatus, 
+/**
+ * Directory holding the export files once completed
+ */
+archive_path: string | null, 
+/**
+ * Relative URL downloading the exported messages once completed
+ */
+download_url: string | null, 
+/**
+ * Why the export failed
+ */
+error: string | null, created_at: string, finished_at: string | null, };
+
+/**
+ * Why the database and the runner disagree about whether a member is running.
+ */
+export type RunningAgentDiscrepancy = "no_live_run" | "not_marked_running";
+
+/**
+ * A session member with a run in progress, as seen by `GET /chat/running`.
+ */
+export type RunningAgent = { session_id: string, session_agent_id: string, agent_id: string, state: ChatSessionAgentState, 
+/**
+ * Run record of the current run, once it has been created
+ */
+run_id: string | null, 
+/**
+ * When the member last changed state, i.e. when it started running
+ */
+started_at: string, 
+/**
+ * Whether the runner holds a cancellation token for the member
+ */
+live: boolean, discrepancy: RunningAgentDiscrepancy | null, };
+
+/**
+ * A cutoff file archived by truncation-based compression in a member's context directory.
+ */
+export type ChatCutoffSummary = { index: number, 
+/**
+ * Session member whose workspace holds the file
+ */
+session_agent_id: string, cutoff_at: string | null, message_count: number, };
+
+/**
+ * Contents of a single cutoff file.
+ */
+export type ChatCutoffArchive = { messages: Array<JsonValue>, index: number, 
+/**
+ * Session member whose workspace holds the file
+ */
+session_agent_id: string, cutoff_at: string | null, message_count: number, };
+
+/**
+ * Result of stopping every running agent of a session.
+ */
+export type StopAllAgentsOutcome = { 
+/**
+ * Number of runs that were stopped
+ */
+stopped: number, 
+/**
+ * Members whose run could not be stopped
+ */
+failed: Array<string>, };
+
+/**
+ * A message the next compaction would fold into the history summary.
+ */
+export type CompactionPreviewMessage = { 
+/**
+ * `None` for the summary or truncation note left by an earlier compaction
+ */
+message_id: string | null, sender: string, content: string, timestamp: string, tokens: number, };
+
+/**
+ * What compaction would archive from the current history, with the settings in effect.
+ */
+export type CompactionPreview = { total_messages: number, total_tokens: number, token_threshold: number, compression_percentage: number, message_count_threshold: number | null, 
+/**
+ * Whether the history currently crosses a threshold, i.e. the next run would compact
+ */
+triggered: boolean, 
+/**
+ * Token budget the compressed prefix is chosen to cover
+ */
+target_tokens: number, selected_tokens: number, 
+/**
+ * Oldest-first prefix that would be summarized and written to a cutoff file
+ */
+messages: Array<CompactionPreviewMessage>, };
+
+/**
+ * Result of summarizing sample messages outside any session.
+ */
+export type DebugSummary = { summary: string, 
+/**
+ * Prompt the agent was given
+ */
+prompt: string, 
+/**
+ * Estimated tokens of the sample messages
+ */
+tokens_before: number, 
+/**
+ * Estimated tokens of the summary entry that would replace them in the history
+ */
+tokens_after: number, };
+
+/**
+ * Why summarization would not use a session agent.
+ */
+export type SummaryFallbackReason = "no_agents" | "all_agents_busy";
+
+/**
+ * Who would summarize the session history if compaction ran now.
+ */
+export type SummaryAgentPreview = { "outcome": "agent", session_agent_id: string, agent_id: string, agent_name: string, state: ChatSessionAgentState, 
+/**
+ * Whether the agent is listed in `summary_agent_priority`
+ */
+preferred: boolean, } | { "outcome": "summarizer", executor_profile: ExecutorProfileId, } | { "outcome": "truncation", reason: SummaryFallbackReason, 
+/**
+ * Seconds the WAIT policy would first give a busy agent to become idle
+ */
+wait_secs: bigint | null, };
+
+/**
+ * How a message is ordered in a busy agent's queue; FIFO within the same priority.
+ */
+export type ChatMessagePriority = "low" | "normal" | "high";
+
+/**
+ * One entry of a session's audit log. Live stream events are not stored, so these are
+ * reconstructed from messages, run records and each run's `meta.json`.
+ */
+export type SessionAuditEvent = { "type": "message_created", timestamp: string, message_id: string, sender_type: ChatSenderType, sender_id: string | null, mentions: Array<string>, } | { "type": "run_started", timestamp: string, run_id: string, session_agent_id: string, run_index: bigint, } | { "type": "compression_warning", timestamp: string, run_id: string, session_agent_id: string, code: string, message: string, } | { "type": "run_finished", timestamp: string, run_id: string, session_agent_id: string, failed: boolean, failure_reason: string | null, } | { "type": "agent_state_changed", timestamp: string, run_id: string, session_agent_id: string, state: ChatSessionAgentState, };
+
 export type Image = { id: string, file_path: string, original_name: string, mime_type: string | null, size_bytes: bigint, hash: string, created_at: string, updated_at: string, };
 
 export type CreateImage = { file_path: string, original_name: string, mime_type: string | null, size_bytes: bigint, hash: string, };
@@ -296,6 +533,12 @@ export type CheckEditorAvailabilityResponse = { available: boolean, };
 
 export type CheckAgentAvailabilityQuery = { executor: BaseCodingAgent, };
 
+export type AgentAvailability = { 
+/**
+ * Output format the installed CLI will be run with, for executors that negotiate one
+ */
+output_format: ExecutorOutputFormat | null, } & AvailabilityInfo;
+
 export type CurrentUserResponse = { user_id: string, };
 
 export type CreateFollowUpAttempt = { prompt: string, executor_profile_id: ExecutorProfileId, retry_process_id: string | null, force_when_dirty: boolean | null, perform_git_reset: boolean | null, };
@@ -306,9 +549,225 @@ export type CreateChatSessionAgentRequest = { agent_id: string, workspace_path:
 
 export type UpdateChatSessionAgentRequest = { workspace_path: string | null, };
 
+export type ReorderChatSessionAgentsRequest = { session_agent_ids: Array<string>, };
+
+export type ApplyChatTeamResult = { 
+/**
+ * Agents created because no agent had the member preset's name yet
+ */
+created_agents: Array<ChatAgent>, 
+/**
+ * Session members added for the team
+ */
+added: Array<ChatSessionAgent>, 
+/**
+ * Member presets skipped because the session already has a member with their name
+ */
+skipped_preset_ids: Array<string>, };
+
+export type ReplayChatSessionRequest = { title: string | null, 
+/**
+ * Agents to add to the new session; user messages are replayed against them
+ */
+agent_ids: Array<string>, };
+
+export type ChatSessionModerator = { moderator_agent_id: string | null, };
+
+export type ChatSessionDefaultAgent = { 
+/**
+ * Member that answers user messages mentioning nobody; unset keeps them unrouted
+ */
+default_agent_id: string | null, };
+
+export type ChatSessionTranslation = { enabled: boolean, 
+/**
+ * Target language for agent replies; defaults to the UI language when unset
+ */
+target_language: string | null, };
+
+export type ChatSessionContextFormat = { 
+/**
+ * Session override of the history file format; unset uses the configured default
+ */
+format: ChatContextFormat | null, };
+
+export type ChatSessionExecutorProfile = { 
+/**
+ * Profile run by members whose agent has no runner type of its own
+ */
+executor_profile: ExecutorProfileId | null, };
+
+export type EstimateChatTokensRequest = { 
+/**
+ * Draft message body that has not been sent yet
+ */
+content: string, 
+/**
+ * Agent whose reported context window should be used; any agent when unset
+ */
+agent_id: string | null, };
+
+export type ChatTokenEstimate = { context_tokens: number, draft_tokens: number, total_tokens: number, 
+/**
+ * Context window last reported by an executor in this session, if any
+ */
+model_context_window: number | null, };
+
+export type ChatCutoffQuery = { 
+/**
+ * Member whose context directory holds the file; defaults to the first match
+ */
+session_agent_id: string | null, };
+
+export type DenyChatRunApproval = { 
+/**
+ * Passed back to the executor as the denial reason
+ */
+reason: string | null, };
+
+export type UpdateChatRunTags = { 
+/**
+ * Complete tag set for the run; duplicates and blank tags are dropped
+ */
+tags: Array<string>, };
+
+export type RunDiffLineKind = "context" | "added" | "removed";
+
+export type RunDiffLine = { kind: RunDiffLineKind, 
+/**
+ * Line text without its leading ` `, `+` or `-`
+ */
+content: string, };
+
+export type RunDiffHunk = { old_start: number, old_lines: number, new_start: number, new_lines: number, 
+/**
+ * Text after the closing `@@`, usually the enclosing function
+ */
+section: string, lines: Array<RunDiffLine>, };
+
+export type RunDiffFile = { 
+/**
+ * Path before the change; null for added files
+ */
+old_path: string | null, 
+/**
+ * Path after the change; null for deleted files
+ */
+new_path: string | null, hunks: Array<RunDiffHunk>, };
+
+/**
+ * Final output of a finished run, for clients that missed the live stream.
+ */
+export type ChatRunOutput = { run_id: string, 
+/**
+ * Content of the run's `output.md`
+ */
+output: string, 
+/**
+ * Parsed `meta.json`; null when the run has none or it cannot be read
+ */
+meta: JsonValue | null, };
+
+/**
+ * One run of a [`ChatRunComparison`].
+ */
+export type ChatRunComparisonSide = { run_index: bigint, 
+/**
+ * `token_usage` from the run's meta; null when it was not recorded
+ */
+token_usage: JsonValue | null, 
+/**
+ * Parsed workspace diff; null when the run captured none
+ */
+diff: Array<RunDiffFile> | null, run_id: string, 
+/**
+ * Content of the run's `output.md`
+ */
+output: string, 
+/**
+ * Parsed `meta.json`; null when the run has none or it cannot be read
+ */
+meta: JsonValue | null, };
+
+/**
+ * Two runs of the same session agent side by side, e.g. before and after a prompt change.
+ */
+export type ChatRunComparison = { session_agent_id: string, base: ChatRunComparisonSide, other: ChatRunComparisonSide, };
+
+export type ChatCompressionConfigUpdate = { config: ChatCompressionConfig, 
+/**
+ * Sessions whose cached compression result no longer matched the new settings
+ */
+invalidated_sessions: Array<string>, };
+
+export type ChatPresetPromptValidation = { preset_id: string, is_builtin: boolean, 
+/**
+ * tiktoken (cl100k) estimate of the preset's system prompt
+ */
+token_estimate: number, warn_threshold: number, 
+/**
+ * Set when `token_estimate` exceeds `warn_threshold`
+ */
+warning: string | null, };
+
+export type DebugSummaryMessage = { 
+/**
+ * Sender as written in the history, e.g. "user:you" or "agent:coder"
+ */
+sender: string, content: string, };
+
+export type DebugSummarizeRequest = { 
+/**
+ * Agent whose executor writes the summary
+ */
+agent_id: string, messages: Array<DebugSummaryMessage>, 
+/**
+ * Replaces the regular summarization prompt; `{messages}` marks where the messages go
+ */
+prompt_template: string | null, };
+
+/**
+ * Control frames a client may send over the session stream socket.
+ */
+export type ChatStreamControl = { "action": "stop_agent", session_agent_id: string, } | { "action": "subscribe_run", run_id: string, } | { "action": "unsubscribe_run", run_id: string, } | { "action": "filter_events", events: Array<string>, };
+
+/**
+ * Replies to control frames, interleaved with the regular stream events.
+ */
+export type ChatStreamControlReply = { "type": "control_ack", action: string, } | { "type": "control_nack", action: string | null, error: string, };
+
+export type ChatStreamQuery = { 
+/**
+ * Comma-separated event types to receive, e.g. "message_new,agent_state"; all when
+ * omitted
+ */
+events: string | null, };
+
 export type ChatMessageListQuery = { limit: bigint | null, };
 
-export type CreateChatMessageRequest = { sender_type: ChatSenderType, sender_id: string | null, content: string, meta: JsonValue | null, };
+export type CreateChatMessageRequest = { sender_type: ChatSenderType, sender_id: string | null, content: string, meta: JsonValue | null, 
+/**
+ * Queue priority for busy agents; stored as `meta.priority`, defaults to `normal`
+ */
+priority: ChatMessagePriority | null, };
+
+export type CreateAgentMessageRequest = { 
+/**
+ * Session member the message is posted as
+ */
+agent_id: string, content: string, meta: JsonValue | null, };
+
+export type SignedAttachmentUrl = { 
+/**
+ * Path serving the attachment without session context, valid until `expires_at`
+ */
+url: string, 
+/**
+ * Unix timestamp (seconds)
+ */
+expires_at: bigint, };
+
+export type SignedAttachmentQuery = { expires: bigint, signature: string, };
 
 export type ChangeTargetBranchRequest = { repo_id: string, new_target_branch: string, };
 
@@ -470,7 +929,11 @@ chat_presets: ChatPresetsConfig,
 /**
  * Chat compression configuration
  */
-chat_compression: ChatCompressionConfig, };
+chat_compression: ChatCompressionConfig, 
+/**
+ * Chat runner configuration
+ */
+chat_runner: ChatRunnerConfig, };
 
 export type NotificationConfig = { sound_enabled: boolean, push_enabled: boolean, sound_file: SoundFile, };
 
@@ -500,7 +963,287 @@ token_threshold: number,
 /**
  * Percentage of messages to compress (default: 25)
  */
-compression_percentage: number, };
+compression_percentage: number, 
+/**
+ * Agent ids tried first for summarization, in order, before state-based ordering
+ */
+summary_agent_priority: Array<string>, 
+/**
+ * Also compress once history holds more than this many messages (unset: tokens only)
+ */
+message_count_threshold: number | null, 
+/**
+ * What summarization does when every session agent is busy (default: SKIP)
+ */
+busy_agents_policy: SummaryBusyAgentsPolicy, 
+/**
+ * Upper bound in seconds for the WAIT policy, clamped to 1..=60 (default: 15)
+ */
+busy_agents_wait_secs: number, 
+/**
+ * Executor profile used by the SUMMARIZER policy instead of a session agent
+ */
+summarizer_profile: ExecutorProfileId | null, 
+/**
+ * Retry once with a stricter prompt when a summary does not reduce the token count,
+ * before falling back to truncation (default: false)
+ */
+retry_ineffective_summary: boolean, 
+/**
+ * Where runs take their shared history from relative to background compaction
+ * (default: FULL_THEN_COMPACT)
+ */
+run_context_mode: RunContextMode, };
+
+/**
+ * What summarization does when every agent in the session is running
+ */
+export type SummaryBusyAgentsPolicy = "SKIP" | "WAIT" | "SUMMARIZER";
+
+/**
+ * How a run's shared history file is built relative to background compaction
+ */
+export type RunContextMode = "FULL_THEN_COMPACT" | "CACHED_THEN_COMPACT";
+
+/**
+ * Shape of the group history file handed to agents
+ */
+export type ChatContextFormat = "JSONL_COMPACT" | "JSONL_RICH" | "MARKDOWN";
+
+/**
+ * A pattern whose matches are replaced in agent output before it is streamed or stored
+ */
+export type RedactionRule = { 
+/**
+ * Regular expression in Rust `regex` syntax
+ */
+pattern: string, 
+/**
+ * Replacement text; `$1` style references to capture groups are expanded
+ * (default: "[REDACTED]")
+ */
+replacement: string, };
+
+/**
+ * A message template users invoke by starting a message with `/name`
+ */
+export type SlashCommand = { 
+/**
+ * Command name without the leading slash, matched case-insensitively
+ */
+name: string, 
+/**
+ * Short help text shown next to the command
+ */
+description: string, 
+/**
+ * Text the message is replaced with; `{param}` inserts a parameter and `{args}` the
+ * whole argument string
+ */
+template: string, 
+/**
+ * Parameter names, filled by `param=value` arguments or else by the remaining
+ * arguments in order
+ */
+params: Array<string>, };
+
+export type ChatRunnerConfig = { 
+/**
+ * Maximum size of a run's raw.log in MB before disk capture stops (default: 256)
+ */
+max_run_log_mb: number, 
+/**
+ * Per-executor overrides of `max_run_log_mb`, keyed by runner type (e.g. "CODEX")
+ */
+max_run_log_mb_overrides: { [key in string]?: number }, 
+/**
+ * Largest attachment in MB copied into a run's context directory; larger ones are
+ * referenced by their original path and marked as not copied. 0 copies any size
+ * (default: 20)
+ */
+max_attachment_copy_mb: number, 
+/**
+ * Seconds to wait for a session moderator to decide on a forward (default: 300)
+ */
+moderator_timeout_secs: number, 
+/**
+ * Action applied to a forward once `moderator_timeout_secs` elapses (default: DROP)
+ */
+moderator_timeout_action: ModeratorTimeoutAction, 
+/**
+ * Seconds allowed for workspace setup and git capture before a run fails (default: 30)
+ */
+workspace_io_timeout_secs: number, 
+/**
+ * Seconds an executor may take to start its process before the run fails; 0 disables
+ * (default: 60)
+ */
+spawn_timeout_secs: number, 
+/**
+ * Maximum number of AI members a single session may contain (default: 20)
+ */
+max_agents_per_session: number, 
+/**
+ * Runs a single message may start at once; further mentions wait for a slot and
+ * start in mention order. 0 starts them all at once (default: 4)
+ */
+mention_fanout_concurrency: number, 
+/**
+ * Handles that cannot be used as AI member names or routed to as agents (default: ["you"])
+ */
+reserved_handles: Array<string>, 
+/**
+ * Match reserved handles, including "you", only with their exact casing, so members
+ * such as "You" or "System" can be created; by default casing is ignored (default: false)
+ */
+reserved_handle_case_sensitive: boolean, 
+/**
+ * Milliseconds to coalesce streaming deltas before sending them; 0 sends each immediately (default: 50)
+ */
+delta_flush_interval_ms: number, 
+/**
+ * Buffered delta size in bytes that triggers an early flush (default: 4096)
+ */
+delta_flush_max_bytes: number, 
+/**
+ * Skip re-sending assistant/thinking content identical to what the stream already
+ * shows, for executors that re-emit whole messages (default: true)
+ */
+dedupe_identical_deltas: boolean, 
+/**
+ * Executor stdout lines longer than this many bytes are not parsed for token usage;
+ * usage events are small and long lines are typically file dumps (default: 65536)
+ */
+max_token_usage_line_bytes: number, 
+/**
+ * Seconds without any executor output before a run is cancelled; 0 disables (default: 300)
+ */
+idle_timeout_secs: number, 
+/**
+ * Seconds a run may take regardless of activity before it is killed, not counting time
+ * spent waiting on a tool approval; agents may override it with
+ * `tools_enabled.max_run_duration_secs`. 0 disables (default: 7200)
+ */
+max_run_duration_secs: number, 
+/**
+ * Timezone for times shown in agent context and exports: "local", "UTC" or an offset
+ * such as "+08:00" (default: "local")
+ */
+display_timezone: string, 
+/**
+ * What to do when a message mentions a handle that matches no agent (default: FAIL)
+ */
+unknown_mention_policy: UnknownMentionPolicy, 
+/**
+ * What to do when an agent routes to a handle that is not an agent of the session;
+ * anything but FAIL takes precedence over `unknown_mention_policy` (default: FAIL)
+ */
+agent_unresolved_routing_policy: AgentUnresolvedRoutingPolicy, 
+/**
+ * System message categories that are only sent as `system_notice` stream events
+ * instead of being stored in the transcript. FORWARD_REVIEW is always stored since the
+ * moderator is routed to it (default: none)
+ */
+suppressed_system_messages: Array<SystemMessageCategory>, 
+/**
+ * Publish non-empty executor stderr lines as live `agent_stderr` events (default: false)
+ */
+stream_stderr_events: boolean, 
+/**
+ * Detect the language of user messages and tell mentioned agents about it (default: false)
+ */
+detect_message_language: boolean, 
+/**
+ * Rules redacting secrets from agent replies; agents may add their own under
+ * `tools_enabled.redaction_rules` (default: none)
+ */
+redaction_rules: Array<RedactionRule>, 
+/**
+ * Slash commands expanded into templates when a user message starts with `/name`;
+ * text starting with an unknown `/word` or with `//` is sent as written (default: none)
+ */
+slash_commands: Array<SlashCommand>, 
+/**
+ * Price in US cents per million tokens used to estimate run costs in the run
+ * statistics; agents may override it with `tools_enabled.cost_per_million_tokens_cents`
+ * (default: 0)
+ */
+cost_per_million_tokens_cents: number, 
+/**
+ * Hours a file may sit untouched in an agent's scratch directory before it is removed
+ * at the start of the next run; 0 keeps scratch files forever (default: 72)
+ */
+scratch_retention_hours: number, 
+/**
+ * Days a run's record directory is kept before the background sweeper deletes it; 0
+ * keeps run directories regardless of age (default: 0)
+ */
+run_retention_days: number, 
+/**
+ * Run directories kept per session agent, newest first, before older ones are swept; 0
+ * keeps any number (default: 0)
+ */
+run_retention_max_per_agent: number, 
+/**
+ * Also delete the database records of swept runs instead of keeping them without their
+ * files (default: false)
+ */
+prune_swept_run_records: boolean, 
+/**
+ * Estimated token budget for a run's prompt; larger prompts drop the referenced message
+ * and then the attachment list. 0 disables the limit (default: 100000)
+ */
+max_prompt_tokens: number, 
+/**
+ * Format of the history file agents read; sessions may override it (default: JSONL_COMPACT)
+ */
+context_format: ChatContextFormat, 
+/**
+ * Seconds of stream silence before the session WebSocket sends a ping; 0 disables (default: 30)
+ */
+ws_heartbeat_interval_secs: number, 
+/**
+ * Events buffered per session stream before slow subscribers start missing them,
+ * clamped to 16..=65536; applies to streams opened afterwards (default: 1024)
+ */
+stream_channel_capacity: number, 
+/**
+ * Debug aid: absolute file that every run's raw executor stdout is also appended to,
+ * for watching with `tail -f`; ignored inside run or workspace directories; unset
+ * disables (default: unset)
+ */
+debug_stdout_tee_path: string | null, 
+/**
+ * Debug aid: enables `POST /chat/debug/summarize` for trying summarization prompts
+ * against sample messages (default: false)
+ */
+debug_summarize_endpoint: boolean, 
+/**
+ * Debug aid: enables `POST /chat/sessions/{session_id}/messages/as-agent` for posting
+ * messages as a session agent, to exercise routing without running models (default: false)
+ */
+debug_agent_messages_endpoint: boolean, };
+
+/**
+ * What happens to a moderated forward when the moderator does not decide in time
+ */
+export type ModeratorTimeoutAction = "DROP" | "PROCEED";
+
+/**
+ * How a mention that matches no agent at all is handled
+ */
+export type UnknownMentionPolicy = "FAIL" | "IGNORE" | "WARN_ONCE";
+
+/**
+ * How an agent's `[sendMessageTo@@X]` is handled when X is not an agent of the session
+ */
+export type AgentUnresolvedRoutingPolicy = "FAIL" | "SILENT_DROP" | "WARN_EVENT_ONLY";
+
+/**
+ * Kind of system message the chat runner posts into a transcript, stored in the
+ * message's `meta.system_category`
+ */
+export type SystemMessageCategory = "MENTION_FAILURE" | "FORWARD_REVIEW" | "FORWARD_OUTCOME";
 
 export type ChatPresetsConfig = { 
 /**
@@ -625,6 +1368,8 @@ name: string, description?: string | null, };
 
 export type AvailabilityInfo = { "type": "LOGIN_DETECTED", last_auth_timestamp: bigint, } | { "type": "INSTALLATION_FOUND" } | { "type": "NOT_FOUND" };
 
+export enum ExecutorOutputFormat { STREAM_JSON = "STREAM_JSON", PLAIN_TEXT = "PLAIN_TEXT" }
+
 export type CommandBuilder = { 
 /**
  * Base executable command (e.g., "npx -y @anthropic-ai/claude-code@latest")
@@ -649,7 +1394,7 @@ export type ExecutorConfig = { [key in string]?: { "CLAUDE_CODE": ClaudeCode } |
 
 export type ExecutorConfigs = { executors: { [key in BaseCodingAgent]?: ExecutorConfig }, };
 
-export enum BaseAgentCapability { SESSION_FORK = "SESSION_FORK", SETUP_HELPER = "SETUP_HELPER", CONTEXT_USAGE = "CONTEXT_USAGE" }
+export enum BaseAgentCapability { SESSION_FORK = "SESSION_FORK", SETUP_HELPER = "SETUP_HELPER", CONTEXT_USAGE = "CONTEXT_USAGE", PERSISTENT_PROCESS = "PERSISTENT_PROCESS" }
 
 export type ClaudeCode = { append_prompt: AppendPrompt, claude_code_router?: boolean | null, plan?: boolean | null, approvals?: boolean | null, model?: string | null, dangerously_skip_permissions?: boolean | null, disable_api_key?: boolean | null, base_command_override?: string | null, additional_params?: Array<string> | null, env?: { [key in string]?: string } | null, };
 
@@ -735,10 +1480,15 @@ export type CommandRunResult = { exit_status: CommandExitStatus | null, output:
 
 export type NormalizedEntry = { timestamp: string | null, entry_type: NormalizedEntryType, content: string, };
 
-export type NormalizedEntryType = { "type": "user_message" } | { "type": "user_feedback", denied_tool: string, } | { "type": "assistant_message" } | { "type": "tool_use", tool_name: string, action_type: ActionType, status: ToolStatus, } | { "type": "system_message" } | { "type": "error_message", error_type: NormalizedEntryError, } | { "type": "thinking" } | { "type": "loading" } | { "type": "next_action", failed: boolean, execution_processes: number, needs_setup: boolean, } | { "type": "token_usage_info" } & TokenUsageInfo;
+export type NormalizedEntryType = { "type": "user_message" } | { "type": "user_feedback", denied_tool: string, } | { "type": "assistant_message" } | { "type": "tool_use", tool_name: string, action_type: ActionType, status: ToolStatus, } | { "type": "system_message" } | { "type": "error_message", error_type: NormalizedEntryError, } | { "type": "thinking" } | { "type": "loading" } | { "type": "next_action", failed: boolean, execution_processes: number, needs_setup: boolean, } | { "type": "token_usage_info" } & TokenUsageInfo | { "type": "citation" } & Citation;
 
 export type TokenUsageInfo = { total_tokens: number, model_context_window: number, input_tokens: number | null, output_tokens: number | null, cache_read_tokens: number | null, is_estimated: boolean, };
 
+/**
+ * A source reference reported by the executor alongside its answer.
+ */
+export type Citation = { title: string | null, url: string | null, snippet: string | null, };
+
 export type FileChange = { "action": "write", content: string, } | { "action": "delete" } | { "action": "rename", new_path: string, } | { "action": "edit", 
 /**
  * Unified diff containing file header and hunks.